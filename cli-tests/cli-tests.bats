@@ -160,7 +160,8 @@ _concurrent_send_test_worker () {
   test 2 = "$(ls "$REPO"/data | wc -l)"
   bupstash gc
   bupstash list
-  test 1 = "$(sqlite3 "$SCRATCH/query-cache.sqlite3" 'select count(*) from ItemOpLog;')"
+  # The cache had synced every op before the gc, so it keeps its last op to continue from.
+  test 2 = "$(sqlite3 "$SCRATCH/query-cache.sqlite3" 'select count(*) from ItemOpLog;')"
   test 1 = "$(sqlite3 "$REPO/bupstash.sqlite3" 'select count(*) from ItemOpLog;')"
   test 1 = "$(sqlite3 "$REPO/bupstash.sqlite3" 'select count(*) from Items;')"
   test 1 = "$(ls "$REPO"/data | wc -l)"
@@ -186,7 +187,9 @@ _concurrent_send_test_worker () {
   bupstash rm id=$id1
   bupstash restore-removed
   test 2 = "$(bupstash list | wc -l)"
-  test 4 = "$(sqlite3 "$SCRATCH/query-cache.sqlite3" 'select count(*) from ItemOpLog;')"
+  # The cache started from a snapshot, so the restore makes it fetch a new one
+  # holding the current items and the last op.
+  test 3 = "$(sqlite3 "$SCRATCH/query-cache.sqlite3" 'select count(*) from ItemOpLog;')"
   test 4 = "$(sqlite3 "$REPO/bupstash.sqlite3" 'select count(*) from ItemOpLog;')"
   test 2 = "$(sqlite3 "$REPO/bupstash.sqlite3" 'select count(*) from Items;')"
   test 2 = "$(ls "$REPO"/data | wc -l)"
@@ -266,7 +269,7 @@ _concurrent_send_test_worker () {
   echo c > "$SCRATCH/foo/bar/c.txt"
   id=$(bupstash put --no-send-log :: "$SCRATCH/foo")
   test 5 = "$(bupstash get id=$id | tar -tf - | wc -l)"
-  id=$(bupstash put --no-stat-cache :: "$SCRATCH/foo")
+  id=$(bupstash put --no-stat-caching :: "$SCRATCH/foo")
   test 5 = "$(bupstash get id=$id | tar -tf - | wc -l)"
 }
 
@@ -458,3 +461,1415 @@ _concurrent_modify_worker () {
     test $(bupstash list-contents  id=$id | wc -l) = 8
   done
 }
+
+@test "directory rollups" {
+  mkdir -p $SCRATCH/foo/bar/baz
+  echo foo > $SCRATCH/foo/foo.txt
+  echo bar > $SCRATCH/foo/bar/bar.txt
+  echo baz > $SCRATCH/foo/bar/baz/baz.txt
+
+  for i in `seq 2`
+  do
+    id="$(bupstash put $SCRATCH/foo)"
+    test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == ".") | .entry_count')" = 5
+    test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == ".") | .total_size')" = 12
+    test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == "bar") | .entry_count')" = 3
+    test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == "bar/baz") | .total_size')" = 4
+  done
+}
+
+@test "list-contents tree" {
+  mkdir -p $SCRATCH/foo/bar/baz
+  echo foo > $SCRATCH/foo/foo.txt
+  echo bar > $SCRATCH/foo/bar/bar.txt
+  echo baz > $SCRATCH/foo/bar/baz/baz.txt
+  id="$(bupstash put $SCRATCH/foo)"
+  test $(bupstash list-contents --tree id=$id | wc -l) = 6
+  test $(bupstash list-contents --tree --max-depth 1 id=$id | wc -l) = 3
+  test $(bupstash list-contents --max-depth 0 id=$id | wc -l) = 1
+  bupstash list-contents --tree id=$id | grep -q "bar/ (3 entries)"
+  bupstash list-contents --tree id=$id | grep -q "baz/ (1 entry)"
+}
+
+@test "get output is stable" {
+  mkdir -p $SCRATCH/foo/bar
+  echo foo > $SCRATCH/foo/foo.txt
+  echo bar > $SCRATCH/foo/bar/bar.txt
+  id="$(bupstash put $SCRATCH/foo)"
+  sum1="$(bupstash get id=$id | sha256sum)"
+  sum2="$(bupstash get id=$id | sha256sum)"
+  test "$sum1" = "$sum2"
+  sum1="$(bupstash get --pick bar id=$id | sha256sum)"
+  sum2="$(bupstash get --pick bar id=$id | sha256sum)"
+  test "$sum1" = "$sum2"
+}
+
+@test "put verify sample" {
+  mkdir $SCRATCH/foo
+  echo foo > $SCRATCH/foo/foo.txt
+  id="$(BUPSTASH_CHECKPOINT_BYTES=1 bupstash put --verify-sample 1 $SCRATCH/foo)"
+  test "$(bupstash get --pick foo.txt id=$id)" = foo
+  id="$(bupstash put --no-send-log --verify-sample 1 -e echo hello)"
+  test "$(bupstash get id=$id)" = hello
+  run bupstash put -k "$SEND_KEY" --verify-sample 1 -e echo hello
+  echo "$output" | grep -q "requires a primary key"
+  test "$status" = 1
+}
+
+@test "put max memory" {
+  mkdir $SCRATCH/foo
+  head -c 20000000 /dev/urandom > $SCRATCH/foo/rand.dat
+  id="$(bupstash put --max-memory 16M $SCRATCH/foo)"
+  cmp <(bupstash get --pick rand.dat id=$id) $SCRATCH/foo/rand.dat
+  run bupstash put --max-memory 1M $SCRATCH/foo
+  test "$status" = 1
+}
+
+@test "key info" {
+  bupstash new-put-key --label "my label" -o $SCRATCH/labeled.key
+  bupstash key-info -k $SCRATCH/labeled.key | grep -q "^type: put$"
+  bupstash key-info -k $SCRATCH/labeled.key | grep -q "^capabilities: put$"
+  bupstash key-info -k $SCRATCH/labeled.key | grep -q "^nonce-mode: derived$"
+  bupstash key-info -k $SCRATCH/labeled.key | grep -q "^label: my label$"
+  bupstash key-info -k $SCRATCH/labeled.key | grep -q "^created: "
+  bupstash key-info -k "$METADATA_KEY" | grep -q "^type: metadata$"
+  test "$(bupstash key-info --format=jsonl | jq -r .type)" = primary
+  if bupstash key-info | grep -q "BEGIN" ; then exit 1 ; fi
+  bupstash new-key --nonce-mode incrementing -o $SCRATCH/incrementing.key
+  bupstash new-put-key -k $SCRATCH/incrementing.key -o $SCRATCH/incrementing-put.key
+  bupstash key-info -k $SCRATCH/incrementing-put.key | grep -q "^nonce-mode: incrementing$"
+  id="$(bupstash put -k $SCRATCH/incrementing-put.key -e :: echo hello)"
+  test "$(bupstash get -k $SCRATCH/incrementing.key id=$id)" = hello
+  run bupstash new-key --nonce-mode random -o $SCRATCH/random.key
+  test "$status" = 1
+}
+
+@test "inspect item metadata" {
+  id="$(bupstash put -e --no-send-log foo=bar :: echo hello)"
+  test "$(bupstash inspect id=$id | jq -r .id)" = "$id"
+  test "$(bupstash inspect foo=bar | jq -r .decrypted_metadata.tags.foo)" = bar
+  test "$(bupstash inspect -k $METADATA_KEY id=$id | jq -r .decrypted_metadata.tags.foo)" = bar
+  test "$(bupstash inspect id=$id | jq -r .plain_text_metadata.index_tree)" = null
+  test "$(bupstash inspect id=$id | jq -r .plain_text_metadata.data_tree.height)" = 0
+}
+
+@test "debug dump htree" {
+  mkdir $SCRATCH/foo
+  head -c 3000000 /dev/urandom > $SCRATCH/foo/rand.dat
+  id="$(bupstash put $SCRATCH/foo)"
+  bupstash debug dump-htree id=$id > $SCRATCH/htree.txt
+  grep -q "^data-tree:$" $SCRATCH/htree.txt
+  grep -q "^index-tree:$" $SCRATCH/htree.txt
+  grep -q "^height=1 address=[0-9a-f]* children=" $SCRATCH/htree.txt
+  addr="$(sed -n 2p $SCRATCH/htree.txt | sed 's/.*address=\([0-9a-f]*\).*/\1/')"
+  rm "$REPO/data/$addr"
+  run bupstash debug dump-htree id=$id
+  test "$status" = 1
+  echo "$output" | grep -q "error="
+}
+
+@test "put files from" {
+  mkdir -p $SCRATCH/foo/a/b $SCRATCH/foo/c
+  echo 1 > $SCRATCH/foo/a/b/f1.txt
+  echo 2 > $SCRATCH/foo/a/f2.txt
+  echo 3 > $SCRATCH/foo/c/f3.txt
+  id="$(printf 'a/b/f1.txt\nc' | bupstash put --files-from - $SCRATCH/foo)"
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r .path | tr '\n' ' ')" = ". a a/b a/b/f1.txt c "
+  test "$(bupstash get --pick a/b/f1.txt id=$id)" = 1
+  id="$(cd $SCRATCH/foo && find . -name 'f*.txt' -print0 | bupstash put --files-from - $SCRATCH/foo)"
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r .path | grep -c txt)" = 3
+  run bupstash put --files-from - $SCRATCH/foo <<< "../escape"
+  test "$status" = 1
+  run bupstash put --files-from - $SCRATCH/foo <<< "missing.txt"
+  test "$status" = 1
+}
+
+@test "put changed since" {
+  mkdir -p $SCRATCH/foo/a
+  echo old > $SCRATCH/foo/a/old.txt
+  id1="$(bupstash put $SCRATCH/foo)"
+  sleep 1.1
+  test "$(bupstash inspect id=$id1 | jq -r .decrypted_metadata.start_timestamp)" != null
+  echo new > $SCRATCH/foo/a/new.txt
+  id2="$(bupstash put --changed-since $id1 $SCRATCH/foo)"
+  test "$(bupstash get --pick a/new.txt id=$id2)" = new
+  run bupstash get --pick a/old.txt id=$id2
+  test "$status" = 1
+  bupstash list-contents id=$id2 | grep -q "a/old.txt (unchanged)"
+  test "$(bupstash list-contents --format=jsonl id=$id2 | jq -r 'select(.unchanged) | .path')" = a/old.txt
+  test "$(bupstash get id=$id2 | tar -tf - | grep -c txt)" = 1
+  id3="$(bupstash put --changed-since 2000-01-01T00:00:00Z $SCRATCH/foo)"
+  test "$(bupstash get id=$id3 | tar -tf - | grep -c txt)" = 2
+  run bupstash put -k "$SEND_KEY" --changed-since $id1 $SCRATCH/foo
+  test "$status" = 1
+}
+
+@test "put note" {
+  id="$(bupstash put --note "pre-upgrade snapshot" -e foo=bar :: echo hello)"
+  test "$(bupstash list --format=jsonl id=$id | jq -r .note)" = "pre-upgrade snapshot"
+  test "$(bupstash list -k $METADATA_KEY --format=jsonl 'note=pre-upgrade*' | jq -r .id)" = "$id"
+  test "$(bupstash inspect id=$id | jq -r .decrypted_metadata.note)" = "pre-upgrade snapshot"
+  test "$(bupstash get id=$id)" = hello
+  id="$(bupstash put -e echo hello)"
+  test "$(bupstash inspect id=$id | jq -r .decrypted_metadata.note)" = null
+  test "$(bupstash inspect id=$id | jq -r .version)" = 2
+}
+
+@test "repo stats lock holders" {
+  bupstash repo-stats | grep -q "^lock: unlocked$"
+  (sleep 2; echo hello) | bupstash put - &
+  sleep 1
+  test "$(bupstash repo-stats --format=jsonl | jq -r '.lock_holders[0].operation')" = put
+  wait
+  test "$(bupstash repo-stats --format=jsonl | jq -r .items)" = 1
+  bupstash repo-stats | grep -q "^lock: unlocked$"
+  run env -u BUPSTASH_REPOSITORY \
+    BUPSTASH_REPOSITORY_COMMAND="bupstash serve --allow-put $BUPSTASH_REPOSITORY" \
+    bupstash repo-stats
+  test "$status" != 0
+}
+
+@test "lock wait is reported" {
+  flock -x "$REPO/repo.lock" sleep 2 &
+  sleep 0.5
+  bupstash put -e :: echo hello 2>&1 | grep -q "waiting for repository lock"
+  wait
+}
+
+@test "get from mirror" {
+  mkdir -p "$SCRATCH/foo/bar"
+  echo -n abc > "$SCRATCH/foo/a.txt"
+  echo -n def > "$SCRATCH/foo/bar/b.txt"
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  cp -r "$REPO" "$SCRATCH/mirror"
+  # A partial and corrupt mirror must still produce the correct data.
+  for f in $(ls "$SCRATCH/mirror/data" | head -n 2)
+  do
+    rm "$SCRATCH/mirror/data/$f"
+  done
+  f="$(ls "$SCRATCH/mirror/data" | head -n 1)"
+  echo corrupt > "$SCRATCH/mirror/data/$f"
+  test "$(bupstash get --mirror "$SCRATCH/mirror" id=$id | sha256sum)" = "$(bupstash get id=$id | sha256sum)"
+  test "$(bupstash get --mirror "$SCRATCH/mirror" --pick bar/b.txt id=$id)" = def
+  rm -rf "$SCRATCH/mirror"
+  # With an empty mirror everything comes from the repository.
+  mkdir -p "$SCRATCH/mirror/data"
+  test "$(bupstash get --mirror "$SCRATCH/mirror" --pick a.txt id=$id)" = abc
+}
+
+@test "honor nodump" {
+  mkdir -p "$SCRATCH/foo/sub"
+  echo a > "$SCRATCH/foo/a.txt"
+  echo b > "$SCRATCH/foo/b.txt"
+  echo c > "$SCRATCH/foo/sub/c.txt"
+  if ! chattr +d "$SCRATCH/foo/b.txt" "$SCRATCH/foo/sub"
+  then
+    skip "filesystem does not support the nodump attribute"
+  fi
+  id="$(bupstash put --honor-nodump :: "$SCRATCH/foo")"
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r .path | sort | tr '\n' ' ')" = ". a.txt "
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  test "$(bupstash list-contents --format=jsonl id=$id | wc -l)" = 5
+  chattr -d "$SCRATCH/foo/b.txt" "$SCRATCH/foo/sub"
+}
+
+@test "selinux contexts" {
+  mkdir "$SCRATCH/foo"
+  echo a > "$SCRATCH/foo/a.txt"
+  if ! setfattr -n security.selinux -v "system_u:object_r:etc_t:s0" "$SCRATCH/foo/a.txt"
+  then
+    skip "unable to set selinux contexts"
+  fi
+  id="$(bupstash put --selinux :: "$SCRATCH/foo")"
+  bupstash get id=$id | grep -a -q "RHT.security.selinux=system_u:object_r:etc_t:s0"
+  test "$(bupstash get --pick a.txt id=$id)" = a
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  ! bupstash get id=$id | grep -a -q "RHT.security.selinux"
+}
+
+@test "one file system" {
+  mkdir -p "$SCRATCH/foo/mnt"
+  echo a > "$SCRATCH/foo/a.txt"
+  if ! mount -t tmpfs none "$SCRATCH/foo/mnt"
+  then
+    skip "unable to mount a tmpfs"
+  fi
+  echo b > "$SCRATCH/foo/mnt/b.txt"
+  id="$(bupstash put --one-file-system :: "$SCRATCH/foo")"
+  bupstash list-contents --format=jsonl id=$id > "$SCRATCH/contents.jsonl"
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  umount "$SCRATCH/foo/mnt"
+  test "$(jq -r .path < "$SCRATCH/contents.jsonl" | sort | tr '\n' ' ')" = ". a.txt mnt "
+  test "$(bupstash list-contents --format=jsonl id=$id | wc -l)" = 4
+}
+
+@test "exclude vcs and build caches" {
+  mkdir -p "$SCRATCH/foo/.git/objects" "$SCRATCH/foo/sub/node_modules/pkg" "$SCRATCH/foo/src"
+  touch "$SCRATCH/foo/.git/objects/obj" "$SCRATCH/foo/sub/node_modules/pkg/index.js" \
+    "$SCRATCH/foo/src/main.c" "$SCRATCH/foo/.gitignore"
+  id="$(bupstash put --exclude-vcs --exclude-build-caches :: "$SCRATCH/foo")"
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r .path | sort | tr '\n' ' ')" = ". .gitignore src src/main.c sub "
+  id="$(bupstash put --exclude-vcs :: "$SCRATCH/foo")"
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r .path | grep -c node_modules)" = 3
+}
+
+@test "require unique" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  id="$(bupstash put --require-unique run=1 "$SCRATCH/foo.txt")"
+  test "$(bupstash list --format=jsonl run=1 | jq -r .id)" = "$id"
+  run bupstash put --require-unique run=1 "$SCRATCH/foo.txt"
+  test "$status" != 0
+  bupstash put --require-unique run=2 "$SCRATCH/foo.txt"
+  test "$(bupstash list | wc -l)" = 2
+  bupstash rm id=$id
+  bupstash put --require-unique run=1 "$SCRATCH/foo.txt"
+  test "$(bupstash list run=1 | wc -l)" = 1
+}
+
+@test "replace items" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  plain="$(bupstash put slot=cur "$SCRATCH/foo.txt")"
+  bupstash put --replace slot=cur "$SCRATCH/foo.txt"
+  id="$(bupstash put --replace slot=cur "$SCRATCH/foo.txt")"
+  test "$(bupstash list --format=jsonl slot=cur | jq -r .id | sort | tr '\n' ' ')" = "$(echo -e "$id\n$plain" | sort | tr '\n' ' ')"
+  run bupstash put --require-unique slot=cur "$SCRATCH/foo.txt"
+  test "$status" != 0
+  run env -u BUPSTASH_REPOSITORY \
+    BUPSTASH_REPOSITORY_COMMAND="bupstash serve --allow-put $BUPSTASH_REPOSITORY" \
+    bupstash put --replace slot=cur "$SCRATCH/foo.txt"
+  test "$status" != 0
+  test "$(bupstash list | wc -l)" = 2
+}
+
+@test "expiring items" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  bupstash put --expires 0s "$SCRATCH/foo.txt"
+  id="$(bupstash put --expires 90d "$SCRATCH/foo.txt")"
+  test "$(bupstash list expires='*' | wc -l)" = 2
+  bupstash gc
+  test "$(bupstash list --format=jsonl | jq -r .id)" = "$id"
+}
+
+@test "serve resource limits" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  repo="$BUPSTASH_REPOSITORY"
+  unset BUPSTASH_REPOSITORY
+  export BUPSTASH_REPOSITORY_COMMAND="bupstash serve --max-connections 1 --idle-timeout 10s $repo"
+  bupstash put "$SCRATCH/foo.txt"
+  run flock "$repo/connections/0" bupstash list
+  test "$status" != 0
+  echo "$output" | grep -q "maximum of 1 connections"
+  test "$(bupstash list | wc -l)" = 1
+  sleep 3 | bupstash serve --idle-timeout 1s "$repo" | grep -a -q "idle"
+  run bupstash serve --max-packet-size 1MiB "$repo"
+  test "$status" != 0
+}
+
+@test "item log rollback" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  bupstash put "$SCRATCH/foo.txt"
+  cp "$BUPSTASH_REPOSITORY/bupstash.sqlite3" "$SCRATCH/old.sqlite3"
+  bupstash put "$SCRATCH/foo.txt"
+  test "$(bupstash list | wc -l)" = 2
+  cp "$SCRATCH/old.sqlite3" "$BUPSTASH_REPOSITORY/bupstash.sqlite3"
+  run bupstash list
+  test "$status" != 0
+  echo "$output" | grep -q "rolled back"
+  rm "$BUPSTASH_QUERY_CACHE"
+  test "$(bupstash list | wc -l)" = 1
+}
+
+@test "default send log per repository" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  bupstash init --repository="$SCRATCH/repo2"
+  export XDG_CACHE_HOME="$SCRATCH/cache"
+  unset BUPSTASH_SEND_LOG
+  bupstash put "$SCRATCH/foo.txt"
+  bupstash put --repository="$SCRATCH/repo2" "$SCRATCH/foo.txt"
+  test "$(ls "$SCRATCH/cache/bupstash/send-logs" | grep -c '\.sendlog$')" = 2
+}
+
+@test "cached content index" {
+  mkdir "$SCRATCH/foo"
+  echo -n abc > "$SCRATCH/foo/a.txt"
+  id="$(bupstash put "$SCRATCH/foo")"
+  test "$(bupstash list-contents id=$id | wc -l)" = 2
+  test "$(BUPSTASH_REPOSITORY="$SCRATCH/missing" bupstash list-contents id=$id | wc -l)" = 2
+  test "$(bupstash get --pick a.txt id=$id)" = abc
+  bupstash rm id=$id
+  bupstash list
+  run env BUPSTASH_REPOSITORY="$SCRATCH/missing" bupstash list-contents id=$id
+  test "$status" != 0
+}
+
+@test "restore into directory" {
+  mkdir -p "$SCRATCH/foo/bar" "$SCRATCH/restore"
+  echo -n abc > "$SCRATCH/foo/bar/a.txt"
+  id="$(bupstash put "$SCRATCH/foo")"
+  bupstash get --restore-into "$SCRATCH/restore" --pick bar id=$id
+  test "$(cat "$SCRATCH/restore/bar/a.txt")" = abc
+  run bupstash get --restore-into "$SCRATCH/restore" --pick bar/a.txt id=$id
+  test "$status" != 0
+  run bupstash get --restore-into "$SCRATCH/missing" id=$id
+  test "$status" != 0
+}
+
+@test "put print stats" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  bupstash put --no-send-log --print-stats "$SCRATCH/foo.txt" 2> "$SCRATCH/stats1" > /dev/null
+  grep -q "^1 chunks added" "$SCRATCH/stats1"
+  bupstash put --no-send-log --print-stats "$SCRATCH/foo.txt" 2> "$SCRATCH/stats2" > /dev/null
+  grep -q "^0 chunks added" "$SCRATCH/stats2"
+  grep -q "^1 chunks already present" "$SCRATCH/stats2"
+  grep -q "^2 items in repository" "$SCRATCH/stats2"
+  grep -q "^3 bytes read" "$SCRATCH/stats2"
+  grep -q "seconds elapsed" "$SCRATCH/stats2"
+}
+
+@test "put stats send log" {
+  head -c 1000000 /dev/urandom > "$SCRATCH/rand.dat"
+  bupstash put --print-stats "$SCRATCH/rand.dat" 2> "$SCRATCH/stats1" > /dev/null
+  grep -q "^0 chunks skipped by the send log" "$SCRATCH/stats1"
+  bupstash put --print-stats "$SCRATCH/rand.dat" 2> "$SCRATCH/stats2" > /dev/null
+  test "$(grep "chunks skipped by the send log" "$SCRATCH/stats2" | cut -d ' ' -f 1)" -gt 0
+  grep -q "^1000000 bytes read" "$SCRATCH/stats2"
+  id="$(bupstash put --progress json "$SCRATCH/rand.dat" 2> "$SCRATCH/events")"
+  grep '"event":"done"' "$SCRATCH/events" | grep -q '"elapsed_seconds"'
+}
+
+@test "list sort and paging" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  for i in $(seq 5)
+  do
+    bupstash put n=$i "$SCRATCH/foo.txt"
+  done
+  test "$(bupstash list --format=jsonl --limit 2 --offset 1 | jq -r .n | tr -d '\n')" = 23
+  test "$(bupstash list --format=jsonl --reverse --limit 2 | jq -r .n | tr -d '\n')" = 54
+  test "$(bupstash list --sort id | wc -l)" = 5
+  test "$(bupstash list --format=jsonl --sort id | jq -r .id)" = "$(bupstash list --format=jsonl | jq -r .id | sort)"
+  run bupstash list --sort name
+  test "$status" != 0
+}
+
+@test "list format template" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  id="$(bupstash put a=b "$SCRATCH/foo.txt")"
+  test "$(bupstash list --format '{id}|{tag:a}|{tag:missing}|{{}}')" = "$id|b||{}"
+  test "$(bupstash list --format '{tag:name}\t{tag:a}')" = "$(printf 'foo.txt\tb')"
+  run bupstash list --format '{a}'
+  test "$status" != 0
+}
+
+@test "item size queries" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  mkdir "$SCRATCH/d"
+  head -c 100000 /dev/urandom > "$SCRATCH/d/big"
+  touch "$SCRATCH/d/empty"
+  id1="$(bupstash put "$SCRATCH/foo.txt")"
+  id2="$(bupstash put "$SCRATCH/d")"
+  test "$(bupstash list --format '{size}' id=$id1)" = "3"
+  test "$(bupstash list --format '{entries}' id=$id2)" = "3"
+  test "$(bupstash list --format "{id}" "size>10KiB")" = "$id2"
+  test "$(bupstash list --format "{id}" size "<=" 3)" = "$id1"
+  test "$(bupstash list --format "{id}" "entries>=3" and "size < 1MB")" = "$id2"
+  run bupstash list "size>10XB"
+  test "$status" != 0
+}
+
+@test "tag schema" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  printf 'required host\nmatch host srv*\n' > "$REPO/tag-schema"
+  run bupstash put "$SCRATCH/foo.txt"
+  test "$status" != 0
+  run bupstash put host=db "$SCRATCH/foo.txt"
+  test "$status" != 0
+  bupstash put host=srv1 "$SCRATCH/foo.txt"
+  rm "$REPO/tag-schema"
+  printf 'allowed host\n' > "$SCRATCH/schema"
+  run bupstash put --tag-schema "$SCRATCH/schema" host=x "$SCRATCH/foo.txt"
+  test "$status" != 0
+  bupstash put --tag-schema "$SCRATCH/schema" --no-default-tags host=x "$SCRATCH/foo.txt"
+  test "$(bupstash list | wc -l)" = 2
+}
+
+@test "put auto tags" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  id="$(bupstash put --auto-tags user=someone "$SCRATCH/foo.txt")"
+  test "$(bupstash list --format '{tag:hostname}' id=$id)" = "$(hostname)"
+  test "$(bupstash list --format '{tag:user}' id=$id)" = "someone"
+  test "$(bupstash list --format '{tag:source-path}' id=$id)" = "$(realpath "$SCRATCH/foo.txt")"
+  bupstash list --format '{tag:put-timestamp}' id=$id | grep -q '^[0-9-]*T[0-9:]*Z$'
+  id="$(echo abc | bupstash put --auto-tags -)"
+  test "$(bupstash list --format '{tag:source-path}' id=$id)" = ""
+  id="$(bupstash put --timestamp-tag --source-path-tag "$SCRATCH")"
+  test "$(bupstash list --format '{tag:source-path}' id=$id)" = "$(realpath "$SCRATCH")"
+  bupstash list --format '{tag:put-timestamp}' id=$id | grep -q '^[0-9-]*T[0-9:]*Z$'
+  test "$(bupstash list --format '{tag:hostname}' id=$id)" = ""
+  id="$(BUPSTASH_AUTO_TAGS=1 bupstash put "$SCRATCH/foo.txt")"
+  test "$(bupstash list --format '{tag:hostname}' id=$id)" = "$(hostname)"
+  id="$(BUPSTASH_AUTO_TAGS=0 bupstash put "$SCRATCH/foo.txt")"
+  test "$(bupstash list --format '{tag:hostname}' id=$id)" = ""
+}
+
+@test "put exec status" {
+  run bupstash put -e :: sh -c 'echo data; exit 1'
+  test "$status" != 0
+  id="$(bupstash put -e --exec-ok-status 1 :: sh -c 'echo data; echo warning >&2; exit 1' 2> "$SCRATCH/stderr")"
+  grep -q warning "$SCRATCH/stderr"
+  test "$(bupstash get id=$id)" = "data"
+  test "$(bupstash list --format '{tag:exec-status}' id=$id)" = "1"
+  test "$(bupstash list --format '{tag:exec-command}' id=$id)" = ""
+  id="$(bupstash put -e --exec-command-tag :: sh -c 'echo data')"
+  test "$(bupstash list --format '{tag:exec-command}' id=$id)" = "sh -c \"echo data\""
+  # The exit status is checked against the tag schema once the command exits.
+  echo "match exec-status 0" > "$SCRATCH/schema"
+  run bupstash put -e --tag-schema "$SCRATCH/schema" --exec-ok-status 1 :: sh -c 'echo data; exit 1'
+  test "$status" = 1
+  echo "required exec-status" > "$SCRATCH/schema"
+  bupstash put -e --tag-schema "$SCRATCH/schema" :: echo data
+}
+
+@test "put exec streams" {
+  id="$(bupstash put --exec-stream schema='echo create' --exec-stream data='seq 3' db=prod)"
+  test "$(bupstash get --pick schema id=$id)" = "create"
+  test "$(bupstash get --pick data id=$id)" = "$(seq 3)"
+  test "$(bupstash list-contents id=$id | wc -l)" = 3
+  run bupstash put --exec-stream a='echo a' --exec-stream b=false
+  test "$status" != 0
+  run bupstash put --exec-stream a/b=true
+  test "$status" != 0
+  test "$(bupstash list | wc -l)" = 1
+  mkdir "$SCRATCH/spool"
+  id="$(bupstash put --exec-stream-dir "$SCRATCH/spool" --exec-stream a='ls "$SCRATCH/spool"')"
+  bupstash get --pick a id=$id | grep -q "^bupstash-exec-streams-"
+  test "$(ls "$SCRATCH/spool" | wc -l)" = 0
+  run bupstash put --exec-stream-dir "$SCRATCH/missing" --exec-stream a='echo a'
+  test "$status" = 1
+}
+
+@test "get split size" {
+  head -c 2500000 /dev/urandom > "$SCRATCH/rand.dat"
+  id="$(bupstash put "$SCRATCH/rand.dat")"
+  mkdir "$SCRATCH/vol"
+  bupstash get --split-size 1M --output-prefix "$SCRATCH/vol/rand.dat." id=$id
+  test "$(ls "$SCRATCH/vol" | wc -l)" = 3
+  test "$(stat -c %s "$SCRATCH/vol/rand.dat.000")" = 1048576
+  cat "$SCRATCH"/vol/rand.dat.* | cmp - "$SCRATCH/rand.dat"
+  run bupstash get --split-size 1M --output-prefix "$SCRATCH/vol/rand.dat." id=$id
+  test "$status" != 0
+  run bupstash get --split-size 1M id=$id
+  test "$status" != 0
+}
+
+@test "put index delta" {
+  mkdir -p "$SCRATCH/foo/sub"
+  for i in $(seq 100); do echo $i > "$SCRATCH/foo/sub/$i.txt"; done
+  id1="$(bupstash put --no-send-log "$SCRATCH/foo")"
+  echo changed > "$SCRATCH/foo/sub/50.txt"
+  rm "$SCRATCH/foo/sub/7.txt"
+  echo new > "$SCRATCH/foo/new.txt"
+  id2="$(bupstash put --no-send-log --index-delta-from $id1 "$SCRATCH/foo")"
+  echo more > "$SCRATCH/foo/sub/60.txt"
+  id3="$(bupstash put --no-send-log --index-delta-from id=$id2 "$SCRATCH/foo")"
+  test "$(bupstash list-contents id=$id3 | wc -l)" = 102
+  test "$(bupstash list-contents id=$id3 | grep -c 'sub/7.txt')" = 0
+  test "$(bupstash get --pick sub/50.txt id=$id3)" = changed
+  test "$(bupstash get --pick sub/60.txt id=$id3)" = more
+  test "$(bupstash get --pick new.txt id=$id3)" = new
+  test "$(bupstash get --pick sub/99.txt id=$id3)" = 99
+  bupstash rm id=$id1
+  bupstash rm id=$id2
+  bupstash gc
+  test "$(bupstash get --pick sub/50.txt id=$id3)" = changed
+  run bupstash put --index-delta-from $id3 "$SCRATCH/foo/new.txt"
+  test "$status" = 1
+}
+
+@test "shared chunks" {
+  mkdir "$SCRATCH/foo"
+  head -c 100000 /dev/urandom > "$SCRATCH/foo/a.dat"
+  id1="$(bupstash put "$SCRATCH/foo")"
+  head -c 100000 /dev/urandom > "$SCRATCH/foo/b.dat"
+  id2="$(bupstash put "$SCRATCH/foo")"
+  test "$(bupstash shared --format=jsonl $id1 id=$id2 | jq -r .shared_chunks)" -gt 0
+  test "$(bupstash shared --format=jsonl $id1 $id2 | jq -r .b_unique_bytes)" -gt 100000
+  test "$(bupstash shared --format=jsonl $id1 $id1 | jq -r .a_unique_chunks)" = 0
+  bupstash shared $id1 $id2 | grep -q "^shared: "
+  run bupstash shared $id1
+  test "$status" = 1
+}
+
+@test "gc pacing" {
+  id1="$(bupstash put -e :: echo hello1)"
+  id2="$(bupstash put -e :: echo hello2)"
+  bupstash rm id=$id1
+  bupstash gc --max-io-rate 100 --pace 1ms
+  test 1 = "$(ls "$REPO"/data | wc -l)"
+  test "$(bupstash get id=$id2)" = hello2
+  run bupstash gc --max-io-rate 0
+  test "$status" = 1
+  run bupstash gc --pace fast
+  test "$status" = 1
+}
+
+@test "gc sweep workers" {
+  for i in $(seq 10); do bupstash put -e :: echo $i > /dev/null; done
+  id="$(bupstash put -e :: echo keep)"
+  bupstash rm --allow-many "~id=$id"
+  bupstash gc --sweep-workers 3
+  test 1 = "$(ls "$REPO"/data | wc -l)"
+  test "$(bupstash get id=$id)" = keep
+  run bupstash gc --sweep-workers 0
+  test "$status" = 1
+}
+
+@test "sync snapshot" {
+  for i in $(seq 20); do bupstash put -e :: echo $i > /dev/null; done
+  id="$(bupstash put -e :: echo keep)"
+  bupstash rm --allow-many "~id=$id"
+  rm "$BUPSTASH_QUERY_CACHE"
+  test 1 = "$(bupstash list | wc -l)"
+  # The snapshot only holds current items and the last log op.
+  test 2 = "$(sqlite3 "$BUPSTASH_QUERY_CACHE" 'select count(*) from ItemOpLog;')"
+  bupstash restore-removed
+  test 21 = "$(bupstash list | wc -l)"
+  test "$(bupstash get id=$id)" = keep
+}
+
+@test "query cache per repository" {
+  unset BUPSTASH_QUERY_CACHE
+  export XDG_CACHE_HOME="$SCRATCH/cache"
+  bupstash init -r "$SCRATCH/repo2"
+  bupstash put -e :: echo hello1
+  bupstash put -r "$SCRATCH/repo2" -e :: echo hello2
+  test 1 = "$(bupstash list | wc -l)"
+  test 1 = "$(bupstash list -r "$SCRATCH/repo2" | wc -l)"
+  # Another path to the same repository uses the same cache.
+  test 1 = "$(bupstash list -r "$SCRATCH/./repo2" | wc -l)"
+  test 2 = "$(ls "$SCRATCH"/cache/bupstash/bupstash-*.qcache | wc -l)"
+}
+
+@test "timeline" {
+  bupstash put -e name=a :: echo a1
+  bupstash put -e name=a :: echo a2
+  bupstash put -e name=b :: echo b1
+  test 2 = "$(bupstash timeline | grep -c '^hostname=')"
+  test 3 = "$(bupstash timeline --group-by name | grep -c '^  [0-9]')"
+  test 2 = "$(bupstash timeline --group-by name name=b | wc -l)"
+  bupstash timeline --gap 1s
+  test 2 = "$(bupstash timeline --format=jsonl | wc -l)"
+  run bupstash timeline --gap nope
+  test "$status" = 1
+}
+
+@test "pick from large directory" {
+  for d in a b c d
+  do
+    mkdir -p $SCRATCH/foo/$d
+    for i in `seq 50`
+    do
+      head -c $((i * 4096)) /dev/urandom > $SCRATCH/foo/$d/$i
+    done
+  done
+  id="$(bupstash put $SCRATCH/foo)"
+  for d in a b c d
+  do
+    mkdir $SCRATCH/restore-$d
+    bupstash get --pick $d id=$id | tar -C $SCRATCH/restore-$d -xf -
+    diff -r $SCRATCH/foo/$d $SCRATCH/restore-$d/$d
+  done
+  bupstash get --pick a/50 id=$id | cmp - $SCRATCH/foo/a/50
+}
+
+@test "clone item" {
+  id="$(bupstash put -e name=nightly :: echo hello)"
+  data_chunks="$(ls "$REPO"/data | wc -l)"
+  id2="$(bupstash clone --tag name=monthly --tag keep=yes id=$id)"
+  test "$data_chunks" = "$(ls "$REPO"/data | wc -l)"
+  test 2 = "$(bupstash list | wc -l)"
+  test "$(bupstash list --format='{tag:keep}' name=monthly)" = yes
+  test "$(bupstash list --format='{timestamp}' id=$id)" = "$(bupstash list --format='{timestamp}' id=$id2)"
+  bupstash rm id=$id
+  bupstash gc
+  test "$(bupstash get id=$id2)" = hello
+  id3="$(bupstash clone --clear-tags --note promoted name=monthly)"
+  test "$(bupstash list --format='{tag:name}{note}' id=$id3)" = promoted
+  run bupstash clone --tag nope id=$id2
+  test "$status" = 1
+  printf 'required name\nmatch name monthly\n' > "$REPO/tag-schema"
+  run bupstash clone --tag name=yearly id=$id2
+  test "$status" = 1
+  echo "$output" | grep -q "refusing to clone item: repository tag schema"
+  run bupstash clone --clear-tags id=$id2
+  test "$status" = 1
+  bupstash clone id=$id2
+  rm "$REPO/tag-schema"
+}
+
+@test "restore send log" {
+  mkdir $SCRATCH/foo
+  for i in $(seq 20)
+  do
+    head -c $((i * 8192)) /dev/urandom > $SCRATCH/foo/$i
+  done
+  id="$(bupstash put --send-log "$SCRATCH/sendlog1" $SCRATCH/foo)"
+  bupstash restore-send-log --send-log "$SCRATCH/sendlog2" id=$id
+  bupstash put --send-log "$SCRATCH/sendlog2" --print-stats $SCRATCH/foo 2> "$SCRATCH/stats" > /dev/null
+  grep -q "^0 chunks sent" "$SCRATCH/stats"
+  # Seeded addresses are not trusted after a gc.
+  bupstash restore-send-log --send-log "$SCRATCH/sendlog3" id=$id
+  bupstash gc
+  bupstash put --send-log "$SCRATCH/sendlog3" --print-stats $SCRATCH/foo 2> "$SCRATCH/stats" > /dev/null
+  test "$(grep "chunks sent" "$SCRATCH/stats")" != "0 chunks sent"
+  run bupstash restore-send-log --send-log "$SCRATCH/sendlog4" id=ffffffffffffffffffffffffffffffff
+  test "$status" = 1
+}
+
+@test "get many items" {
+  mkdir -p $SCRATCH/foo/sub $SCRATCH/bar
+  echo foo > $SCRATCH/foo/sub/a.txt
+  echo bar > $SCRATCH/bar/b.txt
+  id1="$(bupstash put host=web01 $SCRATCH/foo)"
+  id2="$(bupstash put host=web01 $SCRATCH/bar)"
+  bupstash put host=web02 $SCRATCH/bar
+  run bupstash get host=web01
+  test "$status" = 1
+  mkdir $SCRATCH/restore
+  bupstash get --allow-many --restore-into $SCRATCH/restore host=web01
+  test "$(ls $SCRATCH/restore | wc -l)" = 2
+  diff -r $SCRATCH/foo $SCRATCH/restore/$id1
+  diff -r $SCRATCH/bar $SCRATCH/restore/$id2
+  test "$(bupstash get --allow-many host=web01 | tar -tf - | grep -c /)" = 3
+  bupstash put -e host=web01 :: echo hello
+  run bupstash get --allow-many host=web01
+  test "$status" = 1
+}
+
+@test "list aggregates" {
+  bupstash put -e host=a :: echo -n abc
+  bupstash put -e host=a :: echo -n de
+  bupstash put -e host=b :: echo -n f
+  bupstash put -e :: echo -n g
+  test "$(bupstash list --count)" = "count=4"
+  test "$(bupstash list --count --sum-size host=a)" = "count=2 size=5"
+  test "$(bupstash list --count host=nope)" = "count=0"
+  test "$(bupstash list --group-by host | wc -l)" = 3
+  test "$(bupstash list --group-by host host=b)" = 'host="b" count=1'
+  test "$(bupstash list --group-by host --sum-size --format=jsonl host=a | jq -r .size)" = 5
+  test "$(bupstash list --group-by host --format=jsonl host=a | jq -r .group.host)" = a
+  test "$(bupstash list --sum-size --format=jsonl | jq -r .size)" = 7
+  run bupstash list --count --format '{id}'
+  test "$status" = 1
+}
+
+@test "completions" {
+  bupstash completions bash > "$SCRATCH/bupstash.bash"
+  bash -n "$SCRATCH/bupstash.bash"
+  grep -q -- "--allow-many" "$SCRATCH/bupstash.bash"
+  bupstash completions zsh > "$SCRATCH/_bupstash"
+  grep -q "restore-send-log" "$SCRATCH/_bupstash"
+  bupstash completions fish > "$SCRATCH/bupstash.fish"
+  grep -q "__fish_seen_subcommand_from get' -l 'pick'" "$SCRATCH/bupstash.fish"
+  bupstash put -e hostname=web01 :: echo hello
+  bupstash list > /dev/null
+  test "$(bupstash completions --tags | grep -c "^hostname=$")" = 1
+  test "$(env -u BUPSTASH_KEY bupstash completions --tags)" = ""
+  run bupstash completions csh
+  test "$status" = 1
+}
+
+@test "put verify reads" {
+  mkdir $SCRATCH/foo
+  for i in $(seq 10)
+  do
+    head -c $((i * 10000)) /dev/urandom > $SCRATCH/foo/$i
+  done
+  for mode in "" "=stat" "=blocks"
+  do
+    id="$(bupstash put --verify-reads$mode $SCRATCH/foo)"
+    mkdir $SCRATCH/restore$mode
+    bupstash get id=$id | tar -C $SCRATCH/restore$mode -xf -
+    diff -r $SCRATCH/foo $SCRATCH/restore$mode
+  done
+  run bupstash put --verify-reads=bad $SCRATCH/foo
+  test "$status" = 1
+}
+
+@test "serve sandbox" {
+  echo -n abc > "$SCRATCH/foo.txt"
+  repo="$BUPSTASH_REPOSITORY"
+  unset BUPSTASH_REPOSITORY
+  export BUPSTASH_REPOSITORY_COMMAND="bupstash serve --sandbox $repo"
+  run bupstash put "$SCRATCH/foo.txt"
+  if echo "$output" | grep -q "unable to create namespaces"
+  then
+    skip "namespaces are not available"
+  fi
+  test "$status" = 0
+  id="$output"
+  test "$(bupstash get id=$id)" = "abc"
+  bupstash rm id=$id
+  bupstash gc
+  test "$(bupstash list | wc -l)" = 0
+  export BUPSTASH_REPOSITORY_COMMAND="bupstash serve --sandbox $SCRATCH/missing"
+  run bupstash init
+  test "$status" != 0
+  echo "$output" | grep -q "requires an existing repository"
+}
+
+@test "get sandbox" {
+  mkdir -p "$SCRATCH/d/sub" "$SCRATCH/restore" "$SCRATCH/restore-pick"
+  echo -n abc > "$SCRATCH/d/a.txt"
+  echo -n def > "$SCRATCH/d/sub/b.txt"
+  ln -s a.txt "$SCRATCH/d/link"
+  id="$(bupstash put "$SCRATCH/d")"
+  run bupstash get --sandbox --restore-into "$SCRATCH/restore" id=$id
+  if echo "$output" | grep -q "unable to create namespaces"
+  then
+    skip "namespaces are not available"
+  fi
+  test "$status" = 0
+  diff -r --no-dereference "$SCRATCH/d" "$SCRATCH/restore"
+  bupstash get --sandbox --pick sub --restore-into "$SCRATCH/restore-pick" id=$id
+  diff -r "$SCRATCH/d/sub" "$SCRATCH/restore-pick/sub"
+  test "$(bupstash get --sandbox --pick sub/b.txt id=$id)" = "def"
+  test "$(bupstash get --sandbox id=$id | tar -tf - | wc -l)" = 5
+  run bupstash get --sandbox --allow-many id=$id
+  test "$status" = 1
+  run bupstash get --sandbox --restore-into "$SCRATCH/missing" id=$id
+  test "$status" = 1
+}
+
+@test "gc during get" {
+  head -c 20000000 /dev/urandom > "$SCRATCH/rand.dat"
+  id="$(bupstash put "$SCRATCH/rand.dat")"
+  # The slow reader keeps the get in progress while the item is removed and collected.
+  bupstash get id=$id | (sleep 2; cat > "$SCRATCH/out.dat") &
+  sleep 0.5
+  bupstash rm id=$id
+  bupstash gc
+  test "$(bupstash list | wc -l)" = 0
+  wait
+  cmp "$SCRATCH/rand.dat" "$SCRATCH/out.dat"
+  bupstash gc
+  test "$(ls "$REPO/data" | wc -l)" = 0
+}
+
+@test "put tail deltas" {
+  mkdir "$SCRATCH/d"
+  head -c 3000000 /dev/urandom > "$SCRATCH/d/log"
+  bupstash put --tail-deltas "$SCRATCH/d"
+  for i in 1 2 3
+  do
+    head -c 500000 /dev/urandom >> "$SCRATCH/d/log"
+    id="$(bupstash put --tail-deltas "$SCRATCH/d")"
+    bupstash get id=$id | tar -xOf - log | cmp - "$SCRATCH/d/log"
+  done
+  # A change to a sampled block means the whole file is read again.
+  printf 'xxxx' | dd of="$SCRATCH/d/log" bs=1 conv=notrunc
+  echo more >> "$SCRATCH/d/log"
+  id="$(bupstash put --tail-deltas "$SCRATCH/d")"
+  bupstash get id=$id | tar -xOf - log | cmp - "$SCRATCH/d/log"
+}
+
+@test "restore" {
+  mkdir -p "$SCRATCH/foo/bar/baz" "$SCRATCH/foo/ro"
+  echo -n abc > "$SCRATCH/foo/bar/a.txt"
+  chmod 640 "$SCRATCH/foo/bar/a.txt"
+  ln -s bar/a.txt "$SCRATCH/foo/link"
+  echo -n def > "$SCRATCH/foo/ro/b.txt"
+  chmod 555 "$SCRATCH/foo/ro"
+  touch -d 2001-01-01 "$SCRATCH/foo/bar"
+  id="$(bupstash put "$SCRATCH/foo")"
+  bupstash restore --into "$SCRATCH/restore" id=$id
+  diff -r --no-dereference "$SCRATCH/foo" "$SCRATCH/restore"
+  test "$(stat -c %a "$SCRATCH/restore/bar/a.txt")" = 640
+  test "$(stat -c %a "$SCRATCH/restore/ro")" = 555
+  test "$(stat -c %Y "$SCRATCH/restore/bar")" = "$(stat -c %Y "$SCRATCH/foo/bar")"
+  # Restoring never overwrites existing files.
+  run bupstash restore --into "$SCRATCH/restore" id=$id
+  test "$status" != 0
+  bupstash restore --into "$SCRATCH/picked" --pick bar id=$id
+  test "$(cat "$SCRATCH/picked/bar/a.txt")" = abc
+  test ! -e "$SCRATCH/picked/ro"
+  run bupstash restore --into "$SCRATCH/file" --pick bar/a.txt id=$id
+  test "$status" != 0
+  chmod -R u+w "$SCRATCH/foo" "$SCRATCH/restore"
+}
+
+@test "put send workers" {
+  head -c 20000000 /dev/urandom > "$SCRATCH/rand.dat"
+  id="$(bupstash put --no-send-log --send-workers 4 "$SCRATCH/rand.dat")"
+  bupstash get id=$id | cmp - "$SCRATCH/rand.dat"
+  # Chunks are the same regardless of the number of workers.
+  bupstash put --no-send-log --send-workers 1 --print-stats "$SCRATCH/rand.dat" 2> "$SCRATCH/stats" > /dev/null
+  grep -q "^0 chunks added" "$SCRATCH/stats"
+  run bupstash put --send-workers 0 "$SCRATCH/rand.dat"
+  test "$status" != 0
+}
+
+@test "put fastcdc chunker" {
+  head -c 5000000 /dev/urandom > "$SCRATCH/a.dat"
+  (head -c 2500000 "$SCRATCH/a.dat"; echo inserted; tail -c +2500001 "$SCRATCH/a.dat") > "$SCRATCH/b.dat"
+  id="$(bupstash put --chunker fastcdc "$SCRATCH/a.dat")"
+  bupstash get id=$id | cmp - "$SCRATCH/a.dat"
+  bupstash put --no-send-log --chunker fastcdc --print-stats "$SCRATCH/b.dat" 2> "$SCRATCH/stats" > /dev/null
+  test "$(grep "chunks added" "$SCRATCH/stats" | cut -d ' ' -f 1)" -lt 4
+  run bupstash put --chunker nope "$SCRATCH/a.dat"
+  test "$status" != 0
+}
+
+@test "put chunker change invalidates stat cache" {
+  mkdir "$SCRATCH/d"
+  head -c 3000000 /dev/urandom > "$SCRATCH/d/a.dat"
+  bupstash put "$SCRATCH/d"
+  bupstash put --print-stats "$SCRATCH/d" 2> "$SCRATCH/stats1" > /dev/null
+  grep -q "^0 chunks sent" "$SCRATCH/stats1"
+  id="$(bupstash put --chunker fastcdc --print-stats "$SCRATCH/d" 2> "$SCRATCH/stats2")"
+  test "$(grep "chunks sent" "$SCRATCH/stats2" | cut -d ' ' -f 1)" -gt 1
+  bupstash get --pick a.dat id=$id | cmp - "$SCRATCH/d/a.dat"
+}
+
+@test "put chunk size options" {
+  head -c 2000000 /dev/urandom > "$SCRATCH/a.dat"
+  id="$(bupstash put --no-send-log --chunk-min-size 4K --chunk-max-size 64K --chunk-mask-bits 14 --print-stats "$SCRATCH/a.dat" 2> "$SCRATCH/stats")"
+  test "$(grep "chunks sent" "$SCRATCH/stats" | cut -d ' ' -f 1)" -gt 30
+  bupstash get id=$id | cmp - "$SCRATCH/a.dat"
+  run bupstash put --chunk-min-size 64K --chunk-max-size 64K "$SCRATCH/a.dat"
+  test "$status" != 0
+  run bupstash put --chunk-max-size 1G "$SCRATCH/a.dat"
+  test "$status" != 0
+  run bupstash put --chunk-mask-bits 32 "$SCRATCH/a.dat"
+  test "$status" != 0
+  run bupstash put --max-memory 64M --chunk-mask-bits 16 "$SCRATCH/a.dat"
+  test "$status" != 0
+}
+
+@test "posix acls" {
+  mkdir -p "$SCRATCH/foo/sub"
+  echo a > "$SCRATCH/foo/a.txt"
+  if ! setfacl -m u:12345:r-- "$SCRATCH/foo/a.txt"
+  then
+    skip "unable to set acls"
+  fi
+  setfacl -d -m g:12345:rwx "$SCRATCH/foo/sub"
+  id="$(bupstash put --acls :: "$SCRATCH/foo")"
+  bupstash get id=$id | grep -a -q "SCHILY.acl.access=.*user:12345:r--"
+  bupstash list-contents --format=jsonl id=$id | grep a.txt | grep -q '"acl_access":".*user:12345:r--'
+  bupstash list-contents --format=jsonl id=$id | grep sub | grep -q '"acl_default":".*group:12345:rwx'
+  test "$(bupstash list-contents --format=jsonl id=$id | wc -l)" = 3
+  bupstash restore --into "$SCRATCH/restore" id=$id
+  getfacl -n "$SCRATCH/restore/a.txt" | grep -q "^user:12345:r--"
+  getfacl -n "$SCRATCH/restore/sub" | grep -q "^default:group:12345:rwx"
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  ! bupstash get id=$id | grep -a -q "SCHILY.acl"
+}
+
+@test "sparse files" {
+  mkdir "$SCRATCH/foo"
+  truncate -s 64M "$SCRATCH/foo/sparse.img"
+  echo abc | dd of="$SCRATCH/foo/sparse.img" bs=1M seek=32 conv=notrunc 2> /dev/null
+  if test "$(du -k "$SCRATCH/foo/sparse.img" | cut -f 1)" -gt 1024
+  then
+    skip "filesystem does not support sparse files"
+  fi
+  # Read only sparse files are written before their mode is applied.
+  cp --sparse=always "$SCRATCH/foo/sparse.img" "$SCRATCH/foo/readonly.img"
+  chmod 0444 "$SCRATCH/foo/readonly.img"
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  test "$(bupstash list-contents --format=jsonl id=$id | wc -l)" = 3
+  bupstash get --pick sparse.img id=$id | cmp - "$SCRATCH/foo/sparse.img"
+  bupstash restore --into "$SCRATCH/restore" id=$id
+  cmp "$SCRATCH/restore/sparse.img" "$SCRATCH/foo/sparse.img"
+  test "$(du -k "$SCRATCH/restore/sparse.img" | cut -f 1)" -lt 1024
+  cmp "$SCRATCH/restore/readonly.img" "$SCRATCH/foo/readonly.img"
+  test "$(du -k "$SCRATCH/restore/readonly.img" | cut -f 1)" -lt 1024
+  test "$(stat -c %a "$SCRATCH/restore/readonly.img")" = 444
+}
+
+@test "special files" {
+  mkdir "$SCRATCH/foo"
+  mkfifo "$SCRATCH/foo/fifo"
+  python3 -c 'import socket, sys; socket.socket(socket.AF_UNIX).bind(sys.argv[1])' "$SCRATCH/foo/sock"
+  chmod 0640 "$SCRATCH/foo/fifo"
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  test "$(bupstash list-contents id=$id | grep fifo | cut -c 1-10)" = prw-r-----
+  test "$(bupstash list-contents id=$id | grep sock | cut -c 1)" = s
+  test "$(bupstash get id=$id | tar -t | wc -l)" = 2
+  bupstash restore --into "$SCRATCH/restore" id=$id
+  test -p "$SCRATCH/restore/fifo"
+  test -S "$SCRATCH/restore/sock"
+  test "$(stat -c %a "$SCRATCH/restore/fifo")" = 640
+}
+
+@test "ignore files" {
+  mkdir -p "$SCRATCH/foo/sub/build" "$SCRATCH/foo/build"
+  echo a > "$SCRATCH/foo/a.o"
+  echo b > "$SCRATCH/foo/sub/b.o"
+  echo c > "$SCRATCH/foo/sub/c.txt"
+  echo d > "$SCRATCH/foo/sub/build/d.txt"
+  echo e > "$SCRATCH/foo/build/e.txt"
+  printf '# comment\n*.o\n/build\n' > "$SCRATCH/foo/sub/.bupstashignore"
+  id="$(bupstash put --ignore-files :: "$SCRATCH/foo")"
+  bupstash list-contents id=$id > "$SCRATCH/contents"
+  grep -q "a.o" "$SCRATCH/contents"
+  ! grep -q "b.o" "$SCRATCH/contents"
+  ! grep -q "sub/build" "$SCRATCH/contents"
+  grep -q "build/e.txt" "$SCRATCH/contents"
+  grep -q "sub/.bupstashignore" "$SCRATCH/contents"
+  # Editing the ignore file invalidates the cached directories below it.
+  printf '*.txt\n' > "$SCRATCH/foo/sub/.bupstashignore"
+  id="$(bupstash put --ignore-files :: "$SCRATCH/foo")"
+  bupstash list-contents id=$id > "$SCRATCH/contents"
+  grep -q "sub/b.o" "$SCRATCH/contents"
+  ! grep -q "c.txt" "$SCRATCH/contents"
+  ! grep -q "d.txt" "$SCRATCH/contents"
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  test "$(bupstash list-contents id=$id | grep -c "\.txt")" = 3
+}
+
+@test "exclude caches" {
+  mkdir -p "$SCRATCH/foo/cache/sub" "$SCRATCH/foo/notcache"
+  echo a > "$SCRATCH/foo/cache/sub/a.txt"
+  echo b > "$SCRATCH/foo/notcache/b.txt"
+  echo "Signature: 8a477f597d28d172789f06886806bc55" > "$SCRATCH/foo/cache/CACHEDIR.TAG"
+  echo "Signature: not a cache" > "$SCRATCH/foo/notcache/CACHEDIR.TAG"
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  test "$(bupstash list-contents id=$id | grep -c "a.txt")" = 1
+  id="$(bupstash put --exclude-caches :: "$SCRATCH/foo")"
+  bupstash list-contents id=$id > "$SCRATCH/contents"
+  ! grep -qE " cache(/|$)" "$SCRATCH/contents"
+  ! grep -q "a.txt" "$SCRATCH/contents"
+  grep -q "notcache/b.txt" "$SCRATCH/contents"
+}
+
+@test "include patterns" {
+  mkdir -p "$SCRATCH/foo/docs" "$SCRATCH/foo/photos/2020" "$SCRATCH/foo/other"
+  echo a > "$SCRATCH/foo/docs/a.docx"
+  echo b > "$SCRATCH/foo/docs/b.txt"
+  echo c > "$SCRATCH/foo/photos/2020/c.jpg"
+  echo d > "$SCRATCH/foo/photos/2020/d.tmp"
+  echo e > "$SCRATCH/foo/other/e.txt"
+  id="$(bupstash put --include '**/*.docx' --include '**/photos' --exclude '**/*.tmp' :: "$SCRATCH/foo")"
+  bupstash list-contents id=$id > "$SCRATCH/contents"
+  grep -q "docs/a.docx" "$SCRATCH/contents"
+  grep -q "photos/2020/c.jpg" "$SCRATCH/contents"
+  grep -q " other$" "$SCRATCH/contents"
+  ! grep -q "b.txt" "$SCRATCH/contents"
+  ! grep -q "d.tmp" "$SCRATCH/contents"
+  ! grep -q "e.txt" "$SCRATCH/contents"
+}
+
+@test "follow symlinks" {
+  mkdir -p "$SCRATCH/foo/d" "$SCRATCH/target" "$SCRATCH/restore"
+  echo a > "$SCRATCH/target/a.txt"
+  ln -s "$SCRATCH/target" "$SCRATCH/foo/linked"
+  ln -s "$SCRATCH/target/a.txt" "$SCRATCH/foo/b.txt"
+  ln -s "$SCRATCH/foo" "$SCRATCH/foo/d/loop"
+  ln -s "$SCRATCH/missing" "$SCRATCH/foo/dangling"
+  id="$(bupstash put :: "$SCRATCH/foo")"
+  ! bupstash list-contents id=$id | grep -q "linked/a.txt"
+  id="$(bupstash put --follow-symlinks :: "$SCRATCH/foo")"
+  bupstash list-contents id=$id > "$SCRATCH/contents"
+  grep -q "linked/a.txt" "$SCRATCH/contents"
+  grep -q "^l.* d/loop" "$SCRATCH/contents"
+  grep -q "^l.* dangling" "$SCRATCH/contents"
+  bupstash get id=$id | tar -C "$SCRATCH/restore" -xf -
+  test "$(cat "$SCRATCH/restore/b.txt")" = a
+  test -d "$SCRATCH/restore/linked"
+}
+
+@test "multiple directories" {
+  mkdir -p "$SCRATCH/foo/sub" "$SCRATCH/bar" "$SCRATCH/other/foo" "$SCRATCH/restore"
+  echo a > "$SCRATCH/foo/sub/a.txt"
+  echo b > "$SCRATCH/bar/b.txt"
+  id="$(bupstash put :: "$SCRATCH/foo" "$SCRATCH/bar")"
+  bupstash list-contents id=$id > "$SCRATCH/contents"
+  grep -q " foo/sub/a.txt" "$SCRATCH/contents"
+  grep -q " bar/b.txt" "$SCRATCH/contents"
+  test "$(bupstash get --pick bar/b.txt id=$id)" = b
+  bupstash get id=$id | tar -C "$SCRATCH/restore" -xf -
+  test "$(cat "$SCRATCH/restore/foo/sub/a.txt")" = a
+  ! bupstash put :: "$SCRATCH/foo" "$SCRATCH/other/foo"
+  ! bupstash put :: "$SCRATCH/foo" "$SCRATCH/bar/b.txt"
+}
+
+@test "size hint" {
+  id="$(echo data | bupstash put --size-hint 1K -)"
+  test "$(bupstash get id=$id)" = data
+  id="$(bupstash put --size-hint 1 --exec echo data)"
+  test "$(bupstash get id=$id)" = data
+  mkdir "$SCRATCH/foo"
+  ! bupstash put --size-hint 1K :: "$SCRATCH/foo"
+  ! echo data | bupstash put --size-hint bogus -
+}
+
+@test "put hooks" {
+  id="$(bupstash put --pre-hook "echo pre > $SCRATCH/pre" \
+        --post-hook 'echo "$BUPSTASH_PUT_STATUS $BUPSTASH_ITEM_ID" > '"$SCRATCH/post" \
+        --exec cat "$SCRATCH/pre")"
+  test "$(bupstash get id=$id)" = pre
+  test "$(cat "$SCRATCH/post")" = "ok $id"
+  ! bupstash put --pre-hook false --post-hook "echo \$BUPSTASH_PUT_STATUS > $SCRATCH/post" -e echo hi
+  test "$(cat "$SCRATCH/post")" = failed
+  ! bupstash put --post-hook "echo \$BUPSTASH_PUT_STATUS > $SCRATCH/post" -e false
+  test "$(cat "$SCRATCH/post")" = failed
+  ! bupstash put --post-hook false -e echo hi
+  test "$(bupstash list | wc -l)" = 2
+  # The data source may be created by the pre hook.
+  id="$(bupstash put --pre-hook "mkdir $SCRATCH/made && echo made > $SCRATCH/made/f" "$SCRATCH/made")"
+  test "$(bupstash get --pick f id=$id)" = made
+  ! bupstash put --pre-hook false --post-hook "echo \$BUPSTASH_PUT_STATUS > $SCRATCH/post" "$SCRATCH/missing"
+  test "$(cat "$SCRATCH/post")" = failed
+}
+
+@test "file send checkpoints" {
+  head -c 5000000 /dev/urandom > "$SCRATCH/big"
+  id1="$(BUPSTASH_CHECKPOINT_BYTES=1 bupstash put "$SCRATCH/big")"
+  bupstash get id=$id1 | cmp - "$SCRATCH/big"
+  # A completed send leaves nothing to resume.
+  id2="$(BUPSTASH_CHECKPOINT_BYTES=1 bupstash put "$SCRATCH/big" 2>"$SCRATCH/stderr")"
+  ! grep -q "resuming" "$SCRATCH/stderr"
+  bupstash get id=$id2 | cmp - "$SCRATCH/big"
+}
+
+@test "stat workers" {
+  mkdir -p "$SCRATCH/foo/a/b" "$SCRATCH/foo/c"
+  for i in $(seq 100); do echo $i > "$SCRATCH/foo/a/$i"; done
+  for i in $(seq 50); do echo $i > "$SCRATCH/foo/a/b/$i"; ln -s $i "$SCRATCH/foo/c/$i"; done
+  id1="$(bupstash put --no-send-log :: "$SCRATCH/foo")"
+  id2="$(bupstash put --no-send-log --stat-workers 8 :: "$SCRATCH/foo")"
+  diff <(bupstash list-contents id=$id1) <(bupstash list-contents id=$id2)
+  diff <(bupstash get id=$id1 | sha256sum) <(bupstash get id=$id2 | sha256sum)
+  ! bupstash put --stat-workers 0 :: "$SCRATCH/foo"
+}
+
+@test "upload rate" {
+  head -c 300000 /dev/urandom > "$SCRATCH/data"
+  start=$(date +%s%N)
+  id="$(bupstash put --upload-rate 200K "$SCRATCH/data")"
+  end=$(date +%s%N)
+  test $(( (end - start) / 1000000 )) -ge 1000
+  bupstash get id=$id | cmp - "$SCRATCH/data"
+  ! bupstash put --upload-rate 0 "$SCRATCH/data"
+}
+
+@test "stat cache check" {
+  mkdir "$SCRATCH/d"
+  echo abc > "$SCRATCH/d/a.txt"
+  touch -d "2020-01-01 00:00:00" "$SCRATCH/d/a.txt"
+  bupstash put --stat-cache-check mtime-size "$SCRATCH/d"
+  # Same size and modification time, only the change time differs.
+  echo xyz > "$SCRATCH/d/a.txt"
+  touch -d "2020-01-01 00:00:00" "$SCRATCH/d/a.txt"
+  id="$(bupstash put --stat-cache-check mtime-size "$SCRATCH/d")"
+  test "$(bupstash get --pick a.txt id=$id)" = abc
+  id="$(bupstash put --stat-cache-check rehash "$SCRATCH/d")"
+  test "$(bupstash get --pick a.txt id=$id)" = xyz
+  id="$(bupstash put "$SCRATCH/d")"
+  test "$(bupstash get --pick a.txt id=$id)" = xyz
+  run bupstash put --stat-cache-check nope "$SCRATCH/d"
+  test "$status" != 0
+}
+
+@test "entry owners" {
+  mkdir "$SCRATCH/d"
+  echo abc > "$SCRATCH/d/a.txt"
+  id="$(bupstash put "$SCRATCH/d")"
+  bupstash list-contents --format=jsonl id=$id | grep "a.txt" | grep -q "\"uid\":$(id -u),\"gid\":$(id -g)"
+  bupstash list-contents id=$id | grep "a.txt" | grep -q " $(id -un)/$(id -gn) "
+  bupstash restore --numeric-owner --into "$SCRATCH/restore" id=$id
+  test "$(stat -c %u "$SCRATCH/restore/a.txt")" = "$(id -u)"
+}
+
+@test "deterministic tarballs" {
+  mkdir -p "$SCRATCH/a/sub" "$SCRATCH/b/sub"
+  for d in a b; do
+    echo abc > "$SCRATCH/$d/sub/x.txt"
+    echo def > "$SCRATCH/$d/y.txt"
+  done
+  touch -d "2020-01-01 00:00:00" "$SCRATCH/a/y.txt"
+  id1="$(bupstash put --deterministic :: "$SCRATCH/a")"
+  id2="$(bupstash put --deterministic :: "$SCRATCH/b")"
+  diff <(bupstash get id=$id1 | sha256sum) <(bupstash get id=$id2 | sha256sum)
+  test "$(bupstash get id=$id1 | tar -tvf - --numeric-owner | grep y.txt | cut -d ' ' -f 2)" = 0/0
+  id3="$(bupstash put :: "$SCRATCH/b")"
+  ! diff <(bupstash get id=$id1 | sha256sum) <(bupstash get id=$id3 | sha256sum)
+}
+
+@test "json progress" {
+  mkdir "$SCRATCH/d"
+  echo abc > "$SCRATCH/d/a.txt"
+  id="$(bupstash put --progress json "$SCRATCH/d" 2> "$SCRATCH/events")"
+  grep -q '"phase":"sending"' "$SCRATCH/events"
+  grep '"event":"done"' "$SCRATCH/events" | grep -q "\"id\":\"$id\""
+  ! grep -v '^{.*}$' "$SCRATCH/events"
+  run bupstash put --progress nope "$SCRATCH/d"
+  test "$status" != 0
+}
+
+@test "checkpoint by time" {
+  # Excercise time based checkpoints, the upload is slowed
+  # so several checkpoints happen before the byte limit.
+  export BUPSTASH_CHECKPOINT_BYTES=1073741824
+  export BUPSTASH_CHECKPOINT_SECONDS=1
+  head -c 3000000 /dev/urandom > "$SCRATCH/rand.dat"
+  id="$(bupstash put --upload-rate 1M :: "$SCRATCH/rand.dat")"
+  bupstash get id=$id | cmp - "$SCRATCH/rand.dat"
+  ! BUPSTASH_CHECKPOINT_SECONDS=soon bupstash put :: "$SCRATCH/rand.dat"
+}
+
+@test "fixed size chunks" {
+  head -c 1048576 /dev/urandom > "$SCRATCH/disk.img"
+  bupstash put --chunker fixed --chunk-max-size 64K "$SCRATCH/disk.img"
+  head -c 100 /dev/urandom | dd of="$SCRATCH/disk.img" bs=1 seek=300000 conv=notrunc
+  id="$(bupstash put --chunker fixed --chunk-max-size 64K --print-stats "$SCRATCH/disk.img" 2> "$SCRATCH/stats")"
+  test "$(grep "chunks sent" "$SCRATCH/stats" | cut -d ' ' -f 1)" -lt 4
+  bupstash get id=$id | cmp - "$SCRATCH/disk.img"
+  run bupstash put --chunker fixed --chunk-max-size 5000 "$SCRATCH/disk.img"
+  test "$status" != 0
+  run bupstash put --chunker fixed --chunk-min-size 4K "$SCRATCH/disk.img"
+  test "$status" != 0
+}
+
+@test "compression level" {
+  yes abcdefghijklmnop | head -c 2000000 > "$SCRATCH/foo.txt"
+  for level in 1 19
+  do
+    id="$(bupstash put --no-send-log --compression-level $level :: "$SCRATCH/foo.txt")"
+    bupstash get id=$id | cmp - "$SCRATCH/foo.txt"
+  done
+  run bupstash put --compression-level 23 "$SCRATCH/foo.txt"
+  test "$status" != 0
+  run bupstash put --no-compression --compression-level 3 "$SCRATCH/foo.txt"
+  test "$status" != 0
+}
+
+@test "lz4 compression" {
+  yes abcdefghijklmnop | head -c 2000000 > "$SCRATCH/foo.txt"
+  id="$(bupstash put --no-send-log --compression lz4 :: "$SCRATCH/foo.txt")"
+  bupstash get id=$id | cmp - "$SCRATCH/foo.txt"
+  run bupstash put --compression lz4 --compression-level 3 "$SCRATCH/foo.txt"
+  test "$status" != 0
+  run bupstash put --compression brotli "$SCRATCH/foo.txt"
+  test "$status" != 0
+}
+
+@test "skip incompressible files" {
+  mkdir "$SCRATCH/d"
+  head -c 1000000 /dev/urandom > "$SCRATCH/d/rand.dat"
+  yes abcdefghijklmnop | head -c 1000000 > "$SCRATCH/d/photo.jpg"
+  for opt in "" --always-compress
+  do
+    id="$(bupstash put --no-send-log $opt :: "$SCRATCH/d")"
+    rm -rf "$SCRATCH/restore"
+    mkdir "$SCRATCH/restore"
+    bupstash get id=$id | tar -C "$SCRATCH/restore" -xf -
+    diff -r "$SCRATCH/d" "$SCRATCH/restore"
+  done
+  id="$(bupstash put :: "$SCRATCH/d/photo.jpg")"
+  bupstash get id=$id | cmp - "$SCRATCH/d/photo.jpg"
+}
+
+@test "retry files modified while sending" {
+  mkdir "$SCRATCH/foo"
+  head -c 2000000 /dev/urandom > "$SCRATCH/foo/a"
+  head -c 2000000 /dev/urandom > "$SCRATCH/foo/grows"
+  # Keep appending for a while, only this file should be sent again.
+  (for i in $(seq 10); do echo $i >> "$SCRATCH/foo/grows"; sleep 0.1; done) &
+  id="$(bupstash put --no-send-log --upload-rate 2M "$SCRATCH/foo")"
+  wait
+  mkdir "$SCRATCH/restore"
+  bupstash get id=$id | tar -C "$SCRATCH/restore" -xf -
+  cmp "$SCRATCH/foo/a" "$SCRATCH/restore/a"
+  cmp -n 2000000 "$SCRATCH/foo/grows" "$SCRATCH/restore/grows"
+}
+
+@test "skip files removed while sending" {
+  mkdir "$SCRATCH/foo"
+  head -c 4000000 /dev/urandom > "$SCRATCH/foo/a"
+  echo b > "$SCRATCH/foo/b"
+  # Removed after the directory was listed, while a is sent.
+  (sleep 0.5; rm "$SCRATCH/foo/b") &
+  id="$(bupstash put --no-send-log --upload-rate 2M "$SCRATCH/foo")"
+  wait
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r .path | tr '\n' ' ')" = ". a "
+  test "$(bupstash get id=$id | tar -tf - | grep -c 'b$')" = 0
+}
+
+@test "skip unopenable files" {
+  if test "$(id -u)" = 0
+  then
+    skip "root can open any file"
+  fi
+  mkdir "$SCRATCH/foo"
+  echo a > "$SCRATCH/foo/a"
+  echo b > "$SCRATCH/foo/b"
+  chmod 000 "$SCRATCH/foo/b"
+  run bupstash put "$SCRATCH/foo"
+  test "$status" != 0
+  id="$(bupstash put --open-errors skip "$SCRATCH/foo" 2> "$SCRATCH/err")"
+  grep -q "could not be opened" "$SCRATCH/err"
+  grep -q "foo/b$" "$SCRATCH/err"
+  test "$(bupstash list-contents id=$id --format=jsonl | jq -r .path | sort | tr '\n' ' ')" = ". a "
+  chmod 644 "$SCRATCH/foo/b"
+  id="$(bupstash put --open-errors skip "$SCRATCH/foo")"
+  test "$(bupstash list-contents id=$id --format=jsonl | jq -r .path | sort | tr '\n' ' ')" = ". a b "
+  run bupstash put --open-errors sometimes "$SCRATCH/foo"
+  test "$status" != 0
+}
+
+@test "index entry stat" {
+  mkdir "$SCRATCH/d"
+  echo abc > "$SCRATCH/d/a.txt"
+  ln "$SCRATCH/d/a.txt" "$SCRATCH/d/hard.txt"
+  ln -s a.txt "$SCRATCH/d/link"
+  touch -h -d "2020-01-01 00:00:00 UTC" "$SCRATCH/d/a.txt"
+  id="$(bupstash put "$SCRATCH/d")"
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == "link") | .link_target')" = a.txt
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == "link") | .type')" = symlink
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == ".") | .type')" = dir
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == "a.txt") | .nlink')" = 2
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == "a.txt") | .mtime')" = 1577836800
+  bupstash list-contents id=$id | grep -q "link -> a.txt$"
+  id="$(bupstash put --deterministic "$SCRATCH/d")"
+  test "$(bupstash list-contents --format=jsonl id=$id | jq -r 'select(.path == "a.txt") | .mtime')" = 0
+}
+
+@test "content hashes" {
+  mkdir "$SCRATCH/d"
+  echo abc > "$SCRATCH/d/a.txt"
+  echo abc > "$SCRATCH/d/b.txt"
+  echo def > "$SCRATCH/d/c.txt"
+  id="$(bupstash put "$SCRATCH/d")"
+  hash() {
+    bupstash list-contents --format=jsonl id=$1 | jq -r "select(.path == \"$2\") | .content_hash"
+  }
+  test "$(hash $id a.txt)" = "$(hash $id b.txt)"
+  test "$(hash $id a.txt)" != "$(hash $id c.txt)"
+  test "$(hash $id a.txt | wc -c)" = 65
+  test "$(hash $id .)" = null
+  # Cached directories keep their hashes.
+  id2="$(bupstash put "$SCRATCH/d")"
+  test "$(hash $id a.txt)" = "$(hash $id2 a.txt)"
+}
+
+@test "incremental restore" {
+  mkdir -p "$SCRATCH/foo/sub" "$SCRATCH/foo/ro"
+  echo -n abc > "$SCRATCH/foo/a.txt"
+  echo -n def > "$SCRATCH/foo/sub/b.txt"
+  echo -n ghi > "$SCRATCH/foo/ro/c.txt"
+  ln -s a.txt "$SCRATCH/foo/link"
+  chmod 555 "$SCRATCH/foo/ro"
+  id="$(bupstash put "$SCRATCH/foo")"
+  bupstash restore --into "$SCRATCH/restore" id=$id
+  run bupstash restore --into "$SCRATCH/restore" --delete id=$id
+  test "$status" != 0
+  # Unchanged files are left alone, changed and missing files are restored.
+  touch -d 2001-01-01 "$SCRATCH/restore/a.txt"
+  echo -n xyz > "$SCRATCH/restore/sub/b.txt"
+  touch -r "$SCRATCH/foo/sub/b.txt" "$SCRATCH/restore/sub/b.txt"
+  rm "$SCRATCH/restore/link"
+  chmod u+w "$SCRATCH/restore/ro"
+  echo -n jkl > "$SCRATCH/restore/ro/extra.txt"
+  chmod 555 "$SCRATCH/restore/ro"
+  mkdir "$SCRATCH/restore/extra-dir"
+  bupstash restore --into "$SCRATCH/restore" --incremental id=$id
+  test "$(stat -c %Y "$SCRATCH/restore/a.txt")" = "$(stat -c %Y "$SCRATCH/foo/a.txt")"
+  test "$(readlink "$SCRATCH/restore/link")" = a.txt
+  # Same size and time, only the content hash tells them apart.
+  test "$(cat "$SCRATCH/restore/sub/b.txt")" = xyz
+  test -e "$SCRATCH/restore/ro/extra.txt"
+  bupstash restore --into "$SCRATCH/restore" --incremental --checksum --delete id=$id
+  test ! -e "$SCRATCH/restore/ro/extra.txt"
+  test ! -e "$SCRATCH/restore/extra-dir"
+  test "$(stat -c %a "$SCRATCH/restore/ro")" = 555
+  diff -r --no-dereference "$SCRATCH/foo" "$SCRATCH/restore"
+  # Restored files keep the exact mtime of the snapshot.
+  test "$(stat -c %.9Y "$SCRATCH/restore/a.txt")" = "$(stat -c %.9Y "$SCRATCH/foo/a.txt")"
+  # Files recorded as unchanged are not deleted.
+  sleep 1.1
+  echo -n mno > "$SCRATCH/foo/new.txt"
+  id2="$(bupstash put --changed-since $id "$SCRATCH/foo")"
+  bupstash restore --into "$SCRATCH/restore" --incremental --delete id=$id2
+  test "$(cat "$SCRATCH/restore/a.txt")" = abc
+  test "$(cat "$SCRATCH/restore/new.txt")" = mno
+  chmod -R u+w "$SCRATCH/foo" "$SCRATCH/restore"
+}
+
+@test "pick many" {
+  mkdir -p "$SCRATCH/foo/sub" "$SCRATCH/foo/other" "$SCRATCH/picked"
+  echo -n abc > "$SCRATCH/foo/sub/a.txt"
+  echo -n def > "$SCRATCH/foo/sub/b.log"
+  echo -n ghi > "$SCRATCH/foo/other/c.txt"
+  echo -n jkl > "$SCRATCH/foo/d.txt"
+  id="$(bupstash put "$SCRATCH/foo")"
+  bupstash get --pick 'sub/*.txt' --pick other id=$id | tar -C "$SCRATCH/picked" -xf -
+  test "$(cat "$SCRATCH/picked/sub/a.txt")" = abc
+  test "$(cat "$SCRATCH/picked/other/c.txt")" = ghi
+  test ! -e "$SCRATCH/picked/sub/b.log"
+  test ! -e "$SCRATCH/picked/d.txt"
+  test "$(bupstash get --pick '*.txt' id=$id | tar -tf - | wc -l)" = 3
+  # A single plain path is still output as the file contents.
+  test "$(bupstash get --pick d.txt id=$id)" = jkl
+  run bupstash get --pick d.txt --pick 'missing*' id=$id
+  test "$status" != 0
+}
+
+@test "verify items" {
+  mkdir "$SCRATCH/foo"
+  echo -n abc > "$SCRATCH/foo/a.txt"
+  id1="$(bupstash put "$SCRATCH/foo")"
+  id2="$(bupstash put :: "$SCRATCH/foo/a.txt")"
+  test -z "$(bupstash verify id=$id1)"
+  run bupstash verify id="*"
+  test "$status" != 0
+  bupstash verify --allow-many id="*"
+  for f in "$REPO"/data/*
+  do
+    echo 'XXXXXXXXXXXXXXXXXXXXX' > "$f"
+  done
+  run bupstash verify id=$id2
+  echo "$output"
+  test "$status" != 0
+  echo "$output" | grep -q "item $id2 failed verification: corrupt or tampered data in chunk"
+}
+
+@test "deep verify" {
+  mkdir -p "$SCRATCH/foo/sub"
+  echo -n abc > "$SCRATCH/foo/a.txt"
+  touch "$SCRATCH/foo/empty.txt"
+  head -c 5000000 /dev/urandom > "$SCRATCH/foo/sub/big.bin"
+  id="$(bupstash put "$SCRATCH/foo")"
+  test -z "$(bupstash verify --deep id=$id 2>&1)"
+  bupstash restore --into "$SCRATCH/restore" --verify id=$id
+  diff -r "$SCRATCH/foo" "$SCRATCH/restore"
+  rm -rf "$SCRATCH/restore"
+  bupstash restore --into "$SCRATCH/restore" --pick sub --verify id=$id
+  test ! -e "$SCRATCH/restore/a.txt"
+}
+
+@test "diff items" {
+  mkdir -p "$SCRATCH/foo/sub"
+  echo -n abc > "$SCRATCH/foo/a.txt"
+  echo -n def > "$SCRATCH/foo/sub/b.txt"
+  echo -n ghi > "$SCRATCH/foo/c.txt"
+  id1="$(bupstash put "$SCRATCH/foo")"
+  echo -n abcde > "$SCRATCH/foo/a.txt"
+  # Same size and time, only the content hash tells them apart.
+  touch -r "$SCRATCH/foo/sub/b.txt" "$SCRATCH/foo/c.txt"
+  echo -n xyz > "$SCRATCH/foo/sub/b.txt"
+  touch -r "$SCRATCH/foo/c.txt" "$SCRATCH/foo/sub/b.txt"
+  rm "$SCRATCH/foo/c.txt"
+  echo -n new > "$SCRATCH/foo/d.txt"
+  id2="$(bupstash put "$SCRATCH/foo")"
+  bupstash diff $id1 $id2
+  test "$(bupstash diff $id1 $id2 | grep -c '^A d.txt$')" = 1
+  test "$(bupstash diff $id1 $id2 | grep -c '^D c.txt$')" = 1
+  bupstash diff $id1 $id2 | grep -q '^M a.txt (size +2'
+  bupstash diff $id1 $id2 | grep -q '^M sub/b.txt (contents differ)$'
+  test "$(bupstash diff --format=jsonl $id1 $id2 | wc -l)" = "$(bupstash diff $id1 $id2 | wc -l)"
+  bupstash diff --format=jsonl $id1 $id2 | grep '"path":"c.txt"' | grep -q '"change":"removed"'
+  test "$(bupstash diff --format=json $id1 $id2 | jq length)" = "$(bupstash diff $id1 $id2 | wc -l)"
+  test -z "$(bupstash diff $id2 $id2)"
+}
@@ -0,0 +1,253 @@
+// POSIX ACL support, linux stores ACLs in the system.posix_acl_access and
+// system.posix_acl_default extended attributes.
+
+// ACLs in the text form read by 'setfacl' and 'tar --acls', entries are
+// comma separated and use numeric user and group ids.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PosixAcls {
+    pub access: Option<String>,
+    // Only directories have a default ACL.
+    pub default: Option<String>,
+}
+
+impl PosixAcls {
+    pub fn is_empty(&self) -> bool {
+        self.access.is_none() && self.default.is_none()
+    }
+}
+
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+fn invalid_acl(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+// Convert the extended attribute encoding of an ACL to text.
+pub fn xattr_to_text(buf: &[u8]) -> Result<String, std::io::Error> {
+    if buf.len() < 4 || !buf[4..].chunks_exact(8).remainder().is_empty() {
+        return Err(invalid_acl("invalid ACL extended attribute size"));
+    }
+    let version = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    if version != ACL_XATTR_VERSION {
+        return Err(invalid_acl("unsupported ACL extended attribute version"));
+    }
+
+    let mut entries = Vec::new();
+    for ent in buf[4..].chunks_exact(8) {
+        let tag = u16::from_le_bytes([ent[0], ent[1]]);
+        let perm = u16::from_le_bytes([ent[2], ent[3]]);
+        let id = u32::from_le_bytes([ent[4], ent[5], ent[6], ent[7]]);
+        let (tag, qualifier) = match tag {
+            ACL_USER_OBJ => ("user", None),
+            ACL_USER => ("user", Some(id)),
+            ACL_GROUP_OBJ => ("group", None),
+            ACL_GROUP => ("group", Some(id)),
+            ACL_MASK => ("mask", None),
+            ACL_OTHER => ("other", None),
+            _ => return Err(invalid_acl("unknown ACL entry tag")),
+        };
+        entries.push(format!(
+            "{}:{}:{}{}{}",
+            tag,
+            qualifier.map(|id| id.to_string()).unwrap_or_default(),
+            if perm & 4 != 0 { 'r' } else { '-' },
+            if perm & 2 != 0 { 'w' } else { '-' },
+            if perm & 1 != 0 { 'x' } else { '-' },
+        ));
+    }
+    Ok(entries.join(","))
+}
+
+// Convert an ACL in text form to its extended attribute encoding. Besides our
+// own output this accepts the abbreviated tags and trailing numeric ids star writes.
+pub fn text_to_xattr(text: &str) -> Result<Vec<u8>, std::io::Error> {
+    let mut entries = Vec::new();
+    for ent in text.split(&[',', '\n'][..]) {
+        let ent = ent.trim();
+        if ent.is_empty() || ent.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = ent.split(':').collect();
+        if fields.len() < 3 || fields.len() > 4 {
+            return Err(invalid_acl("invalid ACL entry"));
+        }
+        let named = !fields[1].is_empty();
+        let tag = match (fields[0], named) {
+            ("user", false) | ("u", false) => ACL_USER_OBJ,
+            ("user", true) | ("u", true) => ACL_USER,
+            ("group", false) | ("g", false) => ACL_GROUP_OBJ,
+            ("group", true) | ("g", true) => ACL_GROUP,
+            ("mask", false) | ("m", false) => ACL_MASK,
+            ("other", false) | ("o", false) => ACL_OTHER,
+            _ => return Err(invalid_acl("invalid ACL entry tag")),
+        };
+        let id = if named {
+            // Names are only usable with the id that accompanies them.
+            match fields.get(3).unwrap_or(&fields[1]).parse::<u32>() {
+                Ok(id) => id,
+                Err(_) => return Err(invalid_acl("ACL entry has no numeric id")),
+            }
+        } else {
+            ACL_UNDEFINED_ID
+        };
+        let mut perm: u16 = 0;
+        for c in fields[2].chars() {
+            match c {
+                'r' => perm |= 4,
+                'w' => perm |= 2,
+                'x' => perm |= 1,
+                '-' => (),
+                _ => return Err(invalid_acl("invalid ACL entry permissions")),
+            }
+        }
+        entries.push((tag, id, perm));
+    }
+
+    // The kernel requires entries sorted by tag and then id.
+    entries.sort_unstable();
+
+    let mut buf = Vec::with_capacity(4 + entries.len() * 8);
+    buf.extend_from_slice(&ACL_XATTR_VERSION.to_le_bytes()[..]);
+    for (tag, id, perm) in entries.iter() {
+        buf.extend_from_slice(&tag.to_le_bytes()[..]);
+        buf.extend_from_slice(&perm.to_le_bytes()[..]);
+        buf.extend_from_slice(&id.to_le_bytes()[..]);
+    }
+    Ok(buf)
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "linux")] {
+
+        use std::os::unix::ffi::OsStrExt;
+
+        fn get_acl_xattr(
+            path: &std::ffi::CStr,
+            name: &[u8],
+        ) -> Result<Option<Vec<u8>>, std::io::Error> {
+            let mut buf: Vec<u8> = vec![0; 256];
+            loop {
+                let n = unsafe {
+                    libc::getxattr(
+                        path.as_ptr(),
+                        name.as_ptr() as *const libc::c_char,
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                if n >= 0 {
+                    buf.truncate(n as usize);
+                    return Ok(Some(buf));
+                }
+                let err = std::io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::ERANGE) => {
+                        let len = buf.len();
+                        buf.resize(len * 2, 0);
+                    }
+                    Some(libc::ENODATA) | Some(libc::ENOTSUP) => return Ok(None),
+                    _ => return Err(err),
+                }
+            }
+        }
+
+        fn set_acl_xattr(
+            path: &std::ffi::CStr,
+            name: &[u8],
+            value: &[u8],
+        ) -> Result<(), std::io::Error> {
+            let rc = unsafe {
+                libc::setxattr(
+                    path.as_ptr(),
+                    name.as_ptr() as *const libc::c_char,
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                )
+            };
+            if rc != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        // Returns None if the entry has no ACL beyond its mode, or
+        // the filesystem does not support ACLs.
+        pub fn read_acls(
+            full_path: &std::path::Path,
+            metadata: &std::fs::Metadata,
+        ) -> Result<Option<PosixAcls>, std::io::Error> {
+            // Symlinks have no ACLs, and reading one would follow it.
+            if metadata.file_type().is_symlink() {
+                return Ok(None);
+            }
+            let path = std::ffi::CString::new(full_path.as_os_str().as_bytes())?;
+            let mut acls = PosixAcls::default();
+            if let Some(buf) = get_acl_xattr(&path, b"system.posix_acl_access\0")? {
+                acls.access = Some(xattr_to_text(&buf)?);
+            }
+            if metadata.is_dir() {
+                if let Some(buf) = get_acl_xattr(&path, b"system.posix_acl_default\0")? {
+                    acls.default = Some(xattr_to_text(&buf)?);
+                }
+            }
+            Ok(if acls.is_empty() { None } else { Some(acls) })
+        }
+
+        // Must be called after the mode is set, setting the mode changes the ACL mask.
+        pub fn apply_acls(full_path: &std::path::Path, acls: &PosixAcls) -> Result<(), std::io::Error> {
+            let path = std::ffi::CString::new(full_path.as_os_str().as_bytes())?;
+            if let Some(ref access) = acls.access {
+                set_acl_xattr(&path, b"system.posix_acl_access\0", &text_to_xattr(access)?)?;
+            }
+            if let Some(ref default) = acls.default {
+                set_acl_xattr(&path, b"system.posix_acl_default\0", &text_to_xattr(default)?)?;
+            }
+            Ok(())
+        }
+
+    } else {
+
+        pub fn read_acls(
+            _full_path: &std::path::Path,
+            _metadata: &std::fs::Metadata,
+        ) -> Result<Option<PosixAcls>, std::io::Error> {
+            Ok(None)
+        }
+
+        pub fn apply_acls(_full_path: &std::path::Path, _acls: &PosixAcls) -> Result<(), std::io::Error> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "restoring POSIX ACLs is not supported on this platform",
+            ))
+        }
+
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acl_text_round_trip() {
+        let text = "user::rw-,user:1000:r-x,group::r--,group:27:rw-,mask::rwx,other::---";
+        let buf = text_to_xattr(text).unwrap();
+        assert_eq!(buf.len(), 4 + 6 * 8);
+        assert_eq!(xattr_to_text(&buf).unwrap(), text);
+        // Abbreviated, unordered and star style entries encode the same.
+        let star = "o::---,m::rwx,g:wheel:rw-:27,g::r--,u:joe:r-x:1000,u::rw-";
+        assert_eq!(text_to_xattr(star).unwrap(), buf);
+        assert!(text_to_xattr("user:joe:r--").is_err());
+        assert!(text_to_xattr("user::rwz").is_err());
+        assert!(xattr_to_text(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -14,11 +14,20 @@ pub trait Engine {
         self.get_chunk_async(addr).recv()?
     }
 
-    // Remove all chunks not in the reachable set.
+    // The stored size of a chunk, engines able to tell without
+    // reading the chunk should override this.
+    fn chunk_size(&mut self, addr: &Address) -> Result<u64, failure::Error> {
+        Ok(self.get_chunk(addr)?.len() as u64)
+    }
+
+    // Remove all chunks not in the reachable set using up to sweep_workers
+    // threads, calling pacer.pace() before each removal.
     fn gc(
         &mut self,
         reachability_db_path: &std::path::Path,
         reachability_db: &mut rusqlite::Connection,
+        pacer: &repository::GCPacer,
+        sweep_workers: usize,
     ) -> Result<repository::GCStats, failure::Error>;
 
     // Add a chunk, potentially asynchronously. Does not overwrite existing
@@ -31,6 +40,11 @@ pub trait Engine {
     // in stable storage after a call to sync has returned. A backend
     // can use this to implement concurrent background writes.
     fn sync(&mut self) -> Result<(), failure::Error>;
+
+    // The number of chunks and bytes add_chunk actually stored, not counting
+    // chunks that already existed. Only accurate after a call to Engine::sync,
+    // None if the engine is unable to tell.
+    fn added_chunk_stats(&mut self) -> Option<(usize, usize)>;
 }
 
 impl htree::Sink for Box<dyn Engine> {
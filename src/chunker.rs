@@ -1,15 +1,237 @@
-use super::rollsum::{Rollsum, WINDOW_SIZE};
+use super::fastcdc;
+use super::htree;
+use super::protocol;
+use super::rollsum::{self, Rollsum};
 
-pub struct RollsumChunker {
-    rs: Rollsum,
+// XXX TODO these chunk parameters need to be investigated and tuned.
+pub const DEFAULT_MIN_CHUNK_SIZE: usize = 256 * 1024;
+pub const DEFAULT_MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+pub const DEFAULT_CHUNK_MASK: u32 = 0x000f_ffff;
+
+// Fixed size chunks must keep to the block boundaries of disks and disk images.
+pub const DEFAULT_FIXED_CHUNK_SIZE: usize = 1024 * 1024;
+pub const FIXED_CHUNK_ALIGNMENT: usize = 4096;
+
+// Chunks are sent in a single packet, leave plenty of room for the
+// encryption overhead and packet header.
+pub const MAX_CHUNK_SIZE: usize = protocol::DEFAULT_MAX_PACKET_SIZE - 1024 * 1024;
+
+// The smallest max chunk size we are willing to scale down to when
+// working within a memory budget.
+pub const MIN_BUDGET_MAX_CHUNK_SIZE: usize = 512 * 1024;
+
+// Roughly how many max sized chunks may be buffered at once while sending,
+// across the data and index chunkers, hash tree levels and encryption buffers.
+const BUFFERED_CHUNKS_PER_SEND: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkAlgorithm {
+    Rollsum,
+    FastCdc,
+    // Every chunk is max_size bytes, see put --chunker fixed.
+    Fixed,
+}
+
+impl std::str::FromStr for ChunkAlgorithm {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<ChunkAlgorithm, failure::Error> {
+        match s {
+            "rollsum" => Ok(ChunkAlgorithm::Rollsum),
+            "fastcdc" => Ok(ChunkAlgorithm::FastCdc),
+            "fixed" => Ok(ChunkAlgorithm::Fixed),
+            _ => failure::bail!(
+                "unknown chunking algorithm '{}', expected 'rollsum', 'fastcdc' or 'fixed'",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for ChunkAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkAlgorithm::Rollsum => write!(f, "rollsum"),
+            ChunkAlgorithm::FastCdc => write!(f, "fastcdc"),
+            ChunkAlgorithm::Fixed => write!(f, "fixed"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkingParams {
+    pub algorithm: ChunkAlgorithm,
+    pub min_size: usize,
+    pub max_size: usize,
+    pub chunk_mask: u32,
+}
+
+impl Default for ChunkingParams {
+    fn default() -> Self {
+        ChunkingParams {
+            algorithm: ChunkAlgorithm::Rollsum,
+            min_size: DEFAULT_MIN_CHUNK_SIZE,
+            max_size: DEFAULT_MAX_CHUNK_SIZE,
+            chunk_mask: DEFAULT_CHUNK_MASK,
+        }
+    }
+}
+
+impl ChunkingParams {
+    pub fn fixed(size: usize) -> ChunkingParams {
+        ChunkingParams {
+            algorithm: ChunkAlgorithm::Fixed,
+            max_size: size,
+            ..Default::default()
+        }
+    }
+
+    // Scale the chunk sizes down so the buffers used while sending fit
+    // into approximately 'budget' bytes, never scaling above the defaults.
+    pub fn with_memory_budget(budget: usize) -> Result<ChunkingParams, failure::Error> {
+        let max_size = std::cmp::min(budget / BUFFERED_CHUNKS_PER_SEND, DEFAULT_MAX_CHUNK_SIZE);
+        if max_size < MIN_BUDGET_MAX_CHUNK_SIZE {
+            failure::bail!(
+                "memory budget too small, must be at least {} bytes",
+                MIN_BUDGET_MAX_CHUNK_SIZE * BUFFERED_CHUNKS_PER_SEND
+            );
+        }
+        // Round down to a power of two and keep the same ratios as the default parameters.
+        let max_size = 1usize << (63 - (max_size as u64).leading_zeros());
+        let min_size = max_size / (DEFAULT_MAX_CHUNK_SIZE / DEFAULT_MIN_CHUNK_SIZE);
+        let chunk_mask = ((max_size / 8) - 1) as u32;
+        Ok(ChunkingParams {
+            algorithm: ChunkAlgorithm::Rollsum,
+            min_size,
+            max_size,
+            chunk_mask,
+        })
+    }
+
+    // Check parameters given on the command line, the max size
+    // also limits the size of hash tree blocks.
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        if self.max_size < htree::MINIMUM_ADDR_CHUNK_SIZE || self.max_size > MAX_CHUNK_SIZE {
+            failure::bail!(
+                "max chunk size must be between {} and {} bytes",
+                htree::MINIMUM_ADDR_CHUNK_SIZE,
+                MAX_CHUNK_SIZE
+            );
+        }
+        // The min size and mask are unused.
+        if self.algorithm == ChunkAlgorithm::Fixed {
+            if !self.max_size.is_multiple_of(FIXED_CHUNK_ALIGNMENT) {
+                failure::bail!(
+                    "fixed chunk size must be a multiple of {} bytes",
+                    FIXED_CHUNK_ALIGNMENT
+                );
+            }
+            return Ok(());
+        }
+        if self.min_size == 0 || self.min_size >= self.max_size {
+            failure::bail!(
+                "min chunk size must be greater than zero and less than the max chunk size"
+            );
+        }
+        if self.chunk_mask == 0
+            || self.chunk_mask.count_ones() + self.chunk_mask.leading_zeros() != 32
+        {
+            failure::bail!("chunk mask must be a non empty run of low bits");
+        }
+        Ok(())
+    }
+
+    pub fn chunker(&self) -> Chunker {
+        let hash = match self.algorithm {
+            ChunkAlgorithm::Rollsum => {
+                ChunkHash::Rollsum(Rollsum::new_with_chunk_mask(self.chunk_mask))
+            }
+            ChunkAlgorithm::FastCdc => {
+                ChunkHash::FastCdc(fastcdc::Gear::new(self.chunk_mask, self.min_size))
+            }
+            ChunkAlgorithm::Fixed => return Chunker::new(ChunkHash::Fixed, 0, self.max_size),
+        };
+        Chunker::new(hash, self.min_size, self.max_size)
+    }
+
+    // Recorded in the send log, chunks cached for files are only
+    // reused while the parameters that split them stay the same.
+    pub fn description(&self) -> String {
+        format!(
+            "{} {} {} {:x}",
+            self.algorithm, self.min_size, self.max_size, self.chunk_mask
+        )
+    }
+
+    // How much data to read from a file or stream at a time.
+    pub fn read_buffer_size(&self) -> usize {
+        std::cmp::min(self.max_size / 8, 1024 * 1024)
+    }
+}
+
+// The rolling hash that decides where chunks end.
+#[derive(Clone)]
+pub enum ChunkHash {
+    Rollsum(Rollsum),
+    FastCdc(fastcdc::Gear),
+    // Never finds a boundary, chunks are split at the max size.
+    Fixed,
+}
+
+impl ChunkHash {
+    // Bytes that must be rolled before a boundary no longer depends on earlier bytes.
+    fn window_size(&self) -> usize {
+        match self {
+            ChunkHash::Rollsum(_) => rollsum::WINDOW_SIZE,
+            ChunkHash::FastCdc(_) => fastcdc::WINDOW_SIZE,
+            ChunkHash::Fixed => 0,
+        }
+    }
+
+    #[inline(always)]
+    fn roll_byte(&mut self, b: u8, len: usize) -> bool {
+        match self {
+            ChunkHash::Rollsum(rs) => rs.roll_byte(b),
+            ChunkHash::FastCdc(gear) => gear.roll_byte(b, len),
+            ChunkHash::Fixed => false,
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            ChunkHash::Rollsum(rs) => rs.reset(),
+            ChunkHash::FastCdc(gear) => gear.reset(),
+            ChunkHash::Fixed => (),
+        }
+    }
+}
+
+impl From<Rollsum> for ChunkHash {
+    fn from(rs: Rollsum) -> ChunkHash {
+        ChunkHash::Rollsum(rs)
+    }
+}
+
+pub struct Chunker {
+    rs: ChunkHash,
     min_sz: usize,
     max_sz: usize,
     default_chunk_capacity: usize,
     cur_vec: Vec<u8>,
+    mark: Option<ChunkerMark>,
 }
 
-impl RollsumChunker {
-    pub fn new(mut rs: Rollsum, mut min_sz: usize, mut max_sz: usize) -> RollsumChunker {
+// The chunker state saved by mark, the buffered bytes are only
+// copied once a chunk is split off, as until then they are unchanged.
+struct ChunkerMark {
+    rs: ChunkHash,
+    len: usize,
+    buffered: Option<Vec<u8>>,
+}
+
+impl Chunker {
+    pub fn new(rs: impl Into<ChunkHash>, mut min_sz: usize, mut max_sz: usize) -> Chunker {
+        let mut rs = rs.into();
         if min_sz == 0 {
             min_sz = 1
         }
@@ -18,12 +240,13 @@ impl RollsumChunker {
         }
         let default_chunk_capacity = max_sz / 2;
         rs.reset();
-        RollsumChunker {
+        Chunker {
             rs,
             min_sz,
             max_sz,
             default_chunk_capacity,
             cur_vec: Vec::with_capacity(default_chunk_capacity),
+            mark: None,
         }
     }
 
@@ -34,9 +257,37 @@ impl RollsumChunker {
     fn swap_vec(&mut self) -> Vec<u8> {
         let mut v = Vec::with_capacity(self.default_chunk_capacity);
         std::mem::swap(&mut v, &mut self.cur_vec);
+        if let Some(ref mut mark) = self.mark {
+            if mark.buffered.is_none() {
+                mark.buffered = Some(v[..mark.len].to_vec());
+            }
+        }
         v
     }
 
+    // Remember the current state so rewind can undo bytes added after this point.
+    pub fn mark(&mut self) {
+        self.mark = Some(ChunkerMark {
+            rs: self.rs.clone(),
+            len: self.cur_vec.len(),
+            buffered: None,
+        });
+    }
+
+    pub fn clear_mark(&mut self) {
+        self.mark = None;
+    }
+
+    // Restore the state saved by mark, chunks split off since then must be discarded by the caller.
+    pub fn rewind(&mut self) {
+        let mark = self.mark.take().expect("rewind without mark");
+        match mark.buffered {
+            Some(buffered) => self.cur_vec = buffered,
+            None => self.cur_vec.truncate(mark.len),
+        }
+        self.rs = mark.rs;
+    }
+
     pub fn add_bytes(&mut self, buf: &[u8]) -> (usize, Option<Vec<u8>>) {
         debug_assert!(self.cur_vec.len() < self.max_sz);
 
@@ -60,11 +311,20 @@ impl RollsumChunker {
             debug_assert!(self.spare_capacity() >= n_bytes);
         }
 
+        if let ChunkHash::Fixed = self.rs {
+            self.cur_vec.extend_from_slice(&buf[0..n_bytes]);
+            if self.cur_vec.len() == self.max_sz {
+                return (n_bytes, Some(self.swap_vec()));
+            }
+            return (n_bytes, None);
+        }
+
         // None of the bytes we are adding will count towards the
         // next chunk, simply add them all, the bytes don't matter
-        // as we will cycle WINDOW_SIZE too.
-        if self.min_sz >= WINDOW_SIZE
-            && (self.cur_vec.len() + n_bytes < (self.min_sz - WINDOW_SIZE))
+        // as we will cycle the window size too.
+        let window_size = self.rs.window_size();
+        if self.min_sz >= window_size
+            && (self.cur_vec.len() + n_bytes < (self.min_sz - window_size))
         {
             self.cur_vec.extend_from_slice(&buf[0..n_bytes]);
             return (n_bytes, None);
@@ -74,7 +334,7 @@ impl RollsumChunker {
         for b in buf[0..n_bytes].iter() {
             self.cur_vec.push(*b);
             n_added += 1;
-            if (self.rs.roll_byte(*b) && self.cur_vec.len() > self.min_sz)
+            if (self.rs.roll_byte(*b, self.cur_vec.len()) && self.cur_vec.len() > self.min_sz)
                 || self.cur_vec.len() == self.max_sz
             {
                 return (n_added, Some(self.swap_vec()));
@@ -106,10 +366,83 @@ impl RollsumChunker {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_chunking_params_memory_budget() {
+        assert_eq!(
+            ChunkingParams::with_memory_budget(1024 * 1024 * 1024).unwrap(),
+            ChunkingParams::default()
+        );
+        let params = ChunkingParams::with_memory_budget(48 * 1024 * 1024).unwrap();
+        assert_eq!(params.max_size, 2 * 1024 * 1024);
+        assert_eq!(params.min_size, 64 * 1024);
+        assert_eq!(params.chunk_mask, 0x0003_ffff);
+        assert!(ChunkingParams::with_memory_budget(1024 * 1024).is_err());
+    }
+
+    #[test]
+    fn test_chunking_params_validate() {
+        assert!(ChunkingParams::default().validate().is_ok());
+        assert!(ChunkingParams::with_memory_budget(48 * 1024 * 1024)
+            .unwrap()
+            .validate()
+            .is_ok());
+        let params = ChunkingParams {
+            min_size: 4096,
+            max_size: 65536,
+            chunk_mask: 0x3fff,
+            ..Default::default()
+        };
+        assert!(params.validate().is_ok());
+        assert!(ChunkingParams {
+            min_size: 65536,
+            ..params
+        }
+        .validate()
+        .is_err());
+        assert!(ChunkingParams {
+            max_size: MAX_CHUNK_SIZE + 1,
+            ..params
+        }
+        .validate()
+        .is_err());
+        assert!(ChunkingParams {
+            min_size: 1,
+            max_size: 32,
+            ..params
+        }
+        .validate()
+        .is_err());
+        assert!(ChunkingParams {
+            chunk_mask: 0xf0,
+            ..params
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_fixed_chunks() {
+        let params = ChunkingParams::fixed(8192);
+        assert!(params.validate().is_ok());
+        assert!(ChunkingParams::fixed(8191).validate().is_err());
+        let mut ch = params.chunker();
+        let data = vec![7u8; 20000];
+        let mut chunks = Vec::new();
+        let mut buf = &data[..];
+        while !buf.is_empty() {
+            let (n, chunk) = ch.add_bytes(&buf[..std::cmp::min(3000, buf.len())]);
+            chunks.extend(chunk);
+            buf = &buf[n..];
+        }
+        chunks.push(ch.finish());
+        let sizes: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![8192, 8192, 3616]);
+    }
+
     #[test]
     fn test_add_bytes() {
         let rs = Rollsum::new();
-        let mut ch = RollsumChunker::new(rs, 1, 2);
+        let mut ch = Chunker::new(rs, 1, 2);
 
         match ch.add_bytes(b"a") {
             (1, None) => (),
@@ -129,10 +462,34 @@ mod tests {
         assert_eq!(ch.finish(), b"c");
     }
 
+    #[test]
+    fn test_rewind() {
+        let rs = Rollsum::new();
+        let mut ch = Chunker::new(rs, 1, 3);
+        ch.add_bytes(b"a");
+        ch.add_bytes(b"b");
+        ch.mark();
+        ch.add_bytes(b"c");
+        ch.rewind();
+        assert_eq!(ch.buffered_count(), 2);
+
+        ch.mark();
+        match ch.add_bytes(b"c") {
+            (1, Some(v)) => assert_eq!(v, b"abc"),
+            v => panic!("{:?}", v),
+        }
+        ch.add_bytes(b"d");
+        ch.rewind();
+        match ch.add_bytes(b"x") {
+            (1, Some(v)) => assert_eq!(v, b"abx"),
+            v => panic!("{:?}", v),
+        }
+    }
+
     #[test]
     fn test_force_split_bytes() {
         let rs = Rollsum::new();
-        let mut ch = RollsumChunker::new(rs, 10, 100);
+        let mut ch = Chunker::new(rs, 10, 100);
         assert_eq!(ch.force_split(), None);
         ch.add_bytes(b"abc");
 
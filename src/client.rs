@@ -1,3 +1,4 @@
+use super::acl;
 use super::address::*;
 use super::chunker;
 use super::crypto;
@@ -8,54 +9,69 @@ use super::itemset;
 use super::protocol::*;
 use super::querycache;
 use super::repository;
-use super::rollsum;
 use super::sendlog;
+use super::tagschema;
 use super::xid::*;
 use super::xtar;
 use failure::Fail;
 use std::collections::BTreeMap;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::fs::MetadataExt;
-use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::fs::PermissionsExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 
 #[derive(Debug, Fail)]
 pub enum ClientError {
     #[fail(display = "corrupt or tampered data")]
     CorruptOrTamperedDataError,
+    #[fail(display = "corrupt or tampered data in chunk {}", _0)]
+    CorruptOrTamperedChunkError(Address),
 }
 
 pub fn open_repository(
+    progress: &indicatif::ProgressBar,
     w: &mut dyn std::io::Write,
     r: &mut dyn std::io::Read,
     lock_hint: LockHint,
-) -> Result<(), failure::Error> {
+) -> Result<ROpenRepository, failure::Error> {
     write_packet(
         w,
         &Packet::TOpenRepository(TOpenRepository {
-            repository_protocol_version: "1".to_string(),
+            repository_protocol_version: PROTOCOL_VERSION.to_string(),
             lock_hint,
         }),
     )?;
 
-    match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
-        Packet::ROpenRepository(resp) => {
-            let clock_skew = chrono::Utc::now().signed_duration_since(resp.now);
-            const MAX_SKEW_MINS: i64 = 15;
-            if clock_skew > chrono::Duration::minutes(MAX_SKEW_MINS)
-                || clock_skew < chrono::Duration::minutes(-MAX_SKEW_MINS)
-            {
-                // This helps protect against inaccurate item timestamps, which protects users from unintentionally
-                // deleting important backups when deleting based on timestamp queries. Instead they will be notified
-                // of the clock mismatch as soon as we know about it.
-                failure::bail!("server and client have clock skew larger than {} minutes, refusing connection.", MAX_SKEW_MINS);
+    loop {
+        match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+            // The server tells us who holds the repository lock if we must wait for it.
+            // This is printed even when progress is hidden, it explains why we are stalled.
+            Packet::Progress(Progress::Notice(msg)) => {
+                if progress.is_hidden() {
+                    eprintln!("{}", msg);
+                } else {
+                    progress.println(&msg);
+                }
             }
+            Packet::Progress(Progress::SetMessage(msg)) => progress.set_message(&msg),
+            Packet::ROpenRepository(resp) => {
+                let clock_skew = chrono::Utc::now().signed_duration_since(resp.now);
+                const MAX_SKEW_MINS: i64 = 15;
+                if clock_skew > chrono::Duration::minutes(MAX_SKEW_MINS)
+                    || clock_skew < chrono::Duration::minutes(-MAX_SKEW_MINS)
+                {
+                    // This helps protect against inaccurate item timestamps, which protects users from unintentionally
+                    // deleting important backups when deleting based on timestamp queries. Instead they will be notified
+                    // of the clock mismatch as soon as we know about it.
+                    failure::bail!("server and client have clock skew larger than {} minutes, refusing connection.", MAX_SKEW_MINS);
+                }
+                return Ok(resp);
+            }
+            _ => failure::bail!("protocol error, expected begin ack packet"),
         }
-        _ => failure::bail!("protocol error, expected begin ack packet"),
     }
-
-    Ok(())
 }
 
 pub fn init_repository(
@@ -70,14 +86,200 @@ pub fn init_repository(
     }
 }
 
+// Read after write verification of a random sample of sent chunks.
+struct ChunkVerifier {
+    sample_rate: f64,
+    data_dctx: crypto::DecryptionContext,
+    hash_key: crypto::HashKey,
+    pending: Vec<Address>,
+}
+
+// Client side totals of a put, see put --print-stats.
+#[derive(Default, Clone, Debug)]
+pub struct PutStats {
+    // Chunks and their encrypted bytes written to the repository.
+    pub chunks_sent: u64,
+    pub bytes_sent: u64,
+    // Chunks not sent because the send log knew they were already in the repository.
+    pub chunks_deduped: u64,
+}
+
+pub type SharedPutStats = std::sync::Arc<std::sync::Mutex<PutStats>>;
+
+// Newline delimited JSON progress events written to stderr in place
+// of the progress bar, so other programs can show progress, see put --progress.
+#[derive(Clone)]
+pub struct JsonProgress {
+    progress: indicatif::ProgressBar,
+    stats: SharedPutStats,
+    last_event: std::sync::Arc<std::sync::Mutex<Option<std::time::Instant>>>,
+}
+
+// Progress events for single files are dropped if they come faster than this.
+const JSON_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+impl JsonProgress {
+    // Bytes read are taken from the position of progress, which should be hidden.
+    pub fn new(progress: indicatif::ProgressBar, stats: SharedPutStats) -> JsonProgress {
+        JsonProgress {
+            progress,
+            stats,
+            last_event: Default::default(),
+        }
+    }
+
+    fn emit(&self, event: serde_json::Value) {
+        eprintln!("{}", event);
+    }
+
+    fn counters(&self, event: &str) -> serde_json::Value {
+        let stats = self.stats.lock().unwrap();
+        serde_json::json!({
+            "event": event,
+            "bytes_read": self.progress.position(),
+            "chunks_sent": stats.chunks_sent,
+            "bytes_sent": stats.bytes_sent,
+            "chunks_deduped": stats.chunks_deduped,
+        })
+    }
+
+    pub fn phase(&self, phase: &str) {
+        self.emit(serde_json::json!({"event": "phase", "phase": phase}));
+    }
+
+    pub fn path(&self, path: &std::path::Path) {
+        let mut last_event = self.last_event.lock().unwrap();
+        let now = std::time::Instant::now();
+        if let Some(last_event) = *last_event {
+            if now.duration_since(last_event) < JSON_PROGRESS_INTERVAL {
+                return;
+            }
+        }
+        *last_event = Some(now);
+        let mut event = self.counters("progress");
+        event["path"] = path.to_string_lossy().into();
+        self.emit(event);
+    }
+
+    // The put summary, including what the repository reported.
+    pub fn done(&self, ack: &RAddItem, elapsed: std::time::Duration) {
+        let mut event = self.counters("done");
+        event["id"] = ack.item_id.to_string().into();
+        event["elapsed_seconds"] = elapsed.as_secs_f64().into();
+        event["chunks_received"] = ack.stats.chunks_received.into();
+        event["bytes_received"] = ack.stats.bytes_received.into();
+        if let Some(chunks_added) = ack.stats.chunks_added {
+            event["chunks_added"] = chunks_added.into();
+        }
+        if let Some(bytes_added) = ack.stats.bytes_added {
+            event["bytes_added"] = bytes_added.into();
+        }
+        self.emit(event);
+    }
+}
+
+// Show the path being sent in the progress bar and any JSON progress.
+fn report_path(ctx: &SendContext, path: &std::path::Path) {
+    ctx.progress.set_message(&path.to_string_lossy());
+    if let Some(ref json_progress) = ctx.json_progress {
+        json_progress.path(path);
+    }
+}
+
+fn report_phase(ctx: &SendContext, phase: &str, msg: &str) {
+    ctx.progress.set_message(msg);
+    if let Some(ref json_progress) = ctx.json_progress {
+        json_progress.phase(phase);
+    }
+}
+
 struct ConnectionHtreeSink<'a, 'b> {
     checkpoint_bytes: u64,
     dirty_bytes: u64,
+    // Slow uploads checkpoint after this long, even before checkpoint_bytes are sent.
+    checkpoint_interval: Option<std::time::Duration>,
+    last_checkpoint: std::time::Instant,
+    verifier: Option<ChunkVerifier>,
+    stats: SharedPutStats,
     send_log_session: &'a Option<std::cell::RefCell<sendlog::SendLogSession<'b>>>,
     r: &'a mut dyn std::io::Read,
     w: &'a mut dyn std::io::Write,
 }
 
+impl<'a, 'b> ConnectionHtreeSink<'a, 'b> {
+    fn write_chunk(&mut self, addr: &Address, data: Vec<u8>) -> Result<(), failure::Error> {
+        if let Some(ref mut verifier) = self.verifier {
+            if (crypto::randombytes_uniform(1_000_000) as f64) < verifier.sample_rate * 1_000_000.0
+            {
+                verifier.pending.push(*addr);
+            }
+        }
+
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.chunks_sent += 1;
+            stats.bytes_sent += data.len() as u64;
+        }
+
+        write_packet(
+            self.w,
+            &Packet::Chunk(Chunk {
+                address: *addr,
+                data,
+            }),
+        )
+    }
+
+    // Ask the server to flush all sent chunks to storage, then read back
+    // any chunks selected for verification and check them against their address.
+    fn sync(&mut self) -> Result<(), failure::Error> {
+        self.dirty_bytes = 0;
+        self.last_checkpoint = std::time::Instant::now();
+        write_packet(self.w, &Packet::TSendSync)?;
+        match read_packet(self.r, DEFAULT_MAX_PACKET_SIZE)? {
+            Packet::RSendSync => (),
+            _ => failure::bail!("protocol error, expected RSentSync packet"),
+        }
+
+        if let Some(ref mut verifier) = self.verifier {
+            for addr in verifier.pending.drain(..) {
+                write_packet(self.w, &Packet::TRequestChunk(addr))?;
+                let data = match read_packet(self.r, DEFAULT_MAX_PACKET_SIZE)? {
+                    Packet::RRequestChunk(data) => data,
+                    _ => failure::bail!("protocol error, expected RRequestChunk packet"),
+                };
+                // Sent chunks are either unencrypted hash tree nodes or encrypted data.
+                let verified = addr == htree::tree_block_address(&data)
+                    || match verifier.data_dctx.decrypt_data(data) {
+                        Ok(data) => {
+                            addr == crypto::keyed_content_address(&data, &verifier.hash_key)
+                        }
+                        Err(_) => false,
+                    };
+                if !verified {
+                    failure::bail!(
+                        "read after write verification failed, chunk {} is corrupt in the repository",
+                        addr
+                    );
+                }
+            }
+        }
+
+        if let Some(ref send_log_session) = self.send_log_session {
+            send_log_session.borrow_mut().checkpoint()?;
+        }
+
+        Ok(())
+    }
+
+    fn has_pending_verifications(&self) -> bool {
+        match self.verifier {
+            Some(ref verifier) => !verifier.pending.is_empty(),
+            None => false,
+        }
+    }
+}
+
 impl<'a, 'b> htree::Sink for ConnectionHtreeSink<'a, 'b> {
     fn add_chunk(
         &mut self,
@@ -86,44 +288,28 @@ impl<'a, 'b> htree::Sink for ConnectionHtreeSink<'a, 'b> {
     ) -> std::result::Result<(), failure::Error> {
         match self.send_log_session {
             Some(ref send_log_session) => {
-                let mut send_log_session = send_log_session.borrow_mut();
-                if send_log_session.cached_address(addr)? {
-                    send_log_session.add_address(addr)?;
+                if send_log_session.borrow_mut().cached_address(addr)? {
+                    send_log_session.borrow_mut().add_address(addr)?;
+                    self.stats.lock().unwrap().chunks_deduped += 1;
                 } else {
                     self.dirty_bytes += data.len() as u64;
-                    write_packet(
-                        self.w,
-                        &Packet::Chunk(Chunk {
-                            address: *addr,
-                            data,
-                        }),
-                    )?;
-                    send_log_session.add_address(addr)?;
+                    self.write_chunk(addr, data)?;
+                    send_log_session.borrow_mut().add_address(addr)?;
                 }
 
-                if self.dirty_bytes >= self.checkpoint_bytes {
-                    self.dirty_bytes = 0;
-                    write_packet(self.w, &Packet::TSendSync)?;
-                    match read_packet(self.r, DEFAULT_MAX_PACKET_SIZE)? {
-                        Packet::RSendSync => {
-                            send_log_session.checkpoint()?;
-                        }
-                        _ => failure::bail!("protocol error, expected RSentSync packet"),
+                let checkpoint_due = match self.checkpoint_interval {
+                    Some(interval) => {
+                        self.dirty_bytes > 0 && self.last_checkpoint.elapsed() >= interval
                     }
+                    None => false,
+                };
+                if self.dirty_bytes >= self.checkpoint_bytes || checkpoint_due {
+                    self.sync()?;
                 }
 
                 Ok(())
             }
-            None => {
-                write_packet(
-                    self.w,
-                    &Packet::Chunk(Chunk {
-                        address: *addr,
-                        data,
-                    }),
-                )?;
-                Ok(())
-            }
+            None => self.write_chunk(addr, data),
         }
     }
 }
@@ -131,47 +317,549 @@ impl<'a, 'b> htree::Sink for ConnectionHtreeSink<'a, 'b> {
 pub struct SendContext {
     pub progress: indicatif::ProgressBar,
     pub compression: crypto::DataCompression,
+    // Send files that look already compressed without compressing them again.
+    pub skip_incompressible: bool,
     pub use_stat_cache: bool,
+    pub stat_cache_check: StatCacheCheck,
     pub primary_key_id: Xid,
     pub send_key_id: Xid,
     pub hash_key: crypto::HashKey,
     pub data_ectx: crypto::EncryptionContext,
     pub metadata_ectx: crypto::EncryptionContext,
     pub checkpoint_bytes: u64,
+    pub checkpoint_interval: Option<std::time::Duration>,
+    pub chunking: chunker::ChunkingParams,
+    pub verify_sample_rate: f64,
+    // Required to verify sent chunks, only available when sending with a primary key.
+    pub data_dctx: Option<crypto::DecryptionContext>,
+    // Regular files with a ctime and mtime before this are recorded in
+    // the index as unchanged instead of being sent.
+    pub changed_since: Option<chrono::DateTime<chrono::Utc>>,
+    // Skip files and directories with the nodump flag set.
+    pub honor_nodump: bool,
+    // Record SELinux contexts in the tar headers.
+    pub selinux: bool,
+    // Record POSIX ACLs in the tar headers and the index.
+    pub acls: bool,
+    // Read exclusions from IGNORE_FILE_NAME files in each directory sent.
+    pub ignore_files: bool,
+    // Skip directories containing a CACHEDIR.TAG file.
+    pub exclude_caches: bool,
+    // Zero times and owners in the tar headers, see put --deterministic.
+    pub deterministic: bool,
+    // Don't descend into directories on other filesystems.
+    pub one_file_system: bool,
+    // Send what symlinks point to instead of the links themselves.
+    pub follow_symlinks: bool,
+    // Threads used to stat directory entries, see put --stat-workers.
+    pub stat_workers: usize,
+    // See tag_address, the repository rejects the item if
+    // any of these are already in use by another item.
+    pub unique_tags: Vec<Address>,
+    // Current items with any of these tag addresses are removed
+    // in the same transaction that adds the new item.
+    pub replace_tags: Vec<Address>,
+    // Told to the repository in plain text so gc can remove the item.
+    pub expires: Option<chrono::DateTime<chrono::Utc>>,
+    // Checked along with the repository tag schema, see check_tags.
+    pub tag_schema: Option<tagschema::TagSchema>,
+    // When set, directory indexes are delta encoded against this index.
+    pub index_delta_base: Option<IndexDeltaBase>,
+    // Check each file is unchanged after reading it, see put --verify-reads.
+    pub verify_reads: Option<VerifyReads>,
+    // Only read what was appended to files since the last put, see put --tail-deltas.
+    pub tail_deltas: bool,
+    // What to do with files that cannot be opened, see put --open-errors.
+    pub open_errors: OpenErrors,
+    // Files left out because they could not be opened.
+    pub skipped_files: std::collections::BTreeSet<std::path::PathBuf>,
+    // Hashes and encrypts file data on other threads, see put --send-workers.
+    pub chunk_workers: Option<ChunkWorkers>,
+    // Progress events for other programs, see put --progress.
+    pub json_progress: Option<JsonProgress>,
+    pub stats: SharedPutStats,
+}
+
+// A pool of threads that each run a job at a time. Results are handed
+// back in the order the jobs were added.
+pub struct OrderedWorkers<J, R> {
+    job_tx: Option<crossbeam_channel::Sender<(u64, J)>>,
+    done_rx: crossbeam_channel::Receiver<(u64, R)>,
+    handles: Vec<std::thread::JoinHandle<()>>,
+    done: BTreeMap<u64, R>,
+    next_seq: u64,
+    next_out: u64,
+    max_in_flight: u64,
+}
+
+impl<J: Send + 'static, R: Send + 'static> OrderedWorkers<J, R> {
+    // new_work is called once for each worker, so each can own its own state.
+    fn start<W>(
+        n_workers: usize,
+        max_in_flight: usize,
+        mut new_work: impl FnMut() -> W,
+    ) -> Result<OrderedWorkers<J, R>, failure::Error>
+    where
+        W: FnMut(J) -> R + Send + 'static,
+    {
+        let (job_tx, job_rx) = crossbeam_channel::bounded::<(u64, J)>(n_workers * 2);
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+        let mut handles = Vec::with_capacity(n_workers);
+
+        for _ in 0..n_workers {
+            let job_rx = job_rx.clone();
+            let done_tx = done_tx.clone();
+            let mut work = new_work();
+            handles.push(std::thread::Builder::new().spawn(move || {
+                while let Ok((seq, job)) = job_rx.recv() {
+                    if done_tx.send((seq, work(job))).is_err() {
+                        break;
+                    }
+                }
+            })?);
+        }
+
+        Ok(OrderedWorkers {
+            job_tx: Some(job_tx),
+            done_rx,
+            handles,
+            done: BTreeMap::new(),
+            next_seq: 0,
+            next_out: 0,
+            max_in_flight: max_in_flight as u64,
+        })
+    }
+
+    fn add(&mut self, job: J) -> Result<(), failure::Error> {
+        if self
+            .job_tx
+            .as_ref()
+            .unwrap()
+            .send((self.next_seq, job))
+            .is_err()
+        {
+            failure::bail!("worker thread exited unexpectedly");
+        }
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    // A result that is ready already keeps its place in the order.
+    fn add_done(&mut self, result: R) {
+        self.done.insert(self.next_seq, result);
+        self.next_seq += 1;
+    }
+
+    // Drop the results of every job added so far, waiting for those still in flight.
+    fn discard_in_flight(&mut self) -> Result<(), failure::Error> {
+        let n_owed = self.next_seq - self.next_out - self.done.len() as u64;
+        for _ in 0..n_owed {
+            if self.done_rx.recv().is_err() {
+                failure::bail!("worker thread exited unexpectedly");
+            }
+        }
+        self.done.clear();
+        self.next_out = self.next_seq;
+        Ok(())
+    }
+
+    // The next result in order, if it is done. Waits for it when wait is set
+    // or too many jobs are in flight, None once no jobs are in flight.
+    fn next_done(&mut self, wait: bool) -> Result<Option<R>, failure::Error> {
+        loop {
+            if let Some(done) = self.done.remove(&self.next_out) {
+                self.next_out += 1;
+                return Ok(Some(done));
+            }
+            let in_flight = self.next_seq - self.next_out;
+            if in_flight == 0 {
+                return Ok(None);
+            }
+            let (seq, done) = if wait || in_flight >= self.max_in_flight {
+                match self.done_rx.recv() {
+                    Ok(done) => done,
+                    Err(_) => failure::bail!("worker thread exited unexpectedly"),
+                }
+            } else {
+                match self.done_rx.try_recv() {
+                    Ok(done) => done,
+                    Err(crossbeam_channel::TryRecvError::Empty) => return Ok(None),
+                    Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                        failure::bail!("worker thread exited unexpectedly")
+                    }
+                }
+            };
+            self.done.insert(seq, done);
+        }
+    }
+}
+
+impl<J, R> Drop for OrderedWorkers<J, R> {
+    fn drop(&mut self) {
+        drop(self.job_tx.take());
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+// A hashed and encrypted chunk, with the length of its plain text.
+type DoneChunk = (Address, usize, Vec<u8>);
+
+// Threads that hash, compress and encrypt chunks. Chunks are handed back in the
+// order they were added so the tree stays the same as a single threaded send would produce.
+pub type ChunkWorkers = OrderedWorkers<(Vec<u8>, crypto::DataCompression), DoneChunk>;
+
+impl ChunkWorkers {
+    pub fn new(
+        n_workers: usize,
+        hash_key: &crypto::HashKey,
+        ectx: &crypto::EncryptionContext,
+    ) -> Result<ChunkWorkers, failure::Error> {
+        OrderedWorkers::start(
+            n_workers,
+            // Enough to keep every worker busy while we wait on the oldest chunk.
+            n_workers * 2,
+            || {
+                let hash_key = hash_key.clone();
+                let mut ectx = ectx.fork();
+                move |(chunk_data, compression): (Vec<u8>, crypto::DataCompression)| {
+                    let addr = crypto::keyed_content_address(&chunk_data, &hash_key);
+                    let chunk_len = chunk_data.len();
+                    (addr, chunk_len, ectx.encrypt_data(chunk_data, compression))
+                }
+            },
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyReads {
+    // Compare the file metadata before and after reading.
+    Stat,
+    // Also compare the first and last blocks with what was read.
+    Blocks,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpenErrors {
+    Abort,
+    // Leave the file out with a warning.
+    Skip,
+}
+
+// What decides the entries of a directory are unchanged so it can be
+// sent from the stat cache, see put --stat-cache-check.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatCacheCheck {
+    // The change time, which also changes with chown, chmod and hard linking.
+    Ctime,
+    // The modification time and size, which survive metadata churn but miss
+    // changes made by tools that reset the modification time.
+    MtimeSize,
+    // The stat cache is not used, every file is read and hashed again.
+    Rehash,
+}
+
+// Resolves the user and group names recorded in the index, trees
+// usually have few owners so each id is only looked up once.
+#[derive(Default)]
+struct OwnerNames {
+    users: std::collections::HashMap<u32, Option<String>>,
+    groups: std::collections::HashMap<u32, Option<String>>,
+}
+
+impl OwnerNames {
+    fn owner_names(&mut self, metadata: &std::fs::Metadata) -> index::EntryOwnerNames {
+        let (uid, gid) = (metadata.uid(), metadata.gid());
+        let user = self
+            .users
+            .entry(uid)
+            .or_insert_with(
+                || match nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid)) {
+                    Ok(Some(user)) => Some(user.name),
+                    _ => None,
+                },
+            )
+            .clone();
+        let group = self
+            .groups
+            .entry(gid)
+            .or_insert_with(|| {
+                match nix::unistd::Group::from_gid(nix::unistd::Gid::from_raw(gid)) {
+                    Ok(Some(group)) => Some(group.name),
+                    _ => None,
+                }
+            })
+            .clone();
+        index::EntryOwnerNames { user, group }
+    }
+}
+
+fn hash_stat_cache_metadata(
+    hash_state: &mut crypto::HashState,
+    check: StatCacheCheck,
+    metadata: &std::fs::Metadata,
+) {
+    match check {
+        StatCacheCheck::Ctime | StatCacheCheck::Rehash => {
+            hash_state.update(&metadata.ctime().to_le_bytes()[..]);
+            hash_state.update(&metadata.ctime_nsec().to_le_bytes()[..]);
+        }
+        StatCacheCheck::MtimeSize => {
+            hash_state.update(&metadata.mtime().to_le_bytes()[..]);
+            hash_state.update(&metadata.mtime_nsec().to_le_bytes()[..]);
+            hash_state.update(&metadata.size().to_le_bytes()[..]);
+        }
+    }
+}
+
+// How many times a file that keeps changing is sent again, and how many entries of
+// a directory are sent again or left out, before falling back to restarting the whole send.
+const MAX_FILE_RETRIES: usize = 5;
+const MAX_DIR_RETRIES: usize = 20;
+
+const VERIFY_BLOCK_SIZE: usize = 4096;
+
+// How many blocks of a file are compared to decide it was only appended to.
+const TAIL_DELTA_SAMPLES: u64 = 16;
+
+// Delta encoded indexes are rebuilt from every index in the chain when read,
+// so past this depth a full index is written instead.
+pub const MAX_INDEX_DELTA_DEPTH: usize = 16;
+
+pub struct IndexDeltaBase {
+    // The leaf chunks of the base index, reused as the start of the new index.
+    pub leaf_addresses: Vec<Address>,
+    pub index: Vec<index::VersionedIndexEntry>,
+    pub delta_depth: usize,
+    // The gc generation the base was read in, the leaf chunks may
+    // have been collected if the repository generation differs.
+    pub gc_generation: Option<Xid>,
+}
+
+// The server can't see our tags, so unique and replaced tags are matched using a keyed
+// hash of the tag, only clients with the same hash key can produce the same address.
+pub fn tag_address(hash_key: &crypto::HashKey, tag: &str, value: &str) -> Address {
+    let mut data = Vec::with_capacity(tag.len() + value.len() + 32);
+    data.extend_from_slice(b"bupstash-tag\0");
+    data.extend_from_slice(tag.as_bytes());
+    data.push(0);
+    data.extend_from_slice(value.as_bytes());
+    crypto::keyed_content_address(&data, hash_key)
+}
+
+// Tags are checked before sending, and again once the 'exec-status' tag is known.
+pub fn check_tags(
+    tag_schema: &Option<tagschema::TagSchema>,
+    repository_tag_schema: Option<&str>,
+    tags: &BTreeMap<String, String>,
+    note: &Option<String>,
+) -> Result<(), failure::Error> {
+    // No easy way to compute the tag set length without actually encoding it due
+    // to var ints in the bare encoding.
+    if serde_bare::to_vec(tags)?.len() + serde_bare::to_vec(note)?.len() > itemset::MAX_TAG_SET_SIZE
+    {
+        failure::bail!(
+            "tags and note must not exceed {} bytes",
+            itemset::MAX_TAG_SET_SIZE
+        );
+    }
+    if let Some(tag_schema) = tag_schema {
+        tag_schema.validate(tags)?;
+    }
+    if let Some(tag_schema) = repository_tag_schema {
+        tagschema::parse("repository tag schema", tag_schema)?.validate(tags)?;
+    }
+    Ok(())
 }
 
 pub enum DataSource {
-    Subprocess(Vec<String>),
+    Subprocess {
+        args: Vec<String>,
+        // Exit statuses other than 0 that are accepted, e.g. for commands that exit with warnings.
+        ok_statuses: Vec<i32>,
+        // Record the exit status in the 'exec-status' tag.
+        status_tag: bool,
+    },
     Readable {
         description: String,
         data: Box<dyn std::io::Read>,
     },
-    Directory {
+    // A regular file, an interrupted send of it can be resumed, see send_file.
+    File {
+        path: std::path::PathBuf,
+        data: std::fs::File,
+    },
+    // A disk read like a file, usually with fixed size chunks, see put --chunker.
+    BlockDevice {
         path: std::path::PathBuf,
+        data: std::fs::File,
+    },
+    Directory {
+        // Several paths are each sent under their own name.
+        paths: Vec<std::path::PathBuf>,
         exclusions: Vec<glob::Pattern>,
+        // When not empty, only files matching one of these, or inside a matching
+        // directory, are sent. Exclusions take precedence.
+        inclusions: Vec<glob::Pattern>,
+        // When set, only these paths (and their parent directories) are sent
+        // instead of walking the whole directory.
+        file_list: Option<Vec<std::path::PathBuf>>,
     },
 }
 
+// Runs the put hooks around a whole put, see put --pre-hook and --post-hook.
+#[derive(Default)]
+pub struct PutHooks {
+    post_hook: Option<String>,
+}
+
+impl PutHooks {
+    // Run before the data source is read or even resolved, so the hook can create it,
+    // such as by mounting a filesystem snapshot.
+    pub fn run_pre(
+        &mut self,
+        progress: &indicatif::ProgressBar,
+        pre_hook: Option<String>,
+        post_hook: Option<String>,
+    ) -> Result<(), failure::Error> {
+        // Set first, the post hook runs even if the pre hook failed, so it can always clean up.
+        self.post_hook = post_hook;
+        match pre_hook {
+            Some(ref pre_hook) => run_hook(progress, "pre", pre_hook, &[]),
+            None => Ok(()),
+        }
+    }
+
+    pub fn run_post(self, result: Result<Xid, failure::Error>) -> Result<Xid, failure::Error> {
+        let post_hook = match self.post_hook {
+            Some(post_hook) => post_hook,
+            None => return result,
+        };
+        let env = match result {
+            Ok(ref item_id) => vec![
+                ("BUPSTASH_PUT_STATUS", "ok".to_string()),
+                ("BUPSTASH_ITEM_ID", item_id.to_string()),
+            ],
+            Err(ref err) => vec![
+                ("BUPSTASH_PUT_STATUS", "failed".to_string()),
+                ("BUPSTASH_PUT_ERROR", err.to_string()),
+            ],
+        };
+        // The put progress bar is done, hidden bars forward output to stderr.
+        if let Err(err) = run_hook(&indicatif::ProgressBar::hidden(), "post", &post_hook, &env) {
+            match result {
+                Ok(_) => return Err(err),
+                // Report the error that caused the put to fail, and this one as a warning.
+                Err(_) => eprintln!("{}", err),
+            }
+        }
+        result
+    }
+}
+
+// Run a put hook with 'sh -c', its output is forwarded to stderr
+// so it does not mix with the item id printed to stdout.
+fn run_hook(
+    progress: &indicatif::ProgressBar,
+    name: &str,
+    command: &str,
+    env: &[(&str, String)],
+) -> Result<(), failure::Error> {
+    progress.set_message(&format!("running {} hook...", name));
+
+    let mut child = match std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => failure::bail!("unable to run {} hook: {}", name, err),
+    };
+
+    let stdout = child.stdout.take().unwrap();
+    let stdout_progress = progress.clone();
+    let stdout_thread = std::thread::spawn(move || forward_lines(stdout, &stdout_progress));
+    let stderr = child.stderr.take().unwrap();
+    let stderr_progress = progress.clone();
+    let stderr_thread = std::thread::spawn(move || forward_lines(stderr, &stderr_progress));
+    let status = child.wait()?;
+    let _ = stdout_thread.join();
+    let _ = stderr_thread.join();
+
+    if !status.success() {
+        failure::bail!("{} hook failed with {}", name, status);
+    }
+    Ok(())
+}
+
 pub fn send(
     ctx: &mut SendContext,
     r: &mut dyn std::io::Read,
     w: &mut dyn std::io::Write,
     mut send_log: Option<sendlog::SendLog>,
-    tags: BTreeMap<String, String>,
+    mut tags: BTreeMap<String, String>,
+    note: Option<String>,
     data: &mut DataSource,
-) -> Result<Xid, failure::Error> {
+) -> Result<RAddItem, failure::Error> {
+    // Taken before anything is read, so later puts using this item
+    // as a --changed-since reference don't miss files modified during this one.
+    let start_timestamp = chrono::Utc::now();
+
     let send_id = match send_log {
         Some(ref mut send_log) => send_log.last_send_id()?,
         None => None,
     };
 
-    write_packet(w, &Packet::TBeginSend(TBeginSend { delta_id: send_id }))?;
+    write_packet(
+        w,
+        &Packet::TBeginSend(TBeginSend {
+            delta_id: send_id,
+            verify_chunks: ctx.data_dctx.is_some() && ctx.verify_sample_rate > 0.0,
+        }),
+    )?;
 
     let ack = match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
         Packet::RBeginSend(ack) => ack,
         _ => failure::bail!("protocol error, expected begin ack packet"),
     };
 
+    // The exit status of a command is only known once it exits, until then
+    // a placeholder stands in for the 'exec-status' tag.
+    let mut pending_tags = tags.clone();
+    if let DataSource::Subprocess {
+        status_tag: true, ..
+    } = data
+    {
+        pending_tags
+            .entry("exec-status".to_string())
+            .or_insert_with(|| "0".to_string());
+    }
+    check_tags(
+        &ctx.tag_schema,
+        ack.tag_schema.as_deref(),
+        &pending_tags,
+        &note,
+    )?;
+
+    let index_delta_base = match ctx.index_delta_base.take() {
+        Some(base) if base.gc_generation != Some(ack.gc_generation) => {
+            ctx.progress.println(
+                "repository was garbage collected since the index delta base was read, sending a full index..."
+                    .to_string(),
+            );
+            None
+        }
+        base => base,
+    };
+
+    report_phase(ctx, "sending", "sending...");
+
     'retry: for _i in 0..256 {
         let mut index_tree = None;
 
@@ -186,45 +874,81 @@ pub fn send(
             send_log_session
                 .borrow_mut()
                 .perform_cache_invalidations(ack.has_delta_id)?;
+            send_log_session.borrow().set_chunking(&ctx.chunking)?;
         }
 
         let mut sink = ConnectionHtreeSink {
             checkpoint_bytes: ctx.checkpoint_bytes,
             dirty_bytes: 0,
+            checkpoint_interval: ctx.checkpoint_interval,
+            last_checkpoint: std::time::Instant::now(),
+            verifier: match ctx.data_dctx {
+                Some(ref data_dctx) if ctx.verify_sample_rate > 0.0 => Some(ChunkVerifier {
+                    sample_rate: ctx.verify_sample_rate,
+                    data_dctx: data_dctx.clone(),
+                    hash_key: ctx.hash_key.clone(),
+                    pending: Vec::new(),
+                }),
+                _ => None,
+            },
+            stats: ctx.stats.clone(),
             send_log_session: &send_log_session,
             w,
             r,
         };
 
-        // XXX TODO these chunk parameters need to be investigated and tuned.
-        let min_size = 256 * 1024;
-        let max_size = 8 * 1024 * 1024;
-        let chunk_mask = 0x000f_ffff;
+        let max_size = ctx.chunking.max_size;
+        let chunk_mask = ctx.chunking.chunk_mask;
 
-        let mut chunker = chunker::RollsumChunker::new(
-            rollsum::Rollsum::new_with_chunk_mask(chunk_mask),
-            min_size,
-            max_size,
-        );
+        let mut chunker = ctx.chunking.chunker();
         let mut tw = htree::TreeWriter::new(max_size, chunk_mask);
+        let data_size: u64;
+        let mut entry_count: Option<u64> = None;
 
         match data {
-            DataSource::Subprocess(args) => {
+            DataSource::Subprocess {
+                args,
+                ok_statuses,
+                status_tag,
+            } => {
                 let quoted_args: Vec<String> =
                     args.iter().map(|x| shlex::quote(x).to_string()).collect();
                 ctx.progress
                     .set_message(&("exec: ".to_string() + &quoted_args.join(" ")));
 
-                let mut child = std::process::Command::new(args[0].clone())
-                    .args(&args[1..])
-                    .stdin(std::process::Stdio::null())
-                    .stdout(std::process::Stdio::piped())
-                    .spawn()?;
-                let mut data = child.stdout.as_mut().unwrap();
-                send_chunks(ctx, &mut sink, &mut chunker, &mut tw, &mut data, None)?;
+                let mut child = spawn_subprocess(args)?;
+
+                // Forward the child's stderr so it does not garble the progress bar.
+                let stderr = child.child.stderr.take().unwrap();
+                let progress = ctx.progress.clone();
+                let stderr_thread = std::thread::spawn(move || forward_lines(stderr, &progress));
+
+                let mut data = child.child.stdout.take().unwrap();
+                data_size = send_chunks(
+                    ctx,
+                    &mut sink,
+                    &mut chunker,
+                    &mut tw,
+                    &mut data,
+                    false,
+                    None,
+                )? as u64;
                 let status = child.wait()?;
-                if !status.success() {
-                    failure::bail!("child failed with status {}", status.code().unwrap());
+                let _ = stderr_thread.join();
+
+                match status.code() {
+                    Some(code) if code == 0 || ok_statuses.contains(&code) => {
+                        if *status_tag {
+                            tags.entry("exec-status".to_string())
+                                .or_insert_with(|| code.to_string());
+                            check_tags(&ctx.tag_schema, ack.tag_schema.as_deref(), &tags, &note)?;
+                        }
+                    }
+                    Some(code) => failure::bail!("child failed with status {}", code),
+                    None => failure::bail!(
+                        "child was terminated by signal {}",
+                        status.signal().unwrap_or(0)
+                    ),
                 }
             }
             DataSource::Readable {
@@ -232,16 +956,58 @@ pub fn send(
                 ref mut data,
             } => {
                 ctx.progress.set_message(&description);
-                send_chunks(ctx, &mut sink, &mut chunker, &mut tw, data, None)?;
+                data_size =
+                    send_chunks(ctx, &mut sink, &mut chunker, &mut tw, data, false, None)? as u64;
             }
-            DataSource::Directory { path, exclusions } => {
-                let mut idx_chunker = chunker::RollsumChunker::new(
-                    rollsum::Rollsum::new_with_chunk_mask(chunk_mask),
-                    min_size,
-                    max_size,
-                );
+            DataSource::File { path, ref mut data }
+            | DataSource::BlockDevice { path, ref mut data } => {
+                report_path(ctx, path);
+                data_size = send_file(
+                    ctx,
+                    &mut sink,
+                    &mut chunker,
+                    &mut tw,
+                    &send_log_session,
+                    path,
+                    data,
+                )?;
+            }
+            DataSource::Directory {
+                paths,
+                exclusions,
+                inclusions,
+                file_list,
+            } => {
+                let mut idx_chunker = ctx.chunking.chunker();
                 let mut idx_tw = htree::TreeWriter::new(max_size, chunk_mask);
 
+                // The base index chunks are referenced as is, so only the
+                // changes need to be sent, see index::IndexBuilder.
+                if let Some(ref base) = index_delta_base {
+                    for addr in base.leaf_addresses.iter() {
+                        idx_tw.add_addr(&mut sink, 0, addr)?;
+                    }
+                    send_chunks(
+                        ctx,
+                        &mut sink,
+                        &mut idx_chunker,
+                        &mut idx_tw,
+                        &mut std::io::Cursor::new(
+                            &serde_bare::to_vec(&index::VersionedIndexEntry::DeltaStartV1).unwrap(),
+                        ),
+                        false,
+                        None,
+                    )?;
+                }
+
+                let opts = SendDirOptions {
+                    send_log_session: &send_log_session,
+                    index_delta_base: index_delta_base.as_ref(),
+                    paths,
+                    exclusions,
+                    inclusions,
+                    file_list: file_list.as_deref(),
+                };
                 match send_dir(
                     ctx,
                     &mut sink,
@@ -249,11 +1015,11 @@ pub fn send(
                     &mut tw,
                     &mut idx_chunker,
                     &mut idx_tw,
-                    &send_log_session,
-                    &path,
-                    &exclusions,
+                    &opts,
                 ) {
-                    Ok(()) => {
+                    Ok((dir_data_size, dir_entry_count)) => {
+                        data_size = dir_data_size;
+                        entry_count = Some(dir_entry_count);
                         let chunk_data = idx_chunker.finish();
                         let idx_addr = crypto::keyed_content_address(&chunk_data, &ctx.hash_key);
                         idx_tw.add(
@@ -273,15 +1039,11 @@ pub fn send(
                         ctx.progress.println(
                             "filesystem modified while sending, restarting send...".to_string(),
                         );
-                        if let Some(ref send_log_session) = send_log_session {
-                            write_packet(w, &Packet::TSendSync)?;
-                            match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
-                                Packet::RSendSync => {
-                                    send_log_session.borrow_mut().checkpoint()?;
-                                }
-                                _ => failure::bail!("protocol error, expected RSentSync packet"),
-                            }
+                        if send_log_session.is_some() || sink.has_pending_verifications() {
+                            sink.sync()?;
                         }
+                        // Files may open fine on the next attempt, or be gone.
+                        ctx.skipped_files.clear();
                         continue 'retry;
                     }
                     Err(SendDirError::Other(err)) => return Err(err),
@@ -298,6 +1060,10 @@ pub fn send(
         )?;
         let (data_tree_height, data_tree_address) = tw.finish(&mut sink)?;
 
+        if sink.has_pending_verifications() {
+            sink.sync()?;
+        }
+
         let plain_text_metadata = itemset::PlainTextItemMetadata {
             primary_key_id: ctx.primary_key_id,
             data_tree: itemset::HTreeMetadata {
@@ -307,36 +1073,58 @@ pub fn send(
             index_tree,
         };
 
-        let e_metadata = itemset::EncryptedItemMetadata {
-            plain_text_hash: plain_text_metadata.hash(),
+        let plain_text_hash = plain_text_metadata.hash();
+        let timestamp = chrono::Utc::now();
+
+        let e_metadata = itemset::EncryptedItemMetadataV2 {
+            plain_text_hash,
             send_key_id: ctx.send_key_id,
             hash_key_part_2: ctx.hash_key.part2.clone(),
-            timestamp: chrono::Utc::now(),
+            timestamp,
             tags,
+            note,
+            data_size: Some(serde_bare::Uint(data_size)),
+            entry_count: entry_count.map(serde_bare::Uint),
+            start_timestamp: Some(start_timestamp),
         };
 
-        ctx.progress.set_message("syncing disks...");
+        let item = itemset::VersionedItemMetadata::V2(itemset::ItemMetadata {
+            plain_text_metadata,
+            encrypted_metadata: ctx.metadata_ectx.encrypt_data(
+                serde_bare::to_vec(&e_metadata)?,
+                crypto::DataCompression::Zstd(crypto::DEFAULT_ZSTD_LEVEL),
+            ),
+        });
+
+        report_phase(ctx, "syncing", "syncing disks...");
 
         write_packet(
             w,
-            &Packet::TAddItem(AddItem {
-                gc_generation: ack.gc_generation,
-                item: itemset::VersionedItemMetadata::V1(itemset::ItemMetadata {
-                    plain_text_metadata,
-                    encrypted_metadata: ctx.metadata_ectx.encrypt_data(
-                        serde_bare::to_vec(&e_metadata)?,
-                        crypto::DataCompression::Zstd,
-                    ),
-                }),
-            }),
+            &if ctx.unique_tags.is_empty() && ctx.replace_tags.is_empty() && ctx.expires.is_none() {
+                Packet::TAddItem(AddItem {
+                    gc_generation: ack.gc_generation,
+                    item,
+                })
+            } else {
+                Packet::TAddTaggedItem(AddTaggedItem {
+                    gc_generation: ack.gc_generation,
+                    item,
+                    unique_tags: ctx.unique_tags.clone(),
+                    replace_tags: ctx.replace_tags.clone(),
+                    expires: ctx.expires,
+                })
+            },
         )?;
 
         match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
-            Packet::RAddItem(id) => {
+            Packet::RAddItem(ack) => {
                 if send_log_session.is_some() {
-                    send_log_session.unwrap().into_inner().commit(&id)?;
+                    send_log_session
+                        .unwrap()
+                        .into_inner()
+                        .commit(&ack.item_id)?;
                 }
-                return Ok(id);
+                return Ok(ack);
             }
             _ => failure::bail!("protocol error, expected an RAddItem packet"),
         }
@@ -345,42 +1133,236 @@ pub fn send(
     failure::bail!("put retried too many times");
 }
 
-fn send_chunks(
-    ctx: &mut SendContext,
-    sink: &mut dyn htree::Sink,
-    chunker: &mut chunker::RollsumChunker,
-    tw: &mut htree::TreeWriter,
-    data: &mut dyn std::io::Read,
-    mut on_chunk: Option<&mut dyn FnMut(&Address)>,
-) -> Result<usize, failure::Error> {
-    let mut buf: Vec<u8> = vec![0; 1024 * 1024];
-    let mut n_written: usize = 0;
-    loop {
-        match data.read(&mut buf) {
-            Ok(0) => {
-                return Ok(n_written);
-            }
-            Ok(n_read) => {
-                let mut n_chunked = 0;
-                while n_chunked != n_read {
-                    let (n, c) = chunker.add_bytes(&buf[n_chunked..n_read]);
-                    n_chunked += n;
-                    if let Some(chunk_data) = c {
-                        let addr = crypto::keyed_content_address(&chunk_data, &ctx.hash_key);
-                        let encrypted_chunk =
-                            ctx.data_ectx.encrypt_data(chunk_data, ctx.compression);
-                        if let Some(ref mut on_chunk) = on_chunk {
-                            on_chunk(&addr);
+// A child process that is sent SIGTERM if dropped before it exits,
+// for example when a send fails part way through.
+struct Subprocess {
+    child: std::process::Child,
+    exited: bool,
+}
+
+impl Subprocess {
+    fn wait(&mut self) -> Result<std::process::ExitStatus, failure::Error> {
+        let status = self.child.wait()?;
+        self.exited = true;
+        Ok(status)
+    }
+}
+
+impl Drop for Subprocess {
+    fn drop(&mut self) {
+        if !self.exited {
+            let _ = nix::sys::signal::kill(
+                nix::unistd::Pid::from_raw(self.child.id() as i32),
+                nix::sys::signal::Signal::SIGTERM,
+            );
+            let _ = self.child.wait();
+        }
+    }
+}
+
+fn spawn_subprocess(args: &[String]) -> Result<Subprocess, failure::Error> {
+    let mut cmd = std::process::Command::new(&args[0]);
+    cmd.args(&args[1..])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    // Also terminate the child if we are killed outright.
+    #[cfg(target_os = "linux")]
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    Ok(Subprocess {
+        child: cmd.spawn()?,
+        exited: false,
+    })
+}
+
+fn forward_lines(r: impl std::io::Read, progress: &indicatif::ProgressBar) {
+    let mut r = std::io::BufReader::new(r);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match std::io::BufRead::read_until(&mut r, b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches('\n');
+                // Hidden progress bars discard printed lines.
+                if progress.is_hidden() {
+                    eprintln!("{}", line);
+                } else {
+                    progress.println(line);
+                }
+            }
+        }
+    }
+}
+
+// Called with the address and the unencrypted size of each chunk sent.
+type OnChunk<'a> = &'a mut dyn FnMut(&Address, usize);
+
+// Chunks emitted while sending incompressible data are not compressed,
+// see likely_incompressible_file.
+fn send_chunks(
+    ctx: &mut SendContext,
+    sink: &mut dyn htree::Sink,
+    chunker: &mut chunker::Chunker,
+    tw: &mut htree::TreeWriter,
+    data: &mut dyn std::io::Read,
+    incompressible: bool,
+    mut on_chunk: Option<OnChunk>,
+) -> Result<usize, failure::Error> {
+    let compression = if incompressible {
+        crypto::DataCompression::None
+    } else {
+        ctx.compression
+    };
+    let mut buf: Vec<u8> = vec![0; ctx.chunking.read_buffer_size()];
+    let mut n_written: usize = 0;
+    let mut add_chunk = |(addr, chunk_len, encrypted_chunk): DoneChunk| {
+        if let Some(ref mut on_chunk) = on_chunk {
+            on_chunk(&addr, chunk_len);
+        }
+        tw.add(sink, &addr, encrypted_chunk)
+    };
+    let result = loop {
+        match data.read(&mut buf) {
+            Ok(0) => break Ok(n_written),
+            Ok(n_read) => {
+                let mut n_chunked = 0;
+                while n_chunked != n_read {
+                    let (n, c) = chunker.add_bytes(&buf[n_chunked..n_read]);
+                    n_chunked += n;
+                    if let Some(chunk_data) = c {
+                        match ctx.chunk_workers {
+                            Some(ref mut workers) => {
+                                workers.add((chunk_data, compression))?;
+                                while let Some(done) = workers.next_done(false)? {
+                                    add_chunk(done)?;
+                                }
+                            }
+                            None => {
+                                let addr =
+                                    crypto::keyed_content_address(&chunk_data, &ctx.hash_key);
+                                let chunk_len = chunk_data.len();
+                                let encrypted_chunk =
+                                    ctx.data_ectx.encrypt_data(chunk_data, compression);
+                                add_chunk((addr, chunk_len, encrypted_chunk))?;
+                            }
                         }
-                        tw.add(sink, &addr, encrypted_chunk)?;
                     }
                 }
                 ctx.progress.inc(n_read as u64);
                 n_written += n_read;
             }
-            Err(err) => return Err(err.into()),
+            Err(err) => break Err(err.into()),
+        }
+    };
+    // Like a single threaded send, every chunk read is in the tree when we return.
+    if let Some(ref mut workers) = ctx.chunk_workers {
+        while let Some(done) = workers.next_done(true)? {
+            add_chunk(done)?;
+        }
+    }
+    result
+}
+
+// Identifies an unchanged file across sends, see send_file.
+fn stream_checkpoint_key(
+    hash_key: &crypto::HashKey,
+    path: &std::path::Path,
+    metadata: &std::fs::Metadata,
+    size: u64,
+) -> [u8; crypto::HASH_BYTES] {
+    let mut hash_state = crypto::HashState::new(Some(hash_key));
+    hash_state.update(path.as_os_str().as_bytes());
+    hash_state.update(&[0]);
+    hash_state.update(&metadata.dev().to_le_bytes()[..]);
+    hash_state.update(&metadata.ino().to_le_bytes()[..]);
+    hash_state.update(&size.to_le_bytes()[..]);
+    hash_state.update(&metadata.mtime().to_le_bytes()[..]);
+    hash_state.update(&metadata.mtime_nsec().to_le_bytes()[..]);
+    hash_state.update(&metadata.ctime().to_le_bytes()[..]);
+    hash_state.update(&metadata.ctime_nsec().to_le_bytes()[..]);
+    hash_state.finish()
+}
+
+// Send a file as a stream, recording each chunk in the send log so a send that is
+// interrupted can later continue from its last checkpoint instead of reading the whole
+// file again. Chunk boundaries only depend on the data since the previous boundary,
+// so the resumed send produces the same chunks as an uninterrupted one.
+fn send_file(
+    ctx: &mut SendContext,
+    sink: &mut dyn htree::Sink,
+    chunker: &mut chunker::Chunker,
+    tw: &mut htree::TreeWriter,
+    send_log_session: &Option<std::cell::RefCell<sendlog::SendLogSession>>,
+    path: &std::path::Path,
+    f: &mut std::fs::File,
+) -> Result<u64, failure::Error> {
+    let incompressible = likely_incompressible_file(ctx, path, f)?;
+    let metadata = f.metadata()?;
+    let send_log_session = match send_log_session {
+        // Writes to a block device do not change the times of its device node,
+        // so nothing tells us the device is unchanged since an interrupted send.
+        Some(send_log_session) if !metadata.file_type().is_block_device() => send_log_session,
+        _ => return Ok(send_chunks(ctx, sink, chunker, tw, f, incompressible, None)? as u64),
+    };
+
+    let size = fsutil::file_size(f)?;
+    let key = stream_checkpoint_key(&ctx.hash_key, path, &metadata, size);
+    let checkpoints = send_log_session.borrow().stream_checkpoints(&key[..])?;
+    let mut offset = 0;
+    for (end, addr) in checkpoints.iter() {
+        tw.add_addr(sink, 0, addr)?;
+        offset = *end;
+    }
+    if offset != 0 {
+        std::io::Seek::seek(f, std::io::SeekFrom::Start(offset))?;
+        ctx.progress.inc(offset);
+        ctx.progress.println(format!(
+            "resuming interrupted send of {} at byte {}...",
+            path.display(),
+            offset
+        ));
+    }
+
+    let mut idx = checkpoints.len() as u64;
+    let mut end = offset;
+    let mut checkpoint_err = None;
+    let mut on_chunk = |addr: &Address, len: usize| {
+        end += len as u64;
+        if checkpoint_err.is_none() {
+            if let Err(err) =
+                send_log_session
+                    .borrow()
+                    .add_stream_checkpoint(&key[..], idx, end, addr)
+            {
+                checkpoint_err = Some(err);
+            }
         }
+        idx += 1;
+    };
+    let n = send_chunks(
+        ctx,
+        sink,
+        chunker,
+        tw,
+        f,
+        incompressible,
+        Some(&mut on_chunk),
+    )?;
+    if let Some(err) = checkpoint_err {
+        return Err(err);
     }
+    Ok(offset + n as u64)
 }
 
 #[derive(Debug)]
@@ -426,313 +1408,1527 @@ impl From<nix::Error> for SendDirError {
 
 // A smear error is an error likely caused by the filesystem being altered
 // by a concurrent process as we are making a snapshot.
-fn likely_smear_error(err: &std::io::Error) -> bool {
-    matches!(
-        err.kind(),
-        std::io::ErrorKind::NotFound | std::io::ErrorKind::InvalidInput
-    )
+fn is_unchanged(
+    metadata: &std::fs::Metadata,
+    changed_since: &Option<chrono::DateTime<chrono::Utc>>,
+) -> bool {
+    match changed_since {
+        Some(changed_since) => {
+            let changed_since = (
+                changed_since.timestamp(),
+                changed_since.timestamp_subsec_nanos() as i64,
+            );
+            (metadata.ctime(), metadata.ctime_nsec()) < changed_since
+                && (metadata.mtime(), metadata.mtime_nsec()) < changed_since
+        }
+        None => false,
+    }
 }
 
-fn send_dir(
-    ctx: &mut SendContext,
-    sink: &mut dyn htree::Sink,
-    chunker: &mut chunker::RollsumChunker,
-    tw: &mut htree::TreeWriter,
-    idx_chunker: &mut chunker::RollsumChunker,
-    idx_tw: &mut htree::TreeWriter,
-    send_log_session: &Option<std::cell::RefCell<sendlog::SendLogSession>>,
-    path: &std::path::PathBuf,
-    exclusions: &[glob::Pattern],
-) -> Result<(), SendDirError> {
-    let path = fsutil::absolute_path(&path)?;
+// Hashes the data read from a file, see index::IndexEntryV2::content_hash.
+struct ContentHashReader<'a> {
+    inner: &'a mut dyn std::io::Read,
+    hash_state: crypto::HashState,
+}
 
-    let mut addresses: Vec<u8> = Vec::new();
-    let mut work_list = std::collections::VecDeque::new();
-    work_list.push_back(path.clone());
+impl<'a> ContentHashReader<'a> {
+    fn new(inner: &'a mut dyn std::io::Read, hash_key: &crypto::HashKey) -> Self {
+        ContentHashReader {
+            inner,
+            hash_state: crypto::HashState::new(Some(hash_key)),
+        }
+    }
 
-    while let Some(cur_dir) = work_list.pop_front() {
-        ctx.progress.set_message(&cur_dir.to_string_lossy());
-        addresses.clear();
-        let mut hash_state = crypto::HashState::new(Some(&ctx.hash_key));
-        // Incorporate the absolute dir in our cache key.
-        hash_state.update(cur_dir.as_os_str().as_bytes());
-        // Null byte marks the end of path and tar headers in the hash space.
-        hash_state.update(&[0]);
+    fn finish(self) -> Vec<u8> {
+        self.hash_state.finish().to_vec()
+    }
+}
 
-        let mut dir_ents = match fsutil::read_dirents(&cur_dir) {
-            Ok(dir_ents) => dir_ents,
-            Err(err) if likely_smear_error(&err) => return Err(SendDirError::FilesystemModified),
-            Err(err) => return Err(SendDirError::Other(err.into())),
-        };
+impl<'a> std::io::Read for ContentHashReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash_state.update(&buf[..n]);
+        Ok(n)
+    }
+}
 
-        dir_ents.sort_by_key(|a| a.file_name());
+// Remembers the first and last blocks read from a file, for --verify-reads=blocks.
+struct EdgeBlocksReader<'a> {
+    inner: &'a mut dyn std::io::Read,
+    record: bool,
+    first: Vec<u8>,
+    last: Vec<u8>,
+}
 
-        let mut tar_dir_ents = Vec::new();
+impl<'a> EdgeBlocksReader<'a> {
+    fn new(inner: &'a mut dyn std::io::Read, record: bool) -> Self {
+        EdgeBlocksReader {
+            inner,
+            record,
+            first: Vec::new(),
+            last: Vec::new(),
+        }
+    }
+}
 
-        if cur_dir == path {
-            let metadata = std::fs::metadata(&path)?;
-            if !metadata.is_dir() {
-                return Err(SendDirError::Other(failure::format_err!(
-                    "{} is not a directory",
-                    path.display()
-                )));
+impl<'a> std::io::Read for EdgeBlocksReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if self.record {
+            let data = &buf[..n];
+            if self.first.len() < VERIFY_BLOCK_SIZE {
+                let k = std::cmp::min(VERIFY_BLOCK_SIZE - self.first.len(), n);
+                self.first.extend_from_slice(&data[..k]);
             }
-            let tar_path = ".".into();
-            let tar_header_bytes = match xtar::dirent_to_tarheader(&metadata, &path, &tar_path) {
-                Ok(hdr) => hdr,
-                Err(err) if likely_smear_error(&err) => {
-                    return Err(SendDirError::FilesystemModified)
+            if n >= VERIFY_BLOCK_SIZE {
+                self.last.clear();
+                self.last.extend_from_slice(&data[n - VERIFY_BLOCK_SIZE..]);
+            } else {
+                self.last.extend_from_slice(data);
+                if self.last.len() > VERIFY_BLOCK_SIZE {
+                    self.last.drain(..self.last.len() - VERIFY_BLOCK_SIZE);
                 }
-                Err(err) => return Err(SendDirError::Other(err.into())),
-            };
-
-            hash_state.update(&metadata.ctime().to_le_bytes()[..]);
-            hash_state.update(&metadata.ctime_nsec().to_le_bytes()[..]);
-            hash_state.update(&tar_header_bytes);
-            tar_dir_ents.push((path.clone(), tar_path, metadata, tar_header_bytes));
+            }
         }
+        Ok(n)
+    }
+}
 
-        'collect_dir_ents: for entry in dir_ents {
-            let ent_path = entry.path();
-
-            for excl in exclusions {
-                if excl.matches_path(&ent_path) {
-                    continue 'collect_dir_ents;
-                }
-            }
+// Reads a file, producing the zeros of its holes without reading them
+// from disk, and remembering where the holes were.
+struct SparseReader<'a> {
+    f: &'a mut std::fs::File,
+    pos: u64,
+    // Holes are only looked for before this offset, zero for files that are not sparse.
+    search_end: u64,
+    // The end of the hole or data region pos is in.
+    region_end: u64,
+    in_hole: bool,
+    holes: Vec<index::HoleRange>,
+}
 
-            let metadata = match entry.metadata() {
-                Ok(metadata) => metadata,
-                Err(err) if likely_smear_error(&err) => {
-                    return Err(SendDirError::FilesystemModified)
-                }
-                Err(err) => return Err(SendDirError::Other(err.into())),
-            };
-            let tar_path = ent_path.strip_prefix(&path).unwrap().to_path_buf();
-            let tar_header_bytes = match xtar::dirent_to_tarheader(&metadata, &ent_path, &tar_path)
-            {
-                Ok(hdr) => hdr,
-                Err(err) if likely_smear_error(&err) => {
-                    return Err(SendDirError::FilesystemModified)
-                }
-                Err(err) => return Err(SendDirError::Other(err.into())),
-            };
+impl<'a> SparseReader<'a> {
+    fn new(f: &'a mut std::fs::File, pos: u64, search_end: u64) -> Self {
+        SparseReader {
+            f,
+            pos,
+            search_end,
+            region_end: pos,
+            in_hole: false,
+            holes: Vec::new(),
+        }
+    }
 
-            if metadata.is_dir() {
-                work_list.push_back(ent_path.clone());
+    fn next_region(&mut self) -> std::io::Result<()> {
+        self.in_hole = false;
+        if self.pos >= self.search_end {
+            self.region_end = u64::MAX;
+        } else {
+            let data = fsutil::seek_data(self.f, self.pos)?.unwrap_or(self.search_end);
+            if data > self.pos {
+                self.in_hole = true;
+                self.region_end = std::cmp::min(data, self.search_end);
+                self.holes.push(index::HoleRange {
+                    offset: serde_bare::Uint(self.pos),
+                    len: serde_bare::Uint(self.region_end - self.pos),
+                });
+            } else {
+                let hole = fsutil::seek_hole(self.f, self.pos)?;
+                self.region_end = if hole > self.pos {
+                    std::cmp::min(hole, self.search_end)
+                } else {
+                    self.search_end
+                };
             }
-
-            hash_state.update(&metadata.ctime().to_le_bytes()[..]);
-            hash_state.update(&metadata.ctime_nsec().to_le_bytes()[..]);
-            hash_state.update(&tar_header_bytes);
-            tar_dir_ents.push((ent_path, tar_path, metadata, tar_header_bytes));
         }
+        std::io::Seek::seek(self.f, std::io::SeekFrom::Start(self.pos))?;
+        Ok(())
+    }
+}
 
-        let hash = hash_state.finish();
-
-        let cache_lookup = if send_log_session.is_some() && ctx.use_stat_cache {
-            send_log_session
-                .as_ref()
-                .unwrap()
-                .borrow_mut()
-                .stat_cache_lookup(&hash)?
+impl<'a> std::io::Read for SparseReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.region_end {
+            self.next_region()?;
+        }
+        let want = std::cmp::min(buf.len() as u64, self.region_end - self.pos) as usize;
+        let n = if self.in_hole {
+            buf[..want].fill(0);
+            want
         } else {
-            None
+            self.f.read(&mut buf[..want])?
         };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
 
-        match cache_lookup {
-            Some((size, cached_addresses, cached_index)) => {
-                debug_assert!(cached_addresses.len() % ADDRESS_SZ == 0);
+// Files using fewer blocks than their size are likely to have holes.
+fn maybe_sparse(metadata: &std::fs::Metadata) -> bool {
+    metadata.blocks() * 512 < metadata.size()
+}
 
-                let dir_data_chunk_idx = tw.data_chunk_count();
+// Metadata of a regular file being sent, which may be behind a followed symlink.
+fn sent_file_metadata(
+    ctx: &SendContext,
+    path: &std::path::Path,
+) -> Result<std::fs::Metadata, std::io::Error> {
+    if ctx.follow_symlinks {
+        std::fs::metadata(path)
+    } else {
+        std::fs::symlink_metadata(path)
+    }
+}
 
-                let mut address = Address::default();
-                for cached_address in cached_addresses.chunks(ADDRESS_SZ) {
-                    address.bytes[..].clone_from_slice(cached_address);
-                    tw.add_addr(sink, 0, &address)?;
-                }
+fn file_changed_while_read(
+    ctx: &SendContext,
+    path: &std::path::Path,
+    metadata: &std::fs::Metadata,
+    read: &EdgeBlocksReader,
+) -> Result<bool, std::io::Error> {
+    let current = match sent_file_metadata(ctx, path) {
+        Ok(current) => current,
+        Err(err) if likely_smear_error(&err) => return Ok(true),
+        Err(err) => return Err(err),
+    };
+    if current.dev() != metadata.dev()
+        || current.ino() != metadata.ino()
+        || current.size() != metadata.size()
+        || current.mtime() != metadata.mtime()
+        || current.mtime_nsec() != metadata.mtime_nsec()
+        || current.ctime() != metadata.ctime()
+        || current.ctime_nsec() != metadata.ctime_nsec()
+    {
+        return Ok(true);
+    }
 
-                let mut dir_index: Vec<index::VersionedIndexEntry> =
-                    serde_bare::from_slice(&cached_index).unwrap();
+    if read.record {
+        let f = match fsutil::open_file_for_backup(path) {
+            Ok(f) => f,
+            Err(err) if likely_smear_error(&err) => return Ok(true),
+            Err(err) => return Err(err),
+        };
+        let mut buf = vec![0; read.first.len()];
+        let offsets = [
+            (0, &read.first),
+            (current.size() - read.last.len() as u64, &read.last),
+        ];
+        for (offset, expected) in offsets.iter() {
+            buf.resize(expected.len(), 0);
+            match f.read_exact_at(&mut buf, *offset) {
+                Ok(()) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(true),
+                Err(err) => return Err(err),
+            }
+            if buf != **expected {
+                return Ok(true);
+            }
+        }
+    }
 
-                for index_entry in dir_index.iter_mut() {
-                    match index_entry {
-                        index::VersionedIndexEntry::V1(ref mut index_entry) => {
-                            index_entry.data_chunk_idx.0 += dir_data_chunk_idx;
-                            index_entry.data_chunk_content_idx.0 += dir_data_chunk_idx;
-                            index_entry.data_chunk_content_end_idx.0 += dir_data_chunk_idx;
-                            index_entry.data_chunk_end_idx.0 += dir_data_chunk_idx;
-                        }
-                    }
-                    send_chunks(
-                        ctx,
-                        sink,
-                        idx_chunker,
-                        idx_tw,
-                        &mut std::io::Cursor::new(&serde_bare::to_vec(&index_entry).unwrap()),
-                        None,
-                    )?;
-                }
+    Ok(false)
+}
 
-                ctx.progress.inc(size);
+// The chunks holding nothing but the contents of a file, see put --tail-deltas.
+struct FileChunks {
+    // The file offset the chunk being filled starts at, negative
+    // while it holds data from before the file contents.
+    chunk_start: i64,
+    start: u64,
+    ends: Vec<u64>,
+    addresses: Vec<u8>,
+}
 
-                send_log_session
-                    .as_ref()
-                    .unwrap()
-                    .borrow_mut()
-                    .add_stat_cache_data(&hash[..], size, &addresses, &cached_index)?;
-            }
-            None => {
-                let mut total_size: u64 = 0;
-                let mut on_chunk = |addr: &Address| {
-                    addresses.extend_from_slice(&addr.bytes[..]);
-                };
+impl FileChunks {
+    fn new(buffered: usize) -> FileChunks {
+        FileChunks {
+            chunk_start: -(buffered as i64),
+            start: 0,
+            ends: Vec::new(),
+            addresses: Vec::new(),
+        }
+    }
 
-                let dir_data_chunk_idx = tw.data_chunk_count();
-                let mut dir_index: Vec<index::VersionedIndexEntry> =
-                    Vec::with_capacity(tar_dir_ents.len());
+    fn add(&mut self, addr: &Address, len: usize) {
+        let end = self.chunk_start + len as i64;
+        if self.chunk_start >= 0 {
+            if self.ends.is_empty() {
+                self.start = self.chunk_start as u64;
+            }
+            self.ends.push(end as u64);
+            self.addresses.extend_from_slice(&addr.bytes[..]);
+        }
+        self.chunk_start = end;
+    }
+}
 
-                for (ent_path, tar_path, metadata, header_bytes) in tar_dir_ents.drain(..) {
-                    ctx.progress.set_message(&ent_path.to_string_lossy());
+fn file_tail_key(
+    hash_key: &crypto::HashKey,
+    path: &std::path::Path,
+    metadata: &std::fs::Metadata,
+) -> [u8; crypto::HASH_BYTES] {
+    let mut hash_state = crypto::HashState::new(Some(hash_key));
+    hash_state.update(path.as_os_str().as_bytes());
+    hash_state.update(&[0]);
+    hash_state.update(&metadata.dev().to_le_bytes()[..]);
+    hash_state.update(&metadata.ino().to_le_bytes()[..]);
+    hash_state.finish()
+}
 
-                    let mut tar_ent_size = header_bytes.len() as u64;
-                    let ent_data_chunk_idx = tw.data_chunk_count();
-                    let ent_data_chunk_offset = chunker.buffered_count() as u64;
+// A keyed hash of blocks spread over the first size bytes of a file, None if the
+// file is shorter. Appending leaves it unchanged, while most rewrites do not.
+fn sample_file_prefix(
+    hash_key: &crypto::HashKey,
+    f: &std::fs::File,
+    size: u64,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let mut hash_state = crypto::HashState::new(Some(hash_key));
+    hash_state.update(&size.to_le_bytes()[..]);
+    let block_size = std::cmp::min(VERIFY_BLOCK_SIZE as u64, size);
+    let mut buf = vec![0; block_size as usize];
+    for i in 0..TAIL_DELTA_SAMPLES {
+        let offset = i * (size - block_size) / (TAIL_DELTA_SAMPLES - 1);
+        match f.read_exact_at(&mut buf, offset) {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        hash_state.update(&buf);
+    }
+    Ok(Some(hash_state.finish().to_vec()))
+}
 
-                    total_size += send_chunks(
-                        ctx,
-                        sink,
-                        chunker,
-                        tw,
-                        &mut std::io::Cursor::new(header_bytes),
-                        Some(&mut on_chunk),
-                    )? as u64;
-
-                    let ent_data_chunk_content_idx = tw.data_chunk_count();
-                    let ent_data_chunk_content_offset = chunker.buffered_count() as u64;
-
-                    let mut ent_data_chunk_content_end_idx = ent_data_chunk_content_idx;
-                    let mut ent_data_chunk_content_end_offset = ent_data_chunk_content_offset;
-
-                    if metadata.is_file() {
-                        let mut f = match std::fs::OpenOptions::new()
-                            .read(true)
-                            .custom_flags(libc::O_NOATIME)
-                            .open(&ent_path)
-                        {
-                            Ok(f) => f,
-                            Err(err) if likely_smear_error(&err) => {
-                                return Err(SendDirError::FilesystemModified)
-                            }
-                            Err(err) => return Err(SendDirError::Other(err.into())),
-                        };
-
-                        // For linux at least, shift file pages to the tail of the page cache, allowing
-                        // the kernel to quickly evict these pages. This works well for the case of system
-                        // backups, where we don't to trash the users current cache.
-                        // One source on how linux treats this hint - https://lwn.net/Articles/449420
-                        nix::fcntl::posix_fadvise(
-                            f.as_raw_fd(),
-                            0,
-                            0,
-                            nix::fcntl::PosixFadviseAdvice::POSIX_FADV_NOREUSE,
-                        )?;
+// Sends the file contents before tail.start, then references the chunks a previous
+// put split the rest of the unchanged prefix into. Returns the offset f is left at,
+// or None if the file became too short.
+#[allow(clippy::too_many_arguments)]
+fn send_file_tail_prefix(
+    ctx: &mut SendContext,
+    sink: &mut dyn htree::Sink,
+    chunker: &mut chunker::Chunker,
+    tw: &mut htree::TreeWriter,
+    f: &mut std::fs::File,
+    tail: &sendlog::FileTail,
+    incompressible: bool,
+    on_chunk: OnChunk,
+) -> Result<Option<u64>, failure::Error> {
+    let mut prefix = std::io::Read::take(&mut *f, tail.start);
+    if send_chunks(
+        ctx,
+        sink,
+        chunker,
+        tw,
+        &mut prefix,
+        incompressible,
+        Some(&mut *on_chunk),
+    )? as u64
+        != tail.start
+    {
+        return Ok(None);
+    }
 
-                        let file_len =
-                            send_chunks(ctx, sink, chunker, tw, &mut f, Some(&mut on_chunk))?;
-
-                        tar_ent_size += file_len as u64;
-                        total_size += file_len as u64;
-
-                        ent_data_chunk_content_end_idx = tw.data_chunk_count();
-                        ent_data_chunk_content_end_offset = chunker.buffered_count() as u64;
-
-                        /* Tar entries are rounded to 512 bytes */
-                        let remaining = 512 - (file_len % 512);
-                        if remaining < 512 {
-                            tar_ent_size += remaining as u64;
-                            let buf = [0; 512];
-                            total_size += send_chunks(
-                                ctx,
-                                sink,
-                                chunker,
-                                tw,
-                                &mut std::io::Cursor::new(&buf[..remaining as usize]),
-                                Some(&mut on_chunk),
-                            )? as u64;
-                        }
+    // Chunk boundaries only depend on the data since the previous boundary,
+    // so after reusing the chunks we continue as if we had read them.
+    if let Some(chunk_data) = chunker.force_split() {
+        let addr = crypto::keyed_content_address(&chunk_data, &ctx.hash_key);
+        on_chunk(&addr, chunk_data.len());
+        let compression = if incompressible {
+            crypto::DataCompression::None
+        } else {
+            ctx.compression
+        };
+        tw.add(
+            sink,
+            &addr,
+            ctx.data_ectx.encrypt_data(chunk_data, compression),
+        )?;
+    }
 
-                        if file_len != metadata.len() as usize {
-                            return Err(SendDirError::FilesystemModified);
-                        }
-                    }
+    let mut chunk_start = tail.start;
+    let mut address = Address::default();
+    for (end, tail_address) in tail.ends.iter().zip(tail.addresses.chunks(ADDRESS_SZ)) {
+        address.bytes[..].clone_from_slice(tail_address);
+        tw.add_addr(sink, 0, &address)?;
+        on_chunk(&address, (end - chunk_start) as usize);
+        chunk_start = *end;
+    }
+    ctx.progress.inc(chunk_start - tail.start);
 
-                    let ent_data_chunk_end_idx = tw.data_chunk_count();
-                    let ent_data_chunk_end_offset = chunker.buffered_count() as u64;
+    std::io::Seek::seek(f, std::io::SeekFrom::Start(chunk_start))?;
+    Ok(Some(chunk_start))
+}
 
-                    let mut index_entry = index::IndexEntry {
+// Extensions of formats that are compressed or encrypted.
+const INCOMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "7z", "age", "apk", "avi", "avif", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gpg",
+    "gz", "heic", "jar", "jpeg", "jpg", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4",
+    "odt", "ogg", "opus", "png", "rar", "rpm", "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip",
+    "zst",
+];
+
+// Files are only sampled at their start, where most formats
+// with compressed contents have a small uncompressed header.
+const INCOMPRESSIBLE_SAMPLE_SIZE: usize = 65536;
+
+fn likely_incompressible_file(
+    ctx: &SendContext,
+    path: &std::path::Path,
+    f: &std::fs::File,
+) -> Result<bool, std::io::Error> {
+    if !ctx.skip_incompressible || matches!(ctx.compression, crypto::DataCompression::None) {
+        return Ok(false);
+    }
+    if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+        if INCOMPRESSIBLE_EXTENSIONS
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(ext))
+        {
+            return Ok(true);
+        }
+    }
+    let mut sample = vec![0; INCOMPRESSIBLE_SAMPLE_SIZE];
+    let mut n = 0;
+    while n < sample.len() {
+        match f.read_at(&mut sample[n..], n as u64) {
+            Ok(0) => break,
+            Ok(n_read) => n += n_read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(crypto::likely_incompressible(&sample[..n]))
+}
+
+fn likely_smear_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::InvalidInput
+    )
+}
+
+// An entry removed while its directory is sent is left out, unless the
+// directory keeps changing, then the whole send is restarted.
+fn skip_vanished_entry(
+    ctx: &SendContext,
+    path: &std::path::Path,
+    n_dir_retries: &mut usize,
+) -> Result<(), SendDirError> {
+    if *n_dir_retries == MAX_DIR_RETRIES {
+        return Err(SendDirError::FilesystemModified);
+    }
+    *n_dir_retries += 1;
+    ctx.progress.println(format!(
+        "{} removed while sending, skipping it...",
+        path.display()
+    ));
+    Ok(())
+}
+
+// Stat a file that changed while it was sent, so it can be sent again,
+// None if it was since removed or replaced by something else.
+fn restat_modified_file(
+    ctx: &SendContext,
+    path: &std::path::PathBuf,
+    tar_path: &std::path::PathBuf,
+) -> Result<Option<(std::fs::Metadata, DirentHeader)>, SendDirError> {
+    let metadata = match sent_file_metadata(ctx, path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(None),
+        Err(err) if likely_smear_error(&err) => return Ok(None),
+        Err(err) => return Err(SendDirError::Other(err.into())),
+    };
+    match dirent_header(ctx, &metadata, path, tar_path) {
+        Ok(hdr) => Ok(Some((metadata, hdr))),
+        Err(err) if likely_smear_error(&err) => Ok(None),
+        Err(err) => Err(SendDirError::Other(err.into())),
+    }
+}
+
+// Group a list of absolute paths under root by their parent directory,
+// adding any intermediate directories so the result is a complete tree.
+fn group_file_list(
+    root: &std::path::Path,
+    file_list: &[std::path::PathBuf],
+) -> BTreeMap<std::path::PathBuf, std::collections::BTreeSet<std::path::PathBuf>> {
+    let mut dirs = BTreeMap::new();
+    dirs.insert(root.to_path_buf(), std::collections::BTreeSet::new());
+
+    for p in file_list {
+        let mut p = p.as_path();
+        while p != root {
+            let parent = p.parent().unwrap();
+            let ents: &mut std::collections::BTreeSet<_> =
+                dirs.entry(parent.to_path_buf()).or_default();
+            if !ents.insert(p.to_path_buf()) {
+                break;
+            }
+            p = parent;
+        }
+    }
+
+    dirs
+}
+
+pub const IGNORE_FILE_NAME: &str = ".bupstashignore";
+
+// Parse the ignore file in dir into exclusions for the directory tree below it. Patterns
+// containing a '/' are relative to dir, others match entries at any depth below it.
+fn read_ignore_file(dir: &std::path::Path) -> Result<Vec<glob::Pattern>, failure::Error> {
+    let ignore_file = dir.join(IGNORE_FILE_NAME);
+    let data = match std::fs::read(&ignore_file) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => failure::bail!("unable to read {}: {}", ignore_file.display(), err),
+    };
+    let dir = glob::Pattern::escape(&dir.to_string_lossy());
+    let mut exclusions = Vec::new();
+    for (i, line) in String::from_utf8_lossy(&data).lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.trim_end_matches('/');
+        let pattern = if line.contains('/') {
+            format!("{}/{}", dir, line.trim_start_matches('/'))
+        } else {
+            format!("{}/**/{}", dir, line)
+        };
+        match glob::Pattern::new(&pattern) {
+            Ok(pattern) => exclusions.push(pattern),
+            Err(err) => failure::bail!(
+                "{}:{}: {:?} is not a valid glob: {}",
+                ignore_file.display(),
+                i + 1,
+                line,
+                err
+            ),
+        }
+    }
+    Ok(exclusions)
+}
+
+// The tar header of a directory entry, and its ACLs with --acls. The ACLs are
+// part of the header so they are included in the stat cache key.
+type DirentHeader = (Vec<u8>, Option<acl::PosixAcls>);
+
+fn dirent_header(
+    ctx: &SendContext,
+    metadata: &std::fs::Metadata,
+    full_path: &std::path::PathBuf,
+    short_path: &std::path::PathBuf,
+) -> Result<DirentHeader, std::io::Error> {
+    tar_dirent_header(
+        ctx.selinux,
+        ctx.acls,
+        ctx.deterministic,
+        metadata,
+        full_path,
+        short_path,
+    )
+}
+
+fn tar_dirent_header(
+    selinux: bool,
+    acls: bool,
+    deterministic: bool,
+    metadata: &std::fs::Metadata,
+    full_path: &std::path::PathBuf,
+    short_path: &std::path::PathBuf,
+) -> Result<DirentHeader, std::io::Error> {
+    let acls = if acls {
+        acl::read_acls(full_path, metadata)?
+    } else {
+        None
+    };
+    let hdr = xtar::dirent_to_tarheader(
+        metadata,
+        full_path,
+        short_path,
+        selinux,
+        acls.as_ref(),
+        deterministic,
+    )?;
+    Ok((hdr, acls))
+}
+
+// The parts of SendContext needed to stat directory entries on other threads.
+#[derive(Clone, Copy)]
+struct DirentStatOptions {
+    follow_symlinks: bool,
+    honor_nodump: bool,
+    selinux: bool,
+    acls: bool,
+    deterministic: bool,
+}
+
+// What send_dir needs to know about a directory entry before deciding how to send it.
+struct DirentStat {
+    metadata: Result<std::fs::Metadata, std::io::Error>,
+    // What a symlink points to, when following symlinks.
+    target: Option<std::fs::Metadata>,
+    nodump: bool,
+    // The header of the target if there is one, None if the entry could not be read.
+    header: Option<Result<DirentHeader, std::io::Error>>,
+}
+
+fn stat_dirent(
+    opts: &DirentStatOptions,
+    ent_path: &std::path::PathBuf,
+    tar_path: &std::path::PathBuf,
+) -> DirentStat {
+    let metadata = std::fs::symlink_metadata(ent_path);
+    let (target, nodump, header) = match metadata {
+        Ok(ref metadata) => {
+            let target = if opts.follow_symlinks && metadata.file_type().is_symlink() {
+                std::fs::metadata(ent_path).ok()
+            } else {
+                None
+            };
+            // Symlinks are never nodump, the flag is read without following them.
+            let nodump = opts.honor_nodump && fsutil::has_nodump_flag(ent_path, metadata);
+            let header = tar_dirent_header(
+                opts.selinux,
+                opts.acls,
+                opts.deterministic,
+                target.as_ref().unwrap_or(metadata),
+                ent_path,
+                tar_path,
+            );
+            (target, nodump, Some(header))
+        }
+        Err(_) => (None, false, None),
+    };
+    DirentStat {
+        metadata,
+        target,
+        nodump,
+        header,
+    }
+}
+
+// Stats directory entries and builds their tar headers on worker threads, which
+// hides the latency of network filesystems, see put --stat-workers. Results are
+// returned in the order entries were added so the send remains deterministic.
+struct StatWorkers {
+    job_tx: Option<crossbeam_channel::Sender<(u64, std::path::PathBuf, std::path::PathBuf)>>,
+    done_rx: crossbeam_channel::Receiver<(u64, DirentStat)>,
+    handles: Vec<std::thread::JoinHandle<()>>,
+    done: BTreeMap<u64, DirentStat>,
+    next_seq: u64,
+    next_out: u64,
+    max_in_flight: usize,
+}
+
+impl StatWorkers {
+    fn new(n_workers: usize, opts: DirentStatOptions) -> Result<StatWorkers, failure::Error> {
+        // Enough to keep every worker busy while we wait on the oldest entry.
+        let max_in_flight = n_workers * 4;
+        let (job_tx, job_rx) = crossbeam_channel::bounded(max_in_flight);
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+        let mut handles = Vec::with_capacity(n_workers);
+
+        for _ in 0..n_workers {
+            let job_rx = job_rx.clone();
+            let done_tx = done_tx.clone();
+            handles.push(std::thread::Builder::new().spawn(move || {
+                while let Ok((seq, ent_path, tar_path)) = job_rx.recv() {
+                    let stat = stat_dirent(&opts, &ent_path, &tar_path);
+                    if done_tx.send((seq, stat)).is_err() {
+                        break;
+                    }
+                }
+            })?);
+        }
+
+        Ok(StatWorkers {
+            job_tx: Some(job_tx),
+            done_rx,
+            handles,
+            done: BTreeMap::new(),
+            next_seq: 0,
+            next_out: 0,
+            max_in_flight,
+        })
+    }
+
+    fn add(
+        &mut self,
+        ent_path: &std::path::Path,
+        tar_path: &std::path::Path,
+    ) -> Result<(), failure::Error> {
+        if self
+            .job_tx
+            .as_ref()
+            .unwrap()
+            .send((
+                self.next_seq,
+                ent_path.to_path_buf(),
+                tar_path.to_path_buf(),
+            ))
+            .is_err()
+        {
+            failure::bail!("stat worker exited unexpectedly");
+        }
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    // The result for the oldest entry added, waiting for it if needed.
+    fn next(&mut self) -> Result<DirentStat, failure::Error> {
+        assert!(self.next_out < self.next_seq);
+        loop {
+            if let Some(stat) = self.done.remove(&self.next_out) {
+                self.next_out += 1;
+                return Ok(stat);
+            }
+            match self.done_rx.recv() {
+                Ok((seq, stat)) => {
+                    self.done.insert(seq, stat);
+                }
+                Err(_) => failure::bail!("stat worker exited unexpectedly"),
+            }
+        }
+    }
+}
+
+impl Drop for StatWorkers {
+    fn drop(&mut self) {
+        drop(self.job_tx.take());
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+// What send_dir sends, and what it can reuse from earlier sends.
+struct SendDirOptions<'a, 's> {
+    send_log_session: &'a Option<std::cell::RefCell<sendlog::SendLogSession<'s>>>,
+    // Index entries are delta encoded against this index when set.
+    index_delta_base: Option<&'a IndexDeltaBase>,
+    paths: &'a [std::path::PathBuf],
+    exclusions: &'a [glob::Pattern],
+    inclusions: &'a [glob::Pattern],
+    file_list: Option<&'a [std::path::PathBuf]>,
+}
+
+fn send_dir(
+    ctx: &mut SendContext,
+    sink: &mut dyn htree::Sink,
+    chunker: &mut chunker::Chunker,
+    tw: &mut htree::TreeWriter,
+    idx_chunker: &mut chunker::Chunker,
+    idx_tw: &mut htree::TreeWriter,
+    opts: &SendDirOptions,
+) -> Result<(u64, u64), SendDirError> {
+    let SendDirOptions {
+        send_log_session,
+        index_delta_base,
+        paths,
+        exclusions,
+        inclusions,
+        file_list,
+    } = *opts;
+    let mut index_delta = index_delta_base.map(|base| index::IndexDeltaEncoder::new(&base.index));
+    let index_delta = &mut index_delta;
+    // Directories reached so far, a followed link or bind mount leading back
+    // to one of them is not descended into again so we don't loop forever.
+    let mut visited_dirs = std::collections::HashSet::new();
+    let mut roots = Vec::with_capacity(paths.len());
+    for path in paths.iter() {
+        let path = fsutil::absolute_path(path)?;
+        let metadata = std::fs::metadata(&path)?;
+        // Several roots are entries of the top level directory, and are
+        // marked as visited when it is listed.
+        if paths.len() == 1 {
+            visited_dirs.insert((metadata.dev(), metadata.ino()));
+        }
+        roots.push((path, metadata.dev()));
+    }
+    // With several roots each one is sent under its own name, inside
+    // a top level directory with the metadata of the first of them.
+    let multi_root = roots.len() > 1;
+    let root_tar_path = |root: usize| -> std::path::PathBuf {
+        if multi_root {
+            roots[root].0.file_name().unwrap().into()
+        } else {
+            std::path::PathBuf::new()
+        }
+    };
+    let path = roots[0].0.clone();
+
+    let stat_opts = DirentStatOptions {
+        follow_symlinks: ctx.follow_symlinks,
+        honor_nodump: ctx.honor_nodump,
+        selinux: ctx.selinux,
+        acls: ctx.acls,
+        deterministic: ctx.deterministic,
+    };
+    let mut stat_workers = if ctx.stat_workers > 1 {
+        Some(StatWorkers::new(ctx.stat_workers, stat_opts)?)
+    } else {
+        None
+    };
+
+    let mut addresses: Vec<u8> = Vec::new();
+    let mut rollups = index::DirectoryRollupBuilder::new();
+    let mut owner_names = OwnerNames::default();
+    let mut work_list = std::collections::VecDeque::new();
+    // Totals recorded in the item metadata.
+    let mut data_size: u64 = 0;
+    let mut entry_count: u64 = 0;
+
+    // With an explicit file list the set of directories and their
+    // entries are known up front, so no directory reading is needed.
+    let file_list_dirs = file_list.map(|file_list| group_file_list(&path, file_list));
+
+    match file_list_dirs {
+        Some(ref file_list_dirs) => work_list.extend(
+            file_list_dirs
+                .keys()
+                .map(|dir| (dir.clone(), std::rc::Rc::new(Vec::new()), false, Some(0))),
+        ),
+        // The top level directory of several roots has no path of its own.
+        None if multi_root => work_list.push_back((
+            std::path::PathBuf::new(),
+            std::rc::Rc::new(Vec::new()),
+            false,
+            None,
+        )),
+        None => work_list.push_back((path.clone(), std::rc::Rc::new(Vec::new()), false, Some(0))),
+    }
+
+    // Each directory carries the exclusions from the ignore files of its parents,
+    // whether it is below a directory matching an inclusion, and the root it is in.
+    while let Some((cur_dir, mut ignores, dir_included, dir_root)) = work_list.pop_front() {
+        report_path(ctx, &cur_dir);
+        addresses.clear();
+        // Counts entries of the directory that were left out or sent again, see below.
+        let mut n_dir_retries = 0;
+        let top_level = dir_root.is_none() || (!multi_root && cur_dir == path);
+        let mut hash_state = crypto::HashState::new(Some(&ctx.hash_key));
+        // Incorporate the absolute dir in our cache key.
+        hash_state.update(cur_dir.as_os_str().as_bytes());
+        // Null byte marks the end of path and tar headers in the hash space.
+        hash_state.update(&[0]);
+        // Only added when set so existing stat cache entries remain valid.
+        if ctx.stat_cache_check == StatCacheCheck::MtimeSize {
+            hash_state.update(b"mtime-size\0");
+        }
+        // Directories cached before entries were recorded as V2 entries are sent again.
+        hash_state.update(b"v2-entries\0");
+        if ctx.deterministic {
+            hash_state.update(b"deterministic\0");
+        }
+
+        // An explicit file list is sent as it is.
+        if ctx.ignore_files && file_list_dirs.is_none() && dir_root.is_some() {
+            let dir_ignores = read_ignore_file(&cur_dir)?;
+            if !dir_ignores.is_empty() {
+                ignores = std::rc::Rc::new(ignores.iter().cloned().chain(dir_ignores).collect());
+            }
+        }
+        // Only added when there are ignores so existing stat cache entries remain valid.
+        for pattern in ignores.iter() {
+            hash_state.update(pattern.as_str().as_bytes());
+            hash_state.update(&[0]);
+        }
+        if !inclusions.is_empty() {
+            for pattern in inclusions.iter() {
+                hash_state.update(pattern.as_str().as_bytes());
+                hash_state.update(&[0]);
+            }
+            hash_state.update(&[dir_included as u8]);
+        }
+
+        let mut dir_ents: Vec<std::path::PathBuf> = match file_list_dirs {
+            Some(ref file_list_dirs) => file_list_dirs[&cur_dir].iter().cloned().collect(),
+            None if dir_root.is_none() => roots.iter().map(|(root, _)| root.clone()).collect(),
+            None => match fsutil::read_dirents(&cur_dir) {
+                Ok(dir_ents) => dir_ents.iter().map(|ent| ent.path()).collect(),
+                // Its entry was already sent by its parent, and is left empty.
+                Err(err) if likely_smear_error(&err) && !top_level => {
+                    ctx.progress.println(format!(
+                        "{} removed while sending, skipping its contents...",
+                        cur_dir.display()
+                    ));
+                    continue;
+                }
+                Err(err) if likely_smear_error(&err) => {
+                    return Err(SendDirError::FilesystemModified)
+                }
+                Err(err) => return Err(SendDirError::Other(err.into())),
+            },
+        };
+
+        dir_ents.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+        let mut tar_dir_ents = Vec::new();
+        let mut unchanged_dir_ents = Vec::new();
+
+        if top_level {
+            let metadata = std::fs::metadata(&path)?;
+            if !metadata.is_dir() {
+                return Err(SendDirError::Other(failure::format_err!(
+                    "{} is not a directory",
+                    path.display()
+                )));
+            }
+            let tar_path = ".".into();
+            let (tar_header_bytes, acls) = match dirent_header(ctx, &metadata, &path, &tar_path) {
+                Ok(hdr) => hdr,
+                Err(err) if likely_smear_error(&err) => {
+                    return Err(SendDirError::FilesystemModified)
+                }
+                Err(err) => return Err(SendDirError::Other(err.into())),
+            };
+
+            hash_stat_cache_metadata(&mut hash_state, ctx.stat_cache_check, &metadata);
+            hash_state.update(&tar_header_bytes);
+            tar_dir_ents.push((path.clone(), tar_path, metadata, tar_header_bytes, acls));
+        }
+
+        let mut dir_ents = dir_ents
+            .into_iter()
+            .filter(|ent_path| {
+                !exclusions
+                    .iter()
+                    .chain(ignores.iter())
+                    .any(|excl| excl.matches_path(ent_path))
+            })
+            .map(|ent_path| {
+                let ent_root = match dir_root {
+                    Some(root) => root,
+                    None => roots
+                        .iter()
+                        .position(|(root, _)| *root == ent_path)
+                        .unwrap(),
+                };
+                let tar_path = if ent_path == roots[ent_root].0 {
+                    root_tar_path(ent_root)
+                } else {
+                    root_tar_path(ent_root).join(ent_path.strip_prefix(&roots[ent_root].0).unwrap())
+                };
+                (ent_path, tar_path, ent_root)
+            });
+        // Entries waiting on the stat workers, which run ahead of us.
+        let mut stat_queue = std::collections::VecDeque::new();
+
+        'collect_dir_ents: loop {
+            let (ent_path, tar_path, ent_root, stat) = match stat_workers {
+                Some(ref mut stat_workers) => {
+                    while stat_queue.len() < stat_workers.max_in_flight {
+                        match dir_ents.next() {
+                            Some(ent) => {
+                                stat_workers.add(&ent.0, &ent.1)?;
+                                stat_queue.push_back(ent);
+                            }
+                            None => break,
+                        }
+                    }
+                    match stat_queue.pop_front() {
+                        Some((ent_path, tar_path, ent_root)) => {
+                            (ent_path, tar_path, ent_root, stat_workers.next()?)
+                        }
+                        None => break,
+                    }
+                }
+                None => match dir_ents.next() {
+                    Some((ent_path, tar_path, ent_root)) => {
+                        let stat = stat_dirent(&stat_opts, &ent_path, &tar_path);
+                        (ent_path, tar_path, ent_root, stat)
+                    }
+                    None => break,
+                },
+            };
+
+            let mut metadata = match stat.metadata {
+                Ok(metadata) => metadata,
+                Err(err) if likely_smear_error(&err) => {
+                    skip_vanished_entry(ctx, &ent_path, &mut n_dir_retries)?;
+                    continue 'collect_dir_ents;
+                }
+                Err(err) => return Err(SendDirError::Other(err.into())),
+            };
+            let mut header = stat.header;
+
+            if ctx.follow_symlinks && metadata.file_type().is_symlink() {
+                match stat.target {
+                    Some(target) if target.is_dir() => {
+                        if !visited_dirs.contains(&(target.dev(), target.ino())) {
+                            metadata = target;
+                        } else {
+                            // The header is for the target, but the link is sent.
+                            header = None;
+                        }
+                    }
+                    Some(target) => metadata = target,
+                    // Dangling links are sent as links.
+                    None => (),
+                }
+                // The link target is also part of the cache key.
+                hash_state.update(&metadata.ino().to_le_bytes()[..]);
+            }
+
+            // A nodump directory is skipped along with everything below it.
+            if stat.nodump {
+                continue 'collect_dir_ents;
+            }
+
+            if ctx.exclude_caches
+                && metadata.is_dir()
+                && file_list_dirs.is_none()
+                && fsutil::is_tagged_cache_dir(&ent_path)
+            {
+                continue 'collect_dir_ents;
+            }
+
+            // Directories are always sent so included entries below them keep their place.
+            let included = dir_included
+                || inclusions.is_empty()
+                || inclusions.iter().any(|incl| incl.matches_path(&ent_path));
+            if !included && !metadata.is_dir() {
+                continue 'collect_dir_ents;
+            }
+
+            let header = match header {
+                Some(header) => header,
+                None => dirent_header(ctx, &metadata, &ent_path, &tar_path),
+            };
+            let (tar_header_bytes, acls) = match header {
+                Ok(hdr) => hdr,
+                Err(err) if likely_smear_error(&err) => {
+                    skip_vanished_entry(ctx, &ent_path, &mut n_dir_retries)?;
+                    continue 'collect_dir_ents;
+                }
+                Err(err) => return Err(SendDirError::Other(err.into())),
+            };
+
+            // Mount points, and btrfs subvolumes which have their own device
+            // number, are recorded as empty directories with --one-file-system,
+            // as are directories we have already been into.
+            if metadata.is_dir()
+                && file_list_dirs.is_none()
+                && !(ctx.one_file_system && metadata.dev() != roots[ent_root].1)
+                && visited_dirs.insert((metadata.dev(), metadata.ino()))
+            {
+                work_list.push_back((ent_path.clone(), ignores.clone(), included, Some(ent_root)));
+            }
+
+            hash_stat_cache_metadata(&mut hash_state, ctx.stat_cache_check, &metadata);
+            hash_state.update(&tar_header_bytes);
+
+            if metadata.is_file() && is_unchanged(&metadata, &ctx.changed_since) {
+                // Unchanged entries are cached apart from sent ones.
+                hash_state.update(&[1]);
+                unchanged_dir_ents.push(index::VersionedIndexEntry::UnchangedV1(
+                    index::UnchangedEntry {
                         path: tar_path.to_string_lossy().to_string(),
                         mode: serde_bare::Uint(metadata.permissions().mode() as u64),
-                        size: serde_bare::Uint(if metadata.is_file() {
-                            metadata.size()
-                        } else {
-                            0
-                        }),
-                        tar_size: serde_bare::Uint(tar_ent_size as u64),
+                        size: serde_bare::Uint(metadata.size()),
                         ctime: serde_bare::Uint(metadata.ctime() as u64),
                         ctime_nsec: serde_bare::Uint(metadata.ctime_nsec() as u64),
-                        data_chunk_idx: serde_bare::Uint(ent_data_chunk_idx - dir_data_chunk_idx),
-                        data_chunk_content_idx: serde_bare::Uint(
-                            ent_data_chunk_content_idx - dir_data_chunk_idx,
-                        ),
-                        data_chunk_content_end_idx: serde_bare::Uint(
-                            ent_data_chunk_content_end_idx - dir_data_chunk_idx,
-                        ),
-                        data_chunk_end_idx: serde_bare::Uint(
-                            ent_data_chunk_end_idx - dir_data_chunk_idx,
-                        ),
-                        data_chunk_offset: serde_bare::Uint(ent_data_chunk_offset),
-                        data_chunk_content_offset: serde_bare::Uint(ent_data_chunk_content_offset),
-                        data_chunk_content_end_offset: serde_bare::Uint(
-                            ent_data_chunk_content_end_offset,
-                        ),
-                        data_chunk_end_offset: serde_bare::Uint(ent_data_chunk_end_offset),
-                    };
+                    },
+                ));
+                continue;
+            }
 
-                    dir_index.push(index::VersionedIndexEntry::V1(index_entry.clone()));
+            tar_dir_ents.push((ent_path, tar_path, metadata, tar_header_bytes, acls));
+        }
 
-                    index_entry.data_chunk_idx.0 += dir_data_chunk_idx;
-                    index_entry.data_chunk_content_idx.0 += dir_data_chunk_idx;
-                    index_entry.data_chunk_content_end_idx.0 += dir_data_chunk_idx;
-                    index_entry.data_chunk_end_idx.0 += dir_data_chunk_idx;
+        let hash = hash_state.finish();
 
-                    send_chunks(
+        // Rehashing still records the stat cache, so a later send can use it.
+        let cache_lookup = if send_log_session.is_some()
+            && ctx.use_stat_cache
+            && ctx.stat_cache_check != StatCacheCheck::Rehash
+            && n_dir_retries == 0
+        {
+            send_log_session
+                .as_ref()
+                .unwrap()
+                .borrow_mut()
+                .stat_cache_lookup(&hash)?
+        } else {
+            None
+        };
+
+        match cache_lookup {
+            Some((size, cached_addresses, cached_index)) => {
+                debug_assert!(cached_addresses.len() % ADDRESS_SZ == 0);
+
+                let dir_data_chunk_idx = tw.data_chunk_count();
+
+                let mut address = Address::default();
+                for cached_address in cached_addresses.chunks(ADDRESS_SZ) {
+                    address.bytes[..].clone_from_slice(cached_address);
+                    tw.add_addr(sink, 0, &address)?;
+                }
+
+                let mut dir_index: Vec<index::VersionedIndexEntry> =
+                    serde_bare::from_slice(&cached_index).unwrap();
+
+                for index_entry in dir_index.iter_mut() {
+                    match index_entry {
+                        index::VersionedIndexEntry::V1(ref mut index_entry)
+                        | index::VersionedIndexEntry::V2(index::IndexEntryV2 {
+                            entry: ref mut index_entry,
+                            ..
+                        }) => {
+                            index_entry.data_chunk_idx.0 += dir_data_chunk_idx;
+                            index_entry.data_chunk_content_idx.0 += dir_data_chunk_idx;
+                            index_entry.data_chunk_content_end_idx.0 += dir_data_chunk_idx;
+                            index_entry.data_chunk_end_idx.0 += dir_data_chunk_idx;
+                            rollups.add_entry(index_entry);
+                        }
+                        index::VersionedIndexEntry::UnchangedV1(ref index_entry) => {
+                            rollups.add_entry(&index_entry.to_index_entry());
+                        }
+                        index::VersionedIndexEntry::DirectoryRollupV1(_)
+                        | index::VersionedIndexEntry::AclsV1(_)
+                        | index::VersionedIndexEntry::SparseV1(_)
+                        | index::VersionedIndexEntry::DeltaStartV1
+                        | index::VersionedIndexEntry::BaseRangeV1(_) => (),
+                    }
+                    send_index_entry(
                         ctx,
                         sink,
                         idx_chunker,
                         idx_tw,
-                        &mut std::io::Cursor::new(
-                            &serde_bare::to_vec(&index::VersionedIndexEntry::V1(index_entry))
-                                .unwrap(),
-                        ),
-                        None,
+                        index_delta,
+                        index_entry.clone(),
                     )?;
                 }
 
-                if let Some(chunk_data) = chunker.force_split() {
-                    let addr = crypto::keyed_content_address(&chunk_data, &ctx.hash_key);
-                    on_chunk(&addr);
-                    tw.add(
-                        sink,
-                        &addr,
-                        ctx.data_ectx.encrypt_data(chunk_data, ctx.compression),
-                    )?
-                }
+                ctx.progress.inc(size);
+                data_size += size;
+                entry_count += dir_entry_count(&dir_index);
+
+                let send_log_session = send_log_session.as_ref().unwrap();
+                send_log_session.borrow_mut().add_stat_cache_data(
+                    &hash[..],
+                    size,
+                    &addresses,
+                    &cached_index,
+                )?;
 
-                if send_log_session.is_some() && ctx.use_stat_cache {
-                    send_log_session
-                        .as_ref()
-                        .unwrap()
+                // The files were not read, so their previous chunks remain valid.
+                if ctx.tail_deltas {
+                    for (ent_path, _, metadata, _, _) in tar_dir_ents.iter() {
+                        if metadata.is_file() {
+                            send_log_session.borrow_mut().touch_file_tail(
+                                &file_tail_key(&ctx.hash_key, ent_path, metadata)[..],
+                            )?;
+                        }
+                    }
+                }
+            }
+            None => {
+                let mut total_size: u64 = 0;
+                // Set when a file that could not be opened was left out.
+                let mut dir_skipped = false;
+                // Set while sending the contents of a file, see put --tail-deltas.
+                let file_chunks = std::cell::RefCell::new(None::<FileChunks>);
+                let mut on_chunk = |addr: &Address, len: usize| {
+                    addresses.extend_from_slice(&addr.bytes[..]);
+                    if let Some(ref mut file_chunks) = *file_chunks.borrow_mut() {
+                        file_chunks.add(addr, len);
+                    }
+                };
+
+                let dir_data_chunk_idx = tw.data_chunk_count();
+                let mut dir_index: Vec<index::VersionedIndexEntry> =
+                    Vec::with_capacity(tar_dir_ents.len());
+
+                for (ent_path, tar_path, mut metadata, mut header_bytes, mut acls) in
+                    tar_dir_ents.drain(..)
+                {
+                    report_path(ctx, &ent_path);
+
+                    // A file that changed while it was read is sent again on its own, undoing
+                    // just the chunks of that file, --verify-reads finds more such files.
+                    let verify_reads = if metadata.is_file() {
+                        ctx.verify_reads
+                    } else {
+                        None
+                    };
+                    let mut n_retries = 0;
+                    let mut holes = Vec::new();
+                    let mut content_hash = None;
+                    let index_entry = loop {
+                        let total_size_before = total_size;
+
+                        // Opened before its header is sent, so a file that
+                        // cannot be opened can be left out entirely.
+                        let mut opened_file = None;
+                        if metadata.is_file() {
+                            match fsutil::open_file_for_backup(&ent_path) {
+                                Ok(f) => opened_file = Some(f),
+                                Err(err) if likely_smear_error(&err) => {
+                                    skip_vanished_entry(ctx, &ent_path, &mut n_dir_retries)?;
+                                    break None;
+                                }
+                                Err(err) if ctx.open_errors == OpenErrors::Skip => {
+                                    ctx.progress.println(format!(
+                                        "unable to open {}, skipping it: {}",
+                                        ent_path.display(),
+                                        err
+                                    ));
+                                    ctx.skipped_files.insert(ent_path.clone());
+                                    dir_skipped = true;
+                                    break None;
+                                }
+                                Err(err) => return Err(SendDirError::Other(err.into())),
+                            }
+                            chunker.mark();
+                            tw.mark();
+                        }
+
+                        let mut tar_ent_size = header_bytes.len() as u64;
+                        let ent_data_chunk_idx = tw.data_chunk_count();
+                        let ent_data_chunk_offset = chunker.buffered_count() as u64;
+
+                        total_size += send_chunks(
+                            ctx,
+                            sink,
+                            chunker,
+                            tw,
+                            &mut std::io::Cursor::new(&header_bytes[..]),
+                            false,
+                            Some(&mut on_chunk),
+                        )? as u64;
+
+                        let ent_data_chunk_content_idx = tw.data_chunk_count();
+                        let ent_data_chunk_content_offset = chunker.buffered_count() as u64;
+
+                        let mut ent_data_chunk_content_end_idx = ent_data_chunk_content_idx;
+                        let mut ent_data_chunk_content_end_offset = ent_data_chunk_content_offset;
+
+                        if let Some(mut file) = opened_file {
+                            fsutil::advise_read_once(&file)?;
+                            let incompressible = likely_incompressible_file(ctx, &ent_path, &file)?;
+
+                            let tail_key = if ctx.tail_deltas && send_log_session.is_some() {
+                                Some(file_tail_key(&ctx.hash_key, &ent_path, &metadata))
+                            } else {
+                                None
+                            };
+                            if tail_key.is_some() {
+                                *file_chunks.borrow_mut() =
+                                    Some(FileChunks::new(chunker.buffered_count()));
+                            }
+
+                            // The chunks of an unchanged prefix are reused instead of read again,
+                            // --verify-reads checks need the whole file to be read.
+                            let mut reused_len = 0;
+                            if let (Some(ref key), None) = (tail_key, verify_reads) {
+                                let tail = send_log_session
+                                    .as_ref()
+                                    .unwrap()
+                                    .borrow()
+                                    .file_tail_lookup(&key[..])?;
+                                if let Some(tail) = tail {
+                                    if metadata.size() >= tail.size
+                                        && sample_file_prefix(&ctx.hash_key, &file, tail.size)?
+                                            .as_deref()
+                                            == Some(&tail.samples[..])
+                                    {
+                                        reused_len = match send_file_tail_prefix(
+                                            ctx,
+                                            sink,
+                                            chunker,
+                                            tw,
+                                            &mut file,
+                                            &tail,
+                                            incompressible,
+                                            &mut on_chunk,
+                                        )? {
+                                            Some(reused_len) => reused_len as usize,
+                                            // The file changed since it was opened, like below.
+                                            None => {
+                                                if n_retries == MAX_FILE_RETRIES
+                                                    || n_dir_retries == MAX_DIR_RETRIES
+                                                {
+                                                    return Err(SendDirError::FilesystemModified);
+                                                }
+                                                n_retries += 1;
+                                                n_dir_retries += 1;
+                                                file_chunks.borrow_mut().take();
+                                                chunker.rewind();
+                                                tw.rewind();
+                                                total_size = total_size_before;
+                                                match restat_modified_file(
+                                                    ctx, &ent_path, &tar_path,
+                                                )? {
+                                                    Some(restat) => {
+                                                        (metadata, (header_bytes, acls)) = restat
+                                                    }
+                                                    None => break None,
+                                                }
+                                                continue;
+                                            }
+                                        };
+                                    }
+                                }
+                            }
+
+                            let search_end = if maybe_sparse(&metadata) {
+                                metadata.size()
+                            } else {
+                                0
+                            };
+                            let mut sparse =
+                                SparseReader::new(&mut file, reused_len as u64, search_end);
+                            let mut f = EdgeBlocksReader::new(
+                                &mut sparse,
+                                verify_reads == Some(VerifyReads::Blocks),
+                            );
+                            let mut hashed = ContentHashReader::new(&mut f, &ctx.hash_key);
+                            let file_len = reused_len
+                                + send_chunks(
+                                    ctx,
+                                    sink,
+                                    chunker,
+                                    tw,
+                                    &mut hashed,
+                                    incompressible,
+                                    Some(&mut on_chunk),
+                                )?;
+                            // The reused prefix was not read, so cannot be hashed.
+                            content_hash = if reused_len == 0 {
+                                Some(hashed.finish())
+                            } else {
+                                None
+                            };
+                            let content_chunks = file_chunks.borrow_mut().take();
+
+                            tar_ent_size += file_len as u64;
+                            total_size += file_len as u64;
+
+                            ent_data_chunk_content_end_idx = tw.data_chunk_count();
+                            ent_data_chunk_content_end_offset = chunker.buffered_count() as u64;
+
+                            /* Tar entries are rounded to 512 bytes */
+                            let remaining = 512 - (file_len % 512);
+                            if remaining < 512 {
+                                tar_ent_size += remaining as u64;
+                                let buf = [0; 512];
+                                total_size += send_chunks(
+                                    ctx,
+                                    sink,
+                                    chunker,
+                                    tw,
+                                    &mut std::io::Cursor::new(&buf[..remaining]),
+                                    false,
+                                    Some(&mut on_chunk),
+                                )? as u64;
+                            }
+
+                            let modified = if file_len != metadata.len() as usize {
+                                true
+                            } else if verify_reads.is_some() {
+                                file_changed_while_read(ctx, &ent_path, &metadata, &f)?
+                            } else {
+                                false
+                            };
+                            holes = std::mem::take(&mut sparse.holes);
+
+                            if let (Some(key), Some(content_chunks), false) =
+                                (tail_key, content_chunks, modified || n_retries != 0)
+                            {
+                                if !content_chunks.ends.is_empty() {
+                                    if let Some(samples) =
+                                        sample_file_prefix(&ctx.hash_key, &file, file_len as u64)?
+                                    {
+                                        send_log_session.as_ref().unwrap().borrow().add_file_tail(
+                                            &key[..],
+                                            &sendlog::FileTail {
+                                                size: file_len as u64,
+                                                samples,
+                                                start: content_chunks.start,
+                                                ends: content_chunks.ends,
+                                                addresses: content_chunks.addresses,
+                                            },
+                                        )?;
+                                    }
+                                }
+                            }
+
+                            if modified {
+                                if n_retries == MAX_FILE_RETRIES || n_dir_retries == MAX_DIR_RETRIES
+                                {
+                                    return Err(SendDirError::FilesystemModified);
+                                }
+                                n_retries += 1;
+                                n_dir_retries += 1;
+                                chunker.rewind();
+                                tw.rewind();
+                                total_size = total_size_before;
+                                ctx.progress.println(format!(
+                                    "{} modified while sending, sending it again...",
+                                    ent_path.display()
+                                ));
+                                // A file removed or replaced since is left out.
+                                match restat_modified_file(ctx, &ent_path, &tar_path)? {
+                                    Some(restat) => (metadata, (header_bytes, acls)) = restat,
+                                    None => break None,
+                                }
+                                continue;
+                            }
+                        }
+
+                        if metadata.is_file() {
+                            chunker.clear_mark();
+                            tw.clear_mark();
+                        }
+
+                        let ent_data_chunk_end_idx = tw.data_chunk_count();
+                        let ent_data_chunk_end_offset = chunker.buffered_count() as u64;
+
+                        break Some(index::IndexEntry {
+                            path: tar_path.to_string_lossy().to_string(),
+                            mode: serde_bare::Uint(metadata.permissions().mode() as u64),
+                            size: serde_bare::Uint(if metadata.is_file() {
+                                metadata.size()
+                            } else {
+                                0
+                            }),
+                            tar_size: serde_bare::Uint(tar_ent_size as u64),
+                            ctime: serde_bare::Uint(metadata.ctime() as u64),
+                            ctime_nsec: serde_bare::Uint(metadata.ctime_nsec() as u64),
+                            data_chunk_idx: serde_bare::Uint(
+                                ent_data_chunk_idx - dir_data_chunk_idx,
+                            ),
+                            data_chunk_content_idx: serde_bare::Uint(
+                                ent_data_chunk_content_idx - dir_data_chunk_idx,
+                            ),
+                            data_chunk_content_end_idx: serde_bare::Uint(
+                                ent_data_chunk_content_end_idx - dir_data_chunk_idx,
+                            ),
+                            data_chunk_end_idx: serde_bare::Uint(
+                                ent_data_chunk_end_idx - dir_data_chunk_idx,
+                            ),
+                            data_chunk_offset: serde_bare::Uint(ent_data_chunk_offset),
+                            data_chunk_content_offset: serde_bare::Uint(
+                                ent_data_chunk_content_offset,
+                            ),
+                            data_chunk_content_end_offset: serde_bare::Uint(
+                                ent_data_chunk_content_end_offset,
+                            ),
+                            data_chunk_end_offset: serde_bare::Uint(ent_data_chunk_end_offset),
+                        });
+                    };
+                    let mut index_entry = match index_entry {
+                        Some(index_entry) => index_entry,
+                        None => continue,
+                    };
+
+                    let stat = entry_stat(ctx, &ent_path, &metadata, index_entry.kind());
+                    // Restore takes owners from the tar headers, which have none.
+                    let entry_owner_names = if ctx.deterministic {
+                        None
+                    } else {
+                        Some(owner_names.owner_names(&metadata))
+                    };
+                    dir_index.push(index::VersionedIndexEntry::V2(index::IndexEntryV2 {
+                        entry: index_entry.clone(),
+                        stat: stat.clone(),
+                        owner_names: entry_owner_names.clone(),
+                        content_hash: content_hash.clone(),
+                    }));
+                    rollups.add_entry(&index_entry);
+
+                    index_entry.data_chunk_idx.0 += dir_data_chunk_idx;
+                    index_entry.data_chunk_content_idx.0 += dir_data_chunk_idx;
+                    index_entry.data_chunk_content_end_idx.0 += dir_data_chunk_idx;
+                    index_entry.data_chunk_end_idx.0 += dir_data_chunk_idx;
+
+                    send_index_entry(
+                        ctx,
+                        sink,
+                        idx_chunker,
+                        idx_tw,
+                        index_delta,
+                        index::VersionedIndexEntry::V2(index::IndexEntryV2 {
+                            entry: index_entry,
+                            stat,
+                            owner_names: entry_owner_names,
+                            content_hash,
+                        }),
+                    )?;
+
+                    if !holes.is_empty() {
+                        let sparse_entry =
+                            index::VersionedIndexEntry::SparseV1(index::SparseHoles {
+                                path: tar_path.to_string_lossy().to_string(),
+                                holes,
+                            });
+                        send_index_entry(
+                            ctx,
+                            sink,
+                            idx_chunker,
+                            idx_tw,
+                            index_delta,
+                            sparse_entry.clone(),
+                        )?;
+                        dir_index.push(sparse_entry);
+                    }
+
+                    if let Some(acls) = acls {
+                        let acls_entry = index::VersionedIndexEntry::AclsV1(index::EntryAcls {
+                            path: tar_path.to_string_lossy().to_string(),
+                            access: acls.access,
+                            default: acls.default,
+                        });
+                        send_index_entry(
+                            ctx,
+                            sink,
+                            idx_chunker,
+                            idx_tw,
+                            index_delta,
+                            acls_entry.clone(),
+                        )?;
+                        dir_index.push(acls_entry);
+                    }
+                }
+
+                for unchanged_ent in unchanged_dir_ents.drain(..) {
+                    if let index::VersionedIndexEntry::UnchangedV1(ref ent) = unchanged_ent {
+                        rollups.add_entry(&ent.to_index_entry());
+                    }
+                    send_index_entry(
+                        ctx,
+                        sink,
+                        idx_chunker,
+                        idx_tw,
+                        index_delta,
+                        unchanged_ent.clone(),
+                    )?;
+                    dir_index.push(unchanged_ent);
+                }
+
+                if let Some(chunk_data) = chunker.force_split() {
+                    let addr = crypto::keyed_content_address(&chunk_data, &ctx.hash_key);
+                    on_chunk(&addr, chunk_data.len());
+                    tw.add(
+                        sink,
+                        &addr,
+                        ctx.data_ectx.encrypt_data(chunk_data, ctx.compression),
+                    )?
+                }
+
+                data_size += total_size;
+                entry_count += dir_entry_count(&dir_index);
+
+                // The cache key was computed from the metadata of entries that have since
+                // changed or were left out, so the directory is not cached.
+                if send_log_session.is_some()
+                    && ctx.use_stat_cache
+                    && n_dir_retries == 0
+                    && !dir_skipped
+                {
+                    send_log_session
+                        .as_ref()
+                        .unwrap()
                         .borrow_mut()
                         .add_stat_cache_data(
                             &hash[..],
@@ -745,95 +2941,1410 @@ fn send_dir(
         }
     }
 
-    // The final entry in a tarball is two null files.
-    let buf = [0; 1024];
-    send_chunks(
-        ctx,
-        sink,
-        chunker,
-        tw,
-        &mut std::io::Cursor::new(&buf[..]),
-        None,
-    )?;
-
-    Ok(())
+    // The final entry in a tarball is two null files.
+    let buf = [0; 1024];
+    data_size += send_chunks(
+        ctx,
+        sink,
+        chunker,
+        tw,
+        &mut std::io::Cursor::new(&buf[..]),
+        false,
+        None,
+    )? as u64;
+
+    // Directory rollups are only complete once every entry has been seen,
+    // so they are appended after all the regular index entries.
+    for rollup in rollups.finish() {
+        send_index_entry(
+            ctx,
+            sink,
+            idx_chunker,
+            idx_tw,
+            index_delta,
+            index::VersionedIndexEntry::DirectoryRollupV1(rollup),
+        )?;
+    }
+
+    if let Some(index_entry) = index_delta.as_mut().and_then(|d| d.finish()) {
+        send_chunks(
+            ctx,
+            sink,
+            idx_chunker,
+            idx_tw,
+            &mut std::io::Cursor::new(&serde_bare::to_vec(&index_entry).unwrap()),
+            false,
+            None,
+        )?;
+    }
+
+    Ok((data_size, entry_count))
+}
+
+// The stat recorded in V2 index entries, with the owner and
+// modification time zeroed like the tar headers of deterministic sends.
+fn entry_stat(
+    ctx: &SendContext,
+    ent_path: &std::path::Path,
+    metadata: &std::fs::Metadata,
+    kind: index::IndexEntryKind,
+) -> index::EntryStat {
+    let link_target = if metadata.file_type().is_symlink() {
+        std::fs::read_link(ent_path)
+            .ok()
+            .map(|target| target.to_string_lossy().to_string())
+    } else {
+        None
+    };
+    let (uid, gid, mtime, mtime_nsec) = if ctx.deterministic {
+        (0, 0, 0, 0)
+    } else {
+        (
+            metadata.uid() as u64,
+            metadata.gid() as u64,
+            metadata.mtime() as u64,
+            metadata.mtime_nsec() as u64,
+        )
+    };
+    index::EntryStat {
+        kind,
+        uid: serde_bare::Uint(uid),
+        gid: serde_bare::Uint(gid),
+        mtime: serde_bare::Uint(mtime),
+        mtime_nsec: serde_bare::Uint(mtime_nsec),
+        nlink: serde_bare::Uint(metadata.nlink()),
+        link_target,
+    }
+}
+
+// ACLs, holes and content hashes are stored as separate index entries,
+// but are not directory entries themselves.
+fn dir_entry_count(dir_index: &[index::VersionedIndexEntry]) -> u64 {
+    dir_index
+        .iter()
+        .filter(|ent| {
+            !matches!(
+                ent,
+                index::VersionedIndexEntry::AclsV1(_) | index::VersionedIndexEntry::SparseV1(_)
+            )
+        })
+        .count() as u64
+}
+
+fn send_index_entry(
+    ctx: &mut SendContext,
+    sink: &mut dyn htree::Sink,
+    idx_chunker: &mut chunker::Chunker,
+    idx_tw: &mut htree::TreeWriter,
+    index_delta: &mut Option<index::IndexDeltaEncoder>,
+    index_entry: index::VersionedIndexEntry,
+) -> Result<(), failure::Error> {
+    let index_entries = match index_delta {
+        Some(index_delta) => index_delta.add(index_entry),
+        None => vec![index_entry],
+    };
+    for index_entry in index_entries {
+        send_chunks(
+            ctx,
+            sink,
+            idx_chunker,
+            idx_tw,
+            &mut std::io::Cursor::new(&serde_bare::to_vec(&index_entry).unwrap()),
+            false,
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+pub struct DataRequestContext {
+    pub progress: indicatif::ProgressBar,
+    pub primary_key_id: Xid,
+    pub hash_key_part_1: crypto::PartialHashKey,
+    pub data_dctx: crypto::DecryptionContext,
+    pub metadata_dctx: crypto::DecryptionContext,
+    // Shared by every request of a command, see SharedDecodeWorkers.
+    pub decode_workers: SharedDecodeWorkers,
+}
+
+// Ask for the data of an item, the server replies with its metadata, then streams the chunks.
+fn begin_data_request(
+    id: Xid,
+    ranges: Option<Vec<index::HTreeDataRange>>,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<itemset::VersionedItemMetadata, failure::Error> {
+    write_packet(w, &Packet::TRequestData(TRequestData { id, ranges }))?;
+
+    match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+        Packet::RRequestData(resp) => match resp.metadata {
+            Some(metadata) => Ok(metadata),
+            None => failure::bail!("no stored items with the requested id"),
+        },
+        _ => failure::bail!("protocol error, expected ack request packet"),
+    }
+}
+
+// Fetch whole data chunks of an item, on_chunk is called with the index and data of each
+// chunk in the ranges. Used by mount, which caches chunks so a read at any offset of a
+// file does not have to fetch the file from its start.
+pub fn request_data_chunks(
+    mut ctx: DataRequestContext,
+    id: Xid,
+    ranges: Vec<index::HTreeDataRange>,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+    on_chunk: &mut dyn FnMut(u64, Vec<u8>) -> Result<(), failure::Error>,
+) -> Result<(), failure::Error> {
+    let metadata = begin_data_request(id, Some(ranges.clone()), r, w)?;
+    let hash_key = item_hash_key(&mut ctx, &metadata)?;
+    let data_tree = &metadata.plain_text_metadata().data_tree;
+    let mut tr = htree::TreeReader::new(data_tree.height, &data_tree.address);
+    let mut source = ChunkSource::Stream(r);
+    let mut filter = htree::DataRangeFilter::new(ranges, data_tree.height, &data_tree.address);
+
+    while let Some((height, addr)) = tr.next_addr()? {
+        if height != 0 && filter.done() {
+            continue;
+        }
+
+        let data = source.next_chunk(&mut ctx.data_dctx, &hash_key, height, &addr)?;
+
+        if height == 0 {
+            if let Some(chunk_idx) = filter.next_data_chunk() {
+                on_chunk(chunk_idx, data)?;
+            }
+        } else if height == 1 {
+            tr.push_level(0, filter.filter_level(&data))?;
+        } else if !filter.done() {
+            tr.push_level(height - 1, data)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Regular files of an index that have a content hash, in the order of the data stream.
+fn hashed_files(content_index: &[index::VersionedIndexEntry]) -> Vec<(&index::IndexEntry, &[u8])> {
+    content_index
+        .iter()
+        .filter_map(|ent| match ent {
+            index::VersionedIndexEntry::V2(ent) => ent
+                .content_hash
+                .as_ref()
+                .map(|hash| (&ent.entry, &hash[..])),
+            _ => None,
+        })
+        .filter(|(ent, _)| matches!(ent.kind(), index::IndexEntryKind::Regular))
+        .collect()
+}
+
+// Fetch all the data of a directory snapshot, checking every chunk as request_data_stream
+// does, and hash the data of each file. Returns the paths of files whose data does not
+// match the content hash in the index.
+pub fn verify_content_hashes(
+    mut ctx: DataRequestContext,
+    metadata: &itemset::VersionedItemMetadata,
+    id: Xid,
+    content_index: &[index::VersionedIndexEntry],
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<Vec<String>, failure::Error> {
+    let hash_key = item_hash_key(&mut ctx, metadata)?;
+    let mut mismatched = Vec::new();
+    let mut files = Vec::new();
+    for (ent, hash) in hashed_files(content_index).into_iter() {
+        // Empty files may not have a chunk holding their content.
+        if ent.size.0 == 0 {
+            if crypto::HashState::new(Some(&hash_key)).finish()[..] != *hash {
+                mismatched.push(ent.path.clone());
+            }
+        } else {
+            files.push((ent, hash));
+        }
+    }
+
+    // Only the chunks up to the end of the last file are needed.
+    let end_idx = match files.last() {
+        Some((ent, _)) => ent.data_chunk_content_end_idx.0,
+        None => return Ok(mismatched),
+    };
+
+    let mut next_file = 0;
+    let mut hash_state: Option<crypto::HashState> = None;
+    request_data_chunks(
+        ctx,
+        id,
+        vec![index::HTreeDataRange {
+            start_idx: 0,
+            end_idx,
+        }],
+        r,
+        w,
+        &mut |chunk_idx, data| {
+            while let Some((ent, hash)) = files.get(next_file) {
+                if ent.data_chunk_content_idx.0 > chunk_idx {
+                    break;
+                }
+                let start = if ent.data_chunk_content_idx.0 == chunk_idx {
+                    ent.data_chunk_content_offset.0 as usize
+                } else {
+                    0
+                };
+                let end = if ent.data_chunk_content_end_idx.0 == chunk_idx {
+                    ent.data_chunk_content_end_offset.0 as usize
+                } else {
+                    data.len()
+                };
+                if start > end || end > data.len() {
+                    return Err(ClientError::CorruptOrTamperedDataError.into());
+                }
+                hash_state
+                    .get_or_insert_with(|| crypto::HashState::new(Some(&hash_key)))
+                    .update(&data[start..end]);
+                if ent.data_chunk_content_end_idx.0 != chunk_idx {
+                    // The file continues in the next chunk.
+                    break;
+                }
+                if hash_state.take().unwrap().finish()[..] != **hash {
+                    mismatched.push(ent.path.clone());
+                }
+                next_file += 1;
+            }
+            Ok(())
+        },
+    )?;
+
+    // Files the data ended before.
+    for (ent, _) in files[next_file..].iter() {
+        mismatched.push(ent.path.clone());
+    }
+
+    Ok(mismatched)
+}
+
+pub fn request_data_stream(
+    mut ctx: DataRequestContext,
+    id: Xid,
+    pick: Option<index::PickMap>,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+    out: &mut dyn std::io::Write,
+) -> Result<(), failure::Error> {
+    let ranges = pick.as_ref().map(|pick| pick.data_chunk_ranges.clone());
+    let metadata = begin_data_request(id, ranges, r, w)?;
+
+    // We only wanted to show the progress bar until we could start getting
+    // messages, at this point we know the repository is unlocked.
+    ctx.progress.finish_and_clear();
+
+    let hash_key = item_hash_key(&mut ctx, &metadata)?;
+    let data_tree = &metadata.plain_text_metadata().data_tree;
+    let mut tr = htree::TreeReader::new(data_tree.height, &data_tree.address);
+    let mut source = ChunkSource::Stream(r);
+
+    if let Some(pick) = pick {
+        let filter = htree::DataRangeFilter::new(
+            pick.data_chunk_ranges.clone(),
+            data_tree.height,
+            &data_tree.address,
+        );
+        receive_partial_htree(ctx, &hash_key, &mut source, &mut tr, filter, pick, out)?;
+    } else {
+        receive_htree(ctx, &hash_key, &mut source, &mut tr, out)?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+// How 'restore --incremental' decides a file already in the target matches the snapshot.
+pub enum RestoreCheck {
+    // The size and modification time.
+    MtimeSize,
+    // The content hash recorded by put, files without one fall back to MtimeSize.
+    ContentHash(crypto::HashKey),
+}
+
+pub struct IncrementalRestore {
+    pub check: RestoreCheck,
+    // Remove files in the target that are not in the snapshot.
+    pub delete: bool,
+}
+
+// Extract a directory snapshot straight into a local directory, without an
+// external tar. The progress bar counts tar bytes and shows the file being restored.
+#[allow(clippy::too_many_arguments)]
+pub fn restore_to_local_dir(
+    ctx: DataRequestContext,
+    id: Xid,
+    content_index: &[index::VersionedIndexEntry],
+    pick: Option<index::PickMap>,
+    into: &std::path::Path,
+    numeric_owner: bool,
+    incremental: Option<IncrementalRestore>,
+    progress: indicatif::ProgressBar,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<(), failure::Error> {
+    use std::os::unix::io::FromRawFd;
+
+    match std::fs::read_dir(into) {
+        Ok(mut entries) => {
+            if incremental.is_none() && entries.next().is_some() {
+                failure::bail!(
+                    "refusing to restore into non empty directory {}",
+                    into.display()
+                );
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir_all(into)?,
+        Err(err) => failure::bail!("unable to open {}: {}", into.display(), err),
+    }
+
+    let (pipe_r, pipe_w) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+    let pipe_r = unsafe { std::fs::File::from_raw_fd(pipe_r) };
+    let mut pipe_w = unsafe { std::fs::File::from_raw_fd(pipe_w) };
+
+    let mut sparse_files = std::collections::HashMap::new();
+    let mut owners = std::collections::HashMap::new();
+    // Tar headers only have whole seconds, the index has the exact mtime
+    // that incremental restores compare against.
+    let mut mtimes = std::collections::HashMap::new();
+    let mut local_owners = LocalOwners::default();
+    // Sockets are not in the tar stream, they are created from the index.
+    let mut sockets = Vec::new();
+    for ent in content_index.iter() {
+        if let Some(owner) = match ent {
+            index::VersionedIndexEntry::V2(ent) => ent.owner(),
+            _ => None,
+        } {
+            let ids = if numeric_owner {
+                (owner.uid.0 as u32, owner.gid.0 as u32)
+            } else {
+                local_owners.map(&owner)
+            };
+            owners.insert(std::path::PathBuf::from(owner.path), ids);
+        }
+        match ent {
+            index::VersionedIndexEntry::SparseV1(ent) => {
+                sparse_files.insert(std::path::PathBuf::from(&ent.path), ent.holes.clone());
+            }
+            index::VersionedIndexEntry::V2(ent)
+                if ent.stat.kind == index::IndexEntryKind::Regular =>
+            {
+                mtimes.insert(
+                    std::path::PathBuf::from(&ent.entry.path),
+                    (ent.stat.mtime.0, ent.stat.mtime_nsec.0),
+                );
+            }
+            ent => match ent.index_entry() {
+                Some(ent)
+                    if matches!(ent.kind(), index::IndexEntryKind::Socket)
+                        && pick.as_ref().is_none_or(|pick| pick.includes(&ent.path)) =>
+                {
+                    sockets.push((std::path::PathBuf::from(&ent.path), ent.mode.0 as u32));
+                }
+                _ => (),
+            },
+        }
+    }
+
+    let pick = match incremental {
+        Some(incremental) => {
+            progress.set_message("comparing...");
+            Some(prepare_incremental_restore(
+                into,
+                content_index,
+                pick.as_ref(),
+                &incremental,
+            )?)
+        }
+        None => pick,
+    };
+
+    let size = match &pick {
+        Some(pick) => pick.size,
+        None => content_index
+            .iter()
+            .map(|ent| match ent.index_entry() {
+                Some(ent) => ent.tar_size.0,
+                None => 0,
+            })
+            .sum(),
+    };
+    progress.set_length(size);
+    progress.set_message("restoring...");
+
+    let extractor = {
+        let into = into.to_path_buf();
+        let progress = progress.clone();
+        std::thread::spawn(move || {
+            extract_tar(
+                pipe_r,
+                &into,
+                &sparse_files,
+                &owners,
+                &mtimes,
+                &sockets,
+                &progress,
+            )
+        })
+    };
+
+    let result = request_data_stream(ctx, id, pick, r, w, &mut pipe_w);
+    drop(pipe_w);
+    // If extraction failed, its error explains the failure better than the broken pipe we get.
+    extractor.join().unwrap()?;
+    result?;
+
+    progress.finish_and_clear();
+    Ok(())
+}
+
+// Prepare the target of an incremental restore and pick the entries to fetch. Entries
+// that differ from the snapshot are removed so they can be restored again, directory
+// entries are always fetched so their permissions and times are applied last.
+fn prepare_incremental_restore(
+    into: &std::path::Path,
+    content_index: &[index::VersionedIndexEntry],
+    pick: Option<&index::PickMap>,
+    incremental: &IncrementalRestore,
+) -> Result<index::PickMap, failure::Error> {
+    let canonical_into = std::fs::canonicalize(into)?;
+
+    let mut stats = std::collections::HashMap::new();
+    let mut content_hashes = std::collections::HashMap::new();
+    let mut paths = std::collections::HashSet::new();
+    for ent in content_index.iter() {
+        match ent {
+            index::VersionedIndexEntry::V2(ent) => {
+                stats.insert(ent.entry.path.as_str(), &ent.stat);
+                if let Some(hash) = &ent.content_hash {
+                    content_hashes.insert(ent.entry.path.as_str(), &hash[..]);
+                }
+            }
+            // Unchanged files have no data in this snapshot, but are still part of it
+            // and must not be deleted, they are restored from an earlier snapshot.
+            index::VersionedIndexEntry::UnchangedV1(ent)
+                if pick.is_none_or(|pick| pick.includes(&ent.path)) =>
+            {
+                paths.insert(ent.path.as_str());
+            }
+            _ => (),
+        }
+        if let Some(ent) = ent.index_entry() {
+            if pick.is_none_or(|pick| pick.includes(&ent.path)) {
+                paths.insert(ent.path.as_str());
+            }
+        }
+    }
+
+    // Removed entries are restored by the comparison below, so extraneous
+    // entries are deleted first.
+    if incremental.delete {
+        let root = pick.map(|pick| pick.path.as_str()).unwrap_or(".");
+        delete_extraneous(&canonical_into, root, &paths)?;
+    }
+
+    let mut changed = std::collections::HashSet::new();
+    for ent in content_index.iter() {
+        let ent = match ent.index_entry() {
+            Some(ent) if paths.contains(ent.path.as_str()) => ent,
+            _ => continue,
+        };
+        let dest = match restore_path(&canonical_into, std::path::Path::new(&ent.path)) {
+            Some(dest) => dest,
+            None => failure::bail!(
+                "refusing to restore {}, it is outside the target directory",
+                ent.path
+            ),
+        };
+        let existing = match dest.symlink_metadata() {
+            Ok(existing) => existing,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                changed.insert(ent.path.as_str());
+                continue;
+            }
+            Err(err) => failure::bail!("unable to stat {}: {}", dest.display(), err),
+        };
+
+        if ent.kind() == index::IndexEntryKind::Directory {
+            changed.insert(ent.path.as_str());
+            if existing.is_dir() {
+                make_restored_dir_writable(&dest, &existing)?;
+            } else {
+                std::fs::remove_file(&dest)?;
+            }
+            continue;
+        }
+
+        if restored_entry_matches(
+            ent,
+            stats.get(ent.path.as_str()).copied(),
+            content_hashes.get(ent.path.as_str()).copied(),
+            &dest,
+            &existing,
+            &incremental.check,
+        )? {
+            continue;
+        }
+
+        changed.insert(ent.path.as_str());
+        // Unpacking only replaces regular files, remove whatever is in the way.
+        if existing.is_dir() {
+            std::fs::remove_dir_all(&dest)?;
+        } else {
+            std::fs::remove_file(&dest)?;
+        }
+    }
+
+    Ok(index::pick_entries(content_index, &|ent| {
+        changed.contains(ent.path.as_str())
+    }))
+}
+
+// Hash the files restored from a directory snapshot, returning the paths
+// of files that are missing or do not match the content hash in the index.
+pub fn verify_restored_files(
+    into: &std::path::Path,
+    content_index: &[index::VersionedIndexEntry],
+    pick: Option<&index::PickMap>,
+    hash_key: &crypto::HashKey,
+) -> Result<Vec<String>, failure::Error> {
+    let mut mismatched = Vec::new();
+    for (ent, hash) in hashed_files(content_index).into_iter() {
+        if !pick.is_none_or(|pick| pick.includes(&ent.path)) {
+            continue;
+        }
+        let mut f = match std::fs::File::open(into.join(&ent.path)) {
+            Ok(f) => f,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                mismatched.push(ent.path.clone());
+                continue;
+            }
+            Err(err) => failure::bail!("unable to open {}: {}", ent.path, err),
+        };
+        let mut hashed = ContentHashReader::new(&mut f, hash_key);
+        std::io::copy(&mut hashed, &mut std::io::sink())?;
+        if hashed.finish()[..] != *hash {
+            mismatched.push(ent.path.clone());
+        }
+    }
+    Ok(mismatched)
+}
+
+// Whether an existing file already matches its snapshot entry.
+fn restored_entry_matches(
+    ent: &index::IndexEntry,
+    stat: Option<&index::EntryStat>,
+    content_hash: Option<&[u8]>,
+    dest: &std::path::Path,
+    existing: &std::fs::Metadata,
+    check: &RestoreCheck,
+) -> Result<bool, failure::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Entries sent by older versions of bupstash lack the details to compare.
+    let stat = match stat {
+        Some(stat) => stat,
+        None => return Ok(false),
+    };
+    // The mode includes the file type.
+    if existing.mode() as u64 != ent.mode.0 {
+        return Ok(false);
+    }
+    match ent.kind() {
+        index::IndexEntryKind::Regular => {
+            if existing.len() != ent.size.0 {
+                return Ok(false);
+            }
+            match (check, content_hash) {
+                (RestoreCheck::ContentHash(hash_key), Some(content_hash)) => {
+                    let mut f = std::fs::File::open(dest)?;
+                    let mut hashed = ContentHashReader::new(&mut f, hash_key);
+                    std::io::copy(&mut hashed, &mut std::io::sink())?;
+                    Ok(hashed.finish()[..] == *content_hash)
+                }
+                _ => Ok(existing.mtime() == stat.mtime.0 as i64
+                    && existing.mtime_nsec() == stat.mtime_nsec.0 as i64),
+            }
+        }
+        index::IndexEntryKind::Symlink => Ok(Some(std::fs::read_link(dest)?)
+            == stat.link_target.as_ref().map(std::path::PathBuf::from)),
+        index::IndexEntryKind::Fifo => Ok(true),
+        // Device numbers are not in the index and sockets are always
+        // created again, so these are restored every time.
+        _ => Ok(false),
+    }
+}
+
+// An incremental restore changes the contents of read only directories
+// before their entry from the snapshot sets their mode again.
+fn make_restored_dir_writable(
+    dir: &std::path::Path,
+    metadata: &std::fs::Metadata,
+) -> Result<(), failure::Error> {
+    let mode = metadata.permissions().mode();
+    if mode & 0o700 != 0o700 {
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode | 0o700))?;
+    }
+    Ok(())
+}
+
+// Remove the entries below the directory path that are not in the snapshot, for 'restore --delete'.
+fn delete_extraneous(
+    canonical_into: &std::path::Path,
+    path: &str,
+    paths: &std::collections::HashSet<&str>,
+) -> Result<(), failure::Error> {
+    let dir = match restore_path(canonical_into, std::path::Path::new(path)) {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    let metadata = match dir.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => metadata,
+        _ => return Ok(()),
+    };
+    for dirent in std::fs::read_dir(&dir)? {
+        let dirent = dirent?;
+        let name = dirent.file_name();
+        let child = if path == "." {
+            name.to_string_lossy().into_owned()
+        } else {
+            format!("{}/{}", path, name.to_string_lossy())
+        };
+        let file_type = dirent.file_type()?;
+        if paths.contains(child.as_str()) {
+            if file_type.is_dir() {
+                delete_extraneous(canonical_into, &child, paths)?;
+            }
+            continue;
+        }
+        make_restored_dir_writable(&dir, &metadata)?;
+        let removed = if file_type.is_dir() {
+            std::fs::remove_dir_all(dirent.path())
+        } else {
+            std::fs::remove_file(dirent.path())
+        };
+        if let Err(err) = removed {
+            failure::bail!("unable to delete {}: {}", dirent.path().display(), err);
+        }
+    }
+    Ok(())
+}
+
+// Maps the owner names recorded in the index to the ids of the same
+// users and groups on this machine, names unknown here keep their id.
+#[derive(Default)]
+struct LocalOwners {
+    uids: std::collections::HashMap<String, Option<u32>>,
+    gids: std::collections::HashMap<String, Option<u32>>,
+}
+
+impl LocalOwners {
+    fn map(&mut self, owner: &index::EntryOwner) -> (u32, u32) {
+        let uid = owner.user.as_ref().and_then(|name| {
+            *self.uids.entry(name.clone()).or_insert_with(|| {
+                match nix::unistd::User::from_name(name) {
+                    Ok(Some(user)) => Some(user.uid.as_raw()),
+                    _ => None,
+                }
+            })
+        });
+        let gid = owner.group.as_ref().and_then(|name| {
+            *self.gids.entry(name.clone()).or_insert_with(|| {
+                match nix::unistd::Group::from_name(name) {
+                    Ok(Some(group)) => Some(group.gid.as_raw()),
+                    _ => None,
+                }
+            })
+        });
+        (
+            uid.unwrap_or(owner.uid.0 as u32),
+            gid.unwrap_or(owner.gid.0 as u32),
+        )
+    }
+}
+
+struct ProgressReader<R: std::io::Read> {
+    inner: R,
+    progress: indicatif::ProgressBar,
+}
+
+impl<R: std::io::Read> std::io::Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.inc(n as u64);
+        Ok(n)
+    }
+}
+
+// Where an entry is extracted to, None for paths that escape the target,
+// which tar::Entry::unpack_in also refuses to extract.
+fn restore_path(into: &std::path::Path, path: &std::path::Path) -> Option<std::path::PathBuf> {
+    let mut dest = into.to_path_buf();
+    for part in path.components() {
+        match part {
+            std::path::Component::Normal(part) => dest.push(part),
+            std::path::Component::ParentDir => return None,
+            _ => (),
+        }
+    }
+    Some(dest)
+}
+
+// Creates the parent directory of dest if it is missing, false for the target directory itself.
+fn create_restored_parent(
+    canonical_into: &std::path::Path,
+    dest: &std::path::Path,
+) -> Result<bool, failure::Error> {
+    let parent = match dest.parent() {
+        Some(parent) if dest != canonical_into => parent,
+        _ => return Ok(false),
+    };
+    if parent.symlink_metadata().is_err() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // A symlink restored earlier must not lead us out of the target directory.
+    if !std::fs::canonicalize(parent)?.starts_with(canonical_into) {
+        failure::bail!(
+            "refusing to restore {}, it is outside the target directory",
+            dest.display()
+        );
+    }
+    Ok(true)
+}
+
+// Like tar::Entry::unpack_in for a directory, but leaves the mode for later.
+fn create_restored_dir(
+    canonical_into: &std::path::Path,
+    dest: &std::path::Path,
+) -> Result<(), failure::Error> {
+    if !create_restored_parent(canonical_into, dest)? {
+        return Ok(());
+    }
+    match std::fs::create_dir(dest) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            if dest.symlink_metadata()?.is_dir() {
+                Ok(())
+            } else {
+                failure::bail!(
+                    "unable to restore directory {}, a file is in the way",
+                    dest.display()
+                )
+            }
+        }
+        Err(err) => failure::bail!("unable to create {}: {}", dest.display(), err),
+    }
+}
+
+// tar::Entry::unpack_in writes devices and fifos as empty files, so we create them
+// ourselves. The mode is set by the caller, mknod applies the umask to it.
+fn create_restored_special(
+    canonical_into: &std::path::Path,
+    dest: &std::path::Path,
+    kind: nix::sys::stat::SFlag,
+    dev: u64,
+) -> Result<(), failure::Error> {
+    create_restored_parent(canonical_into, dest)?;
+    let perm = nix::sys::stat::Mode::from_bits_truncate(0o600);
+    if let Err(err) = nix::sys::stat::mknod(dest, kind, perm, dev as libc::dev_t) {
+        failure::bail!("unable to create {}: {}", dest.display(), err);
+    }
+    Ok(())
+}
+
+fn set_restored_mtime(
+    path: &std::path::Path,
+    mtime: u64,
+    mtime_nsec: u64,
+) -> Result<(), failure::Error> {
+    use nix::sys::time::TimeValLike;
+    let t = nix::sys::time::TimeSpec::seconds(mtime as i64)
+        + nix::sys::time::TimeSpec::nanoseconds(mtime_nsec as i64);
+    nix::sys::stat::utimensat(
+        None,
+        path,
+        &t,
+        &t,
+        nix::sys::stat::UtimensatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+
+// The ACLs 'put --acls' recorded in the pax header of an entry.
+fn tar_entry_acls<R: std::io::Read>(
+    entry: &mut tar::Entry<R>,
+) -> Result<Option<acl::PosixAcls>, failure::Error> {
+    let mut acls = acl::PosixAcls::default();
+    if let Some(extensions) = entry.pax_extensions()? {
+        for ext in extensions {
+            let ext = ext?;
+            match ext.key_bytes() {
+                b"SCHILY.acl.access" => acls.access = Some(ext.value()?.to_string()),
+                b"SCHILY.acl.default" => acls.default = Some(ext.value()?.to_string()),
+                _ => (),
+            }
+        }
+    }
+    Ok(if acls.is_empty() { None } else { Some(acls) })
+}
+
+// Unpacking would write the zeros of holes out in full, so sparse files are
+// written by seeking over them. The mode is set by the caller once the data is written.
+fn extract_sparse_file<R: std::io::Read>(
+    canonical_into: &std::path::Path,
+    dest: &std::path::Path,
+    entry: &mut tar::Entry<R>,
+    holes: &[index::HoleRange],
+) -> Result<(), failure::Error> {
+    use std::io::Read;
+    use std::os::unix::fs::OpenOptionsExt;
+    create_restored_parent(canonical_into, dest)?;
+    let size = entry.header().size()?;
+    let open = || {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(dest)
+    };
+    // Like tar::Entry::unpack_in, replace what is in the way rather than write through it.
+    let mut f = match open() {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            std::fs::remove_file(dest)?;
+            open()?
+        }
+        f => f?,
+    };
+    let mut pos = 0;
+    for hole in holes.iter() {
+        let start = hole.offset.0.max(pos).min(size);
+        let end = (hole.offset.0 + hole.len.0).max(start).min(size);
+        std::io::copy(&mut (&mut *entry).take(start - pos), &mut f)?;
+        std::io::copy(&mut (&mut *entry).take(end - start), &mut std::io::sink())?;
+        std::io::Seek::seek(&mut f, std::io::SeekFrom::Start(end))?;
+        pos = end;
+    }
+    std::io::copy(entry, &mut f)?;
+    // A hole at the end of a file is not followed by data that would extend it.
+    f.set_len(size)?;
+    Ok(())
+}
+
+fn extract_tar(
+    r: std::fs::File,
+    into: &std::path::Path,
+    sparse_files: &std::collections::HashMap<std::path::PathBuf, Vec<index::HoleRange>>,
+    owners: &std::collections::HashMap<std::path::PathBuf, (u32, u32)>,
+    mtimes: &std::collections::HashMap<std::path::PathBuf, (u64, u64)>,
+    sockets: &[(std::path::PathBuf, u32)],
+    progress: &indicatif::ProgressBar,
+) -> Result<(), failure::Error> {
+    // Only root can give files back their owners.
+    let privileged = nix::unistd::geteuid().is_root();
+    let mut r = ProgressReader {
+        inner: r,
+        progress: progress.clone(),
+    };
+
+    // Directories get their mode and times once they are filled, otherwise
+    // read only directories could not be restored into, and adding
+    // their children would change their mtime.
+    let mut dirs = Vec::new();
+    let canonical_into = std::fs::canonicalize(into)?;
+
+    {
+        let mut archive = tar::Archive::new(&mut r);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            progress.set_message(&path.to_string_lossy());
+
+            let dest = match restore_path(&canonical_into, &path) {
+                Some(dest) => dest,
+                None => failure::bail!(
+                    "refusing to restore {}, it is outside the target directory",
+                    path.display()
+                ),
+            };
+            let header = entry.header();
+            let kind = header.entry_type();
+            let mode = header.mode()? & 0o7777;
+            let mtime = header.mtime()?;
+            // Items sent by older versions of bupstash only have the ids in the tar header.
+            let owner = match owners.get(&path) {
+                Some(owner) => *owner,
+                None => (header.uid()? as u32, header.gid()? as u32),
+            };
+            let special = if kind.is_character_special() {
+                Some(nix::sys::stat::SFlag::S_IFCHR)
+            } else if kind.is_block_special() {
+                Some(nix::sys::stat::SFlag::S_IFBLK)
+            } else if kind.is_fifo() {
+                Some(nix::sys::stat::SFlag::S_IFIFO)
+            } else {
+                None
+            };
+            let dev = match special {
+                Some(_) => match (header.device_major()?, header.device_minor()?) {
+                    (Some(major), Some(minor)) => xtar::make_dev(major, minor),
+                    _ => 0,
+                },
+                None => 0,
+            };
+            let acls = tar_entry_acls(&mut entry)?;
+
+            if kind.is_dir() {
+                create_restored_dir(&canonical_into, &dest)?;
+                dirs.push((dest, mode, mtime, owner, acls));
+                continue;
+            }
+
+            match special {
+                // Only root may create device nodes.
+                Some(nix::sys::stat::SFlag::S_IFCHR) | Some(nix::sys::stat::SFlag::S_IFBLK)
+                    if !privileged =>
+                {
+                    progress.println(format!(
+                        "skipping device node {}, only root can restore it",
+                        path.display()
+                    ));
+                    continue;
+                }
+                Some(special) => {
+                    create_restored_special(&canonical_into, &dest, special, dev)?;
+                    std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(mode))?;
+                    set_restored_mtime(&dest, mtime, 0)?;
+                }
+                None => match sparse_files.get(&path) {
+                    Some(holes) if kind.is_file() => {
+                        extract_sparse_file(&canonical_into, &dest, &mut entry, holes)?;
+                        std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(mode))?;
+                        set_restored_mtime(&dest, mtime, 0)?;
+                    }
+                    _ => {
+                        entry.unpack_in(&canonical_into)?;
+                    }
+                },
+            }
+
+            if kind.is_file() {
+                if let Some((mtime, mtime_nsec)) = mtimes.get(&path) {
+                    set_restored_mtime(&dest, *mtime, *mtime_nsec)?;
+                }
+            }
+
+            if privileged {
+                std::os::unix::fs::lchown(&dest, Some(owner.0), Some(owner.1))?;
+                // Changing the owner clears setuid and setgid bits.
+                if !kind.is_symlink() {
+                    std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(mode))?;
+                }
+            }
+
+            if let Some(acls) = acls {
+                if let Err(err) = acl::apply_acls(&dest, &acls) {
+                    failure::bail!("unable to restore ACLs of {}: {}", dest.display(), err);
+                }
+            }
+        }
+    }
+
+    // The data stream includes the end of archive padding, read it so the
+    // sender does not see a broken pipe.
+    std::io::copy(&mut r, &mut std::io::sink())?;
+
+    // Before directory times are set, creating a socket changes them.
+    for (path, mode) in sockets.iter() {
+        let dest = match restore_path(&canonical_into, path) {
+            Some(dest) => dest,
+            None => failure::bail!(
+                "refusing to restore {}, it is outside the target directory",
+                path.display()
+            ),
+        };
+        create_restored_special(&canonical_into, &dest, nix::sys::stat::SFlag::S_IFSOCK, 0)?;
+        std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+
+    // Children before their parents, so setting a parent's mtime is the last change to it.
+    for (dest, mode, mtime, owner, acls) in dirs.iter().rev() {
+        if privileged {
+            std::os::unix::fs::lchown(dest, Some(owner.0), Some(owner.1))?;
+        }
+        std::fs::set_permissions(dest, std::fs::Permissions::from_mode(*mode))?;
+        if let Some(acls) = acls {
+            if let Err(err) = acl::apply_acls(dest, acls) {
+                failure::bail!("unable to restore ACLs of {}: {}", dest.display(), err);
+            }
+        }
+        set_restored_mtime(dest, *mtime, 0)?;
+    }
+
+    Ok(())
+}
+
+// Like request_data_stream, but chunks are read from a local mirror of the
+// repository where possible, only missing chunks are requested from the server.
+pub fn request_mirrored_data(
+    mut ctx: DataRequestContext,
+    metadata: &itemset::VersionedItemMetadata,
+    pick: Option<index::PickMap>,
+    mirror: &std::path::Path,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+    out: &mut dyn std::io::Write,
+) -> Result<(), failure::Error> {
+    ctx.progress.finish_and_clear();
+
+    let hash_key = item_hash_key(&mut ctx, metadata)?;
+    let data_tree = &metadata.plain_text_metadata().data_tree;
+    let mut tr = htree::TreeReader::new(data_tree.height, &data_tree.address);
+    let mut source = ChunkSource::Mirror {
+        data_dir: mirror_data_dir(mirror)?,
+        r,
+        w,
+    };
+
+    if let Some(pick) = pick {
+        let filter = htree::DataRangeFilter::new(
+            pick.data_chunk_ranges.clone(),
+            data_tree.height,
+            &data_tree.address,
+        );
+        receive_partial_htree(ctx, &hash_key, &mut source, &mut tr, filter, pick, out)?;
+    } else {
+        receive_htree(ctx, &hash_key, &mut source, &mut tr, out)?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+pub fn item_hash_key(
+    ctx: &mut DataRequestContext,
+    metadata: &itemset::VersionedItemMetadata,
+) -> Result<crypto::HashKey, failure::Error> {
+    if ctx.primary_key_id != metadata.plain_text_metadata().primary_key_id {
+        failure::bail!("decryption key does not match master key used for encryption");
+    }
+
+    let encrypted_metadata = metadata.decrypt_metadata(&mut ctx.metadata_dctx)?;
+    Ok(crypto::derive_hash_key(
+        &ctx.hash_key_part_1,
+        &encrypted_metadata.hash_key_part_2,
+    ))
+}
+
+fn mirror_data_dir(mirror: &std::path::Path) -> Result<std::path::PathBuf, failure::Error> {
+    let mut data_dir = mirror.to_path_buf();
+    data_dir.push("data");
+    if !data_dir.is_dir() {
+        failure::bail!(
+            "mirror {} is not a repository using directory storage",
+            mirror.display()
+        );
+    }
+    Ok(data_dir)
+}
+
+// Where the chunks of a hash tree come from while we receive it.
+enum ChunkSource<'a> {
+    // Chunks arrive in tree order in response to a data or index request.
+    Stream(&'a mut dyn std::io::Read),
+    // Like Stream, but also saving the raw chunks so they can be cached.
+    RecordedStream {
+        r: &'a mut dyn std::io::Read,
+        chunks: &'a mut htree::RawChunks,
+    },
+    // Raw chunks saved from an earlier RecordedStream.
+    Recorded(std::slice::Iter<'a, (Address, Vec<u8>)>),
+    // Chunks are read from a local mirror, falling back to requesting them
+    // from the server one at a time when missing or corrupt.
+    Mirror {
+        data_dir: std::path::PathBuf,
+        r: &'a mut dyn std::io::Read,
+        w: &'a mut dyn std::io::Write,
+    },
+}
+
+impl<'a> ChunkSource<'a> {
+    // Returns the still encrypted data of the next chunk, so it can be decoded on
+    // another thread. None for mirrors, which must decode a chunk to know whether
+    // to fall back to the server, next_chunk does that.
+    fn next_raw_chunk(&mut self, addr: &Address) -> Result<Option<Vec<u8>>, failure::Error> {
+        match self {
+            ChunkSource::Stream(r) => match read_packet(*r, DEFAULT_MAX_PACKET_SIZE)? {
+                Packet::Chunk(chunk) => {
+                    if *addr != chunk.address {
+                        return Err(ClientError::CorruptOrTamperedChunkError(*addr).into());
+                    }
+                    Ok(Some(chunk.data))
+                }
+                _ => failure::bail!("protocol error, expected begin chunk packet"),
+            },
+            ChunkSource::RecordedStream { r, chunks } => {
+                match read_packet(*r, DEFAULT_MAX_PACKET_SIZE)? {
+                    Packet::Chunk(chunk) => {
+                        if *addr != chunk.address {
+                            return Err(ClientError::CorruptOrTamperedChunkError(*addr).into());
+                        }
+                        chunks.push((chunk.address, chunk.data.clone()));
+                        Ok(Some(chunk.data))
+                    }
+                    _ => failure::bail!("protocol error, expected begin chunk packet"),
+                }
+            }
+            ChunkSource::Recorded(chunks) => match chunks.next() {
+                Some((chunk_addr, data)) if chunk_addr == addr => Ok(Some(data.clone())),
+                _ => Err(ClientError::CorruptOrTamperedChunkError(*addr).into()),
+            },
+            ChunkSource::Mirror { .. } => Ok(None),
+        }
+    }
+
+    // Returns the decrypted data for leaf chunks and the raw block for tree nodes.
+    fn next_chunk(
+        &mut self,
+        data_dctx: &mut crypto::DecryptionContext,
+        hash_key: &crypto::HashKey,
+        height: usize,
+        addr: &Address,
+    ) -> Result<Vec<u8>, failure::Error> {
+        match self {
+            ChunkSource::Mirror { data_dir, r, w } => {
+                data_dir.push(addr.as_hex_addr().as_str());
+                let mirrored = std::fs::read(&data_dir);
+                data_dir.pop();
+                if let Ok(data) = mirrored {
+                    if let Ok(data) = decode_chunk(data_dctx, hash_key, height, addr, data) {
+                        return Ok(data);
+                    }
+                }
+                write_packet(*w, &Packet::TRequestChunk(*addr))?;
+                match read_packet(*r, DEFAULT_MAX_PACKET_SIZE)? {
+                    Packet::RRequestChunk(data) => {
+                        decode_chunk(data_dctx, hash_key, height, addr, data)
+                    }
+                    _ => failure::bail!("protocol error, expected RRequestChunk packet"),
+                }
+            }
+            source => match source.next_raw_chunk(addr)? {
+                Some(data) => decode_chunk(data_dctx, hash_key, height, addr, data),
+                None => unreachable!(),
+            },
+        }
+    }
+
+    // Queue the next leaf chunk on the decode workers.
+    fn queue_leaf_chunk(
+        &mut self,
+        workers: &mut DecodeWorkers,
+        data_dctx: &mut crypto::DecryptionContext,
+        hash_key: &crypto::HashKey,
+        addr: &Address,
+    ) -> Result<(), failure::Error> {
+        match self.next_raw_chunk(addr)? {
+            Some(data) => workers.add(*addr, data),
+            None => {
+                workers.add_decoded(self.next_chunk(data_dctx, hash_key, 0, addr)?);
+                Ok(())
+            }
+        }
+    }
 }
 
-pub struct DataRequestContext {
-    pub progress: indicatif::ProgressBar,
-    pub primary_key_id: Xid,
-    pub hash_key_part_1: crypto::PartialHashKey,
-    pub data_dctx: crypto::DecryptionContext,
-    pub metadata_dctx: crypto::DecryptionContext,
+type DecodedChunk = Result<Vec<u8>, failure::Error>;
+type DecodeJob = (std::sync::Arc<crypto::HashKey>, Address, Vec<u8>);
+
+// Commands that receive many trees start the decode workers on first use and
+// keep them for the rest of the command, rather than for every item and index.
+pub type SharedDecodeWorkers = std::sync::Arc<std::sync::Mutex<Option<DecodeWorkers>>>;
+
+// Threads that decrypt and check the leaf chunks of a get, so reading from the
+// repository and writing the output are not stalled by decryption.
+pub struct DecodeWorkers {
+    workers: OrderedWorkers<DecodeJob, DecodedChunk>,
+    // The hash key of the tree currently being received, see begin.
+    hash_key: std::sync::Arc<crypto::HashKey>,
 }
 
-pub fn request_data_stream(
-    mut ctx: DataRequestContext,
-    id: Xid,
-    pick: Option<index::PickMap>,
-    r: &mut dyn std::io::Read,
-    w: &mut dyn std::io::Write,
-    out: &mut dyn std::io::Write,
-) -> Result<(), failure::Error> {
-    write_packet(
-        w,
-        &Packet::TRequestData(TRequestData {
-            id,
-            ranges: if let Some(ref pick) = pick {
-                Some(pick.data_chunk_ranges.clone())
-            } else {
-                None
+impl DecodeWorkers {
+    fn new(
+        data_dctx: &crypto::DecryptionContext,
+        hash_key: &crypto::HashKey,
+    ) -> Result<DecodeWorkers, failure::Error> {
+        let n_workers = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let workers = OrderedWorkers::start(
+            n_workers,
+            // Bounds the decoded chunks buffered while the output is slow,
+            // while still keeping every worker busy.
+            n_workers * 4,
+            || {
+                let mut data_dctx = data_dctx.clone();
+                move |(hash_key, addr, data): DecodeJob| {
+                    decode_chunk(&mut data_dctx, &hash_key, 0, &addr, data)
+                }
             },
-        }),
-    )?;
+        )?;
+        Ok(DecodeWorkers {
+            workers,
+            hash_key: std::sync::Arc::new(hash_key.clone()),
+        })
+    }
 
-    let metadata = match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
-        Packet::RRequestData(resp) => match resp.metadata {
-            Some(metadata) => metadata,
-            None => failure::bail!("no stored items with the requested id"),
-        },
-        _ => failure::bail!("protocol error, expected ack request packet"),
-    };
+    fn add(&mut self, addr: Address, data: Vec<u8>) -> Result<(), failure::Error> {
+        self.workers.add((self.hash_key.clone(), addr, data))
+    }
 
-    // We only wanted to show the progress bar until we could start getting
-    // messages, at this point we know the repository is unlocked.
-    ctx.progress.finish_and_clear();
+    // Get ready to receive a tree with the given hash key, dropping any chunks
+    // left over from an earlier request that failed part way.
+    fn begin(&mut self, hash_key: &crypto::HashKey) -> Result<(), failure::Error> {
+        self.workers.discard_in_flight()?;
+        self.hash_key = std::sync::Arc::new(hash_key.clone());
+        Ok(())
+    }
 
-    match metadata {
-        itemset::VersionedItemMetadata::V1(metadata) => {
-            if ctx.primary_key_id != metadata.plain_text_metadata.primary_key_id {
-                failure::bail!("decryption key does not match master key used for encryption");
-            }
+    fn add_decoded(&mut self, data: Vec<u8>) {
+        self.workers.add_done(Ok(data));
+    }
+
+    fn next_chunk(&mut self, wait: bool) -> Result<Option<Vec<u8>>, failure::Error> {
+        self.workers.next_done(wait)?.transpose()
+    }
+}
 
-            let encrypted_metadata = metadata.decrypt_metadata(&mut ctx.metadata_dctx)?;
-            let plain_text_metadata = metadata.plain_text_metadata;
+// The shared decode workers, started if this is their first use.
+fn begin_decode_workers<'a>(
+    shared: &'a mut Option<DecodeWorkers>,
+    data_dctx: &crypto::DecryptionContext,
+    hash_key: &crypto::HashKey,
+) -> Result<&'a mut DecodeWorkers, failure::Error> {
+    match shared {
+        Some(workers) => workers.begin(hash_key)?,
+        None => *shared = Some(DecodeWorkers::new(data_dctx, hash_key)?),
+    }
+    Ok(shared.as_mut().unwrap())
+}
 
-            let hash_key =
-                crypto::derive_hash_key(&ctx.hash_key_part_1, &encrypted_metadata.hash_key_part_2);
+fn decode_chunk(
+    data_dctx: &mut crypto::DecryptionContext,
+    hash_key: &crypto::HashKey,
+    height: usize,
+    addr: &Address,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, failure::Error> {
+    if height == 0 {
+        // Data that fails to decrypt is just as corrupt as data with the wrong address.
+        let data = match data_dctx.decrypt_data(data) {
+            Ok(data) => data,
+            Err(_) => return Err(ClientError::CorruptOrTamperedChunkError(*addr).into()),
+        };
+        if *addr != crypto::keyed_content_address(&data, &hash_key) {
+            return Err(ClientError::CorruptOrTamperedChunkError(*addr).into());
+        }
+        Ok(data)
+    } else {
+        if *addr != htree::tree_block_address(&data) {
+            return Err(ClientError::CorruptOrTamperedChunkError(*addr).into());
+        }
+        Ok(data)
+    }
+}
 
-            let mut tr = htree::TreeReader::new(
-                plain_text_metadata.data_tree.height,
-                &plain_text_metadata.data_tree.address,
-            );
+// Decodes index entries as the index data arrives so we never
+// need to buffer the raw index data as well as the decoded index.
+struct IndexDecoder {
+    partial_entry: Vec<u8>,
+    builder: index::IndexBuilder,
+}
 
-            if let Some(pick) = pick {
-                receive_partial_htree(ctx, &hash_key, r, &mut tr, pick, out)?;
-            } else {
-                receive_htree(ctx, &hash_key, r, &mut tr, out)?;
+impl std::io::Write for IndexDecoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.partial_entry.extend_from_slice(buf);
+        let mut index_data = std::io::Cursor::new(&self.partial_entry[..]);
+        let mut n_consumed = 0;
+        while n_consumed != self.partial_entry.len() {
+            match serde_bare::from_reader(&mut index_data) {
+                Ok(index_entry) => {
+                    if let Err(err) = self.builder.push(index_entry) {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            err.to_string(),
+                        ));
+                    }
+                    n_consumed = index_data.position() as usize;
+                }
+                // Most likely an entry split across chunks, try again once we have more data.
+                Err(_) => break,
             }
-
-            out.flush()?;
-            Ok(())
         }
+        self.partial_entry.drain(..n_consumed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
 pub fn request_index(
+    ctx: DataRequestContext,
+    id: Xid,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<
+    (
+        itemset::VersionedItemMetadata,
+        Vec<index::VersionedIndexEntry>,
+    ),
+    failure::Error,
+> {
+    receive_requested_index(ctx, id, None, r, w)
+}
+
+// Like request_index, but also returns the raw index chunks,
+// so the index can be cached and later read with cached_index.
+pub fn request_cacheable_index(
+    ctx: DataRequestContext,
+    id: Xid,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<
+    (
+        itemset::VersionedItemMetadata,
+        htree::RawChunks,
+        Vec<index::VersionedIndexEntry>,
+    ),
+    failure::Error,
+> {
+    let mut chunks = Vec::new();
+    let (metadata, index) = receive_requested_index(ctx, id, Some(&mut chunks), r, w)?;
+    Ok((metadata, chunks, index))
+}
+
+// Chunks are only recorded when the caller will cache them.
+fn receive_requested_index(
     mut ctx: DataRequestContext,
     id: Xid,
+    chunks: Option<&mut htree::RawChunks>,
     r: &mut dyn std::io::Read,
     w: &mut dyn std::io::Write,
-) -> Result<Vec<index::VersionedIndexEntry>, failure::Error> {
+) -> Result<
+    (
+        itemset::VersionedItemMetadata,
+        Vec<index::VersionedIndexEntry>,
+    ),
+    failure::Error,
+> {
     write_packet(w, &Packet::TRequestIndex(TRequestIndex { id }))?;
 
     let metadata = match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
@@ -846,76 +4357,199 @@ pub fn request_index(
 
     ctx.progress.set_message("fetching content index...");
 
-    match metadata {
-        itemset::VersionedItemMetadata::V1(metadata) => {
-            if ctx.primary_key_id != metadata.plain_text_metadata.primary_key_id {
-                failure::bail!("decryption key does not match master key used for encryption");
-            }
+    let hash_key = item_hash_key(&mut ctx, &metadata)?;
+    let mut source = match chunks {
+        Some(chunks) => ChunkSource::RecordedStream { r, chunks },
+        None => ChunkSource::Stream(r),
+    };
+    let index = receive_index(ctx, &hash_key, &metadata, &mut source)?;
+    Ok((metadata, index))
+}
 
-            let encrypted_metadata = metadata.decrypt_metadata(&mut ctx.metadata_dctx)?;
-            let plain_text_metadata = metadata.plain_text_metadata;
+// Decode an index from chunks saved by request_cacheable_index, without the server.
+pub fn cached_index(
+    mut ctx: DataRequestContext,
+    metadata: &itemset::VersionedItemMetadata,
+    chunks: &[(Address, Vec<u8>)],
+) -> Result<Vec<index::VersionedIndexEntry>, failure::Error> {
+    let hash_key = item_hash_key(&mut ctx, metadata)?;
+    let mut source = ChunkSource::Recorded(chunks.iter());
+    let index = receive_index(ctx, &hash_key, metadata, &mut source)?;
+    if let ChunkSource::Recorded(mut remaining) = source {
+        if remaining.next().is_some() {
+            return Err(ClientError::CorruptOrTamperedDataError.into());
+        }
+    }
+    Ok(index)
+}
 
-            let hash_key =
-                crypto::derive_hash_key(&ctx.hash_key_part_1, &encrypted_metadata.hash_key_part_2);
+// Fetch the content index of an item, reading and filling the copy in the query cache.
+pub fn fetch_content_index(
+    ctx: DataRequestContext,
+    id: Xid,
+    query_cache: &mut querycache::QueryCache,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<Vec<index::VersionedIndexEntry>, failure::Error> {
+    if let Some((metadata, chunks)) = query_cache.transaction()?.lookup_content_index(&id)? {
+        return cached_index(ctx, &metadata, &chunks);
+    }
+    let progress = ctx.progress.clone();
+    let (metadata, chunks, content_index) = request_cacheable_index(ctx, id, r, w)?;
+    cache_content_index(&progress, query_cache, &id, &metadata, &chunks);
+    Ok(content_index)
+}
 
-            let index_tree = match plain_text_metadata.index_tree {
-               Some(index_tree) => index_tree,
-                None => failure::bail!("requested item does not have a content index (tarball was not created by bupstash)"),
-            };
+// The index has already been fetched, so a failed cache write only costs
+// a refetch next time and is not worth failing the command over.
+pub fn cache_content_index(
+    progress: &indicatif::ProgressBar,
+    query_cache: &mut querycache::QueryCache,
+    id: &Xid,
+    metadata: &itemset::VersionedItemMetadata,
+    chunks: &htree::RawChunks,
+) {
+    let result = query_cache.transaction().and_then(|mut tx| {
+        tx.cache_content_index(id, metadata, chunks)?;
+        tx.commit()
+    });
+    if let Err(err) = result {
+        let msg = format!("warning: unable to cache the index of item {}: {}", id, err);
+        if progress.is_hidden() {
+            eprintln!("{}", msg);
+        } else {
+            progress.println(&msg);
+        }
+    }
+}
 
-            let mut tr = htree::TreeReader::new(index_tree.height, &index_tree.address);
+// Like request_index, but reading chunks from a local mirror where possible.
+pub fn request_mirrored_index(
+    mut ctx: DataRequestContext,
+    metadata: &itemset::VersionedItemMetadata,
+    mirror: &std::path::Path,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<Vec<index::VersionedIndexEntry>, failure::Error> {
+    ctx.progress.set_message("fetching content index...");
 
-            let mut index_data = std::io::Cursor::new(Vec::new());
-            receive_htree(ctx, &hash_key, r, &mut tr, &mut index_data)?;
+    let hash_key = item_hash_key(&mut ctx, metadata)?;
+    let mut source = ChunkSource::Mirror {
+        data_dir: mirror_data_dir(mirror)?,
+        r,
+        w,
+    };
+    receive_index(ctx, &hash_key, metadata, &mut source)
+}
 
-            let mut index: Vec<index::VersionedIndexEntry> = Vec::new();
+// Read the index of an item from chunks saved by request_cacheable_index
+// for use as the base of a delta encoded index.
+pub fn index_delta_base(
+    mut ctx: DataRequestContext,
+    metadata: &itemset::VersionedItemMetadata,
+    chunks: &[(Address, Vec<u8>)],
+    send_hash_key: &crypto::HashKey,
+    gc_generation: Option<Xid>,
+) -> Result<IndexDeltaBase, failure::Error> {
+    let hash_key = item_hash_key(&mut ctx, metadata)?;
+    // Chunk addresses are keyed, reused chunks must have been sent with the same hash key.
+    if hash_key != *send_hash_key {
+        failure::bail!("index delta base item was not sent with the same key");
+    }
 
-            let index_data_size = index_data.position();
-            index_data.set_position(0);
-            while index_data.position() != index_data_size {
-                match serde_bare::from_reader(&mut index_data) {
-                    Ok(index_entry) => index.push(index_entry),
-                    Err(err) => failure::bail!("error deserializing index: {}", err),
-                }
-            }
+    let mut tr = index_tree_reader(metadata)?;
+    let mut source = ChunkSource::Recorded(chunks.iter());
+    let mut index_decoder = IndexDecoder {
+        partial_entry: Vec::new(),
+        builder: index::IndexBuilder::default(),
+    };
+    let mut leaf_addresses = Vec::new();
 
-            Ok(index)
+    while let Some((height, addr)) = tr.next_addr()? {
+        let data = source.next_chunk(&mut ctx.data_dctx, &hash_key, height, &addr)?;
+        if height == 0 {
+            leaf_addresses.push(addr);
+            std::io::Write::write_all(&mut index_decoder, &data)?;
+        } else {
+            tr.push_level(height - 1, data)?;
         }
     }
+
+    if !index_decoder.partial_entry.is_empty() {
+        failure::bail!("error deserializing index, index data is truncated or corrupt");
+    }
+
+    Ok(IndexDeltaBase {
+        leaf_addresses,
+        index: index_decoder.builder.index,
+        delta_depth: index_decoder.builder.delta_depth,
+        gc_generation,
+    })
+}
+
+fn index_tree_reader(
+    metadata: &itemset::VersionedItemMetadata,
+) -> Result<htree::TreeReader, failure::Error> {
+    match &metadata.plain_text_metadata().index_tree {
+        Some(index_tree) => Ok(htree::TreeReader::new(
+            index_tree.height,
+            &index_tree.address,
+        )),
+        None => failure::bail!(
+            "requested item does not have a content index (tarball was not created by bupstash)"
+        ),
+    }
+}
+
+fn receive_index(
+    ctx: DataRequestContext,
+    hash_key: &crypto::HashKey,
+    metadata: &itemset::VersionedItemMetadata,
+    source: &mut ChunkSource,
+) -> Result<Vec<index::VersionedIndexEntry>, failure::Error> {
+    let mut tr = index_tree_reader(metadata)?;
+
+    let mut index_decoder = IndexDecoder {
+        partial_entry: Vec::new(),
+        builder: index::IndexBuilder::default(),
+    };
+    receive_htree(ctx, hash_key, source, &mut tr, &mut index_decoder)?;
+
+    if !index_decoder.partial_entry.is_empty() {
+        failure::bail!("error deserializing index, index data is truncated or corrupt");
+    }
+
+    Ok(index_decoder.builder.index)
 }
 
 fn receive_htree(
     mut ctx: DataRequestContext,
     hash_key: &crypto::HashKey,
-    r: &mut dyn std::io::Read,
+    source: &mut ChunkSource,
     tr: &mut htree::TreeReader,
     out: &mut dyn std::io::Write,
 ) -> Result<(), failure::Error> {
-    while let Some((height, addr)) = tr.next_addr()? {
-        let data = match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
-            Packet::Chunk(chunk) => {
-                if addr != chunk.address {
-                    return Err(ClientError::CorruptOrTamperedDataError.into());
-                }
-                chunk.data
-            }
-            _ => failure::bail!("protocol error, expected begin chunk packet"),
-        };
+    // Leaf chunks are decoded by the workers while we keep reading, tree
+    // blocks are needed to continue the walk, so are decoded here.
+    let mut shared_workers = ctx.decode_workers.lock().unwrap();
+    let workers = begin_decode_workers(&mut shared_workers, &ctx.data_dctx, hash_key)?;
 
+    while let Some((height, addr)) = tr.next_addr()? {
         if height == 0 {
-            let data = ctx.data_dctx.decrypt_data(data)?;
-            if addr != crypto::keyed_content_address(&data, &hash_key) {
-                return Err(ClientError::CorruptOrTamperedDataError.into());
+            source.queue_leaf_chunk(workers, &mut ctx.data_dctx, hash_key, &addr)?;
+            while let Some(data) = workers.next_chunk(false)? {
+                out.write_all(&data)?;
             }
-            out.write_all(&data)?;
         } else {
-            if addr != htree::tree_block_address(&data) {
-                return Err(ClientError::CorruptOrTamperedDataError.into());
-            }
+            let data = source.next_chunk(&mut ctx.data_dctx, hash_key, height, &addr)?;
             tr.push_level(height - 1, data)?;
         }
     }
 
+    while let Some(data) = workers.next_chunk(true)? {
+        out.write_all(&data)?;
+    }
+
     out.flush()?;
     Ok(())
 }
@@ -923,80 +4557,70 @@ fn receive_htree(
 fn receive_partial_htree(
     mut ctx: DataRequestContext,
     hash_key: &crypto::HashKey,
-    r: &mut dyn std::io::Read,
+    source: &mut ChunkSource,
     tr: &mut htree::TreeReader,
+    mut filter: htree::DataRangeFilter,
     pick: index::PickMap,
     out: &mut dyn std::io::Write,
 ) -> Result<(), failure::Error> {
     let mut n_written: u64 = 0;
-    let mut range_idx: usize = 0;
-    let mut current_data_chunk_idx: u64 = 0;
-    let mut pending_data_chunks = std::collections::VecDeque::new();
-
-    while let Some((height, addr)) = tr.next_addr()? {
-        let data = match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
-            Packet::Chunk(chunk) => {
-                if addr != chunk.address {
-                    return Err(ClientError::CorruptOrTamperedDataError.into());
+    // The index of each chunk queued on the workers, in the order they are handed back.
+    let mut queued_data_chunks = std::collections::VecDeque::new();
+    let mut shared_workers = ctx.decode_workers.lock().unwrap();
+    let workers = begin_decode_workers(&mut shared_workers, &ctx.data_dctx, hash_key)?;
+
+    let mut write_chunk = |chunk_idx: u64, data: Vec<u8>| -> Result<(), failure::Error> {
+        match pick.incomplete_data_chunks.get(&chunk_idx) {
+            Some(ranges) => {
+                for range in ranges.iter() {
+                    let data = &data[range.start..std::cmp::min(data.len(), range.end)];
+                    n_written += data.len() as u64;
+                    out.write_all(data)?;
                 }
-                chunk.data
             }
-            _ => failure::bail!("protocol error, expected begin chunk packet"),
-        };
+            None => {
+                n_written += data.len() as u64;
+                out.write_all(&data)?;
+            }
+        }
+        Ok(())
+    };
+
+    while let Some((height, addr)) = tr.next_addr()? {
+        // Not sent by the server, see DataRangeFilter::done.
+        if height != 0 && filter.done() {
+            continue;
+        }
 
         if height == 0 {
-            let data = ctx.data_dctx.decrypt_data(data)?;
-            if addr != crypto::keyed_content_address(&data, &hash_key) {
-                return Err(ClientError::CorruptOrTamperedDataError.into());
-            }
-
-            if let Some(chunk_idx) = pending_data_chunks.pop_back() {
-                match pick.incomplete_data_chunks.get(&chunk_idx) {
-                    Some(ranges) => {
-                        for range in ranges.iter() {
-                            let data = &data[range.start..std::cmp::min(data.len(), range.end)];
-                            n_written += data.len() as u64;
-                            out.write_all(data)?;
-                        }
-                    }
-                    None => {
-                        n_written += data.len() as u64;
-                        out.write_all(&data)?;
-                    }
+            source.queue_leaf_chunk(workers, &mut ctx.data_dctx, hash_key, &addr)?;
+            queued_data_chunks.push_back(filter.next_data_chunk());
+            while let Some(data) = workers.next_chunk(false)? {
+                if let Some(chunk_idx) = queued_data_chunks.pop_front().unwrap() {
+                    write_chunk(chunk_idx, data)?;
                 }
             }
         } else {
-            if addr != htree::tree_block_address(&data) {
-                return Err(ClientError::CorruptOrTamperedDataError.into());
-            }
-
+            let data = source.next_chunk(&mut ctx.data_dctx, hash_key, height, &addr)?;
             if height == 1 {
-                let mut filtered_data = Vec::with_capacity(data.len());
-
-                for addr_bytes in data.chunks(ADDRESS_SZ) {
-                    if let Some(current_range) = pick.data_chunk_ranges.get(range_idx) {
-                        if current_data_chunk_idx >= current_range.start_idx
-                            && current_data_chunk_idx <= current_range.end_idx
-                        {
-                            filtered_data.extend_from_slice(addr_bytes);
-                            pending_data_chunks.push_front(current_data_chunk_idx);
-                        }
-                        current_data_chunk_idx += 1;
-
-                        if current_data_chunk_idx > current_range.end_idx {
-                            range_idx += 1;
-                        }
-                    }
-                }
-
-                tr.push_level(height - 1, filtered_data)?;
-            } else if pick.data_chunk_ranges.get(range_idx).is_some() {
+                tr.push_level(0, filter.filter_level(&data))?;
+            } else if !filter.done() {
                 tr.push_level(height - 1, data)?;
             }
         }
     }
 
-    debug_assert!(n_written == pick.size);
+    while let Some(data) = workers.next_chunk(true)? {
+        if let Some(chunk_idx) = queued_data_chunks.pop_front().unwrap() {
+            write_chunk(chunk_idx, data)?;
+        }
+    }
+
+    // A pick must produce exactly the bytes recorded in the index, anything
+    // else means the index and data stream disagree.
+    if n_written != pick.size {
+        return Err(ClientError::CorruptOrTamperedDataError.into());
+    }
 
     if pick.is_subtar {
         // The final entry in a tarball is two null files.
@@ -1022,13 +4646,86 @@ pub fn restore_removed(
     }
 }
 
+pub fn repo_stats(
+    progress: indicatif::ProgressBar,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<RRepoStats, failure::Error> {
+    progress.set_message("fetching repository stats...");
+    write_packet(w, &Packet::TRepoStats)?;
+    match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+        Packet::RRepoStats(stats) => Ok(stats),
+        _ => failure::bail!("protocol error, expected repository stats packet"),
+    }
+}
+
+pub fn chunk_sharing(
+    progress: indicatif::ProgressBar,
+    a: Xid,
+    b: Xid,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<repository::ChunkSharingStats, failure::Error> {
+    progress.set_message("walking items...");
+    write_packet(w, &Packet::TChunkSharing(TChunkSharing { a, b }))?;
+    match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+        Packet::RChunkSharing(stats) => Ok(stats),
+        _ => failure::bail!("protocol error, expected chunk sharing packet"),
+    }
+}
+
+pub fn clone_item(
+    progress: indicatif::ProgressBar,
+    source: Xid,
+    item: itemset::VersionedItemMetadata,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<Xid, failure::Error> {
+    progress.set_message("cloning item...");
+    write_packet(w, &Packet::TCloneItem(TCloneItem { source, item }))?;
+    match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+        Packet::RCloneItem(RCloneItem { item_id }) => Ok(item_id),
+        _ => failure::bail!("protocol error, expected clone item packet"),
+    }
+}
+
+pub fn item_addresses(
+    progress: indicatif::ProgressBar,
+    items: Vec<Xid>,
+    on_addresses: &mut dyn FnMut(&[Address]) -> Result<(), failure::Error>,
+    r: &mut dyn std::io::Read,
+    w: &mut dyn std::io::Write,
+) -> Result<(), failure::Error> {
+    progress.set_message("walking items...");
+    write_packet(w, &Packet::TItemAddresses(TItemAddresses { items }))?;
+    loop {
+        match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+            Packet::ItemAddresses(addresses) => {
+                if addresses.is_empty() {
+                    return Ok(());
+                }
+                on_addresses(&addresses)?;
+            }
+            _ => failure::bail!("protocol error, expected item addresses packet"),
+        }
+    }
+}
+
 pub fn gc(
     progress: indicatif::ProgressBar,
+    pacing: repository::GCPacing,
+    sweep_workers: Option<u32>,
     r: &mut dyn std::io::Read,
     w: &mut dyn std::io::Write,
 ) -> Result<repository::GCStats, failure::Error> {
     progress.set_message("collecting garbage...");
-    write_packet(w, &Packet::TGc(TGc {}))?;
+    write_packet(
+        w,
+        &Packet::TGc(TGc {
+            pacing,
+            sweep_workers,
+        }),
+    )?;
 
     loop {
         match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
@@ -1054,38 +4751,127 @@ pub fn sync(
 
     let mut tx = query_cache.transaction()?;
 
-    let after = tx.last_log_op()?;
-    let gc_generation = tx.current_gc_generation()?;
+    let mut want_snapshot = false;
 
-    write_packet(
-        w,
-        &Packet::TRequestItemSync(TRequestItemSync {
-            after,
-            gc_generation,
-        }),
-    )?;
+    let item_log_head = loop {
+        // Caches from before the item log was verified must be synced from scratch.
+        let item_log_head = tx.item_log_head()?;
+        if item_log_head.is_none() {
+            tx.clear()?;
+        }
 
-    let gc_generation = match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
-        Packet::RRequestItemSync(ack) => ack.gc_generation,
-        _ => failure::bail!("protocol error, expected items packet"),
-    };
+        let after = tx.last_log_op()?;
+        let gc_generation = tx.current_gc_generation()?;
 
-    tx.start_sync(gc_generation)?;
+        write_packet(
+            w,
+            &Packet::TRequestItemSync(TRequestItemSync {
+                after,
+                gc_generation,
+                want_snapshot,
+                accept_snapshot: true,
+                accept_compressed: true,
+            }),
+        )?;
 
-    loop {
-        match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
-            Packet::SyncLogOps(ops) => {
-                if ops.is_empty() {
+        let ack = match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+            Packet::RRequestItemSync(ack) => ack,
+            _ => failure::bail!("protocol error, expected items packet"),
+        };
+
+        tx.start_sync(ack.gc_generation)?;
+        if ack.snapshot {
+            tx.clear()?;
+            tx.set_partial_log()?;
+        }
+        let partial_log = tx.partial_log()?;
+        // Without a verified head there is nothing to check a snapshot against.
+        let verify = !ack.snapshot || item_log_head.is_some();
+        let mut item_log_head = item_log_head.unwrap_or([0; crypto::HASH_BYTES]);
+        // Digests of the ops after ours, a snapshot is checked against these.
+        let mut digests = std::collections::HashMap::new();
+        let mut last_digest_op = after;
+        let mut needs_snapshot = false;
+
+        loop {
+            let ops = match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+                Packet::SyncLogOps(ops) => {
+                    if ops.is_empty() {
+                        break;
+                    }
+                    ops
+                }
+                Packet::CompressedSyncLogOps(data) => decompress_log_ops(&data)?,
+                Packet::SyncLogDigests(batch) => {
+                    if !ack.snapshot {
+                        failure::bail!("protocol error, unexpected log digests");
+                    }
+                    for (opid, digest) in batch {
+                        if opid <= last_digest_op {
+                            failure::bail!("protocol error, log digests out of order");
+                        }
+                        last_digest_op = opid;
+                        item_log_head = itemset::chain_log_digest(&item_log_head, &digest);
+                        digests.insert(opid, digest);
+                    }
+                    continue;
+                }
+                _ => failure::bail!("protocol error, expected items packet"),
+            };
+            if needs_snapshot {
+                continue;
+            }
+            for (opid, item_id, op) in ops {
+                // Items removed before a snapshot are missing from our cache, so we
+                // can't apply a restore and must fetch a new snapshot instead.
+                if partial_log && !ack.snapshot && matches!(op, itemset::LogOp::RestoreRemoved) {
+                    needs_snapshot = true;
                     break;
                 }
-                for (opid, item_id, op) in ops {
-                    tx.sync_op(opid, item_id, op)?;
+                if !ack.snapshot {
+                    item_log_head = itemset::chain_log_op(&item_log_head, opid, &item_id, &op)?;
+                } else if verify
+                    && opid > after
+                    && digests.get(&opid) != Some(&itemset::log_op_digest(opid, &item_id, &op)?)
+                {
+                    failure::bail!("snapshot item does not match the item log digests");
                 }
+                tx.sync_op(opid, item_id, op)?;
             }
-            _ => failure::bail!("protocol error, expected items packet"),
         }
-    }
 
+        if needs_snapshot {
+            if want_snapshot {
+                failure::bail!("protocol error, expected a snapshot sync");
+            }
+            if !ack.snapshots_supported {
+                failure::bail!("the query cache must be synced from a snapshot, but the server does not support them, remove the query cache and try again");
+            }
+            want_snapshot = true;
+            // Undo the ops applied so far, the snapshot follows on from our last sync.
+            drop(tx);
+            tx = query_cache.transaction()?;
+            continue;
+        }
+
+        if !verify {
+            tx.set_last_synced_op(ack.last_op_id)?;
+            break ack.item_log_head;
+        }
+
+        if item_log_head != ack.item_log_head {
+            failure::bail!(
+            "the repository item log does not follow on from the last verified sync, items may have been \
+            dropped or the repository rolled back to an earlier state; if this is expected (for example \
+            the repository was restored from a backup), remove the query cache and try again"
+            );
+        }
+
+        tx.set_last_synced_op(ack.last_op_id)?;
+        break item_log_head;
+    };
+
+    tx.set_item_log_head(&item_log_head)?;
     tx.commit()?;
     Ok(())
 }
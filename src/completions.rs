@@ -0,0 +1,341 @@
+// Shell completion scripts for 'bupstash completions'.
+//
+// Subcommands come from the main help text and their options from the
+// help text of each subcommand, so the scripts never drift from the cli.
+
+pub struct CompletionOpt {
+    pub short: Option<char>,
+    pub long: String,
+    // The value hint, such as PATH, for options that take a value.
+    pub arg: Option<String>,
+    pub description: String,
+}
+
+pub struct CompletionCommand {
+    pub name: String,
+    pub description: String,
+    pub opts: Vec<CompletionOpt>,
+}
+
+// Commands whose free arguments are a query, these complete tag names.
+const QUERY_COMMANDS: &[&str] = &[
+    "list",
+    "list-contents",
+    "timeline",
+    "get",
+    "restore",
+    "verify",
+    "inspect",
+    "mount",
+    "rm",
+    "remove",
+    "shared",
+    "diff",
+    "clone",
+    "restore-send-log",
+];
+
+// Parses the subcommand table of the main help text, 'rm/remove' is two subcommands.
+pub fn parse_subcommands(help: &str) -> Vec<(String, String)> {
+    let mut subcommands = Vec::new();
+    let mut in_table = false;
+    for line in help.lines() {
+        if line.starts_with("Subcommands:") {
+            in_table = true;
+            continue;
+        }
+        if !in_table {
+            continue;
+        }
+        if !line.starts_with("  ") {
+            if !subcommands.is_empty() {
+                break;
+            }
+            continue;
+        }
+        let line = line.trim();
+        let (names, description) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        for name in names.split('/') {
+            subcommands.push((name.to_string(), description.to_string()));
+        }
+    }
+    subcommands
+}
+
+// Parses the option rows of getopts usage text, descriptions may wrap onto following lines.
+pub fn parse_usage(usage: &str) -> Vec<CompletionOpt> {
+    let mut opts: Vec<CompletionOpt> = Vec::new();
+    let mut in_options = false;
+    for line in usage.lines() {
+        if line.starts_with("Options:") {
+            in_options = true;
+            continue;
+        }
+        if !in_options || line.trim().is_empty() {
+            continue;
+        }
+        let trimmed = line.trim_start();
+        let (short, rest) = if let Some(rest) = trimmed.strip_prefix('-') {
+            if rest.starts_with('-') {
+                (None, trimmed)
+            } else {
+                let mut chars = rest.chars();
+                let short = chars.next();
+                (short, chars.as_str().trim_start_matches(',').trim_start())
+            }
+        } else {
+            if let Some(last) = opts.last_mut() {
+                if !last.description.is_empty() {
+                    last.description.push(' ');
+                }
+                last.description.push_str(trimmed);
+            }
+            continue;
+        };
+        let rest = match rest.strip_prefix("--") {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let mut words = rest.splitn(2, ' ');
+        let long = words.next().unwrap().to_string();
+        let rest = words.next().unwrap_or("");
+        // The value hint is separated from the description by more than one space.
+        let (arg, description) = match rest.find("  ") {
+            Some(i) => (rest[..i].trim(), rest[i..].trim()),
+            None => (rest.trim(), ""),
+        };
+        opts.push(CompletionOpt {
+            short,
+            long,
+            arg: if arg.is_empty() {
+                None
+            } else {
+                Some(arg.to_string())
+            },
+            description: description.to_string(),
+        });
+    }
+    opts
+}
+
+fn is_query_command(name: &str) -> bool {
+    QUERY_COMMANDS.contains(&name)
+}
+
+// Shells show descriptions on a single line, the first sentence is enough.
+fn first_sentence(description: &str) -> &str {
+    match description.find(". ") {
+        Some(i) => &description[..i + 1],
+        None => description,
+    }
+}
+
+pub fn bash(commands: &[CompletionCommand]) -> String {
+    let mut s = String::new();
+    s.push_str("# bash completion for bupstash, generated by 'bupstash completions bash'.\n\n");
+    s.push_str("_bupstash() {\n");
+    s.push_str("  local cur prev opts argopts query\n");
+    s.push_str("  cur=\"${COMP_WORDS[COMP_CWORD]}\"\n");
+    s.push_str("  prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    s.push_str("  if [ \"$COMP_CWORD\" -eq 1 ]; then\n");
+    let names: Vec<&str> = commands.iter().map(|c| c.name.as_str()).collect();
+    s.push_str(&format!(
+        "    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n",
+        names.join(" ")
+    ));
+    s.push_str("    return\n");
+    s.push_str("  fi\n");
+    s.push_str("  case \"${COMP_WORDS[1]}\" in\n");
+    for c in commands.iter() {
+        let mut opts = Vec::new();
+        let mut argopts = Vec::new();
+        for o in c.opts.iter() {
+            let mut names = vec![format!("--{}", o.long)];
+            if let Some(short) = o.short {
+                names.push(format!("-{}", short));
+            }
+            if o.arg.is_some() {
+                argopts.extend(names.iter().cloned());
+            }
+            opts.extend(names);
+        }
+        s.push_str(&format!("    {})\n", c.name));
+        s.push_str(&format!("      opts=\"{}\"\n", opts.join(" ")));
+        s.push_str(&format!("      argopts=\"{}\"\n", argopts.join(" ")));
+        if is_query_command(&c.name) {
+            s.push_str("      query=1\n");
+        }
+        s.push_str("      ;;\n");
+    }
+    s.push_str("    *)\n");
+    s.push_str("      return\n");
+    s.push_str("      ;;\n");
+    s.push_str("  esac\n");
+    s.push_str("  if [[ \" $argopts \" == *\" $prev \"* ]]; then\n");
+    s.push_str("    COMPREPLY=($(compgen -f -- \"$cur\"))\n");
+    s.push_str("  elif [[ \"$cur\" == -* ]]; then\n");
+    s.push_str("    COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n");
+    s.push_str("  elif [ -n \"$query\" ]; then\n");
+    s.push_str(
+        "    COMPREPLY=($(compgen -W \"$(bupstash completions --tags 2>/dev/null)\" -- \"$cur\"))\n",
+    );
+    s.push_str("    compopt -o nospace\n");
+    s.push_str("  else\n");
+    s.push_str("    COMPREPLY=($(compgen -f -- \"$cur\"))\n");
+    s.push_str("  fi\n");
+    s.push_str("}\n\n");
+    s.push_str("complete -F _bupstash bupstash\n");
+    s
+}
+
+fn zsh_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+pub fn zsh(commands: &[CompletionCommand]) -> String {
+    let mut s = String::new();
+    s.push_str("#compdef bupstash\n");
+    s.push_str("# zsh completion for bupstash, generated by 'bupstash completions zsh'.\n\n");
+    s.push_str("_bupstash_tags() {\n");
+    s.push_str("  local -a tags\n");
+    s.push_str("  tags=(${(f)\"$(bupstash completions --tags 2>/dev/null)\"})\n");
+    s.push_str("  compadd -S '' -a tags\n");
+    s.push_str("}\n\n");
+    s.push_str("_bupstash() {\n");
+    s.push_str("  local -a commands\n");
+    s.push_str("  commands=(\n");
+    for c in commands.iter() {
+        s.push_str(&format!(
+            "    '{}:{}'\n",
+            c.name,
+            zsh_escape(&c.description)
+        ));
+    }
+    s.push_str("  )\n");
+    s.push_str("  if (( CURRENT == 2 )); then\n");
+    s.push_str("    _describe 'command' commands\n");
+    s.push_str("    return\n");
+    s.push_str("  fi\n");
+    s.push_str("  words=(${words[2,-1]})\n");
+    s.push_str("  (( CURRENT-- ))\n");
+    s.push_str("  case $words[1] in\n");
+    for c in commands.iter() {
+        s.push_str(&format!("    {})\n", c.name));
+        s.push_str("      _arguments -s");
+        for o in c.opts.iter() {
+            let value = match o.arg {
+                Some(ref arg) => format!(":{}:_files", zsh_escape(arg)),
+                None => String::new(),
+            };
+            let description = zsh_escape(first_sentence(&o.description));
+            s.push_str(" \\\n        ");
+            match o.short {
+                Some(short) => s.push_str(&format!(
+                    "'(-{} --{})'{{-{},--{}}}'[{}]{}'",
+                    short, o.long, short, o.long, description, value
+                )),
+                None => s.push_str(&format!("'--{}[{}]{}'", o.long, description, value)),
+            }
+        }
+        if is_query_command(&c.name) {
+            s.push_str(" \\\n        '*:query:_bupstash_tags'");
+        } else {
+            s.push_str(" \\\n        '*:file:_files'");
+        }
+        s.push_str("\n      ;;\n");
+    }
+    s.push_str("  esac\n");
+    s.push_str("}\n\n");
+    s.push_str("_bupstash \"$@\"\n");
+    s
+}
+
+fn fish_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+pub fn fish(commands: &[CompletionCommand]) -> String {
+    let mut s = String::new();
+    s.push_str("# fish completion for bupstash, generated by 'bupstash completions fish'.\n\n");
+    s.push_str("complete -c bupstash -f\n");
+    for c in commands.iter() {
+        s.push_str(&format!(
+            "complete -c bupstash -n '__fish_use_subcommand' -a '{}' -d '{}'\n",
+            c.name,
+            fish_escape(&c.description)
+        ));
+    }
+    for c in commands.iter() {
+        let condition = format!("__fish_seen_subcommand_from {}", c.name);
+        for o in c.opts.iter() {
+            s.push_str(&format!(
+                "complete -c bupstash -n '{}' -l '{}'",
+                condition, o.long
+            ));
+            if let Some(short) = o.short {
+                s.push_str(&format!(" -s '{}'", short));
+            }
+            if o.arg.is_some() {
+                s.push_str(" -r -F");
+            }
+            s.push_str(&format!(
+                " -d '{}'\n",
+                fish_escape(first_sentence(&o.description))
+            ));
+        }
+        if is_query_command(&c.name) {
+            s.push_str(&format!(
+                "complete -c bupstash -n '{}' -a '(bupstash completions --tags 2>/dev/null)'\n",
+                condition
+            ));
+        } else {
+            s.push_str(&format!("complete -c bupstash -n '{}' -F\n", condition));
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_help_text() {
+        let help = "bupstash\n\nSubcommands:\n\n  init      Initialize a repository.\n  rm/remove Remove items.\n\n\nFor help.\n";
+        let subcommands = parse_subcommands(help);
+        assert_eq!(
+            subcommands,
+            vec![
+                ("init".to_string(), "Initialize a repository.".to_string()),
+                ("rm".to_string(), "Remove items.".to_string()),
+                ("remove".to_string(), "Remove items.".to_string()),
+            ]
+        );
+
+        let usage = "bupstash get [OPTIONS] QUERY\n\nOptions:\n    -h, --help          print this help menu.\n    -r, --repository REPO\n                        Repository to interact with,\n                        see the manual.\n        --pick PATH     Pick a single file.\n        --allow-many    Get many items.\n";
+        let opts = parse_usage(usage);
+        assert_eq!(opts.len(), 4);
+        assert_eq!(opts[0].short, Some('h'));
+        assert_eq!(opts[0].long, "help");
+        assert!(opts[0].arg.is_none());
+        assert_eq!(opts[1].short, Some('r'));
+        assert_eq!(opts[1].arg.as_deref(), Some("REPO"));
+        assert_eq!(
+            opts[1].description,
+            "Repository to interact with, see the manual."
+        );
+        assert_eq!(opts[2].short, None);
+        assert_eq!(opts[2].long, "pick");
+        assert_eq!(opts[2].arg.as_deref(), Some("PATH"));
+        assert_eq!(opts[2].description, "Pick a single file.");
+        assert_eq!(opts[3].long, "allow-many");
+        assert!(opts[3].arg.is_none());
+    }
+}
@@ -17,8 +17,18 @@ pub const BOX_MACBYTES: usize = sodium::crypto_box_curve25519xchacha20poly1305_M
 
 pub const BOX_PRE_SHARED_KEY_BYTES: usize = sodium::crypto_generichash_KEYBYTES as usize;
 
+// Cipher text ends with a version byte with the high bit set. Legacy cipher text ends
+// with a curve25519 public key, whose final byte never has the high bit set, so the
+// layout is known before decrypting.
+pub const CIPHER_TEXT_VERSIONED: u8 = 0x80;
+// The box key was derived with box_compute_key_hkdf, and the nonce is incremented.
+pub const CIPHER_TEXT_HKDF_KEY_EXCHANGE: u8 = CIPHER_TEXT_VERSIONED | 1;
+// As above, but the nonce was derived from the plain text, see derive_nonce.
+pub const CIPHER_TEXT_HKDF_DERIVED_NONCE: u8 = CIPHER_TEXT_VERSIONED | 2;
+
 pub const CHUNK_FOOTER_NO_COMPRESSION: u8 = 0;
 pub const CHUNK_FOOTER_ZSTD_COMPRESSED: u8 = 1;
+pub const CHUNK_FOOTER_LZ4_COMPRESSED: u8 = 2;
 
 pub fn init() {
     unsafe {
@@ -33,6 +43,11 @@ pub fn randombytes(buf: &mut [u8]) {
     }
 }
 
+#[inline(always)]
+pub fn randombytes_uniform(upper_bound: u32) -> u32 {
+    unsafe { sodium::randombytes_uniform(upper_bound) }
+}
+
 #[inline(always)]
 pub fn memzero(buf: &mut [u8]) {
     unsafe {
@@ -103,6 +118,16 @@ pub fn box_keypair() -> (BoxPublicKey, BoxSecretKey) {
     (pk, sk)
 }
 
+pub fn box_public_key(sk: &BoxSecretKey) -> BoxPublicKey {
+    let mut pk = BoxPublicKey {
+        bytes: [0; BOX_PUBLICKEYBYTES],
+    };
+    unsafe {
+        sodium::crypto_scalarmult_base(pk.bytes.as_mut_ptr(), sk.bytes.as_ptr());
+    }
+    pk
+}
+
 #[derive(Clone)]
 pub struct BoxKey {
     pub bytes: [u8; BOX_BEFORENMBYTES],
@@ -118,8 +143,14 @@ impl Drop for BoxKey {
     }
 }
 
+// Legacy key derivation, only used to read data written before box_compute_key_hkdf.
+// Returns None for public keys with no usable shared secret, such as all zero keys.
 #[inline(always)]
-pub fn box_compute_key(pk: &BoxPublicKey, sk: &BoxSecretKey, psk: &BoxPreSharedKey) -> BoxKey {
+pub fn box_compute_key(
+    pk: &BoxPublicKey,
+    sk: &BoxSecretKey,
+    psk: &BoxPreSharedKey,
+) -> Option<BoxKey> {
     let mut unmixed_key_bytes: [u8; BOX_BEFORENMBYTES] =
         unsafe { std::mem::MaybeUninit::uninit().assume_init() };
     if unsafe {
@@ -130,9 +161,7 @@ pub fn box_compute_key(pk: &BoxPublicKey, sk: &BoxSecretKey, psk: &BoxPreSharedK
         )
     } != 0
     {
-        BoxKey {
-            bytes: [0; BOX_BEFORENMBYTES],
-        }
+        None
     } else {
         /*
           XXX TODO FIXME REVIEWME:
@@ -170,10 +199,75 @@ pub fn box_compute_key(pk: &BoxPublicKey, sk: &BoxSecretKey, psk: &BoxPreSharedK
             }
         };
 
-        BoxKey {
+        Some(BoxKey {
             bytes: mixed_key_bytes,
+        })
+    }
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut out: [u8; 32] = [0; 32];
+    let mut st = std::mem::MaybeUninit::<sodium::crypto_auth_hmacsha256_state>::uninit();
+    unsafe {
+        if sodium::crypto_auth_hmacsha256_init(st.as_mut_ptr(), key.as_ptr(), key.len()) != 0 {
+            panic!();
+        }
+        for part in parts.iter() {
+            if sodium::crypto_auth_hmacsha256_update(
+                st.as_mut_ptr(),
+                part.as_ptr(),
+                part.len().try_into().unwrap(),
+            ) != 0
+            {
+                panic!();
+            }
+        }
+        if sodium::crypto_auth_hmacsha256_final(st.as_mut_ptr(), out.as_mut_ptr()) != 0 {
+            panic!();
         }
     }
+    out
+}
+
+// Derive a box key from the diffie hellman secret between sk and pk using HKDF-SHA256.
+// The pre shared key is the extract salt, so the decrypting party must have had access
+// to one of our keys even if the asymmetric keys are broken. Expanding with both public
+// keys binds the box key to this particular exchange. Returns None for public keys
+// with no usable shared secret, such as low order or all zero keys.
+pub fn box_compute_key_hkdf(
+    pk: &BoxPublicKey,
+    sk: &BoxSecretKey,
+    recipient_pk: &BoxPublicKey,
+    ephemeral_pk: &BoxPublicKey,
+    psk: &BoxPreSharedKey,
+) -> Option<BoxKey> {
+    const HKDF_INFO_LABEL: &[u8] = b"bupstash-box-key-hkdf-v1";
+    let mut shared_secret: [u8; BOX_BEFORENMBYTES] = [0; BOX_BEFORENMBYTES];
+    if unsafe {
+        sodium::crypto_scalarmult(
+            shared_secret.as_mut_ptr(),
+            sk.bytes.as_ptr(),
+            pk.bytes.as_ptr(),
+        )
+    } != 0
+    {
+        return None;
+    }
+    let mut prk = hmac_sha256(&psk.bytes[..], &[&shared_secret[..]]);
+    let bk = BoxKey {
+        bytes: hmac_sha256(
+            &prk[..],
+            &[
+                HKDF_INFO_LABEL,
+                &recipient_pk.bytes[..],
+                &ephemeral_pk.bytes[..],
+                &[1],
+            ],
+        ),
+    };
+    memzero(&mut shared_secret[..]);
+    memzero(&mut prk[..]);
+    Some(bk)
 }
 
 #[inline(always)]
@@ -222,10 +316,97 @@ pub fn box_decrypt(pt: &mut [u8], bt: &[u8], bk: &BoxKey) -> bool {
     true
 }
 
-fn zstd_compress_chunk(mut data: Vec<u8>) -> Vec<u8> {
-    // Our max chunk size means this should never happen.
-    assert!(data.len() <= 0xffffffff);
-    let mut compressed_data = zstd::block::compress(&data, 0).unwrap();
+// How an encryption context picks nonces, chosen per key. Keys written before
+// the nonce mode was recorded use derived nonces, reading data does not depend on it.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+pub enum NonceMode {
+    // A random starting nonce incremented for each encryption.
+    Incrementing,
+    // The incrementing nonce hashed with the plain text, see derive_nonce.
+    #[default]
+    Derived,
+}
+
+impl NonceMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NonceMode::Incrementing => "incrementing",
+            NonceMode::Derived => "derived",
+        }
+    }
+}
+
+// Nonces for a box key are derived from the running nonce and the plain text, so
+// if the running nonce is ever repeated (for example when a virtual machine running a
+// put is restored from a snapshot) a nonce can only be reused for the same plain text,
+// which reveals only that the plain text was repeated. The nonce is stored alongside
+// the cipher text as before, so decryption does not need to know how it was chosen.
+fn derive_nonce_key(bk: &BoxKey) -> BoxKey {
+    const NONCE_KEY_LABEL: &[u8] = b"bupstash-derived-nonce-key";
+    let mut nonce_key = BoxKey {
+        bytes: [0; BOX_BEFORENMBYTES],
+    };
+    if unsafe {
+        sodium::crypto_generichash(
+            nonce_key.bytes.as_mut_ptr(),
+            nonce_key.bytes.len(),
+            NONCE_KEY_LABEL.as_ptr(),
+            NONCE_KEY_LABEL.len().try_into().unwrap(),
+            bk.bytes.as_ptr(),
+            bk.bytes.len(),
+        )
+    } != 0
+    {
+        panic!();
+    }
+    nonce_key
+}
+
+fn derive_nonce(nonce_key: &BoxKey, running_nonce: &BoxNonce, pt: &[u8]) -> BoxNonce {
+    let mut nonce = BoxNonce {
+        bytes: [0; BOX_NONCEBYTES],
+    };
+    let mut st = std::mem::MaybeUninit::<sodium::crypto_generichash_state>::uninit();
+    unsafe {
+        if sodium::crypto_generichash_init(
+            st.as_mut_ptr(),
+            nonce_key.bytes.as_ptr(),
+            nonce_key.bytes.len(),
+            BOX_NONCEBYTES,
+        ) != 0
+            || sodium::crypto_generichash_update(
+                st.as_mut_ptr(),
+                running_nonce.bytes.as_ptr(),
+                running_nonce.bytes.len().try_into().unwrap(),
+            ) != 0
+            || sodium::crypto_generichash_update(
+                st.as_mut_ptr(),
+                pt.as_ptr(),
+                pt.len().try_into().unwrap(),
+            ) != 0
+            || sodium::crypto_generichash_final(
+                st.as_mut_ptr(),
+                nonce.bytes.as_mut_ptr(),
+                nonce.bytes.len(),
+            ) != 0
+        {
+            panic!();
+        }
+    }
+    nonce
+}
+
+// Level 0 selects the zstd default, currently 3.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 0;
+pub const MAX_ZSTD_LEVEL: i32 = 22;
+
+// Compressed chunks end with their decompressed size and the footer,
+// data that does not shrink is stored uncompressed.
+fn add_compressed_chunk_footer(
+    mut data: Vec<u8>,
+    mut compressed_data: Vec<u8>,
+    footer: u8,
+) -> Vec<u8> {
     if (compressed_data.len() + 4) >= data.len() {
         data.push(CHUNK_FOOTER_NO_COMPRESSION);
         data
@@ -236,11 +417,46 @@ fn zstd_compress_chunk(mut data: Vec<u8>) -> Vec<u8> {
         compressed_data.push(((sz & 0x0000ff00) >> 8) as u8);
         compressed_data.push(((sz & 0x00ff0000) >> 16) as u8);
         compressed_data.push(((sz & 0xff000000) >> 24) as u8);
-        compressed_data.push(CHUNK_FOOTER_ZSTD_COMPRESSED);
+        compressed_data.push(footer);
         compressed_data
     }
 }
 
+fn zstd_compress_chunk(data: Vec<u8>, level: i32) -> Vec<u8> {
+    // Our max chunk size means this should never happen.
+    assert!(data.len() <= 0xffffffff);
+    let compressed_data = zstd::block::compress(&data, level).unwrap();
+    add_compressed_chunk_footer(data, compressed_data, CHUNK_FOOTER_ZSTD_COMPRESSED)
+}
+
+fn lz4_compress_chunk(data: Vec<u8>) -> Vec<u8> {
+    assert!(data.len() <= 0xffffffff);
+    let compressed_data = lz4_flex::block::compress(&data);
+    add_compressed_chunk_footer(data, compressed_data, CHUNK_FOOTER_LZ4_COMPRESSED)
+}
+
+// Samples shorter than this say too little about the data.
+const MIN_ENTROPY_SAMPLE: usize = 4096;
+
+// Guess whether data is already compressed or encrypted from the byte entropy
+// of a sample of it, such data uses close to all 8 bits of each byte.
+pub fn likely_incompressible(sample: &[u8]) -> bool {
+    if sample.len() < MIN_ENTROPY_SAMPLE {
+        return false;
+    }
+    let mut counts = [0usize; 256];
+    for b in sample.iter() {
+        counts[*b as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let mut entropy = 0.0;
+    for count in counts.iter().filter(|c| **c != 0) {
+        let p = *count as f64 / len;
+        entropy -= p * p.log2();
+    }
+    entropy > 7.8
+}
+
 fn decompress_chunk(mut data: Vec<u8>) -> Result<Vec<u8>, failure::Error> {
     if data.is_empty() {
         failure::bail!("data chunk was too small, missing footer");
@@ -251,7 +467,9 @@ fn decompress_chunk(mut data: Vec<u8>) -> Result<Vec<u8>, failure::Error> {
             data.pop();
             data
         }
-        footer if footer == CHUNK_FOOTER_ZSTD_COMPRESSED => {
+        footer
+            if footer == CHUNK_FOOTER_ZSTD_COMPRESSED || footer == CHUNK_FOOTER_LZ4_COMPRESSED =>
+        {
             data.pop();
             if data.len() < 4 {
                 failure::bail!("data footer missing decompressed size");
@@ -262,7 +480,15 @@ fn decompress_chunk(mut data: Vec<u8>) -> Result<Vec<u8>, failure::Error> {
                 | ((data[data_len - 3] as u32) << 8)
                 | (data[data_len - 4] as u32);
             data.truncate(data.len() - 4);
-            zstd::block::decompress(&data, decompressed_sz as usize)?
+            if footer == CHUNK_FOOTER_ZSTD_COMPRESSED {
+                zstd::block::decompress(&data, decompressed_sz as usize)?
+            } else {
+                let data = lz4_flex::block::decompress(&data, decompressed_sz as usize)?;
+                if data.len() != decompressed_sz as usize {
+                    failure::bail!("decompressed data size does not match footer");
+                }
+                data
+            }
         }
         _ => failure::bail!("unknown footer type type"),
     };
@@ -272,46 +498,75 @@ fn decompress_chunk(mut data: Vec<u8>) -> Result<Vec<u8>, failure::Error> {
 #[derive(Clone, Copy)]
 pub enum DataCompression {
     None,
-    Zstd,
+    // The compression level, the footer does not record it as
+    // decompression does not need it.
+    Zstd(i32),
+    Lz4,
 }
 
 #[derive(Clone)]
 pub struct EncryptionContext {
     nonce: BoxNonce,
+    nonce_mode: NonceMode,
+    nonce_key: BoxKey,
     ephemeral_pk: BoxPublicKey,
     ephemeral_bk: BoxKey,
 }
 
 impl EncryptionContext {
-    pub fn new(recipient: &BoxPublicKey, psk: &BoxPreSharedKey) -> EncryptionContext {
+    pub fn new(
+        recipient: &BoxPublicKey,
+        psk: &BoxPreSharedKey,
+        nonce_mode: NonceMode,
+    ) -> EncryptionContext {
         let nonce = BoxNonce::new();
         let (ephemeral_pk, ephemeral_sk) = box_keypair();
-        let ephemeral_bk = box_compute_key(recipient, &ephemeral_sk, &psk);
+        let ephemeral_bk =
+            box_compute_key_hkdf(recipient, &ephemeral_sk, recipient, &ephemeral_pk, &psk)
+                .expect("invalid recipient public key");
+        let nonce_key = derive_nonce_key(&ephemeral_bk);
         EncryptionContext {
             nonce,
+            nonce_mode,
+            nonce_key,
             ephemeral_pk,
             ephemeral_bk,
         }
     }
 
+    // A context for another thread, it shares the ephemeral key but
+    // starts from its own random nonce so the two never collide.
+    pub fn fork(&self) -> EncryptionContext {
+        EncryptionContext {
+            nonce: BoxNonce::new(),
+            ..self.clone()
+        }
+    }
+
     pub fn encrypt_data(&mut self, mut pt: Vec<u8>, compression: DataCompression) -> Vec<u8> {
         let pt = match compression {
             DataCompression::None => {
                 pt.push(CHUNK_FOOTER_NO_COMPRESSION);
                 pt
             }
-            DataCompression::Zstd => zstd_compress_chunk(pt),
+            DataCompression::Zstd(level) => zstd_compress_chunk(pt, level),
+            DataCompression::Lz4 => lz4_compress_chunk(pt),
         };
-        let ct_len = pt.len() + BOX_NONCEBYTES + BOX_MACBYTES + self.ephemeral_pk.bytes.len();
+        let box_len = pt.len() + BOX_NONCEBYTES + BOX_MACBYTES;
+        let ct_len = box_len + self.ephemeral_pk.bytes.len() + 1;
         let mut ct = Vec::with_capacity(ct_len);
         unsafe { ct.set_len(ct_len) };
-        box_encrypt(
-            &mut ct[..ct_len - self.ephemeral_pk.bytes.len()],
-            &pt,
-            &mut self.nonce,
-            &self.ephemeral_bk,
-        );
-        ct[ct_len - self.ephemeral_pk.bytes.len()..].clone_from_slice(&self.ephemeral_pk.bytes[..]);
+        let (mut nonce, version) = match self.nonce_mode {
+            NonceMode::Incrementing => (self.nonce.clone(), CIPHER_TEXT_HKDF_KEY_EXCHANGE),
+            NonceMode::Derived => (
+                derive_nonce(&self.nonce_key, &self.nonce, &pt),
+                CIPHER_TEXT_HKDF_DERIVED_NONCE,
+            ),
+        };
+        self.nonce.inc();
+        box_encrypt(&mut ct[..box_len], &pt, &mut nonce, &self.ephemeral_bk);
+        ct[box_len..ct_len - 1].clone_from_slice(&self.ephemeral_pk.bytes[..]);
+        ct[ct_len - 1] = version;
         ct
     }
 }
@@ -319,54 +574,103 @@ impl EncryptionContext {
 #[derive(Clone)]
 pub struct DecryptionContext {
     sk: BoxSecretKey,
+    pk: BoxPublicKey,
     psk: BoxPreSharedKey,
+    // Box keys are cached for the most recent ephemeral key, computed as needed.
     ephemeral_pk: BoxPublicKey,
-    ephemeral_bk: BoxKey,
+    ephemeral_hkdf_bk: Option<BoxKey>,
+    ephemeral_legacy_bk: Option<BoxKey>,
 }
 
 impl DecryptionContext {
     pub fn new(sk: BoxSecretKey, psk: BoxPreSharedKey) -> DecryptionContext {
+        let pk = box_public_key(&sk);
         DecryptionContext {
             sk,
+            pk,
             psk,
             ephemeral_pk: BoxPublicKey {
                 bytes: [0; BOX_PUBLICKEYBYTES],
             },
-            ephemeral_bk: BoxKey {
-                bytes: [0; BOX_BEFORENMBYTES],
-            },
+            ephemeral_hkdf_bk: None,
+            ephemeral_legacy_bk: None,
+        }
+    }
+
+    fn set_ephemeral_pk(&mut self, pk_slice: &[u8]) {
+        if pk_slice != &self.ephemeral_pk.bytes[..] {
+            self.ephemeral_pk.bytes[..].clone_from_slice(pk_slice);
+            self.ephemeral_hkdf_bk = None;
+            self.ephemeral_legacy_bk = None;
+        }
+    }
+
+    fn try_decrypt(bt: &[u8], bk: &BoxKey) -> Option<Vec<u8>> {
+        let pt_len = bt.len() - BOX_NONCEBYTES - BOX_MACBYTES;
+        let mut pt = Vec::with_capacity(pt_len);
+        unsafe { pt.set_len(pt_len) };
+        if box_decrypt(&mut pt, bt, bk) {
+            Some(pt)
+        } else {
+            None
         }
     }
 
     pub fn decrypt_data(&mut self, ct: Vec<u8>) -> Result<Vec<u8>, failure::Error> {
-        if ct.len() < BOX_PUBLICKEYBYTES + BOX_NONCEBYTES + BOX_MACBYTES {
+        let min_len = BOX_PUBLICKEYBYTES + BOX_NONCEBYTES + BOX_MACBYTES;
+        if ct.len() < min_len {
             failure::bail!("data corrupt (too small)");
         }
 
+        let version = ct[ct.len() - 1];
+        let pt = if version & CIPHER_TEXT_VERSIONED == 0 {
+            let pk_start = ct.len() - BOX_PUBLICKEYBYTES;
+            self.set_ephemeral_pk(&ct[pk_start..]);
+            if self.ephemeral_legacy_bk.is_none() {
+                match box_compute_key(&self.ephemeral_pk, &self.sk, &self.psk) {
+                    Some(bk) => self.ephemeral_legacy_bk = Some(bk),
+                    None => failure::bail!("data corrupt (invalid ephemeral key)"),
+                }
+            }
+            DecryptionContext::try_decrypt(
+                &ct[..pk_start],
+                self.ephemeral_legacy_bk.as_ref().unwrap(),
+            )
+        } else if version == CIPHER_TEXT_HKDF_KEY_EXCHANGE
+            || version == CIPHER_TEXT_HKDF_DERIVED_NONCE
         {
-            let pk_slice = &ct[ct.len() - BOX_PUBLICKEYBYTES..];
-            for i in 0..BOX_PUBLICKEYBYTES {
-                if pk_slice[i] != self.ephemeral_pk.bytes[i] {
-                    self.ephemeral_pk.bytes[..].clone_from_slice(pk_slice);
-                    self.ephemeral_bk = box_compute_key(&self.ephemeral_pk, &self.sk, &self.psk);
-                    break;
+            if ct.len() == min_len {
+                failure::bail!("data corrupt (too small)");
+            }
+            let pk_start = ct.len() - 1 - BOX_PUBLICKEYBYTES;
+            self.set_ephemeral_pk(&ct[pk_start..ct.len() - 1]);
+            if self.ephemeral_hkdf_bk.is_none() {
+                match box_compute_key_hkdf(
+                    &self.ephemeral_pk,
+                    &self.sk,
+                    &self.pk,
+                    &self.ephemeral_pk,
+                    &self.psk,
+                ) {
+                    Some(bk) => self.ephemeral_hkdf_bk = Some(bk),
+                    None => failure::bail!("data corrupt (invalid ephemeral key)"),
                 }
             }
-        }
-
-        let pt_len = ct.len() - BOX_NONCEBYTES - BOX_MACBYTES - BOX_PUBLICKEYBYTES;
-        let mut pt = Vec::with_capacity(pt_len);
-        unsafe { pt.set_len(pt_len) };
+            DecryptionContext::try_decrypt(
+                &ct[..pk_start],
+                self.ephemeral_hkdf_bk.as_ref().unwrap(),
+            )
+        } else {
+            failure::bail!(
+                "data uses unknown cipher text version {}, a newer version of bupstash may be needed",
+                version & !CIPHER_TEXT_VERSIONED
+            );
+        };
 
-        if !box_decrypt(
-            &mut pt,
-            &ct[..ct.len() - BOX_PUBLICKEYBYTES],
-            &self.ephemeral_bk,
-        ) {
-            failure::bail!("data corrupt");
+        match pt {
+            Some(pt) => decompress_chunk(pt),
+            None => failure::bail!("data corrupt"),
         }
-
-        decompress_chunk(pt)
     }
 }
 
@@ -521,7 +825,7 @@ mod tests {
         let mut nonce = BoxNonce::new();
         let (pk, sk) = box_keypair();
         let psk = BoxPreSharedKey::new();
-        let bk = box_compute_key(&pk, &sk, &psk);
+        let bk = box_compute_key(&pk, &sk, &psk).unwrap();
         let pt1 = vec![1, 2, 3];
         let mut bt = Vec::new();
         bt.resize_with(pt1.len() + BOX_NONCEBYTES + BOX_MACBYTES, Default::default);
@@ -538,15 +842,130 @@ mod tests {
         let (pk, sk) = box_keypair();
         let psk = BoxPreSharedKey::new();
         let pt1 = vec![1, 2, 3];
-        let mut ectx1 = EncryptionContext::new(&pk, &psk);
-        let mut ectx2 = EncryptionContext::new(&pk, &psk);
+        let mut ectx1 = EncryptionContext::new(&pk, &psk, NonceMode::Derived);
+        let mut ectx2 = EncryptionContext::new(&pk, &psk, NonceMode::Derived);
         let ct1 = ectx1.encrypt_data(pt1.clone(), DataCompression::None);
-        let ct2 = ectx2.encrypt_data(pt1.clone(), DataCompression::Zstd);
+        let ct2 = ectx2.encrypt_data(pt1.clone(), DataCompression::Zstd(DEFAULT_ZSTD_LEVEL));
         let mut dctx = DecryptionContext::new(sk, psk);
         let pt2 = dctx.decrypt_data(ct1).unwrap();
         let pt3 = dctx.decrypt_data(ct2).unwrap();
         assert_eq!(pt1, pt2);
         assert_eq!(pt1, pt3);
+        // Any level decompresses the same way.
+        let pt4 = vec![7; 4096];
+        for level in [1, 19, MAX_ZSTD_LEVEL].iter() {
+            let ct = ectx1.encrypt_data(pt4.clone(), DataCompression::Zstd(*level));
+            assert_eq!(dctx.decrypt_data(ct).unwrap(), pt4);
+        }
+        let ct = ectx1.encrypt_data(pt4.clone(), DataCompression::Lz4);
+        assert!(ct.len() < pt4.len());
+        assert_eq!(dctx.decrypt_data(ct).unwrap(), pt4);
+    }
+
+    #[test]
+    fn incompressible_samples() {
+        init();
+        let mut random = vec![0; 65536];
+        randombytes(&mut random);
+        assert!(likely_incompressible(&random));
+        assert!(!likely_incompressible(&random[..100]));
+        let text = "the quick brown fox jumps over the lazy dog\n".repeat(1000);
+        assert!(!likely_incompressible(text.as_bytes()));
+        assert!(!likely_incompressible(&[0; 65536][..]));
+    }
+
+    #[test]
+    fn forked_context() {
+        init();
+        let (pk, sk) = box_keypair();
+        let psk = BoxPreSharedKey::new();
+        let mut ectx1 = EncryptionContext::new(&pk, &psk, NonceMode::Derived);
+        let mut ectx2 = ectx1.fork();
+        let ct1 = ectx1.encrypt_data(vec![1, 2, 3], DataCompression::None);
+        let ct2 = ectx2.encrypt_data(vec![1, 2, 3], DataCompression::None);
+        assert!(ct1[..BOX_NONCEBYTES] != ct2[..BOX_NONCEBYTES]);
+        let mut dctx = DecryptionContext::new(sk, psk);
+        assert_eq!(dctx.decrypt_data(ct1).unwrap(), vec![1, 2, 3]);
+        assert_eq!(dctx.decrypt_data(ct2).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn repeated_nonce_state() {
+        init();
+        let (pk, sk) = box_keypair();
+        let psk = BoxPreSharedKey::new();
+        let mut ectx1 = EncryptionContext::new(&pk, &psk, NonceMode::Derived);
+        // Simulate restoring a snapshot of a running process.
+        let mut ectx2 = ectx1.clone();
+        let ct1 = ectx1.encrypt_data(vec![1, 2, 3], DataCompression::None);
+        let ct2 = ectx2.encrypt_data(vec![1, 2, 3], DataCompression::None);
+        let ct3 = ectx1.encrypt_data(vec![1, 2, 3], DataCompression::None);
+        let ct4 = ectx2.encrypt_data(vec![4, 5, 6], DataCompression::None);
+        assert_eq!(ct1, ct2);
+        assert!(ct1[..BOX_NONCEBYTES] != ct3[..BOX_NONCEBYTES]);
+        assert!(ct3[..BOX_NONCEBYTES] != ct4[..BOX_NONCEBYTES]);
+        let mut dctx = DecryptionContext::new(sk, psk);
+        assert_eq!(dctx.decrypt_data(ct4).unwrap(), vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn legacy_data_round_trip() {
+        init();
+        let (pk, sk) = box_keypair();
+        let psk = BoxPreSharedKey::new();
+        let mut dctx = DecryptionContext::new(sk.clone(), psk.clone());
+        // Legacy cipher text ends in a public key byte, which never looks like a version byte.
+        for i in 0..1000 {
+            let mut nonce = BoxNonce::new();
+            let (ephemeral_pk, ephemeral_sk) = box_keypair();
+            let bk = box_compute_key(&pk, &ephemeral_sk, &psk).unwrap();
+            let pt = vec![i as u8, CHUNK_FOOTER_NO_COMPRESSION];
+            let mut ct = Vec::new();
+            ct.resize_with(pt.len() + BOX_NONCEBYTES + BOX_MACBYTES, Default::default);
+            box_encrypt(&mut ct, &pt, &mut nonce, &bk);
+            ct.extend_from_slice(&ephemeral_pk.bytes[..]);
+            assert_eq!(ct[ct.len() - 1] & CIPHER_TEXT_VERSIONED, 0);
+            assert_eq!(dctx.decrypt_data(ct).unwrap(), vec![i as u8]);
+        }
+        let mut ectx = EncryptionContext::new(&pk, &psk, NonceMode::Incrementing);
+        let ct = ectx.encrypt_data(vec![1, 2, 3], DataCompression::None);
+        assert_eq!(ct[ct.len() - 1], CIPHER_TEXT_HKDF_KEY_EXCHANGE);
+        assert_eq!(dctx.decrypt_data(ct).unwrap(), vec![1, 2, 3]);
+        let mut ectx = EncryptionContext::new(&pk, &psk, NonceMode::Derived);
+        let mut ct = ectx.encrypt_data(vec![1, 2, 3], DataCompression::None);
+        assert_eq!(ct[ct.len() - 1], CIPHER_TEXT_HKDF_DERIVED_NONCE);
+        assert_eq!(dctx.decrypt_data(ct.clone()).unwrap(), vec![1, 2, 3]);
+        let ct_len = ct.len();
+        ct[ct_len - 1] = CIPHER_TEXT_VERSIONED | 0x7f;
+        assert!(dctx.decrypt_data(ct).is_err());
+    }
+
+    #[test]
+    fn zero_ephemeral_key_rejected() {
+        init();
+        let (_, sk) = box_keypair();
+        let psk = BoxPreSharedKey::new();
+        // Cipher text boxed with the all zero key a failed key exchange used to produce,
+        // which anyone can compute without the secret or pre shared keys.
+        let zero_bk = BoxKey {
+            bytes: [0; BOX_BEFORENMBYTES],
+        };
+        let zero_pk = [0; BOX_PUBLICKEYBYTES];
+        let pt = vec![1, 2, 3, CHUNK_FOOTER_NO_COMPRESSION];
+        let mut boxed = Vec::new();
+        boxed.resize_with(pt.len() + BOX_NONCEBYTES + BOX_MACBYTES, Default::default);
+        box_encrypt(&mut boxed, &pt, &mut BoxNonce::new(), &zero_bk);
+
+        let mut ct = boxed.clone();
+        ct.extend_from_slice(&zero_pk[..]);
+        ct.push(CIPHER_TEXT_HKDF_KEY_EXCHANGE);
+        let mut dctx = DecryptionContext::new(sk.clone(), psk.clone());
+        assert!(dctx.decrypt_data(ct).is_err());
+
+        let mut legacy_ct = boxed;
+        legacy_ct.extend_from_slice(&zero_pk[..]);
+        let mut dctx = DecryptionContext::new(sk, psk);
+        assert!(dctx.decrypt_data(legacy_ct).is_err());
     }
 
     #[test]
@@ -7,7 +7,7 @@ use super::repository;
 use std::convert::TryInto;
 use std::io::Write;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 const RENAME_BATCH_SIZE: u64 = 256;
@@ -47,12 +47,16 @@ pub struct DirStorage {
     write_worker_tx: Vec<crossbeam_channel::Sender<WriteWorkerMsg>>,
     write_chunk_count: u64,
     write_round_robin_index: usize,
+    added_chunks: Arc<AtomicUsize>,
+    added_bytes: Arc<AtomicUsize>,
 }
 
 impl DirStorage {
     fn add_write_worker_thread(&mut self) -> Result<(), failure::Error> {
         let mut data_path = self.dir_path.clone();
         let had_io_error = self.had_io_error.clone();
+        let added_chunks = self.added_chunks.clone();
+        let added_bytes = self.added_bytes.clone();
         let (write_worker_tx, write_worker_rx) = crossbeam_channel::bounded(0);
 
         let mut pending_batch_rename = Vec::new();
@@ -138,6 +142,8 @@ impl DirStorage {
                                 .open(&tmp));
 
                             worker_try!(tmp_file.write_all(&data));
+                            added_chunks.fetch_add(1, Ordering::SeqCst);
+                            added_bytes.fetch_add(data.len(), Ordering::SeqCst);
 
                             pending_batch_rename.push((dest, tmp.into(), tmp_file));
                             if pending_batch_rename.len() >= RENAME_BATCH_SIZE.try_into().unwrap() {
@@ -292,6 +298,8 @@ impl DirStorage {
             write_worker_tx,
             write_chunk_count: 0,
             write_round_robin_index: 0,
+            added_chunks: Arc::new(AtomicUsize::new(0)),
+            added_bytes: Arc::new(AtomicUsize::new(0)),
         })
     }
 }
@@ -342,14 +350,29 @@ impl Engine for DirStorage {
         }
     }
 
+    fn chunk_size(&mut self, addr: &Address) -> Result<u64, failure::Error> {
+        let mut chunk_path = self.dir_path.clone();
+        chunk_path.push(addr.as_hex_addr().as_str());
+        Ok(std::fs::metadata(&chunk_path)?.len())
+    }
+
     fn sync(&mut self) -> Result<(), failure::Error> {
         self.sync_write_workers()
     }
 
+    fn added_chunk_stats(&mut self) -> Option<(usize, usize)> {
+        Some((
+            self.added_chunks.load(Ordering::SeqCst),
+            self.added_bytes.load(Ordering::SeqCst),
+        ))
+    }
+
     fn gc(
         &mut self,
         _reachability_db_path: &std::path::Path,
         reachability_db: &mut rusqlite::Connection,
+        pacer: &repository::GCPacer,
+        sweep_workers: usize,
     ) -> Result<repository::GCStats, failure::Error> {
         self.stop_workers();
 
@@ -398,9 +421,37 @@ impl Engine for DirStorage {
             }
         }
 
-        for p in to_remove.iter() {
-            std::fs::remove_file(p)?;
+        // Removal is mostly waiting on the filesystem, which may be able to
+        // process many unlinks at once, so spread them over a pool of workers.
+        let (remove_tx, remove_rx) = crossbeam_channel::unbounded();
+        for p in to_remove.drain(..) {
+            remove_tx.send(p).unwrap();
+        }
+        std::mem::drop(remove_tx);
+
+        let mut remove_worker_handles = Vec::new();
+        for _i in 0..sweep_workers.max(1) {
+            let remove_rx = remove_rx.clone();
+            let pacer = pacer.clone();
+            let worker = std::thread::Builder::new().stack_size(256 * 1024).spawn(
+                move || -> Result<(), std::io::Error> {
+                    for p in remove_rx.iter() {
+                        pacer.pace();
+                        std::fs::remove_file(&p)?;
+                    }
+                    Ok(())
+                },
+            )?;
+            remove_worker_handles.push(worker);
+        }
+
+        let mut result: Result<(), std::io::Error> = Ok(());
+        for h in remove_worker_handles.drain(..) {
+            if let Err(err) = h.join().unwrap() {
+                result = Err(err);
+            }
         }
+        result?;
 
         Ok(repository::GCStats {
             chunks_remaining: Some(chunks_remaining),
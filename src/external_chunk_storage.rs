@@ -308,10 +308,18 @@ impl Engine for ExternalStorage {
         self.sync_write_workers()
     }
 
+    fn added_chunk_stats(&mut self) -> Option<(usize, usize)> {
+        // The external storage protocol does not say if a chunk already existed.
+        None
+    }
+
     fn gc(
         &mut self,
         reachability_db_path: &std::path::Path,
         _reachability_db: &mut rusqlite::Connection,
+        // Removal is done by the storage plugin.
+        _pacer: &repository::GCPacer,
+        _sweep_workers: usize,
     ) -> Result<repository::GCStats, failure::Error> {
         self.stop_workers();
 
@@ -0,0 +1,106 @@
+// Gear hash based content defined chunking, as described in
+// "FastCDC: a Fast and Efficient Content-Defined Chunking Approach
+// for Data Deduplication" by Wen Xia et al.
+
+// Each byte shifts the hash left by one, so after this many bytes
+// the hash no longer depends on bytes before them.
+pub const WINDOW_SIZE: usize = 64;
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64 with a fixed seed, chunk boundaries must never change.
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6275_7073_7461_7368;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+static GEAR: [u64; 256] = gear_table();
+
+// A mask of the n most significant bits, the low bits of
+// the gear hash only depend on the last few bytes.
+fn top_bits(n: u32) -> u64 {
+    match n {
+        0 => 0,
+        n if n >= 64 => !0,
+        n => !0 << (64 - n),
+    }
+}
+
+#[derive(Clone)]
+pub struct Gear {
+    hash: u64,
+    // Normalized chunking, a harder to match mask is used before the normal size,
+    // and an easier one after it, keeping chunk sizes close to the normal size.
+    normal_size: usize,
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl Gear {
+    // Chunks average roughly min_size + chunk_mask + 1 bytes, like the rollsum chunker.
+    pub fn new(chunk_mask: u32, min_size: usize) -> Gear {
+        let bits = chunk_mask.count_ones();
+        Gear {
+            hash: 0,
+            normal_size: min_size + chunk_mask as usize + 1,
+            mask_small: top_bits(bits + 2),
+            mask_large: top_bits(bits.saturating_sub(2)),
+        }
+    }
+
+    // Roll in the byte that makes the pending chunk len bytes long.
+    #[inline(always)]
+    pub fn roll_byte(&mut self, b: u8, len: usize) -> bool {
+        self.hash = (self.hash << 1).wrapping_add(GEAR[b as usize]);
+        let mask = if len < self.normal_size {
+            self.mask_small
+        } else {
+            self.mask_large
+        };
+        self.hash & mask == 0
+    }
+
+    #[inline]
+    pub fn reset(&mut self) {
+        self.hash = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gear_window() {
+        let mut a = Gear::new(0xff, 0);
+        let mut b = Gear::new(0xff, 0);
+        for i in 0..100u8 {
+            a.roll_byte(i, 1);
+        }
+        for i in 0..WINDOW_SIZE as u8 {
+            b.roll_byte(i + 7, 1);
+        }
+        for i in 0..WINDOW_SIZE as u8 {
+            a.roll_byte(i, 1);
+            b.roll_byte(i, 1);
+        }
+        assert_eq!(a.hash, b.hash);
+    }
+
+    #[test]
+    fn test_gear_normalized() {
+        let g = Gear::new(0xfffff, 1024);
+        assert_eq!(g.normal_size, 1024 + 0x100000);
+        assert_eq!(g.mask_small.count_ones(), 22);
+        assert_eq!(g.mask_large.count_ones(), 18);
+        assert_eq!(g.mask_small.leading_ones(), 22);
+    }
+}
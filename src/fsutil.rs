@@ -22,6 +22,25 @@ impl FileLock {
         f.lock_shared()?;
         Ok(FileLock { f })
     }
+
+    // Returns None instead of blocking if the lock is held elsewhere.
+    pub fn try_get_exclusive(p: &Path) -> Result<Option<FileLock>, std::io::Error> {
+        let f = fs::File::open(p)?;
+        match FileExt::try_lock_exclusive(&f) {
+            Ok(()) => Ok(Some(FileLock { f })),
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn try_get_shared(p: &Path) -> Result<Option<FileLock>, std::io::Error> {
+        let f = fs::File::open(p)?;
+        match FileExt::try_lock_shared(&f) {
+            Ok(()) => Ok(Some(FileLock { f })),
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
 }
 
 impl Drop for FileLock {
@@ -87,6 +106,36 @@ where
     Ok(absolute_path)
 }
 
+// See https://bford.info/cachedir/, the tag file must start with this signature.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+// Whether the directory is marked as a cache with a CACHEDIR.TAG file.
+pub fn is_tagged_cache_dir(p: &Path) -> bool {
+    let mut f = match fs::File::open(p.join("CACHEDIR.TAG")) {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+    let mut buf = [0; CACHEDIR_TAG_SIGNATURE.len()];
+    match std::io::Read::read_exact(&mut f, &mut buf) {
+        Ok(()) => buf[..] == *CACHEDIR_TAG_SIGNATURE,
+        Err(_) => false,
+    }
+}
+
+// The size of a file, or of the disk behind a block device, which stat reports as empty.
+pub fn file_size(f: &mut fs::File) -> std::io::Result<u64> {
+    use std::io::Seek;
+    use std::os::unix::fs::FileTypeExt;
+    let metadata = f.metadata()?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(metadata.len());
+    }
+    let pos = f.stream_position()?;
+    let size = f.seek(std::io::SeekFrom::End(0))?;
+    f.seek(std::io::SeekFrom::Start(pos))?;
+    Ok(size)
+}
+
 pub fn read_dirents(path: &Path) -> std::io::Result<Vec<std::fs::DirEntry>> {
     let mut dir_ents = Vec::new();
     for entry in std::fs::read_dir(&path)? {
@@ -94,3 +143,173 @@ pub fn read_dirents(path: &Path) -> std::io::Result<Vec<std::fs::DirEntry>> {
     }
     Ok(dir_ents)
 }
+
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "linux")] {
+
+        // Open a file we are backing up, without updating its access time
+        // when we are allowed to, O_NOATIME needs us to own the file.
+        pub fn open_file_for_backup(p: &Path) -> std::io::Result<fs::File> {
+            use std::os::unix::fs::OpenOptionsExt;
+            match fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOATIME)
+                .open(p)
+            {
+                Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                    fs::OpenOptions::new().read(true).open(p)
+                }
+                result => result,
+            }
+        }
+
+    } else {
+
+        // Other platforms have no equivalent of O_NOATIME.
+        pub fn open_file_for_backup(p: &Path) -> std::io::Result<fs::File> {
+            fs::OpenOptions::new().read(true).open(p)
+        }
+
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "freebsd"))] {
+
+        // Shift file pages to the tail of the page cache, allowing the kernel to quickly
+        // evict them. This works well for system backups, where we don't want to trash the
+        // users current cache. One source on how linux treats this hint - https://lwn.net/Articles/449420
+        pub fn advise_read_once(f: &fs::File) -> Result<(), nix::Error> {
+            use std::os::unix::io::AsRawFd;
+            nix::fcntl::posix_fadvise(
+                f.as_raw_fd(),
+                0,
+                0,
+                nix::fcntl::PosixFadviseAdvice::POSIX_FADV_NOREUSE,
+            )?;
+            Ok(())
+        }
+
+    } else {
+
+        pub fn advise_read_once(_f: &fs::File) -> Result<(), nix::Error> {
+            Ok(())
+        }
+
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "linux")] {
+
+        // From linux/fs.h, FS_IOC_GETFLAGS is _IOR('f', 1, long).
+        nix::ioctl_read!(fs_ioc_getflags, b'f', 1, libc::c_long);
+        const FS_NODUMP_FL: libc::c_long = 0x0000_0040;
+
+        // Files marked with 'chattr +d' are excluded from backups, like dump(8).
+        // Only regular files and directories are checked, opening other
+        // file types to query flags may have side effects.
+        pub fn has_nodump_flag(p: &Path, metadata: &fs::Metadata) -> bool {
+            use std::os::unix::fs::OpenOptionsExt;
+            use std::os::unix::io::AsRawFd;
+
+            if !metadata.is_file() && !metadata.is_dir() {
+                return false;
+            }
+            let f = match fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+                .open(p)
+            {
+                Ok(f) => f,
+                // Anything we cannot open is reported when we try to send it.
+                Err(_) => return false,
+            };
+            let mut flags: libc::c_long = 0;
+            // Filesystems without flag support fail with ENOTTY or similar.
+            match unsafe { fs_ioc_getflags(f.as_raw_fd(), &mut flags) } {
+                Ok(_) => (flags & FS_NODUMP_FL) != 0,
+                Err(_) => false,
+            }
+        }
+
+    } else if #[cfg(any(
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly",
+        target_os = "macos"
+    ))] {
+
+        // The same value on every BSD, see chflags(2).
+        const UF_NODUMP: u32 = 0x0000_0001;
+
+        // Files marked with 'chflags nodump' are excluded from backups, like dump(8) and bsdtar.
+        pub fn has_nodump_flag(_p: &Path, metadata: &fs::Metadata) -> bool {
+            cfg_if::cfg_if! {
+                if #[cfg(target_os = "freebsd")] {
+                    use std::os::freebsd::fs::MetadataExt;
+                } else if #[cfg(target_os = "openbsd")] {
+                    use std::os::openbsd::fs::MetadataExt;
+                } else if #[cfg(target_os = "netbsd")] {
+                    use std::os::netbsd::fs::MetadataExt;
+                } else if #[cfg(target_os = "dragonfly")] {
+                    use std::os::dragonfly::fs::MetadataExt;
+                } else {
+                    use std::os::macos::fs::MetadataExt;
+                }
+            }
+            (metadata.st_flags() & UF_NODUMP) != 0
+        }
+
+    } else {
+
+        pub fn has_nodump_flag(_p: &Path, _metadata: &fs::Metadata) -> bool {
+            false
+        }
+
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "freebsd"))] {
+
+        fn lseek_sparse(f: &fs::File, offset: u64, whence: libc::c_int) -> std::io::Result<Option<u64>> {
+            use std::os::unix::io::AsRawFd;
+            let off = unsafe { libc::lseek(f.as_raw_fd(), offset as libc::off_t, whence) };
+            if off >= 0 {
+                return Ok(Some(off as u64));
+            }
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::ENXIO) => Ok(None),
+                _ => Err(err),
+            }
+        }
+
+        // The start of the first data region at or after offset, None if only a
+        // hole follows it. Moves the file position, so callers must seek back.
+        // Filesystems without hole support report the whole file as data.
+        pub fn seek_data(f: &fs::File, offset: u64) -> std::io::Result<Option<u64>> {
+            lseek_sparse(f, offset, libc::SEEK_DATA)
+        }
+
+        // The start of the first hole at or after offset, the end of the file
+        // counts as a hole. Moves the file position, so callers must seek back.
+        pub fn seek_hole(f: &fs::File, offset: u64) -> std::io::Result<u64> {
+            Ok(lseek_sparse(f, offset, libc::SEEK_HOLE)?.unwrap_or(offset))
+        }
+
+    } else {
+
+        // Holes cannot be found on this platform, all of the file is data.
+        pub fn seek_data(_f: &fs::File, offset: u64) -> std::io::Result<Option<u64>> {
+            Ok(Some(offset))
+        }
+
+        pub fn seek_hole(_f: &fs::File, _offset: u64) -> std::io::Result<u64> {
+            Ok(u64::MAX)
+        }
+
+    }
+}
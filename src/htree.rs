@@ -1,5 +1,6 @@
 use super::address::*;
 use super::crypto;
+use super::index;
 use super::rollsum;
 use failure::Fail;
 
@@ -14,6 +15,9 @@ pub enum HTreeError {
     DataMissing,
 }
 
+// The raw chunks of a tree in tree order, as they were received from the repository.
+pub type RawChunks = Vec<(Address, Vec<u8>)>;
+
 pub trait Sink {
     fn add_chunk(&mut self, addr: &Address, data: Vec<u8>) -> Result<(), failure::Error>;
 }
@@ -47,6 +51,13 @@ pub struct TreeWriter {
     chunk_mask: u32,
     rollsums: Vec<rollsum::Rollsum>,
     data_chunk_count: u64,
+    mark: Option<TreeWriterMark>,
+}
+
+// Like the chunker mark, each level's block is only copied once it is cleared.
+struct TreeWriterMark {
+    levels: Vec<(usize, rollsum::Rollsum, Option<Vec<u8>>)>,
+    data_chunk_count: u64,
 }
 
 pub fn tree_block_address(data: &[u8]) -> Address {
@@ -64,14 +75,52 @@ impl TreeWriter {
             tree_blocks: Vec::new(),
             rollsums: Vec::new(),
             data_chunk_count: 0,
+            mark: None,
         }
     }
 
+    // Remember the current state so rewind can undo addresses added after this point.
+    pub fn mark(&mut self) {
+        self.mark = Some(TreeWriterMark {
+            levels: self
+                .tree_blocks
+                .iter()
+                .zip(self.rollsums.iter())
+                .map(|(block, rs)| (block.len(), rs.clone(), None))
+                .collect(),
+            data_chunk_count: self.data_chunk_count,
+        });
+    }
+
+    pub fn clear_mark(&mut self) {
+        self.mark = None;
+    }
+
+    // Restore the state saved by mark, tree blocks written since then are left unreferenced.
+    pub fn rewind(&mut self) {
+        let mark = self.mark.take().expect("rewind without mark");
+        self.tree_blocks.truncate(mark.levels.len());
+        self.rollsums.truncate(mark.levels.len());
+        for (level, (len, rs, block)) in mark.levels.into_iter().enumerate() {
+            match block {
+                Some(block) => self.tree_blocks[level] = block,
+                None => self.tree_blocks[level].truncate(len),
+            }
+            self.rollsums[level] = rs;
+        }
+        self.data_chunk_count = mark.data_chunk_count;
+    }
+
     fn clear_level(&mut self, sink: &mut dyn Sink, level: usize) -> Result<(), failure::Error> {
         // Writing empty blocks the parent level is pointless.
         if !self.tree_blocks[level].is_empty() {
             let mut block = Vec::with_capacity(MINIMUM_ADDR_CHUNK_SIZE);
             std::mem::swap(&mut block, &mut self.tree_blocks[level]);
+            if let Some((len, _, ref mut saved @ None)) =
+                self.mark.as_mut().and_then(|m| m.levels.get_mut(level))
+            {
+                *saved = Some(block[..*len].to_vec());
+            }
             let block_address = tree_block_address(&block);
             sink.add_chunk(&block_address, block)?;
             self.add_addr(sink, level + 1, &block_address)?;
@@ -227,10 +276,179 @@ impl TreeReader {
     }
 }
 
+// Tracks which data chunks of a tree walk fall in the requested ranges, the server
+// only sends those chunks and the tree blocks leading to them.
+pub struct DataRangeFilter {
+    ranges: Vec<index::HTreeDataRange>,
+    range_idx: usize,
+    current_data_chunk_idx: u64,
+    // Indexes of the wanted chunks of the last filtered level, next at the back.
+    pending_data_chunks: std::collections::VecDeque<u64>,
+}
+
+impl DataRangeFilter {
+    pub fn new(
+        ranges: Vec<index::HTreeDataRange>,
+        height: usize,
+        addr: &Address,
+    ) -> DataRangeFilter {
+        let mut filter = DataRangeFilter {
+            ranges,
+            range_idx: 0,
+            current_data_chunk_idx: 0,
+            pending_data_chunks: std::collections::VecDeque::new(),
+        };
+        // A tree of height 0 is a single data chunk that no tree block lists.
+        if height == 0 {
+            filter.filter_level(&addr.bytes);
+        }
+        filter
+    }
+
+    // Once past the last range the remaining tree blocks can't contain
+    // wanted data chunks, so the server does not send them.
+    pub fn done(&self) -> bool {
+        self.range_idx >= self.ranges.len()
+    }
+
+    // Keep the addresses of wanted data chunks from a height 1 tree block.
+    pub fn filter_level(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut filtered_data = Vec::with_capacity(data.len());
+
+        for addr_bytes in data.chunks(ADDRESS_SZ) {
+            if let Some(current_range) = self.ranges.get(self.range_idx) {
+                if self.current_data_chunk_idx >= current_range.start_idx
+                    && self.current_data_chunk_idx <= current_range.end_idx
+                {
+                    filtered_data.extend_from_slice(addr_bytes);
+                    self.pending_data_chunks
+                        .push_front(self.current_data_chunk_idx);
+                }
+                self.current_data_chunk_idx += 1;
+
+                if self.current_data_chunk_idx > current_range.end_idx {
+                    self.range_idx += 1;
+                }
+            }
+        }
+
+        filtered_data
+    }
+
+    // The index of the next wanted data chunk in tree order.
+    pub fn next_data_chunk(&mut self) -> Option<u64> {
+        self.pending_data_chunks.pop_back()
+    }
+
+    // Once the whole tree has been walked, a range that is still open
+    // goes past the last data chunk.
+    pub fn finish(&self) -> Result<(), failure::Error> {
+        if !self.done() {
+            failure::bail!("malformed htree fetch range, past the end of the data");
+        }
+        Ok(())
+    }
+}
+
+// Print the structure of a tree, one line per block with the
+// depth shown as indentation. Tree blocks that are missing or corrupt
+// are reported and skipped so a damaged tree can still be examined,
+// the number of such problems is returned.
+pub fn dump_tree(
+    source: &mut dyn Source,
+    height: usize,
+    addr: &Address,
+    out: &mut dyn std::io::Write,
+) -> Result<u64, failure::Error> {
+    let mut n_problems = 0;
+    let mut tr = TreeReader::new(height, addr);
+
+    while let Some((level, addr)) = tr.next_addr()? {
+        let indent = "  ".repeat(height - level);
+        if level == 0 {
+            writeln!(out, "{}height=0 address={}", indent, addr)?;
+            continue;
+        }
+
+        let data = match source.get_chunk(&addr) {
+            Ok(data) => data,
+            Err(err) => {
+                n_problems += 1;
+                writeln!(
+                    out,
+                    "{}height={} address={} error=\"{}\"",
+                    indent, level, addr, err
+                )?;
+                continue;
+            }
+        };
+
+        if tree_block_address(&data) != addr || data.len() % ADDRESS_SZ != 0 {
+            n_problems += 1;
+            writeln!(
+                out,
+                "{}height={} address={} error=\"{}\"",
+                indent,
+                level,
+                addr,
+                HTreeError::CorruptOrTamperedDataError
+            )?;
+            continue;
+        }
+
+        writeln!(
+            out,
+            "{}height={} address={} children={}",
+            indent,
+            level,
+            addr,
+            data.len() / ADDRESS_SZ
+        )?;
+        tr.push_level(level - 1, data)?;
+    }
+
+    Ok(n_problems)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_rewind() {
+        let mut chunks = HashMap::<Address, Vec<u8>>::new();
+        let mut tw = TreeWriter::new(MINIMUM_ADDR_CHUNK_SIZE, 0xffffffff);
+        tw.add(&mut chunks, &Address::from_bytes(&[1; ADDRESS_SZ]), vec![])
+            .unwrap();
+        tw.mark();
+        for i in 2..6 {
+            tw.add(&mut chunks, &Address::from_bytes(&[i; ADDRESS_SZ]), vec![])
+                .unwrap();
+        }
+        tw.rewind();
+        assert_eq!(tw.data_chunk_count(), 1);
+        tw.add(&mut chunks, &Address::from_bytes(&[2; ADDRESS_SZ]), vec![0])
+            .unwrap();
+        let (_, result) = tw.finish(&mut chunks).unwrap();
+
+        let mut expected_chunks = HashMap::<Address, Vec<u8>>::new();
+        let mut tw = TreeWriter::new(MINIMUM_ADDR_CHUNK_SIZE, 0xffffffff);
+        tw.add(
+            &mut expected_chunks,
+            &Address::from_bytes(&[1; ADDRESS_SZ]),
+            vec![],
+        )
+        .unwrap();
+        tw.add(
+            &mut expected_chunks,
+            &Address::from_bytes(&[2; ADDRESS_SZ]),
+            vec![0],
+        )
+        .unwrap();
+        let (_, expected) = tw.finish(&mut expected_chunks).unwrap();
+        assert_eq!(result, expected);
+    }
+
     #[test]
     fn test_write_no_level() {
         let mut chunks = HashMap::<Address, Vec<u8>>::new();
@@ -406,4 +624,126 @@ mod tests {
         assert_eq!(count, 6);
         assert_eq!(leaf_count, 3);
     }
+
+    #[test]
+    fn test_dump_tree() {
+        let mut chunks = HashMap::<Address, Vec<u8>>::new();
+        let mut tw = TreeWriter::new(MINIMUM_ADDR_CHUNK_SIZE, 0xffffffff);
+        for i in 1..4 {
+            tw.add(&mut chunks, &Address::from_bytes(&[i; ADDRESS_SZ]), vec![i])
+                .unwrap();
+        }
+        let (height, addr) = tw.finish(&mut chunks).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(dump_tree(&mut chunks, height, &addr, &mut out).unwrap(), 0);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], format!("height=2 address={} children=2", addr));
+        assert!(lines[1].starts_with("  height=1 "));
+        assert!(lines[1].ends_with(" children=2"));
+        assert!(lines[2].starts_with("    height=0 "));
+
+        // Damage one of the tree blocks.
+        let level1_addr = lines[1]
+            .split(' ')
+            .find(|f| f.starts_with("address="))
+            .unwrap();
+        let level1_addr = Address::from_hex_str(&level1_addr["address=".len()..]).unwrap();
+        chunks.get_mut(&level1_addr).unwrap()[0] ^= 1;
+        let mut out = Vec::new();
+        assert_eq!(dump_tree(&mut chunks, height, &addr, &mut out).unwrap(), 1);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 4);
+        assert!(out.lines().nth(1).unwrap().contains("error="));
+    }
+
+    // Walk a tree the way the server does for a partial fetch, returning
+    // the indexes of the data chunks that would be sent.
+    fn walk_ranges(
+        chunks: &HashMap<Address, Vec<u8>>,
+        height: usize,
+        addr: &Address,
+        ranges: Vec<index::HTreeDataRange>,
+    ) -> Result<Vec<u64>, failure::Error> {
+        let mut tr = TreeReader::new(height, addr);
+        let mut filter = DataRangeFilter::new(ranges, height, addr);
+        let mut sent = Vec::new();
+        while let Some((height, addr)) = tr.next_addr()? {
+            if height == 0 {
+                sent.push(filter.next_data_chunk().unwrap());
+            } else if height == 1 {
+                tr.push_level(0, filter.filter_level(chunks.get(&addr).unwrap()))?;
+            } else if !filter.done() {
+                tr.push_level(height - 1, chunks.get(&addr).unwrap().clone())?;
+            }
+        }
+        filter.finish()?;
+        Ok(sent)
+    }
+
+    #[test]
+    fn test_data_range_filter_whole_tree() {
+        let mut chunks = HashMap::<Address, Vec<u8>>::new();
+        let mut tw = TreeWriter::new(MINIMUM_ADDR_CHUNK_SIZE, 0xffffffff);
+        for i in 0..10 {
+            tw.add(&mut chunks, &Address::from_bytes(&[i; ADDRESS_SZ]), vec![i])
+                .unwrap();
+        }
+        let (height, addr) = tw.finish(&mut chunks).unwrap();
+        assert!(height > 1);
+
+        let whole = vec![index::HTreeDataRange {
+            start_idx: 0,
+            end_idx: 9,
+        }];
+        assert_eq!(
+            walk_ranges(&chunks, height, &addr, whole).unwrap(),
+            (0..10).collect::<Vec<u64>>()
+        );
+
+        let some = vec![
+            index::HTreeDataRange {
+                start_idx: 1,
+                end_idx: 2,
+            },
+            index::HTreeDataRange {
+                start_idx: 7,
+                end_idx: 9,
+            },
+        ];
+        assert_eq!(
+            walk_ranges(&chunks, height, &addr, some).unwrap(),
+            vec![1, 2, 7, 8, 9]
+        );
+
+        let past_end = vec![index::HTreeDataRange {
+            start_idx: 0,
+            end_idx: 10,
+        }];
+        assert!(walk_ranges(&chunks, height, &addr, past_end).is_err());
+    }
+
+    #[test]
+    fn test_data_range_filter_single_chunk() {
+        let mut chunks = HashMap::<Address, Vec<u8>>::new();
+        let mut tw = TreeWriter::new(MINIMUM_ADDR_CHUNK_SIZE, 0xffffffff);
+        tw.add(&mut chunks, &Address::from_bytes(&[1; ADDRESS_SZ]), vec![1])
+            .unwrap();
+        let (height, addr) = tw.finish(&mut chunks).unwrap();
+        assert_eq!(height, 0);
+
+        let whole = vec![index::HTreeDataRange {
+            start_idx: 0,
+            end_idx: 0,
+        }];
+        assert_eq!(walk_ranges(&chunks, height, &addr, whole).unwrap(), vec![0]);
+
+        let past_end = vec![index::HTreeDataRange {
+            start_idx: 0,
+            end_idx: 1,
+        }];
+        assert!(walk_ranges(&chunks, height, &addr, past_end).is_err());
+    }
 }
@@ -1,12 +1,41 @@
 use serde::{Deserialize, Serialize};
 
 #[non_exhaustive]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum VersionedIndexEntry {
     V1(IndexEntry),
+    DirectoryRollupV1(DirectoryRollup),
+    UnchangedV1(UnchangedEntry),
+    // Entries before this marker form the base index that
+    // following BaseRangeV1 entries copy from, see IndexBuilder.
+    DeltaStartV1,
+    BaseRangeV1(BaseRange),
+    AclsV1(EntryAcls),
+    SparseV1(SparseHoles),
+    // Written in place of V1 entries by newer versions of put.
+    V2(IndexEntryV2),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+impl VersionedIndexEntry {
+    // The directory entry, for both V1 and V2 entries.
+    pub fn index_entry(&self) -> Option<&IndexEntry> {
+        match self {
+            VersionedIndexEntry::V1(ent) => Some(ent),
+            VersionedIndexEntry::V2(ent) => Some(&ent.entry),
+            _ => None,
+        }
+    }
+
+    pub fn index_entry_mut(&mut self) -> Option<&mut IndexEntry> {
+        match self {
+            VersionedIndexEntry::V1(ent) => Some(ent),
+            VersionedIndexEntry::V2(ent) => Some(&mut ent.entry),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum IndexEntryKind {
     Other,
     Regular,
@@ -15,9 +44,10 @@ pub enum IndexEntryKind {
     Block,
     Directory,
     Fifo,
+    Socket,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct IndexEntry {
     pub path: String,
     pub mode: serde_bare::Uint,
@@ -44,10 +74,31 @@ impl IndexEntry {
             libc::S_IFBLK => IndexEntryKind::Block,
             libc::S_IFDIR => IndexEntryKind::Directory,
             libc::S_IFIFO => IndexEntryKind::Fifo,
+            libc::S_IFSOCK => IndexEntryKind::Socket,
             _ => IndexEntryKind::Other,
         }
     }
 
+    // The number of directories between this entry and the root of the snapshot.
+    pub fn depth(&self) -> usize {
+        if self.path == "." {
+            0
+        } else {
+            self.path.matches('/').count() + 1
+        }
+    }
+
+    pub fn parent_path(&self) -> Option<&str> {
+        if self.path == "." {
+            None
+        } else {
+            match self.path.rfind('/') {
+                Some(idx) => Some(&self.path[..idx]),
+                None => Some("."),
+            }
+        }
+    }
+
     pub fn display_mode(&self) -> String {
         let mode = self.mode.0 as libc::mode_t;
 
@@ -61,6 +112,7 @@ impl IndexEntry {
             IndexEntryKind::Block => 'b',
             IndexEntryKind::Directory => 'd',
             IndexEntryKind::Fifo => 'p',
+            IndexEntryKind::Socket => 's',
         });
         result.push(if (mode & libc::S_IRUSR) != 0 {
             'r'
@@ -132,6 +184,594 @@ impl IndexEntry {
     }
 }
 
+// An entry with the rest of its metadata, so listings can show
+// what is in a snapshot without reading the tarball.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexEntryV2 {
+    pub entry: IndexEntry,
+    pub stat: EntryStat,
+    // None for deterministic sends, which record no owners.
+    pub owner_names: Option<EntryOwnerNames>,
+    // A keyed hash of the contents of a regular file. Files whose data was
+    // only partly read, see put --tail-deltas, have none.
+    pub content_hash: Option<Vec<u8>>,
+}
+
+impl IndexEntryV2 {
+    pub fn owner(&self) -> Option<EntryOwner> {
+        self.owner_names.as_ref().map(|names| EntryOwner {
+            path: self.entry.path.clone(),
+            uid: self.stat.uid,
+            gid: self.stat.gid,
+            user: names.user.clone(),
+            group: names.group.clone(),
+        })
+    }
+}
+
+// The names of the owner of an entry, they let restore map owners
+// to the ids of the same users on another machine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EntryOwnerNames {
+    // None if the id had no name on the sending machine.
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EntryStat {
+    pub kind: IndexEntryKind,
+    pub uid: serde_bare::Uint,
+    pub gid: serde_bare::Uint,
+    pub mtime: serde_bare::Uint,
+    pub mtime_nsec: serde_bare::Uint,
+    pub nlink: serde_bare::Uint,
+    // Only set for symlinks.
+    pub link_target: Option<String>,
+}
+
+impl IndexEntryKind {
+    // The type of an entry as shown by 'list-contents --format=jsonl'.
+    pub fn name(&self) -> &'static str {
+        match self {
+            IndexEntryKind::Other => "other",
+            IndexEntryKind::Regular => "file",
+            IndexEntryKind::Symlink => "symlink",
+            IndexEntryKind::Char => "char",
+            IndexEntryKind::Block => "block",
+            IndexEntryKind::Directory => "dir",
+            IndexEntryKind::Fifo => "fifo",
+            IndexEntryKind::Socket => "socket",
+        }
+    }
+}
+
+// A file that was skipped by 'put --changed-since', it is recorded
+// in the index but has no data in the tarball.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnchangedEntry {
+    pub path: String,
+    pub mode: serde_bare::Uint,
+    pub size: serde_bare::Uint,
+    pub ctime: serde_bare::Uint,
+    pub ctime_nsec: serde_bare::Uint,
+}
+
+impl UnchangedEntry {
+    // An index entry for display purposes, it does not refer to any data.
+    pub fn to_index_entry(&self) -> IndexEntry {
+        IndexEntry {
+            path: self.path.clone(),
+            mode: self.mode,
+            size: self.size,
+            tar_size: serde_bare::Uint(0),
+            ctime: self.ctime,
+            ctime_nsec: self.ctime_nsec,
+            data_chunk_idx: serde_bare::Uint(0),
+            data_chunk_content_idx: serde_bare::Uint(0),
+            data_chunk_content_end_idx: serde_bare::Uint(0),
+            data_chunk_end_idx: serde_bare::Uint(0),
+            data_chunk_offset: serde_bare::Uint(0),
+            data_chunk_content_offset: serde_bare::Uint(0),
+            data_chunk_content_end_offset: serde_bare::Uint(0),
+            data_chunk_end_offset: serde_bare::Uint(0),
+        }
+    }
+}
+
+// The POSIX ACLs of an entry sent with 'put --acls', written after
+// the entry itself and only for entries with ACLs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EntryAcls {
+    pub path: String,
+    pub access: Option<String>,
+    pub default: Option<String>,
+}
+
+// The owner of an entry, from the stat and owner names of a V2 entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryOwner {
+    pub path: String,
+    pub uid: serde_bare::Uint,
+    pub gid: serde_bare::Uint,
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+impl EntryOwner {
+    // The owner as shown by 'list-contents', ids without a name are shown as numbers.
+    pub fn display_owner(&self) -> String {
+        format!(
+            "{}/{}",
+            self.user.clone().unwrap_or_else(|| self.uid.0.to_string()),
+            self.group.clone().unwrap_or_else(|| self.gid.0.to_string())
+        )
+    }
+}
+
+// The holes of a sparse file, written after the entry itself. Holes are
+// read as zeros in the tar stream, restore seeks over them instead of writing them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SparseHoles {
+    pub path: String,
+    pub holes: Vec<HoleRange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HoleRange {
+    pub offset: serde_bare::Uint,
+    pub len: serde_bare::Uint,
+}
+
+// Per directory totals, these are appended to the index stream
+// once the whole directory tree has been sent so that listings
+// can report the size of a directory without summing every child.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DirectoryRollup {
+    pub path: String,
+    // Number of entries contained in the directory, recursively.
+    pub entry_count: serde_bare::Uint,
+    // Sum of the sizes of all entries contained in the directory, recursively.
+    pub total_size: serde_bare::Uint,
+}
+
+#[derive(Default)]
+pub struct DirectoryRollupBuilder {
+    rollups: std::collections::BTreeMap<String, DirectoryRollup>,
+}
+
+impl DirectoryRollupBuilder {
+    pub fn new() -> Self {
+        DirectoryRollupBuilder::default()
+    }
+
+    fn rollup_mut(&mut self, path: &str) -> &mut DirectoryRollup {
+        self.rollups
+            .entry(path.to_string())
+            .or_insert_with(|| DirectoryRollup {
+                path: path.to_string(),
+                entry_count: serde_bare::Uint(0),
+                total_size: serde_bare::Uint(0),
+            })
+    }
+
+    pub fn add_entry(&mut self, ent: &IndexEntry) {
+        if let IndexEntryKind::Directory = ent.kind() {
+            self.rollup_mut(&ent.path);
+        }
+
+        if ent.path == "." {
+            return;
+        }
+
+        let mut path: &str = &ent.path;
+        loop {
+            let parent = match path.rfind('/') {
+                Some(idx) => &path[..idx],
+                None => ".",
+            };
+            let rollup = self.rollup_mut(parent);
+            rollup.entry_count.0 += 1;
+            rollup.total_size.0 += ent.size.0;
+            if parent == "." {
+                break;
+            }
+            path = parent;
+        }
+    }
+
+    pub fn finish(self) -> Vec<DirectoryRollup> {
+        self.rollups.into_values().collect()
+    }
+}
+
+// A run of count entries copied from the base index starting at start,
+// with data_chunk_offset added to the data chunk indices of each entry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BaseRange {
+    pub start: serde_bare::Uint,
+    pub count: serde_bare::Uint,
+    pub data_chunk_offset: serde_bare::Int,
+}
+
+impl IndexEntry {
+    fn with_data_chunk_offset(&self, offset: i64) -> Option<IndexEntry> {
+        let shift = |idx: serde_bare::Uint| -> Option<serde_bare::Uint> {
+            let idx = (idx.0 as i64).checked_add(offset)?;
+            if idx < 0 {
+                None
+            } else {
+                Some(serde_bare::Uint(idx as u64))
+            }
+        };
+        let mut ent = self.clone();
+        ent.data_chunk_idx = shift(self.data_chunk_idx)?;
+        ent.data_chunk_content_idx = shift(self.data_chunk_content_idx)?;
+        ent.data_chunk_content_end_idx = shift(self.data_chunk_content_end_idx)?;
+        ent.data_chunk_end_idx = shift(self.data_chunk_end_idx)?;
+        Some(ent)
+    }
+}
+
+// Rebuilds an index from a stream of entries, expanding delta encoded sections.
+#[derive(Default)]
+pub struct IndexBuilder {
+    pub index: Vec<VersionedIndexEntry>,
+    base: Vec<VersionedIndexEntry>,
+    // The number of delta sections seen.
+    pub delta_depth: usize,
+}
+
+impl IndexBuilder {
+    pub fn push(&mut self, ent: VersionedIndexEntry) -> Result<(), failure::Error> {
+        match ent {
+            VersionedIndexEntry::DeltaStartV1 => {
+                self.base = std::mem::take(&mut self.index);
+                self.delta_depth += 1;
+            }
+            VersionedIndexEntry::BaseRangeV1(range) => {
+                let start = range.start.0 as usize;
+                let end = match start.checked_add(range.count.0 as usize) {
+                    Some(end) if end <= self.base.len() => end,
+                    _ => failure::bail!("index base range is out of bounds, index is corrupt"),
+                };
+                for ent in self.base[start..end].iter() {
+                    let mut ent = ent.clone();
+                    if let Some(index_ent) = ent.index_entry_mut() {
+                        match index_ent.with_data_chunk_offset(range.data_chunk_offset.0) {
+                            Some(shifted) => *index_ent = shifted,
+                            None => failure::bail!(
+                                "index base range has an invalid offset, index is corrupt"
+                            ),
+                        }
+                    }
+                    self.index.push(ent);
+                }
+            }
+            ent => self.index.push(ent),
+        }
+        Ok(())
+    }
+}
+
+// Encodes index entries as runs of entries copied from a base index where possible,
+// the output is written after the entries of the base index and a DeltaStartV1.
+pub struct IndexDeltaEncoder<'a> {
+    base: &'a [VersionedIndexEntry],
+    positions: std::collections::HashMap<(u8, &'a str), usize>,
+    // A range being extended, with an offset of None matching any offset.
+    pending: Option<(usize, usize, Option<i64>)>,
+}
+
+fn delta_key(ent: &VersionedIndexEntry) -> Option<(u8, &str)> {
+    match ent {
+        VersionedIndexEntry::V1(ent) => Some((0, &ent.path)),
+        VersionedIndexEntry::DirectoryRollupV1(rollup) => Some((1, &rollup.path)),
+        VersionedIndexEntry::UnchangedV1(ent) => Some((2, &ent.path)),
+        VersionedIndexEntry::AclsV1(acls) => Some((3, &acls.path)),
+        VersionedIndexEntry::SparseV1(sparse) => Some((4, &sparse.path)),
+        VersionedIndexEntry::V2(ent) => Some((6, &ent.entry.path)),
+        _ => None,
+    }
+}
+
+// Whether ent is base with some data chunk offset applied, returning
+// Some(None) if the entry has no data chunk indices.
+fn delta_offset(base: &VersionedIndexEntry, ent: &VersionedIndexEntry) -> Option<Option<i64>> {
+    let entry_offset = |base: &IndexEntry, ent: &IndexEntry| {
+        let offset = (ent.data_chunk_idx.0 as i64).checked_sub(base.data_chunk_idx.0 as i64)?;
+        if base.with_data_chunk_offset(offset).as_ref() == Some(ent) {
+            Some(Some(offset))
+        } else {
+            None
+        }
+    };
+    match (base, ent) {
+        (VersionedIndexEntry::V1(base), VersionedIndexEntry::V1(ent)) => entry_offset(base, ent),
+        (VersionedIndexEntry::V2(base), VersionedIndexEntry::V2(ent)) if base.stat == ent.stat => {
+            entry_offset(&base.entry, &ent.entry)
+        }
+        (base, ent) if base == ent => Some(None),
+        _ => None,
+    }
+}
+
+impl<'a> IndexDeltaEncoder<'a> {
+    pub fn new(base: &'a [VersionedIndexEntry]) -> Self {
+        let mut positions = std::collections::HashMap::new();
+        for (i, ent) in base.iter().enumerate() {
+            if let Some(key) = delta_key(ent) {
+                positions.insert(key, i);
+            }
+        }
+        IndexDeltaEncoder {
+            base,
+            positions,
+            pending: None,
+        }
+    }
+
+    fn take_pending(&mut self) -> Option<VersionedIndexEntry> {
+        self.pending.take().map(|(start, count, offset)| {
+            VersionedIndexEntry::BaseRangeV1(BaseRange {
+                start: serde_bare::Uint(start as u64),
+                count: serde_bare::Uint(count as u64),
+                data_chunk_offset: serde_bare::Int(offset.unwrap_or(0)),
+            })
+        })
+    }
+
+    // Returns the entries to write in place of ent, which may be none
+    // while a range of base entries is being extended.
+    pub fn add(&mut self, ent: VersionedIndexEntry) -> Vec<VersionedIndexEntry> {
+        if let Some((start, ref mut count, ref mut offset)) = self.pending {
+            if let Some(base) = self.base.get(start + *count) {
+                match (delta_offset(base, &ent), *offset) {
+                    (Some(None), _) => {
+                        *count += 1;
+                        return vec![];
+                    }
+                    (Some(Some(ent_offset)), None) => {
+                        *count += 1;
+                        *offset = Some(ent_offset);
+                        return vec![];
+                    }
+                    (Some(Some(ent_offset)), Some(range_offset)) if ent_offset == range_offset => {
+                        *count += 1;
+                        return vec![];
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let mut out: Vec<VersionedIndexEntry> = self.take_pending().into_iter().collect();
+        let base_pos = delta_key(&ent).and_then(|key| self.positions.get(&key).copied());
+        match base_pos.and_then(|pos| delta_offset(&self.base[pos], &ent).map(|o| (pos, o))) {
+            Some((pos, offset)) => self.pending = Some((pos, 1, offset)),
+            None => out.push(ent),
+        }
+        out
+    }
+
+    pub fn finish(&mut self) -> Option<VersionedIndexEntry> {
+        self.take_pending()
+    }
+}
+
+pub struct SplitIndex {
+    pub entries: Vec<IndexEntry>,
+    pub rollups: std::collections::HashMap<String, DirectoryRollup>,
+    // Paths of entries in 'entries' that were skipped as unchanged.
+    pub unchanged: std::collections::HashSet<String>,
+    pub acls: std::collections::HashMap<String, EntryAcls>,
+    pub owners: std::collections::HashMap<String, EntryOwner>,
+    // Only entries sent as V2 entries have their stat recorded.
+    pub stats: std::collections::HashMap<String, EntryStat>,
+    pub content_hashes: std::collections::HashMap<String, Vec<u8>>,
+}
+
+// Split an index into its entries and a lookup table of directory rollups,
+// unchanged entries are included in the entries for display.
+pub fn split_index(index: Vec<VersionedIndexEntry>) -> SplitIndex {
+    let mut entries = Vec::with_capacity(index.len());
+    let mut rollups = std::collections::HashMap::new();
+    let mut unchanged = std::collections::HashSet::new();
+    let mut acls = std::collections::HashMap::new();
+    let mut owners = std::collections::HashMap::new();
+    let mut stats = std::collections::HashMap::new();
+    let mut content_hashes = std::collections::HashMap::new();
+    for ent in index.into_iter() {
+        match ent {
+            VersionedIndexEntry::V1(ent) => entries.push(ent),
+            VersionedIndexEntry::V2(ent) => {
+                if let Some(owner) = ent.owner() {
+                    owners.insert(ent.entry.path.clone(), owner);
+                }
+                if let Some(hash) = ent.content_hash {
+                    content_hashes.insert(ent.entry.path.clone(), hash);
+                }
+                stats.insert(ent.entry.path.clone(), ent.stat);
+                entries.push(ent.entry);
+            }
+            VersionedIndexEntry::DirectoryRollupV1(rollup) => {
+                rollups.insert(rollup.path.clone(), rollup);
+            }
+            VersionedIndexEntry::UnchangedV1(ent) => {
+                unchanged.insert(ent.path.clone());
+                entries.push(ent.to_index_entry());
+            }
+            VersionedIndexEntry::AclsV1(ent) => {
+                acls.insert(ent.path.clone(), ent);
+            }
+            // Only used by restore.
+            VersionedIndexEntry::SparseV1(_) => (),
+            // Expanded by IndexBuilder.
+            VersionedIndexEntry::DeltaStartV1 | VersionedIndexEntry::BaseRangeV1(_) => (),
+        }
+    }
+    SplitIndex {
+        entries,
+        rollups,
+        unchanged,
+        acls,
+        owners,
+        stats,
+        content_hashes,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+// An entry that differs between two indexes, see diff_indexes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryDiff {
+    pub kind: DiffKind,
+    pub path: String,
+    // None for added entries.
+    pub old: Option<DiffSide>,
+    // None for removed entries.
+    pub new: Option<DiffSide>,
+    // None unless both entries are files with content hashes.
+    pub content_changed: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffSide {
+    pub entry: IndexEntry,
+    pub stat: Option<EntryStat>,
+    pub content_hash: Option<Vec<u8>>,
+}
+
+// The modification times of both sides. Entries sent by older versions of bupstash, and
+// unchanged entries, only have the change time, which is then compared on both sides.
+fn compared_times(old: &DiffSide, new: &DiffSide) -> ((u64, u64), (u64, u64)) {
+    match (&old.stat, &new.stat) {
+        (Some(old_stat), Some(new_stat)) => (
+            (old_stat.mtime.0, old_stat.mtime_nsec.0),
+            (new_stat.mtime.0, new_stat.mtime_nsec.0),
+        ),
+        _ => (
+            (old.entry.ctime.0, old.entry.ctime_nsec.0),
+            (new.entry.ctime.0, new.entry.ctime_nsec.0),
+        ),
+    }
+}
+
+impl EntryDiff {
+    // The change in size in bytes, new minus old.
+    pub fn size_delta(&self) -> i64 {
+        let size = |side: &Option<DiffSide>| side.as_ref().map(|s| s.entry.size.0).unwrap_or(0);
+        size(&self.new) as i64 - size(&self.old) as i64
+    }
+
+    // The change in modification time in seconds, new minus old,
+    // or in change time if either side lacks the modification time.
+    pub fn mtime_delta(&self) -> Option<i64> {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => {
+                let (old_time, new_time) = compared_times(old, new);
+                Some(new_time.0 as i64 - old_time.0 as i64)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn diff_sides(index: Vec<VersionedIndexEntry>) -> std::collections::BTreeMap<String, DiffSide> {
+    let mut split = split_index(index);
+    let mut sides = std::collections::BTreeMap::new();
+    for entry in split.entries.into_iter() {
+        let stat = split.stats.remove(&entry.path);
+        let content_hash = split.content_hashes.remove(&entry.path);
+        sides.insert(
+            entry.path.clone(),
+            DiffSide {
+                entry,
+                stat,
+                content_hash,
+            },
+        );
+    }
+    sides
+}
+
+fn sides_differ(old: &DiffSide, new: &DiffSide, content_changed: Option<bool>) -> bool {
+    if old.entry.mode != new.entry.mode || content_changed == Some(true) {
+        return true;
+    }
+    if let (Some(old_stat), Some(new_stat)) = (&old.stat, &new.stat) {
+        if old_stat.uid != new_stat.uid
+            || old_stat.gid != new_stat.gid
+            || old_stat.link_target != new_stat.link_target
+        {
+            return true;
+        }
+    }
+    match old.entry.kind() {
+        // Directory times change with their contents, which are compared themselves.
+        IndexEntryKind::Directory => false,
+        _ => {
+            let (old_time, new_time) = compared_times(old, new);
+            old.entry.size != new.entry.size || old_time != new_time
+        }
+    }
+}
+
+// Compare the entries of two indexes by path, in path order. Entries are changed
+// when their type, permissions, owner, size, modification time, symlink target
+// or content hash differ.
+pub fn diff_indexes(
+    old: Vec<VersionedIndexEntry>,
+    new: Vec<VersionedIndexEntry>,
+) -> Vec<EntryDiff> {
+    let mut old = diff_sides(old);
+    let mut diffs = Vec::new();
+    for (path, new) in diff_sides(new).into_iter() {
+        match old.remove(&path) {
+            Some(old) => {
+                let content_changed = match (&old.content_hash, &new.content_hash) {
+                    (Some(old_hash), Some(new_hash)) => Some(old_hash != new_hash),
+                    _ => None,
+                };
+                if sides_differ(&old, &new, content_changed) {
+                    diffs.push(EntryDiff {
+                        kind: DiffKind::Changed,
+                        path,
+                        old: Some(old),
+                        new: Some(new),
+                        content_changed,
+                    });
+                }
+            }
+            None => diffs.push(EntryDiff {
+                kind: DiffKind::Added,
+                path,
+                old: None,
+                new: Some(new),
+                content_changed: None,
+            }),
+        }
+    }
+    for (path, old) in old.into_iter() {
+        diffs.push(EntryDiff {
+            kind: DiffKind::Removed,
+            path,
+            old: Some(old),
+            new: None,
+            content_changed: None,
+        });
+    }
+    // Sorting by component keeps the entries of a directory together.
+    diffs.sort_by(|a, b| a.path.split('/').cmp(b.path.split('/')));
+    diffs
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct HTreeDataRange {
     pub start_idx: u64,
@@ -139,15 +779,203 @@ pub struct HTreeDataRange {
 }
 
 pub struct PickMap {
+    pub path: String,
     pub is_subtar: bool,
     pub size: u64,
     pub data_chunk_ranges: Vec<HTreeDataRange>,
     pub incomplete_data_chunks: std::collections::HashMap<u64, rangemap::RangeSet<usize>>,
 }
 
+// Ranges separated by at most this many unwanted data chunks are merged,
+// fetching a few extra chunks costs less than fragmenting the request.
+const PICK_RANGE_MERGE_GAP: u64 = 2;
+
+// Sort and merge ranges so they are fetched in one pass over the tree, the chunks
+// fetched only to fill a gap between ranges have nothing to output.
+fn coalesce_ranges(
+    mut ranges: Vec<HTreeDataRange>,
+    incomplete_data_chunks: &mut std::collections::HashMap<u64, rangemap::RangeSet<usize>>,
+) -> Vec<HTreeDataRange> {
+    ranges.sort_by_key(|r| r.start_idx);
+    let mut coalesced: Vec<HTreeDataRange> = Vec::with_capacity(ranges.len());
+    for r in ranges.into_iter() {
+        match coalesced.last_mut() {
+            Some(last) if r.start_idx <= last.end_idx + 1 + PICK_RANGE_MERGE_GAP => {
+                for idx in last.end_idx + 1..r.start_idx {
+                    incomplete_data_chunks.insert(idx, rangemap::RangeSet::new());
+                }
+                last.end_idx = std::cmp::max(last.end_idx, r.end_idx);
+            }
+            _ => coalesced.push(r),
+        }
+    }
+    coalesced
+}
+
+// Mark part of a data chunk as wanted. Empty ranges, such as an entry ending on a
+// chunk boundary, are not accepted by rangemap, the chunk is still marked incomplete
+// so none of it is output.
+fn insert_incomplete_range(
+    incomplete_data_chunks: &mut std::collections::HashMap<u64, rangemap::RangeSet<usize>>,
+    idx: u64,
+    range: std::ops::Range<usize>,
+) {
+    let ranges = incomplete_data_chunks.entry(idx).or_default();
+    if !range.is_empty() {
+        ranges.insert(range);
+    }
+}
+
+// Add the data chunks holding the tar entry of ent to a pick.
+fn push_entry_ranges(
+    ent: &IndexEntry,
+    data_chunk_ranges: &mut Vec<HTreeDataRange>,
+    incomplete_data_chunks: &mut std::collections::HashMap<u64, rangemap::RangeSet<usize>>,
+) {
+    data_chunk_ranges.push(HTreeDataRange {
+        start_idx: ent.data_chunk_idx.0,
+        end_idx: ent.data_chunk_end_idx.0,
+    });
+
+    if ent.data_chunk_idx == ent.data_chunk_end_idx {
+        insert_incomplete_range(
+            incomplete_data_chunks,
+            ent.data_chunk_idx.0,
+            ent.data_chunk_offset.0 as usize..ent.data_chunk_end_offset.0 as usize,
+        );
+    } else {
+        insert_incomplete_range(
+            incomplete_data_chunks,
+            ent.data_chunk_idx.0,
+            ent.data_chunk_offset.0 as usize..usize::MAX,
+        );
+        insert_incomplete_range(
+            incomplete_data_chunks,
+            ent.data_chunk_end_idx.0,
+            0..ent.data_chunk_end_offset.0 as usize,
+        );
+    }
+}
+
+// Pick the tar entries of the snapshot that wanted returns true for, as a subtar
+// of the whole snapshot. Used for glob picks, and by 'restore --incremental' to fetch
+// changed entries only.
+pub fn pick_entries(
+    index: &[VersionedIndexEntry],
+    wanted: &dyn Fn(&IndexEntry) -> bool,
+) -> PickMap {
+    let mut size = 0;
+    let mut data_chunk_ranges = Vec::new();
+    let mut incomplete_data_chunks = std::collections::HashMap::new();
+
+    for ent in index.iter() {
+        let ent = match ent.index_entry() {
+            Some(ent) => ent,
+            None => continue,
+        };
+        if !wanted(ent) {
+            continue;
+        }
+        size += ent.tar_size.0;
+        push_entry_ranges(ent, &mut data_chunk_ranges, &mut incomplete_data_chunks);
+    }
+
+    let data_chunk_ranges = coalesce_ranges(data_chunk_ranges, &mut incomplete_data_chunks);
+
+    PickMap {
+        path: ".".to_string(),
+        is_subtar: true,
+        size,
+        data_chunk_ranges,
+        incomplete_data_chunks,
+    }
+}
+
+// Pick several paths at once, each a glob pattern matched against the paths shown by
+// list-contents. A lone plain path is picked as with pick, so a single file is output as
+// its contents, otherwise the pick is a tarball of every matching entry and the children
+// of matching directories, in their stored order.
+pub fn pick_many(
+    patterns: &[String],
+    index: &[VersionedIndexEntry],
+) -> Result<PickMap, failure::Error> {
+    if let [path] = patterns {
+        if glob::Pattern::escape(path) == *path {
+            return pick(path, index);
+        }
+    }
+
+    let mut globs = Vec::with_capacity(patterns.len());
+    for pattern in patterns.iter() {
+        match glob::Pattern::new(pattern) {
+            Ok(glob) => globs.push(glob),
+            Err(err) => failure::bail!("invalid pick pattern '{}': {}", pattern, err),
+        }
+    }
+
+    let mut matched = vec![false; globs.len()];
+    let mut picked = std::collections::HashSet::new();
+    // The children of picked directories are picked too, a directory comes before its children.
+    let mut picked_dirs: Vec<String> = Vec::new();
+    for ent in index.iter() {
+        let ent = match ent.index_entry() {
+            Some(ent) => ent,
+            None => continue,
+        };
+        let mut is_picked = picked_dirs
+            .iter()
+            .any(|prefix| ent.path.starts_with(prefix.as_str()));
+        for (i, glob) in globs.iter().enumerate() {
+            if glob.matches(&ent.path) {
+                matched[i] = true;
+                is_picked = true;
+            }
+        }
+        if !is_picked {
+            continue;
+        }
+        if ent.kind() == IndexEntryKind::Directory {
+            picked_dirs.push(if ent.path == "." {
+                "".to_string()
+            } else {
+                format!("{}/", ent.path)
+            });
+        }
+        picked.insert(ent.path.as_str());
+    }
+
+    for (pattern, matched) in patterns.iter().zip(matched.iter()) {
+        if !matched {
+            failure::bail!("pick pattern '{}' does not match any entries", pattern);
+        }
+    }
+
+    Ok(pick_entries(index, &|ent| {
+        picked.contains(ent.path.as_str())
+    }))
+}
+
+impl PickMap {
+    // Whether the entry at path is part of the picked data.
+    pub fn includes(&self, path: &str) -> bool {
+        if self.path == path {
+            return true;
+        }
+        self.is_subtar && (self.path == "." || path.starts_with(&format!("{}/", self.path)))
+    }
+}
+
 pub fn pick(path: &str, index: &[VersionedIndexEntry]) -> Result<PickMap, failure::Error> {
     for i in 0..index.len() {
-        let VersionedIndexEntry::V1(ent) = &index[i];
+        let ent = match &index[i] {
+            VersionedIndexEntry::V1(ent) => ent,
+            VersionedIndexEntry::V2(ent) => &ent.entry,
+            VersionedIndexEntry::UnchangedV1(ent) if ent.path == path => failure::bail!(
+                "{} was unchanged when this item was sent, its data is not stored in this item",
+                path
+            ),
+            _ => continue,
+        };
 
         if ent.path != path {
             continue;
@@ -168,71 +996,26 @@ pub fn pick(path: &str, index: &[VersionedIndexEntry]) -> Result<PickMap, failur
                     rangemap::RangeSet<usize>,
                 > = std::collections::HashMap::new();
 
-                for (j, VersionedIndexEntry::V1(ref ent)) in index.iter().enumerate().skip(i) {
+                for (j, ent) in index.iter().enumerate().skip(i) {
+                    let ent = match ent.index_entry() {
+                        Some(ent) => ent,
+                        None => continue,
+                    };
+
                     // Match the directory and its children.
                     if !(j == i || ent.path.starts_with(&prefix)) {
                         continue;
                     }
 
                     size += ent.tar_size.0;
-
-                    // Either coalesce the existing range or insert a new range.
-                    if !data_chunk_ranges.is_empty()
-                        && ((data_chunk_ranges.last().unwrap().end_idx == ent.data_chunk_idx.0)
-                            || (data_chunk_ranges.last().unwrap().end_idx + 1
-                                == ent.data_chunk_idx.0))
-                    {
-                        data_chunk_ranges.last_mut().unwrap().end_idx = ent.data_chunk_end_idx.0
-                    } else {
-                        data_chunk_ranges.push(HTreeDataRange {
-                            start_idx: ent.data_chunk_idx.0,
-                            end_idx: ent.data_chunk_end_idx.0,
-                        })
-                    }
-
-                    if ent.data_chunk_idx == ent.data_chunk_end_idx {
-                        let range =
-                            ent.data_chunk_offset.0 as usize..ent.data_chunk_end_offset.0 as usize;
-
-                        match incomplete_data_chunks.get_mut(&ent.data_chunk_idx.0) {
-                            Some(range_set) => {
-                                range_set.insert(range);
-                            }
-                            None => {
-                                let mut range_set = rangemap::RangeSet::new();
-                                range_set.insert(range);
-                                incomplete_data_chunks.insert(ent.data_chunk_idx.0, range_set);
-                            }
-                        }
-                    } else {
-                        let start_range = ent.data_chunk_offset.0 as usize..usize::MAX;
-                        let end_range = 0..ent.data_chunk_end_offset.0 as usize;
-
-                        match incomplete_data_chunks.get_mut(&ent.data_chunk_idx.0) {
-                            Some(range_set) => {
-                                range_set.insert(start_range);
-                            }
-                            None => {
-                                let mut range_set = rangemap::RangeSet::new();
-                                range_set.insert(start_range);
-                                incomplete_data_chunks.insert(ent.data_chunk_idx.0, range_set);
-                            }
-                        }
-
-                        match incomplete_data_chunks.get_mut(&ent.data_chunk_end_idx.0) {
-                            Some(range_set) => {
-                                range_set.insert(end_range);
-                            }
-                            None => {
-                                let mut range_set = rangemap::RangeSet::new();
-                                range_set.insert(end_range);
-                                incomplete_data_chunks.insert(ent.data_chunk_end_idx.0, range_set);
-                            }
-                        }
-                    }
+                    push_entry_ranges(ent, &mut data_chunk_ranges, &mut incomplete_data_chunks);
                 }
 
+                let data_chunk_ranges =
+                    coalesce_ranges(data_chunk_ranges, &mut incomplete_data_chunks);
+
                 return Ok(PickMap {
+                    path: path.to_string(),
                     is_subtar: true,
                     size,
                     data_chunk_ranges,
@@ -243,27 +1026,27 @@ pub fn pick(path: &str, index: &[VersionedIndexEntry]) -> Result<PickMap, failur
                 let mut incomplete_data_chunks = std::collections::HashMap::new();
 
                 if ent.data_chunk_content_idx == ent.data_chunk_content_end_idx {
-                    let mut range_set = rangemap::RangeSet::new();
-
-                    range_set.insert(
+                    insert_incomplete_range(
+                        &mut incomplete_data_chunks,
+                        ent.data_chunk_content_idx.0,
                         ent.data_chunk_content_offset.0 as usize
                             ..ent.data_chunk_content_end_offset.0 as usize,
                     );
-
-                    incomplete_data_chunks.insert(ent.data_chunk_content_idx.0, range_set);
                 } else {
-                    let mut start_range_set = rangemap::RangeSet::new();
-                    start_range_set.insert(ent.data_chunk_content_offset.0 as usize..usize::MAX);
-
-                    incomplete_data_chunks.insert(ent.data_chunk_content_idx.0, start_range_set);
-
-                    let mut end_range_set = rangemap::RangeSet::new();
-                    end_range_set.insert(0..ent.data_chunk_content_end_offset.0 as usize);
-
-                    incomplete_data_chunks.insert(ent.data_chunk_content_end_idx.0, end_range_set);
+                    insert_incomplete_range(
+                        &mut incomplete_data_chunks,
+                        ent.data_chunk_content_idx.0,
+                        ent.data_chunk_content_offset.0 as usize..usize::MAX,
+                    );
+                    insert_incomplete_range(
+                        &mut incomplete_data_chunks,
+                        ent.data_chunk_content_end_idx.0,
+                        0..ent.data_chunk_content_end_offset.0 as usize,
+                    );
                 }
 
                 return Ok(PickMap {
+                    path: path.to_string(),
                     is_subtar: false,
                     size: ent.size.0,
                     data_chunk_ranges: vec![HTreeDataRange {
@@ -283,3 +1066,363 @@ pub fn pick(path: &str, index: &[VersionedIndexEntry]) -> Result<PickMap, failur
 
     failure::bail!("{} not found in content index", path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_entry(path: &str, mode: u32, size: u64) -> IndexEntry {
+        IndexEntry {
+            path: path.to_string(),
+            mode: serde_bare::Uint(mode as u64),
+            size: serde_bare::Uint(size),
+            tar_size: serde_bare::Uint(0),
+            ctime: serde_bare::Uint(0),
+            ctime_nsec: serde_bare::Uint(0),
+            data_chunk_idx: serde_bare::Uint(0),
+            data_chunk_content_idx: serde_bare::Uint(0),
+            data_chunk_content_end_idx: serde_bare::Uint(0),
+            data_chunk_end_idx: serde_bare::Uint(0),
+            data_chunk_offset: serde_bare::Uint(0),
+            data_chunk_content_offset: serde_bare::Uint(0),
+            data_chunk_content_end_offset: serde_bare::Uint(0),
+            data_chunk_end_offset: serde_bare::Uint(0),
+        }
+    }
+
+    #[test]
+    fn test_coalesce_ranges() {
+        let r = |start_idx, end_idx| HTreeDataRange { start_idx, end_idx };
+        let mut incomplete = std::collections::HashMap::new();
+        let ranges = coalesce_ranges(
+            vec![r(9, 12), r(0, 1), r(1, 2), r(5, 6), r(16, 20)],
+            &mut incomplete,
+        );
+        assert_eq!(ranges, vec![r(0, 12), r(16, 20)]);
+        // The gap chunks are fetched but none of their data is output.
+        let mut gap_chunks: Vec<u64> = incomplete.keys().cloned().collect();
+        gap_chunks.sort_unstable();
+        assert_eq!(gap_chunks, vec![3, 4, 7, 8]);
+        assert!(incomplete.values().all(|s| s.iter().next().is_none()));
+    }
+
+    #[test]
+    fn test_pick_entries() {
+        // Three 100 byte tar entries, packed into chunks of 128 bytes.
+        let index: Vec<VersionedIndexEntry> = ["a", "b", "c"]
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let mut ent = test_entry(path, libc::S_IFREG | 0o644, 0);
+                let (start, end) = (i as u64 * 100, i as u64 * 100 + 100);
+                ent.tar_size = serde_bare::Uint(100);
+                ent.data_chunk_idx = serde_bare::Uint(start / 128);
+                ent.data_chunk_offset = serde_bare::Uint(start % 128);
+                ent.data_chunk_end_idx = serde_bare::Uint(end / 128);
+                ent.data_chunk_end_offset = serde_bare::Uint(end % 128);
+                VersionedIndexEntry::V1(ent)
+            })
+            .collect();
+        let pick = pick_entries(&index, &|ent| ent.path != "b");
+        assert!(pick.is_subtar);
+        assert!(pick.includes("b"));
+        assert_eq!(pick.size, 200);
+        assert_eq!(
+            pick.data_chunk_ranges,
+            vec![HTreeDataRange {
+                start_idx: 0,
+                end_idx: 2
+            }]
+        );
+        let chunk = |idx| -> Vec<std::ops::Range<usize>> {
+            pick.incomplete_data_chunks[&idx].iter().cloned().collect()
+        };
+        assert_eq!(chunk(0), vec![0..100]);
+        assert_eq!(chunk(1), vec![72..usize::MAX]);
+        assert_eq!(chunk(2), vec![0..44]);
+    }
+
+    #[test]
+    fn test_pick_many() {
+        let mut offset = 0;
+        let mut index: Vec<VersionedIndexEntry> = [
+            (".", libc::S_IFDIR),
+            ("a", libc::S_IFDIR),
+            ("a/x.txt", libc::S_IFREG),
+            ("a/y.log", libc::S_IFREG),
+            ("b.txt", libc::S_IFREG),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, (path, kind))| {
+            let mut ent = test_entry(path, kind | 0o755, 0);
+            ent.tar_size = serde_bare::Uint(1 << i);
+            ent.data_chunk_offset = serde_bare::Uint(offset);
+            offset += 1 << i;
+            ent.data_chunk_end_offset = serde_bare::Uint(offset);
+            VersionedIndexEntry::V1(ent)
+        })
+        .collect();
+        // An entry with an empty data range, as if it ended on a chunk boundary.
+        let mut empty = test_entry("c.txt", libc::S_IFREG | 0o644, 0);
+        empty.data_chunk_idx = serde_bare::Uint(1);
+        empty.data_chunk_end_idx = serde_bare::Uint(1);
+        index.push(VersionedIndexEntry::V1(empty));
+        let pick = |patterns: &[&str]| {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            pick_many(&patterns, &index)
+        };
+        assert_eq!(pick(&["*.txt"]).unwrap().size, 4 + 16);
+        assert_eq!(pick(&["a/*.txt", "b.txt"]).unwrap().size, 4 + 16);
+        // Matching a directory picks its children.
+        assert_eq!(pick(&["a*"]).unwrap().size, 2 + 4 + 8);
+        assert_eq!(pick(&["a", "b.*"]).unwrap().size, 2 + 4 + 8 + 16);
+        // A lone plain path is an ordinary pick.
+        assert!(!pick(&["b.txt"]).unwrap().is_subtar);
+        assert!(pick(&["b.txt", "a/x.txt"]).unwrap().is_subtar);
+        assert!(pick(&["b.txt", "d*"]).is_err());
+        let empty = pick(&["b.txt", "c*"]).unwrap();
+        assert_eq!(empty.size, 16);
+        assert_eq!(empty.incomplete_data_chunks[&1].iter().count(), 0);
+    }
+
+    #[test]
+    fn test_diff_indexes() {
+        let file = |path: &str, size: u64, ctime: u64| {
+            let mut ent = test_entry(path, libc::S_IFREG | 0o644, size);
+            ent.ctime = serde_bare::Uint(ctime);
+            VersionedIndexEntry::V1(ent)
+        };
+        let hashed = |path: &str, hash: u8| {
+            let mut ent = test_entry(path, libc::S_IFREG | 0o644, 3);
+            ent.ctime = serde_bare::Uint(10);
+            VersionedIndexEntry::V2(IndexEntryV2 {
+                stat: EntryStat {
+                    kind: ent.kind(),
+                    uid: serde_bare::Uint(0),
+                    gid: serde_bare::Uint(0),
+                    mtime: serde_bare::Uint(0),
+                    mtime_nsec: serde_bare::Uint(0),
+                    nlink: serde_bare::Uint(1),
+                    link_target: None,
+                },
+                entry: ent,
+                owner_names: None,
+                content_hash: Some(vec![hash; 4]),
+            })
+        };
+        let mut dir = test_entry(".", libc::S_IFDIR | 0o755, 0);
+        let old = vec![
+            VersionedIndexEntry::V1(dir.clone()),
+            file("a.txt", 3, 10),
+            hashed("b.txt", 1),
+            file("c.txt", 3, 10),
+            hashed("d.txt", 1),
+        ];
+        // Directory times are not compared.
+        dir.ctime = serde_bare::Uint(20);
+        let new = vec![
+            VersionedIndexEntry::V1(dir),
+            file("a.txt", 5, 30),
+            hashed("b.txt", 2),
+            hashed("d.txt", 1),
+            file("e.txt", 1, 10),
+        ];
+        let diffs = diff_indexes(old, new);
+        let summary: Vec<(DiffKind, &str)> = diffs
+            .iter()
+            .map(|diff| (diff.kind, diff.path.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (DiffKind::Changed, "a.txt"),
+                (DiffKind::Changed, "b.txt"),
+                (DiffKind::Removed, "c.txt"),
+                (DiffKind::Added, "e.txt"),
+            ]
+        );
+        assert_eq!(diffs[0].size_delta(), 2);
+        assert_eq!(diffs[0].mtime_delta(), Some(20));
+        assert_eq!(diffs[0].content_changed, None);
+        assert_eq!(diffs[1].content_changed, Some(true));
+        assert_eq!(diffs[2].size_delta(), -3);
+        assert_eq!(diffs[2].mtime_delta(), None);
+
+        // Entries with a stat on one side only compare change times, so items sent
+        // by older versions of bupstash only show files that really changed.
+        let with_stat = |path: &str, mtime: u64| {
+            let mut ent = test_entry(path, libc::S_IFREG | 0o644, 3);
+            ent.ctime = serde_bare::Uint(10);
+            VersionedIndexEntry::V2(IndexEntryV2 {
+                stat: EntryStat {
+                    kind: ent.kind(),
+                    uid: serde_bare::Uint(0),
+                    gid: serde_bare::Uint(0),
+                    mtime: serde_bare::Uint(mtime),
+                    mtime_nsec: serde_bare::Uint(0),
+                    nlink: serde_bare::Uint(1),
+                    link_target: None,
+                },
+                entry: ent,
+                owner_names: None,
+                content_hash: None,
+            })
+        };
+        let old = vec![file("a.txt", 3, 10), file("b.txt", 3, 10)];
+        let new = vec![with_stat("a.txt", 5), with_stat("b.txt", 5)];
+        assert!(diff_indexes(old.clone(), new).is_empty());
+        let new = vec![with_stat("a.txt", 5), file("b.txt", 3, 15)];
+        let diffs = diff_indexes(old, new);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].path, "b.txt");
+        assert_eq!(diffs[0].mtime_delta(), Some(5));
+        // With a stat on both sides the modification times are compared.
+        let old = vec![with_stat("a.txt", 5)];
+        assert!(diff_indexes(old.clone(), vec![with_stat("a.txt", 5)]).is_empty());
+        assert_eq!(
+            diff_indexes(old, vec![with_stat("a.txt", 7)])[0].mtime_delta(),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_display_owner() {
+        let mut owner = EntryOwner {
+            path: "a".to_string(),
+            uid: serde_bare::Uint(1000),
+            gid: serde_bare::Uint(100),
+            user: Some("alice".to_string()),
+            group: None,
+        };
+        assert_eq!(owner.display_owner(), "alice/100");
+        owner.group = Some("users".to_string());
+        assert_eq!(owner.display_owner(), "alice/users");
+    }
+
+    #[test]
+    fn test_directory_rollups() {
+        let mut builder = DirectoryRollupBuilder::new();
+        builder.add_entry(&test_entry(".", libc::S_IFDIR, 0));
+        builder.add_entry(&test_entry("a", libc::S_IFDIR, 0));
+        builder.add_entry(&test_entry("b.txt", libc::S_IFREG, 3));
+        builder.add_entry(&test_entry("a/c", libc::S_IFDIR, 0));
+        builder.add_entry(&test_entry("a/c/d.txt", libc::S_IFREG, 5));
+        let rollups = builder.finish();
+        assert_eq!(rollups.len(), 3);
+        assert_eq!(rollups[0].path, ".");
+        assert_eq!(rollups[0].entry_count.0, 4);
+        assert_eq!(rollups[0].total_size.0, 8);
+        assert_eq!(rollups[1].path, "a");
+        assert_eq!(rollups[1].entry_count.0, 2);
+        assert_eq!(rollups[1].total_size.0, 5);
+        assert_eq!(rollups[2].path, "a/c");
+        assert_eq!(rollups[2].entry_count.0, 1);
+        assert_eq!(rollups[2].total_size.0, 5);
+    }
+
+    #[test]
+    fn test_index_delta() {
+        let mut base = Vec::new();
+        for (i, path) in ["a", "b", "c", "d"].iter().enumerate() {
+            let mut ent = test_entry(path, libc::S_IFREG, 1);
+            ent.data_chunk_idx.0 = i as u64;
+            ent.data_chunk_end_idx.0 = i as u64 + 1;
+            base.push(VersionedIndexEntry::V1(ent));
+        }
+        base.push(VersionedIndexEntry::DirectoryRollupV1(DirectoryRollup {
+            path: ".".to_string(),
+            entry_count: serde_bare::Uint(4),
+            total_size: serde_bare::Uint(4),
+        }));
+
+        // A new file at the start shifts the data of the others.
+        let mut index = vec![VersionedIndexEntry::V1(test_entry("0", libc::S_IFREG, 1))];
+        for ent in base[..4].iter() {
+            if let VersionedIndexEntry::V1(ent) = ent {
+                if ent.path != "c" {
+                    index.push(VersionedIndexEntry::V1(
+                        ent.with_data_chunk_offset(1).unwrap(),
+                    ));
+                }
+            }
+        }
+        index.push(base[4].clone());
+
+        let mut encoder = IndexDeltaEncoder::new(&base);
+        let mut encoded = Vec::new();
+        for ent in index.iter() {
+            encoded.extend(encoder.add(ent.clone()));
+        }
+        encoded.extend(encoder.finish());
+        // The new entry, then ranges for a-b and for d with the rollup.
+        assert_eq!(encoded.len(), 3);
+
+        let mut builder = IndexBuilder::default();
+        for ent in base
+            .iter()
+            .chain(std::iter::once(&VersionedIndexEntry::DeltaStartV1))
+        {
+            builder.push(ent.clone()).unwrap();
+        }
+        for ent in encoded.into_iter() {
+            builder.push(ent).unwrap();
+        }
+        assert_eq!(builder.delta_depth, 1);
+        assert_eq!(builder.index, index);
+
+        let mut builder = IndexBuilder::default();
+        builder.push(VersionedIndexEntry::DeltaStartV1).unwrap();
+        assert!(builder
+            .push(VersionedIndexEntry::BaseRangeV1(BaseRange {
+                start: serde_bare::Uint(0),
+                count: serde_bare::Uint(1),
+                data_chunk_offset: serde_bare::Int(0),
+            }))
+            .is_err());
+    }
+
+    #[test]
+    fn test_index_entry_v2() {
+        let stat = EntryStat {
+            kind: IndexEntryKind::Symlink,
+            uid: serde_bare::Uint(1000),
+            gid: serde_bare::Uint(100),
+            mtime: serde_bare::Uint(1600000000),
+            mtime_nsec: serde_bare::Uint(5),
+            nlink: serde_bare::Uint(1),
+            link_target: Some("b".to_string()),
+        };
+        let v1 = VersionedIndexEntry::V1(test_entry("a", libc::S_IFLNK, 0));
+        let mut ent = test_entry("l", libc::S_IFLNK, 0);
+        ent.data_chunk_idx.0 = 1;
+        ent.data_chunk_end_idx.0 = 1;
+        let v2 = VersionedIndexEntry::V2(IndexEntryV2 {
+            entry: ent.clone(),
+            stat: stat.clone(),
+            owner_names: None,
+            content_hash: None,
+        });
+
+        // Indexes from older versions are read as before.
+        let index = vec![v1.clone(), v2.clone()];
+        let decoded: Vec<VersionedIndexEntry> =
+            serde_bare::from_slice(&serde_bare::to_vec(&index).unwrap()).unwrap();
+        assert_eq!(decoded, index);
+        let split = split_index(decoded);
+        assert_eq!(split.entries.len(), 2);
+        assert_eq!(split.entries[1], ent);
+        assert!(!split.stats.contains_key("a"));
+        assert_eq!(split.stats.get("l"), Some(&stat));
+
+        // Shifted V2 entries are delta encoded like V1 entries.
+        let shifted = VersionedIndexEntry::V2(IndexEntryV2 {
+            entry: ent.with_data_chunk_offset(3).unwrap(),
+            stat,
+            owner_names: None,
+            content_hash: None,
+        });
+        assert_eq!(delta_offset(&v2, &shifted), Some(Some(3)));
+        assert_eq!(delta_offset(&v1, &shifted), None);
+    }
+}
@@ -45,6 +45,25 @@ pub struct ItemMetadata {
     pub encrypted_metadata: Vec<u8>,
 }
 
+// The same as EncryptedItemMetadata with the addition of a free form note, the item
+// size and the time the put started.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct EncryptedItemMetadataV2 {
+    pub plain_text_hash: [u8; crypto::HASH_BYTES],
+    pub send_key_id: Xid,
+    pub hash_key_part_2: crypto::PartialHashKey,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    // We want ordered serialization.
+    pub tags: std::collections::BTreeMap<String, String>,
+    pub note: Option<String>,
+    // Total bytes of the item data stream.
+    pub data_size: Option<serde_bare::Uint>,
+    // Number of index entries for directory snapshots.
+    pub entry_count: Option<serde_bare::Uint>,
+    // Files modified after this time may not be captured by the item.
+    pub start_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+}
+
 impl ItemMetadata {
     pub fn decrypt_metadata(
         &self,
@@ -57,12 +76,66 @@ impl ItemMetadata {
         }
         Ok(emd)
     }
+
+    pub fn decrypt_metadata_v2(
+        &self,
+        dctx: &mut crypto::DecryptionContext,
+    ) -> Result<EncryptedItemMetadataV2, failure::Error> {
+        let data = dctx.decrypt_data(self.encrypted_metadata.clone())?;
+        let emd: EncryptedItemMetadataV2 = serde_bare::from_slice(&data)?;
+        if self.plain_text_metadata.hash() != emd.plain_text_hash {
+            failure::bail!("item metadata is corrupt or tampered with");
+        }
+        Ok(emd)
+    }
 }
 
 #[non_exhaustive]
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum VersionedItemMetadata {
     V1(ItemMetadata),
+    // Encrypted metadata is an EncryptedItemMetadataV2, written for all new items.
+    V2(ItemMetadata),
+}
+
+impl VersionedItemMetadata {
+    pub fn plain_text_metadata(&self) -> &PlainTextItemMetadata {
+        match self {
+            VersionedItemMetadata::V1(md) => &md.plain_text_metadata,
+            VersionedItemMetadata::V2(md) => &md.plain_text_metadata,
+        }
+    }
+
+    pub fn encrypted_metadata_size(&self) -> usize {
+        match self {
+            VersionedItemMetadata::V1(md) => md.encrypted_metadata.len(),
+            VersionedItemMetadata::V2(md) => md.encrypted_metadata.len(),
+        }
+    }
+
+    // Decrypt the metadata of any version into the latest representation.
+    pub fn decrypt_metadata(
+        &self,
+        dctx: &mut crypto::DecryptionContext,
+    ) -> Result<EncryptedItemMetadataV2, failure::Error> {
+        match self {
+            VersionedItemMetadata::V1(md) => {
+                let emd = md.decrypt_metadata(dctx)?;
+                Ok(EncryptedItemMetadataV2 {
+                    plain_text_hash: emd.plain_text_hash,
+                    send_key_id: emd.send_key_id,
+                    hash_key_part_2: emd.hash_key_part_2,
+                    timestamp: emd.timestamp,
+                    tags: emd.tags,
+                    note: None,
+                    data_size: None,
+                    entry_count: None,
+                    start_timestamp: None,
+                })
+            }
+            VersionedItemMetadata::V2(md) => md.decrypt_metadata_v2(dctx),
+        }
+    }
 }
 
 #[non_exhaustive]
@@ -121,6 +194,103 @@ pub fn add_item(
     Ok(item_id)
 }
 
+fn init_item_tag_tables(tx: &rusqlite::Transaction) -> Result<(), failure::Error> {
+    // Created on demand so older repositories don't need a migration.
+    tx.execute(
+        "create table if not exists ItemTagAddresses(Tag NOT NULL, ItemId NOT NULL);",
+        rusqlite::NO_PARAMS,
+    )?;
+    tx.execute(
+        "create index if not exists ItemTagAddressesTagIdx on ItemTagAddresses(Tag);",
+        rusqlite::NO_PARAMS,
+    )?;
+    tx.execute(
+        "create table if not exists ItemExpiry(ItemId PRIMARY KEY, Expires INTEGER NOT NULL) WITHOUT ROWID;",
+        rusqlite::NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+fn items_with_tag_address(
+    tx: &rusqlite::Transaction,
+    tag: &Address,
+) -> Result<Vec<Xid>, failure::Error> {
+    // Only tags of items that have not been removed count.
+    let mut stmt = tx.prepare(
+        "select Items.ItemId from ItemTagAddresses join Items on ItemTagAddresses.ItemId = Items.ItemId where Tag = ?;",
+    )?;
+    let mut items = Vec::new();
+    let mut rows = stmt.query(&[&tag.bytes[..]])?;
+    while let Some(row) = rows.next()? {
+        items.push(row.get(0)?);
+    }
+    Ok(items)
+}
+
+pub fn add_tagged_item(
+    tx: &rusqlite::Transaction,
+    md: VersionedItemMetadata,
+    unique_tags: &[Address],
+    replace_tags: &[Address],
+    expires: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Xid, failure::Error> {
+    init_item_tag_tables(tx)?;
+
+    for tag in unique_tags.iter() {
+        if !items_with_tag_address(tx, tag)?.is_empty() {
+            failure::bail!("an item with the required unique tag already exists");
+        }
+    }
+
+    let mut replaced = Vec::new();
+    for tag in replace_tags.iter() {
+        replaced.append(&mut items_with_tag_address(tx, tag)?);
+    }
+    if !replaced.is_empty() {
+        remove_items(tx, replaced)?;
+    }
+
+    let item_id = add_item(tx, md)?;
+
+    for tag in unique_tags.iter().chain(replace_tags.iter()) {
+        tx.execute(
+            "insert into ItemTagAddresses(Tag, ItemId) values(?, ?);",
+            rusqlite::params![&tag.bytes[..], &item_id],
+        )?;
+    }
+
+    if let Some(expires) = expires {
+        tx.execute(
+            "insert into ItemExpiry(ItemId, Expires) values(?, ?);",
+            rusqlite::params![&item_id, expires.timestamp()],
+        )?;
+    }
+
+    Ok(item_id)
+}
+
+pub fn remove_expired_items(
+    tx: &rusqlite::Transaction,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<u64, failure::Error> {
+    init_item_tag_tables(tx)?;
+    let mut expired = Vec::new();
+    {
+        let mut stmt = tx.prepare(
+            "select Items.ItemId from ItemExpiry join Items on ItemExpiry.ItemId = Items.ItemId where Expires <= ?;",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![now.timestamp()])?;
+        while let Some(row) = rows.next()? {
+            expired.push(row.get(0)?);
+        }
+    }
+    let n_expired = expired.len() as u64;
+    if n_expired != 0 {
+        remove_items(tx, expired)?;
+    }
+    Ok(n_expired)
+}
+
 pub fn remove_items(tx: &rusqlite::Transaction, items: Vec<Xid>) -> Result<(), failure::Error> {
     let mut existed = Vec::new();
     for item_id in items.iter() {
@@ -223,12 +393,135 @@ pub fn sync_ops(
     }
 }
 
+// The item log head commits to every op in the log, each op digest hashes the previous head.
+// A log with no ops has a head of all zeros.
+pub fn chain_log_op(
+    prev_head: &[u8; crypto::HASH_BYTES],
+    op_id: i64,
+    item_id: &Option<Xid>,
+    op: &LogOp,
+) -> Result<[u8; crypto::HASH_BYTES], failure::Error> {
+    Ok(chain_log_digest(
+        prev_head,
+        &log_op_digest(op_id, item_id, op)?,
+    ))
+}
+
+pub fn chain_log_digest(
+    prev_head: &[u8; crypto::HASH_BYTES],
+    digest: &[u8; crypto::HASH_BYTES],
+) -> [u8; crypto::HASH_BYTES] {
+    let mut hs = crypto::HashState::new(None);
+    hs.update(&prev_head[..]);
+    hs.update(&digest[..]);
+    hs.finish()
+}
+
+// Compaction keeps the digest of each op it removes so the chain can be
+// verified across it.
+pub fn log_op_digest(
+    op_id: i64,
+    item_id: &Option<Xid>,
+    op: &LogOp,
+) -> Result<[u8; crypto::HASH_BYTES], failure::Error> {
+    let mut hs = crypto::HashState::new(None);
+    hs.update(&op_id.to_le_bytes()[..]);
+    match item_id {
+        Some(item_id) => {
+            hs.update(&[1]);
+            hs.update(&item_id.bytes[..]);
+        }
+        None => hs.update(&[0]),
+    }
+    hs.update(&serde_bare::to_vec(op)?);
+    Ok(hs.finish())
+}
+
+// Calls f with the digest of every op after after_op in order, including ops removed by compaction.
+pub fn walk_log_digests(
+    tx: &rusqlite::Transaction,
+    after_op: i64,
+    f: &mut dyn FnMut(i64, [u8; crypto::HASH_BYTES]) -> Result<(), failure::Error>,
+) -> Result<(), failure::Error> {
+    let mut compacted = Vec::new();
+    let has_digests = match tx.query_row(
+        "select 1 from sqlite_master where type = 'table' and name = 'ItemOpLogDigests';",
+        rusqlite::NO_PARAMS,
+        |_row| Ok(true),
+    ) {
+        Ok(_) => true,
+        Err(rusqlite::Error::QueryReturnedNoRows) => false,
+        Err(e) => return Err(e.into()),
+    };
+    if has_digests {
+        let mut stmt = tx.prepare(
+            "select OpId, Digest from ItemOpLogDigests where OpId > ? order by OpId asc;",
+        )?;
+        let mut rows = stmt.query([after_op])?;
+        while let Some(row) = rows.next()? {
+            let op_id: i64 = row.get(0)?;
+            let digest: Vec<u8> = row.get(1)?;
+            if digest.len() != crypto::HASH_BYTES {
+                failure::bail!("corrupt op log digest");
+            }
+            let mut bytes = [0; crypto::HASH_BYTES];
+            bytes[..].clone_from_slice(&digest[..]);
+            compacted.push((op_id, bytes));
+        }
+    }
+    let mut compacted = compacted.into_iter().peekable();
+    walk_log(tx, after_op, &mut |op_id, item_id, op| {
+        while let Some(&(compacted_op_id, digest)) = compacted.peek() {
+            if compacted_op_id > op_id {
+                break;
+            }
+            f(compacted_op_id, digest)?;
+            compacted.next();
+        }
+        f(op_id, log_op_digest(op_id, &item_id, &op)?)
+    })?;
+    for (op_id, digest) in compacted {
+        f(op_id, digest)?;
+    }
+    Ok(())
+}
+
 pub fn compact(tx: &rusqlite::Transaction) -> Result<(), failure::Error> {
+    // Created on demand so older repositories don't need a migration.
+    tx.execute(
+        "create table if not exists ItemOpLogDigests(OpId INTEGER PRIMARY KEY, Digest NOT NULL);",
+        rusqlite::NO_PARAMS,
+    )?;
+    {
+        let mut stmt = tx.prepare(
+            "select OpId, ItemId, OpData from ItemOpLog where OpId not in (select OpId from Items);",
+        )?;
+        let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let op_id: i64 = row.get(0)?;
+            let item_id: Option<Xid> = row.get(1)?;
+            let op: Vec<u8> = row.get(2)?;
+            let op: LogOp = serde_bare::from_slice(&op)?;
+            tx.execute(
+                "insert into ItemOpLogDigests(OpId, Digest) values(?, ?);",
+                rusqlite::params![op_id, &log_op_digest(op_id, &item_id, &op)?[..]],
+            )?;
+        }
+    }
     // Remove everything not in the aggregated set.
     tx.execute(
         "delete from ItemOpLog where OpId not in (select OpId from Items);",
         rusqlite::NO_PARAMS,
     )?;
+    init_item_tag_tables(tx)?;
+    tx.execute(
+        "delete from ItemTagAddresses where ItemId not in (select ItemId from Items);",
+        rusqlite::NO_PARAMS,
+    )?;
+    tx.execute(
+        "delete from ItemExpiry where ItemId not in (select ItemId from Items);",
+        rusqlite::NO_PARAMS,
+    )?;
     Ok(())
 }
 
@@ -242,6 +535,13 @@ pub fn has_item_with_id(tx: &rusqlite::Transaction, id: &Xid) -> Result<bool, fa
     }
 }
 
+pub fn count_items(tx: &rusqlite::Transaction) -> Result<u64, failure::Error> {
+    let count: i64 = tx.query_row("select count(*) from Items;", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    Ok(count as u64)
+}
+
 pub fn lookup_item_by_id(
     tx: &rusqlite::Transaction,
     id: &Xid,
@@ -263,6 +563,17 @@ pub fn lookup_item_by_id(
 pub fn walk_items(
     tx: &rusqlite::Transaction,
     f: &mut dyn FnMut(i64, Xid, VersionedItemMetadata) -> Result<(), failure::Error>,
+) -> Result<(), failure::Error> {
+    walk_items_while(tx, &mut |op_id, item_id, metadata| {
+        f(op_id, item_id, metadata)?;
+        Ok(true)
+    })
+}
+
+// Like walk_items, but stops early once f returns false.
+pub fn walk_items_while(
+    tx: &rusqlite::Transaction,
+    f: &mut dyn FnMut(i64, Xid, VersionedItemMetadata) -> Result<bool, failure::Error>,
 ) -> Result<(), failure::Error> {
     let mut stmt = tx.prepare("select OpId, ItemId, Metadata from Items order by OpId asc;")?;
     let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
@@ -273,7 +584,9 @@ pub fn walk_items(
                 let item_id: Xid = row.get(1)?;
                 let metadata: Vec<u8> = row.get(2)?;
                 let metadata: VersionedItemMetadata = serde_bare::from_slice(&metadata)?;
-                f(op_id, item_id, metadata)?;
+                if !f(op_id, item_id, metadata)? {
+                    return Ok(());
+                }
             }
             None => {
                 return Ok(());
@@ -305,3 +618,61 @@ pub fn walk_log(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decrypt_metadata() {
+        crypto::init();
+        let (pk, sk) = crypto::box_keypair();
+        let psk = crypto::BoxPreSharedKey::new();
+        let mut ectx = crypto::EncryptionContext::new(&pk, &psk, crypto::NonceMode::Derived);
+        let mut dctx = crypto::DecryptionContext::new(sk, psk);
+        let plain_text_metadata = PlainTextItemMetadata {
+            primary_key_id: Xid::new(),
+            data_tree: HTreeMetadata {
+                height: 0,
+                address: Address::default(),
+            },
+            index_tree: None,
+        };
+        let mut encrypt = |data: Vec<u8>| ItemMetadata {
+            plain_text_metadata: plain_text_metadata.clone(),
+            encrypted_metadata: ectx.encrypt_data(
+                data,
+                crypto::DataCompression::Zstd(crypto::DEFAULT_ZSTD_LEVEL),
+            ),
+        };
+        let emd = EncryptedItemMetadataV2 {
+            plain_text_hash: plain_text_metadata.hash(),
+            send_key_id: Xid::new(),
+            hash_key_part_2: crypto::PartialHashKey::new(),
+            timestamp: chrono::Utc::now(),
+            tags: std::collections::BTreeMap::new(),
+            note: Some("note".to_string()),
+            data_size: Some(serde_bare::Uint(1)),
+            entry_count: None,
+            start_timestamp: Some(chrono::Utc::now()),
+        };
+        let md = VersionedItemMetadata::V2(encrypt(serde_bare::to_vec(&emd).unwrap()));
+        assert_eq!(md.decrypt_metadata(&mut dctx).unwrap(), emd);
+
+        // Items written before the extra fields read with them unset.
+        let md = VersionedItemMetadata::V1(encrypt(
+            serde_bare::to_vec(&EncryptedItemMetadata {
+                plain_text_hash: emd.plain_text_hash,
+                send_key_id: emd.send_key_id,
+                hash_key_part_2: emd.hash_key_part_2.clone(),
+                timestamp: emd.timestamp,
+                tags: emd.tags.clone(),
+            })
+            .unwrap(),
+        ));
+        let v1_emd = md.decrypt_metadata(&mut dctx).unwrap();
+        assert_eq!(v1_emd.send_key_id, emd.send_key_id);
+        assert_eq!(v1_emd.note, None);
+        assert_eq!(v1_emd.start_timestamp, None);
+    }
+}
@@ -32,6 +32,9 @@ pub struct PrimaryKey {
     pub metadata_pk: crypto::BoxPublicKey,
     pub metadata_sk: crypto::BoxSecretKey,
     pub metadata_psk: crypto::BoxPreSharedKey,
+    /* Stored outside the key, see KeyFile. */
+    #[serde(skip)]
+    pub nonce_mode: crypto::NonceMode,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -44,6 +47,8 @@ pub struct SendKey {
     pub data_psk: crypto::BoxPreSharedKey,
     pub metadata_pk: crypto::BoxPublicKey,
     pub metadata_psk: crypto::BoxPreSharedKey,
+    #[serde(skip)]
+    pub nonce_mode: crypto::NonceMode,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -53,15 +58,66 @@ pub struct MetadataKey {
     pub metadata_pk: crypto::BoxPublicKey,
     pub metadata_sk: crypto::BoxSecretKey,
     pub metadata_psk: crypto::BoxPreSharedKey,
+    #[serde(skip)]
+    pub nonce_mode: crypto::NonceMode,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "KeyFile", into = "KeyFile")]
 pub enum Key {
     PrimaryKeyV1(PrimaryKey),
     PutKeyV1(SendKey),
     MetadataKeyV1(MetadataKey),
 }
 
+// How keys are stored in key files. Keys derived from a primary key copy its nonce mode,
+// keys written by older versions of bupstash have none and use the default.
+#[derive(Serialize, Deserialize)]
+enum KeyFile {
+    PrimaryKeyV1(PrimaryKey),
+    PutKeyV1(SendKey),
+    MetadataKeyV1(MetadataKey),
+    PrimaryKeyV2(PrimaryKey, crypto::NonceMode),
+    PutKeyV2(SendKey, crypto::NonceMode),
+    MetadataKeyV2(MetadataKey, crypto::NonceMode),
+}
+
+impl From<Key> for KeyFile {
+    fn from(k: Key) -> KeyFile {
+        match k {
+            Key::PrimaryKeyV1(k) => {
+                let nonce_mode = k.nonce_mode;
+                KeyFile::PrimaryKeyV2(k, nonce_mode)
+            }
+            Key::PutKeyV1(k) => {
+                let nonce_mode = k.nonce_mode;
+                KeyFile::PutKeyV2(k, nonce_mode)
+            }
+            Key::MetadataKeyV1(k) => {
+                let nonce_mode = k.nonce_mode;
+                KeyFile::MetadataKeyV2(k, nonce_mode)
+            }
+        }
+    }
+}
+
+impl From<KeyFile> for Key {
+    fn from(k: KeyFile) -> Key {
+        match k {
+            KeyFile::PrimaryKeyV1(k) => Key::PrimaryKeyV1(k),
+            KeyFile::PutKeyV1(k) => Key::PutKeyV1(k),
+            KeyFile::MetadataKeyV1(k) => Key::MetadataKeyV1(k),
+            KeyFile::PrimaryKeyV2(k, nonce_mode) => {
+                Key::PrimaryKeyV1(PrimaryKey { nonce_mode, ..k })
+            }
+            KeyFile::PutKeyV2(k, nonce_mode) => Key::PutKeyV1(SendKey { nonce_mode, ..k }),
+            KeyFile::MetadataKeyV2(k, nonce_mode) => {
+                Key::MetadataKeyV1(MetadataKey { nonce_mode, ..k })
+            }
+        }
+    }
+}
+
 fn pem_tag(k: &Key) -> &str {
     match k {
         Key::PrimaryKeyV1(_) => "BUPSTASH KEY",
@@ -71,7 +127,13 @@ fn pem_tag(k: &Key) -> &str {
 }
 
 impl Key {
-    pub fn write_to_file(&self, path: &str) -> Result<(), Error> {
+    pub fn write_to_file(&self, path: &str, label: Option<&str>) -> Result<(), Error> {
+        if let Some(label) = label {
+            if label.contains('\n') {
+                failure::bail!("key label must not contain newlines");
+            }
+        }
+
         let mut f = OpenOptions::new()
             .mode(0o600)
             .write(true)
@@ -94,6 +156,16 @@ impl Key {
                 )?;
             }
         }
+        f.write_all(
+            format!(
+                "# created={}\n",
+                chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            )
+            .as_bytes(),
+        )?;
+        if let Some(label) = label {
+            f.write_all(format!("# label={}\n", label).as_bytes())?;
+        }
         f.write_all("\n".to_string().as_bytes())?;
 
         let pem_data = pem::encode(&pem::Pem {
@@ -117,14 +189,7 @@ impl Key {
     }
 
     pub fn load_from_file(path: &str) -> Result<Key, Error> {
-        let mut f = OpenOptions::new()
-            .read(true)
-            .open(path)
-            .with_context(|e| format!("error opening {}: {}", path, e))?;
-
-        let mut pem_data = Vec::new();
-        f.read_to_end(&mut pem_data)?;
-        Key::from_slice(&pem_data)
+        Key::from_slice(&read_key_file(path)?)
     }
 
     pub fn primary_key_id(&self) -> Xid {
@@ -142,10 +207,66 @@ impl Key {
             Key::MetadataKeyV1(k) => k.id,
         }
     }
+
+    pub fn nonce_mode(&self) -> crypto::NonceMode {
+        match self {
+            Key::PrimaryKeyV1(k) => k.nonce_mode,
+            Key::PutKeyV1(k) => k.nonce_mode,
+            Key::MetadataKeyV1(k) => k.nonce_mode,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Key::PrimaryKeyV1(_) => "primary",
+            Key::PutKeyV1(_) => "put",
+            Key::MetadataKeyV1(_) => "metadata",
+        }
+    }
+
+    // The operations a key is able to perform.
+    pub fn capabilities(&self) -> &'static [&'static str] {
+        match self {
+            Key::PrimaryKeyV1(_) => &["put", "get", "list", "list-contents", "rm"],
+            Key::PutKeyV1(_) => &["put"],
+            Key::MetadataKeyV1(_) => &["list", "rm"],
+        }
+    }
+}
+
+pub fn read_key_file(path: &str) -> Result<Vec<u8>, Error> {
+    let mut f = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .with_context(|e| format!("error opening {}: {}", path, e))?;
+
+    let mut pem_data = Vec::new();
+    f.read_to_end(&mut pem_data)?;
+    Ok(pem_data)
+}
+
+// Parse the '# name=value' comments that precede the pem data in a key file.
+// These are purely informational and are not authenticated in any way.
+pub fn key_file_annotations(pem_data: &[u8]) -> std::collections::BTreeMap<String, String> {
+    let mut annotations = std::collections::BTreeMap::new();
+    for line in String::from_utf8_lossy(pem_data).lines() {
+        if line.starts_with("-----BEGIN") {
+            break;
+        }
+        if let Some(annotation) = line.strip_prefix('#') {
+            if let Some(idx) = annotation.find('=') {
+                annotations.insert(
+                    annotation[..idx].trim().to_string(),
+                    annotation[idx + 1..].trim().to_string(),
+                );
+            }
+        }
+    }
+    annotations
 }
 
 impl PrimaryKey {
-    pub fn gen() -> PrimaryKey {
+    pub fn gen(nonce_mode: crypto::NonceMode) -> PrimaryKey {
         let id = Xid::new();
         let hash_key_part_1 = crypto::PartialHashKey::new();
         let hash_key_part_2 = crypto::PartialHashKey::new();
@@ -163,6 +284,7 @@ impl PrimaryKey {
             metadata_pk,
             metadata_sk,
             metadata_psk,
+            nonce_mode,
         }
     }
 }
@@ -179,6 +301,7 @@ impl SendKey {
             data_psk: mk.data_psk.clone(),
             metadata_pk: mk.metadata_pk.clone(),
             metadata_psk: mk.metadata_psk.clone(),
+            nonce_mode: mk.nonce_mode,
         }
     }
 }
@@ -191,6 +314,39 @@ impl MetadataKey {
             metadata_pk: mk.metadata_pk.clone(),
             metadata_sk: mk.metadata_sk.clone(),
             metadata_psk: mk.metadata_psk.clone(),
+            nonce_mode: mk.nonce_mode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_file_annotations() {
+        let annotations = key_file_annotations(
+            b"# This file contains a key.\n#\n# key-id=abc\n# label=my label\n\n-----BEGIN BUPSTASH KEY-----\n# x=y\n",
+        );
+        assert_eq!(annotations.len(), 2);
+        assert_eq!(annotations.get("key-id").unwrap(), "abc");
+        assert_eq!(annotations.get("label").unwrap(), "my label");
+    }
+
+    #[test]
+    fn test_key_nonce_mode() {
+        crypto::init();
+        let k = PrimaryKey::gen(crypto::NonceMode::Incrementing);
+        let put_key = Key::PutKeyV1(SendKey::gen(&k));
+        let k = Key::PrimaryKeyV1(k);
+        for k in [k, put_key].iter() {
+            let k: Key = serde_bare::from_slice(&serde_bare::to_vec(k).unwrap()).unwrap();
+            assert_eq!(k.nonce_mode(), crypto::NonceMode::Incrementing);
         }
+        // Key files written by older versions of bupstash record no nonce mode.
+        let k = PrimaryKey::gen(crypto::NonceMode::Incrementing);
+        let data = serde_bare::to_vec(&KeyFile::PrimaryKeyV1(k)).unwrap();
+        let k: Key = serde_bare::from_slice(&data).unwrap();
+        assert_eq!(k.nonce_mode(), crypto::NonceMode::Derived);
     }
 }
@@ -0,0 +1,127 @@
+// Output templates for 'bupstash list --format', for example "{id}\t{tag:name}\t{timestamp}".
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Tag(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+// Placeholders that are pseudo tags added when listing, other tags need a 'tag:' prefix.
+const BUILTIN_PLACEHOLDERS: &[&str] = &[
+    "id",
+    "timestamp",
+    "note",
+    "size",
+    "entries",
+    "decryption-key-id",
+];
+
+pub fn is_template(format: &str) -> bool {
+    format.contains('{')
+}
+
+pub fn parse(format: &str) -> Result<Template, failure::Error> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('t') => literal.push('\t'),
+                Some('n') => literal.push('\n'),
+                Some('\\') => literal.push('\\'),
+                Some(c) => failure::bail!("invalid escape '\\{}' in format template", c),
+                None => failure::bail!("format template ends with an incomplete escape"),
+            },
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => failure::bail!("unterminated placeholder in format template"),
+                    }
+                }
+                let tag = if let Some(tag) = placeholder.strip_prefix("tag:") {
+                    tag.to_string()
+                } else if BUILTIN_PLACEHOLDERS.contains(&placeholder.as_str()) {
+                    placeholder
+                } else {
+                    failure::bail!(
+                        "unknown placeholder '{{{}}}' in format template, use '{{tag:{}}}' for tags",
+                        placeholder,
+                        placeholder
+                    );
+                };
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(Part::Tag(tag));
+            }
+            '}' => {
+                failure::bail!("unmatched '}' in format template, use '}}' for a literal '}'")
+            }
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+
+    Ok(Template { parts })
+}
+
+impl Template {
+    // Missing tags are rendered as empty strings.
+    pub fn render(&self, tags: &std::collections::BTreeMap<String, String>) -> String {
+        let mut out = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(s) => out.push_str(s),
+                Part::Tag(t) => {
+                    if let Some(v) = tags.get(t) {
+                        out.push_str(v)
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_template() {
+        let mut tags = std::collections::BTreeMap::new();
+        tags.insert("id".to_string(), "abc".to_string());
+        tags.insert("name".to_string(), "foo.tar".to_string());
+        let t = parse("{id}\\t{tag:name}\\t{tag:missing}{{x}}\\n").unwrap();
+        assert_eq!(t.render(&tags), "abc\tfoo.tar\t{x}\n");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(parse("{name}").is_err());
+        assert!(parse("{id").is_err());
+        assert!(parse("id}").is_err());
+        assert!(parse("\\x").is_err());
+    }
+}
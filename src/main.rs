@@ -1,33 +1,45 @@
+pub mod acl;
 pub mod address;
 pub mod base64;
 pub mod chunk_storage;
 pub mod chunker;
 pub mod client;
+pub mod completions;
 pub mod crypto;
 pub mod dir_chunk_storage;
 pub mod external_chunk_storage;
+pub mod fastcdc;
 pub mod fsutil;
 pub mod hex;
 pub mod htree;
 pub mod index;
 pub mod itemset;
 pub mod keys;
+pub mod listformat;
+pub mod mount;
 pub mod pem;
 pub mod protocol;
 pub mod query;
 pub mod querycache;
+pub mod ratelimit;
 pub mod repository;
 pub mod rollsum;
+pub mod sandbox;
 pub mod sendlog;
 pub mod server;
 pub mod sodium;
+pub mod tagschema;
+pub mod timeline;
 pub mod xid;
 pub mod xtar;
 
 use failure::Fail;
 use getopts::{Matches, Options};
 use std::collections::BTreeMap;
-use std::io::{BufRead, Write};
+use std::convert::TryInto;
+use std::io::{BufRead, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
 
 fn die(s: String) -> ! {
     eprintln!("{}", s);
@@ -50,6 +62,29 @@ fn cache_dir() -> Result<std::path::PathBuf, failure::Error> {
     Ok(cache_dir)
 }
 
+// Parse a size such as '4096', '512K', '128M' or '2G', suffixes are powers of 1024.
+fn parse_size(s: &str) -> Result<u64, failure::Error> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("iB")
+        .or_else(|| s.strip_suffix('B'))
+        .unwrap_or(s);
+    let (digits, multiplier) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1024),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        Some('t') | Some('T') => (&s[..s.len() - 1], 1024 * 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) => match n.checked_mul(multiplier) {
+            Some(n) => Ok(n),
+            None => failure::bail!("size '{}' is too large", s),
+        },
+        Err(err) => failure::bail!("unable to parse size '{}': {}", s, err),
+    }
+}
+
 fn print_help_and_exit(subcommand: &str, opts: &Options) {
     let brief = match subcommand {
         "init" => include_str!("../doc/cli/init.txt"),
@@ -57,15 +92,28 @@ fn print_help_and_exit(subcommand: &str, opts: &Options) {
         "new-key" => include_str!("../doc/cli/new-key.txt"),
         "new-put-key" => include_str!("../doc/cli/new-put-key.txt"),
         "new-metadata-key" => include_str!("../doc/cli/new-metadata-key.txt"),
+        "key-info" => include_str!("../doc/cli/key-info.txt"),
         "put" => include_str!("../doc/cli/put.txt"),
         "list" => include_str!("../doc/cli/list.txt"),
         "list-contents" => include_str!("../doc/cli/list-contents.txt"),
+        "timeline" => include_str!("../doc/cli/timeline.txt"),
         "get" => include_str!("../doc/cli/get.txt"),
+        "restore" => include_str!("../doc/cli/restore.txt"),
+        "inspect" => include_str!("../doc/cli/inspect.txt"),
+        "mount" => include_str!("../doc/cli/mount.txt"),
         "rm" | "remove" => include_str!("../doc/cli/rm.txt"),
         "restore-removed" => include_str!("../doc/cli/restore-removed.txt"),
         "gc" => include_str!("../doc/cli/gc.txt"),
+        "repo-stats" => include_str!("../doc/cli/repo-stats.txt"),
+        "shared" => include_str!("../doc/cli/shared.txt"),
+        "diff" => include_str!("../doc/cli/diff.txt"),
+        "clone" => include_str!("../doc/cli/clone.txt"),
+        "restore-send-log" => include_str!("../doc/cli/restore-send-log.txt"),
+        "completions" => include_str!("../doc/cli/completions.txt"),
         "serve" => include_str!("../doc/cli/serve.txt"),
         "version" => include_str!("../doc/cli/version.txt"),
+        "verify" => include_str!("../doc/cli/verify.txt"),
+        "debug-dump-htree" => include_str!("../doc/cli/debug-dump-htree.txt"),
         _ => panic!(),
     };
     print!("{}", opts.usage(brief));
@@ -178,11 +226,18 @@ fn matches_to_key(matches: &Matches) -> Result<keys::Key, failure::Error> {
 }
 
 fn matches_to_opt_key(matches: &Matches) -> Result<Option<keys::Key>, failure::Error> {
+    match matches_to_opt_key_data(matches)? {
+        Some(key_data) => Ok(Some(keys::Key::from_slice(&key_data)?)),
+        None => Ok(None),
+    }
+}
+
+fn matches_to_opt_key_data(matches: &Matches) -> Result<Option<Vec<u8>>, failure::Error> {
     match matches.opt_str("key") {
-        Some(k) => Ok(Some(keys::Key::load_from_file(&k)?)),
+        Some(k) => Ok(Some(keys::read_key_file(&k)?)),
         None => {
             if let Some(k) = std::env::var_os("BUPSTASH_KEY") {
-                Ok(Some(keys::Key::load_from_file(&k.into_string().unwrap())?))
+                Ok(Some(keys::read_key_file(&k.into_string().unwrap())?))
             } else if let Some(cmd) = std::env::var_os("BUPSTASH_KEY_COMMAND") {
                 match shlex::split(&cmd.into_string().unwrap()) {
                     Some(mut args) => {
@@ -197,7 +252,7 @@ fn matches_to_opt_key(matches: &Matches) -> Result<Option<keys::Key>, failure::E
                             .stdin(std::process::Stdio::inherit())
                             .output()
                         {
-                            Ok(key_data) => Ok(Some(keys::Key::from_slice(&key_data.stdout)?)),
+                            Ok(key_data) => Ok(Some(key_data.stdout)),
                             Err(e) => failure::bail!("error running BUPSTASH_KEY_COMMAND: {}", e),
                         }
                     }
@@ -213,21 +268,42 @@ fn matches_to_opt_key(matches: &Matches) -> Result<Option<keys::Key>, failure::E
 fn new_key_main(args: Vec<String>) -> Result<(), failure::Error> {
     let mut opts = default_cli_opts();
     opts.reqopt("o", "output", "set output file.", "PATH");
+    opts.optopt("", "label", "Label to record in the key file.", "LABEL");
+    opts.optopt(
+        "",
+        "nonce-mode",
+        "How data is given encryption nonces, valid values are 'derived' or 'incrementing', defaults to 'derived'.",
+        "MODE",
+    );
     let matches = parse_cli_opts(opts, &args[..]);
-    let primary_key = keys::Key::PrimaryKeyV1(keys::PrimaryKey::gen());
-    primary_key.write_to_file(&matches.opt_str("o").unwrap())
+    let nonce_mode = match matches.opt_str("nonce-mode").as_deref() {
+        Some("derived") | None => crypto::NonceMode::Derived,
+        Some("incrementing") => crypto::NonceMode::Incrementing,
+        Some(_) => {
+            failure::bail!("invalid --nonce-mode, expected one of 'derived' or 'incrementing'")
+        }
+    };
+    let primary_key = keys::Key::PrimaryKeyV1(keys::PrimaryKey::gen(nonce_mode));
+    primary_key.write_to_file(
+        &matches.opt_str("o").unwrap(),
+        matches.opt_str("label").as_deref(),
+    )
 }
 
 fn new_send_key_main(args: Vec<String>) -> Result<(), failure::Error> {
     let mut opts = default_cli_opts();
     opts.optopt("k", "key", "primary key to derive put-key from.", "PATH");
     opts.reqopt("o", "output", "output file.", "PATH");
+    opts.optopt("", "label", "Label to record in the key file.", "LABEL");
     let matches = parse_cli_opts(opts, &args[..]);
     let k = matches_to_key(&matches)?;
     match k {
         keys::Key::PrimaryKeyV1(primary_key) => {
             let send_key = keys::Key::PutKeyV1(keys::SendKey::gen(&primary_key));
-            send_key.write_to_file(&matches.opt_str("o").unwrap())
+            send_key.write_to_file(
+                &matches.opt_str("o").unwrap(),
+                matches.opt_str("label").as_deref(),
+            )
         }
         _ => failure::bail!("key is not a primary key"),
     }
@@ -242,29 +318,201 @@ fn new_metadata_key_main(args: Vec<String>) -> Result<(), failure::Error> {
         "PATH",
     );
     opts.reqopt("o", "output", "output file.", "PATH");
+    opts.optopt("", "label", "Label to record in the key file.", "LABEL");
     let matches = parse_cli_opts(opts, &args[..]);
     let k = matches_to_key(&matches)?;
     match k {
         keys::Key::PrimaryKeyV1(primary_key) => {
             let send_key = keys::Key::MetadataKeyV1(keys::MetadataKey::gen(&primary_key));
-            send_key.write_to_file(&matches.opt_str("o").unwrap())
+            send_key.write_to_file(
+                &matches.opt_str("o").unwrap(),
+                matches.opt_str("label").as_deref(),
+            )
         }
         _ => failure::bail!("key is not a primary key"),
     }
 }
 
-fn matches_to_query_cache(matches: &Matches) -> Result<querycache::QueryCache, failure::Error> {
-    match matches.opt_str("query-cache") {
-        Some(query_cache) => querycache::QueryCache::open(&std::path::PathBuf::from(query_cache)),
-        None => match std::env::var_os("BUPSTASH_QUERY_CACHE") {
-            Some(query_cache) => {
-                querycache::QueryCache::open(&std::path::PathBuf::from(query_cache))
+fn key_info_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    opts.optopt("k", "key", "Key to print information about.", "PATH");
+    opts.optopt(
+        "",
+        "format",
+        "Output format, valid values are 'human' or 'jsonl'.",
+        "FORMAT",
+    );
+    let matches = parse_cli_opts(opts, &args[..]);
+
+    let list_format = match matches.opt_str("format") {
+        Some(f) => match &f[..] {
+            "jsonl" => ListFormat::Jsonl,
+            "human" => ListFormat::Human,
+            _ => failure::bail!("invalid --format, expected one of 'human' or 'jsonl'"),
+        },
+        None => ListFormat::Human,
+    };
+
+    let key_data = match matches_to_opt_key_data(&matches)? {
+        Some(key_data) => key_data,
+        None => failure::bail!("please set --key, BUPSTASH_KEY or BUPSTASH_KEY_COMMAND"),
+    };
+    let key = keys::Key::from_slice(&key_data)?;
+    let annotations = keys::key_file_annotations(&key_data);
+
+    let created = annotations.get("created");
+    let label = annotations.get("label");
+
+    match list_format {
+        ListFormat::Human => {
+            println!("type: {}", key.type_name());
+            println!("key-id: {}", key.id());
+            println!("primary-key-id: {}", key.primary_key_id());
+            match key {
+                keys::Key::PrimaryKeyV1(_) | keys::Key::PutKeyV1(_) => {
+                    println!("put-key-id: {}", key.id())
+                }
+                keys::Key::MetadataKeyV1(_) => (),
+            }
+            println!("capabilities: {}", key.capabilities().join(","));
+            println!("nonce-mode: {}", key.nonce_mode().name());
+            if let Some(created) = created {
+                println!("created: {}", created);
+            }
+            if let Some(label) = label {
+                println!("label: {}", label);
             }
+        }
+        ListFormat::Jsonl => {
+            print!("{{");
+            print!("\"type\":{},", serde_json::to_string(key.type_name())?);
+            print!("\"key_id\":\"{}\",", key.id());
+            print!("\"primary_key_id\":\"{}\",", key.primary_key_id());
+            match key {
+                keys::Key::PrimaryKeyV1(_) | keys::Key::PutKeyV1(_) => {
+                    print!("\"put_key_id\":\"{}\",", key.id())
+                }
+                keys::Key::MetadataKeyV1(_) => print!("\"put_key_id\":null,"),
+            }
+            print!(
+                "\"capabilities\":{},",
+                serde_json::to_string(key.capabilities())?
+            );
+            print!("\"nonce_mode\":\"{}\",", key.nonce_mode().name());
+            print!("\"created\":{},", serde_json::to_string(&created)?);
+            print!("\"label\":{}", serde_json::to_string(&label)?);
+            println!("}}");
+        }
+    }
+
+    Ok(())
+}
+
+// The query cache given with --query-cache or BUPSTASH_QUERY_CACHE, if any.
+fn matches_to_query_cache_path(matches: &Matches) -> Option<std::path::PathBuf> {
+    match matches.opt_str("query-cache") {
+        Some(query_cache) => Some(std::path::PathBuf::from(query_cache)),
+        None => std::env::var_os("BUPSTASH_QUERY_CACHE").map(std::path::PathBuf::from),
+    }
+}
+
+// Each repository gets its own default query cache, named by the repository id
+// so it is shared by every path to the repository, and not reused when a
+// repository is recreated at the same location.
+fn matches_to_query_cache(
+    matches: &Matches,
+    repository_id: &xid::Xid,
+) -> Result<querycache::QueryCache, failure::Error> {
+    match matches_to_query_cache_path(matches) {
+        Some(query_cache) => querycache::QueryCache::open(&query_cache),
+        None => {
+            let mut p = cache_dir()?;
+            std::fs::create_dir_all(&p)?;
+            let name = format!("bupstash-{}.qcache", repository_id);
+            update_query_cache_link(matches, &p, &name)?;
+            p.push(name);
+            querycache::QueryCache::open(&p)
+        }
+    }
+}
+
+// Like matches_to_query_cache, for commands that can work without connecting
+// to the repository, None if there is no known query cache for it.
+fn matches_to_offline_query_cache(
+    matches: &Matches,
+) -> Result<Option<querycache::QueryCache>, failure::Error> {
+    if let Some(query_cache) = matches_to_query_cache_path(matches) {
+        return Ok(Some(querycache::QueryCache::open(&query_cache)?));
+    }
+    let mut p = cache_dir()?;
+    match query_cache_link_name(matches) {
+        Some(name) => p.push(name),
+        None => return Ok(None),
+    }
+    if !p.exists() {
+        return Ok(None);
+    }
+    Ok(Some(querycache::QueryCache::open(&p)?))
+}
+
+// We only learn the repository id once connected, so the default query cache
+// is also linked from a name derived from the repository location, which lets
+// commands that don't connect find it.
+fn query_cache_link_name(matches: &Matches) -> Option<String> {
+    let repo = match matches.opt_str("repository") {
+        Some(repo) => Some(repo),
+        None => std::env::var_os("BUPSTASH_REPOSITORY")
+            .or_else(|| std::env::var_os("BUPSTASH_REPOSITORY_COMMAND"))
+            .map(|r| r.to_string_lossy().to_string()),
+    };
+    repo.map(|repo| {
+        let mut hs = crypto::HashState::new(None);
+        hs.update(repo.as_bytes());
+        let h = hs.finish();
+        format!(
+            "bupstash-{}.qcache-link",
+            hex::easy_encode_to_string(&h[..8])
+        )
+    })
+}
+
+fn update_query_cache_link(
+    matches: &Matches,
+    dir: &std::path::Path,
+    query_cache_name: &str,
+) -> Result<(), failure::Error> {
+    let link = match query_cache_link_name(matches) {
+        Some(name) => dir.join(name),
+        None => return Ok(()),
+    };
+    if let Ok(target) = std::fs::read_link(&link) {
+        if target == std::path::Path::new(query_cache_name) {
+            return Ok(());
+        }
+    }
+    let tmp_link = dir.join(format!("{}.tmp", xid::Xid::new()));
+    std::os::unix::fs::symlink(query_cache_name, &tmp_link)?;
+    std::fs::rename(&tmp_link, &link)?;
+    Ok(())
+}
+
+fn matches_to_send_log(
+    matches: &Matches,
+    repository_id: &xid::Xid,
+    primary_key_id: &xid::Xid,
+) -> Result<sendlog::SendLog, failure::Error> {
+    match matches.opt_str("send-log") {
+        Some(send_log) => sendlog::SendLog::open(&std::path::PathBuf::from(send_log)),
+        None => match std::env::var_os("BUPSTASH_SEND_LOG") {
+            Some(send_log) => sendlog::SendLog::open(&std::path::PathBuf::from(send_log)),
             None => {
+                // A send log per repository and key, so puts to different
+                // repositories don't invalidate each other's logs.
                 let mut p = cache_dir()?;
+                p.push("send-logs");
                 std::fs::create_dir_all(&p)?;
-                p.push("bupstash.qcache");
-                querycache::QueryCache::open(&p)
+                p.push(format!("{}-{}.sendlog", repository_id, primary_key_id));
+                sendlog::SendLog::open(&p)
             }
         },
     }
@@ -288,7 +536,88 @@ fn matches_to_id_and_query(
     Ok((id, query))
 }
 
-fn matches_to_serve_process(matches: &Matches) -> Result<std::process::Child, failure::Error> {
+// The ids of the items in the query cache matching a query, there must be
+// at least one and, unless allow_many is set, at most one.
+fn query_cache_matching_ids(
+    matches: &Matches,
+    query_cache: &mut querycache::QueryCache,
+    primary_key_id: xid::Xid,
+    metadata_dctx: &crypto::DecryptionContext,
+    query: query::Query,
+    allow_many: bool,
+) -> Result<Vec<xid::Xid>, failure::Error> {
+    let mut ids = Vec::new();
+
+    let mut on_match = |item_id: xid::Xid, _tags: std::collections::BTreeMap<String, String>| {
+        ids.push(item_id);
+
+        if ids.len() > 1 && !allow_many {
+            if matches.opt_defined("allow-many") {
+                failure::bail!(
+                    "the provided query matched {} items, need a single match unless --allow-many is specified",
+                    ids.len()
+                );
+            }
+            failure::bail!(
+                "the provided query matched {} items, need a single match",
+                ids.len()
+            );
+        }
+
+        Ok(())
+    };
+
+    let mut tx = query_cache.transaction()?;
+    tx.list(
+        querycache::ListOptions {
+            primary_key_id: Some(primary_key_id),
+            metadata_dctx: Some(metadata_dctx.clone()),
+            list_encrypted: matches.opt_present("query-encrypted"),
+            utc_timestamps: matches.opt_present("utc-timestamps"),
+            query: Some(query),
+            now: chrono::Utc::now(),
+            order: querycache::ListOrder::default(),
+        },
+        &mut on_match,
+    )?;
+
+    if ids.is_empty() {
+        failure::bail!("no stored items match the provided query");
+    }
+
+    Ok(ids)
+}
+
+// A connection to the repository, the fields are named after the
+// pipes of a 'bupstash serve' subprocess.
+struct ServeProcess {
+    stdin: Option<Box<dyn std::io::Write + Send>>,
+    stdout: Option<Box<dyn std::io::Read + Send>>,
+    server_thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for ServeProcess {
+    fn drop(&mut self) {
+        // Hang up so an in process server finishes before we exit.
+        self.stdin = None;
+        self.stdout = None;
+        if let Some(server_thread) = self.server_thread.take() {
+            let _ = server_thread.join();
+        }
+    }
+}
+
+// We only serve repositories in process if they are on the local disk and
+// owned by us, anything else may rely on the permissions of a separate serve process.
+fn can_serve_in_process(repo: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match std::fs::metadata(repo) {
+        Ok(md) => md.is_dir() && md.uid() == nix::unistd::geteuid().as_raw(),
+        Err(_) => false,
+    }
+}
+
+fn matches_to_serve_process(matches: &Matches) -> Result<ServeProcess, failure::Error> {
     let mut serve_cmd_args = {
         let repo = if matches.opt_present("repository") {
             Some(matches.opt_str("repository").unwrap())
@@ -319,6 +648,22 @@ fn matches_to_serve_process(matches: &Matches) -> Result<std::process::Child, fa
                         args.push(repo_path);
                     }
                     args
+                } else if can_serve_in_process(&repo) {
+                    let (r, w, server_thread) = server::serve_in_process(server::ServerConfig {
+                        allow_init: true,
+                        allow_put: true,
+                        allow_remove: true,
+                        allow_gc: true,
+                        allow_get: true,
+                        max_connections: None,
+                        max_packet_size: protocol::DEFAULT_MAX_PACKET_SIZE,
+                        repo_path: std::path::PathBuf::from(repo),
+                    });
+                    return Ok(ServeProcess {
+                        stdin: Some(Box::new(w)),
+                        stdout: Some(Box::new(r)),
+                        server_thread: Some(server_thread),
+                    });
                 } else {
                     vec![
                         std::env::current_exe()?.to_string_lossy().to_string(),
@@ -351,7 +696,7 @@ fn matches_to_serve_process(matches: &Matches) -> Result<std::process::Child, fa
 
     let bin = serve_cmd_args.remove(0);
 
-    let serve_proc = match std::process::Command::new(bin)
+    let mut serve_proc = match std::process::Command::new(bin)
         .args(serve_cmd_args)
         .stderr(std::process::Stdio::inherit())
         .stdin(std::process::Stdio::piped())
@@ -362,7 +707,124 @@ fn matches_to_serve_process(matches: &Matches) -> Result<std::process::Child, fa
         Err(err) => return Err(err.context("error spawning serve command").into()),
     };
 
-    Ok(serve_proc)
+    Ok(ServeProcess {
+        stdin: Some(Box::new(serve_proc.stdin.take().unwrap())),
+        stdout: Some(Box::new(serve_proc.stdout.take().unwrap())),
+        server_thread: None,
+    })
+}
+
+// Matches a --restore-into target on another host.
+const REMOTE_RESTORE_TARGET: &str = r"^(?:([^@/:]+)@)?([^/:]+):(.*)$";
+
+// Spawn tar to extract a restored tarball into a local directory, or into
+// a directory on another host when given as [USER@]HOST:PATH.
+fn spawn_restore_into(target: &str) -> Result<std::process::Child, failure::Error> {
+    let re = regex::Regex::new(REMOTE_RESTORE_TARGET)?;
+    let mut cmd = match re.captures(target) {
+        Some(caps) => {
+            let mut cmd = std::process::Command::new("ssh");
+            if let Some(user) = caps.get(1) {
+                cmd.arg("-o").arg("User=".to_owned() + user.as_str());
+            }
+            let path = if caps[3].is_empty() { "." } else { &caps[3] };
+            cmd.arg(&caps[2])
+                .arg("--")
+                .arg("tar")
+                .arg("-x")
+                .arg("-C")
+                .arg(shlex::quote(path).to_string());
+            cmd
+        }
+        None => {
+            let mut cmd = std::process::Command::new("tar");
+            cmd.arg("-x").arg("-C").arg(target);
+            cmd
+        }
+    };
+
+    match cmd
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()
+    {
+        Ok(c) => Ok(c),
+        Err(err) => Err(err.context("error spawning restore command").into()),
+    }
+}
+
+// Writes output to the files PREFIX000, PREFIX001, ... starting a
+// new file every split_size bytes, see 'get --split-size'.
+struct SplitWriter {
+    prefix: String,
+    split_size: u64,
+    n_files: usize,
+    remaining: u64,
+    f: Option<std::fs::File>,
+}
+
+impl SplitWriter {
+    fn new(prefix: String, split_size: u64) -> SplitWriter {
+        SplitWriter {
+            prefix,
+            split_size,
+            n_files: 0,
+            remaining: 0,
+            f: None,
+        }
+    }
+
+    fn next_file(&mut self) -> std::io::Result<()> {
+        let path = format!("{}{:03}", self.prefix, self.n_files);
+        let f = match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(f) => f,
+            Err(err) => {
+                return Err(std::io::Error::new(
+                    err.kind(),
+                    format!("unable to create {}: {}", path, err),
+                ))
+            }
+        };
+        self.f = Some(f);
+        self.n_files += 1;
+        self.remaining = self.split_size;
+        Ok(())
+    }
+
+    // Empty output still creates a single empty file.
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.f.is_none() {
+            self.next_file()?;
+        }
+        self.f.as_mut().unwrap().flush()
+    }
+}
+
+impl std::io::Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.f.is_none() || self.remaining == 0 {
+            self.next_file()?;
+        }
+        let n = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        let n_written = self.f.as_mut().unwrap().write(&buf[..n])?;
+        self.remaining -= n_written as u64;
+        Ok(n_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.f {
+            Some(ref mut f) => f.flush(),
+            None => Ok(()),
+        }
+    }
 }
 
 fn matches_to_progress_bar(
@@ -404,22 +866,89 @@ fn list_main(args: Vec<String>) -> Result<(), failure::Error> {
     opts.optopt(
         "",
         "format",
-        "Output format, valid values are 'human' or 'jsonl'.",
+        "Output format, valid values are 'human', 'jsonl' or a template such as '{id}\\t{tag:name}'.",
         "FORMAT",
     );
+    opts.optopt(
+        "",
+        "sort",
+        "Sort items by 'timestamp' or 'id', instead of the order they were added.",
+        "FIELD",
+    );
+    opts.optflag("", "reverse", "Reverse the order items are listed in.");
+    opts.optopt("", "limit", "List at most N items.", "N");
+    opts.optopt("", "offset", "Skip the first N matching items.", "N");
+    opts.optflag("", "count", "Print the number of matching items.");
+    opts.optflag("", "sum-size", "Print the total size of matching items.");
+    opts.optopt(
+        "",
+        "group-by",
+        "Comma separated tags to group --count and --sum-size by, implies --count if neither is given.",
+        "TAGS",
+    );
     query_opts(&mut opts);
 
     let matches = parse_cli_opts(opts, &args[..]);
 
+    let group_by: Option<Vec<String>> = match matches.opt_str("group-by") {
+        Some(group_by) => {
+            let group_by: Vec<String> = group_by.split(',').map(|t| t.trim().to_string()).collect();
+            if group_by.iter().any(|t| t.is_empty()) {
+                failure::bail!("invalid --group-by, tags must not be empty");
+            }
+            Some(group_by)
+        }
+        None => None,
+    };
+    let sum_size = matches.opt_present("sum-size");
+    let count = matches.opt_present("count") || (group_by.is_some() && !sum_size);
+    let aggregate = count || sum_size;
+
+    let mut template = None;
     let list_format = match matches.opt_str("format") {
         Some(f) => match &f[..] {
             "jsonl" => ListFormat::Jsonl,
             "human" => ListFormat::Human,
-            _ => failure::bail!("invalid --format, expected one of 'human' or 'jsonl'"),
+            f if listformat::is_template(f) => {
+                if aggregate {
+                    failure::bail!("template formats cannot be used with --count, --sum-size or --group-by");
+                }
+                template = Some(listformat::parse(f)?);
+                ListFormat::Human
+            }
+            _ => failure::bail!(
+                "invalid --format, expected one of 'human', 'jsonl' or a template containing '{...}'"
+            ),
         },
         None => ListFormat::Human,
     };
 
+    let order = querycache::ListOrder {
+        sort: match matches.opt_str("sort") {
+            Some(sort) => match &sort[..] {
+                "timestamp" => Some(querycache::ListSort::Timestamp),
+                "id" => Some(querycache::ListSort::Id),
+                _ => failure::bail!("invalid --sort, expected one of 'timestamp' or 'id'"),
+            },
+            None => None,
+        },
+        reverse: matches.opt_present("reverse"),
+        offset: match matches.opt_str("offset") {
+            Some(offset) => match offset.parse() {
+                Ok(offset) => offset,
+                Err(err) => failure::bail!("unable to parse --offset: {}", err),
+            },
+            None => 0,
+        },
+        limit: match matches.opt_str("limit") {
+            Some(limit) => match limit.parse() {
+                Ok(limit) => Some(limit),
+                Err(err) => failure::bail!("unable to parse --limit: {}", err),
+            },
+            None => None,
+        },
+    };
+
     let (primary_key_id, metadata_dctx) = match matches_to_opt_key(&matches)? {
         Some(key) => {
             let primary_key_id = key.primary_key_id();
@@ -460,18 +989,46 @@ fn list_main(args: Vec<String>) -> Result<(), failure::Error> {
         indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
     )?;
 
-    let mut query_cache = matches_to_query_cache(&matches)?;
-
     let mut serve_proc = matches_to_serve_process(&matches)?;
     let mut serve_out = serve_proc.stdout.as_mut().unwrap();
     let mut serve_in = serve_proc.stdin.as_mut().unwrap();
 
     progress.set_message(&"acquiring repository lock...");
-    client::open_repository(&mut serve_in, &mut serve_out, protocol::LockHint::Read)?;
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+
+    let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
     client::sync(progress, &mut query_cache, &mut serve_out, &mut serve_in)?;
     client::hangup(&mut serve_in)?;
 
+    // Item counts and sizes, by the values of the group by tags.
+    let mut groups: std::collections::BTreeMap<Vec<Option<String>>, (u64, u64)> =
+        std::collections::BTreeMap::new();
+
     let mut on_match = |_item_id: xid::Xid, tags: std::collections::BTreeMap<String, String>| {
+        if aggregate {
+            let key = match group_by {
+                Some(ref group_by) => group_by.iter().map(|t| tags.get(t).cloned()).collect(),
+                None => Vec::new(),
+            };
+            let group = groups.entry(key).or_default();
+            group.0 += 1;
+            // Items without a size, such as encrypted items, are not counted.
+            if let Some(size) = tags.get("size").and_then(|s| s.parse::<u64>().ok()) {
+                group.1 += size;
+            }
+            return Ok(());
+        }
+
+        if let Some(ref template) = template {
+            println!("{}", template.render(&tags));
+            return Ok(());
+        }
+
         let mut tags: Vec<(String, String)> = tags.into_iter().collect();
 
         // Custom sort to be more human friendly.
@@ -525,278 +1082,3589 @@ fn list_main(args: Vec<String>) -> Result<(), failure::Error> {
             list_encrypted: matches.opt_present("query-encrypted"),
             utc_timestamps: matches.opt_present("utc-timestamps"),
             now: chrono::Utc::now(),
+            order,
         },
         &mut on_match,
     )?;
 
+    if !aggregate {
+        return Ok(());
+    }
+
+    if group_by.is_none() && groups.is_empty() {
+        groups.insert(Vec::new(), (0, 0));
+    }
+
+    for (key, (n_items, size)) in groups.into_iter() {
+        let key: Vec<(String, Option<String>)> = match group_by {
+            Some(ref group_by) => group_by.iter().cloned().zip(key).collect(),
+            None => Vec::new(),
+        };
+        match list_format {
+            ListFormat::Human => {
+                let mut line = vec![format_group_key(&key)];
+                if count {
+                    line.push(format!("count={}", n_items));
+                }
+                if sum_size {
+                    line.push(format!("size={}", size));
+                }
+                line.retain(|s| !s.is_empty());
+                println!("{}", line.join(" "));
+            }
+            ListFormat::Jsonl => {
+                print!("{{");
+                if !key.is_empty() {
+                    print!("\"group\":{{");
+                    for (i, (k, v)) in key.iter().enumerate() {
+                        if i != 0 {
+                            print!(",");
+                        }
+                        print!(
+                            "{}:{}",
+                            serde_json::to_string(&k)?,
+                            serde_json::to_string(&v)?
+                        );
+                    }
+                    print!("}}");
+                }
+                let mut fields = Vec::new();
+                if count {
+                    fields.push(format!("\"count\":{}", n_items));
+                }
+                if sum_size {
+                    fields.push(format!("\"size\":{}", size));
+                }
+                if !key.is_empty() {
+                    print!(",");
+                }
+                println!("{}}}", fields.join(","));
+            }
+        }
+    }
+
     Ok(())
 }
 
-fn put_main(args: Vec<String>) -> Result<(), failure::Error> {
+// Group keys are shown as tags, with <none> for items that lack the tag.
+fn format_group_key(key: &[(String, Option<String>)]) -> String {
+    let key: Vec<String> = key
+        .iter()
+        .map(|(k, v)| match v {
+            Some(v) => format!(
+                "{}=\"{}\"",
+                k,
+                v.replace("\\", "\\\\").replace("\"", "\\\"")
+            ),
+            None => format!("{}=<none>", k),
+        })
+        .collect();
+    key.join(" ")
+}
+
+fn timeline_main(args: Vec<String>) -> Result<(), failure::Error> {
     let mut opts = default_cli_opts();
     repo_opts(&mut opts);
     opts.optopt(
         "k",
         "key",
-        "Primary or put key to encrypt data with.",
+        "primary or metadata key to decrypt item metadata with.",
         "PATH",
     );
-    opts.optflag("", "no-compression", "Disable compression.");
-    opts.optflag("", "no-default-tags", "Disable the default tag(s) 'name'.");
-
-    opts.optflag("q", "quiet", "Suppress progress indicators.");
-
-    opts.optflag(
-        "e",
-        "exec",
-        "Treat all arguments after '::' as a command to run, ensuring it succeeds before committing the send.",
-    );
-    opts.optflag(
-        "",
-        "no-stat-caching",
-        "Do not use stat caching to skip sending directories to the server.",
-    );
-    opts.optflag(
+    opts.optopt(
         "",
-        "no-send-log",
-        "Disable logging of previously sent data, implies --no-stat-cache.",
+        "group-by",
+        "Comma separated tags to group items by, defaults to 'hostname,name'.",
+        "TAGS",
     );
     opts.optopt(
         "",
-        "send-log",
-        "Use the file at PATH as a 'send log', used to skip data that was previously sent to the server.",
-        "PATH",
+        "gap",
+        "Report intervals between items longer than DURATION, \
+        defaults to twice the median interval of each group.",
+        "DURATION",
     );
-    opts.optmulti(
+    opts.optopt(
         "",
-        "exclude",
-        "Exclude directory entries matching the given glob pattern when saving a directory, may be passed multiple times.",
-        "PATTERN",
+        "format",
+        "Output format, valid values are 'human' or 'jsonl'.",
+        "FORMAT",
     );
+    query_opts(&mut opts);
 
-    let matches = parse_cli_opts(opts, &args);
+    let matches = parse_cli_opts(opts, &args[..]);
 
-    let tag_re = regex::Regex::new(r"^([a-zA-Z0-9\\-_]+)=(.+)$").unwrap();
+    if matches.opt_present("query-encrypted") {
+        failure::bail!("--query-encrypted is not supported, item timestamps are encrypted");
+    }
 
-    let mut tags = BTreeMap::<String, String>::new();
-    let mut source_args = Vec::new();
+    let list_format = match matches.opt_str("format") {
+        Some(f) => match &f[..] {
+            "jsonl" => ListFormat::Jsonl,
+            "human" => ListFormat::Human,
+            _ => failure::bail!("invalid --format, expected one of 'human' or 'jsonl'"),
+        },
+        None => ListFormat::Human,
+    };
 
-    {
-        let mut collecting_tags = true;
+    let group_by: Vec<String> = match matches.opt_str("group-by") {
+        Some(group_by) => group_by.split(',').map(|t| t.trim().to_string()).collect(),
+        None => vec!["hostname".to_string(), "name".to_string()],
+    };
+    if group_by.iter().any(|t| t.is_empty()) {
+        failure::bail!("invalid --group-by, tags must not be empty");
+    }
 
-        for a in &matches.free {
-            if collecting_tags && a == "::" {
-                collecting_tags = false;
+    let gap = match matches.opt_str("gap") {
+        Some(gap) => match humantime::parse_duration(&gap) {
+            Ok(gap) => Some(chrono::Duration::from_std(gap)?),
+            Err(err) => failure::bail!("unable to parse --gap: {}", err),
+        },
+        None => None,
+    };
+
+    let key = matches_to_key(&matches)?;
+    let primary_key_id = key.primary_key_id();
+    let metadata_dctx = match key {
+        keys::Key::PrimaryKeyV1(k) => crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk),
+        keys::Key::MetadataKeyV1(k) => {
+            crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk)
+        }
+        _ => failure::bail!("provided key is not valid for metadata decryption"),
+    };
+
+    let query = if !matches.free.is_empty() {
+        match query::parse(&matches.free.join("•")) {
+            Ok(query) => Some(query),
+            Err(e) => {
+                query::report_parse_error(e);
+                failure::bail!("query parse error");
+            }
+        }
+    } else {
+        None
+    };
+
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
+
+    let mut serve_proc = matches_to_serve_process(&matches)?;
+    let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+    let mut serve_in = serve_proc.stdin.as_mut().unwrap();
+
+    progress.set_message("acquiring repository lock...");
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+
+    let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+    client::sync(progress, &mut query_cache, &mut serve_out, &mut serve_in)?;
+    client::hangup(&mut serve_in)?;
+
+    let utc_timestamps = matches.opt_present("utc-timestamps");
+    let now = chrono::Utc::now();
+
+    let mut items = Vec::new();
+    let mut tx = query_cache.transaction()?;
+    tx.list_timestamped(
+        querycache::ListOptions {
+            primary_key_id: Some(primary_key_id),
+            query,
+            metadata_dctx: Some(metadata_dctx),
+            list_encrypted: false,
+            utc_timestamps,
+            now,
+            order: querycache::ListOrder::default(),
+        },
+        &mut |id, timestamp, tags| {
+            if let Some(timestamp) = timestamp {
+                items.push((id, timestamp, tags));
+            }
+            Ok(())
+        },
+    )?;
+
+    let format_timestamp = |ts: chrono::DateTime<chrono::Utc>| {
+        if utc_timestamps {
+            ts.format("%Y/%m/%d %T").to_string()
+        } else {
+            let ts: chrono::DateTime<chrono::Local> = chrono::DateTime::from(ts);
+            ts.format("%Y/%m/%d %T").to_string()
+        }
+    };
+
+    for group in timeline::group_items(&group_by, items) {
+        let gaps = match gap.or_else(|| timeline::default_gap_threshold(&group.entries)) {
+            Some(threshold) => timeline::find_gaps(&group.entries, threshold, now),
+            None => Vec::new(),
+        };
+
+        match list_format {
+            ListFormat::Human => {
+                println!("{}", format_group_key(&group.key));
+                let mut gaps = gaps.iter().peekable();
+                for (i, e) in group.entries.iter().enumerate() {
+                    println!("  {} {}", format_timestamp(e.timestamp), e.id);
+                    if let Some((_, d)) = gaps.next_if(|(gi, _)| *gi == i) {
+                        if i + 1 == group.entries.len() {
+                            println!(
+                                "  -- no item for {}, until now --",
+                                timeline::format_gap(*d)
+                            );
+                        } else {
+                            println!("  -- no item for {} --", timeline::format_gap(*d));
+                        }
+                    }
+                }
+            }
+            ListFormat::Jsonl => {
+                print!("{{\"group\":{{");
+                for (i, (k, v)) in group.key.iter().enumerate() {
+                    if i != 0 {
+                        print!(",");
+                    }
+                    print!(
+                        "{}:{}",
+                        serde_json::to_string(&k)?,
+                        serde_json::to_string(&v)?
+                    );
+                }
+                print!("}},\"items\":[");
+                for (i, e) in group.entries.iter().enumerate() {
+                    if i != 0 {
+                        print!(",");
+                    }
+                    print!(
+                        "{{\"id\":\"{}\",\"timestamp\":{}}}",
+                        e.id,
+                        serde_json::to_string(&format_timestamp(e.timestamp))?
+                    );
+                }
+                print!("],\"gaps\":[");
+                for (i, (gi, d)) in gaps.iter().enumerate() {
+                    if i != 0 {
+                        print!(",");
+                    }
+                    let before = match group.entries.get(gi + 1) {
+                        Some(e) => format!("\"{}\"", e.id),
+                        None => "null".to_string(),
+                    };
+                    print!(
+                        "{{\"after\":\"{}\",\"before\":{},\"seconds\":{}}}",
+                        group.entries[*gi].id,
+                        before,
+                        d.num_seconds()
+                    );
+                }
+                println!("]}}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Read a list of paths for --files-from, relative paths are
+// relative to the directory being sent.
+fn read_file_list(
+    files_from: &str,
+    root: &std::path::Path,
+) -> Result<Vec<std::path::PathBuf>, failure::Error> {
+    let mut list_data = Vec::new();
+    if files_from == "-" {
+        std::io::stdin().read_to_end(&mut list_data)?;
+    } else {
+        match std::fs::File::open(files_from) {
+            Ok(mut f) => f.read_to_end(&mut list_data)?,
+            Err(err) => failure::bail!("unable to open --files-from {}: {}", files_from, err),
+        };
+    }
+
+    let separator = if list_data.contains(&0) { 0 } else { b'\n' };
+
+    let mut file_list = Vec::new();
+    for p in list_data.split(|b| *b == separator) {
+        if p.is_empty() {
+            continue;
+        }
+        let p = std::path::Path::new(std::ffi::OsStr::from_bytes(p));
+        let p = path_clean::PathClean::clean(&root.join(p));
+        if !p.starts_with(root) {
+            failure::bail!("{} is not within {}", p.display(), root.display());
+        }
+        if p == root {
+            continue;
+        }
+        if let Err(err) = std::fs::symlink_metadata(&p) {
+            failure::bail!("unable to stat {}: {}", p.display(), err);
+        }
+        file_list.push(p);
+    }
+
+    Ok(file_list)
+}
+
+// Keep these in sync with the list in bupstash-put(1).
+const VCS_EXCLUSIONS: &[&str] = &[
+    "**/.git",
+    "**/.hg",
+    "**/.svn",
+    "**/.bzr",
+    "**/_darcs",
+    "**/CVS",
+];
+
+const BUILD_CACHE_EXCLUSIONS: &[&str] = &[
+    "**/node_modules",
+    "**/__pycache__",
+    "**/.pytest_cache",
+    "**/.mypy_cache",
+    "**/.tox",
+    "**/.gradle",
+    "**/.ccache",
+    "**/.sass-cache",
+];
+
+// A temporary directory removed along with its contents on drop.
+struct SpoolDir {
+    path: std::path::PathBuf,
+}
+
+impl Drop for SpoolDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+// Run each NAME=COMMAND in turn, writing the output to the file NAME of
+// a new directory which is then sent like any other directory.
+fn spool_exec_streams(
+    exec_streams: &[String],
+    ok_statuses: &[i32],
+    spool_parent: std::path::PathBuf,
+    progress: &indicatif::ProgressBar,
+) -> Result<SpoolDir, failure::Error> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let mut streams = Vec::new();
+    for s in exec_streams {
+        match s.find('=') {
+            Some(idx) => {
+                let (name, command) = (&s[..idx], &s[idx + 1..]);
+                if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                    failure::bail!("--exec-stream name {:?} is not a valid file name", name);
+                }
+                if streams.iter().any(|(n, _)| *n == name) {
+                    failure::bail!("--exec-stream name {:?} given more than once", name);
+                }
+                streams.push((name, command));
+            }
+            None => failure::bail!("--exec-stream option {:?} is not a NAME=COMMAND pair", s),
+        }
+    }
+
+    let mut path = spool_parent;
+    path.push(format!("bupstash-exec-streams-{}", xid::Xid::new()));
+    if let Err(err) = std::fs::DirBuilder::new().mode(0o700).create(&path) {
+        failure::bail!(
+            "unable to create exec stream spool directory {}: {}",
+            path.display(),
+            err
+        );
+    }
+    let spool_dir = SpoolDir { path };
+
+    for (name, command) in streams {
+        progress.set_message(&format!("exec: {}", command));
+        let mut stream_path = spool_dir.path.clone();
+        stream_path.push(name);
+        let out = std::fs::File::create(&stream_path)?;
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(std::process::Stdio::null())
+            .stdout(out)
+            .status()?;
+        match status.code() {
+            Some(code) if code == 0 || ok_statuses.contains(&code) => (),
+            Some(code) => failure::bail!("exec stream {:?} failed with status {}", name, code),
+            None => failure::bail!("exec stream {:?} was terminated by a signal", name),
+        }
+    }
+
+    Ok(spool_dir)
+}
+
+// Tags describing where a put came from, see 'put --auto-tags'.
+fn auto_tags() -> Result<Vec<(String, String)>, failure::Error> {
+    let mut tags = Vec::new();
+
+    let mut buf = [0u8; 256];
+    let hostname = nix::unistd::gethostname(&mut buf)?;
+    tags.push((
+        "hostname".to_string(),
+        hostname.to_string_lossy().to_string(),
+    ));
+
+    let uid = nix::unistd::getuid();
+    let user = match nix::unistd::User::from_uid(uid)? {
+        Some(user) => user.name,
+        None => uid.to_string(),
+    };
+    tags.push(("user".to_string(), user));
+
+    Ok(tags)
+}
+
+// One worker per cpu, but each worker buffers up to two chunks, so keep
+// the buffers within an eighth of physical memory on small machines.
+fn default_send_workers(max_chunk_size: usize) -> usize {
+    let n_cpus = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let (n_pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    if n_pages <= 0 || page_size <= 0 {
+        return n_cpus;
+    }
+    let memory = (n_pages as u64).saturating_mul(page_size as u64);
+    let by_memory = memory / 8 / (2 * max_chunk_size as u64);
+    n_cpus.min(by_memory.max(1) as usize)
+}
+
+fn put_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut hooks = client::PutHooks::default();
+    let result = put(args, &mut hooks);
+    let item_id = hooks.run_post(result)?;
+    println!("{}", item_id);
+    Ok(())
+}
+
+fn put(args: Vec<String>, hooks: &mut client::PutHooks) -> Result<xid::Xid, failure::Error> {
+    let mut opts = default_cli_opts();
+    repo_opts(&mut opts);
+    opts.optopt(
+        "k",
+        "key",
+        "Primary or put key to encrypt data with.",
+        "PATH",
+    );
+    opts.optflag("", "no-compression", "Disable compression.");
+    opts.optopt(
+        "",
+        "compression",
+        "Compress data with 'zstd' (the default), 'lz4' or 'none'.",
+        "ALGO",
+    );
+    opts.optflag(
+        "",
+        "always-compress",
+        "Compress files even if they look already compressed.",
+    );
+    opts.optopt(
+        "",
+        "compression-level",
+        "Zstd compression level from 1 to 22, higher is smaller but slower (default 3).",
+        "N",
+    );
+    opts.optflag("", "no-default-tags", "Disable the default tag(s) 'name'.");
+    opts.optflag(
+        "",
+        "exec-command-tag",
+        "In --exec mode, record the command line in the 'exec-command' tag.",
+    );
+    opts.optflag(
+        "",
+        "auto-tags",
+        "Add the tags 'hostname', 'user', 'put-timestamp' and 'source-path' unless given explicitly.",
+    );
+    opts.optflag(
+        "",
+        "timestamp-tag",
+        "Record the time the put started in the 'put-timestamp' tag.",
+    );
+    opts.optflag(
+        "",
+        "source-path-tag",
+        "Record the absolute path of the file or directory sent in the 'source-path' tag.",
+    );
+    opts.optopt(
+        "",
+        "note",
+        "Attach a free form encrypted note to the item.",
+        "TEXT",
+    );
+
+    opts.optmulti(
+        "",
+        "require-unique",
+        "Add the tag TAG=VALUE, failing if an item sent with the same key and tag already exists, may be passed multiple times.",
+        "TAG=VALUE",
+    );
+
+    opts.optmulti(
+        "",
+        "replace",
+        "Add the tag TAG=VALUE, removing items previously sent with '--replace TAG=VALUE' in the same transaction, may be passed multiple times.",
+        "TAG=VALUE",
+    );
+
+    opts.optopt(
+        "",
+        "expires",
+        "Add the tag 'expires' and have the repository remove the item during the first gc after DURATION (e.g. '90d').",
+        "DURATION",
+    );
+
+    opts.optflag("q", "quiet", "Suppress progress indicators.");
+
+    opts.optflag(
+        "e",
+        "exec",
+        "Treat all arguments after '::' as a command to run, ensuring it succeeds before committing the send.",
+    );
+    opts.optmulti(
+        "",
+        "exec-ok-status",
+        "Also accept STATUS as a successful exit status of --exec and --exec-stream commands, may be passed multiple times.",
+        "STATUS",
+    );
+    opts.optmulti(
+        "",
+        "exec-stream",
+        "Run COMMAND with 'sh -c', saving its output as the entry NAME of a single item, may be passed multiple times.",
+        "NAME=COMMAND",
+    );
+    opts.optopt(
+        "",
+        "exec-stream-dir",
+        "Directory the outputs of --exec-stream commands are spooled in until sent, defaults to TMPDIR.",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "no-stat-caching",
+        "Do not use stat caching to skip sending directories to the server.",
+    );
+    opts.optopt(
+        "",
+        "open-errors",
+        "What to do with files that cannot be opened, 'abort' (the default) or 'skip' with a warning.",
+        "POLICY",
+    );
+    opts.optopt(
+        "",
+        "stat-cache-check",
+        "How directories are found unchanged for the stat cache, 'ctime' (the default), \
+        'mtime-size', or 'rehash' to read every file again.",
+        "CHECK",
+    );
+    opts.optflag(
+        "",
+        "print-stats",
+        "Print how much data was sent and how much of it was new to the repository to stderr.",
+    );
+    opts.optflag(
+        "",
+        "no-send-log",
+        "Disable logging of previously sent data, implies --no-stat-caching.",
+    );
+    opts.optopt(
+        "",
+        "send-log",
+        "Use the file at PATH as a 'send log', used to skip data that was previously sent to the server.",
+        "PATH",
+    );
+    opts.optmulti(
+        "",
+        "exclude",
+        "Exclude directory entries matching the given glob pattern when saving a directory, may be passed multiple times.",
+        "PATTERN",
+    );
+    opts.optmulti(
+        "",
+        "include",
+        "Only save files matching the given glob pattern, or inside a matching directory, when saving a directory, may be passed multiple times.",
+        "PATTERN",
+    );
+    opts.optflag(
+        "",
+        "exclude-caches",
+        "Exclude directories containing a CACHEDIR.TAG file when saving a directory.",
+    );
+    opts.optflag(
+        "",
+        "deterministic",
+        "Zero modification times and owners in the tar stream of a directory, so identical trees send identical data.",
+    );
+    opts.optflag(
+        "",
+        "ignore-files",
+        "Read extra exclusions from .bupstashignore files when saving a directory.",
+    );
+    opts.optflag(
+        "",
+        "one-file-system",
+        "Do not descend into directories on other filesystems, including btrfs subvolumes.",
+    );
+    opts.optflag(
+        "",
+        "follow-symlinks",
+        "Save the files and directories symlinks point to instead of the symlinks themselves.",
+    );
+    opts.optflag(
+        "",
+        "selinux",
+        "Record the SELinux context of each directory entry, restored with 'tar --selinux -x'.",
+    );
+    opts.optflag(
+        "",
+        "acls",
+        "Record the POSIX ACLs of each directory entry, restored by 'bupstash restore'.",
+    );
+    opts.optflag(
+        "",
+        "honor-nodump",
+        "Skip files and directories with the nodump flag set (see chattr(1) or chflags(1)).",
+    );
+    opts.optflagopt(
+        "",
+        "verify-reads",
+        "Check each file is unchanged after reading it and send changed files again, \
+        with 'blocks' the first and last blocks are also read again.",
+        "blocks",
+    );
+    opts.optopt(
+        "",
+        "send-workers",
+        "Hash, compress and encrypt data with N threads, defaults to the number of cpus.",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "upload-rate",
+        "Limit the rate data is sent to the repository to RATE bytes per second, e.g. '500K'.",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "stat-workers",
+        "Read the metadata of directory entries with N threads, which speeds up saving \
+        directories on network filesystems, defaults to 1.",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "tail-deltas",
+        "Assume files that grew since the last put were only appended to, and only read the new data, \
+        checked by comparing samples of the old data.",
+    );
+    opts.optflag(
+        "",
+        "exclude-vcs",
+        "Exclude version control directories such as '.git', '.hg' and '.svn'.",
+    );
+    opts.optflag(
+        "",
+        "exclude-build-caches",
+        "Exclude common build and package caches such as 'node_modules' and '__pycache__'.",
+    );
+    opts.optopt(
+        "",
+        "changed-since",
+        "Record regular files not modified since TIMESTAMP (RFC3339), or since the \
+        item with the given id was sent, as unchanged instead of sending them.",
+        "TIMESTAMP|ID",
+    );
+    opts.optopt(
+        "",
+        "index-delta-from",
+        "Store the content index as changes to the index of the item with the given id, \
+        reducing the index size of frequent snapshots of large directories.",
+        "ID",
+    );
+    opts.optopt(
+        "",
+        "query-cache",
+        "Path to the query cache, used to look up items given to --changed-since and --index-delta-from.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "files-from",
+        "Only send the paths listed in the file at PATH ('-' for stdin), \
+        one path per line, or NUL separated.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "chunker",
+        "Algorithm that splits data into chunks, 'rollsum' (the default), 'fastcdc', \
+        or 'fixed' for chunks of the max size, the default for block devices.",
+        "ALGORITHM",
+    );
+    opts.optopt(
+        "",
+        "chunk-min-size",
+        "Never split chunks smaller than SIZE, defaults to '256K'.",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "chunk-max-size",
+        "Always split chunks at SIZE, defaults to '8M'.",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "chunk-mask-bits",
+        "Chunks average about the min size plus 2^N bytes, defaults to 20.",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "pre-hook",
+        "Run COMMAND with 'sh -c' before resolving the data source, the put fails if it fails.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "post-hook",
+        "Run COMMAND with 'sh -c' after the put completes or fails, even if the pre hook failed.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "progress",
+        "Progress output, 'bar' (the default) or 'json' for newline delimited JSON events on stderr.",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "size-hint",
+        "Expected size of data read from stdin or --exec, shown as a percentage and ETA in the progress bar, e.g. '20G'.",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "max-memory",
+        "Approximate memory budget for send buffers, chunk sizes are reduced to fit, e.g. '64M'.",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "tag-schema",
+        "Refuse to send items with tags not conforming to the tag schema at PATH.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "verify-sample",
+        "Read back and verify a random fraction of uploaded chunks after each checkpoint, \
+        RATE is a number between 0 and 1.",
+        "RATE",
+    );
+
+    let matches = parse_cli_opts(opts, &args);
+
+    let tag_re = regex::Regex::new(r"^([a-zA-Z0-9\\-_]+)=(.+)$").unwrap();
+
+    let mut tags = BTreeMap::<String, String>::new();
+    let mut source_args = Vec::new();
+
+    {
+        let mut collecting_tags = true;
+
+        for a in &matches.free {
+            if collecting_tags && a == "::" {
+                collecting_tags = false;
                 continue;
             }
-            if collecting_tags {
-                match tag_re.captures(&a) {
-                    Some(caps) => {
-                        let t = &caps[1];
-                        let v = &caps[2];
-                        tags.insert(t.to_string(), v.to_string());
+            if collecting_tags {
+                match tag_re.captures(&a) {
+                    Some(caps) => {
+                        let t = &caps[1];
+                        let v = &caps[2];
+                        tags.insert(t.to_string(), v.to_string());
+                    }
+                    None => {
+                        collecting_tags = false;
+                        source_args.push(a.to_string());
+                    }
+                }
+            } else {
+                source_args.push(a.to_string());
+            }
+        }
+    }
+
+    let algorithm = match matches.opt_str("compression") {
+        Some(_) if matches.opt_present("no-compression") => {
+            failure::bail!("--compression cannot be used with --no-compression")
+        }
+        Some(algorithm) => algorithm,
+        None if matches.opt_present("no-compression") => "none".to_string(),
+        None => "zstd".to_string(),
+    };
+    let compression = match (algorithm.as_str(), matches.opt_str("compression-level")) {
+        ("zstd", Some(level)) => match level.parse::<i32>() {
+            Ok(level) if (1..=crypto::MAX_ZSTD_LEVEL).contains(&level) => {
+                crypto::DataCompression::Zstd(level)
+            }
+            Ok(_) => failure::bail!(
+                "--compression-level must be between 1 and {}",
+                crypto::MAX_ZSTD_LEVEL
+            ),
+            Err(err) => failure::bail!("unable to parse --compression-level: {}", err),
+        },
+        ("zstd", None) => crypto::DataCompression::Zstd(crypto::DEFAULT_ZSTD_LEVEL),
+        ("lz4", None) => crypto::DataCompression::Lz4,
+        ("none", None) => crypto::DataCompression::None,
+        ("lz4", Some(_)) | ("none", Some(_)) => {
+            failure::bail!("--compression-level can only be used with zstd compression")
+        }
+        (algorithm, _) => failure::bail!(
+            "unknown compression '{}', expected 'zstd', 'lz4' or 'none'",
+            algorithm
+        ),
+    };
+
+    let upload_rate = match matches.opt_str("upload-rate") {
+        Some(rate) => match parse_size(&rate)? {
+            0 => failure::bail!("--upload-rate must be greater than 0"),
+            rate => Some(rate),
+        },
+        None => None,
+    };
+
+    let stat_workers: usize = match matches.opt_str("stat-workers") {
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => n,
+            Ok(_) => failure::bail!("--stat-workers must be greater than 0"),
+            Err(err) => failure::bail!("unable to parse --stat-workers: {}", err),
+        },
+        None => 1,
+    };
+
+    let exec_streams = matches.opt_strs("exec-stream");
+    // Exec streams are spooled to a new directory each time, so never hit the stat cache.
+    let use_stat_cache = !matches.opt_present("no-stat-caching") && exec_streams.is_empty();
+
+    let mut chunking = match matches.opt_str("max-memory") {
+        Some(max_memory) => {
+            chunker::ChunkingParams::with_memory_budget(parse_size(&max_memory)?.try_into()?)?
+        }
+        None => chunker::ChunkingParams::default(),
+    };
+    if let Some(algorithm) = matches.opt_str("chunker") {
+        chunking.algorithm = algorithm.parse()?;
+    }
+    let fixed_chunk_size = match matches.opt_str("chunk-max-size") {
+        Some(size) => parse_size(&size)?.try_into()?,
+        None => chunker::DEFAULT_FIXED_CHUNK_SIZE,
+    };
+    if chunking.algorithm == chunker::ChunkAlgorithm::Fixed {
+        if matches.opt_present("chunk-min-size") || matches.opt_present("chunk-mask-bits") {
+            failure::bail!("--chunk-min-size and --chunk-mask-bits cannot be used with --chunker fixed, set the chunk size with --chunk-max-size");
+        }
+        if matches.opt_present("max-memory") {
+            failure::bail!("--max-memory cannot be used with --chunker fixed");
+        }
+        chunking = chunker::ChunkingParams::fixed(fixed_chunk_size);
+        if let Err(err) = chunking.validate() {
+            failure::bail!("invalid chunking parameters: {}", err);
+        }
+    } else if ["chunk-min-size", "chunk-max-size", "chunk-mask-bits"]
+        .iter()
+        .any(|o| matches.opt_present(o))
+    {
+        if matches.opt_present("max-memory") {
+            failure::bail!("--max-memory cannot be used with --chunk-min-size, --chunk-max-size or --chunk-mask-bits");
+        }
+        if let Some(size) = matches.opt_str("chunk-min-size") {
+            chunking.min_size = parse_size(&size)?.try_into()?;
+        }
+        if let Some(size) = matches.opt_str("chunk-max-size") {
+            chunking.max_size = parse_size(&size)?.try_into()?;
+        }
+        if let Some(bits) = matches.opt_str("chunk-mask-bits") {
+            chunking.chunk_mask = match bits.parse::<u32>() {
+                Ok(bits) if (1..=31).contains(&bits) => (1 << bits) - 1,
+                Ok(_) => failure::bail!("--chunk-mask-bits must be between 1 and 31"),
+                Err(err) => failure::bail!("unable to parse --chunk-mask-bits: {}", err),
+            };
+        }
+        if let Err(err) = chunking.validate() {
+            failure::bail!("invalid chunking parameters: {}", err);
+        }
+    }
+
+    let send_workers: usize = match matches.opt_str("send-workers") {
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => n,
+            Ok(_) => failure::bail!("--send-workers must be greater than 0"),
+            Err(err) => failure::bail!("unable to parse --send-workers: {}", err),
+        },
+        // Chunks in flight between workers are not part of a memory budget.
+        None if matches.opt_present("max-memory") => 1,
+        None => default_send_workers(chunking.max_size),
+    };
+
+    let verify_sample_rate: f64 = match matches.opt_str("verify-sample") {
+        Some(rate) => match rate.parse() {
+            Ok(rate) if (0.0..=1.0).contains(&rate) => rate,
+            Ok(_) => failure::bail!("--verify-sample must be between 0 and 1"),
+            Err(err) => failure::bail!("unable to parse --verify-sample: {}", err),
+        },
+        None => 0.0,
+    };
+
+    let (mut changed_since, changed_since_id) = match matches.opt_str("changed-since") {
+        Some(reference) => {
+            let reference = reference.strip_prefix("id=").unwrap_or(&reference);
+            match xid::Xid::parse(reference) {
+                Ok(id) => (None, Some(id)),
+                Err(_) => match chrono::DateTime::parse_from_rfc3339(reference) {
+                    Ok(ts) => (Some(ts.with_timezone(&chrono::Utc)), None),
+                    Err(err) => failure::bail!(
+                        "unable to parse --changed-since, expected an item id or RFC3339 timestamp: {}",
+                        err
+                    ),
+                },
+            }
+        }
+        None => (None, None),
+    };
+
+    let index_delta_from = match matches.opt_str("index-delta-from") {
+        Some(id) => match xid::Xid::parse(id.strip_prefix("id=").unwrap_or(&id)) {
+            Ok(id) => Some(id),
+            Err(err) => failure::bail!("unable to parse --index-delta-from id: {}", err),
+        },
+        None => None,
+    };
+
+    let checkpoint_bytes: u64 = match std::env::var("BUPSTASH_CHECKPOINT_BYTES") {
+        Ok(v) => match v.parse() {
+            Ok(v) => v,
+            Err(err) => failure::bail!("unable to parse BUPSTASH_CHECKPOINT_BYTES: {}", err),
+        },
+        Err(_) => 1073741824,
+    };
+
+    let checkpoint_interval = match std::env::var("BUPSTASH_CHECKPOINT_SECONDS") {
+        Ok(v) => match v.parse() {
+            Ok(0) => None,
+            Ok(v) => Some(std::time::Duration::from_secs(v)),
+            Err(err) => failure::bail!("unable to parse BUPSTASH_CHECKPOINT_SECONDS: {}", err),
+        },
+        Err(_) => Some(std::time::Duration::from_secs(300)),
+    };
+
+    let key = matches_to_key(&matches)?;
+    let primary_key_id = key.primary_key_id();
+    let send_key_id = key.id();
+    let (hash_key, data_ectx, data_dctx, metadata_ectx, mut metadata_dctx) = match key {
+        keys::Key::PrimaryKeyV1(k) => {
+            let hash_key = crypto::derive_hash_key(&k.hash_key_part_1, &k.hash_key_part_2);
+            let data_ectx = crypto::EncryptionContext::new(&k.data_pk, &k.data_psk, k.nonce_mode);
+            let data_dctx = crypto::DecryptionContext::new(k.data_sk, k.data_psk.clone());
+            let metadata_ectx =
+                crypto::EncryptionContext::new(&k.metadata_pk, &k.metadata_psk, k.nonce_mode);
+            let metadata_dctx = crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk);
+            (
+                hash_key,
+                data_ectx,
+                Some(data_dctx),
+                metadata_ectx,
+                Some(metadata_dctx),
+            )
+        }
+        keys::Key::PutKeyV1(k) => {
+            let hash_key = crypto::derive_hash_key(&k.hash_key_part_1, &k.hash_key_part_2);
+            let data_ectx = crypto::EncryptionContext::new(&k.data_pk, &k.data_psk, k.nonce_mode);
+            let metadata_ectx =
+                crypto::EncryptionContext::new(&k.metadata_pk, &k.metadata_psk, k.nonce_mode);
+            (hash_key, data_ectx, None, metadata_ectx, None)
+        }
+        _ => failure::bail!("can only send data with a primary-key or put-key."),
+    };
+
+    if verify_sample_rate > 0.0 && data_dctx.is_none() {
+        failure::bail!("--verify-sample requires a primary key to decrypt sent data.");
+    }
+
+    if changed_since_id.is_some() && metadata_dctx.is_none() {
+        failure::bail!(
+            "--changed-since with an item id requires a primary key to read the item timestamp."
+        );
+    }
+
+    if index_delta_from.is_some() && data_dctx.is_none() {
+        failure::bail!("--index-delta-from requires a primary key to read the base index.");
+    }
+
+    let default_tags = !matches.opt_present("no-default-tags");
+
+    let mut data_source: client::DataSource;
+    let mut source_path = None;
+
+    let size_hint = match matches.opt_str("size-hint") {
+        Some(size_hint) => Some(parse_size(&size_hint)?),
+        None => None,
+    };
+
+    let progress = match size_hint {
+        Some(size_hint) => {
+            let progress = matches_to_progress_bar(
+                &matches,
+                indicatif::ProgressStyle::default_bar().template(
+                    "[{elapsed_precise}] [{bar:30}] {bytes}/{total_bytes} ({eta}) {wide_msg}",
+                ),
+            )?;
+            progress.set_length(size_hint);
+            progress
+        }
+        None => matches_to_progress_bar(
+            &matches,
+            indicatif::ProgressStyle::default_spinner()
+                .template("[{elapsed_precise}] {wide_msg} [{bytes} sent, {bytes_per_sec}]"),
+        )?,
+    };
+
+    let start_time = std::time::Instant::now();
+    let put_stats = client::SharedPutStats::default();
+
+    let json_progress = match matches.opt_str("progress").as_deref() {
+        None | Some("bar") => None,
+        Some("json") => {
+            // Events are written even when stderr is not a terminal.
+            progress.disable_steady_tick();
+            progress.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+            Some(client::JsonProgress::new(
+                progress.clone(),
+                put_stats.clone(),
+            ))
+        }
+        Some(_) => failure::bail!("invalid --progress, expected 'bar' or 'json'"),
+    };
+
+    hooks.run_pre(
+        &progress,
+        matches.opt_str("pre-hook"),
+        matches.opt_str("post-hook"),
+    )?;
+
+    let mut ok_statuses = Vec::new();
+    for status in matches.opt_strs("exec-ok-status") {
+        match status.parse() {
+            Ok(status) => ok_statuses.push(status),
+            Err(err) => failure::bail!("unable to parse --exec-ok-status: {}", err),
+        }
+    }
+
+    // Kept until the put is complete, removing the spooled outputs on drop.
+    let mut _exec_stream_dir = None;
+
+    if !exec_streams.is_empty() {
+        if matches.opt_present("exec") || !source_args.is_empty() {
+            failure::bail!("--exec-stream cannot be combined with other data sources.");
+        }
+        let spool_parent = match matches.opt_str("exec-stream-dir") {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => match std::env::var_os("BUPSTASH_EXEC_STREAM_DIR") {
+                Some(dir) => std::path::PathBuf::from(dir),
+                None => std::env::temp_dir(),
+            },
+        };
+        let spool_dir = spool_exec_streams(&exec_streams, &ok_statuses, spool_parent, &progress)?;
+        data_source = client::DataSource::Directory {
+            paths: vec![spool_dir.path.clone()],
+            exclusions: Vec::new(),
+            inclusions: Vec::new(),
+            file_list: None,
+        };
+        _exec_stream_dir = Some(spool_dir);
+    } else if matches.opt_present("exec") {
+        if source_args.is_empty() {
+            failure::bail!("--exec requires a command to run.");
+        }
+        if matches.opt_present("exec-command-tag") {
+            let quoted_args: Vec<String> = source_args
+                .iter()
+                .map(|x| shlex::quote(x).to_string())
+                .collect();
+            tags.entry("exec-command".to_string())
+                .or_insert_with(|| quoted_args.join(" "));
+        }
+        data_source = client::DataSource::Subprocess {
+            args: source_args,
+            ok_statuses,
+            status_tag: default_tags,
+        }
+    } else if source_args.is_empty() {
+        failure::bail!("data sources should be a file, directory, or command (use '-' for stdin).");
+    } else {
+        if source_args.len() > 1 && source_args.iter().any(|a| a == "-") {
+            failure::bail!("stdin cannot be combined with other data sources.");
+        }
+
+        if source_args[0] == "-" {
+            data_source = client::DataSource::Readable {
+                description: "<stdin>".to_string(),
+                data: Box::new(Box::new(std::io::stdin())),
+            };
+        } else {
+            let mut input_paths = Vec::new();
+            for a in source_args.iter() {
+                input_paths.push(std::fs::canonicalize(a)?);
+            }
+            let input_path = input_paths[0].clone();
+            source_path = Some(
+                input_paths
+                    .iter()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            );
+
+            let md = match std::fs::metadata(&input_path) {
+                Ok(md) => md,
+                Err(err) => failure::bail!("unable to open input source {:?}: {}", input_path, err),
+            };
+
+            let name = match input_path.file_name() {
+                Some(name) => name.to_string_lossy().to_string(),
+                None => "rootfs".to_string(),
+            };
+
+            let mut exclusions = Vec::new();
+            let mut inclusions = Vec::new();
+
+            let mut exclusion_patterns = matches.opt_strs("exclude");
+            if matches.opt_present("exclude-vcs") {
+                exclusion_patterns.extend(VCS_EXCLUSIONS.iter().map(|p| p.to_string()));
+            }
+            if matches.opt_present("exclude-build-caches") {
+                exclusion_patterns.extend(BUILD_CACHE_EXCLUSIONS.iter().map(|p| p.to_string()));
+            }
+
+            for e in exclusion_patterns {
+                match glob::Pattern::new(&e) {
+                    Ok(pattern) => exclusions.push(pattern),
+                    Err(err) => {
+                        failure::bail!("--exclude option {:?} is not a valid glob: {}", e, err)
+                    }
+                }
+            }
+
+            for i in matches.opt_strs("include") {
+                match glob::Pattern::new(&i) {
+                    Ok(pattern) => inclusions.push(pattern),
+                    Err(err) => {
+                        failure::bail!("--include option {:?} is not a valid glob: {}", i, err)
+                    }
+                }
+            }
+
+            if input_paths.len() > 1 {
+                // Each directory is stored under its own name, so the names must differ.
+                let mut names = std::collections::HashSet::new();
+                for p in input_paths.iter() {
+                    match std::fs::metadata(p) {
+                        Ok(md) if md.is_dir() => (),
+                        Ok(_) => failure::bail!(
+                            "{} is not a directory, only directories can be saved together",
+                            p.display()
+                        ),
+                        Err(err) => {
+                            failure::bail!("unable to open input source {:?}: {}", p, err)
+                        }
+                    }
+                    match p.file_name() {
+                        Some(name) if names.insert(name.to_os_string()) => (),
+                        Some(_) => failure::bail!(
+                            "{} has the same name as another directory being saved",
+                            p.display()
+                        ),
+                        None => failure::bail!(
+                            "{} cannot be saved together with other directories",
+                            p.display()
+                        ),
+                    }
+                }
+                if matches.opt_present("files-from") {
+                    failure::bail!("--files-from requires a single directory data source");
+                }
+
+                data_source = client::DataSource::Directory {
+                    paths: input_paths,
+                    exclusions,
+                    inclusions,
+                    file_list: None,
+                };
+            } else if md.is_dir() {
+                if default_tags {
+                    tags.insert("name".to_string(), name + ".tar");
+                }
+
+                let file_list = match matches.opt_str("files-from") {
+                    Some(files_from) => Some(read_file_list(&files_from, &input_path)?),
+                    None => None,
+                };
+
+                data_source = client::DataSource::Directory {
+                    paths: vec![input_path],
+                    exclusions,
+                    inclusions,
+                    file_list,
+                };
+            } else if md.is_file() {
+                if matches.opt_present("files-from") {
+                    failure::bail!("--files-from requires a directory data source");
+                }
+                if matches.opt_present("changed-since") {
+                    failure::bail!("--changed-since requires a directory data source");
+                }
+
+                if default_tags {
+                    tags.insert("name".to_string(), name);
+                }
+
+                data_source = client::DataSource::File {
+                    data: std::fs::File::open(&input_path)?,
+                    path: input_path,
+                };
+            } else if md.file_type().is_block_device() {
+                if matches.opt_present("files-from") {
+                    failure::bail!("--files-from requires a directory data source");
+                }
+                if matches.opt_present("changed-since") {
+                    failure::bail!("--changed-since requires a directory data source");
+                }
+
+                if default_tags {
+                    tags.insert("name".to_string(), name);
+                }
+
+                // Data on disks moves in whole blocks, which fixed size chunks
+                // find again where content defined chunks could split them anywhere.
+                if !matches.opt_present("chunker") {
+                    if matches.opt_present("chunk-min-size")
+                        || matches.opt_present("chunk-mask-bits")
+                        || matches.opt_present("max-memory")
+                    {
+                        failure::bail!("block devices use --chunker fixed by default, only --chunk-max-size can be set, or pass --chunker explicitly");
+                    }
+                    chunking = chunker::ChunkingParams::fixed(fixed_chunk_size);
+                    if let Err(err) = chunking.validate() {
+                        failure::bail!("invalid chunking parameters: {}", err);
+                    }
+                }
+
+                let mut data = std::fs::File::open(&input_path)?;
+                let size = fsutil::file_size(&mut data)?;
+                progress.set_style(indicatif::ProgressStyle::default_bar().template(
+                    "[{elapsed_precise}] [{bar:30}] {bytes}/{total_bytes} ({eta}) {wide_msg}",
+                ));
+                progress.set_length(size);
+
+                data_source = client::DataSource::BlockDevice {
+                    data,
+                    path: input_path,
+                };
+            } else {
+                failure::bail!(
+                    "{} is not a file, a directory or a block device",
+                    source_args[0]
+                );
+            }
+        }
+    };
+
+    if size_hint.is_some() {
+        match data_source {
+            client::DataSource::Readable { .. } | client::DataSource::Subprocess { .. } => (),
+            _ => failure::bail!("--size-hint requires stdin or --exec as the data source"),
+        }
+    }
+
+    if index_delta_from.is_some() {
+        match data_source {
+            client::DataSource::Directory { .. } => (),
+            _ => failure::bail!("--index-delta-from requires a directory data source"),
+        }
+    }
+
+    // Lets machines opt in once instead of in every backup script.
+    let auto_tags_env = match std::env::var_os("BUPSTASH_AUTO_TAGS") {
+        Some(v) => !v.is_empty() && v != "0",
+        None => false,
+    };
+
+    let all_auto_tags = matches.opt_present("auto-tags") || auto_tags_env;
+    if all_auto_tags {
+        for (t, v) in auto_tags()? {
+            tags.entry(t).or_insert(v);
+        }
+    }
+    if all_auto_tags || matches.opt_present("timestamp-tag") {
+        tags.entry("put-timestamp".to_string()).or_insert_with(|| {
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        });
+    }
+    // Stdin and --exec have no source path.
+    if all_auto_tags || matches.opt_present("source-path-tag") {
+        if let Some(source_path) = source_path {
+            tags.entry("source-path".to_string()).or_insert(source_path);
+        }
+    }
+
+    let mut unique_tags = Vec::new();
+    let mut replace_tags = Vec::new();
+    for (opt, addresses) in [
+        ("require-unique", &mut unique_tags),
+        ("replace", &mut replace_tags),
+    ]
+    .iter_mut()
+    {
+        for a in matches.opt_strs(opt) {
+            match tag_re.captures(&a) {
+                Some(caps) => {
+                    let t = &caps[1];
+                    let v = &caps[2];
+                    tags.insert(t.to_string(), v.to_string());
+                    addresses.push(client::tag_address(&hash_key, t, v));
+                }
+                None => failure::bail!("--{} option {:?} is not a TAG=VALUE pair", opt, a),
+            }
+        }
+    }
+
+    let expires = match matches.opt_str("expires") {
+        Some(duration) => {
+            let duration = match humantime::parse_duration(&duration) {
+                Ok(duration) => duration,
+                Err(err) => failure::bail!("unable to parse --expires duration: {}", err),
+            };
+            let expires = chrono::Utc::now() + chrono::Duration::from_std(duration)?;
+            tags.insert(
+                "expires".to_string(),
+                expires.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            );
+            Some(expires)
+        }
+        None => None,
+    };
+
+    let note = matches.opt_str("note");
+
+    // Tags and the note are checked against the schemas and size limit by client::send.
+    let tag_schema = match matches.opt_str("tag-schema") {
+        Some(p) => Some(std::path::PathBuf::from(p)),
+        None => std::env::var_os("BUPSTASH_TAG_SCHEMA").map(std::path::PathBuf::from),
+    };
+    let tag_schema = match tag_schema {
+        Some(tag_schema) => {
+            let text = match std::fs::read_to_string(&tag_schema) {
+                Ok(text) => text,
+                Err(err) => failure::bail!(
+                    "unable to read tag schema {}: {}",
+                    tag_schema.display(),
+                    err
+                ),
+            };
+            Some(tagschema::parse(
+                &format!("tag schema {}", tag_schema.display()),
+                &text,
+            )?)
+        }
+        None => None,
+    };
+
+    let mut serve_proc = matches_to_serve_process(&matches)?;
+    let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+    let mut serve_in = serve_proc.stdin.as_mut().unwrap();
+
+    progress.set_message(&"acquiring repository lock...");
+    if let Some(ref json_progress) = json_progress {
+        json_progress.phase("locking");
+    }
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Write,
+    )?;
+
+    let send_log = if matches.opt_present("no-send-log") {
+        None
+    } else {
+        Some(matches_to_send_log(
+            &matches,
+            &repo_info.repository_id,
+            &primary_key_id,
+        )?)
+    };
+
+    if let Some(changed_since_id) = changed_since_id {
+        let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+        client::sync(
+            progress.clone(),
+            &mut query_cache,
+            &mut serve_out,
+            &mut serve_in,
+        )?;
+        let metadata = match query_cache
+            .transaction()?
+            .lookup_item_by_id(&changed_since_id)?
+        {
+            Some(metadata) => metadata,
+            None => failure::bail!("--changed-since item {} does not exist", changed_since_id),
+        };
+        if metadata.plain_text_metadata().primary_key_id != primary_key_id {
+            failure::bail!("--changed-since item was not sent with the same primary key");
+        }
+        // The item timestamp is taken after its upload finished, files modified during
+        // that put could be older than it, only the start time is a safe cutoff.
+        changed_since = metadata
+            .decrypt_metadata(metadata_dctx.as_mut().unwrap())?
+            .start_timestamp;
+        if changed_since.is_none() {
+            let msg =
+                "--changed-since item does not record when its put started, sending all files";
+            if progress.is_hidden() {
+                eprintln!("{}", msg);
+            } else {
+                progress.println(msg.to_string());
+            }
+        }
+    }
+
+    let index_delta_base = match index_delta_from {
+        Some(index_delta_from) => {
+            let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+            let decode_workers = client::SharedDecodeWorkers::default();
+            let request_ctx = || client::DataRequestContext {
+                progress: progress.clone(),
+                primary_key_id,
+                hash_key_part_1: hash_key.part1.clone(),
+                data_dctx: data_dctx.clone().unwrap(),
+                metadata_dctx: metadata_dctx.clone().unwrap(),
+                decode_workers: decode_workers.clone(),
+            };
+            // Syncing drops cached indexes of removed items and everything cached
+            // before a gc, so the base index chunks still exist in the repository.
+            client::sync(
+                progress.clone(),
+                &mut query_cache,
+                &mut serve_out,
+                &mut serve_in,
+            )?;
+            let mut tx = query_cache.transaction()?;
+            if tx.lookup_item_by_id(&index_delta_from)?.is_none() {
+                failure::bail!(
+                    "--index-delta-from item {} does not exist",
+                    index_delta_from
+                );
+            }
+            let gc_generation = tx.current_gc_generation()?;
+            let cached = tx.lookup_content_index(&index_delta_from)?;
+            drop(tx);
+            let (metadata, chunks) = match cached {
+                Some(cached) => cached,
+                None => {
+                    let (metadata, chunks, _) = client::request_cacheable_index(
+                        request_ctx(),
+                        index_delta_from,
+                        &mut serve_out,
+                        &mut serve_in,
+                    )?;
+                    client::cache_content_index(
+                        &progress,
+                        &mut query_cache,
+                        &index_delta_from,
+                        &metadata,
+                        &chunks,
+                    );
+                    (metadata, chunks)
+                }
+            };
+            let base = client::index_delta_base(
+                request_ctx(),
+                &metadata,
+                &chunks,
+                &hash_key,
+                gc_generation,
+            )?;
+            if base.delta_depth < client::MAX_INDEX_DELTA_DEPTH {
+                Some(base)
+            } else {
+                progress.println(format!(
+                    "index of {} has {} deltas, sending a full index",
+                    index_delta_from, base.delta_depth
+                ));
+                None
+            }
+        }
+        None => None,
+    };
+
+    let stat_cache_check = match matches.opt_str("stat-cache-check").as_deref() {
+        None | Some("ctime") => client::StatCacheCheck::Ctime,
+        Some("mtime-size") => client::StatCacheCheck::MtimeSize,
+        Some("rehash") => client::StatCacheCheck::Rehash,
+        Some(_) => {
+            failure::bail!("invalid --stat-cache-check, expected 'ctime', 'mtime-size' or 'rehash'")
+        }
+    };
+
+    let open_errors = match matches.opt_str("open-errors").as_deref() {
+        None | Some("abort") => client::OpenErrors::Abort,
+        Some("skip") => client::OpenErrors::Skip,
+        Some(_) => failure::bail!("invalid --open-errors, expected 'abort' or 'skip'"),
+    };
+
+    let verify_reads = if matches.opt_present("verify-reads") {
+        match matches.opt_str("verify-reads").as_deref() {
+            None | Some("stat") => Some(client::VerifyReads::Stat),
+            Some("blocks") => Some(client::VerifyReads::Blocks),
+            Some(_) => failure::bail!("invalid --verify-reads, expected 'stat' or 'blocks'"),
+        }
+    } else {
+        None
+    };
+
+    let mut ctx = client::SendContext {
+        progress: progress.clone(),
+        compression,
+        skip_incompressible: !matches.opt_present("always-compress"),
+        checkpoint_bytes,
+        checkpoint_interval,
+        chunking,
+        verify_sample_rate,
+        data_dctx,
+        changed_since,
+        honor_nodump: matches.opt_present("honor-nodump"),
+        selinux: matches.opt_present("selinux"),
+        acls: matches.opt_present("acls"),
+        ignore_files: matches.opt_present("ignore-files"),
+        exclude_caches: matches.opt_present("exclude-caches"),
+        deterministic: matches.opt_present("deterministic"),
+        one_file_system: matches.opt_present("one-file-system"),
+        follow_symlinks: matches.opt_present("follow-symlinks"),
+        stat_workers,
+        unique_tags,
+        replace_tags,
+        expires,
+        tag_schema,
+        index_delta_base,
+        verify_reads,
+        tail_deltas: matches.opt_present("tail-deltas"),
+        open_errors,
+        skipped_files: std::collections::BTreeSet::new(),
+        json_progress: json_progress.clone(),
+        stats: put_stats.clone(),
+        chunk_workers: if send_workers > 1 {
+            Some(client::ChunkWorkers::new(
+                send_workers,
+                &hash_key,
+                &data_ectx,
+            )?)
+        } else {
+            None
+        },
+        use_stat_cache,
+        stat_cache_check,
+        primary_key_id,
+        send_key_id,
+        hash_key,
+        data_ectx,
+        metadata_ectx,
+    };
+
+    let ack = match upload_rate {
+        Some(upload_rate) => client::send(
+            &mut ctx,
+            &mut serve_out,
+            &mut ratelimit::RateLimitedWriter::new(&mut serve_in, upload_rate),
+            send_log,
+            tags,
+            note,
+            &mut data_source,
+        )?,
+        None => client::send(
+            &mut ctx,
+            &mut serve_out,
+            &mut serve_in,
+            send_log,
+            tags,
+            note,
+            &mut data_source,
+        )?,
+    };
+    client::hangup(&mut serve_in)?;
+
+    let bytes_read = progress.position();
+    progress.finish_and_clear();
+
+    if let Some(ref json_progress) = json_progress {
+        json_progress.done(&ack, start_time.elapsed());
+    }
+
+    let put_stats = put_stats.lock().unwrap().clone();
+    let stats = ack.stats;
+    let usage = ack.usage;
+
+    if matches.opt_present("print-stats") {
+        eprintln!("{} bytes read", bytes_read);
+        eprintln!(
+            "{} chunks skipped by the send log",
+            put_stats.chunks_deduped
+        );
+        if bytes_read > 0 {
+            eprintln!(
+                "{:.1}% of bytes read were sent",
+                put_stats.bytes_sent as f64 * 100.0 / bytes_read as f64
+            );
+        }
+        eprintln!("{:.2} seconds elapsed", start_time.elapsed().as_secs_f64());
+        eprintln!("{} chunks sent", stats.chunks_received);
+        eprintln!("{} bytes sent", stats.bytes_received);
+        if let Some(chunks_added) = stats.chunks_added {
+            eprintln!("{} chunks added", chunks_added);
+            eprintln!(
+                "{} chunks already present",
+                stats.chunks_received.saturating_sub(chunks_added)
+            );
+        }
+        if let Some(bytes_added) = stats.bytes_added {
+            eprintln!("{} bytes added", bytes_added);
+        }
+        eprintln!("{} items in repository", usage.item_count);
+        if let Some(data_bytes) = usage.data_bytes {
+            eprintln!("{} bytes in repository", data_bytes);
+        }
+        if let Some(fs_bytes_available) = usage.fs_bytes_available {
+            eprintln!("{} bytes available", fs_bytes_available);
+        }
+    }
+
+    if !ctx.skipped_files.is_empty() {
+        eprintln!(
+            "warning: {} file(s) could not be opened and were not saved:",
+            ctx.skipped_files.len()
+        );
+        for path in ctx.skipped_files.iter() {
+            eprintln!("{}", path.display());
+        }
+    }
+
+    if let (Some(available), Some(total)) = (usage.fs_bytes_available, usage.fs_bytes_total) {
+        const LOW_SPACE_PERCENT: u64 = 10;
+        if available.saturating_mul(100) < total.saturating_mul(LOW_SPACE_PERCENT) {
+            eprintln!(
+                "warning: the repository disk is almost full, {} of {} available",
+                indicatif::HumanBytes(available),
+                indicatif::HumanBytes(total)
+            );
+        }
+    }
+
+    Ok(ack.item_id)
+}
+
+fn get_main(args: Vec<String>) -> Result<(), failure::Error> {
+    if std::env::var_os("BUPSTASH_SANDBOXED_GET").is_some() {
+        return sandboxed_get_child();
+    }
+
+    let mut opts = default_cli_opts();
+    repo_opts(&mut opts);
+    query_opts(&mut opts);
+    opts.optopt("k", "key", "Primary key to decrypt data with.", "PATH");
+    opts.optmulti(
+        "",
+        "pick",
+        "Pick a file or directory from a directory snapshot, may be a glob and given more than once.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "mirror",
+        "A local copy of the repository to read data from before asking the repository.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "restore-into",
+        "Extract the directory snapshot into DIR, or into a directory on another host given as [USER@]HOST:DIR.",
+        "TARGET",
+    );
+    opts.optopt(
+        "",
+        "split-size",
+        "Write the output to files of at most SIZE bytes named by --output-prefix, e.g. '4G'.",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "output-prefix",
+        "Prefix of the files written with --split-size, followed by a 3 digit number.",
+        "PREFIX",
+    );
+    opts.optflag(
+        "",
+        "allow-many",
+        "Get all directory snapshots matching the query, each in a directory named by its id.",
+    );
+    opts.optflag(
+        "",
+        "sandbox",
+        "Decode and extract the item in a confined child process, see the manual.",
+    );
+
+    let matches = parse_cli_opts(opts, &args[..]);
+    let allow_many = matches.opt_present("allow-many");
+    if allow_many && matches.opt_present("pick") {
+        failure::bail!("--pick cannot be used with --allow-many");
+    }
+    let sandbox = matches.opt_present("sandbox");
+    if sandbox {
+        if matches.opt_present("mirror") || allow_many || matches.opt_present("split-size") {
+            failure::bail!("--sandbox cannot be used with --mirror, --allow-many or --split-size");
+        }
+        if let Some(target) = matches.opt_str("restore-into") {
+            if regex::Regex::new(REMOTE_RESTORE_TARGET)?.is_match(&target) {
+                failure::bail!("--sandbox requires --restore-into to be a local directory");
+            }
+        }
+    }
+
+    let mut split_writer = match (
+        matches.opt_str("split-size"),
+        matches.opt_str("output-prefix"),
+    ) {
+        (Some(split_size), Some(prefix)) => {
+            if matches.opt_present("restore-into") {
+                failure::bail!("--split-size cannot be used with --restore-into");
+            }
+            let split_size = parse_size(&split_size)?;
+            if split_size == 0 {
+                failure::bail!("--split-size must be greater than zero");
+            }
+            Some(SplitWriter::new(prefix, split_size))
+        }
+        (None, None) => None,
+        (Some(_), None) => failure::bail!("--split-size requires --output-prefix"),
+        (None, Some(_)) => failure::bail!("--output-prefix requires --split-size"),
+    };
+
+    let key = matches_to_key(&matches)?;
+    let primary_key_id = key.primary_key_id();
+    let (hash_key_part_1, data_dctx, metadata_dctx) = match &key {
+        keys::Key::PrimaryKeyV1(k) => {
+            let hash_key_part_1 = k.hash_key_part_1.clone();
+            let data_dctx = crypto::DecryptionContext::new(k.data_sk.clone(), k.data_psk.clone());
+            let metadata_dctx =
+                crypto::DecryptionContext::new(k.metadata_sk.clone(), k.metadata_psk.clone());
+            (hash_key_part_1, data_dctx, metadata_dctx)
+        }
+        _ => failure::bail!("provided key is not a decryption key"),
+    };
+    let decode_workers = client::SharedDecodeWorkers::default();
+
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
+
+    let (id, query) = matches_to_id_and_query(&matches)?;
+    let mut serve_proc = matches_to_serve_process(&matches)?;
+    let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+    let mut serve_in = serve_proc.stdin.as_mut().unwrap();
+
+    progress.set_message(&"acquiring repository lock...");
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+
+    let mirror = matches.opt_str("mirror").map(std::path::PathBuf::from);
+
+    // Reading from a mirror, or getting many items, needs the item metadata
+    // up front, which we get from the query cache.
+    let mut query_cache = if id.is_none() || mirror.is_some() || allow_many {
+        let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+        client::sync(
+            progress.clone(),
+            &mut query_cache,
+            &mut serve_out,
+            &mut serve_in,
+        )?;
+        Some(query_cache)
+    } else {
+        None
+    };
+
+    let ids = match (id, query) {
+        (Some(id), _) => vec![id],
+        (_, query) => query_cache_matching_ids(
+            &matches,
+            query_cache.as_mut().unwrap(),
+            primary_key_id,
+            &metadata_dctx,
+            query,
+            allow_many,
+        )?,
+    };
+
+    if allow_many {
+        return get_many(
+            &matches,
+            client::DataRequestContext {
+                progress,
+                primary_key_id,
+                hash_key_part_1,
+                data_dctx,
+                metadata_dctx,
+                decode_workers: decode_workers.clone(),
+            },
+            &ids,
+            query_cache.as_mut().unwrap(),
+            mirror.as_deref(),
+            split_writer,
+            &mut serve_out,
+            &mut serve_in,
+        );
+    }
+    let id = ids[0];
+
+    if sandbox {
+        return get_sandboxed(
+            SandboxedGet {
+                key,
+                id,
+                picks: matches.opt_strs("pick"),
+                restore_into: matches.opt_str("restore-into"),
+            },
+            progress,
+            serve_proc,
+        );
+    }
+
+    let mirrored_metadata = match (&mirror, &mut query_cache) {
+        (Some(_), Some(query_cache)) => match query_cache.transaction()?.lookup_item_by_id(&id)? {
+            Some(metadata) => Some(metadata),
+            None => failure::bail!("no stored items with the requested id"),
+        },
+        _ => None,
+    };
+
+    let pick = if matches.opt_present("pick") {
+        let ctx = client::DataRequestContext {
+            progress: progress.clone(),
+            primary_key_id,
+            hash_key_part_1: hash_key_part_1.clone(),
+            data_dctx: data_dctx.clone(),
+            metadata_dctx: metadata_dctx.clone(),
+            decode_workers: decode_workers.clone(),
+        };
+        let content_index = match (&mirror, &mirrored_metadata) {
+            (Some(mirror), Some(metadata)) => client::request_mirrored_index(
+                ctx,
+                metadata,
+                mirror,
+                &mut serve_out,
+                &mut serve_in,
+            )?,
+            _ => {
+                if query_cache.is_none() {
+                    query_cache = Some(matches_to_query_cache(&matches, &repo_info.repository_id)?);
+                }
+                client::fetch_content_index(
+                    ctx,
+                    id,
+                    query_cache.as_mut().unwrap(),
+                    &mut serve_out,
+                    &mut serve_in,
+                )?
+            }
+        };
+
+        let pick = index::pick_many(&matches.opt_strs("pick"), &content_index)?;
+        if !pick.is_subtar && matches.opt_present("restore-into") {
+            failure::bail!("--restore-into requires --pick to select a directory");
+        }
+        Some(pick)
+    } else {
+        None
+    };
+
+    let mut restore_proc = match matches.opt_str("restore-into") {
+        Some(target) => Some(spawn_restore_into(&target)?),
+        None => None,
+    };
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let out: &mut dyn std::io::Write = match (&mut restore_proc, &mut split_writer) {
+        (Some(ref mut restore_proc), _) => restore_proc.stdin.as_mut().unwrap(),
+        (None, Some(ref mut split_writer)) => split_writer,
+        (None, None) => &mut stdout,
+    };
+
+    let ctx = client::DataRequestContext {
+        progress: progress.clone(),
+        primary_key_id,
+        hash_key_part_1,
+        data_dctx,
+        metadata_dctx,
+        decode_workers: decode_workers.clone(),
+    };
+    let result = match (&mirror, &mirrored_metadata) {
+        (Some(mirror), Some(metadata)) => client::request_mirrored_data(
+            ctx,
+            metadata,
+            pick,
+            mirror,
+            &mut serve_out,
+            &mut serve_in,
+            out,
+        ),
+        _ => client::request_data_stream(ctx, id, pick, &mut serve_out, &mut serve_in, out),
+    };
+
+    // If tar failed, its exit status explains the error better than the broken pipe we get.
+    if let Some(mut restore_proc) = restore_proc {
+        drop(restore_proc.stdin.take());
+        let status = restore_proc.wait()?;
+        if !status.success() {
+            failure::bail!("restore command failed: {}", status);
+        }
+    }
+    result?;
+
+    if let Some(mut split_writer) = split_writer {
+        split_writer.finish()?;
+    }
+
+    client::hangup(&mut serve_in)?;
+
+    progress.finish_and_clear();
+
+    Ok(())
+}
+
+fn restore_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    repo_opts(&mut opts);
+    query_opts(&mut opts);
+    opts.optopt("k", "key", "Primary key to decrypt data with.", "PATH");
+    opts.optopt(
+        "",
+        "into",
+        "Directory to restore into, it is created if missing and must be empty unless --incremental is set.",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "pick",
+        "Only restore the given directory from the snapshot.",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "numeric-owner",
+        "Restore the recorded user and group ids instead of mapping owners by name.",
+    );
+    opts.optflag(
+        "",
+        "incremental",
+        "Restore into an existing directory, only fetching files that differ from the snapshot.",
+    );
+    opts.optflag(
+        "",
+        "checksum",
+        "With --incremental, compare file contents with the hashes recorded in the snapshot.",
+    );
+    opts.optflag(
+        "",
+        "delete",
+        "With --incremental, delete files that are not in the snapshot.",
+    );
+    opts.optflag(
+        "",
+        "verify",
+        "After restoring, compare the contents of each file with the hash recorded in the snapshot.",
+    );
+
+    let matches = parse_cli_opts(opts, &args[..]);
+
+    let into = match matches.opt_str("into") {
+        Some(into) => std::path::PathBuf::from(into),
+        None => failure::bail!("please set --into to the directory to restore into"),
+    };
+
+    let incremental = matches.opt_present("incremental");
+    for opt in ["checksum", "delete"].iter() {
+        if matches.opt_present(opt) && !incremental {
+            failure::bail!("--{} requires --incremental", opt);
+        }
+    }
+
+    let key = matches_to_key(&matches)?;
+    let primary_key_id = key.primary_key_id();
+    let (hash_key_part_1, data_dctx, metadata_dctx) = match key {
+        keys::Key::PrimaryKeyV1(k) => {
+            let hash_key_part_1 = k.hash_key_part_1.clone();
+            let data_dctx = crypto::DecryptionContext::new(k.data_sk, k.data_psk.clone());
+            let metadata_dctx = crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk);
+            (hash_key_part_1, data_dctx, metadata_dctx)
+        }
+        _ => failure::bail!("provided key is not a decryption key"),
+    };
+    let decode_workers = client::SharedDecodeWorkers::default();
+
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
+
+    let (id, query) = matches_to_id_and_query(&matches)?;
+    let mut serve_proc = matches_to_serve_process(&matches)?;
+    let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+    let mut serve_in = serve_proc.stdin.as_mut().unwrap();
+
+    progress.set_message("acquiring repository lock...");
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+
+    let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+
+    let id = match (id, query) {
+        (Some(id), _) => id,
+        (_, query) => {
+            client::sync(
+                progress.clone(),
+                &mut query_cache,
+                &mut serve_out,
+                &mut serve_in,
+            )?;
+
+            query_cache_matching_ids(
+                &matches,
+                &mut query_cache,
+                primary_key_id,
+                &metadata_dctx,
+                query,
+                false,
+            )?[0]
+        }
+    };
+
+    let content_index = client::fetch_content_index(
+        client::DataRequestContext {
+            progress: progress.clone(),
+            primary_key_id,
+            hash_key_part_1: hash_key_part_1.clone(),
+            data_dctx: data_dctx.clone(),
+            metadata_dctx: metadata_dctx.clone(),
+            decode_workers: decode_workers.clone(),
+        },
+        id,
+        &mut query_cache,
+        &mut serve_out,
+        &mut serve_in,
+    )?;
+
+    let pick = match matches.opt_str("pick") {
+        Some(path) => {
+            let pick = index::pick(&path, &content_index)?;
+            if !pick.is_subtar {
+                failure::bail!(
+                    "--pick must select a directory, use 'bupstash get' for single files"
+                );
+            }
+            Some(pick)
+        }
+        None => None,
+    };
+
+    let content_hash_key = if matches.opt_present("checksum") || matches.opt_present("verify") {
+        // Fetching the index cached its metadata, which holds the key of the hashes.
+        let metadata = match query_cache.transaction()?.lookup_content_index(&id)? {
+            Some((metadata, _)) => metadata,
+            None => failure::bail!("the index of the item is missing from the query cache"),
+        };
+        Some(client::item_hash_key(
+            &mut client::DataRequestContext {
+                progress: progress.clone(),
+                primary_key_id,
+                hash_key_part_1: hash_key_part_1.clone(),
+                data_dctx: data_dctx.clone(),
+                metadata_dctx: metadata_dctx.clone(),
+                decode_workers: decode_workers.clone(),
+            },
+            &metadata,
+        )?)
+    } else {
+        None
+    };
+
+    let incremental = if incremental {
+        let check = if matches.opt_present("checksum") {
+            client::RestoreCheck::ContentHash(content_hash_key.clone().unwrap())
+        } else {
+            client::RestoreCheck::MtimeSize
+        };
+        Some(client::IncrementalRestore {
+            check,
+            delete: matches.opt_present("delete"),
+        })
+    } else {
+        None
+    };
+
+    // The index is fetched and the data is next, switch to a bar showing restored files.
+    progress.finish_and_clear();
+    let restore_progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{bar:30}] {bytes}/{total_bytes} {wide_msg}"),
+    )?;
+
+    client::restore_to_local_dir(
+        client::DataRequestContext {
+            progress: progress.clone(),
+            primary_key_id,
+            hash_key_part_1,
+            data_dctx,
+            metadata_dctx,
+            decode_workers: decode_workers.clone(),
+        },
+        id,
+        &content_index,
+        pick,
+        &into,
+        matches.opt_present("numeric-owner"),
+        incremental,
+        restore_progress,
+        &mut serve_out,
+        &mut serve_in,
+    )?;
+
+    client::hangup(&mut serve_in)?;
+
+    if let Some(hash_key) = content_hash_key.filter(|_| matches.opt_present("verify")) {
+        let pick = match matches.opt_str("pick") {
+            Some(path) => Some(index::pick(&path, &content_index)?),
+            None => None,
+        };
+        let mismatched =
+            client::verify_restored_files(&into, &content_index, pick.as_ref(), &hash_key)?;
+        for path in mismatched.iter() {
+            eprintln!("{}: content does not match the snapshot", path);
+        }
+        if !mismatched.is_empty() {
+            failure::bail!(
+                "{} restored files do not match the snapshot",
+                mismatched.len()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Sent by 'get --sandbox' to its child before the repository connection.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SandboxedGet {
+    key: keys::Key,
+    id: xid::Xid,
+    picks: Vec<String>,
+    restore_into: Option<String>,
+}
+
+// Decoding and extracting the item happens in a child process that confines
+// itself with sandbox::enter_restore, we only relay its repository connection.
+fn get_sandboxed(
+    request: SandboxedGet,
+    progress: indicatif::ProgressBar,
+    mut serve_proc: ServeProcess,
+) -> Result<(), failure::Error> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::process::CommandExt;
+
+    progress.finish_and_clear();
+
+    let (child_stdin, to_child) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+    let (from_child, child_out) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+    let mut to_child = unsafe { std::fs::File::from_raw_fd(to_child) };
+    let mut from_child = unsafe { std::fs::File::from_raw_fd(from_child) };
+    let child_out = unsafe { std::fs::File::from_raw_fd(child_out) };
+
+    let mut cmd = std::process::Command::new(std::env::current_exe()?);
+    cmd.arg("get")
+        .env("BUPSTASH_SANDBOXED_GET", "1")
+        .stdin(unsafe { std::process::Stdio::from_raw_fd(child_stdin) });
+    let child_out_fd = child_out.as_raw_fd();
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::dup2(child_out_fd, 3) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => return Err(err.context("error spawning sandboxed get").into()),
+    };
+    drop(cmd);
+    drop(child_out);
+
+    let request = serde_bare::to_vec(&request)?;
+    to_child.write_all(&(request.len() as u64).to_le_bytes())?;
+    to_child.write_all(&request)?;
+
+    let mut serve_in = serve_proc.stdin.take().unwrap();
+    let mut serve_out = serve_proc.stdout.take().unwrap();
+    // Hanging up once the child exits lets the server finish.
+    let relay = std::thread::spawn(move || {
+        let _ = std::io::copy(&mut from_child, &mut serve_in);
+    });
+    let _ = std::io::copy(&mut serve_out, &mut to_child);
+    drop(to_child);
+    let status = child.wait()?;
+    let _ = relay.join();
+    drop(serve_out);
+    drop(serve_proc);
+
+    if !status.success() {
+        // The child has already reported its error.
+        match status.code() {
+            Some(code) => std::process::exit(code),
+            None => failure::bail!("sandboxed get failed: {}", status),
+        }
+    }
+    Ok(())
+}
+
+// The child of get_sandboxed, its repository connection is stdin and fd 3.
+fn sandboxed_get_child() -> Result<(), failure::Error> {
+    use std::os::unix::io::FromRawFd;
+
+    let stdin = std::io::stdin();
+    let mut serve_out = stdin.lock();
+    let mut serve_in = unsafe { std::fs::File::from_raw_fd(3) };
+
+    let mut request_len = [0; 8];
+    serve_out.read_exact(&mut request_len)?;
+    let mut request = vec![0; u64::from_le_bytes(request_len) as usize];
+    serve_out.read_exact(&mut request)?;
+    let request: SandboxedGet = serde_bare::from_slice(&request)?;
+
+    // Extraction reads the restored tarball from a pipe, created while we still can.
+    let extract_pipe = match request.restore_into {
+        Some(_) => Some(nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?),
+        None => None,
+    };
+
+    sandbox::enter_restore(request.restore_into.as_deref().map(std::path::Path::new))?;
+
+    let primary_key_id = request.key.primary_key_id();
+    let (hash_key_part_1, data_dctx, metadata_dctx) = match request.key {
+        keys::Key::PrimaryKeyV1(k) => {
+            let hash_key_part_1 = k.hash_key_part_1.clone();
+            let data_dctx = crypto::DecryptionContext::new(k.data_sk, k.data_psk.clone());
+            let metadata_dctx = crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk);
+            (hash_key_part_1, data_dctx, metadata_dctx)
+        }
+        _ => failure::bail!("provided key is not a decryption key"),
+    };
+    let decode_workers = client::SharedDecodeWorkers::default();
+    let ctx = || client::DataRequestContext {
+        progress: indicatif::ProgressBar::hidden(),
+        primary_key_id,
+        hash_key_part_1: hash_key_part_1.clone(),
+        data_dctx: data_dctx.clone(),
+        metadata_dctx: metadata_dctx.clone(),
+        decode_workers: decode_workers.clone(),
+    };
+
+    let pick = if !request.picks.is_empty() {
+        let (_, content_index) =
+            client::request_index(ctx(), request.id, &mut serve_out, &mut serve_in)?;
+        let pick = index::pick_many(&request.picks, &content_index)?;
+        if !pick.is_subtar && request.restore_into.is_some() {
+            failure::bail!("--restore-into requires --pick to select a directory");
+        }
+        Some(pick)
+    } else {
+        None
+    };
+
+    match extract_pipe {
+        Some((r, w)) => {
+            let mut r = unsafe { std::fs::File::from_raw_fd(r) };
+            let mut w = unsafe { std::fs::File::from_raw_fd(w) };
+            // We are chrooted into the restore target, ACL pax records are
+            // ignored as setxattr is not allowed in the sandbox.
+            let extract = std::thread::spawn(move || -> std::io::Result<()> {
+                tar::Archive::new(&mut r).unpack("/")?;
+                // Consume the padding after the end of the archive.
+                std::io::copy(&mut r, &mut std::io::sink())?;
+                Ok(())
+            });
+            let result = client::request_data_stream(
+                ctx(),
+                request.id,
+                pick,
+                &mut serve_out,
+                &mut serve_in,
+                &mut w,
+            );
+            drop(w);
+            // If extraction failed, its error explains the failure better than the broken pipe we get.
+            if let Err(err) = extract.join().unwrap() {
+                failure::bail!("unable to extract the directory snapshot: {}", err);
+            }
+            result?;
+        }
+        None => {
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            client::request_data_stream(
+                ctx(),
+                request.id,
+                pick,
+                &mut serve_out,
+                &mut serve_in,
+                &mut stdout,
+            )?;
+        }
+    }
+
+    client::hangup(&mut serve_in)?;
+
+    Ok(())
+}
+
+// Writes directory snapshots as a single tar stream with an item id directory for each.
+#[allow(clippy::too_many_arguments)]
+fn get_many(
+    matches: &Matches,
+    ctx: client::DataRequestContext,
+    ids: &[xid::Xid],
+    query_cache: &mut querycache::QueryCache,
+    mirror: Option<&std::path::Path>,
+    mut split_writer: Option<SplitWriter>,
+    serve_out: &mut dyn std::io::Read,
+    serve_in: &mut dyn std::io::Write,
+) -> Result<(), failure::Error> {
+    let mut items = Vec::with_capacity(ids.len());
+    let mut tx = query_cache.transaction()?;
+    for id in ids.iter() {
+        let metadata = match tx.lookup_item_by_id(id)? {
+            Some(metadata) => metadata,
+            None => failure::bail!("no stored items with the requested id"),
+        };
+        if metadata.plain_text_metadata().index_tree.is_none() {
+            failure::bail!(
+                "item {} is not a directory snapshot, --allow-many only gets directory snapshots",
+                id
+            );
+        }
+        items.push((*id, metadata));
+    }
+    drop(tx);
+
+    let mut restore_proc = match matches.opt_str("restore-into") {
+        Some(target) => Some(spawn_restore_into(&target)?),
+        None => None,
+    };
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let out: &mut dyn std::io::Write = match (&mut restore_proc, &mut split_writer) {
+        (Some(ref mut restore_proc), _) => restore_proc.stdin.as_mut().unwrap(),
+        (None, Some(ref mut split_writer)) => split_writer,
+        (None, None) => &mut stdout,
+    };
+
+    let mut out = xtar::PrefixTarWriter::new(out);
+    let mut result = Ok(());
+    for (id, metadata) in items.iter() {
+        let ctx = client::DataRequestContext {
+            progress: ctx.progress.clone(),
+            primary_key_id: ctx.primary_key_id,
+            hash_key_part_1: ctx.hash_key_part_1.clone(),
+            data_dctx: ctx.data_dctx.clone(),
+            metadata_dctx: ctx.metadata_dctx.clone(),
+            decode_workers: ctx.decode_workers.clone(),
+        };
+        out.start_archive(&id.to_string());
+        result = match mirror {
+            Some(mirror) => client::request_mirrored_data(
+                ctx, metadata, None, mirror, serve_out, serve_in, &mut out,
+            ),
+            None => client::request_data_stream(ctx, *id, None, serve_out, serve_in, &mut out),
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_ok() {
+        result = out.finish().map_err(|err| err.into());
+    } else {
+        drop(out);
+    }
+
+    // If tar failed, its exit status explains the error better than the broken pipe we get.
+    if let Some(mut restore_proc) = restore_proc {
+        drop(restore_proc.stdin.take());
+        let status = restore_proc.wait()?;
+        if !status.success() {
+            failure::bail!("restore command failed: {}", status);
+        }
+    }
+    result?;
+
+    if let Some(mut split_writer) = split_writer {
+        split_writer.finish()?;
+    }
+
+    client::hangup(serve_in)?;
+
+    ctx.progress.finish_and_clear();
+
+    Ok(())
+}
+
+fn verify_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    repo_opts(&mut opts);
+    query_opts(&mut opts);
+    opts.optopt("k", "key", "Primary key to decrypt data with.", "PATH");
+    opts.optflag("", "allow-many", "Verify all items matching the query.");
+    opts.optflag(
+        "",
+        "deep",
+        "Also hash the data of each file of directory snapshots and compare it to the index.",
+    );
+
+    let matches = parse_cli_opts(opts, &args[..]);
+    let allow_many = matches.opt_present("allow-many");
+    let deep = matches.opt_present("deep");
+
+    let key = matches_to_key(&matches)?;
+    let primary_key_id = key.primary_key_id();
+    let (hash_key_part_1, data_dctx, metadata_dctx) = match &key {
+        keys::Key::PrimaryKeyV1(k) => {
+            let hash_key_part_1 = k.hash_key_part_1.clone();
+            let data_dctx = crypto::DecryptionContext::new(k.data_sk.clone(), k.data_psk.clone());
+            let metadata_dctx =
+                crypto::DecryptionContext::new(k.metadata_sk.clone(), k.metadata_psk.clone());
+            (hash_key_part_1, data_dctx, metadata_dctx)
+        }
+        _ => failure::bail!("provided key is not a decryption key"),
+    };
+    let decode_workers = client::SharedDecodeWorkers::default();
+
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
+
+    let (id, query) = matches_to_id_and_query(&matches)?;
+    let mut serve_proc = matches_to_serve_process(&matches)?;
+    let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+    let mut serve_in = serve_proc.stdin.as_mut().unwrap();
+
+    progress.set_message("acquiring repository lock...");
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+
+    let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+    client::sync(
+        progress.clone(),
+        &mut query_cache,
+        &mut serve_out,
+        &mut serve_in,
+    )?;
+
+    let ids = match id {
+        Some(id) => vec![id],
+        None => query_cache_matching_ids(
+            &matches,
+            &mut query_cache,
+            primary_key_id,
+            &metadata_dctx,
+            query,
+            allow_many,
+        )?,
+    };
+
+    let mut n_failed = 0;
+    let mut tx = query_cache.transaction()?;
+    for id in ids.iter() {
+        let metadata = match tx.lookup_item_by_id(id)? {
+            Some(metadata) => metadata,
+            None => failure::bail!("no stored items with the requested id"),
+        };
+        let ctx = || client::DataRequestContext {
+            progress: progress.clone(),
+            primary_key_id,
+            hash_key_part_1: hash_key_part_1.clone(),
+            data_dctx: data_dctx.clone(),
+            metadata_dctx: metadata_dctx.clone(),
+            decode_workers: decode_workers.clone(),
+        };
+
+        progress.set_message(&format!("verifying item {}...", id));
+        let is_snapshot = metadata.plain_text_metadata().index_tree.is_some();
+        // The data is checked exactly as get would check it, the
+        // server cannot tell a verify from a get.
+        let result = if deep && is_snapshot {
+            client::request_index(ctx(), *id, &mut serve_out, &mut serve_in).and_then(
+                |(_, content_index)| {
+                    client::verify_content_hashes(
+                        ctx(),
+                        &metadata,
+                        *id,
+                        &content_index,
+                        &mut serve_out,
+                        &mut serve_in,
+                    )
+                },
+            )
+        } else {
+            client::request_data_stream(
+                ctx(),
+                *id,
+                None,
+                &mut serve_out,
+                &mut serve_in,
+                &mut std::io::sink(),
+            )
+            .and_then(|_| {
+                if is_snapshot {
+                    client::request_index(ctx(), *id, &mut serve_out, &mut serve_in)?;
+                }
+                Ok(Vec::new())
+            })
+        };
+        // A failed request leaves the rest of its reply unread, so we cannot continue.
+        let mismatched = match result {
+            Ok(mismatched) => mismatched,
+            Err(err) => failure::bail!("item {} failed verification: {}", id, err),
+        };
+        for path in mismatched.iter() {
+            let msg = format!("item {}: content of {} does not match its hash", id, path);
+            if progress.is_hidden() {
+                eprintln!("{}", msg);
+            } else {
+                progress.println(&msg);
+            }
+        }
+        if !mismatched.is_empty() {
+            n_failed += 1;
+        }
+    }
+    drop(tx);
+
+    if n_failed != 0 {
+        failure::bail!("{} of {} items failed verification", n_failed, ids.len());
+    }
+
+    client::hangup(&mut serve_in)?;
+    progress.finish_and_clear();
+
+    Ok(())
+}
+
+fn inspect_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    repo_opts(&mut opts);
+    query_opts(&mut opts);
+    opts.optopt(
+        "k",
+        "key",
+        "Primary or metadata key to decrypt item metadata with.",
+        "PATH",
+    );
+
+    let matches = parse_cli_opts(opts, &args[..]);
+
+    let key = matches_to_key(&matches)?;
+    let primary_key_id = key.primary_key_id();
+    let mut metadata_dctx = match key {
+        keys::Key::PrimaryKeyV1(k) => crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk),
+        keys::Key::MetadataKeyV1(k) => {
+            crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk)
+        }
+        _ => failure::bail!("provided key is not valid for metadata decryption"),
+    };
+
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
+
+    let (id, query) = matches_to_id_and_query(&matches)?;
+    let mut serve_proc = matches_to_serve_process(&matches)?;
+    let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+    let mut serve_in = serve_proc.stdin.as_mut().unwrap();
+
+    progress.set_message("acquiring repository lock...");
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+
+    let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+    client::sync(
+        progress.clone(),
+        &mut query_cache,
+        &mut serve_out,
+        &mut serve_in,
+    )?;
+    client::hangup(&mut serve_in)?;
+    progress.finish_and_clear();
+
+    let mut tx = query_cache.transaction()?;
+
+    let id = match id {
+        Some(id) => id,
+        None => {
+            let mut n_matches: u64 = 0;
+            let mut id = xid::Xid::default();
+
+            let mut on_match =
+                |item_id: xid::Xid, _tags: std::collections::BTreeMap<String, String>| {
+                    n_matches += 1;
+                    id = item_id;
+
+                    if n_matches > 1 {
+                        failure::bail!(
+                            "the provided query matched {} items, need a single match",
+                            n_matches
+                        );
+                    }
+
+                    Ok(())
+                };
+
+            tx.list(
+                querycache::ListOptions {
+                    primary_key_id: Some(primary_key_id),
+                    metadata_dctx: Some(metadata_dctx.clone()),
+                    list_encrypted: matches.opt_present("query-encrypted"),
+                    utc_timestamps: matches.opt_present("utc-timestamps"),
+                    query: Some(query),
+                    now: chrono::Utc::now(),
+                    order: querycache::ListOrder::default(),
+                },
+                &mut on_match,
+            )?;
+
+            if n_matches == 0 {
+                failure::bail!("no stored items match the provided query");
+            }
+
+            id
+        }
+    };
+
+    let metadata = match tx.lookup_item_by_id(&id)? {
+        Some(metadata) => metadata,
+        None => failure::bail!("no stored items with the requested id"),
+    };
+
+    let htree_to_json = |tree: &itemset::HTreeMetadata| {
+        serde_json::json!({
+            "height": tree.height,
+            "address": tree.address.to_string(),
+        })
+    };
+
+    let version = match metadata {
+        itemset::VersionedItemMetadata::V1(_) => 1,
+        itemset::VersionedItemMetadata::V2(_) => 2,
+    };
+
+    let plain_text_metadata = metadata.plain_text_metadata();
+    // Items sent with a different primary key are still inspected,
+    // we just can't show anything that is encrypted.
+    let decrypted_metadata = if plain_text_metadata.primary_key_id == primary_key_id {
+        let emd = metadata.decrypt_metadata(&mut metadata_dctx)?;
+        let mut plain_text_hash = [0; crypto::HASH_BYTES * 2];
+        hex::encode(&emd.plain_text_hash, &mut plain_text_hash);
+        serde_json::json!({
+            "plain_text_hash": std::str::from_utf8(&plain_text_hash)?,
+            "send_key_id": emd.send_key_id.to_string(),
+            "timestamp": emd.timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            "tags": emd.tags,
+            "note": emd.note,
+            "data_size": emd.data_size.map(|sz| sz.0),
+            "entry_count": emd.entry_count.map(|n| n.0),
+            "start_timestamp": emd
+                .start_timestamp
+                .map(|ts| ts.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+        })
+    } else {
+        serde_json::Value::Null
+    };
+
+    let inspected = serde_json::json!({
+        "id": id.to_string(),
+        "version": version,
+        "plain_text_metadata": {
+            "primary_key_id": plain_text_metadata.primary_key_id.to_string(),
+            "data_tree": htree_to_json(&plain_text_metadata.data_tree),
+            "index_tree": plain_text_metadata.index_tree.as_ref().map(htree_to_json),
+        },
+        "encrypted_metadata_size": metadata.encrypted_metadata_size(),
+        "decrypted_metadata": decrypted_metadata,
+    });
+
+    println!("{}", serde_json::to_string_pretty(&inspected)?);
+
+    Ok(())
+}
+
+fn list_contents_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    repo_opts(&mut opts);
+    query_opts(&mut opts);
+    opts.optopt("k", "key", "Primary key to decrypt data with.", "PATH");
+    opts.optopt(
+        "",
+        "format",
+        "Output format, valid values are 'human' or 'jsonl'.",
+        "FORMAT",
+    );
+    opts.optflag(
+        "",
+        "tree",
+        "Display contents as a tree with per directory totals.",
+    );
+    opts.optopt(
+        "",
+        "max-depth",
+        "Only list entries at most N directories deep.",
+        "N",
+    );
+
+    let matches = parse_cli_opts(opts, &args[..]);
+
+    let list_format = match matches.opt_str("format") {
+        Some(f) => match &f[..] {
+            "jsonl" => ListFormat::Jsonl,
+            "human" => ListFormat::Human,
+            _ => failure::bail!("invalid --format, expected one of 'human' or 'jsonl'"),
+        },
+        None => ListFormat::Human,
+    };
+
+    let tree = matches.opt_present("tree");
+
+    if tree {
+        if let ListFormat::Jsonl = list_format {
+            failure::bail!("--tree is only supported with --format=human");
+        }
+    }
+
+    let max_depth: Option<usize> = match matches.opt_str("max-depth") {
+        Some(max_depth) => match max_depth.parse() {
+            Ok(max_depth) => Some(max_depth),
+            Err(err) => failure::bail!("unable to parse --max-depth: {}", err),
+        },
+        None => None,
+    };
+
+    let key = matches_to_key(&matches)?;
+    let primary_key_id = key.primary_key_id();
+    let (hash_key_part_1, data_dctx, metadata_dctx) = match key {
+        keys::Key::PrimaryKeyV1(k) => {
+            let hash_key_part_1 = k.hash_key_part_1.clone();
+            let data_dctx = crypto::DecryptionContext::new(k.data_sk, k.data_psk.clone());
+            let metadata_dctx = crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk);
+            (hash_key_part_1, data_dctx, metadata_dctx)
+        }
+        _ => failure::bail!("provided key is not a decryption key"),
+    };
+    let decode_workers = client::SharedDecodeWorkers::default();
+
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
+
+    let (id, query) = matches_to_id_and_query(&matches)?;
+
+    let ctx = client::DataRequestContext {
+        progress: progress.clone(),
+        primary_key_id,
+        hash_key_part_1,
+        data_dctx,
+        metadata_dctx: metadata_dctx.clone(),
+        decode_workers: decode_workers.clone(),
+    };
+
+    // With a fully specified id and a cached index we don't need the server at all.
+    let cached_index = match (id, matches_to_offline_query_cache(&matches)?) {
+        (Some(id), Some(mut query_cache)) => {
+            let mut tx = query_cache.transaction()?;
+            tx.lookup_content_index(&id)?
+        }
+        _ => None,
+    };
+
+    let content_index = if let Some((metadata, chunks)) = cached_index {
+        client::cached_index(ctx, &metadata, &chunks)?
+    } else {
+        let mut serve_proc = matches_to_serve_process(&matches)?;
+        let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+        let mut serve_in = serve_proc.stdin.as_mut().unwrap();
+
+        progress.set_message(&"acquiring repository lock...");
+        let repo_info = client::open_repository(
+            &progress,
+            &mut serve_in,
+            &mut serve_out,
+            protocol::LockHint::Read,
+        )?;
+
+        let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+
+        let id = match (id, query) {
+            (Some(id), _) => id,
+            (_, query) => {
+                client::sync(
+                    progress.clone(),
+                    &mut query_cache,
+                    &mut serve_out,
+                    &mut serve_in,
+                )?;
+
+                query_cache_matching_ids(
+                    &matches,
+                    &mut query_cache,
+                    primary_key_id,
+                    &metadata_dctx,
+                    query,
+                    false,
+                )?[0]
+            }
+        };
+
+        let content_index =
+            client::fetch_content_index(ctx, id, &mut query_cache, &mut serve_out, &mut serve_in)?;
+        client::hangup(&mut serve_in)?;
+        content_index
+    };
+
+    progress.finish_and_clear();
+
+    let index::SplitIndex {
+        entries: mut content_index,
+        mut rollups,
+        unchanged,
+        acls,
+        owners,
+        stats,
+        content_hashes,
+    } = index::split_index(content_index);
+
+    // Items sent by older versions of bupstash have no rollups, compute them here instead.
+    if rollups.is_empty() {
+        let mut builder = index::DirectoryRollupBuilder::new();
+        for ent in content_index.iter() {
+            builder.add_entry(ent);
+        }
+        for rollup in builder.finish() {
+            rollups.insert(rollup.path.clone(), rollup);
+        }
+    }
+
+    if let Some(max_depth) = max_depth {
+        content_index.retain(|ent| ent.depth() <= max_depth);
+    }
+
+    // Due to how 'put' works, our tarballs are not ordered in a way that is pleasant by default.
+    content_index.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let utc_timestamps = matches.opt_present("utc-timestamps");
+
+    match list_format {
+        ListFormat::Human if tree => {
+            print_content_tree(&content_index, &rollups, &unchanged)?;
+        }
+        ListFormat::Human => {
+            let mut max_size_digits = 0;
+            for item in content_index.iter() {
+                max_size_digits = std::cmp::max(item.size.0.to_string().len(), max_size_digits)
+            }
+
+            // Items sent by older versions of bupstash have no owners, they get no owner column.
+            let owner_column = |item: &index::IndexEntry| -> Option<String> {
+                if owners.is_empty() {
+                    return None;
+                }
+                Some(match owners.get(&item.path) {
+                    Some(owner) => owner.display_owner(),
+                    None => "?".to_string(),
+                })
+            };
+            let mut max_owner_len = 0;
+            for item in content_index.iter() {
+                if let Some(owner) = owner_column(item) {
+                    max_owner_len = std::cmp::max(owner.chars().count(), max_owner_len)
+                }
+            }
+
+            for item in content_index.iter() {
+                let ts = chrono::NaiveDateTime::from_timestamp(
+                    item.ctime.0 as i64,
+                    item.ctime_nsec.0 as u32,
+                );
+                let ts = chrono::DateTime::<chrono::Utc>::from_utc(ts, chrono::Utc);
+
+                let tsfmt = "%Y/%m/%d %T";
+
+                let ts = if utc_timestamps {
+                    ts.format(tsfmt).to_string()
+                } else {
+                    chrono::DateTime::<chrono::Local>::from(ts)
+                        .format(tsfmt)
+                        .to_string()
+                };
+
+                let size = format!("{}", item.size.0);
+                let size_padding: String = std::iter::repeat(' ')
+                    .take(max_size_digits - size.len())
+                    .collect();
+
+                let owner = match owner_column(item) {
+                    Some(owner) => {
+                        let padding = " ".repeat(max_owner_len - owner.chars().count());
+                        format!(" {}{}", owner, padding)
                     }
-                    None => {
-                        collecting_tags = false;
-                        source_args.push(a.to_string());
+                    None => String::new(),
+                };
+
+                let link_target = match stats.get(&item.path) {
+                    Some(index::EntryStat {
+                        link_target: Some(ref target),
+                        ..
+                    }) => format!(" -> {}", target),
+                    _ => String::new(),
+                };
+
+                println!(
+                    "{}{} {}{} {} {}{}{}",
+                    item.display_mode(),
+                    owner,
+                    size,
+                    size_padding,
+                    ts,
+                    item.path,
+                    link_target,
+                    if unchanged.contains(&item.path) {
+                        " (unchanged)"
+                    } else {
+                        ""
+                    },
+                );
+            }
+        }
+        ListFormat::Jsonl => {
+            for item in content_index.iter() {
+                print!("{{");
+                print!("\"mode\":{},", serde_json::to_string(&item.mode.0)?);
+                print!("\"size\":{},", item.size.0);
+                print!("\"path\":{},", serde_json::to_string(&item.path)?);
+                print!("\"ctime\":{},", serde_json::to_string(&item.ctime.0)?);
+                print!(
+                    "\"ctime_nsec\":{}",
+                    serde_json::to_string(&item.ctime_nsec.0)?
+                );
+                if let Some(rollup) = rollups.get(&item.path) {
+                    print!(",\"entry_count\":{}", rollup.entry_count.0);
+                    print!(",\"total_size\":{}", rollup.total_size.0);
+                }
+                if unchanged.contains(&item.path) {
+                    print!(",\"unchanged\":true");
+                }
+                if let Some(stat) = stats.get(&item.path) {
+                    print!(",\"type\":\"{}\"", stat.kind.name());
+                    print!(",\"mtime\":{}", stat.mtime.0);
+                    print!(",\"mtime_nsec\":{}", stat.mtime_nsec.0);
+                    print!(",\"nlink\":{}", stat.nlink.0);
+                    if let Some(ref target) = stat.link_target {
+                        print!(",\"link_target\":{}", serde_json::to_string(target)?);
+                    }
+                    if !owners.contains_key(&item.path) {
+                        print!(",\"uid\":{}", stat.uid.0);
+                        print!(",\"gid\":{}", stat.gid.0);
                     }
                 }
-            } else {
-                source_args.push(a.to_string());
+                if let Some(hash) = content_hashes.get(&item.path) {
+                    print!(",\"content_hash\":\"{}\"", hex::easy_encode_to_string(hash));
+                }
+                if let Some(owner) = owners.get(&item.path) {
+                    print!(",\"uid\":{}", owner.uid.0);
+                    print!(",\"gid\":{}", owner.gid.0);
+                    if let Some(ref user) = owner.user {
+                        print!(",\"user\":{}", serde_json::to_string(user)?);
+                    }
+                    if let Some(ref group) = owner.group {
+                        print!(",\"group\":{}", serde_json::to_string(group)?);
+                    }
+                }
+                if let Some(acls) = acls.get(&item.path) {
+                    if let Some(ref access) = acls.access {
+                        print!(",\"acl_access\":{}", serde_json::to_string(access)?);
+                    }
+                    if let Some(ref default) = acls.default {
+                        print!(",\"acl_default\":{}", serde_json::to_string(default)?);
+                    }
+                }
+                print!("}}");
+                println!();
             }
         }
     }
 
-    let compression = if matches.opt_present("no-compression") {
-        crypto::DataCompression::None
-    } else {
-        crypto::DataCompression::Zstd
-    };
+    std::io::stdout().flush()?;
 
-    let use_stat_cache = !matches.opt_present("no-stat-cache");
+    Ok(())
+}
 
-    let checkpoint_bytes: u64 = match std::env::var("BUPSTASH_CHECKPOINT_BYTES") {
-        Ok(v) => match v.parse() {
-            Ok(v) => v,
-            Err(err) => failure::bail!("unable to parse BUPSTASH_CHECKPOINT_BYTES: {}", err),
-        },
-        Err(_) => 1073741824,
+fn print_content_tree(
+    content_index: &[index::IndexEntry],
+    rollups: &std::collections::HashMap<String, index::DirectoryRollup>,
+    unchanged: &std::collections::HashSet<String>,
+) -> Result<(), failure::Error> {
+    let mut children: std::collections::HashMap<&str, Vec<usize>> =
+        std::collections::HashMap::new();
+    let mut roots = Vec::new();
+    let paths: std::collections::HashSet<&str> =
+        content_index.iter().map(|ent| ent.path.as_str()).collect();
+
+    for (i, ent) in content_index.iter().enumerate() {
+        match ent.parent_path() {
+            Some(parent) if paths.contains(parent) => children.entry(parent).or_default().push(i),
+            _ => roots.push(i),
+        }
+    }
+
+    let entry_size = |ent: &index::IndexEntry| match rollups.get(&ent.path) {
+        Some(rollup) => rollup.total_size.0,
+        None => ent.size.0,
     };
 
-    let send_log = if matches.opt_present("no-send-log") {
-        None
-    } else {
-        match matches.opt_str("send-log") {
-            Some(send_log) => Some(sendlog::SendLog::open(&std::path::PathBuf::from(send_log))?),
-            None => match std::env::var_os("BUPSTASH_SEND_LOG") {
-                Some(send_log) => {
-                    Some(sendlog::SendLog::open(&std::path::PathBuf::from(send_log))?)
-                }
-                None => {
-                    let mut p = cache_dir()?;
-                    std::fs::create_dir_all(&p)?;
-                    p.push("bupstash.sendlog");
-                    Some(sendlog::SendLog::open(&p)?)
-                }
-            },
+    let mut max_size_digits = 0;
+    for ent in content_index.iter() {
+        max_size_digits = std::cmp::max(entry_size(ent).to_string().len(), max_size_digits)
+    }
+
+    let mut out = std::io::stdout();
+
+    // Depth first walk, each work item is (entry, prefix, is_last_child, is_root).
+    let mut work_list: Vec<(usize, String, bool, bool)> = roots
+        .iter()
+        .rev()
+        .map(|i| (*i, String::new(), false, true))
+        .collect();
+
+    while let Some((i, prefix, is_last, is_root)) = work_list.pop() {
+        let ent = &content_index[i];
+
+        let name = if is_root {
+            ent.path.as_str()
+        } else {
+            match ent.path.rfind('/') {
+                Some(idx) => &ent.path[idx + 1..],
+                None => ent.path.as_str(),
+            }
+        };
+
+        let (connector, child_prefix) = if is_root {
+            ("", String::new())
+        } else if is_last {
+            ("└── ", format!("{}    ", prefix))
+        } else {
+            ("├── ", format!("{}│   ", prefix))
+        };
+
+        let size = format!("{}", entry_size(ent));
+        let size_padding = " ".repeat(max_size_digits - size.len());
+
+        match rollups.get(&ent.path) {
+            Some(rollup) => writeln!(
+                out,
+                "{} {}{} {}{}{}/ ({} {})",
+                ent.display_mode(),
+                size,
+                size_padding,
+                prefix,
+                connector,
+                name,
+                rollup.entry_count.0,
+                if rollup.entry_count.0 == 1 {
+                    "entry"
+                } else {
+                    "entries"
+                },
+            )?,
+            None => writeln!(
+                out,
+                "{} {}{} {}{}{}{}",
+                ent.display_mode(),
+                size,
+                size_padding,
+                prefix,
+                connector,
+                name,
+                if unchanged.contains(&ent.path) {
+                    " (unchanged)"
+                } else {
+                    ""
+                },
+            )?,
         }
+
+        if let Some(child_idxs) = children.get(ent.path.as_str()) {
+            for (n, child) in child_idxs.iter().enumerate().rev() {
+                work_list.push((
+                    *child,
+                    child_prefix.clone(),
+                    n == child_idxs.len() - 1,
+                    false,
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn mount_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    repo_opts(&mut opts);
+    query_opts(&mut opts);
+    opts.optopt("k", "key", "Primary key to decrypt data with.", "PATH");
+    opts.optflag(
+        "",
+        "allow-many",
+        "Mount all directory snapshots matching the query, each in a directory named by its timestamp.",
+    );
+    opts.optopt(
+        "",
+        "cache-size",
+        "Memory used to cache recently read data chunks, e.g. '256M', defaults to 64M.",
+        "SIZE",
+    );
+
+    let mut matches = parse_cli_opts(opts, &args[..]);
+    if matches.free.is_empty() {
+        failure::bail!("you must specify a mount point");
+    }
+    let mountpoint = std::path::PathBuf::from(matches.free.remove(0));
+    let allow_many = matches.opt_present("allow-many");
+    let utc_timestamps = matches.opt_present("utc-timestamps");
+    let cache_size = match matches.opt_str("cache-size") {
+        Some(cache_size) => parse_size(&cache_size)? as usize,
+        None => 64 * 1024 * 1024,
     };
 
     let key = matches_to_key(&matches)?;
     let primary_key_id = key.primary_key_id();
-    let send_key_id = key.id();
-    let (hash_key, data_ectx, metadata_ectx) = match key {
+    let (hash_key_part_1, data_dctx, mut metadata_dctx) = match &key {
         keys::Key::PrimaryKeyV1(k) => {
-            let hash_key = crypto::derive_hash_key(&k.hash_key_part_1, &k.hash_key_part_2);
-            let data_ectx = crypto::EncryptionContext::new(&k.data_pk, &k.data_psk);
-            let metadata_ectx = crypto::EncryptionContext::new(&k.metadata_pk, &k.metadata_psk);
-            (hash_key, data_ectx, metadata_ectx)
-        }
-        keys::Key::PutKeyV1(k) => {
-            let hash_key = crypto::derive_hash_key(&k.hash_key_part_1, &k.hash_key_part_2);
-            let data_ectx = crypto::EncryptionContext::new(&k.data_pk, &k.data_psk);
-            let metadata_ectx = crypto::EncryptionContext::new(&k.metadata_pk, &k.metadata_psk);
-            (hash_key, data_ectx, metadata_ectx)
+            let hash_key_part_1 = k.hash_key_part_1.clone();
+            let data_dctx = crypto::DecryptionContext::new(k.data_sk.clone(), k.data_psk.clone());
+            let metadata_dctx =
+                crypto::DecryptionContext::new(k.metadata_sk.clone(), k.metadata_psk.clone());
+            (hash_key_part_1, data_dctx, metadata_dctx)
         }
-        _ => failure::bail!("can only send data with a primary-key or put-key."),
+        _ => failure::bail!("provided key is not a decryption key"),
     };
 
-    let default_tags = !matches.opt_present("no-default-tags");
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
 
-    let mut data_source: client::DataSource;
+    let (id, query) = matches_to_id_and_query(&matches)?;
+    let mut serve_proc = matches_to_serve_process(&matches)?;
+    let mut serve_out = serve_proc.stdout.take().unwrap();
+    let mut serve_in = serve_proc.stdin.take().unwrap();
+
+    progress.set_message(&"acquiring repository lock...");
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+
+    // Naming the items needs their metadata, which we get from the query cache.
+    let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+    client::sync(
+        progress.clone(),
+        &mut query_cache,
+        &mut serve_out,
+        &mut serve_in,
+    )?;
+
+    let ids = match id {
+        Some(id) => vec![id],
+        None => query_cache_matching_ids(
+            &matches,
+            &mut query_cache,
+            primary_key_id,
+            &metadata_dctx,
+            query,
+            allow_many,
+        )?,
+    };
+
+    let mut items: Vec<mount::MountedItem> = Vec::with_capacity(ids.len());
+    let mut tx = query_cache.transaction()?;
+    for id in ids.iter() {
+        let metadata = match tx.lookup_item_by_id(id)? {
+            Some(metadata) => metadata,
+            None => failure::bail!("no stored items with the requested id"),
+        };
+        if metadata.plain_text_metadata().index_tree.is_none() {
+            failure::bail!("item {} is not a directory snapshot", id);
+        }
+        if metadata.plain_text_metadata().primary_key_id != primary_key_id {
+            failure::bail!("item {} was not sent with the provided key", id);
+        }
+        let timestamp = metadata.decrypt_metadata(&mut metadata_dctx)?.timestamp;
+        let mut name = if utc_timestamps {
+            timestamp.format("%Y-%m-%dT%H:%M:%S").to_string()
+        } else {
+            timestamp
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%dT%H:%M:%S")
+                .to_string()
+        };
+        // Items sent in the same second are told apart by their id.
+        if items.iter().any(|item| item.name == name) {
+            name = format!("{}-{}", name, id);
+        }
+        items.push(mount::MountedItem {
+            id: *id,
+            name,
+            timestamp: timestamp.into(),
+        });
+    }
+    drop(tx);
+
+    progress.finish_and_clear();
+
+    mount::mount(
+        &mountpoint,
+        items,
+        allow_many,
+        mount::MountContext {
+            primary_key_id,
+            hash_key_part_1,
+            data_dctx,
+            metadata_dctx,
+            decode_workers: client::SharedDecodeWorkers::default(),
+        },
+        query_cache,
+        serve_out,
+        serve_in,
+        cache_size,
+    )
+}
+
+fn remove_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    repo_opts(&mut opts);
+    query_opts(&mut opts);
+
+    opts.optopt(
+        "k",
+        "key",
+        "Primary or metadata key to decrypt metadata with.",
+        "PATH",
+    );
+
+    opts.optflag(
+        "",
+        "ids-from-stdin",
+        "Remove items with IDs read from stdin, one per line, instead of executing a query.",
+    );
+
+    opts.optflag("", "allow-many", "Allow multiple removals.");
+
+    let matches = parse_cli_opts(opts, &args[..]);
+
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
+
+    if matches.opt_present("ids-from-stdin") {
+        let mut ids = Vec::new();
+
+        for l in std::io::stdin().lock().lines() {
+            let l = l?;
+            if l.is_empty() {
+                continue;
+            }
+            match xid::Xid::parse(&l) {
+                Ok(id) => ids.push(id),
+                Err(err) => failure::bail!("error id parsing {:?}: {}", l, err),
+            };
+        }
+
+        let mut serve_proc = matches_to_serve_process(&matches)?;
+        let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+        let mut serve_in = serve_proc.stdin.as_mut().unwrap();
+
+        progress.set_message(&"acquiring repository lock...");
+        client::open_repository(
+            &progress,
+            &mut serve_in,
+            &mut serve_out,
+            protocol::LockHint::Write,
+        )?;
+        client::remove(progress.clone(), ids, &mut serve_out, &mut serve_in)?;
+        client::hangup(&mut serve_in)?;
+    } else {
+        let mut serve_proc = matches_to_serve_process(&matches)?;
+        let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+        let mut serve_in = serve_proc.stdin.as_mut().unwrap();
+        progress.set_message(&"acquiring repository lock...");
+        let repo_info = client::open_repository(
+            &progress,
+            &mut serve_in,
+            &mut serve_out,
+            protocol::LockHint::Write,
+        )?;
+
+        let ids: Vec<xid::Xid> = match matches_to_id_and_query(&matches)? {
+            (Some(id), _) => vec![id],
+            (_, query) => {
+                let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+
+                // Only sync the client if we have a non id query.
+                client::sync(
+                    progress.clone(),
+                    &mut query_cache,
+                    &mut serve_out,
+                    &mut serve_in,
+                )?;
+
+                let (primary_key_id, metadata_dctx) = match matches_to_opt_key(&matches)? {
+                    Some(key) => {
+                        let primary_key_id = key.primary_key_id();
+                        let metadata_dctx = match key {
+                            keys::Key::PrimaryKeyV1(k) => {
+                                crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk)
+                            }
+                            keys::Key::MetadataKeyV1(k) => {
+                                crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk)
+                            }
+                            _ => {
+                                failure::bail!("provided key is not valid for metadata decryption")
+                            }
+                        };
+
+                        (Some(primary_key_id), Some(metadata_dctx))
+                    }
+                    None => {
+                        if !matches.opt_present("query-encrypted") {
+                            failure::bail!("please set --key, BUPSTASH_KEY, BUPSTASH_KEY_COMMAND or pass --query-encrypted");
+                        }
+                        (None, None)
+                    }
+                };
 
-    let progress = matches_to_progress_bar(
-        &matches,
-        indicatif::ProgressStyle::default_spinner()
-            .template("[{elapsed_precise}] {wide_msg} [{bytes} sent, {bytes_per_sec}]"),
-    )?;
+                let mut ids = Vec::new();
 
-    if matches.opt_present("exec") {
-        data_source = client::DataSource::Subprocess(source_args)
-    } else if source_args.is_empty() {
-        failure::bail!("data sources should be a file, directory, or command (use '-' for stdin).");
-    } else {
-        if !source_args.len() == 1 {
-            failure::bail!("expected a single data source, got {:?}", source_args);
-        }
+                let mut on_match =
+                    |item_id: xid::Xid, _tags: std::collections::BTreeMap<String, String>| {
+                        ids.push(item_id);
+                        Ok(())
+                    };
 
-        if source_args[0] == "-" {
-            data_source = client::DataSource::Readable {
-                description: "<stdin>".to_string(),
-                data: Box::new(Box::new(std::io::stdin())),
-            };
-        } else {
-            let input_path: std::path::PathBuf = std::convert::From::from(&source_args[0]);
-            let input_path = std::fs::canonicalize(&input_path)?;
+                let mut tx = query_cache.transaction()?;
+                tx.list(
+                    querycache::ListOptions {
+                        primary_key_id,
+                        metadata_dctx,
+                        list_encrypted: matches.opt_present("query-encrypted"),
+                        utc_timestamps: matches.opt_present("utc-timestamps"),
+                        query: Some(query),
+                        now: chrono::Utc::now(),
+                        order: querycache::ListOrder::default(),
+                    },
+                    &mut on_match,
+                )?;
 
-            let md = match std::fs::metadata(&input_path) {
-                Ok(md) => md,
-                Err(err) => failure::bail!("unable to open input source {:?}: {}", input_path, err),
-            };
+                if ids.len() > 1 && !matches.opt_present("allow-many") {
+                    failure::bail!(
+                        "the provided query matched {} items, need a single match unless --allow-many is specified",
+                        ids.len()
+                    );
+                };
 
-            let name = match input_path.file_name() {
-                Some(name) => name.to_string_lossy().to_string(),
-                None => "rootfs".to_string(),
-            };
+                ids
+            }
+        };
+        client::remove(progress.clone(), ids, &mut serve_out, &mut serve_in)?;
+        client::hangup(&mut serve_in)?;
+    };
 
-            let mut exclusions = Vec::new();
+    progress.finish_and_clear();
 
-            for e in matches.opt_strs("exclude") {
-                match glob::Pattern::new(&e) {
-                    Ok(pattern) => exclusions.push(pattern),
-                    Err(err) => {
-                        failure::bail!("--exclude option {:?} is not a valid glob: {}", e, err)
-                    }
-                }
-            }
+    Ok(())
+}
 
-            if md.is_dir() {
-                if default_tags {
-                    tags.insert("name".to_string(), name + ".tar");
-                }
+fn gc_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    opts.optflag("q", "quiet", "Suppress progress indicators.");
+    opts.optopt(
+        "",
+        "max-io-rate",
+        "Limit the chunks read while walking and removed while sweeping to OPS per second.",
+        "OPS",
+    );
+    opts.optopt(
+        "",
+        "pace",
+        "Sleep for DURATION (e.g. '10ms') before each chunk read or removal.",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "sweep-workers",
+        "Remove unused chunks with N threads, defaults to 4.",
+        "N",
+    );
 
-                data_source = client::DataSource::Directory {
-                    path: input_path,
-                    exclusions,
-                };
-            } else if md.is_file() {
-                if default_tags {
-                    tags.insert("name".to_string(), name);
-                }
+    repo_opts(&mut opts);
+    let matches = parse_cli_opts(opts, &args[..]);
 
-                data_source = client::DataSource::Readable {
-                    description: input_path.to_string_lossy().to_string(),
-                    data: Box::new(std::fs::File::open(input_path)?),
-                };
-            } else {
-                failure::bail!("{} is not a file or a directory", source_args[0]);
-            }
-        }
+    let pacing = repository::GCPacing {
+        pause: match matches.opt_str("pace") {
+            Some(pace) => match humantime::parse_duration(&pace) {
+                Ok(pace) => Some(pace),
+                Err(err) => failure::bail!("unable to parse --pace duration: {}", err),
+            },
+            None => None,
+        },
+        max_io_rate: match matches.opt_str("max-io-rate") {
+            Some(rate) => match rate.parse() {
+                Ok(rate) if rate > 0 => Some(rate),
+                Ok(_) => failure::bail!("--max-io-rate must be greater than 0"),
+                Err(err) => failure::bail!("unable to parse --max-io-rate: {}", err),
+            },
+            None => None,
+        },
     };
 
-    // No easy way to compute the tag set length without actually encoding it due
-    // to var ints in the bare encoding.
-    if serde_bare::to_vec(&tags)?.len() > itemset::MAX_TAG_SET_SIZE {
-        failure::bail!("tags must not exceed {} bytes", itemset::MAX_TAG_SET_SIZE);
-    }
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
 
     let mut serve_proc = matches_to_serve_process(&matches)?;
     let mut serve_out = serve_proc.stdout.as_mut().unwrap();
     let mut serve_in = serve_proc.stdin.as_mut().unwrap();
-    let mut ctx = client::SendContext {
-        progress: progress.clone(),
-        compression,
-        checkpoint_bytes,
-        use_stat_cache,
-        primary_key_id,
-        send_key_id,
-        hash_key,
-        data_ectx,
-        metadata_ectx,
-    };
 
     progress.set_message(&"acquiring repository lock...");
-    client::open_repository(&mut serve_in, &mut serve_out, protocol::LockHint::Write)?;
-    let id = client::send(
-        &mut ctx,
+    client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Gc,
+    )?;
+    let sweep_workers = match matches.opt_str("sweep-workers") {
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => Some(n),
+            Ok(_) => failure::bail!("--sweep-workers must be greater than 0"),
+            Err(err) => failure::bail!("unable to parse --sweep-workers: {}", err),
+        },
+        None => None,
+    };
+
+    let stats = client::gc(
+        progress.clone(),
+        pacing,
+        sweep_workers,
         &mut serve_out,
         &mut serve_in,
-        send_log,
-        tags,
-        &mut data_source,
     )?;
     client::hangup(&mut serve_in)?;
 
     progress.finish_and_clear();
 
-    println!("{}", id);
+    if let Some(chunks_freed) = stats.chunks_freed {
+        println!("{} chunks freed", chunks_freed);
+    }
+    if let Some(chunks_remaining) = stats.chunks_remaining {
+        println!("{} chunks remaining", chunks_remaining);
+    }
+    if let Some(bytes_freed) = stats.bytes_freed {
+        println!("{} bytes freed", bytes_freed);
+    }
+    if let Some(bytes_remaining) = stats.bytes_remaining {
+        println!("{} bytes remaining", bytes_remaining);
+    }
     Ok(())
 }
 
-fn get_main(args: Vec<String>) -> Result<(), failure::Error> {
+fn repo_stats_main(args: Vec<String>) -> Result<(), failure::Error> {
     let mut opts = default_cli_opts();
-    repo_opts(&mut opts);
-    query_opts(&mut opts);
-    opts.optopt("k", "key", "Primary key to decrypt data with.", "PATH");
+    opts.optflag("q", "quiet", "Suppress progress indicators.");
     opts.optopt(
         "",
-        "pick",
-        "Pick a single file or directory from a directory snapshot.",
-        "PATH",
+        "format",
+        "Output format, valid values are 'human' or 'jsonl'.",
+        "FORMAT",
     );
 
+    repo_opts(&mut opts);
     let matches = parse_cli_opts(opts, &args[..]);
 
-    let key = matches_to_key(&matches)?;
-    let primary_key_id = key.primary_key_id();
-    let (hash_key_part_1, data_dctx, metadata_dctx) = match key {
-        keys::Key::PrimaryKeyV1(k) => {
-            let hash_key_part_1 = k.hash_key_part_1.clone();
-            let data_dctx = crypto::DecryptionContext::new(k.data_sk, k.data_psk.clone());
-            let metadata_dctx = crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk);
-            (hash_key_part_1, data_dctx, metadata_dctx)
-        }
-        _ => failure::bail!("provided key is not a decryption key"),
+    let list_format = match matches.opt_str("format") {
+        Some(f) => match &f[..] {
+            "jsonl" => ListFormat::Jsonl,
+            "human" => ListFormat::Human,
+            _ => failure::bail!("invalid --format, expected one of 'human' or 'jsonl'"),
+        },
+        None => ListFormat::Human,
     };
 
     let progress = matches_to_progress_bar(
@@ -804,443 +4672,577 @@ fn get_main(args: Vec<String>) -> Result<(), failure::Error> {
         indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
     )?;
 
-    let (id, query) = matches_to_id_and_query(&matches)?;
     let mut serve_proc = matches_to_serve_process(&matches)?;
     let mut serve_out = serve_proc.stdout.as_mut().unwrap();
     let mut serve_in = serve_proc.stdin.as_mut().unwrap();
 
-    progress.set_message(&"acquiring repository lock...");
-    client::open_repository(&mut serve_in, &mut serve_out, protocol::LockHint::Read)?;
-
-    let id = match (id, query) {
-        (Some(id), _) => id,
-        (_, query) => {
-            let mut query_cache = matches_to_query_cache(&matches)?;
+    client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+    let stats = client::repo_stats(progress.clone(), &mut serve_out, &mut serve_in)?;
+    client::hangup(&mut serve_in)?;
 
-            // Only sync the client if we have a non id query.
-            client::sync(
-                progress.clone(),
-                &mut query_cache,
-                &mut serve_out,
-                &mut serve_in,
-            )?;
+    progress.finish_and_clear();
 
-            let mut n_matches: u64 = 0;
-            let mut id = xid::Xid::default();
+    match list_format {
+        ListFormat::Human => {
+            println!("gc-generation: {}", stats.gc_generation);
+            println!("items: {}", stats.item_count.0);
+            println!(
+                "item-log-head: {}",
+                hex::easy_encode_to_string(&stats.item_log_head[..])
+            );
+            if stats.lock_holders.is_empty() {
+                println!("lock: unlocked");
+            } else {
+                let now = chrono::Utc::now();
+                for holder in stats.lock_holders.iter() {
+                    println!("lock: {}", holder.describe(now));
+                }
+            }
+        }
+        ListFormat::Jsonl => {
+            print!("{{");
+            print!("\"gc_generation\":\"{}\",", stats.gc_generation);
+            print!("\"items\":{},", stats.item_count.0);
+            print!(
+                "\"item_log_head\":\"{}\",",
+                hex::easy_encode_to_string(&stats.item_log_head[..])
+            );
+            print!(
+                "\"lock_holders\":{}",
+                serde_json::to_string(&stats.lock_holders)?
+            );
+            println!("}}");
+        }
+    }
 
-            let mut on_match =
-                |item_id: xid::Xid, _tags: std::collections::BTreeMap<String, String>| {
-                    n_matches += 1;
-                    id = item_id;
+    Ok(())
+}
 
-                    if n_matches > 1 {
-                        failure::bail!(
-                            "the provided query matched {} items, need a single match",
-                            n_matches
-                        );
-                    }
+fn shared_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    opts.optflag("q", "quiet", "Suppress progress indicators.");
+    opts.optopt(
+        "",
+        "format",
+        "Output format, valid values are 'human' or 'jsonl'.",
+        "FORMAT",
+    );
 
-                    Ok(())
-                };
+    repo_opts(&mut opts);
+    let matches = parse_cli_opts(opts, &args[..]);
 
-            let mut tx = query_cache.transaction()?;
-            tx.list(
-                querycache::ListOptions {
-                    primary_key_id: Some(primary_key_id),
-                    metadata_dctx: Some(metadata_dctx.clone()),
-                    list_encrypted: matches.opt_present("query-encrypted"),
-                    utc_timestamps: matches.opt_present("utc-timestamps"),
-                    query: Some(query),
-                    now: chrono::Utc::now(),
-                },
-                &mut on_match,
-            )?;
+    let list_format = match matches.opt_str("format") {
+        Some(f) => match &f[..] {
+            "jsonl" => ListFormat::Jsonl,
+            "human" => ListFormat::Human,
+            _ => failure::bail!("invalid --format, expected one of 'human' or 'jsonl'"),
+        },
+        None => ListFormat::Human,
+    };
 
-            id
+    let mut ids = Vec::new();
+    for id in matches.free.iter() {
+        match xid::Xid::parse(id.strip_prefix("id=").unwrap_or(id)) {
+            Ok(id) => ids.push(id),
+            Err(err) => failure::bail!("unable to parse item id {:?}: {}", id, err),
         }
-    };
+    }
+    if ids.len() != 2 {
+        failure::bail!("expected two item ids to compare");
+    }
 
-    let pick = if matches.opt_present("pick") {
-        let content_index = client::request_index(
-            client::DataRequestContext {
-                progress: progress.clone(),
-                primary_key_id,
-                hash_key_part_1: hash_key_part_1.clone(),
-                data_dctx: data_dctx.clone(),
-                metadata_dctx: metadata_dctx.clone(),
-            },
-            id,
-            &mut serve_out,
-            &mut serve_in,
-        )?;
+    let progress = matches_to_progress_bar(
+        &matches,
+        indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
+    )?;
 
-        Some(index::pick(
-            &matches.opt_str("pick").unwrap(),
-            &content_index,
-        )?)
-    } else {
-        None
-    };
+    let mut serve_proc = matches_to_serve_process(&matches)?;
+    let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+    let mut serve_in = serve_proc.stdin.as_mut().unwrap();
 
-    client::request_data_stream(
-        client::DataRequestContext {
-            progress: progress.clone(),
-            primary_key_id,
-            hash_key_part_1,
-            data_dctx,
-            metadata_dctx,
-        },
-        id,
-        pick,
+    client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+    let stats = client::chunk_sharing(
+        progress.clone(),
+        ids[0],
+        ids[1],
         &mut serve_out,
         &mut serve_in,
-        &mut std::io::stdout().lock(),
     )?;
-
     client::hangup(&mut serve_in)?;
 
     progress.finish_and_clear();
 
+    match list_format {
+        ListFormat::Human => {
+            println!(
+                "only in {}: {} chunks, {}",
+                ids[0],
+                stats.a_unique_chunks,
+                indicatif::HumanBytes(stats.a_unique_bytes)
+            );
+            println!(
+                "only in {}: {} chunks, {}",
+                ids[1],
+                stats.b_unique_chunks,
+                indicatif::HumanBytes(stats.b_unique_bytes)
+            );
+            println!(
+                "shared: {} chunks, {}",
+                stats.shared_chunks,
+                indicatif::HumanBytes(stats.shared_bytes)
+            );
+        }
+        ListFormat::Jsonl => {
+            print!("{{");
+            print!("\"a\":\"{}\",", ids[0]);
+            print!("\"b\":\"{}\",", ids[1]);
+            print!("\"a_unique_chunks\":{},", stats.a_unique_chunks);
+            print!("\"a_unique_bytes\":{},", stats.a_unique_bytes);
+            print!("\"b_unique_chunks\":{},", stats.b_unique_chunks);
+            print!("\"b_unique_bytes\":{},", stats.b_unique_bytes);
+            print!("\"shared_chunks\":{},", stats.shared_chunks);
+            print!("\"shared_bytes\":{}", stats.shared_bytes);
+            println!("}}");
+        }
+    }
+
     Ok(())
 }
 
-fn list_contents_main(args: Vec<String>) -> Result<(), failure::Error> {
+fn diff_main(args: Vec<String>) -> Result<(), failure::Error> {
     let mut opts = default_cli_opts();
     repo_opts(&mut opts);
-    query_opts(&mut opts);
-    opts.optopt("k", "key", "Primary key to decrypt data with.", "PATH");
+    opts.optflag("q", "quiet", "Suppress progress indicators.");
+    opts.optopt(
+        "k",
+        "key",
+        "Primary key to decrypt the indexes with.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "query-cache",
+        "Path to the query cache, fetched indexes are saved in it.",
+        "PATH",
+    );
     opts.optopt(
         "",
         "format",
-        "Output format, valid values are 'human' or 'jsonl'.",
+        "Output format, valid values are 'human', 'jsonl' or 'json'.",
         "FORMAT",
     );
 
     let matches = parse_cli_opts(opts, &args[..]);
 
-    let list_format = match matches.opt_str("format") {
+    // 'json' prints the objects of 'jsonl' as a single array.
+    let (list_format, json_array) = match matches.opt_str("format") {
         Some(f) => match &f[..] {
-            "jsonl" => ListFormat::Jsonl,
-            "human" => ListFormat::Human,
-            _ => failure::bail!("invalid --format, expected one of 'human' or 'jsonl'"),
+            "jsonl" => (ListFormat::Jsonl, false),
+            "json" => (ListFormat::Jsonl, true),
+            "human" => (ListFormat::Human, false),
+            _ => failure::bail!("invalid --format, expected one of 'human', 'jsonl' or 'json'"),
         },
-        None => ListFormat::Human,
+        None => (ListFormat::Human, false),
     };
 
+    let mut ids = Vec::new();
+    for id in matches.free.iter() {
+        match xid::Xid::parse(id.strip_prefix("id=").unwrap_or(id)) {
+            Ok(id) => ids.push(id),
+            Err(err) => failure::bail!("unable to parse item id {:?}: {}", id, err),
+        }
+    }
+    if ids.len() != 2 {
+        failure::bail!("expected two item ids to compare");
+    }
+
     let key = matches_to_key(&matches)?;
     let primary_key_id = key.primary_key_id();
-    let (hash_key_part_1, data_dctx, metadata_dctx) = match key {
+    let (hash_key_part_1, data_dctx, metadata_dctx) = match &key {
         keys::Key::PrimaryKeyV1(k) => {
             let hash_key_part_1 = k.hash_key_part_1.clone();
-            let data_dctx = crypto::DecryptionContext::new(k.data_sk, k.data_psk.clone());
-            let metadata_dctx = crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk);
+            let data_dctx = crypto::DecryptionContext::new(k.data_sk.clone(), k.data_psk.clone());
+            let metadata_dctx =
+                crypto::DecryptionContext::new(k.metadata_sk.clone(), k.metadata_psk.clone());
             (hash_key_part_1, data_dctx, metadata_dctx)
         }
         _ => failure::bail!("provided key is not a decryption key"),
     };
+    let decode_workers = client::SharedDecodeWorkers::default();
 
     let progress = matches_to_progress_bar(
         &matches,
         indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
     )?;
 
-    let (id, query) = matches_to_id_and_query(&matches)?;
     let mut serve_proc = matches_to_serve_process(&matches)?;
     let mut serve_out = serve_proc.stdout.as_mut().unwrap();
     let mut serve_in = serve_proc.stdin.as_mut().unwrap();
 
-    progress.set_message(&"acquiring repository lock...");
-    client::open_repository(&mut serve_in, &mut serve_out, protocol::LockHint::Read)?;
-
-    let id = match (id, query) {
-        (Some(id), _) => id,
-        (_, query) => {
-            let mut query_cache = matches_to_query_cache(&matches)?;
-
-            // Only sync the client if we have a non id query.
-            client::sync(
-                progress.clone(),
-                &mut query_cache,
-                &mut serve_out,
-                &mut serve_in,
-            )?;
-
-            let mut n_matches: u64 = 0;
-            let mut id = xid::Xid::default();
-
-            let mut on_match =
-                |item_id: xid::Xid, _tags: std::collections::BTreeMap<String, String>| {
-                    n_matches += 1;
-                    id = item_id;
-
-                    if n_matches > 1 {
-                        failure::bail!(
-                            "the provided query matched {} items, need a single match",
-                            n_matches
-                        );
-                    }
-
-                    Ok(())
-                };
-
-            let mut tx = query_cache.transaction()?;
-            tx.list(
-                querycache::ListOptions {
-                    primary_key_id: Some(primary_key_id),
-                    metadata_dctx: Some(metadata_dctx.clone()),
-                    list_encrypted: matches.opt_present("query-encrypted"),
-                    utc_timestamps: matches.opt_present("utc-timestamps"),
-                    query: Some(query),
-                    now: chrono::Utc::now(),
-                },
-                &mut on_match,
-            )?;
-
-            id
-        }
-    };
-
-    let mut content_index = client::request_index(
-        client::DataRequestContext {
-            progress: progress.clone(),
-            primary_key_id,
-            hash_key_part_1,
-            data_dctx,
-            metadata_dctx,
-        },
-        id,
-        &mut serve_out,
+    progress.set_message("acquiring repository lock...");
+    let repo_info = client::open_repository(
+        &progress,
         &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
     )?;
 
+    let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+
+    let mut indexes = Vec::with_capacity(2);
+    for id in ids.iter() {
+        indexes.push(client::fetch_content_index(
+            client::DataRequestContext {
+                progress: progress.clone(),
+                primary_key_id,
+                hash_key_part_1: hash_key_part_1.clone(),
+                data_dctx: data_dctx.clone(),
+                metadata_dctx: metadata_dctx.clone(),
+                decode_workers: decode_workers.clone(),
+            },
+            *id,
+            &mut query_cache,
+            &mut serve_out,
+            &mut serve_in,
+        )?);
+    }
     client::hangup(&mut serve_in)?;
 
     progress.finish_and_clear();
 
-    // Due to how 'put' works, our tarballs are not ordered in a way that is pleasant by default.
-    content_index.sort_by(|a, b| match (a, b) {
-        (index::VersionedIndexEntry::V1(ref a), index::VersionedIndexEntry::V1(ref b)) => {
-            a.path.cmp(&b.path)
+    let new_index = indexes.pop().unwrap();
+    let old_index = indexes.pop().unwrap();
+
+    // Content hashes are keyed by the key that sent an item, they
+    // can only be compared for items sent with the same send key.
+    let mut tx = query_cache.transaction()?;
+    let mut hash_keys = Vec::with_capacity(2);
+    for id in ids.iter() {
+        match tx.lookup_content_index(id)? {
+            Some((metadata, _)) => hash_keys.push(
+                metadata
+                    .decrypt_metadata(&mut metadata_dctx.clone())?
+                    .hash_key_part_2,
+            ),
+            None => failure::bail!("the index of item {} is missing from the query cache", id),
         }
-    });
+    }
+    drop(tx);
 
-    let utc_timestamps = matches.opt_present("utc-timestamps");
+    let mut diffs = index::diff_indexes(old_index, new_index);
+    if hash_keys[0] != hash_keys[1] {
+        for diff in diffs.iter_mut() {
+            diff.content_changed = None;
+        }
+    }
 
-    match list_format {
-        ListFormat::Human => {
-            let mut max_size_digits = 0;
-            for item in content_index.iter() {
-                match item {
-                    index::VersionedIndexEntry::V1(item) => {
-                        max_size_digits =
-                            std::cmp::max(item.size.0.to_string().len(), max_size_digits)
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    let mut json_diffs = Vec::new();
+    for diff in diffs.iter() {
+        let kind = diff
+            .new
+            .as_ref()
+            .or(diff.old.as_ref())
+            .unwrap()
+            .entry
+            .kind();
+        match list_format {
+            ListFormat::Human => {
+                let (prefix, is_file) = match diff.kind {
+                    index::DiffKind::Added => ("A", false),
+                    index::DiffKind::Removed => ("D", false),
+                    index::DiffKind::Changed => ("M", true),
+                };
+                let mut details = Vec::new();
+                if is_file {
+                    let size_delta = diff.size_delta();
+                    if size_delta != 0 {
+                        details.push(format!("size {:+}", size_delta));
                     }
-                }
-            }
-
-            for item in content_index.iter() {
-                match item {
-                    index::VersionedIndexEntry::V1(item) => {
-                        let ts = chrono::NaiveDateTime::from_timestamp(
-                            item.ctime.0 as i64,
-                            item.ctime_nsec.0 as u32,
-                        );
-                        let ts = chrono::DateTime::<chrono::Utc>::from_utc(ts, chrono::Utc);
-
-                        let tsfmt = "%Y/%m/%d %T";
-
-                        let ts = if utc_timestamps {
-                            ts.format(tsfmt).to_string()
-                        } else {
-                            chrono::DateTime::<chrono::Local>::from(ts)
-                                .format(tsfmt)
-                                .to_string()
-                        };
-
-                        let size = format!("{}", item.size.0);
-                        let size_padding: String = std::iter::repeat(' ')
-                            .take(max_size_digits - size.len())
-                            .collect();
-
-                        println!(
-                            "{} {}{} {} {}",
-                            item.display_mode(),
-                            size,
-                            size_padding,
-                            ts,
-                            item.path,
-                        );
+                    match diff.mtime_delta() {
+                        Some(mtime_delta) if mtime_delta != 0 => {
+                            details.push(format!("mtime {:+}s", mtime_delta))
+                        }
+                        _ => (),
+                    }
+                    if diff.content_changed == Some(true) {
+                        details.push("contents differ".to_string());
                     }
                 }
+                let suffix = if kind == index::IndexEntryKind::Directory {
+                    "/"
+                } else {
+                    ""
+                };
+                if details.is_empty() {
+                    writeln!(out, "{} {}{}", prefix, diff.path, suffix)?;
+                } else {
+                    writeln!(
+                        out,
+                        "{} {}{} ({})",
+                        prefix,
+                        diff.path,
+                        suffix,
+                        details.join(", ")
+                    )?;
+                }
             }
-        }
-        ListFormat::Jsonl => {
-            for item in content_index.iter() {
-                match item {
-                    index::VersionedIndexEntry::V1(item) => {
-                        print!("{{");
-                        print!("\"mode\":{},", serde_json::to_string(&item.mode.0)?);
-                        print!("\"size\":{},", item.size.0);
-                        print!("\"path\":{},", serde_json::to_string(&item.path)?);
-                        print!("\"ctime\":{},", serde_json::to_string(&item.ctime.0)?);
-                        print!(
-                            "\"ctime_nsec\":{}",
-                            serde_json::to_string(&item.ctime_nsec.0)?
-                        );
-                        print!("}}");
-                        println!();
-                    }
+            ListFormat::Jsonl => {
+                let change = match diff.kind {
+                    index::DiffKind::Added => "added",
+                    index::DiffKind::Removed => "removed",
+                    index::DiffKind::Changed => "changed",
+                };
+                let json = serde_json::json!({
+                    "change": change,
+                    "path": diff.path,
+                    "type": kind.name(),
+                    "old_size": diff.old.as_ref().map(|side| side.entry.size.0),
+                    "new_size": diff.new.as_ref().map(|side| side.entry.size.0),
+                    "size_delta": diff.size_delta(),
+                    "mtime_delta": diff.mtime_delta(),
+                    "content_changed": diff.content_changed,
+                });
+                if json_array {
+                    json_diffs.push(json);
+                } else {
+                    writeln!(out, "{}", json)?;
                 }
             }
         }
     }
-
-    std::io::stdout().flush()?;
+    if json_array {
+        writeln!(out, "{}", serde_json::Value::Array(json_diffs))?;
+    }
+    out.flush()?;
 
     Ok(())
 }
 
-fn remove_main(args: Vec<String>) -> Result<(), failure::Error> {
+fn clone_main(args: Vec<String>) -> Result<(), failure::Error> {
     let mut opts = default_cli_opts();
     repo_opts(&mut opts);
     query_opts(&mut opts);
-
     opts.optopt(
         "k",
         "key",
-        "Primary or metadata key to decrypt metadata with.",
+        "Primary or metadata key to decrypt and encrypt item metadata with.",
         "PATH",
     );
-
+    opts.optmulti(
+        "",
+        "tag",
+        "Set TAG=VALUE on the new item, may be given more than once.",
+        "TAG=VALUE",
+    );
     opts.optflag(
         "",
-        "ids-from-stdin",
-        "Remove items with IDs read from stdin, one per line, instead of executing a query.",
+        "clear-tags",
+        "Start the new item with no tags instead of the tags of the source item.",
     );
-
-    opts.optflag("", "allow-many", "Allow multiple removals.");
+    opts.optopt("", "note", "Set the note of the new item.", "NOTE");
 
     let matches = parse_cli_opts(opts, &args[..]);
 
+    let key = matches_to_key(&matches)?;
+    let primary_key_id = key.primary_key_id();
+    let (mut metadata_dctx, mut metadata_ectx) = match key {
+        keys::Key::PrimaryKeyV1(k) => (
+            crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk.clone()),
+            crypto::EncryptionContext::new(&k.metadata_pk, &k.metadata_psk, k.nonce_mode),
+        ),
+        keys::Key::MetadataKeyV1(k) => (
+            crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk.clone()),
+            crypto::EncryptionContext::new(&k.metadata_pk, &k.metadata_psk, k.nonce_mode),
+        ),
+        _ => failure::bail!("provided key is not valid for metadata decryption"),
+    };
+
+    let tag_re = regex::Regex::new(r"^([a-zA-Z0-9\\-_]+)=(.+)$").unwrap();
+    let mut set_tags = Vec::new();
+    for a in matches.opt_strs("tag") {
+        match tag_re.captures(&a) {
+            Some(caps) => set_tags.push((caps[1].to_string(), caps[2].to_string())),
+            None => failure::bail!("--tag option {:?} is not a TAG=VALUE pair", a),
+        }
+    }
+
     let progress = matches_to_progress_bar(
         &matches,
         indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
     )?;
 
-    if matches.opt_present("ids-from-stdin") {
-        let mut ids = Vec::new();
-
-        for l in std::io::stdin().lock().lines() {
-            let l = l?;
-            if l.is_empty() {
-                continue;
-            }
-            match xid::Xid::parse(&l) {
-                Ok(id) => ids.push(id),
-                Err(err) => failure::bail!("error id parsing {:?}: {}", l, err),
-            };
-        }
-
-        let mut serve_proc = matches_to_serve_process(&matches)?;
-        let mut serve_out = serve_proc.stdout.as_mut().unwrap();
-        let mut serve_in = serve_proc.stdin.as_mut().unwrap();
-
-        progress.set_message(&"acquiring repository lock...");
-        client::open_repository(&mut serve_in, &mut serve_out, protocol::LockHint::Write)?;
-        client::remove(progress.clone(), ids, &mut serve_out, &mut serve_in)?;
-        client::hangup(&mut serve_in)?;
-    } else {
-        let mut serve_proc = matches_to_serve_process(&matches)?;
-        let mut serve_out = serve_proc.stdout.as_mut().unwrap();
-        let mut serve_in = serve_proc.stdin.as_mut().unwrap();
-        progress.set_message(&"acquiring repository lock...");
-        client::open_repository(&mut serve_in, &mut serve_out, protocol::LockHint::Write)?;
-
-        let ids: Vec<xid::Xid> = match matches_to_id_and_query(&matches)? {
-            (Some(id), _) => vec![id],
-            (_, query) => {
-                let mut query_cache = matches_to_query_cache(&matches)?;
+    let (id, query) = matches_to_id_and_query(&matches)?;
+    let mut serve_proc = matches_to_serve_process(&matches)?;
+    let mut serve_out = serve_proc.stdout.as_mut().unwrap();
+    let mut serve_in = serve_proc.stdin.as_mut().unwrap();
 
-                // Only sync the client if we have a non id query.
-                client::sync(
-                    progress.clone(),
-                    &mut query_cache,
-                    &mut serve_out,
-                    &mut serve_in,
-                )?;
+    progress.set_message("acquiring repository lock...");
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Write,
+    )?;
 
-                let (primary_key_id, metadata_dctx) = match matches_to_opt_key(&matches)? {
-                    Some(key) => {
-                        let primary_key_id = key.primary_key_id();
-                        let metadata_dctx = match key {
-                            keys::Key::PrimaryKeyV1(k) => {
-                                crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk)
-                            }
-                            keys::Key::MetadataKeyV1(k) => {
-                                crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk)
-                            }
-                            _ => {
-                                failure::bail!("provided key is not valid for metadata decryption")
-                            }
-                        };
+    let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+    client::sync(
+        progress.clone(),
+        &mut query_cache,
+        &mut serve_out,
+        &mut serve_in,
+    )?;
 
-                        (Some(primary_key_id), Some(metadata_dctx))
-                    }
-                    None => {
-                        if !matches.opt_present("query-encrypted") {
-                            failure::bail!("please set --key, BUPSTASH_KEY, BUPSTASH_KEY_COMMAND or pass --query-encrypted");
-                        }
-                        (None, None)
-                    }
-                };
+    let mut tx = query_cache.transaction()?;
 
-                let mut ids = Vec::new();
+    let id = match id {
+        Some(id) => id,
+        None => {
+            let mut n_matches: u64 = 0;
+            let mut id = xid::Xid::default();
 
-                let mut on_match =
-                    |item_id: xid::Xid, _tags: std::collections::BTreeMap<String, String>| {
-                        ids.push(item_id);
-                        Ok(())
-                    };
+            let mut on_match =
+                |item_id: xid::Xid, _tags: std::collections::BTreeMap<String, String>| {
+                    n_matches += 1;
+                    id = item_id;
 
-                let mut tx = query_cache.transaction()?;
-                tx.list(
-                    querycache::ListOptions {
-                        primary_key_id,
-                        metadata_dctx,
-                        list_encrypted: matches.opt_present("query-encrypted"),
-                        utc_timestamps: matches.opt_present("utc-timestamps"),
-                        query: Some(query),
-                        now: chrono::Utc::now(),
-                    },
-                    &mut on_match,
-                )?;
+                    if n_matches > 1 {
+                        failure::bail!(
+                            "the provided query matched {} items, need a single match",
+                            n_matches
+                        );
+                    }
 
-                if ids.len() > 1 && !matches.opt_present("allow-many") {
-                    failure::bail!(
-                        "the provided query matched {} items, need a single match unless --allow-many is specified",
-                        ids.len()
-                    );
+                    Ok(())
                 };
 
-                ids
+            tx.list(
+                querycache::ListOptions {
+                    primary_key_id: Some(primary_key_id),
+                    metadata_dctx: Some(metadata_dctx.clone()),
+                    list_encrypted: matches.opt_present("query-encrypted"),
+                    utc_timestamps: matches.opt_present("utc-timestamps"),
+                    query: Some(query),
+                    now: chrono::Utc::now(),
+                    order: querycache::ListOrder::default(),
+                },
+                &mut on_match,
+            )?;
+
+            if n_matches == 0 {
+                failure::bail!("no stored items match the provided query");
             }
-        };
-        client::remove(progress.clone(), ids, &mut serve_out, &mut serve_in)?;
-        client::hangup(&mut serve_in)?;
+
+            id
+        }
+    };
+
+    let metadata = match tx.lookup_item_by_id(&id)? {
+        Some(metadata) => metadata,
+        None => failure::bail!("no stored items with the requested id"),
     };
 
+    let plain_text_metadata = metadata.plain_text_metadata().clone();
+    if plain_text_metadata.primary_key_id != primary_key_id {
+        failure::bail!("the requested item was not sent with the provided key");
+    }
+
+    // The clone keeps the timestamp of its source, it holds the same data.
+    let mut emd = metadata.decrypt_metadata(&mut metadata_dctx)?;
+    if matches.opt_present("clear-tags") {
+        emd.tags.clear();
+    }
+    for (t, v) in set_tags.into_iter() {
+        emd.tags.insert(t, v);
+    }
+    if let Some(note) = matches.opt_str("note") {
+        emd.note = Some(note);
+    }
+
+    // Tags are encrypted, so the server is unable to check them.
+    if let Err(err) =
+        client::check_tags(&None, repo_info.tag_schema.as_deref(), &emd.tags, &emd.note)
+    {
+        failure::bail!("refusing to clone item: {}", err);
+    }
+
+    let item = itemset::VersionedItemMetadata::V2(itemset::ItemMetadata {
+        plain_text_metadata,
+        encrypted_metadata: metadata_ectx.encrypt_data(
+            serde_bare::to_vec(&emd)?,
+            crypto::DataCompression::Zstd(crypto::DEFAULT_ZSTD_LEVEL),
+        ),
+    });
+
+    let new_id = client::clone_item(progress.clone(), id, item, &mut serve_out, &mut serve_in)?;
+    client::hangup(&mut serve_in)?;
     progress.finish_and_clear();
 
+    println!("{}", new_id);
+
     Ok(())
 }
 
-fn gc_main(args: Vec<String>) -> Result<(), failure::Error> {
+fn restore_send_log_main(args: Vec<String>) -> Result<(), failure::Error> {
     let mut opts = default_cli_opts();
-    opts.optflag("q", "quiet", "Suppress progress indicators.");
-
     repo_opts(&mut opts);
+    query_opts(&mut opts);
+    opts.optopt(
+        "k",
+        "key",
+        "Key the send log is used with, a primary or metadata key is needed \
+        to decrypt item metadata unless --query-encrypted is given.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "send-log",
+        "Path to the send log to seed, see the put manual for the default.",
+        "PATH",
+    );
+
     let matches = parse_cli_opts(opts, &args[..]);
 
+    let key = matches_to_key(&matches)?;
+    let primary_key_id = key.primary_key_id();
+    let metadata_dctx = match key {
+        keys::Key::PrimaryKeyV1(k) => Some(crypto::DecryptionContext::new(
+            k.metadata_sk,
+            k.metadata_psk,
+        )),
+        keys::Key::MetadataKeyV1(k) => Some(crypto::DecryptionContext::new(
+            k.metadata_sk,
+            k.metadata_psk,
+        )),
+        _ => None,
+    };
+    if metadata_dctx.is_none() && !matches.opt_present("query-encrypted") {
+        failure::bail!("provided key is not valid for metadata decryption, pass --query-encrypted to query without decrypting");
+    }
+
+    let query = if !matches.free.is_empty() {
+        match query::parse(&matches.free.join("•")) {
+            Ok(query) => query,
+            Err(e) => {
+                query::report_parse_error(e);
+                failure::bail!("query parse error");
+            }
+        }
+    } else {
+        failure::bail!("you must specify a query");
+    };
+
     let progress = matches_to_progress_bar(
         &matches,
         indicatif::ProgressStyle::default_spinner().template("[{elapsed_precise}] {wide_msg}"),
@@ -1250,24 +5252,192 @@ fn gc_main(args: Vec<String>) -> Result<(), failure::Error> {
     let mut serve_out = serve_proc.stdout.as_mut().unwrap();
     let mut serve_in = serve_proc.stdin.as_mut().unwrap();
 
-    progress.set_message(&"acquiring repository lock...");
-    client::open_repository(&mut serve_in, &mut serve_out, protocol::LockHint::Gc)?;
-    let stats = client::gc(progress.clone(), &mut serve_out, &mut serve_in)?;
+    progress.set_message("acquiring repository lock...");
+    let repo_info = client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Read,
+    )?;
+
+    let mut query_cache = matches_to_query_cache(&matches, &repo_info.repository_id)?;
+    client::sync(
+        progress.clone(),
+        &mut query_cache,
+        &mut serve_out,
+        &mut serve_in,
+    )?;
+
+    let mut tx = query_cache.transaction()?;
+    // Seeded addresses are only valid until the next gc.
+    let gc_generation = match tx.current_gc_generation()? {
+        Some(gc_generation) => gc_generation,
+        None => failure::bail!("query cache has no gc generation after sync"),
+    };
+    let mut ids = Vec::new();
+    tx.list(
+        querycache::ListOptions {
+            primary_key_id: Some(primary_key_id),
+            metadata_dctx,
+            list_encrypted: matches.opt_present("query-encrypted"),
+            utc_timestamps: matches.opt_present("utc-timestamps"),
+            query: Some(query),
+            now: chrono::Utc::now(),
+            order: querycache::ListOrder::default(),
+        },
+        &mut |id, _tags| {
+            ids.push(id);
+            Ok(())
+        },
+    )?;
+    drop(tx);
+
+    if ids.is_empty() {
+        failure::bail!("no stored items match the provided query");
+    }
+    let n_items = ids.len();
+
+    let mut send_log = matches_to_send_log(&matches, &repo_info.repository_id, &primary_key_id)?;
+    let session = send_log.session(gc_generation)?;
+    let mut n_addresses: u64 = 0;
+    client::item_addresses(
+        progress.clone(),
+        ids,
+        &mut |addresses| {
+            for addr in addresses.iter() {
+                session.add_address(addr)?;
+            }
+            n_addresses += addresses.len() as u64;
+            Ok(())
+        },
+        &mut serve_out,
+        &mut serve_in,
+    )?;
     client::hangup(&mut serve_in)?;
+    session.commit_seeded()?;
 
     progress.finish_and_clear();
 
-    if let Some(chunks_freed) = stats.chunks_freed {
-        println!("{} chunks freed", chunks_freed);
-    }
-    if let Some(chunks_remaining) = stats.chunks_remaining {
-        println!("{} chunks remaining", chunks_remaining);
+    println!(
+        "{} chunk address(es) from {} item(s) added to the send log",
+        n_addresses, n_items
+    );
+
+    Ok(())
+}
+
+fn completions_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    repo_opts(&mut opts);
+    opts.optopt(
+        "k",
+        "key",
+        "Primary or metadata key to decrypt tag names with, used with --tags.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "query-cache",
+        "Path to the query cache to read tag names from, used with --tags.",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "tags",
+        "Print the tag names in the query cache, used by the completion scripts.",
+    );
+
+    let matches = parse_cli_opts(opts, &args[..]);
+
+    if matches.opt_present("tags") {
+        return print_completion_tags(&matches);
     }
-    if let Some(bytes_freed) = stats.bytes_freed {
-        println!("{} bytes freed", bytes_freed);
+
+    let shell = match matches.free.len() {
+        1 => matches.free[0].clone(),
+        _ => failure::bail!("expected a single shell name, one of 'bash', 'zsh' or 'fish'"),
+    };
+    let generate = match shell.as_str() {
+        "bash" => completions::bash,
+        "zsh" => completions::zsh,
+        "fish" => completions::fish,
+        _ => failure::bail!(
+            "unsupported shell '{}', expected one of 'bash', 'zsh' or 'fish'",
+            shell
+        ),
+    };
+
+    // Options are read from the help of each subcommand, so they always match the cli.
+    let exe = std::env::current_exe()?;
+    let mut commands = Vec::new();
+    for (name, description) in
+        completions::parse_subcommands(include_str!("../doc/cli/help.txt")).into_iter()
+    {
+        let opts = match name.as_str() {
+            "help" | "version" => Vec::new(),
+            _ => {
+                let output = std::process::Command::new(&exe)
+                    .arg(&name)
+                    .arg("--help")
+                    .stdin(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .output()?;
+                if !output.status.success() {
+                    failure::bail!("unable to get the options of '{}'", name);
+                }
+                completions::parse_usage(&String::from_utf8_lossy(&output.stdout))
+            }
+        };
+        commands.push(completions::CompletionCommand {
+            name,
+            description,
+            opts,
+        });
     }
-    if let Some(bytes_remaining) = stats.bytes_remaining {
-        println!("{} bytes remaining", bytes_remaining);
+
+    print!("{}", generate(&commands));
+    Ok(())
+}
+
+// Completion runs often, so tags come from the query cache as it is, without syncing.
+fn print_completion_tags(matches: &Matches) -> Result<(), failure::Error> {
+    let key = match matches_to_opt_key(matches)? {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+    let primary_key_id = key.primary_key_id();
+    let metadata_dctx = match key {
+        keys::Key::PrimaryKeyV1(k) => crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk),
+        keys::Key::MetadataKeyV1(k) => {
+            crypto::DecryptionContext::new(k.metadata_sk, k.metadata_psk)
+        }
+        _ => return Ok(()),
+    };
+
+    let mut tag_names = std::collections::BTreeSet::new();
+    let mut query_cache = match matches_to_offline_query_cache(matches)? {
+        Some(query_cache) => query_cache,
+        None => return Ok(()),
+    };
+    let mut tx = query_cache.transaction()?;
+    tx.list(
+        querycache::ListOptions {
+            primary_key_id: Some(primary_key_id),
+            metadata_dctx: Some(metadata_dctx),
+            list_encrypted: false,
+            utc_timestamps: false,
+            query: None,
+            now: chrono::Utc::now(),
+            order: querycache::ListOrder::default(),
+        },
+        &mut |_id, tags| {
+            tag_names.extend(tags.into_keys());
+            Ok(())
+        },
+    )?;
+
+    for name in tag_names.iter() {
+        println!("{}=", name);
     }
     Ok(())
 }
@@ -1289,7 +5459,12 @@ fn restore_removed(args: Vec<String>) -> Result<(), failure::Error> {
     let mut serve_in = serve_proc.stdin.as_mut().unwrap();
 
     progress.set_message(&"acquiring repository lock...");
-    client::open_repository(&mut serve_in, &mut serve_out, protocol::LockHint::Write)?;
+    client::open_repository(
+        &progress,
+        &mut serve_in,
+        &mut serve_out,
+        protocol::LockHint::Write,
+    )?;
     let n_restored = client::restore_removed(progress.clone(), &mut serve_out, &mut serve_in)?;
     client::hangup(&mut serve_in)?;
 
@@ -1300,6 +5475,9 @@ fn restore_removed(args: Vec<String>) -> Result<(), failure::Error> {
     Ok(())
 }
 
+// Enough for hash tree nodes and the chunks sent by 'put --max-memory' with a small budget.
+const MIN_SERVE_MAX_PACKET_SIZE: usize = 2 * 1024 * 1024;
+
 fn serve_main(args: Vec<String>) -> Result<(), failure::Error> {
     let mut opts = default_cli_opts();
     opts.optflag(
@@ -1327,6 +5505,29 @@ fn serve_main(args: Vec<String>) -> Result<(), failure::Error> {
         "allow-get",
         "Allow client to get data from the repository.",
     );
+    opts.optopt(
+        "",
+        "max-connections",
+        "Refuse the client if the repository already has N connections from other 'bupstash serve' processes using this option.",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "idle-timeout",
+        "Disconnect the client if it sends nothing for DURATION (e.g. '10m') while the server is waiting for it.",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "max-packet-size",
+        "Refuse packets larger than SIZE, limiting how much memory the client can make the server buffer (default 16MiB).",
+        "SIZE",
+    );
+    opts.optflag(
+        "",
+        "sandbox",
+        "Confine the server to the repository directory, see the manual.",
+    );
 
     let matches = parse_cli_opts(opts, &args[..]);
 
@@ -1353,23 +5554,152 @@ fn serve_main(args: Vec<String>) -> Result<(), failure::Error> {
         allow_get = matches.opt_present("allow-get");
     }
 
+    let max_connections = match matches.opt_str("max-connections") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => failure::bail!("--max-connections must be a positive integer"),
+        },
+        None => None,
+    };
+
+    let idle_timeout = match matches.opt_str("idle-timeout") {
+        Some(duration) => match humantime::parse_duration(&duration) {
+            Ok(duration) => Some(duration),
+            Err(err) => failure::bail!("unable to parse --idle-timeout duration: {}", err),
+        },
+        None => None,
+    };
+
+    let max_packet_size = match matches.opt_str("max-packet-size") {
+        Some(size) => {
+            let size: usize = parse_size(&size)?.try_into()?;
+            if size < MIN_SERVE_MAX_PACKET_SIZE {
+                failure::bail!(
+                    "--max-packet-size must be at least {} bytes",
+                    MIN_SERVE_MAX_PACKET_SIZE
+                );
+            }
+            size
+        }
+        None => protocol::DEFAULT_MAX_PACKET_SIZE,
+    };
+
     if atty::is(atty::Stream::Stdout) {
         eprintln!("'bupstash serve' running on stdin/stdout...");
     }
 
-    server::serve(
-        server::ServerConfig {
-            allow_init,
-            allow_put,
-            allow_remove,
-            allow_gc,
-            allow_get,
-            repo_path: std::path::Path::new(&matches.free[0]).to_path_buf(),
+    let mut repo_path = std::path::Path::new(&matches.free[0]).to_path_buf();
+    if matches.opt_present("sandbox") {
+        sandbox::enter_serve(&repo_path)?;
+        repo_path = std::path::PathBuf::from("/");
+    }
+
+    let cfg = server::ServerConfig {
+        allow_init,
+        allow_put,
+        allow_remove,
+        allow_gc,
+        allow_get,
+        max_connections,
+        max_packet_size,
+        repo_path,
+    };
+
+    match idle_timeout {
+        Some(idle_timeout) => server::serve(
+            cfg,
+            &mut std::io::BufReader::new(server::IdleTimeoutReader::new(0, idle_timeout)),
+            &mut std::io::stdout().lock(),
+        )?,
+        None => server::serve(
+            cfg,
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+        )?,
+    }
+
+    Ok(())
+}
+
+// Maintenance commands that are not part of the normal interface,
+// these are deliberately not listed in the help text.
+fn debug_main(mut args: Vec<String>) -> Result<(), failure::Error> {
+    if args.len() < 2 {
+        failure::bail!("expected a debug subcommand, one of 'dump-htree'");
+    }
+    args.remove(0);
+    match args[0].as_str() {
+        "dump-htree" => {
+            args[0] = "debug-dump-htree".to_string();
+            debug_dump_htree_main(args)
+        }
+        _ => failure::bail!("unknown debug subcommand '{}'", args[0]),
+    }
+}
+
+fn debug_dump_htree_main(args: Vec<String>) -> Result<(), failure::Error> {
+    let mut opts = default_cli_opts();
+    opts.optopt(
+        "r",
+        "repository",
+        "Path to a local repository, if not set, defaults to BUPSTASH_REPOSITORY.",
+        "PATH",
+    );
+    let matches = parse_cli_opts(opts, &args[..]);
+
+    let repo = match matches.opt_str("repository") {
+        Some(repo) => repo,
+        None => match std::env::var_os("BUPSTASH_REPOSITORY") {
+            Some(repo) => repo.into_string().unwrap(),
+            None => failure::bail!("please set --repository or BUPSTASH_REPOSITORY"),
+        },
+    };
+    if repo.starts_with("ssh://") {
+        failure::bail!("dump-htree only works with local repositories");
+    }
+
+    if matches.free.len() != 1 {
+        failure::bail!("expected a single item id");
+    }
+    let id = &matches.free[0];
+    let id = xid::Xid::parse(id.strip_prefix("id=").unwrap_or(id))?;
+
+    let mut repo = repository::Repo::open(std::path::Path::new(&repo))?;
+    // The write lock, which puts share but gc waits for, stops chunks being removed while we walk.
+    repo.alter_lock_mode(
+        repository::LockMode::Write,
+        "debug dump-htree",
+        &mut |msg| {
+            eprintln!("{}", msg);
+            Ok(())
         },
-        &mut std::io::stdin().lock(),
-        &mut std::io::stdout().lock(),
     )?;
 
+    let metadata = match repo.lookup_item_by_id(&id)? {
+        Some(metadata) => metadata,
+        None => failure::bail!("no stored items with the requested id"),
+    };
+
+    let plain_text_metadata = metadata.plain_text_metadata().clone();
+
+    let mut storage_engine = repo.storage_engine()?;
+    let mut out = std::io::stdout();
+    let mut n_problems = 0;
+
+    let mut trees = vec![("data-tree", plain_text_metadata.data_tree)];
+    if let Some(index_tree) = plain_text_metadata.index_tree {
+        trees.push(("index-tree", index_tree));
+    }
+
+    for (name, tree) in trees {
+        writeln!(out, "{}:", name)?;
+        n_problems += htree::dump_tree(&mut storage_engine, tree.height, &tree.address, &mut out)?;
+    }
+
+    if n_problems != 0 {
+        failure::bail!("{} damaged tree blocks", n_problems);
+    }
+
     Ok(())
 }
 
@@ -1392,14 +5722,27 @@ fn main() {
         "new-key" => new_key_main(args),
         "new-put-key" => new_send_key_main(args),
         "new-metadata-key" => new_metadata_key_main(args),
+        "key-info" => key_info_main(args),
         "list" => list_main(args),
         "list-contents" => list_contents_main(args),
+        "timeline" => timeline_main(args),
         "put" => put_main(args),
         "get" => get_main(args),
+        "restore" => restore_main(args),
+        "verify" => verify_main(args),
+        "inspect" => inspect_main(args),
+        "mount" => mount_main(args),
         "gc" => gc_main(args),
+        "repo-stats" => repo_stats_main(args),
+        "shared" => shared_main(args),
+        "diff" => diff_main(args),
+        "clone" => clone_main(args),
+        "restore-send-log" => restore_send_log_main(args),
+        "completions" => completions_main(args),
         "remove" | "rm" => remove_main(args),
         "serve" => serve_main(args),
         "restore-removed" => restore_removed(args),
+        "debug" => debug_main(args),
         "version" | "--version" => {
             args[0] = "version".to_string();
             version_main(args)
@@ -0,0 +1,717 @@
+// A read only FUSE filesystem of directory snapshots, see 'bupstash mount'.
+//
+// Directory listings come from the content index of each item, file reads fetch
+// the data chunks holding the file and keep the most recently used chunks in a cache.
+// The index only records where a file starts and ends in its first and last data
+// chunk, the size of the chunks in between is learned by fetching them.
+
+use super::client;
+use super::crypto;
+use super::index;
+use super::querycache;
+use super::xid::*;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::rc::Rc;
+
+// Items never change, so the kernel may cache what we tell it for a long time.
+const TTL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+// Data chunks are fetched this many at a time when reading a file, so
+// sequential reads do not wait on the repository for every chunk.
+const READ_AHEAD_CHUNKS: u64 = 8;
+
+const ROOT_INO: u64 = 1;
+
+pub struct MountedItem {
+    pub id: Xid,
+    // The name of the item directory when several items are mounted.
+    pub name: String,
+    pub timestamp: std::time::SystemTime,
+}
+
+pub struct MountContext {
+    pub primary_key_id: Xid,
+    pub hash_key_part_1: crypto::PartialHashKey,
+    pub data_dctx: crypto::DecryptionContext,
+    pub metadata_dctx: crypto::DecryptionContext,
+    pub decode_workers: client::SharedDecodeWorkers,
+}
+
+impl MountContext {
+    fn request_context(&self) -> client::DataRequestContext {
+        client::DataRequestContext {
+            progress: indicatif::ProgressBar::hidden(),
+            primary_key_id: self.primary_key_id,
+            hash_key_part_1: self.hash_key_part_1.clone(),
+            data_dctx: self.data_dctx.clone(),
+            metadata_dctx: self.metadata_dctx.clone(),
+            decode_workers: self.decode_workers.clone(),
+        }
+    }
+}
+
+enum NodeContent {
+    // The index of an item is fetched the first time its directory is used.
+    UnloadedItem,
+    Directory(BTreeMap<OsString, u64>),
+    File {
+        entry: index::IndexEntry,
+        // Where the part of the file in each data chunk read so far ends.
+        chunk_ends: Vec<u64>,
+    },
+    Symlink {
+        entry: index::IndexEntry,
+        // Only known up front for entries with stat details.
+        target: Option<OsString>,
+    },
+    Special,
+}
+
+struct Node {
+    parent: u64,
+    item: usize,
+    attr: fuser::FileAttr,
+    content: NodeContent,
+}
+
+type CachedChunk = Rc<Vec<u8>>;
+
+// The most recently used data chunks, keyed by item and chunk index.
+struct ChunkCache {
+    chunks: HashMap<(usize, u64), CachedChunk>,
+    // Least recently used first.
+    order: VecDeque<(usize, u64)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl ChunkCache {
+    fn touch(&mut self, key: (usize, u64)) {
+        if let Some(pos) = self.order.iter().position(|k| *k == key) {
+            self.order.remove(pos);
+            self.order.push_back(key);
+        }
+    }
+
+    fn get(&mut self, key: (usize, u64)) -> Option<CachedChunk> {
+        let data = self.chunks.get(&key).cloned();
+        if data.is_some() {
+            self.touch(key);
+        }
+        data
+    }
+
+    fn insert(&mut self, key: (usize, u64), data: CachedChunk) {
+        if self.chunks.contains_key(&key) {
+            self.touch(key);
+            return;
+        }
+        self.size += data.len();
+        self.order.push_back(key);
+        self.chunks.insert(key, data);
+        while self.size > self.max_size && self.order.len() > 1 {
+            let evicted = self.order.pop_front().unwrap();
+            if let Some(data) = self.chunks.remove(&evicted) {
+                self.size -= data.len();
+            }
+        }
+    }
+}
+
+struct ItemFs {
+    ctx: MountContext,
+    items: Vec<MountedItem>,
+    // Inode n is nodes[n - 1].
+    nodes: Vec<Node>,
+    cache: ChunkCache,
+    query_cache: querycache::QueryCache,
+    serve_out: Box<dyn std::io::Read + Send>,
+    serve_in: Box<dyn std::io::Write + Send>,
+}
+
+fn system_time(secs: u64, nsecs: u64) -> std::time::SystemTime {
+    std::time::UNIX_EPOCH + std::time::Duration::new(secs, nsecs as u32)
+}
+
+fn dir_attr(ino: u64, mtime: std::time::SystemTime) -> fuser::FileAttr {
+    fuser::FileAttr {
+        ino,
+        size: 0,
+        blocks: 0,
+        atime: mtime,
+        mtime,
+        ctime: mtime,
+        crtime: mtime,
+        kind: fuser::FileType::Directory,
+        perm: 0o555,
+        nlink: 2,
+        uid: nix::unistd::getuid().as_raw(),
+        gid: nix::unistd::getgid().as_raw(),
+        rdev: 0,
+        blksize: 4096,
+        flags: 0,
+    }
+}
+
+fn entry_attr(
+    ino: u64,
+    ent: &index::IndexEntry,
+    stat: Option<&index::EntryStat>,
+) -> fuser::FileAttr {
+    let ctime = system_time(ent.ctime.0, ent.ctime_nsec.0);
+    // Entries sent by older versions of bupstash only have the change time.
+    let mtime = match stat {
+        Some(stat) => system_time(stat.mtime.0, stat.mtime_nsec.0),
+        None => ctime,
+    };
+    let (uid, gid) = match stat {
+        Some(stat) => (stat.uid.0 as u32, stat.gid.0 as u32),
+        None => (0, 0),
+    };
+    let kind = match ent.kind() {
+        index::IndexEntryKind::Directory => fuser::FileType::Directory,
+        index::IndexEntryKind::Symlink => fuser::FileType::Symlink,
+        index::IndexEntryKind::Char => fuser::FileType::CharDevice,
+        index::IndexEntryKind::Block => fuser::FileType::BlockDevice,
+        index::IndexEntryKind::Fifo => fuser::FileType::NamedPipe,
+        index::IndexEntryKind::Socket => fuser::FileType::Socket,
+        index::IndexEntryKind::Regular | index::IndexEntryKind::Other => {
+            fuser::FileType::RegularFile
+        }
+    };
+    fuser::FileAttr {
+        ino,
+        size: ent.size.0,
+        blocks: ent.size.0.div_ceil(512),
+        atime: mtime,
+        mtime,
+        ctime,
+        crtime: mtime,
+        kind,
+        perm: (ent.mode.0 & 0o7777) as u16,
+        nlink: if kind == fuser::FileType::Directory {
+            2
+        } else {
+            1
+        },
+        uid,
+        gid,
+        rdev: 0,
+        blksize: 4096,
+        flags: 0,
+    }
+}
+
+impl ItemFs {
+    fn node(&self, ino: u64) -> Option<&Node> {
+        ino.checked_sub(1).and_then(|i| self.nodes.get(i as usize))
+    }
+
+    // Add the entries of the index of an item below its directory.
+    fn load_item(&mut self, ino: u64) -> Result<(), failure::Error> {
+        let item = self.nodes[ino as usize - 1].item;
+        let content_index = client::fetch_content_index(
+            self.ctx.request_context(),
+            self.items[item].id,
+            &mut self.query_cache,
+            &mut self.serve_out,
+            &mut self.serve_in,
+        )?;
+
+        let mut stats = HashMap::new();
+        for ent in content_index.iter() {
+            if let index::VersionedIndexEntry::V2(ent) = ent {
+                stats.insert(ent.entry.path.as_str(), &ent.stat);
+            }
+        }
+
+        let mut dirs = HashMap::new();
+        dirs.insert(".", ino);
+        self.nodes[ino as usize - 1].content = NodeContent::Directory(BTreeMap::new());
+
+        for ent in content_index.iter() {
+            let ent = match ent.index_entry() {
+                Some(ent) => ent,
+                None => continue,
+            };
+            let stat = stats.get(ent.path.as_str()).copied();
+
+            if ent.path == "." {
+                self.nodes[ino as usize - 1].attr = entry_attr(ino, ent, stat);
+                continue;
+            }
+            let parent = match ent.parent_path().and_then(|parent| dirs.get(parent)) {
+                Some(parent) => *parent,
+                None => continue,
+            };
+
+            let child_ino = self.nodes.len() as u64 + 1;
+            let content = match ent.kind() {
+                index::IndexEntryKind::Directory => {
+                    dirs.insert(ent.path.as_str(), child_ino);
+                    NodeContent::Directory(BTreeMap::new())
+                }
+                index::IndexEntryKind::Regular => NodeContent::File {
+                    entry: ent.clone(),
+                    chunk_ends: Vec::new(),
+                },
+                index::IndexEntryKind::Symlink => NodeContent::Symlink {
+                    entry: ent.clone(),
+                    target: stat
+                        .and_then(|stat| stat.link_target.clone())
+                        .map(OsString::from),
+                },
+                _ => NodeContent::Special,
+            };
+            self.nodes.push(Node {
+                parent,
+                item,
+                attr: entry_attr(child_ino, ent, stat),
+                content,
+            });
+
+            let name = ent.path.rsplit('/').next().unwrap();
+            if let NodeContent::Directory(children) = &mut self.nodes[parent as usize - 1].content {
+                children.insert(OsString::from(name), child_ino);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn children(&mut self, ino: u64) -> Result<&BTreeMap<OsString, u64>, libc::c_int> {
+        match self.node(ino).map(|node| &node.content) {
+            Some(NodeContent::UnloadedItem) => {
+                if let Err(err) = self.load_item(ino) {
+                    eprintln!("bupstash mount: {}", err);
+                    return Err(libc::EIO);
+                }
+            }
+            Some(NodeContent::Directory(_)) => (),
+            Some(_) => return Err(libc::ENOTDIR),
+            None => return Err(libc::ENOENT),
+        }
+        match &self.nodes[ino as usize - 1].content {
+            NodeContent::Directory(children) => Ok(children),
+            _ => Err(libc::EIO),
+        }
+    }
+
+    fn chunk_ends(&mut self, ino: u64) -> &mut Vec<u64> {
+        match &mut self.nodes[ino as usize - 1].content {
+            NodeContent::File { chunk_ends, .. } => chunk_ends,
+            _ => unreachable!(),
+        }
+    }
+
+    fn fetch_chunks(
+        &mut self,
+        item: usize,
+        start_idx: u64,
+        end_idx: u64,
+    ) -> Result<Vec<(u64, CachedChunk)>, failure::Error> {
+        let mut fetched = Vec::new();
+        client::request_data_chunks(
+            self.ctx.request_context(),
+            self.items[item].id,
+            vec![index::HTreeDataRange { start_idx, end_idx }],
+            &mut self.serve_out,
+            &mut self.serve_in,
+            &mut |chunk_idx, data| {
+                fetched.push((chunk_idx, Rc::new(data)));
+                Ok(())
+            },
+        )?;
+        if fetched.len() as u64 != end_idx - start_idx + 1 {
+            failure::bail!("data chunks of item {} are missing", self.items[item].id);
+        }
+        for (chunk_idx, data) in fetched.iter() {
+            self.cache.insert((item, *chunk_idx), data.clone());
+        }
+        Ok(fetched)
+    }
+
+    fn read_file(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, libc::c_int> {
+        let (item, entry) = match self.node(ino) {
+            Some(Node {
+                item,
+                content: NodeContent::File { entry, .. },
+                ..
+            }) => (*item, entry.clone()),
+            Some(_) => return Err(libc::EINVAL),
+            None => return Err(libc::ENOENT),
+        };
+        let end = std::cmp::min(offset.saturating_add(size), entry.size.0);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        match self.read_file_range(ino, item, &entry, offset, end) {
+            Ok(data) => Ok(data),
+            Err(err) => {
+                eprintln!("bupstash mount: {}", err);
+                Err(libc::EIO)
+            }
+        }
+    }
+
+    // A data chunk of an item, fetched with the chunks after it
+    // up to last_idx to read ahead if it is not cached.
+    fn chunk(
+        &mut self,
+        item: usize,
+        chunk_idx: u64,
+        last_idx: u64,
+    ) -> Result<CachedChunk, failure::Error> {
+        if let Some(data) = self.cache.get((item, chunk_idx)) {
+            return Ok(data);
+        }
+        let end_idx = std::cmp::min(chunk_idx + READ_AHEAD_CHUNKS - 1, last_idx);
+        let mut fetched = self.fetch_chunks(item, chunk_idx, end_idx)?;
+        Ok(fetched.swap_remove(0).1)
+    }
+
+    fn read_file_range(
+        &mut self,
+        ino: u64,
+        item: usize,
+        entry: &index::IndexEntry,
+        offset: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, failure::Error> {
+        let first_chunk = entry.data_chunk_content_idx.0;
+        let last_chunk = entry.data_chunk_content_end_idx.0;
+        // The part of a data chunk that belongs to the file.
+        let content_range = |chunk_idx: u64, len: usize| {
+            let start = if chunk_idx == first_chunk {
+                entry.data_chunk_content_offset.0 as usize
+            } else {
+                0
+            };
+            let end = if chunk_idx == last_chunk {
+                entry.data_chunk_content_end_offset.0 as usize
+            } else {
+                len
+            };
+            start..std::cmp::max(start, std::cmp::min(end, len))
+        };
+
+        // Where the file starts in its last chunk is known from the index,
+        // so reads there don't need the chunks before it.
+        let last_chunk_start = entry
+            .size
+            .0
+            .saturating_sub(entry.data_chunk_content_end_offset.0);
+        if last_chunk != first_chunk && offset >= last_chunk_start {
+            let data = self.chunk(item, last_chunk, last_chunk)?;
+            let range = content_range(last_chunk, data.len());
+            let from = range.start + (offset - last_chunk_start) as usize;
+            let to = range.start + (end - last_chunk_start) as usize;
+            if to > range.end {
+                failure::bail!("data of {} ends before its recorded size", entry.path);
+            }
+            return Ok(data[from..to].to_vec());
+        }
+
+        // Otherwise where the file is split between chunks is only known once they are
+        // fetched, only the chunks holding the requested range are kept for the read.
+        let mut parts: HashMap<u64, CachedChunk> = HashMap::new();
+        loop {
+            let known = self.chunk_ends(ino).len() as u64;
+            if self.chunk_ends(ino).last().copied().unwrap_or(0) >= end {
+                break;
+            }
+            if first_chunk + known > last_chunk {
+                failure::bail!("data of {} ends before its recorded size", entry.path);
+            }
+            let start_idx = first_chunk + known;
+            let end_idx = std::cmp::min(start_idx + READ_AHEAD_CHUNKS - 1, last_chunk);
+            for (chunk_idx, data) in self.fetch_chunks(item, start_idx, end_idx)? {
+                let range = content_range(chunk_idx, data.len());
+                let ends = self.chunk_ends(ino);
+                let chunk_start = ends.last().copied().unwrap_or(0);
+                let chunk_end = chunk_start + range.len() as u64;
+                ends.push(chunk_end);
+                if chunk_end > offset && chunk_start < end {
+                    parts.insert(chunk_idx, data);
+                }
+            }
+        }
+
+        let mut buf = Vec::with_capacity((end - offset) as usize);
+        let ends = self.chunk_ends(ino).clone();
+        let mut chunk_start = 0;
+        for (i, chunk_end) in ends.iter().copied().enumerate() {
+            let chunk_idx = first_chunk + i as u64;
+            if chunk_end > offset && chunk_start < end {
+                let data = match parts.remove(&chunk_idx) {
+                    Some(data) => data,
+                    None => self.chunk(item, chunk_idx, last_chunk)?,
+                };
+                let range = content_range(chunk_idx, data.len());
+                let from = std::cmp::max(offset, chunk_start) - chunk_start;
+                let to = std::cmp::min(end, chunk_end) - chunk_start;
+                buf.extend_from_slice(
+                    &data[range.start + from as usize..range.start + to as usize],
+                );
+            }
+            if chunk_end >= end {
+                break;
+            }
+            chunk_start = chunk_end;
+        }
+
+        Ok(buf)
+    }
+
+    // Snapshots sent by older versions of bupstash only have
+    // link targets in the tar header of the entry.
+    fn tar_link_target(
+        &mut self,
+        item: usize,
+        entry: &index::IndexEntry,
+    ) -> Result<OsString, failure::Error> {
+        let pick = index::pick_entries(&[index::VersionedIndexEntry::V1(entry.clone())], &|_| true);
+        let mut tar_data = Vec::new();
+        client::request_data_stream(
+            self.ctx.request_context(),
+            self.items[item].id,
+            Some(pick),
+            &mut self.serve_out,
+            &mut self.serve_in,
+            &mut tar_data,
+        )?;
+        let mut archive = tar::Archive::new(&tar_data[..]);
+        for tar_entry in archive.entries()? {
+            if let Some(target) = tar_entry?.link_name()? {
+                return Ok(target.into_owned().into_os_string());
+            }
+        }
+        failure::bail!("symlink {} has no target", entry.path)
+    }
+}
+
+impl fuser::Filesystem for ItemFs {
+    fn destroy(&mut self) {
+        let _ = client::hangup(&mut self.serve_in);
+    }
+
+    fn lookup(
+        &mut self,
+        _req: &fuser::Request,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        let child = match self.children(parent) {
+            Ok(children) => children.get(name).copied(),
+            Err(errno) => return reply.error(errno),
+        };
+        match child.and_then(|ino| self.node(ino)) {
+            Some(node) => reply.entry(&TTL, &node.attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(
+        &mut self,
+        _req: &fuser::Request,
+        ino: u64,
+        _fh: Option<u64>,
+        reply: fuser::ReplyAttr,
+    ) {
+        match self.node(ino) {
+            Some(node) => reply.attr(&TTL, &node.attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request, ino: u64, reply: fuser::ReplyData) {
+        let (item, entry) = match self.node(ino) {
+            Some(Node {
+                content:
+                    NodeContent::Symlink {
+                        target: Some(target),
+                        ..
+                    },
+                ..
+            }) => return reply.data(target.as_bytes()),
+            Some(Node {
+                item,
+                content: NodeContent::Symlink { entry, .. },
+                ..
+            }) => (*item, entry.clone()),
+            Some(_) => return reply.error(libc::EINVAL),
+            None => return reply.error(libc::ENOENT),
+        };
+        match self.tar_link_target(item, &entry) {
+            Ok(target) => {
+                reply.data(target.as_bytes());
+                if let NodeContent::Symlink { target: known, .. } =
+                    &mut self.nodes[ino as usize - 1].content
+                {
+                    *known = Some(target);
+                }
+            }
+            Err(err) => {
+                eprintln!("bupstash mount: {}", err);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &fuser::Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        if offset < 0 {
+            return reply.error(libc::EINVAL);
+        }
+        match self.read_file(ino, offset as u64, size as u64) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &fuser::Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        let children: Vec<(OsString, u64)> = match self.children(ino) {
+            Ok(children) => children
+                .iter()
+                .map(|(name, ino)| (name.clone(), *ino))
+                .collect(),
+            Err(errno) => return reply.error(errno),
+        };
+        let parent = self.nodes[ino as usize - 1].parent;
+        let mut entries = vec![
+            (OsString::from("."), ino, fuser::FileType::Directory),
+            (OsString::from(".."), parent, fuser::FileType::Directory),
+        ];
+        for (name, child) in children.into_iter() {
+            entries.push((name, child, self.nodes[child as usize - 1].attr.kind));
+        }
+        for (i, (name, ino, kind)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // The offset of an entry is where the next readdir continues from.
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+// Mount items on mountpoint until it is unmounted. A single item is the root
+// of the filesystem, several items are directories named after them.
+#[allow(clippy::too_many_arguments)]
+pub fn mount(
+    mountpoint: &std::path::Path,
+    items: Vec<MountedItem>,
+    as_directories: bool,
+    ctx: MountContext,
+    query_cache: querycache::QueryCache,
+    serve_out: Box<dyn std::io::Read + Send>,
+    serve_in: Box<dyn std::io::Write + Send>,
+    cache_size: usize,
+) -> Result<(), failure::Error> {
+    let mut fs = ItemFs {
+        ctx,
+        items,
+        nodes: Vec::new(),
+        cache: ChunkCache {
+            chunks: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+            max_size: cache_size,
+        },
+        query_cache,
+        serve_out,
+        serve_in,
+    };
+
+    if as_directories {
+        let mut children = BTreeMap::new();
+        for (i, item) in fs.items.iter().enumerate() {
+            let ino = ROOT_INO + 1 + i as u64;
+            children.insert(OsString::from(&item.name), ino);
+        }
+        fs.nodes.push(Node {
+            parent: ROOT_INO,
+            item: 0,
+            attr: dir_attr(ROOT_INO, std::time::SystemTime::now()),
+            content: NodeContent::Directory(children),
+        });
+        for i in 0..fs.items.len() {
+            let ino = ROOT_INO + 1 + i as u64;
+            fs.nodes.push(Node {
+                parent: ROOT_INO,
+                item: i,
+                attr: dir_attr(ino, fs.items[i].timestamp),
+                content: NodeContent::UnloadedItem,
+            });
+        }
+    } else {
+        fs.nodes.push(Node {
+            parent: ROOT_INO,
+            item: 0,
+            attr: dir_attr(ROOT_INO, fs.items[0].timestamp),
+            content: NodeContent::UnloadedItem,
+        });
+        // Fetch the index now, so problems are reported before mounting.
+        fs.load_item(ROOT_INO)?;
+    }
+
+    let options = [
+        fuser::MountOption::RO,
+        fuser::MountOption::FSName("bupstash".to_string()),
+        fuser::MountOption::Subtype("bupstash".to_string()),
+    ];
+    if let Err(err) = fuser::mount2(fs, mountpoint, &options) {
+        failure::bail!("unable to mount {}: {}", mountpoint.display(), err);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_cache_evicts_least_recently_used() {
+        let mut cache = ChunkCache {
+            chunks: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+            max_size: 10,
+        };
+        cache.insert((0, 0), Rc::new(vec![0; 4]));
+        cache.insert((0, 1), Rc::new(vec![0; 4]));
+        cache.insert((0, 1), Rc::new(vec![0; 4]));
+        assert_eq!(cache.size, 8);
+        // Reading the oldest chunk keeps it over the one read after it.
+        assert!(cache.get((0, 0)).is_some());
+        cache.insert((1, 0), Rc::new(vec![0; 4]));
+        assert!(cache.chunks.contains_key(&(0, 0)));
+        assert!(!cache.chunks.contains_key(&(0, 1)));
+        assert_eq!(cache.size, 8);
+        // The latest chunk is kept even if it is over the limit.
+        cache.insert((1, 1), Rc::new(vec![0; 20]));
+        assert_eq!(cache.chunks.len(), 1);
+        assert!(cache.chunks.contains_key(&(1, 1)));
+    }
+}
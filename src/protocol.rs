@@ -1,4 +1,5 @@
 use super::address::*;
+use super::crypto;
 use super::index;
 use super::itemset;
 use super::repository;
@@ -8,6 +9,10 @@ use std::convert::TryInto;
 
 pub const DEFAULT_MAX_PACKET_SIZE: usize = 1024 * 1024 * 16;
 
+// Must change whenever a packet is added or its layout changes, mismatched
+// clients and servers are rejected when the repository is opened.
+pub const PROTOCOL_VERSION: &str = "3";
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum LockHint {
     Read,
@@ -24,6 +29,11 @@ pub struct TOpenRepository {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct ROpenRepository {
     pub now: chrono::DateTime<chrono::Utc>,
+    pub repository_id: Xid,
+    // See itemset::chain_log_op.
+    pub item_log_head: [u8; crypto::HASH_BYTES],
+    // The repository tag schema, for clients adding items without a send, such as clone.
+    pub tag_schema: Option<String>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -35,12 +45,16 @@ pub struct Chunk {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct TBeginSend {
     pub delta_id: Option<Xid>,
+    // Set when the client reads back chunks with TRequestChunk to verify them.
+    pub verify_chunks: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RBeginSend {
     pub gc_generation: Xid,
     pub has_delta_id: bool,
+    // The repository tag schema, checked by the client as tags are encrypted.
+    pub tag_schema: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -65,7 +79,18 @@ pub struct RRequestIndex {
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub struct TGc {}
+pub struct RAddItem {
+    pub item_id: Xid,
+    pub stats: repository::SendStats,
+    pub usage: repository::RepoUsage,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TGc {
+    pub pacing: repository::GCPacing,
+    // Defaults to repository::DEFAULT_GC_SWEEP_WORKERS.
+    pub sweep_workers: Option<u32>,
+}
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RGc {
@@ -76,11 +101,58 @@ pub struct RGc {
 pub struct TRequestItemSync {
     pub after: i64,
     pub gc_generation: Option<Xid>,
+    // Asks for a snapshot of the current items, only sent if the server
+    // reported snapshot support in an earlier RRequestItemSync.
+    pub want_snapshot: bool,
+    // The client can apply snapshots and CompressedSyncLogOps batches, the
+    // server sends neither unless these are set.
+    pub accept_snapshot: bool,
+    pub accept_compressed: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RRequestItemSync {
     pub gc_generation: Xid,
+    // The item log head after the synced ops.
+    pub item_log_head: [u8; crypto::HASH_BYTES],
+    // When set the synced ops are the current items and not the full item log,
+    // the client must discard its cache. The snapshot is preceded by SyncLogDigests
+    // for every op after the requested one, so the client can still verify the head.
+    pub snapshot: bool,
+    pub snapshots_supported: bool,
+    // The last op in the log, the client continues the next sync after it.
+    pub last_op_id: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RRepoStats {
+    pub gc_generation: Xid,
+    pub item_count: serde_bare::Uint,
+    pub item_log_head: [u8; crypto::HASH_BYTES],
+    pub lock_holders: Vec<repository::LockHolder>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TCloneItem {
+    pub source: Xid,
+    // Must have the same plain text metadata as the source item.
+    pub item: itemset::VersionedItemMetadata,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RCloneItem {
+    pub item_id: Xid,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TItemAddresses {
+    pub items: Vec<Xid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TChunkSharing {
+    pub a: Xid,
+    pub b: Xid,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -95,6 +167,18 @@ pub struct AddItem {
     pub item: itemset::VersionedItemMetadata,
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AddTaggedItem {
+    pub gc_generation: Xid,
+    pub item: itemset::VersionedItemMetadata,
+    // Keyed hashes of tags that must not already be in use.
+    pub unique_tags: Vec<Address>,
+    // Keyed hashes of tags whose current items are removed by this add.
+    pub replace_tags: Vec<Address>,
+    // The repository removes the item during the first gc after this time.
+    pub expires: Option<chrono::DateTime<chrono::Utc>>,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Progress {
     Notice(String),
@@ -130,7 +214,7 @@ pub enum Packet {
     TSendSync,
     RSendSync,
     TAddItem(AddItem),
-    RAddItem(Xid),
+    RAddItem(RAddItem),
     TRmItems(Vec<Xid>),
     RRmItems,
     TRequestData(TRequestData),
@@ -140,6 +224,7 @@ pub enum Packet {
     TRequestItemSync(TRequestItemSync),
     RRequestItemSync(RRequestItemSync),
     SyncLogOps(Vec<(i64, Option<Xid>, itemset::LogOp)>),
+    CompressedSyncLogOps(Vec<u8>),
     TRequestChunk(Address),
     RRequestChunk(Vec<u8>),
     Progress(Progress),
@@ -148,6 +233,16 @@ pub enum Packet {
     RRestoreRemoved(RRestoreRemoved),
     TRequestIndex(TRequestIndex),
     RRequestIndex(RRequestIndex),
+    TRepoStats,
+    RRepoStats(RRepoStats),
+    TAddTaggedItem(AddTaggedItem),
+    TChunkSharing(TChunkSharing),
+    RChunkSharing(repository::ChunkSharingStats),
+    TCloneItem(TCloneItem),
+    RCloneItem(RCloneItem),
+    TItemAddresses(TItemAddresses),
+    ItemAddresses(Vec<Address>),
+    SyncLogDigests(Vec<(i64, [u8; crypto::HASH_BYTES])>),
     TStorageWriteBarrier,
     RStorageWriteBarrier,
     StorageConnect(StorageConnect),
@@ -185,6 +280,17 @@ const PACKET_KIND_T_RESTORE_REMOVED: u8 = 24;
 const PACKET_KIND_R_RESTORE_REMOVED: u8 = 25;
 const PACKET_KIND_T_REQUEST_INDEX: u8 = 26;
 const PACKET_KIND_R_REQUEST_INDEX: u8 = 27;
+const PACKET_KIND_T_REPO_STATS: u8 = 28;
+const PACKET_KIND_R_REPO_STATS: u8 = 29;
+const PACKET_KIND_T_ADD_TAGGED_ITEM: u8 = 30;
+const PACKET_KIND_T_CHUNK_SHARING: u8 = 31;
+const PACKET_KIND_R_CHUNK_SHARING: u8 = 32;
+const PACKET_KIND_COMPRESSED_SYNC_LOG_OPS: u8 = 33;
+const PACKET_KIND_T_CLONE_ITEM: u8 = 34;
+const PACKET_KIND_R_CLONE_ITEM: u8 = 35;
+const PACKET_KIND_T_ITEM_ADDRESSES: u8 = 36;
+const PACKET_KIND_ITEM_ADDRESSES: u8 = 37;
+const PACKET_KIND_SYNC_LOG_DIGESTS: u8 = 38;
 
 // Backend storage protocol messages.
 const PACKET_KIND_T_STORAGE_WRITE_BARRIER: u8 = 100;
@@ -197,10 +303,11 @@ const PACKET_KIND_STORAGE_GC_COMPLETE: u8 = 105;
 const PACKET_KIND_END_OF_TRANSMISSION: u8 = 255;
 
 fn read_from_remote(r: &mut dyn std::io::Read, buf: &mut [u8]) -> Result<(), failure::Error> {
-    if r.read_exact(buf).is_err() {
-        failure::bail!("remote disconnected");
-    };
-    Ok(())
+    match r.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::TimedOut => failure::bail!("{}", err),
+        Err(_) => failure::bail!("remote disconnected"),
+    }
 }
 
 pub fn read_packet(
@@ -272,11 +379,22 @@ pub fn read_packet_raw(
         PACKET_KIND_R_REQUEST_DATA => Packet::RRequestData(serde_bare::from_slice(&buf)?),
         PACKET_KIND_T_REQUEST_INDEX => Packet::TRequestIndex(serde_bare::from_slice(&buf)?),
         PACKET_KIND_R_REQUEST_INDEX => Packet::RRequestIndex(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_T_REPO_STATS => Packet::TRepoStats,
+        PACKET_KIND_R_REPO_STATS => Packet::RRepoStats(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_T_ADD_TAGGED_ITEM => Packet::TAddTaggedItem(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_T_CHUNK_SHARING => Packet::TChunkSharing(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_R_CHUNK_SHARING => Packet::RChunkSharing(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_T_CLONE_ITEM => Packet::TCloneItem(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_R_CLONE_ITEM => Packet::RCloneItem(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_T_ITEM_ADDRESSES => Packet::TItemAddresses(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_ITEM_ADDRESSES => Packet::ItemAddresses(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_SYNC_LOG_DIGESTS => Packet::SyncLogDigests(serde_bare::from_slice(&buf)?),
         PACKET_KIND_T_GC => Packet::TGc(serde_bare::from_slice(&buf)?),
         PACKET_KIND_R_GC => Packet::RGc(serde_bare::from_slice(&buf)?),
         PACKET_KIND_T_REQUEST_ITEM_SYNC => Packet::TRequestItemSync(serde_bare::from_slice(&buf)?),
         PACKET_KIND_R_REQUEST_ITEM_SYNC => Packet::RRequestItemSync(serde_bare::from_slice(&buf)?),
         PACKET_KIND_SYNC_LOG_OPS => Packet::SyncLogOps(serde_bare::from_slice(&buf)?),
+        PACKET_KIND_COMPRESSED_SYNC_LOG_OPS => Packet::CompressedSyncLogOps(buf),
         PACKET_KIND_T_REQUEST_CHUNK => Packet::TRequestChunk(serde_bare::from_slice(&buf)?),
         PACKET_KIND_R_REQUEST_CHUNK => Packet::RRequestChunk(buf),
         PACKET_KIND_PROGRESS => Packet::Progress(serde_bare::from_slice(&buf)?),
@@ -299,6 +417,28 @@ pub fn read_packet_raw(
     Ok(packet)
 }
 
+pub fn compress_log_ops(
+    ops: &[(i64, Option<Xid>, itemset::LogOp)],
+) -> Result<Vec<u8>, failure::Error> {
+    let b = serde_bare::to_vec(&ops)?;
+    Ok(zstd::stream::encode_all(&b[..], 0)?)
+}
+
+pub fn decompress_log_ops(
+    data: &[u8],
+) -> Result<Vec<(i64, Option<Xid>, itemset::LogOp)>, failure::Error> {
+    use std::io::Read;
+    // Bound the decompressed size so a bad packet can't exhaust our memory.
+    let mut b = Vec::new();
+    zstd::stream::read::Decoder::new(data)?
+        .take(DEFAULT_MAX_PACKET_SIZE as u64 + 1)
+        .read_to_end(&mut b)?;
+    if b.len() > DEFAULT_MAX_PACKET_SIZE {
+        failure::bail!("protocol error, compressed log ops too large");
+    }
+    Ok(serde_bare::from_slice(&b)?)
+}
+
 fn send_hdr(w: &mut dyn std::io::Write, kind: u8, sz: u32) -> Result<(), failure::Error> {
     let mut hdr: [u8; 5] = [0; 5];
     hdr[4] = kind;
@@ -393,6 +533,54 @@ pub fn write_packet(w: &mut dyn std::io::Write, pkt: &Packet) -> Result<(), fail
             send_hdr(w, PACKET_KIND_R_REQUEST_INDEX, b.len().try_into()?)?;
             w.write_all(&b)?;
         }
+        Packet::TRepoStats => {
+            send_hdr(w, PACKET_KIND_T_REPO_STATS, 0)?;
+        }
+        Packet::RRepoStats(ref v) => {
+            let b = serde_bare::to_vec(&v)?;
+            send_hdr(w, PACKET_KIND_R_REPO_STATS, b.len().try_into()?)?;
+            w.write_all(&b)?;
+        }
+        Packet::TAddTaggedItem(ref v) => {
+            let b = serde_bare::to_vec(&v)?;
+            send_hdr(w, PACKET_KIND_T_ADD_TAGGED_ITEM, b.len().try_into()?)?;
+            w.write_all(&b)?;
+        }
+        Packet::TChunkSharing(ref v) => {
+            let b = serde_bare::to_vec(&v)?;
+            send_hdr(w, PACKET_KIND_T_CHUNK_SHARING, b.len().try_into()?)?;
+            w.write_all(&b)?;
+        }
+        Packet::RChunkSharing(ref v) => {
+            let b = serde_bare::to_vec(&v)?;
+            send_hdr(w, PACKET_KIND_R_CHUNK_SHARING, b.len().try_into()?)?;
+            w.write_all(&b)?;
+        }
+        Packet::TCloneItem(ref v) => {
+            let b = serde_bare::to_vec(&v)?;
+            send_hdr(w, PACKET_KIND_T_CLONE_ITEM, b.len().try_into()?)?;
+            w.write_all(&b)?;
+        }
+        Packet::RCloneItem(ref v) => {
+            let b = serde_bare::to_vec(&v)?;
+            send_hdr(w, PACKET_KIND_R_CLONE_ITEM, b.len().try_into()?)?;
+            w.write_all(&b)?;
+        }
+        Packet::TItemAddresses(ref v) => {
+            let b = serde_bare::to_vec(&v)?;
+            send_hdr(w, PACKET_KIND_T_ITEM_ADDRESSES, b.len().try_into()?)?;
+            w.write_all(&b)?;
+        }
+        Packet::ItemAddresses(ref v) => {
+            let b = serde_bare::to_vec(&v)?;
+            send_hdr(w, PACKET_KIND_ITEM_ADDRESSES, b.len().try_into()?)?;
+            w.write_all(&b)?;
+        }
+        Packet::SyncLogDigests(ref v) => {
+            let b = serde_bare::to_vec(&v)?;
+            send_hdr(w, PACKET_KIND_SYNC_LOG_DIGESTS, b.len().try_into()?)?;
+            w.write_all(&b)?;
+        }
         Packet::TGc(ref v) => {
             let b = serde_bare::to_vec(&v)?;
             send_hdr(w, PACKET_KIND_T_GC, b.len().try_into()?)?;
@@ -418,6 +606,10 @@ pub fn write_packet(w: &mut dyn std::io::Write, pkt: &Packet) -> Result<(), fail
             send_hdr(w, PACKET_KIND_SYNC_LOG_OPS, b.len().try_into()?)?;
             w.write_all(&b)?;
         }
+        Packet::CompressedSyncLogOps(ref v) => {
+            send_hdr(w, PACKET_KIND_COMPRESSED_SYNC_LOG_OPS, v.len().try_into()?)?;
+            w.write_all(v)?;
+        }
         Packet::TRequestChunk(ref v) => {
             let b = serde_bare::to_vec(&v)?;
             send_hdr(w, PACKET_KIND_T_REQUEST_CHUNK, b.len().try_into()?)?;
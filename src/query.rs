@@ -29,6 +29,14 @@ pub enum AgeAssertion {
     NewerThan,
 }
 
+#[derive(Eq, PartialEq, Debug)]
+pub enum Comparison {
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub enum Query {
     Glob {
@@ -52,6 +60,12 @@ pub enum Query {
         span: (usize, usize),
         duration: std::time::Duration,
     },
+    Compare {
+        tag: String,
+        op: Comparison,
+        span: (usize, usize),
+        value: u64,
+    },
 }
 
 fn is_tag_char(c: char) -> bool {
@@ -62,6 +76,40 @@ fn is_tag_char(c: char) -> bool {
         || c == '_'
 }
 
+// Parse a number with an optional size unit, for example '512', '10GiB' or '1.5MB'.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let unit_start = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (n, unit) = s.split_at(unit_start);
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        "tb" => 1000 * 1000 * 1000 * 1000,
+        "kib" => 1024,
+        "mib" => 1024 * 1024,
+        "gib" => 1024 * 1024 * 1024,
+        "tib" => 1024 * 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown size unit '{}'", unit)),
+    };
+    if n.is_empty() {
+        return Err("expected a number".to_string());
+    }
+    if let Ok(n) = n.parse::<u64>() {
+        return match n.checked_mul(multiplier) {
+            Some(v) => Ok(v),
+            None => Err("size is too large".to_string()),
+        };
+    }
+    match n.parse::<f64>() {
+        Ok(n) => Ok((n * multiplier as f64) as u64),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
 macro_rules! impl_binop {
     ($name:ident, $opi:ident, $ops:literal , $sub:ident) => {
         fn $name(&mut self) -> Result<Query, ParseError> {
@@ -302,11 +350,31 @@ impl Parser {
         Ok(v)
     }
 
+    fn consume_blanks(&mut self) {
+        while !self.is_eof() && (self.lookahead("•") || self.lookahead(" ")) {
+            self.advance(1);
+        }
+    }
+
     fn parse_eq(&mut self) -> Result<Query, ParseError> {
         let (_, tag_pos) = self.peek();
         let tag = self.parse_tag()?;
         let (_, tag_end_pos) = self.peek();
 
+        // Comparisons may be separated from the tag, as in 'size > 10GiB'.
+        self.consume_blanks();
+        for (op_str, op) in [
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("<", Comparison::LessThan),
+            (">", Comparison::GreaterThan),
+        ] {
+            if self.consume_if_matches(op_str) {
+                return self.parse_comparison(tag, op, tag_pos);
+            }
+        }
+        self.offset = tag_end_pos;
+
         let escape: bool;
 
         if self.consume_if_matches("==") {
@@ -350,6 +418,43 @@ impl Parser {
         })
     }
 
+    fn parse_comparison(
+        &mut self,
+        tag: String,
+        op: Comparison,
+        tag_pos: usize,
+    ) -> Result<Query, ParseError> {
+        self.consume_blanks();
+        let (_, value_pos) = self.peek();
+
+        let mut v = String::new();
+        loop {
+            match self.peek() {
+                (c, _) if c != '•' => {
+                    self.advance(1);
+                    v.push(c);
+                }
+                _ => break,
+            }
+        }
+        let (_, end_pos) = self.peek();
+        self.consume_if_matches("•");
+
+        match parse_size(&v) {
+            Ok(value) => Ok(Query::Compare {
+                tag,
+                op,
+                value,
+                span: (tag_pos, end_pos),
+            }),
+            Err(err) => Err(ParseError::SyntaxError {
+                query: self.query_chars.iter().collect(),
+                msg: format!("error parsing size: {}", err),
+                span: (value_pos, end_pos),
+            }),
+        }
+    }
+
     fn parse_unop(&mut self) -> Result<Query, ParseError> {
         let (op, op_pos) = self.peek();
 
@@ -444,6 +549,21 @@ pub fn query_matches(q: &Query, ctx: &QueryContext) -> bool {
             AgeAssertion::OlderThan => ctx.age > *duration,
             AgeAssertion::NewerThan => ctx.age < *duration,
         },
+        Query::Compare { tag, op, value, .. } => compare_tag(ctx.tagset, tag, op, *value),
+    }
+}
+
+// Tags that are not numbers never match a comparison.
+fn compare_tag(tagset: &BTreeMap<String, String>, tag: &str, op: &Comparison, value: u64) -> bool {
+    let tag_value = match tagset.get(tag).map(|v| parse_size(v)) {
+        Some(Ok(tag_value)) => tag_value,
+        _ => return false,
+    };
+    match op {
+        Comparison::LessThan => tag_value < value,
+        Comparison::LessOrEqual => tag_value <= value,
+        Comparison::GreaterThan => tag_value > value,
+        Comparison::GreaterOrEqual => tag_value >= value,
     }
 }
 
@@ -471,6 +591,7 @@ pub fn query_matches_encrypted(q: &Query, ctx: &QueryEncryptedContext) -> bool {
             Unop::Not => !query_matches_encrypted(&query, ctx),
         },
         Query::AgeAssertion { .. } => false,
+        Query::Compare { tag, op, value, .. } => compare_tag(ctx.tagset, tag, op, *value),
     }
 }
 
@@ -502,6 +623,17 @@ mod tests {
         assert_eq!(get_id_query(&parse("foo=123").unwrap()), None);
     }
 
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("2KB"), Ok(2000));
+        assert_eq!(parse_size("10GiB"), Ok(10 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("1.5mib"), Ok(1024 * 1024 + 512 * 1024));
+        assert!(parse_size("10XB").is_err());
+        assert!(parse_size("GiB").is_err());
+        assert!(parse("size•>").is_err());
+    }
+
     #[test]
     fn test_query_match() {
         let mut tagset = BTreeMap::<String, String>::new();
@@ -521,6 +653,15 @@ mod tests {
         assert!(!query_matches(&parse("older-than•6s").unwrap(), &ctx));
         assert!(!query_matches(&parse("newer-than•2s").unwrap(), &ctx));
         assert!(!query_matches(&parse("~•[•foo==123•]").unwrap(), &ctx));
+        assert!(query_matches(&parse("foo•>•100").unwrap(), &ctx));
+        assert!(query_matches(
+            &parse("foo<=123•and•foo>=123").unwrap(),
+            &ctx
+        ));
+        assert!(query_matches(&parse("foo < 1KiB").unwrap(), &ctx));
+        assert!(!query_matches(&parse("foo•<•123").unwrap(), &ctx));
+        assert!(!query_matches(&parse("bar•>•0").unwrap(), &ctx));
+        assert!(!query_matches(&parse("missing•<•1").unwrap(), &ctx));
 
         assert!(query_matches_encrypted(
             &parse("foo=123•and•bar=").unwrap(),
@@ -1,7 +1,9 @@
 use super::crypto;
+use super::htree;
 use super::itemset;
 use super::query;
 use super::xid::*;
+use std::convert::TryInto;
 use std::path::PathBuf;
 
 pub struct QueryCache {
@@ -19,8 +21,32 @@ pub struct ListOptions {
     pub primary_key_id: Option<Xid>,
     pub metadata_dctx: Option<crypto::DecryptionContext>,
     pub query: Option<query::Query>,
+    pub order: ListOrder,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum ListSort {
+    Timestamp,
+    Id,
+}
+
+// How matching items are ordered and paged, by default in the order they were added.
+#[derive(Default)]
+pub struct ListOrder {
+    pub sort: Option<ListSort>,
+    pub reverse: bool,
+    pub offset: u64,
+    pub limit: Option<u64>,
+}
+
+// Called with each item list_timestamped finds, and its timestamp if we can decrypt it.
+pub type TimestampedMatchFn<'a> = dyn FnMut(
+        Xid,
+        Option<chrono::DateTime<chrono::Utc>>,
+        std::collections::BTreeMap<String, String>,
+    ) -> Result<(), failure::Error>
+    + 'a;
+
 impl QueryCache {
     pub fn open(p: &PathBuf) -> Result<QueryCache, failure::Error> {
         let mut conn = rusqlite::Connection::open(p)?;
@@ -57,6 +83,12 @@ impl QueryCache {
 
         itemset::init_tables(&tx)?;
 
+        // Encrypted content index chunks, so an item's index can be read without the server.
+        tx.execute(
+            "create table if not exists ContentIndexes(ItemId primary key, Metadata, Chunks) without rowid;",
+            rusqlite::NO_PARAMS,
+        )?;
+
         let recently_cleared = match tx.query_row(
             "select Value from QueryCacheMeta where Key = 'recently-cleared';",
             rusqlite::NO_PARAMS,
@@ -95,10 +127,24 @@ impl QueryCache {
 }
 
 impl<'a> QueryCacheTx<'a> {
-    fn clear(&mut self) -> Result<(), failure::Error> {
+    pub fn clear(&mut self) -> Result<(), failure::Error> {
         self.tx.execute("delete from Items;", rusqlite::NO_PARAMS)?;
         self.tx
             .execute("delete from ItemOpLog;", rusqlite::NO_PARAMS)?;
+        self.tx
+            .execute("delete from ContentIndexes;", rusqlite::NO_PARAMS)?;
+        self.tx.execute(
+            "delete from QueryCacheMeta where Key = 'item-log-head';",
+            rusqlite::NO_PARAMS,
+        )?;
+        self.tx.execute(
+            "delete from QueryCacheMeta where Key = 'partial-log';",
+            rusqlite::NO_PARAMS,
+        )?;
+        self.tx.execute(
+            "delete from QueryCacheMeta where Key = 'last-synced-op';",
+            rusqlite::NO_PARAMS,
+        )?;
         self.tx.execute(
             "insert or replace into QueryCacheMeta(Key, Value) values('recently-cleared', 1);",
             rusqlite::NO_PARAMS,
@@ -120,7 +166,29 @@ impl<'a> QueryCacheTx<'a> {
             Err(err) => return Err(err.into()),
         };
 
-        Ok(last_id)
+        // A snapshot may end on an op gc removed, so we also remember the last op synced.
+        let synced_id = match self.tx.query_row(
+            "select Value from QueryCacheMeta where Key = 'last-synced-op';",
+            rusqlite::NO_PARAMS,
+            |r| {
+                let last: i64 = r.get(0)?;
+                Ok(last)
+            },
+        ) {
+            Ok(last) => last,
+            Err(rusqlite::Error::QueryReturnedNoRows) => -1,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(last_id.max(synced_id))
+    }
+
+    pub fn set_last_synced_op(&mut self, op_id: i64) -> Result<(), failure::Error> {
+        self.tx.execute(
+            "insert or replace into QueryCacheMeta(Key, Value) values('last-synced-op', ?);",
+            rusqlite::params![op_id],
+        )?;
+        Ok(())
     }
 
     pub fn current_gc_generation(&mut self) -> Result<Option<Xid>, failure::Error> {
@@ -138,7 +206,65 @@ impl<'a> QueryCacheTx<'a> {
         }
     }
 
-    pub fn start_sync(&mut self, gc_generation: Xid) -> Result<(), failure::Error> {
+    // The item log head of the cached ops, as verified by the last sync.
+    pub fn item_log_head(&mut self) -> Result<Option<[u8; crypto::HASH_BYTES]>, failure::Error> {
+        match self.tx.query_row(
+            "select Value from QueryCacheMeta where Key = 'item-log-head';",
+            rusqlite::NO_PARAMS,
+            |r| {
+                let head: Vec<u8> = r.get(0)?;
+                Ok(head)
+            },
+        ) {
+            Ok(head) => {
+                if head.len() != crypto::HASH_BYTES {
+                    failure::bail!("query cache item log head is corrupt");
+                }
+                let mut bytes = [0; crypto::HASH_BYTES];
+                bytes[..].clone_from_slice(&head[..]);
+                Ok(Some(bytes))
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn set_item_log_head(
+        &mut self,
+        head: &[u8; crypto::HASH_BYTES],
+    ) -> Result<(), failure::Error> {
+        self.tx.execute(
+            "insert or replace into QueryCacheMeta(Key, Value) values('item-log-head', ?);",
+            &[&head[..]],
+        )?;
+        Ok(())
+    }
+
+    // Returns true if the cache was cleared for a full sync.
+    // A cache synced from a snapshot lacks removed items, so it cannot apply a restore.
+    pub fn partial_log(&mut self) -> Result<bool, failure::Error> {
+        match self.tx.query_row(
+            "select 1 from QueryCacheMeta where Key = 'partial-log';",
+            rusqlite::NO_PARAMS,
+            |_r| Ok(true),
+        ) {
+            Ok(v) => Ok(v),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn set_partial_log(&mut self) -> Result<(), failure::Error> {
+        self.tx.execute(
+            "insert or replace into QueryCacheMeta(Key, Value) values('partial-log', 1);",
+            rusqlite::NO_PARAMS,
+        )?;
+        Ok(())
+    }
+
+    // Returns true if the gc generation changed. Synced items are kept, the
+    // server sends a snapshot if gc removed ops this cache has not seen.
+    pub fn start_sync(&mut self, gc_generation: Xid) -> Result<bool, failure::Error> {
         match self.tx.query_row(
             "select value from QueryCacheMeta where key = 'gc-generation';",
             rusqlite::NO_PARAMS,
@@ -149,11 +275,19 @@ impl<'a> QueryCacheTx<'a> {
         ) {
             Ok(old_generation) => {
                 if gc_generation != old_generation {
-                    self.clear()?;
+                    self.tx
+                        .execute("delete from ContentIndexes;", rusqlite::NO_PARAMS)?;
+                    // Compact like the server did, but keep our last op as the
+                    // point the next sync continues from.
+                    self.tx.execute(
+                        "delete from ItemOpLog where OpId not in (select OpId from Items) and OpId != (select max(OpId) from ItemOpLog);",
+                        rusqlite::NO_PARAMS,
+                    )?;
                     self.tx.execute(
                         "update QueryCacheMeta set Value = ? where Key = 'gc-generation';",
                         &[&gc_generation],
                     )?;
+                    return Ok(true);
                 }
             }
             Err(rusqlite::Error::QueryReturnedNoRows) => {
@@ -162,11 +296,12 @@ impl<'a> QueryCacheTx<'a> {
                     "insert into QueryCacheMeta(Key, Value) values('gc-generation', ?);",
                     &[&gc_generation],
                 )?;
+                return Ok(true);
             }
             Err(err) => return Err(err.into()),
         }
 
-        Ok(())
+        Ok(false)
     }
 
     pub fn sync_op(
@@ -175,94 +310,217 @@ impl<'a> QueryCacheTx<'a> {
         item_id: Option<Xid>,
         op: itemset::LogOp,
     ) -> Result<(), failure::Error> {
+        if let itemset::LogOp::RemoveItems(ref items) = op {
+            for item_id in items.iter() {
+                self.tx
+                    .execute("delete from ContentIndexes where ItemId = ?;", &[item_id])?;
+            }
+        }
         itemset::sync_ops(&self.tx, op_id, item_id, &op)
     }
 
+    pub fn lookup_content_index(
+        &mut self,
+        id: &Xid,
+    ) -> Result<Option<(itemset::VersionedItemMetadata, htree::RawChunks)>, failure::Error> {
+        match self.tx.query_row(
+            "select Metadata, Chunks from ContentIndexes where ItemId = ?;",
+            &[id],
+            |r| {
+                let metadata: Vec<u8> = r.get(0)?;
+                let chunks: Vec<u8> = r.get(1)?;
+                Ok((metadata, chunks))
+            },
+        ) {
+            Ok((metadata, chunks)) => Ok(Some((
+                serde_bare::from_slice(&metadata)?,
+                serde_bare::from_slice(&chunks)?,
+            ))),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn cache_content_index(
+        &mut self,
+        id: &Xid,
+        metadata: &itemset::VersionedItemMetadata,
+        chunks: &htree::RawChunks,
+    ) -> Result<(), failure::Error> {
+        self.tx.execute(
+            "insert or replace into ContentIndexes(ItemId, Metadata, Chunks) values(?, ?, ?);",
+            rusqlite::params![
+                id,
+                serde_bare::to_vec(metadata)?,
+                serde_bare::to_vec(&chunks)?
+            ],
+        )?;
+        Ok(())
+    }
+
     pub fn commit(self) -> Result<(), failure::Error> {
         self.tx.commit()?;
         Ok(())
     }
 
+    pub fn lookup_item_by_id(
+        &mut self,
+        id: &Xid,
+    ) -> Result<Option<itemset::VersionedItemMetadata>, failure::Error> {
+        itemset::lookup_item_by_id(&self.tx, id)
+    }
+
     pub fn list(
         &mut self,
-        mut opts: ListOptions,
+        opts: ListOptions,
         on_match: &mut dyn FnMut(
             Xid,
             std::collections::BTreeMap<String, String>,
         ) -> Result<(), failure::Error>,
     ) -> Result<(), failure::Error> {
+        self.list_timestamped(opts, &mut |item_id, _timestamp, tags| {
+            on_match(item_id, tags)
+        })
+    }
+
+    // Like list, but also passes the timestamp of items we can decrypt,
+    // so it does not need to be parsed back from the timestamp tag.
+    pub fn list_timestamped(
+        &mut self,
+        mut opts: ListOptions,
+        on_match: &mut TimestampedMatchFn<'_>,
+    ) -> Result<(), failure::Error> {
+        let order = std::mem::take(&mut opts.order);
+        // Without sorting we can page through matches as we find them.
+        let streaming = order.sort.is_none() && !order.reverse;
+        let mut n_skipped: u64 = 0;
+        let mut n_emitted: u64 = 0;
+        let mut sorted_matches = Vec::new();
+
+        // Returns false once the limit is reached, so the walk can stop early.
+        let mut add_match = |item_id: Xid,
+                             timestamp: Option<chrono::DateTime<chrono::Utc>>,
+                             tags: std::collections::BTreeMap<String, String>|
+         -> Result<bool, failure::Error> {
+            if !streaming {
+                sorted_matches.push((item_id, timestamp, tags));
+            } else if n_skipped < order.offset {
+                n_skipped += 1;
+            } else if order.limit.map(|limit| n_emitted < limit).unwrap_or(true) {
+                n_emitted += 1;
+                on_match(item_id, timestamp, tags)?;
+            }
+            Ok(!streaming || order.limit.map(|limit| n_emitted < limit).unwrap_or(true))
+        };
+
         let mut f = |_op_id: i64, item_id: Xid, metadata: itemset::VersionedItemMetadata| {
-            match metadata {
-                itemset::VersionedItemMetadata::V1(metadata) => {
-                    if !opts.list_encrypted
-                        && opts.primary_key_id.is_some()
-                        && opts.primary_key_id.unwrap()
-                            == metadata.plain_text_metadata.primary_key_id
-                    {
-                        let mut dmetadata =
-                            metadata.decrypt_metadata(opts.metadata_dctx.as_mut().unwrap())?;
-
-                        let ts = if opts.utc_timestamps {
-                            dmetadata.timestamp.format("%Y/%m/%d %T").to_string()
-                        } else {
-                            let local_ts: chrono::DateTime<chrono::Local> =
-                                chrono::DateTime::from(dmetadata.timestamp);
-                            local_ts.format("%Y/%m/%d %T").to_string()
-                        };
-
-                        // Add special builtin tags.
-                        dmetadata.tags.insert("id".to_string(), item_id.to_string());
-                        dmetadata.tags.insert("timestamp".to_string(), ts);
-
-                        let query_matches = match opts.query {
-                            Some(ref query) => query::query_matches(
-                                query,
-                                &query::QueryContext {
-                                    age: opts
-                                        .now
-                                        .signed_duration_since(dmetadata.timestamp)
-                                        .to_std()?,
-                                    tagset: &dmetadata.tags,
-                                },
-                            ),
-                            None => true,
-                        };
-
-                        if query_matches {
-                            on_match(item_id, dmetadata.tags)?;
-                        }
-
-                        Ok(())
-                    } else {
-                        if !opts.list_encrypted {
-                            return Ok(());
-                        }
-
-                        let mut tags = std::collections::BTreeMap::new();
-
-                        tags.insert("id".to_string(), item_id.to_string());
-                        tags.insert(
-                            "decryption-key-id".to_string(),
-                            metadata.plain_text_metadata.primary_key_id.to_string(),
-                        );
-
-                        let query_matches = match opts.query {
-                            Some(ref query) => query::query_matches_encrypted(
-                                query,
-                                &query::QueryEncryptedContext { tagset: &tags },
-                            ),
-                            None => true,
-                        };
-
-                        if query_matches {
-                            on_match(item_id, tags)?;
-                        }
-
-                        Ok(())
-                    }
+            if !opts.list_encrypted
+                && opts.primary_key_id.is_some()
+                && opts.primary_key_id.unwrap() == metadata.plain_text_metadata().primary_key_id
+            {
+                let mut dmetadata =
+                    metadata.decrypt_metadata(opts.metadata_dctx.as_mut().unwrap())?;
+
+                let ts = if opts.utc_timestamps {
+                    dmetadata.timestamp.format("%Y/%m/%d %T").to_string()
+                } else {
+                    let local_ts: chrono::DateTime<chrono::Local> =
+                        chrono::DateTime::from(dmetadata.timestamp);
+                    local_ts.format("%Y/%m/%d %T").to_string()
+                };
+
+                // Add special builtin tags.
+                dmetadata.tags.insert("id".to_string(), item_id.to_string());
+                dmetadata.tags.insert("timestamp".to_string(), ts);
+                if let Some(note) = dmetadata.note {
+                    dmetadata.tags.insert("note".to_string(), note);
+                }
+                if let Some(data_size) = dmetadata.data_size {
+                    dmetadata
+                        .tags
+                        .insert("size".to_string(), data_size.0.to_string());
+                }
+                if let Some(entry_count) = dmetadata.entry_count {
+                    dmetadata
+                        .tags
+                        .insert("entries".to_string(), entry_count.0.to_string());
+                }
+
+                let query_matches = match opts.query {
+                    Some(ref query) => query::query_matches(
+                        query,
+                        &query::QueryContext {
+                            age: opts
+                                .now
+                                .signed_duration_since(dmetadata.timestamp)
+                                .to_std()?,
+                            tagset: &dmetadata.tags,
+                        },
+                    ),
+                    None => true,
+                };
+
+                if query_matches {
+                    return add_match(item_id, Some(dmetadata.timestamp), dmetadata.tags);
+                }
+
+                Ok(true)
+            } else {
+                if !opts.list_encrypted {
+                    return Ok(true);
+                }
+
+                let mut tags = std::collections::BTreeMap::new();
+
+                tags.insert("id".to_string(), item_id.to_string());
+                tags.insert(
+                    "decryption-key-id".to_string(),
+                    metadata.plain_text_metadata().primary_key_id.to_string(),
+                );
+
+                let query_matches = match opts.query {
+                    Some(ref query) => query::query_matches_encrypted(
+                        query,
+                        &query::QueryEncryptedContext { tagset: &tags },
+                    ),
+                    None => true,
+                };
+
+                if query_matches {
+                    return add_match(item_id, None, tags);
                 }
+
+                Ok(true)
             }
         };
-        itemset::walk_items(&self.tx, &mut f)
+        if streaming && order.limit == Some(0) {
+            return Ok(());
+        }
+        itemset::walk_items_while(&self.tx, &mut f)?;
+
+        if streaming {
+            return Ok(());
+        }
+
+        // Items we can't decrypt have no timestamp and sort first.
+        match order.sort {
+            Some(ListSort::Timestamp) => sorted_matches.sort_by_key(|m| m.1),
+            Some(ListSort::Id) => sorted_matches.sort_by_key(|m| m.0.bytes),
+            None => (),
+        }
+        if order.reverse {
+            sorted_matches.reverse();
+        }
+
+        let limit = order.limit.unwrap_or(u64::MAX);
+        for (item_id, timestamp, tags) in sorted_matches
+            .into_iter()
+            .skip(order.offset.try_into()?)
+            .take(limit.try_into().unwrap_or(usize::MAX))
+        {
+            on_match(item_id, timestamp, tags)?;
+        }
+
+        Ok(())
     }
 }
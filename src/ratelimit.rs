@@ -0,0 +1,80 @@
+// Limits the rate data is written to the repository, see put --upload-rate.
+// A token bucket, writes spend tokens that are refilled at the given rate,
+// and once they are spent we sleep until there are enough again.
+pub struct RateLimitedWriter<W: std::io::Write> {
+    w: W,
+    // Bytes per second.
+    rate: f64,
+    // The most that can be written at once after being idle.
+    burst: f64,
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+
+impl<W: std::io::Write> RateLimitedWriter<W> {
+    pub fn new(w: W, rate: u64) -> RateLimitedWriter<W> {
+        assert!(rate > 0);
+        let rate = rate as f64;
+        // An eighth of a second of data keeps the rate smooth without tiny writes.
+        let burst = (rate / 8.0).max(1.0);
+        RateLimitedWriter {
+            w,
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: std::time::Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for RateLimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.refill();
+        if self.tokens < 1.0 {
+            std::thread::sleep(std::time::Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.rate,
+            ));
+            self.refill();
+        }
+        // Rounding can leave us just short of a token after sleeping, writing
+        // nothing would make write_all fail, so go a little into debt instead.
+        let n = std::cmp::min(buf.len(), std::cmp::max(self.tokens as usize, 1));
+        let n = self.w.write(&buf[..n])?;
+        self.tokens -= n as f64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn limits_write_rate() {
+        let mut out = Vec::new();
+        let start = std::time::Instant::now();
+        {
+            let mut w = RateLimitedWriter::new(&mut out, 200_000);
+            let data = vec![1; 100_000];
+            w.write_all(&data).unwrap();
+        }
+        // The first 25000 bytes are the burst, the rest take 0.375 seconds,
+        // the bounds are loose so a busy test machine does not fail this.
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!(elapsed > 0.25, "elapsed {}", elapsed);
+        assert!(elapsed < 10.0, "elapsed {}", elapsed);
+        assert_eq!(out.len(), 100_000);
+    }
+}
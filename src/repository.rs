@@ -1,3 +1,4 @@
+use super::address::*;
 use super::chunk_storage;
 use super::crypto;
 use super::dir_chunk_storage;
@@ -51,15 +52,189 @@ pub struct GCStats {
     pub bytes_remaining: Option<usize>,
 }
 
+// Slows gc down so it does not starve other users of the storage, see bupstash-gc(1).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct GCPacing {
+    // Sleep before each storage operation.
+    pub pause: Option<std::time::Duration>,
+    // Maximum storage operations per second.
+    pub max_io_rate: Option<u32>,
+}
+
+// The number of workers removing chunks when gc is not told otherwise.
+pub const DEFAULT_GC_SWEEP_WORKERS: usize = 4;
+
+#[derive(Clone)]
+pub struct GCPacer {
+    pacing: GCPacing,
+    // Shared between clones so the rate limit applies to all sweep workers together.
+    next_op: std::sync::Arc<std::sync::Mutex<Option<std::time::Instant>>>,
+}
+
+impl GCPacer {
+    pub fn new(pacing: GCPacing) -> GCPacer {
+        GCPacer {
+            pacing,
+            next_op: std::sync::Arc::new(std::sync::Mutex::new(None)),
+        }
+    }
+
+    // Called before each chunk read or removal.
+    pub fn pace(&self) {
+        if let Some(pause) = self.pacing.pause {
+            std::thread::sleep(pause);
+        }
+        if let Some(max_io_rate) = self.pacing.max_io_rate {
+            // Reserve the next free slot, then wait for it without holding the lock.
+            let op_time = {
+                let mut next_op = self.next_op.lock().unwrap();
+                let now = std::time::Instant::now();
+                let op_time = match *next_op {
+                    Some(next_op) if next_op > now => next_op,
+                    _ => now,
+                };
+                *next_op = Some(op_time + std::time::Duration::from_secs(1) / max_io_rate.max(1));
+                op_time
+            };
+            let now = std::time::Instant::now();
+            if op_time > now {
+                std::thread::sleep(op_time - now);
+            }
+        }
+    }
+}
+
+// Chunks reachable from only one of two items or from both, see Repo::chunk_sharing.
+// Byte counts are the stored, compressed and encrypted, size of the chunks.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct ChunkSharingStats {
+    pub a_unique_chunks: u64,
+    pub a_unique_bytes: u64,
+    pub b_unique_chunks: u64,
+    pub b_unique_bytes: u64,
+    pub shared_chunks: u64,
+    pub shared_bytes: u64,
+}
+
+fn item_chunk_addresses(
+    storage_engine: &mut Box<dyn chunk_storage::Engine>,
+    metadata: &itemset::VersionedItemMetadata,
+) -> Result<std::collections::HashSet<Address>, failure::Error> {
+    let plain_text_metadata = metadata.plain_text_metadata();
+    let mut trees = vec![plain_text_metadata.data_tree.clone()];
+    trees.extend(plain_text_metadata.index_tree.clone());
+
+    let mut addresses = std::collections::HashSet::new();
+    for tree in trees {
+        let mut tr = htree::TreeReader::new(tree.height, &tree.address);
+        while let Some((height, addr)) = tr.next_addr()? {
+            // Like gc, subtrees already seen are not walked again.
+            if addresses.insert(addr) && height != 0 {
+                let data = storage_engine.get_chunk(&addr)?;
+                tr.push_level(height - 1, data)?;
+            }
+        }
+    }
+    Ok(addresses)
+}
+
+// Chunks received while adding an item, and how many of them were new
+// to the repository. The storage engine may be unable to tell.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SendStats {
+    pub chunks_received: u64,
+    pub bytes_received: u64,
+    pub chunks_added: Option<u64>,
+    pub bytes_added: Option<u64>,
+}
+
+// Repository usage reported to the client after adding an item. The data size is
+// an estimate kept since the last gc, and the filesystem figures are only known
+// for repositories storing their data locally.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct RepoUsage {
+    pub item_count: u64,
+    pub data_bytes: Option<u64>,
+    pub fs_bytes_available: Option<u64>,
+    pub fs_bytes_total: Option<u64>,
+}
+
+// Describes a process holding the repository lock, a record is written
+// to the lock-holders directory for as long as the lock is held.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LockHolder {
+    pub operation: String,
+    pub exclusive: bool,
+    pub pid: u32,
+    pub acquired: chrono::DateTime<chrono::Utc>,
+}
+
+impl LockHolder {
+    pub fn describe(&self, now: chrono::DateTime<chrono::Utc>) -> String {
+        let held_for = now
+            .signed_duration_since(self.acquired)
+            .to_std()
+            .unwrap_or_else(|_| std::time::Duration::from_secs(0));
+        format!(
+            "{} ({}, pid {}) for {}",
+            self.operation,
+            if self.exclusive {
+                "exclusive"
+            } else {
+                "shared"
+            },
+            self.pid,
+            humantime::format_duration(std::time::Duration::from_secs(held_for.as_secs()))
+        )
+    }
+}
+
+struct LockHolderRecord {
+    path: PathBuf,
+    holder: LockHolder,
+}
+
+impl Drop for LockHolderRecord {
+    fn drop(&mut self) {
+        // A stale record is only cosmetic, it is cleaned up by the next exclusive lock.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Held by connections reading without the repository lock, a gc that
+// starts a new generation keeps the data of the items they can see
+// while any read snapshot of an older generation is held.
+struct ReadSnapshot {
+    path: PathBuf,
+    _lock: fsutil::FileLock,
+}
+
+impl Drop for ReadSnapshot {
+    fn drop(&mut self) {
+        // Removed before the lock is released, a stale file is removed by the next gc.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
 pub struct Repo {
     repo_path: PathBuf,
     conn: rusqlite::Connection,
     _repo_lock_mode: LockMode,
+    // Must be declared before _repo_lock so the record is removed before the lock is released.
+    _repo_lock_holder: Option<LockHolderRecord>,
     _repo_lock: Option<fsutil::FileLock>,
+    _read_snapshot: Option<ReadSnapshot>,
 }
 
+// Log ops are sent in batches of roughly this many serialized bytes.
+const SYNC_BATCH_BYTES: usize = 1024 * 1024;
+
 pub enum ItemSyncEvent {
-    Start(Xid),
+    // The gc generation, log head and last op id. The final flag is true when the
+    // sync is a snapshot of the current items rather than the item log.
+    Start(Xid, [u8; crypto::HASH_BYTES], i64, bool),
+    // Digests of every op after the requested one, sent before a snapshot.
+    LogDigests(Vec<(i64, [u8; crypto::HASH_BYTES])>),
     LogOps(Vec<(i64, Option<Xid>, itemset::LogOp)>),
     End,
 }
@@ -71,6 +246,24 @@ impl Repo {
         lock_path
     }
 
+    fn lock_holders_dir_path(repo_path: &Path) -> PathBuf {
+        let mut lock_holders_path = repo_path.to_path_buf();
+        lock_holders_path.push("lock-holders");
+        lock_holders_path
+    }
+
+    fn read_snapshots_dir_path(repo_path: &Path) -> PathBuf {
+        let mut read_snapshots_path = repo_path.to_path_buf();
+        read_snapshots_path.push("read-snapshots");
+        read_snapshots_path
+    }
+
+    fn connections_dir_path(repo_path: &Path) -> PathBuf {
+        let mut connections_path = repo_path.to_path_buf();
+        connections_path.push("connections");
+        connections_path
+    }
+
     fn tmp_dir_path(repo_path: &Path) -> PathBuf {
         let mut lock_path = repo_path.to_path_buf();
         lock_path.push("tmp");
@@ -166,6 +359,14 @@ impl Repo {
         fs::DirBuilder::new().create(path_buf.as_path())?;
         path_buf.pop();
 
+        path_buf.push("lock-holders");
+        fs::DirBuilder::new().create(path_buf.as_path())?;
+        path_buf.pop();
+
+        path_buf.push("read-snapshots");
+        fs::DirBuilder::new().create(path_buf.as_path())?;
+        path_buf.pop();
+
         path_buf.push("storage-engine.json");
         let storage_engine_buf = serde_json::to_vec_pretty(&storage_engine)?;
         fsutil::atomic_add_file(path_buf.as_path(), &storage_engine_buf)?;
@@ -205,6 +406,13 @@ impl Repo {
             "insert into RepositoryMeta(Key, Value) values('gc-dirty', ?);",
             rusqlite::params![false],
         )?;
+        // Only the dir store can tell us how much data it adds, see Repo::usage.
+        if let StorageEngineSpec::DirStore = storage_engine {
+            tx.execute(
+                "insert into RepositoryMeta(Key, Value) values('data-size', 0);",
+                rusqlite::NO_PARAMS,
+            )?;
+        }
 
         itemset::init_tables(&tx)?;
 
@@ -232,18 +440,19 @@ impl Repo {
             return Err(RepoError::UnsupportedSchemaVersion.into());
         }
 
-        let mut r = Repo {
+        Ok(Repo {
             conn,
             repo_path: fs::canonicalize(&repo_path)?,
             _repo_lock_mode: LockMode::None,
+            _repo_lock_holder: None,
             _repo_lock: None,
-        };
-
-        r.handle_gc_dirty()?;
-
-        Ok(r)
+            _read_snapshot: None,
+        })
     }
 
+    // Only called with the repository lock held. Reads do not need the
+    // lock and are unaffected by the deletions of an interrupted gc, so
+    // they are not delayed, and never wait for a running gc to finish.
     fn handle_gc_dirty(&mut self) -> Result<(), failure::Error> {
         // The gc_dirty flag gets set when a garbage collection exits without
         // proper cleanup. For external storage engines we handle this by applying a delay to any repository
@@ -276,9 +485,6 @@ impl Repo {
         )?;
 
         if gc_dirty {
-            // Don't reset gc-dirty unless we have the write lock.
-            self.alter_lock_mode(LockMode::Write)?;
-
             let storage_spec = self.storage_engine_spec()?;
             let tx = self
                 .conn
@@ -306,24 +512,213 @@ impl Repo {
         Ok(())
     }
 
-    pub fn alter_lock_mode(&mut self, lock_mode: LockMode) -> Result<(), failure::Error> {
+    pub fn alter_lock_mode(
+        &mut self,
+        lock_mode: LockMode,
+        operation: &str,
+        on_lock_wait: &mut dyn FnMut(String) -> Result<(), failure::Error>,
+    ) -> Result<(), failure::Error> {
+        if lock_mode == LockMode::None {
+            if self._read_snapshot.is_none() {
+                self._read_snapshot = Some(self.begin_read_snapshot()?);
+            }
+        } else {
+            // Our own read snapshot would keep gc from compacting the item log.
+            self._read_snapshot = None;
+        }
         // On error we should perhaps put a poison value.
         if self._repo_lock_mode != lock_mode {
             self._repo_lock_mode = lock_mode.clone();
+            self._repo_lock_holder = None;
             self._repo_lock = None;
-            self._repo_lock = match lock_mode {
-                LockMode::None => None,
-                LockMode::Write => Some(fsutil::FileLock::get_shared(&Repo::repo_lock_path(
-                    &self.repo_path,
-                ))?),
-                LockMode::Exclusive => Some(fsutil::FileLock::get_exclusive(
-                    &Repo::repo_lock_path(&self.repo_path),
-                )?),
+            let exclusive = match lock_mode {
+                LockMode::None => return Ok(()),
+                LockMode::Write => false,
+                LockMode::Exclusive => true,
+            };
+            let lock_path = Repo::repo_lock_path(&self.repo_path);
+            let try_lock = if exclusive {
+                fsutil::FileLock::try_get_exclusive(&lock_path)?
+            } else {
+                fsutil::FileLock::try_get_shared(&lock_path)?
+            };
+            let lock = match try_lock {
+                Some(lock) => lock,
+                None => {
+                    // Tell the user who they are waiting on, so they don't
+                    // kill a long running operation assuming it is stuck.
+                    let now = chrono::Utc::now();
+                    let holders = self.lock_holders()?;
+                    let msg = if holders.is_empty() {
+                        "waiting for repository lock held by an unknown process...".to_string()
+                    } else {
+                        format!(
+                            "waiting for repository lock held by {}...",
+                            holders
+                                .iter()
+                                .map(|h| h.describe(now))
+                                .collect::<Vec<String>>()
+                                .join(", ")
+                        )
+                    };
+                    on_lock_wait(msg)?;
+                    if exclusive {
+                        fsutil::FileLock::get_exclusive(&lock_path)?
+                    } else {
+                        fsutil::FileLock::get_shared(&lock_path)?
+                    }
+                }
             };
+            self._repo_lock = Some(lock);
+            self._repo_lock_holder = Some(self.add_lock_holder_record(operation, exclusive)?);
+            self.handle_gc_dirty()?;
+        } else if let Some(ref mut record) = self._repo_lock_holder {
+            // The lock is often taken when the repository is opened, before
+            // we know the real operation, so keep the record up to date.
+            if record.holder.operation != operation {
+                record.holder.operation = operation.to_string();
+                fsutil::atomic_add_file(&record.path, &serde_json::to_vec_pretty(&record.holder)?)?;
+            }
         }
         Ok(())
     }
 
+    fn begin_read_snapshot(&mut self) -> Result<ReadSnapshot, failure::Error> {
+        loop {
+            let gc_generation = self.gc_generation()?;
+            let mut path = Repo::read_snapshots_dir_path(&self.repo_path);
+            path.push(format!("{}", gc_generation));
+            path.push(format!("{}", Xid::new()));
+            // A gc may remove the file or directory before we hold the lock, in which
+            // case it has also started a new generation and we try again.
+            let lock = fs::create_dir_all(path.parent().unwrap())
+                .and_then(|_| fsutil::create_empty_file(&path))
+                .and_then(|_| fsutil::FileLock::get_shared(&path));
+            let lock = match lock {
+                Ok(lock) => lock,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let snapshot = ReadSnapshot { path, _lock: lock };
+            // A gc only looks for snapshots after starting a new generation, so once
+            // the snapshot is held the generation must still be the same for gc to see it.
+            if self.gc_generation()? == gc_generation {
+                return Ok(snapshot);
+            }
+        }
+    }
+
+    // Counts the read snapshots held for generations other than the current
+    // one, removing any left by crashed processes.
+    fn count_old_read_snapshots(&self, gc_generation: &Xid) -> Result<usize, failure::Error> {
+        let read_snapshots_dir = Repo::read_snapshots_dir_path(&self.repo_path);
+        let generations = match fs::read_dir(&read_snapshots_dir) {
+            Ok(generations) => generations,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let current = format!("{}", gc_generation);
+        let mut count = 0;
+        for generation_dir in generations {
+            let generation_dir = generation_dir?.path();
+            if generation_dir.file_name() == Some(std::ffi::OsStr::new(&current)) {
+                continue;
+            }
+            for e in fs::read_dir(&generation_dir)? {
+                let p = e?.path();
+                match fsutil::FileLock::try_get_exclusive(&p) {
+                    Ok(Some(_)) => fs::remove_file(&p)?,
+                    Ok(None) => count += 1,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            // Fails if the directory is still in use.
+            let _ = fs::remove_dir(&generation_dir);
+        }
+        Ok(count)
+    }
+
+    fn add_lock_holder_record(
+        &mut self,
+        operation: &str,
+        exclusive: bool,
+    ) -> Result<LockHolderRecord, failure::Error> {
+        let lock_holders_dir = Repo::lock_holders_dir_path(&self.repo_path);
+        // Repositories created by older versions lack the directory.
+        fs::create_dir_all(&lock_holders_dir)?;
+        if exclusive {
+            // Nobody else can hold the lock, so any records left over are from crashed processes.
+            for e in fs::read_dir(&lock_holders_dir)? {
+                fs::remove_file(e?.path())?;
+            }
+        }
+        let holder = LockHolder {
+            operation: operation.to_string(),
+            exclusive,
+            pid: std::process::id(),
+            acquired: chrono::Utc::now(),
+        };
+        let mut path = lock_holders_dir;
+        path.push(format!("{}.json", Xid::new()));
+        fsutil::atomic_add_file(&path, &serde_json::to_vec_pretty(&holder)?)?;
+        Ok(LockHolderRecord { path, holder })
+    }
+
+    // Holders are recorded after the lock is acquired and stale records
+    // from crashed processes may linger until the next exclusive lock,
+    // so this is only suitable for diagnostics.
+    pub fn lock_holders(&self) -> Result<Vec<LockHolder>, failure::Error> {
+        let mut holders = Vec::new();
+        let lock_holders_dir = Repo::lock_holders_dir_path(&self.repo_path);
+        let entries = match fs::read_dir(&lock_holders_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(holders),
+            Err(err) => return Err(err.into()),
+        };
+        for e in entries {
+            let p = e?.path();
+            if p.extension() != Some(std::ffi::OsStr::new("json")) {
+                continue;
+            }
+            // Records can be removed while we iterate.
+            let buf = match fs::read(&p) {
+                Ok(buf) => buf,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            holders.push(serde_json::from_slice(&buf)?);
+        }
+        holders.sort_by_key(|h: &LockHolder| h.acquired);
+        Ok(holders)
+    }
+
+    // Each connection holds a lock on one of max_connections slot files
+    // for as long as it is connected.
+    pub fn acquire_connection_slot(
+        &self,
+        max_connections: usize,
+    ) -> Result<fsutil::FileLock, failure::Error> {
+        let connections_dir = Repo::connections_dir_path(&self.repo_path);
+        fs::create_dir_all(&connections_dir)?;
+        for i in 0..max_connections {
+            let mut slot_path = connections_dir.clone();
+            slot_path.push(format!("{}", i));
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&slot_path)?;
+            if let Some(slot) = fsutil::FileLock::try_get_exclusive(&slot_path)? {
+                return Ok(slot);
+            }
+        }
+        failure::bail!(
+            "repository already has the maximum of {} connections, try again later",
+            max_connections
+        );
+    }
+
     pub fn storage_engine_spec(&self) -> Result<StorageEngineSpec, failure::Error> {
         let mut p = self.repo_path.clone();
         p.push("storage-engine.json");
@@ -334,6 +729,17 @@ impl Repo {
         Ok(spec)
     }
 
+    // An optional hand written file, see tagschema.rs.
+    pub fn tag_schema(&self) -> Result<Option<String>, failure::Error> {
+        let mut p = self.repo_path.clone();
+        p.push("tag-schema");
+        match std::fs::read_to_string(p) {
+            Ok(schema) => Ok(Some(schema)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub fn storage_engine_from_spec(
         &self,
         spec: &StorageEngineSpec,
@@ -362,6 +768,14 @@ impl Repo {
         self.storage_engine_from_spec(&spec)
     }
 
+    pub fn id(&self) -> Result<Xid, failure::Error> {
+        Ok(self.conn.query_row(
+            "select Value from RepositoryMeta where Key='id';",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?)
+    }
+
     pub fn gc_generation(&self) -> Result<Xid, failure::Error> {
         Ok(self.conn.query_row(
             "select Value from RepositoryMeta where Key='gc-generation';",
@@ -370,10 +784,77 @@ impl Repo {
         )?)
     }
 
+    // The head of the item log and the op it was last updated for. Repositories
+    // from before the log was chained have no stored head, and start from an empty log.
+    fn stored_item_log_head(
+        tx: &rusqlite::Transaction,
+    ) -> Result<([u8; crypto::HASH_BYTES], i64), failure::Error> {
+        let head: Option<Vec<u8>> = match tx.query_row(
+            "select Value from RepositoryMeta where Key='item-log-head';",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        ) {
+            Ok(head) => Some(head),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(err) => return Err(err.into()),
+        };
+        match head {
+            Some(head) => {
+                if head.len() != crypto::HASH_BYTES {
+                    failure::bail!("repository item log head is corrupt");
+                }
+                let last_op_id: i64 = tx.query_row(
+                    "select Value from RepositoryMeta where Key='item-log-head-op';",
+                    rusqlite::NO_PARAMS,
+                    |row| row.get(0),
+                )?;
+                let mut bytes = [0; crypto::HASH_BYTES];
+                bytes[..].clone_from_slice(&head[..]);
+                Ok((bytes, last_op_id))
+            }
+            None => Ok(([0; crypto::HASH_BYTES], -1)),
+        }
+    }
+
+    fn current_item_log_head(
+        tx: &rusqlite::Transaction,
+    ) -> Result<([u8; crypto::HASH_BYTES], i64), failure::Error> {
+        let (mut head, mut last_op_id) = Repo::stored_item_log_head(tx)?;
+        itemset::walk_log(tx, last_op_id, &mut |op_id, item_id, op| {
+            head = itemset::chain_log_op(&head, op_id, &item_id, &op)?;
+            last_op_id = op_id;
+            Ok(())
+        })?;
+        Ok((head, last_op_id))
+    }
+
+    // Must be called in any transaction that appends to the item log.
+    fn update_item_log_head(tx: &rusqlite::Transaction) -> Result<(), failure::Error> {
+        let (head, last_op_id) = Repo::current_item_log_head(tx)?;
+        tx.execute(
+            "insert or replace into RepositoryMeta(Key, Value) values('item-log-head', ?);",
+            rusqlite::params![&head[..]],
+        )?;
+        tx.execute(
+            "insert or replace into RepositoryMeta(Key, Value) values('item-log-head-op', ?);",
+            rusqlite::params![last_op_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn item_log_head(&mut self) -> Result<[u8; crypto::HASH_BYTES], failure::Error> {
+        let tx = self.conn.transaction()?;
+        let (head, _) = Repo::current_item_log_head(&tx)?;
+        Ok(head)
+    }
+
     pub fn add_item(
         &mut self,
         gc_generation: Xid,
         item: itemset::VersionedItemMetadata,
+        unique_tags: &[Address],
+        replace_tags: &[Address],
+        expires: Option<chrono::DateTime<chrono::Utc>>,
     ) -> Result<Xid, failure::Error> {
         match self._repo_lock_mode {
             LockMode::None => panic!("BUG: write lock not held when adding item"),
@@ -394,18 +875,56 @@ impl Repo {
             failure::bail!("gc generation changed during send, aborting");
         }
 
+        let id = if unique_tags.is_empty() && replace_tags.is_empty() && expires.is_none() {
+            itemset::add_item(&tx, item)?
+        } else {
+            itemset::add_tagged_item(&tx, item, unique_tags, replace_tags, expires)?
+        };
+        Repo::update_item_log_head(&tx)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    // Add an item sharing the data and index trees of a current item, no data is
+    // copied and the client provides the new encrypted metadata.
+    pub fn clone_item(
+        &mut self,
+        source: &Xid,
+        item: itemset::VersionedItemMetadata,
+    ) -> Result<Xid, failure::Error> {
+        match self._repo_lock_mode {
+            LockMode::None => panic!("BUG: write lock not held when cloning item"),
+            LockMode::Write | LockMode::Exclusive => (),
+        }
+
+        let tx = self
+            .conn
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+
+        let source_item = match itemset::lookup_item_by_id(&tx, source)? {
+            Some(source_item) => source_item,
+            None => failure::bail!("no stored items with the requested id"),
+        };
+
+        // Otherwise a client could add an item referencing chunks it never sent.
+        if source_item.plain_text_metadata() != item.plain_text_metadata() {
+            failure::bail!("a cloned item must reference the same data as its source");
+        }
+
         let id = itemset::add_item(&tx, item)?;
+        Repo::update_item_log_head(&tx)?;
         tx.commit()?;
         Ok(id)
     }
 
     pub fn remove_items(&mut self, items: Vec<Xid>) -> Result<(), failure::Error> {
-        self.alter_lock_mode(LockMode::Write)?;
+        self.alter_lock_mode(LockMode::Write, "rm", &mut |_| Ok(()))?;
 
         let tx = self
             .conn
             .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
         itemset::remove_items(&tx, items)?;
+        Repo::update_item_log_head(&tx)?;
         tx.commit()?;
         Ok(())
     }
@@ -418,6 +937,112 @@ impl Repo {
         itemset::lookup_item_by_id(&tx, id)
     }
 
+    // The chunk addresses of items in batches, each address is only given once.
+    pub fn item_addresses(
+        &mut self,
+        items: &[Xid],
+        on_addresses: &mut dyn FnMut(Vec<Address>) -> Result<(), failure::Error>,
+    ) -> Result<(), failure::Error> {
+        const BATCH_SIZE: usize = 64 * 1024;
+        let mut storage_engine = self.storage_engine()?;
+        let mut seen = std::collections::HashSet::new();
+        let mut batch = Vec::new();
+        for id in items.iter() {
+            let metadata = match self.lookup_item_by_id(id)? {
+                Some(metadata) => metadata,
+                None => failure::bail!("no stored items with the requested id"),
+            };
+            for addr in item_chunk_addresses(&mut storage_engine, &metadata)?.into_iter() {
+                if seen.insert(addr) {
+                    batch.push(addr);
+                    if batch.len() >= BATCH_SIZE {
+                        on_addresses(std::mem::take(&mut batch))?;
+                    }
+                }
+            }
+        }
+        if !batch.is_empty() {
+            on_addresses(batch)?;
+        }
+        Ok(())
+    }
+
+    // Walks the data and index trees of two items to find the chunks they share,
+    // removing an item only frees the chunks unique to it (and not used by other items).
+    pub fn chunk_sharing(&mut self, a: &Xid, b: &Xid) -> Result<ChunkSharingStats, failure::Error> {
+        let mut storage_engine = self.storage_engine()?;
+        let mut item_chunks = Vec::with_capacity(2);
+        for id in [a, b].iter() {
+            let metadata = match self.lookup_item_by_id(id)? {
+                Some(metadata) => metadata,
+                None => failure::bail!("no stored items with the id {}", id),
+            };
+            item_chunks.push(item_chunk_addresses(&mut storage_engine, &metadata)?);
+        }
+        let (a_chunks, b_chunks) = (&item_chunks[0], &item_chunks[1]);
+
+        let mut stats = ChunkSharingStats::default();
+        for addr in a_chunks.iter() {
+            let size = storage_engine.chunk_size(addr)?;
+            if b_chunks.contains(addr) {
+                stats.shared_chunks += 1;
+                stats.shared_bytes += size;
+            } else {
+                stats.a_unique_chunks += 1;
+                stats.a_unique_bytes += size;
+            }
+        }
+        for addr in b_chunks.difference(a_chunks) {
+            stats.b_unique_chunks += 1;
+            stats.b_unique_bytes += storage_engine.chunk_size(addr)?;
+        }
+        Ok(stats)
+    }
+
+    // Keep the estimated data size up to date, repositories without one are left alone.
+    pub fn add_data_size(&mut self, bytes: usize) -> Result<(), failure::Error> {
+        self.conn.execute(
+            "update RepositoryMeta set Value = Value + ? where Key = 'data-size';",
+            rusqlite::params![bytes as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn usage(&mut self) -> Result<RepoUsage, failure::Error> {
+        let item_count = self.item_count()?;
+        let data_bytes = match self.conn.query_row(
+            "select Value from RepositoryMeta where Key = 'data-size';",
+            rusqlite::NO_PARAMS,
+            |row| row.get::<_, i64>(0),
+        ) {
+            Ok(data_bytes) => Some(data_bytes as u64),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(err) => return Err(err.into()),
+        };
+        let (fs_bytes_available, fs_bytes_total) = match self.storage_engine_spec()? {
+            StorageEngineSpec::DirStore => {
+                let st = nix::sys::statvfs::statvfs(&self.repo_path)?;
+                let frsize = st.fragment_size() as u64;
+                (
+                    Some(st.blocks_available() as u64 * frsize),
+                    Some(st.blocks() as u64 * frsize),
+                )
+            }
+            StorageEngineSpec::ExternalStore { .. } => (None, None),
+        };
+        Ok(RepoUsage {
+            item_count,
+            data_bytes,
+            fs_bytes_available,
+            fs_bytes_total,
+        })
+    }
+
+    pub fn item_count(&mut self) -> Result<u64, failure::Error> {
+        let tx = self.conn.transaction()?;
+        itemset::count_items(&tx)
+    }
+
     pub fn has_item_with_id(&mut self, id: &Xid) -> Result<bool, failure::Error> {
         let tx = self.conn.transaction()?;
         itemset::has_item_with_id(&tx, id)
@@ -427,6 +1052,8 @@ impl Repo {
         &mut self,
         after: i64,
         start_gc_generation: Option<Xid>,
+        want_snapshot: bool,
+        accept_snapshot: bool,
         on_sync_event: &mut dyn FnMut(ItemSyncEvent) -> Result<(), failure::Error>,
     ) -> Result<(), failure::Error> {
         let tx = self.conn.transaction()?;
@@ -437,24 +1064,83 @@ impl Repo {
             |row| row.get(0),
         )?;
 
-        let after = match start_gc_generation {
-            Some(start_gc_generation) if start_gc_generation == current_gc_generation => after,
-            _ => -1,
+        // Ops up to this id may have been removed from the log by gc.
+        let compacted_op_id: i64 = match tx.query_row(
+            "select Value from RepositoryMeta where Key='item-log-compacted-op';",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        ) {
+            Ok(op_id) => op_id,
+            Err(rusqlite::Error::QueryReturnedNoRows) => -1,
+            Err(err) => return Err(err.into()),
         };
 
-        on_sync_event(ItemSyncEvent::Start(current_gc_generation))?;
+        // A client that already has every op gc could have removed can continue
+        // from the log, even from an earlier gc generation. Otherwise it must start
+        // from scratch, instead of replaying the whole item log we send it only the
+        // items that currently exist.
+        let snapshot = want_snapshot || start_gc_generation.is_none() || after < compacted_op_id;
+        if snapshot && !accept_snapshot {
+            failure::bail!(
+                "the client must sync from a snapshot of the current items, but does not support them"
+            );
+        }
+
+        let (item_log_head, last_op_id) = Repo::current_item_log_head(&tx)?;
+
+        on_sync_event(ItemSyncEvent::Start(
+            current_gc_generation,
+            item_log_head,
+            last_op_id,
+            snapshot,
+        ))?;
+
+        // A client that synced before chains these digests on to the head it verified.
+        if snapshot && start_gc_generation.is_some() {
+            let mut digests = Vec::new();
+            itemset::walk_log_digests(&tx, after, &mut |op_id, digest| {
+                digests.push((op_id, digest));
+                if digests.len() * (8 + crypto::HASH_BYTES) >= SYNC_BATCH_BYTES {
+                    on_sync_event(ItemSyncEvent::LogDigests(std::mem::take(&mut digests)))?;
+                }
+                Ok(())
+            })?;
+            if !digests.is_empty() {
+                on_sync_event(ItemSyncEvent::LogDigests(digests))?;
+            }
+        }
 
         let mut logops = Vec::new();
+        let mut logops_size = 0;
 
-        itemset::walk_log(&tx, after, &mut |op_id, item_id, op| {
+        let mut add_logop = |op_id, item_id, op| -> Result<(), failure::Error> {
+            logops_size += serde_bare::to_vec(&op)?.len();
             logops.push((op_id, item_id, op));
-            if logops.len() >= 64 {
-                let mut v = Vec::new();
-                std::mem::swap(&mut v, &mut logops);
-                on_sync_event(ItemSyncEvent::LogOps(v))?;
+            if logops_size >= SYNC_BATCH_BYTES {
+                logops_size = 0;
+                on_sync_event(ItemSyncEvent::LogOps(std::mem::take(&mut logops)))?;
             }
             Ok(())
-        })?;
+        };
+
+        if snapshot {
+            let mut last_sent_op_id = -1;
+            itemset::walk_items(&tx, &mut |op_id, item_id, metadata| {
+                last_sent_op_id = op_id;
+                add_logop(op_id, Some(item_id), itemset::LogOp::AddItem(metadata))
+            })?;
+            // The client resumes future syncs from the last op it has, so it must
+            // also receive the final log op.
+            if last_op_id > last_sent_op_id {
+                itemset::walk_log(&tx, last_op_id - 1, &mut |op_id, item_id, op| {
+                    add_logop(op_id, item_id, op)
+                })?;
+            }
+        } else {
+            itemset::walk_log(&tx, after, &mut |op_id, item_id, op| {
+                add_logop(op_id, item_id, op)
+            })?;
+        }
 
         if !logops.is_empty() {
             on_sync_event(ItemSyncEvent::LogOps(logops))?;
@@ -468,13 +1154,14 @@ impl Repo {
     }
 
     pub fn restore_removed(&mut self) -> Result<u64, failure::Error> {
-        self.alter_lock_mode(LockMode::Write)?;
+        self.alter_lock_mode(LockMode::Write, "restore-removed", &mut |_| Ok(()))?;
 
         let tx = self
             .conn
             .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
         let n_restored = itemset::restore_removed(&tx)?;
         if n_restored > 0 {
+            Repo::update_item_log_head(&tx)?;
             tx.commit()?;
         }
         Ok(n_restored)
@@ -482,9 +1169,11 @@ impl Repo {
 
     pub fn gc(
         &mut self,
+        pacing: GCPacing,
+        sweep_workers: usize,
         update_progress_msg: &mut dyn FnMut(String) -> Result<(), failure::Error>,
     ) -> Result<GCStats, failure::Error> {
-        self.alter_lock_mode(LockMode::Exclusive)?;
+        self.alter_lock_mode(LockMode::Exclusive, "gc", update_progress_msg)?;
         // We remove stale temporary files first so we don't accumulate them during failed gc attempts.
         // For example, this could make out of space problems even worse.
         update_progress_msg("removing temporary files...".to_string())?;
@@ -499,7 +1188,7 @@ impl Repo {
             }
         }
         // Once we have removed temporary files, we can go back to a shared lock.
-        self.alter_lock_mode(LockMode::Write)?;
+        self.alter_lock_mode(LockMode::Write, "gc", update_progress_msg)?;
 
         let reachability_db_path = Repo::random_tmp_reachability_db_path(&self.repo_path);
         let mut reachability_db = rusqlite::Connection::open(&reachability_db_path)?;
@@ -521,39 +1210,51 @@ impl Repo {
         )?;
 
         let mut storage_engine = self.storage_engine()?;
+        let pacer = GCPacer::new(pacing);
 
-        let mut walk_item = |_op_id, _item_id, metadata| match metadata {
-            itemset::VersionedItemMetadata::V1(metadata) => {
-                let mut add_reachability_stmt = reachability_tx.prepare_cached(
-                    "insert into Reachability(Address) values(?) on conflict do nothing;",
-                )?;
+        let mut walk_item = |_op_id, _item_id, metadata: itemset::VersionedItemMetadata| {
+            let mut add_reachability_stmt = reachability_tx.prepare_cached(
+                "insert into Reachability(Address) values(?) on conflict do nothing;",
+            )?;
 
-                // It seems likely we could do some sort of pipelining or parallel fetch when we walk the tree.
-                // For garbage collection walking in order is not a concern, we just need to ensure we touch each reachable node.
+            // It seems likely we could do some sort of pipelining or parallel fetch when we walk the tree.
+            // For garbage collection walking in order is not a concern, we just need to ensure we touch each reachable node.
 
-                let data_tree = metadata.plain_text_metadata.data_tree;
+            let plain_text_metadata = metadata.plain_text_metadata();
+            let data_tree = plain_text_metadata.data_tree.clone();
 
-                let trees = if let Some(index_tree) = metadata.plain_text_metadata.index_tree {
-                    vec![data_tree, index_tree]
-                } else {
-                    vec![data_tree]
-                };
+            let trees = if let Some(index_tree) = plain_text_metadata.index_tree.clone() {
+                vec![data_tree, index_tree]
+            } else {
+                vec![data_tree]
+            };
 
-                for tree in trees {
-                    let mut tr = htree::TreeReader::new(tree.height, &tree.address);
-                    while let Some((height, addr)) = tr.next_addr()? {
-                        let rows_changed =
-                            add_reachability_stmt.execute(rusqlite::params![&addr.bytes[..]])?;
-                        if rows_changed != 0 && height != 0 {
-                            let data = storage_engine.get_chunk(&addr)?;
-                            tr.push_level(height - 1, data)?;
-                        }
+            for tree in trees {
+                let mut tr = htree::TreeReader::new(tree.height, &tree.address);
+                while let Some((height, addr)) = tr.next_addr()? {
+                    let rows_changed =
+                        add_reachability_stmt.execute(rusqlite::params![&addr.bytes[..]])?;
+                    if rows_changed != 0 && height != 0 {
+                        pacer.pace();
+                        let data = storage_engine.get_chunk(&addr)?;
+                        tr.push_level(height - 1, data)?;
                     }
                 }
-                Ok(())
             }
+            Ok(())
         };
 
+        update_progress_msg("removing expired items...".to_string())?;
+        {
+            let tx = self
+                .conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+            if itemset::remove_expired_items(&tx, chrono::Utc::now())? != 0 {
+                Repo::update_item_log_head(&tx)?;
+                tx.commit()?;
+            }
+        }
+
         update_progress_msg("walking reachable data...".to_string())?;
         {
             // Walk all reachable data WITHOUT an exclusive repo lock, this means
@@ -567,16 +1268,21 @@ impl Repo {
         }
 
         update_progress_msg("acquiring exclusive repository lock...".to_string())?;
-        self.alter_lock_mode(LockMode::Exclusive)?;
+        self.alter_lock_mode(LockMode::Exclusive, "gc", update_progress_msg)?;
 
         // We must commit the new gc generation before we start
         // deleting any chunks, the gc generation is how we invalidate
         // client side put caches.
+        let gc_generation = Xid::new();
         self.conn.execute(
             "update RepositoryMeta set Value = ? where Key = 'gc-generation';",
-            rusqlite::params![Xid::new()],
+            rusqlite::params![gc_generation],
         )?;
 
+        // Reads started before the new generation may still fetch any item they
+        // synced, including those removed since, so we keep all logged items.
+        let old_read_snapshots = self.count_old_read_snapshots(&gc_generation)?;
+
         {
             let tx = self
                 .conn
@@ -587,8 +1293,30 @@ impl Repo {
             // an exclusive repository lock.
             itemset::walk_items(&tx, &mut walk_item)?;
 
-            update_progress_msg("compacting item log...".to_string())?;
-            itemset::compact(&tx)?;
+            if old_read_snapshots != 0 {
+                update_progress_msg(format!(
+                    "keeping removed items for {} reads in progress...",
+                    old_read_snapshots
+                ))?;
+                itemset::walk_log(&tx, 0, &mut |op_id, item_id, op| match (item_id, op) {
+                    (Some(item_id), itemset::LogOp::AddItem(metadata)) => {
+                        walk_item(op_id, item_id, metadata)
+                    }
+                    _ => Ok(()),
+                })?;
+                // The log is not compacted so the next gc can do the same
+                // if these reads are still in progress.
+            } else {
+                update_progress_msg("compacting item log...".to_string())?;
+                // The stored head is a checkpoint of the chain up to the last op, it
+                // keeps committing to the removed ops so clients can continue from it.
+                Repo::update_item_log_head(&tx)?;
+                itemset::compact(&tx)?;
+                tx.execute(
+                    "insert or replace into RepositoryMeta(Key, Value) select 'item-log-compacted-op', Value from RepositoryMeta where Key = 'item-log-head-op';",
+                    rusqlite::NO_PARAMS,
+                )?;
+            }
 
             tx.commit()?;
         }
@@ -605,7 +1333,23 @@ impl Repo {
         reachability_tx.commit()?;
 
         update_progress_msg("deleting unused chunks...".to_string())?;
-        let stats = storage_engine.gc(&reachability_db_path, &mut reachability_db)?;
+        let stats = storage_engine.gc(
+            &reachability_db_path,
+            &mut reachability_db,
+            &pacer,
+            sweep_workers,
+        )?;
+
+        match stats.bytes_remaining {
+            Some(bytes_remaining) => self.conn.execute(
+                "insert or replace into RepositoryMeta(Key, Value) values('data-size', ?);",
+                rusqlite::params![bytes_remaining as i64],
+            )?,
+            None => self.conn.execute(
+                "delete from RepositoryMeta where Key = 'data-size';",
+                rusqlite::NO_PARAMS,
+            )?,
+        };
 
         // We no longer need this reachability database.
         std::fs::remove_file(&reachability_db_path)?;
@@ -621,7 +1365,6 @@ impl Repo {
 
 #[cfg(test)]
 mod tests {
-    use super::super::address::*;
     use super::*;
 
     #[test]
@@ -640,4 +1383,17 @@ mod tests {
         let v = storage_engine.get_chunk(&addr).unwrap();
         assert_eq!(v, vec![1]);
     }
+
+    #[test]
+    fn gc_pacer_rate() {
+        let pacer = GCPacer::new(GCPacing {
+            pause: None,
+            max_io_rate: Some(100),
+        });
+        let start = std::time::Instant::now();
+        for _ in 0..4 {
+            pacer.pace();
+        }
+        assert!(start.elapsed() >= std::time::Duration::from_millis(30));
+    }
 }
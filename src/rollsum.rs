@@ -37,6 +37,7 @@ const CHAR_OFFSET: usize = 31;
 
 /// Rolling checksum method used by `bup`
 /// based on: https://github.com/bup/bup/lib/bup/bupsplit.c
+#[derive(Clone)]
 pub struct Rollsum {
     s1: usize,
     s2: usize,
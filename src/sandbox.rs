@@ -0,0 +1,315 @@
+// Confinement for 'bupstash serve --sandbox' and 'bupstash get --sandbox',
+// see bupstash-serve(1) and bupstash-get(1).
+//
+// The process is chrooted into the only directory it needs, loses its
+// privileges and is limited to the system calls it needs, so a bug in
+// the code handling data from the other side cannot reach the rest of the system.
+
+use std::path::Path;
+
+cfg_if::cfg_if! {
+    if #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))] {
+        use super::repository;
+        use std::os::unix::fs::MetadataExt;
+
+        // Not yet in the libc crate.
+        const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+        const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+        const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+        const BPF_LD_W_ABS: u16 = 0x20;
+        const BPF_JEQ_K: u16 = 0x15;
+        const BPF_RET_K: u16 = 0x06;
+        const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+        #[repr(C)]
+        struct SockFilter {
+            code: u16,
+            jt: u8,
+            jf: u8,
+            k: u32,
+        }
+
+        #[repr(C)]
+        struct SockFprog {
+            len: u16,
+            filter: *const SockFilter,
+        }
+
+        #[repr(C)]
+        struct CapHeader {
+            version: u32,
+            pid: libc::c_int,
+        }
+
+        #[repr(C)]
+        #[derive(Default, Clone, Copy)]
+        struct CapData {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+
+        // Offsets into struct seccomp_data.
+        const SECCOMP_DATA_NR: u32 = 0;
+        const SECCOMP_DATA_ARCH: u32 = 4;
+
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                const AUDIT_ARCH: u32 = 0xc000_003e;
+                const SYS_RSEQ: libc::c_long = 334;
+                const ARCH_BASE_SYSCALLS: &[libc::c_long] = &[libc::SYS_poll, libc::SYS_dup2];
+                const ARCH_FILE_SYSCALLS: &[libc::c_long] = &[
+                    libc::SYS_open,
+                    libc::SYS_stat,
+                    libc::SYS_lstat,
+                    libc::SYS_access,
+                    libc::SYS_readlink,
+                    libc::SYS_rename,
+                    libc::SYS_unlink,
+                    libc::SYS_mkdir,
+                    libc::SYS_rmdir,
+                    libc::SYS_fadvise64,
+                ];
+                const ARCH_EXTRACT_SYSCALLS: &[libc::c_long] =
+                    &[libc::SYS_symlink, libc::SYS_link, libc::SYS_chmod];
+            } else {
+                const AUDIT_ARCH: u32 = 0xc000_00b7;
+                const SYS_RSEQ: libc::c_long = 293;
+                const ARCH_BASE_SYSCALLS: &[libc::c_long] = &[];
+                const ARCH_FILE_SYSCALLS: &[libc::c_long] = &[
+                    // fadvise64_64
+                    223,
+                ];
+                const ARCH_EXTRACT_SYSCALLS: &[libc::c_long] = &[];
+            }
+        }
+
+        const SYS_CLONE3: libc::c_long = 435;
+
+        // What the rust runtime needs to read and write already open files,
+        // allocate memory and run threads.
+        const BASE_SYSCALLS: &[libc::c_long] = &[
+            libc::SYS_read,
+            libc::SYS_write,
+            libc::SYS_readv,
+            libc::SYS_writev,
+            libc::SYS_close,
+            libc::SYS_fstat,
+            libc::SYS_lseek,
+            libc::SYS_fcntl,
+            libc::SYS_dup,
+            libc::SYS_dup3,
+            libc::SYS_ppoll,
+            libc::SYS_mmap,
+            libc::SYS_munmap,
+            libc::SYS_mremap,
+            libc::SYS_mprotect,
+            libc::SYS_madvise,
+            libc::SYS_brk,
+            libc::SYS_futex,
+            libc::SYS_clone,
+            SYS_CLONE3,
+            SYS_RSEQ,
+            libc::SYS_set_robust_list,
+            libc::SYS_set_tid_address,
+            libc::SYS_sched_getaffinity,
+            libc::SYS_sched_yield,
+            libc::SYS_membarrier,
+            libc::SYS_rt_sigaction,
+            libc::SYS_rt_sigprocmask,
+            libc::SYS_rt_sigreturn,
+            libc::SYS_sigaltstack,
+            libc::SYS_nanosleep,
+            libc::SYS_clock_nanosleep,
+            libc::SYS_clock_gettime,
+            libc::SYS_gettimeofday,
+            libc::SYS_getrandom,
+            libc::SYS_getpid,
+            libc::SYS_gettid,
+            libc::SYS_getuid,
+            libc::SYS_geteuid,
+            libc::SYS_getgid,
+            libc::SYS_getegid,
+            libc::SYS_exit,
+            libc::SYS_exit_group,
+        ];
+
+        // Everything the repository and sqlite use on files below the chroot.
+        const FILE_SYSCALLS: &[libc::c_long] = &[
+            libc::SYS_pread64,
+            libc::SYS_pwrite64,
+            libc::SYS_preadv,
+            libc::SYS_pwritev,
+            libc::SYS_openat,
+            libc::SYS_newfstatat,
+            libc::SYS_statx,
+            libc::SYS_statfs,
+            libc::SYS_fstatfs,
+            libc::SYS_faccessat,
+            libc::SYS_readlinkat,
+            libc::SYS_getcwd,
+            libc::SYS_getdents64,
+            libc::SYS_mkdirat,
+            libc::SYS_unlinkat,
+            libc::SYS_renameat,
+            libc::SYS_renameat2,
+            libc::SYS_linkat,
+            libc::SYS_ftruncate,
+            libc::SYS_fallocate,
+            libc::SYS_fsync,
+            libc::SYS_fdatasync,
+            libc::SYS_fchmod,
+            libc::SYS_fchown,
+            libc::SYS_flock,
+        ];
+
+        fn check(what: &str, rc: libc::c_int) -> Result<(), failure::Error> {
+            if rc != 0 {
+                failure::bail!("sandbox: unable to {}: {}", what, std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        fn drop_capabilities() -> Result<(), failure::Error> {
+            let header = CapHeader {
+                version: LINUX_CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+            let data = [CapData::default(); 2];
+            let rc = unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) };
+            check("drop capabilities", rc as libc::c_int)
+        }
+
+        // What tar extraction needs on top of FILE_SYSCALLS.
+        const EXTRACT_SYSCALLS: &[libc::c_long] = &[
+            libc::SYS_symlinkat,
+            libc::SYS_fchmodat,
+            libc::SYS_utimensat,
+        ];
+
+        fn syscall_filter(allowed: &[&[libc::c_long]]) -> Vec<SockFilter> {
+            let stmt = |code, k| SockFilter { code, jt: 0, jf: 0, k };
+            let mut filter = vec![
+                // Syscall numbers differ between architectures, refuse any we do not expect.
+                stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+                SockFilter { code: BPF_JEQ_K, jt: 1, jf: 0, k: AUDIT_ARCH },
+                stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+                stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+            ];
+            for nr in allowed.iter().flat_map(|syscalls| syscalls.iter()) {
+                filter.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 1, k: *nr as u32 });
+                filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+            }
+            // Failing the call lets the process report an error, unlike killing it.
+            filter.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+            filter
+        }
+
+        // Returns whether we were root, unprivileged users need a user namespace to chroot.
+        fn chroot_into(root: &Path) -> Result<bool, failure::Error> {
+            let privileged = nix::unistd::geteuid().is_root();
+            let mut flags = nix::sched::CloneFlags::CLONE_NEWNS
+                | nix::sched::CloneFlags::CLONE_NEWNET
+                | nix::sched::CloneFlags::CLONE_NEWIPC;
+            if !privileged {
+                flags |= nix::sched::CloneFlags::CLONE_NEWUSER;
+            }
+            let uid = nix::unistd::geteuid();
+            let gid = nix::unistd::getegid();
+            if let Err(err) = nix::sched::unshare(flags) {
+                failure::bail!("sandbox: unable to create namespaces: {}", err);
+            }
+            if !privileged {
+                std::fs::write("/proc/self/setgroups", "deny")?;
+                std::fs::write("/proc/self/uid_map", format!("{} {} 1", uid, uid))?;
+                std::fs::write("/proc/self/gid_map", format!("{} {} 1", gid, gid))?;
+            }
+            if let Err(err) = nix::unistd::chroot(root) {
+                failure::bail!("sandbox: unable to chroot into {}: {}", root.to_string_lossy(), err);
+            }
+            nix::unistd::chdir("/")?;
+            Ok(privileged)
+        }
+
+        fn restrict_syscalls(allowed: &[&[libc::c_long]]) -> Result<(), failure::Error> {
+            drop_capabilities()?;
+            check("set no_new_privs", unsafe {
+                libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0)
+            })?;
+            let filter = syscall_filter(allowed);
+            let prog = SockFprog {
+                len: filter.len() as u16,
+                filter: filter.as_ptr(),
+            };
+            check("install the syscall filter", unsafe {
+                libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    libc::SECCOMP_MODE_FILTER,
+                    &prog as *const SockFprog,
+                )
+            })
+        }
+
+        // Must be called before the server starts any threads.
+        pub fn enter_serve(repo_path: &Path) -> Result<(), failure::Error> {
+            let metadata = match std::fs::metadata(repo_path) {
+                Ok(metadata) => metadata,
+                Err(err) => failure::bail!(
+                    "--sandbox requires an existing repository at {}: {}",
+                    repo_path.to_string_lossy(),
+                    err
+                ),
+            };
+
+            // The socket of an external storage engine is outside of the sandbox.
+            let spec: repository::StorageEngineSpec =
+                serde_json::from_slice(&std::fs::read(repo_path.join("storage-engine.json"))?)?;
+            if let repository::StorageEngineSpec::ExternalStore { .. } = spec {
+                failure::bail!("--sandbox does not support repositories using an external storage engine");
+            }
+
+            if chroot_into(repo_path)? {
+                // Serve as whoever owns the repository.
+                nix::unistd::setgroups(&[])?;
+                nix::unistd::setgid(nix::unistd::Gid::from_raw(metadata.gid()))?;
+                nix::unistd::setuid(nix::unistd::Uid::from_raw(metadata.uid()))?;
+            }
+            restrict_syscalls(&[
+                BASE_SYSCALLS,
+                ARCH_BASE_SYSCALLS,
+                FILE_SYSCALLS,
+                ARCH_FILE_SYSCALLS,
+            ])
+        }
+
+        // Confines a restore to extracting into target, or without a target to
+        // writing to the files it already has open. Must be called before starting any threads.
+        pub fn enter_restore(target: Option<&Path>) -> Result<(), failure::Error> {
+            match target {
+                Some(target) => {
+                    if !target.is_dir() {
+                        failure::bail!("--restore-into {} is not a directory", target.to_string_lossy());
+                    }
+                    chroot_into(target)?;
+                    restrict_syscalls(&[
+                        BASE_SYSCALLS,
+                        ARCH_BASE_SYSCALLS,
+                        FILE_SYSCALLS,
+                        ARCH_FILE_SYSCALLS,
+                        EXTRACT_SYSCALLS,
+                        ARCH_EXTRACT_SYSCALLS,
+                    ])
+                }
+                None => restrict_syscalls(&[BASE_SYSCALLS, ARCH_BASE_SYSCALLS]),
+            }
+        }
+    } else {
+        pub fn enter_serve(_repo_path: &Path) -> Result<(), failure::Error> {
+            failure::bail!("--sandbox is only supported on linux x86_64 and aarch64")
+        }
+
+        pub fn enter_restore(_target: Option<&Path>) -> Result<(), failure::Error> {
+            failure::bail!("--sandbox is only supported on linux x86_64 and aarch64")
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use super::address::*;
+use super::chunker;
 use super::xid::*;
 use std::path::PathBuf;
 
@@ -6,6 +7,17 @@ pub struct SendLog {
     conn: rusqlite::Connection,
 }
 
+// How a previous put chunked the contents of a regular file, see put --tail-deltas.
+pub struct FileTail {
+    pub size: u64,
+    // A keyed hash of sampled blocks of the first 'size' bytes.
+    pub samples: Vec<u8>,
+    // The chunks cover the file contents from 'start' to the last of 'ends'.
+    pub start: u64,
+    pub ends: Vec<u64>,
+    pub addresses: Vec<u8>,
+}
+
 pub struct SendLogSession<'a> {
     gc_generation: Xid,
     session_id: Xid,
@@ -91,6 +103,16 @@ impl SendLog {
             rusqlite::NO_PARAMS,
         )?;
 
+        tx.execute(
+            "create table if not exists FileTails(Key primary key, Size, Samples, Start, Ends, Addresses, GCGeneration, LatestSessionId, ItemId) without rowid; ",
+            rusqlite::NO_PARAMS,
+        )?;
+
+        tx.execute(
+            "create table if not exists StreamCheckpoints(Key, Idx, End, Address, GCGeneration, primary key (Key, Idx)) without rowid; ",
+            rusqlite::NO_PARAMS,
+        )?;
+
         tx.commit()?;
 
         /* Simple policy to decide when to defragment our send log. */
@@ -143,14 +165,25 @@ impl<'a> SendLogSession<'a> {
         let last_send_id = self.log.last_send_id()?;
 
         if had_send_id {
+            // Entries without an item id were never part of a sent item, so
+            // they can't be kept across a gc.
+            self.log.conn.execute(
+                "delete from Sent where (GCGeneration != ?) and (ItemId is not ?);",
+                rusqlite::params![self.gc_generation, last_send_id],
+            )?;
             self.log.conn.execute(
-                "delete from Sent where (GCGeneration != ?) and (ItemId != ?);",
+                "delete from StatCache where (GCGeneration != ?) and (ItemId is not ?);",
                 rusqlite::params![self.gc_generation, last_send_id],
             )?;
             self.log.conn.execute(
-                "delete from StatCache where (GCGeneration != ?) and (ItemId != ?);",
+                "delete from FileTails where (GCGeneration != ?) and (ItemId is not ?);",
                 rusqlite::params![self.gc_generation, last_send_id],
             )?;
+            // Stream checkpoints never belong to a sent item.
+            self.log.conn.execute(
+                "delete from StreamCheckpoints where GCGeneration != ?;",
+                rusqlite::params![self.gc_generation],
+            )?;
         } else {
             self.log.conn.execute(
                 "delete from Sent where GCGeneration != ?;",
@@ -160,11 +193,60 @@ impl<'a> SendLogSession<'a> {
                 "delete from StatCache where GCGeneration != ?;",
                 &[self.gc_generation],
             )?;
+            self.log.conn.execute(
+                "delete from FileTails where GCGeneration != ?;",
+                rusqlite::params![self.gc_generation],
+            )?;
+            self.log.conn.execute(
+                "delete from StreamCheckpoints where GCGeneration != ?;",
+                rusqlite::params![self.gc_generation],
+            )?;
         }
 
         Ok(())
     }
 
+    // Cached file chunks were split with the chunking parameters recorded by the last
+    // send, they are dropped when they change so a file is never sent split both ways.
+    pub fn set_chunking(&self, chunking: &chunker::ChunkingParams) -> Result<(), failure::Error> {
+        if !self.tx_active {
+            failure::bail!("no active transaction");
+        };
+
+        let chunking = chunking.description();
+        let last_chunking = match self.log.conn.query_row(
+            "select Value from LogMeta where Key = 'chunking';",
+            rusqlite::NO_PARAMS,
+            |r| r.get(0),
+        ) {
+            Ok(last_chunking) => last_chunking,
+            // Older send logs were always written with the defaults.
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                chunker::ChunkingParams::default().description()
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if chunking != last_chunking {
+            self.log
+                .conn
+                .execute("delete from StatCache;", rusqlite::NO_PARAMS)?;
+            self.log
+                .conn
+                .execute("delete from FileTails;", rusqlite::NO_PARAMS)?;
+            self.log
+                .conn
+                .execute("delete from StreamCheckpoints;", rusqlite::NO_PARAMS)?;
+        }
+
+        self.log.conn.execute(
+            "insert or replace into LogMeta(Key, Value) Values('chunking', ?);",
+            rusqlite::params![chunking],
+        )?;
+
+        Ok(())
+    }
+
     pub fn add_address(&self, addr: &Address) -> Result<(), failure::Error> {
         if !self.tx_active {
             failure::bail!("no active transaction");
@@ -259,6 +341,126 @@ impl<'a> SendLogSession<'a> {
         }
     }
 
+    pub fn add_file_tail(&self, key: &[u8], tail: &FileTail) -> Result<(), failure::Error> {
+        if !self.tx_active {
+            failure::bail!("no active transaction");
+        };
+
+        // Unlike the stat cache the file contents may have changed, so the entry is replaced.
+        let mut stmt = self.log.conn.prepare_cached(
+            "insert or replace into FileTails(GCGeneration, LatestSessionId, Key, Size, Samples, Start, Ends, Addresses) \
+            Values($1, $2, $3, $4, $5, $6, $7, $8);",
+        )?;
+
+        let ends: Vec<serde_bare::Uint> = tail.ends.iter().map(|e| serde_bare::Uint(*e)).collect();
+
+        stmt.execute(rusqlite::params![
+            self.gc_generation,
+            self.session_id,
+            key,
+            tail.size as i64,
+            tail.samples,
+            tail.start as i64,
+            serde_bare::to_vec(&ends)?,
+            tail.addresses,
+        ])?;
+        Ok(())
+    }
+
+    // Keeps the entry of a file that was not read because its directory was unchanged.
+    pub fn touch_file_tail(&self, key: &[u8]) -> Result<(), failure::Error> {
+        if !self.tx_active {
+            failure::bail!("no active transaction");
+        };
+
+        let mut stmt = self
+            .log
+            .conn
+            .prepare_cached("update FileTails set LatestSessionId = $1 where Key = $2;")?;
+        stmt.execute(rusqlite::params![self.session_id, key])?;
+        Ok(())
+    }
+
+    pub fn file_tail_lookup(&self, key: &[u8]) -> Result<Option<FileTail>, failure::Error> {
+        let mut stmt = self.log.conn.prepare_cached(
+            "select Size, Samples, Start, Ends, Addresses from FileTails where Key = $1;",
+        )?;
+
+        match stmt.query_row(rusqlite::params![key], |r| {
+            let size: i64 = r.get(0)?;
+            let samples: Vec<u8> = r.get(1)?;
+            let start: i64 = r.get(2)?;
+            let ends: Vec<u8> = r.get(3)?;
+            let addresses: Vec<u8> = r.get(4)?;
+            Ok((size as u64, samples, start as u64, ends, addresses))
+        }) {
+            Ok((size, samples, start, ends, addresses)) => {
+                let ends: Vec<serde_bare::Uint> = serde_bare::from_slice(&ends)?;
+                Ok(Some(FileTail {
+                    size,
+                    samples,
+                    start,
+                    ends: ends.iter().map(|e| e.0).collect(),
+                    addresses,
+                }))
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Record the idx'th chunk of a file being sent as a stream, ending at file offset end.
+    // Once checkpointed the chunk is in the repository, so an interrupted send of the
+    // same file can resume after it, see stream_checkpoints.
+    pub fn add_stream_checkpoint(
+        &self,
+        key: &[u8],
+        idx: u64,
+        end: u64,
+        addr: &Address,
+    ) -> Result<(), failure::Error> {
+        if !self.tx_active {
+            failure::bail!("no active transaction");
+        };
+
+        let mut stmt = self.log.conn.prepare_cached(
+            "insert or replace into StreamCheckpoints(GCGeneration, Key, Idx, End, Address) \
+            Values($1, $2, $3, $4, $5);",
+        )?;
+
+        stmt.execute(rusqlite::params![
+            self.gc_generation,
+            key,
+            idx as i64,
+            end as i64,
+            &addr.bytes[..]
+        ])?;
+        Ok(())
+    }
+
+    // The file offsets and addresses of the leading chunks recorded for key, in order.
+    pub fn stream_checkpoints(&self, key: &[u8]) -> Result<Vec<(u64, Address)>, failure::Error> {
+        let mut stmt = self.log.conn.prepare_cached(
+            "select Idx, End, Address from StreamCheckpoints where Key = $1 order by Idx;",
+        )?;
+
+        let mut checkpoints = Vec::new();
+        let mut rows = stmt.query(rusqlite::params![key])?;
+        while let Some(row) = rows.next()? {
+            let idx: i64 = row.get(0)?;
+            let end: i64 = row.get(1)?;
+            let addr: Vec<u8> = row.get(2)?;
+            // Only an unbroken run of chunks from the start of the file is usable.
+            if idx as usize != checkpoints.len() || addr.len() != ADDRESS_SZ {
+                break;
+            }
+            let mut address = Address::default();
+            address.bytes[..].clone_from_slice(&addr);
+            checkpoints.push((end as u64, address));
+        }
+        Ok(checkpoints)
+    }
+
     pub fn checkpoint(&mut self) -> Result<(), failure::Error> {
         if !self.tx_active {
             failure::bail!("no active transaction");
@@ -289,6 +491,16 @@ impl<'a> SendLogSession<'a> {
             &[&self.session_id],
         )?;
 
+        self.log.conn.execute(
+            "delete from FileTails where LatestSessionId != ?;",
+            &[&self.session_id],
+        )?;
+
+        // Any interrupted stream was either completed by this send, or is not being retried.
+        self.log
+            .conn
+            .execute("delete from StreamCheckpoints;", rusqlite::NO_PARAMS)?;
+
         self.log.conn.execute(
             "update StatCache set ItemId = ? where LatestSessionId = ?;",
             &[id, &self.session_id],
@@ -299,6 +511,11 @@ impl<'a> SendLogSession<'a> {
             &[id, &self.session_id],
         )?;
 
+        self.log.conn.execute(
+            "update FileTails set ItemId = ? where LatestSessionId = ?;",
+            &[id, &self.session_id],
+        )?;
+
         self.log.conn.execute(
             "insert or replace into LogMeta(Key, Value) Values('last-send-id', ?);",
             &[id],
@@ -308,6 +525,18 @@ impl<'a> SendLogSession<'a> {
         self.tx_active = false;
         Ok(())
     }
+
+    // Commit addresses known to be in the repository without sending an item, unlike
+    // a normal commit the existing entries are kept. The addresses are forgotten once
+    // the gc generation changes.
+    pub fn commit_seeded(mut self) -> Result<(), failure::Error> {
+        if !self.tx_active {
+            failure::bail!("no active transaction");
+        };
+        self.log.conn.execute("commit;", rusqlite::NO_PARAMS)?;
+        self.tx_active = false;
+        Ok(())
+    }
 }
 
 impl<'a> Drop for SendLogSession<'a> {
@@ -441,6 +670,52 @@ mod tests {
         drop(sendlog);
     }
 
+    #[test]
+    fn stream_checkpoints() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let log_path = {
+            let mut d = PathBuf::from(tmp_dir.path());
+            d.push("send.log");
+            d
+        };
+
+        let gc_generation = Xid::new();
+        let addr = Address::default();
+
+        // Only checkpointed chunks survive an interrupted send.
+        let mut sendlog = SendLog::open(&log_path).unwrap();
+        {
+            let mut session = sendlog.session(gc_generation).unwrap();
+            session.add_stream_checkpoint(b"f", 0, 10, &addr).unwrap();
+            session.add_stream_checkpoint(b"f", 1, 25, &addr).unwrap();
+            session.checkpoint().unwrap();
+            session.add_stream_checkpoint(b"f", 2, 40, &addr).unwrap();
+        }
+        drop(sendlog);
+
+        let mut sendlog = SendLog::open(&log_path).unwrap();
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            session.perform_cache_invalidations(false).unwrap();
+            let checkpoints = session.stream_checkpoints(b"f").unwrap();
+            assert_eq!(checkpoints, vec![(10, addr), (25, addr)]);
+            assert!(session.stream_checkpoints(b"g").unwrap().is_empty());
+            // A gap ends the usable run of chunks.
+            session.add_stream_checkpoint(b"g", 1, 10, &addr).unwrap();
+            assert!(session.stream_checkpoints(b"g").unwrap().is_empty());
+            session.commit(&Xid::new()).unwrap();
+        }
+        drop(sendlog);
+
+        // Completed sends forget them.
+        let mut sendlog = SendLog::open(&log_path).unwrap();
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            assert!(session.stream_checkpoints(b"f").unwrap().is_empty());
+        }
+        drop(sendlog);
+    }
+
     #[test]
     fn cache_commit_then_checkpoint() {
         let tmp_dir = tempfile::tempdir().unwrap();
@@ -507,4 +782,143 @@ mod tests {
         }
         drop(sendlog);
     }
+
+    #[test]
+    fn cache_seeded() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let log_path = {
+            let mut d = PathBuf::from(tmp_dir.path());
+            d.push("send.log");
+            d
+        };
+
+        let gc_generation = Xid::new();
+        let id = Xid::new();
+        let addr = Address::default();
+        let seeded_addr = Address {
+            bytes: [1; ADDRESS_SZ],
+        };
+
+        let mut sendlog = SendLog::open(&log_path).unwrap();
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            session.add_address(&addr).unwrap();
+            session.commit(&id).unwrap();
+        }
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            session.add_address(&seeded_addr).unwrap();
+            session.commit_seeded().unwrap();
+        }
+        drop(sendlog);
+
+        let mut sendlog = SendLog::open(&log_path).unwrap();
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            // Seeding keeps existing entries and the last send id.
+            assert_eq!(session.last_send_id().unwrap(), Some(id));
+            session.perform_cache_invalidations(true).unwrap();
+            assert!(session.cached_address(&addr).unwrap());
+            assert!(session.cached_address(&seeded_addr).unwrap());
+        }
+        {
+            let session = sendlog.session(Xid::new()).unwrap();
+            // The seeded address has no item id to keep it across a gc.
+            session.perform_cache_invalidations(true).unwrap();
+            assert!(session.cached_address(&addr).unwrap());
+            assert!(!session.cached_address(&seeded_addr).unwrap());
+        }
+        drop(sendlog);
+    }
+
+    #[test]
+    fn file_tails() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let log_path = {
+            let mut d = PathBuf::from(tmp_dir.path());
+            d.push("send.log");
+            d
+        };
+
+        let gc_generation = Xid::new();
+        let tail = FileTail {
+            size: 10,
+            samples: vec![1, 2, 3],
+            start: 2,
+            ends: vec![5, 9],
+            addresses: vec![7; 2 * ADDRESS_SZ],
+        };
+
+        let mut sendlog = SendLog::open(&log_path).unwrap();
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            session.add_file_tail(b"a", &tail).unwrap();
+            session.add_file_tail(b"b", &tail).unwrap();
+            session.commit(&Xid::new()).unwrap();
+        }
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            let cached = session.file_tail_lookup(b"a").unwrap().unwrap();
+            assert_eq!(cached.size, tail.size);
+            assert_eq!(cached.samples, tail.samples);
+            assert_eq!(cached.start, tail.start);
+            assert_eq!(cached.ends, tail.ends);
+            assert_eq!(cached.addresses, tail.addresses);
+            // Only entries used or touched in a session survive its commit.
+            session.touch_file_tail(b"a").unwrap();
+            session.commit(&Xid::new()).unwrap();
+        }
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            assert!(session.file_tail_lookup(b"a").unwrap().is_some());
+            assert!(session.file_tail_lookup(b"b").unwrap().is_none());
+        }
+        {
+            let session = sendlog.session(Xid::new()).unwrap();
+            session.perform_cache_invalidations(false).unwrap();
+            assert!(session.file_tail_lookup(b"a").unwrap().is_none());
+        }
+        drop(sendlog);
+    }
+
+    #[test]
+    fn chunking_change() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let log_path = {
+            let mut d = PathBuf::from(tmp_dir.path());
+            d.push("send.log");
+            d
+        };
+
+        let gc_generation = Xid::new();
+        let fastcdc = chunker::ChunkingParams {
+            algorithm: chunker::ChunkAlgorithm::FastCdc,
+            ..Default::default()
+        };
+
+        let mut sendlog = SendLog::open(&log_path).unwrap();
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            session
+                .set_chunking(&chunker::ChunkingParams::default())
+                .unwrap();
+            session.add_stat_cache_data(b"a", 1, &[], &[]).unwrap();
+            session.add_address(&Address::default()).unwrap();
+            session.commit(&Xid::new()).unwrap();
+        }
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            session.set_chunking(&fastcdc).unwrap();
+            assert!(session.stat_cache_lookup(b"a").unwrap().is_none());
+            assert!(session.cached_address(&Address::default()).unwrap());
+            session.add_stat_cache_data(b"b", 1, &[], &[]).unwrap();
+            session.commit(&Xid::new()).unwrap();
+        }
+        {
+            let session = sendlog.session(gc_generation).unwrap();
+            session.set_chunking(&fastcdc).unwrap();
+            assert!(session.stat_cache_lookup(b"b").unwrap().is_some());
+        }
+        drop(sendlog);
+    }
 }
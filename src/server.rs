@@ -1,7 +1,5 @@
-use super::address;
 use super::htree;
 use super::index;
-use super::itemset;
 use super::protocol::*;
 use super::repository;
 use super::xid::*;
@@ -13,6 +11,133 @@ pub struct ServerConfig {
     pub allow_get: bool,
     pub allow_put: bool,
     pub allow_remove: bool,
+    pub max_connections: Option<usize>,
+    pub max_packet_size: usize,
+}
+
+// Reads from a file descriptor, failing if nothing arrives within the
+// timeout. Wrap in a BufReader so we only wait when out of buffered data.
+pub struct IdleTimeoutReader {
+    fd: std::os::unix::io::RawFd,
+    timeout: std::time::Duration,
+}
+
+impl IdleTimeoutReader {
+    pub fn new(fd: std::os::unix::io::RawFd, timeout: std::time::Duration) -> IdleTimeoutReader {
+        IdleTimeoutReader { fd, timeout }
+    }
+}
+
+fn nix_to_io_error(err: nix::Error) -> std::io::Error {
+    std::io::Error::from_raw_os_error(err.as_errno().map(|e| e as i32).unwrap_or(libc::EIO))
+}
+
+impl std::io::Read for IdleTimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let timeout_ms = std::cmp::min(self.timeout.as_millis(), libc::c_int::MAX as u128);
+        let mut fds = [nix::poll::PollFd::new(
+            self.fd,
+            nix::poll::PollFlags::POLLIN,
+        )];
+        loop {
+            match nix::poll::poll(&mut fds, timeout_ms as libc::c_int) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "client was idle for too long",
+                    ))
+                }
+                Ok(_) => break,
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(err) => return Err(nix_to_io_error(err)),
+            }
+        }
+        loop {
+            match nix::unistd::read(self.fd, buf) {
+                Ok(n) => return Ok(n),
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(err) => return Err(nix_to_io_error(err)),
+            }
+        }
+    }
+}
+
+// The read half of an in process connection, see serve_in_process.
+pub struct ChannelReader {
+    rx: std::sync::mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl std::io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.buf.len() {
+            match self.rx.recv() {
+                Ok(data) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                // The other side hung up.
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// The write half of an in process connection, see serve_in_process.
+pub struct ChannelWriter {
+    tx: std::sync::mpsc::SyncSender<Vec<u8>>,
+}
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.tx.send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "connection closed",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn channel() -> (ChannelWriter, ChannelReader) {
+    // Bounded like a pipe, so a fast writer can't buffer unlimited data.
+    let (tx, rx) = std::sync::mpsc::sync_channel(8);
+    (
+        ChannelWriter { tx },
+        ChannelReader {
+            rx,
+            buf: Vec::new(),
+            pos: 0,
+        },
+    )
+}
+
+// Serve a repository from a thread in this process, the returned reader and
+// writer are the client side of the connection. The thread exits once the
+// client hangs up or drops its side of the connection.
+pub fn serve_in_process(
+    cfg: ServerConfig,
+) -> (ChannelReader, ChannelWriter, std::thread::JoinHandle<()>) {
+    let (client_w, mut server_r) = channel();
+    let (mut server_w, client_r) = channel();
+    let server_thread = std::thread::spawn(move || {
+        // Errors are reported to the client, if it is still there.
+        let _ = serve(cfg, &mut server_r, &mut server_w);
+    });
+    (client_r, client_w, server_thread)
 }
 
 pub fn serve(
@@ -38,27 +163,39 @@ fn serve2(
     w: &mut dyn std::io::Write,
 ) -> Result<(), failure::Error> {
     loop {
-        match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+        match read_packet(r, cfg.max_packet_size)? {
             Packet::TOpenRepository(req) => {
-                if req.repository_protocol_version != "1" {
+                if req.repository_protocol_version != PROTOCOL_VERSION {
                     failure::bail!(
-                        "server does not support bupstash protocol version {}",
-                        req.repository_protocol_version
+                        "client protocol version {} does not match server protocol version {}, the client and server bupstash versions must be compatible",
+                        req.repository_protocol_version,
+                        PROTOCOL_VERSION
                     )
                 }
 
                 let mut repo = repository::Repo::open(&cfg.repo_path)?;
 
+                // Held until the client disconnects.
+                let _connection_slot = match cfg.max_connections {
+                    Some(max_connections) => Some(repo.acquire_connection_slot(max_connections)?),
+                    None => None,
+                };
+
                 match req.lock_hint {
-                    LockHint::Read => repo.alter_lock_mode(repository::LockMode::None)?,
-                    LockHint::Write => repo.alter_lock_mode(repository::LockMode::Write)?,
-                    LockHint::Gc => repo.alter_lock_mode(repository::LockMode::Write)?,
+                    LockHint::Read => lock_repo(&mut repo, repository::LockMode::None, "read", w)?,
+                    LockHint::Write => {
+                        lock_repo(&mut repo, repository::LockMode::Write, "write", w)?
+                    }
+                    LockHint::Gc => lock_repo(&mut repo, repository::LockMode::Write, "gc", w)?,
                 }
 
                 write_packet(
                     w,
                     &Packet::ROpenRepository(ROpenRepository {
                         now: chrono::Utc::now(),
+                        repository_id: repo.id()?,
+                        item_log_head: repo.item_log_head()?,
+                        tag_schema: repo.tag_schema()?,
                     }),
                 )?;
 
@@ -85,8 +222,12 @@ fn serve_repository(
     r: &mut dyn std::io::Read,
     w: &mut dyn std::io::Write,
 ) -> Result<(), failure::Error> {
+    // Created on the first chunk request, clients restoring from a mirror
+    // may request many chunks individually.
+    let mut chunk_storage = None;
+
     loop {
-        match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+        match read_packet(r, cfg.max_packet_size)? {
             Packet::TInitRepository(_) => {
                 failure::bail!(
                     "protocol error, repository initialization must be the first request"
@@ -96,42 +237,53 @@ fn serve_repository(
                 if !cfg.allow_put {
                     failure::bail!("server has disabled put for this client")
                 }
-                repo.alter_lock_mode(repository::LockMode::Write)?;
-                recv(repo, begin, r, w)?;
+                lock_repo(repo, repository::LockMode::Write, "put", w)?;
+                recv(repo, begin, &cfg, r, w)?;
             }
             Packet::TRequestData(req) => {
                 if !cfg.allow_get {
                     failure::bail!("server has disabled get for this client")
                 }
-                repo.alter_lock_mode(repository::LockMode::None)?;
+                lock_repo(repo, repository::LockMode::None, "get", w)?;
                 send(repo, req.id, req.ranges, w)?;
             }
             Packet::TRequestIndex(req) => {
                 if !cfg.allow_get {
                     failure::bail!("server has disabled get for this client")
                 }
-                repo.alter_lock_mode(repository::LockMode::None)?;
+                lock_repo(repo, repository::LockMode::None, "get", w)?;
                 send_index(repo, req.id, w)?;
             }
-            Packet::TGc(_) => {
+            Packet::TRequestChunk(address) => {
+                if !cfg.allow_get {
+                    failure::bail!("server has disabled get for this client")
+                }
+                lock_repo(repo, repository::LockMode::None, "get", w)?;
+                if chunk_storage.is_none() {
+                    chunk_storage = Some(repo.storage_engine()?);
+                }
+                let data = chunk_storage.as_mut().unwrap().get_chunk(&address)?;
+                write_packet(w, &Packet::RRequestChunk(data))?;
+            }
+            Packet::TGc(req) => {
                 if !cfg.allow_gc {
                     failure::bail!("server has disabled garbage collection for this client")
                 }
-                repo.alter_lock_mode(repository::LockMode::Write)?;
-                gc(repo, w)?;
+                lock_repo(repo, repository::LockMode::Write, "gc", w)?;
+                gc(repo, req, w)?;
             }
             Packet::TRequestItemSync(req) => {
                 if !cfg.allow_get && !cfg.allow_remove {
                     failure::bail!("server has disabled query and search for this client")
                 }
-                repo.alter_lock_mode(repository::LockMode::None)?;
-                item_sync(repo, req.after, req.gc_generation, w)?;
+                lock_repo(repo, repository::LockMode::None, "sync", w)?;
+                item_sync(repo, req, w)?;
             }
             Packet::TRmItems(items) => {
                 if !cfg.allow_remove {
                     failure::bail!("server has disabled remove for this client")
                 }
-                repo.alter_lock_mode(repository::LockMode::Write)?;
+                lock_repo(repo, repository::LockMode::Write, "rm", w)?;
                 if !items.is_empty() {
                     repo.remove_items(items)?;
                 }
@@ -141,7 +293,7 @@ fn serve_repository(
                 if !cfg.allow_put || !cfg.allow_get {
                     failure::bail!("server has disabled restore for this client (restore requires get and put permissions).")
                 }
-                repo.alter_lock_mode(repository::LockMode::Write)?;
+                lock_repo(repo, repository::LockMode::Write, "restore-removed", w)?;
                 let n_restored = repo.restore_removed()?;
                 write_packet(
                     w,
@@ -150,15 +302,71 @@ fn serve_repository(
                     }),
                 )?;
             }
+            Packet::TRepoStats => {
+                // Item counts and the log head reveal as much as listing does.
+                if !cfg.allow_get && !cfg.allow_remove {
+                    failure::bail!("server has disabled repository stats for this client")
+                }
+                lock_repo(repo, repository::LockMode::None, "repo-stats", w)?;
+                write_packet(
+                    w,
+                    &Packet::RRepoStats(RRepoStats {
+                        gc_generation: repo.gc_generation()?,
+                        item_count: serde_bare::Uint(repo.item_count()?),
+                        item_log_head: repo.item_log_head()?,
+                        lock_holders: repo.lock_holders()?,
+                    }),
+                )?;
+            }
+            Packet::TChunkSharing(req) => {
+                if !cfg.allow_get {
+                    failure::bail!("server has disabled get for this client")
+                }
+                lock_repo(repo, repository::LockMode::None, "shared", w)?;
+                write_packet(
+                    w,
+                    &Packet::RChunkSharing(repo.chunk_sharing(&req.a, &req.b)?),
+                )?;
+            }
+            Packet::TCloneItem(req) => {
+                if !cfg.allow_put || !cfg.allow_get {
+                    failure::bail!("server has disabled clone for this client (clone requires get and put permissions).")
+                }
+                lock_repo(repo, repository::LockMode::Write, "clone", w)?;
+                let item_id = repo.clone_item(&req.source, req.item)?;
+                write_packet(w, &Packet::RCloneItem(RCloneItem { item_id }))?;
+            }
+            Packet::TItemAddresses(req) => {
+                if !cfg.allow_get {
+                    failure::bail!("server has disabled get for this client")
+                }
+                lock_repo(repo, repository::LockMode::None, "restore-send-log", w)?;
+                repo.item_addresses(&req.items, &mut |addresses| {
+                    write_packet(w, &Packet::ItemAddresses(addresses))
+                })?;
+                write_packet(w, &Packet::ItemAddresses(vec![]))?;
+            }
             Packet::EndOfTransmission => return Ok(()),
             _ => failure::bail!("protocol error, unexpected packet kind"),
         };
     }
 }
 
+fn lock_repo(
+    repo: &mut repository::Repo,
+    lock_mode: repository::LockMode,
+    operation: &str,
+    w: &mut dyn std::io::Write,
+) -> Result<(), failure::Error> {
+    repo.alter_lock_mode(lock_mode, operation, &mut |msg| {
+        write_packet(w, &Packet::Progress(Progress::Notice(msg)))
+    })
+}
+
 fn recv(
     repo: &mut repository::Repo,
     begin: TBeginSend,
+    cfg: &ServerConfig,
     r: &mut dyn std::io::Read,
     w: &mut dyn std::io::Write,
 ) -> Result<(), failure::Error> {
@@ -171,30 +379,87 @@ fn recv(
             } else {
                 false
             },
+            tag_schema: repo.tag_schema()?,
         }),
     )?;
 
     let mut store_engine = repo.storage_engine()?;
 
-    loop {
-        match read_packet(r, DEFAULT_MAX_PACKET_SIZE)? {
+    // Clients may read back chunks for verification, but only chunks they
+    // sent in the most recently synced checkpoint, this means a put only client
+    // is unable to fetch arbitrary data from the repository. Addresses are only
+    // tracked when the client asked to verify chunks, they would otherwise grow
+    // with the size of the put for nothing.
+    let mut unsynced_addresses = std::collections::HashSet::new();
+    let mut verifiable_addresses = std::collections::HashSet::new();
+
+    let mut chunks_received: u64 = 0;
+    let mut bytes_received: u64 = 0;
+
+    let item_id = loop {
+        match read_packet(r, cfg.max_packet_size)? {
             Packet::Chunk(chunk) => {
+                chunks_received += 1;
+                bytes_received += chunk.data.len() as u64;
+                if begin.verify_chunks {
+                    unsynced_addresses.insert(chunk.address);
+                }
                 store_engine.add_chunk(&chunk.address, chunk.data)?;
             }
             Packet::TSendSync => {
                 store_engine.sync()?;
+                verifiable_addresses = std::mem::take(&mut unsynced_addresses);
                 write_packet(w, &Packet::RSendSync)?;
             }
+            Packet::TRequestChunk(address) => {
+                if !verifiable_addresses.contains(&address) {
+                    failure::bail!("protocol error, client requested a chunk it did not just send");
+                }
+                let data = store_engine.get_chunk(&address)?;
+                write_packet(w, &Packet::RRequestChunk(data))?;
+            }
             Packet::TAddItem(add_item) => {
                 store_engine.sync()?;
-                let item_id = repo.add_item(add_item.gc_generation, add_item.item)?;
-                write_packet(w, &Packet::RAddItem(item_id))?;
-                break;
+                break repo.add_item(add_item.gc_generation, add_item.item, &[], &[], None)?;
+            }
+            Packet::TAddTaggedItem(add_item) => {
+                if !add_item.replace_tags.is_empty() && !cfg.allow_remove {
+                    failure::bail!(
+                        "server has disabled remove for this client, unable to replace items"
+                    )
+                }
+                store_engine.sync()?;
+                break repo.add_item(
+                    add_item.gc_generation,
+                    add_item.item,
+                    &add_item.unique_tags,
+                    &add_item.replace_tags,
+                    add_item.expires,
+                )?;
             }
             _ => failure::bail!("protocol error, unexpected packet"),
         }
+    };
+
+    let added = store_engine.added_chunk_stats();
+    if let Some((_, bytes_added)) = added {
+        repo.add_data_size(bytes_added)?;
     }
 
+    write_packet(
+        w,
+        &Packet::RAddItem(RAddItem {
+            item_id,
+            stats: repository::SendStats {
+                chunks_received,
+                bytes_received,
+                chunks_added: added.map(|(chunks, _)| chunks as u64),
+                bytes_added: added.map(|(_, bytes)| bytes as u64),
+            },
+            usage: repo.usage()?,
+        }),
+    )?;
+
     Ok(())
 }
 
@@ -220,19 +485,13 @@ fn send(
         }
     };
 
-    match metadata {
-        itemset::VersionedItemMetadata::V1(metadata) => {
-            let mut tr = htree::TreeReader::new(
-                metadata.plain_text_metadata.data_tree.height,
-                &metadata.plain_text_metadata.data_tree.address,
-            );
+    let data_tree = &metadata.plain_text_metadata().data_tree;
+    let mut tr = htree::TreeReader::new(data_tree.height, &data_tree.address);
 
-            if let Some(ranges) = ranges {
-                send_partial_htree(repo, &mut tr, ranges, w)?;
-            } else {
-                send_htree(repo, &mut tr, w)?;
-            }
-        }
+    if let Some(ranges) = ranges {
+        send_partial_htree(repo, &mut tr, ranges, w)?;
+    } else {
+        send_htree(repo, &mut tr, w)?;
     }
 
     Ok(())
@@ -259,13 +518,9 @@ fn send_index(
         }
     };
 
-    match metadata {
-        itemset::VersionedItemMetadata::V1(metadata) => {
-            if let Some(index_tree) = metadata.plain_text_metadata.index_tree {
-                let mut tr = htree::TreeReader::new(index_tree.height, &index_tree.address);
-                send_htree(repo, &mut tr, w)?;
-            }
-        }
+    if let Some(index_tree) = &metadata.plain_text_metadata().index_tree {
+        let mut tr = htree::TreeReader::new(index_tree.height, &index_tree.address);
+        send_htree(repo, &mut tr, w)?;
     }
 
     Ok(())
@@ -331,6 +586,9 @@ fn send_partial_htree(
     w: &mut dyn std::io::Write,
 ) -> Result<(), failure::Error> {
     // The ranges are sent from the client, first validate them.
+    if ranges.is_empty() {
+        failure::bail!("malformed htree fetch range, no ranges");
+    }
     for (i, r) in ranges.iter().enumerate() {
         if r.start_idx > r.end_idx {
             failure::bail!("malformed htree fetch range, start point after end");
@@ -352,42 +610,35 @@ fn send_partial_htree(
     let mut storage_engine = repo.storage_engine()?;
 
     let (height, chunk_address) = tr.next_addr()?.unwrap();
+    let mut filter = htree::DataRangeFilter::new(ranges, height, &chunk_address);
     let mut next = (
         height,
         chunk_address,
         storage_engine.get_chunk_async(&chunk_address),
     );
 
-    let mut range_idx: usize = 0;
-    let mut current_data_chunk_idx: u64 = 0;
-
     loop {
         let (height, chunk_address, pending_chunk) = next;
         let chunk_data = pending_chunk.recv()??;
 
-        if height == 1 {
-            let mut filtered_chunk_data = Vec::with_capacity(chunk_data.len());
+        if height == 0 {
+            filter.next_data_chunk();
+        } else if height == 1 {
+            tr.push_level(0, filter.filter_level(&chunk_data))?;
+        } else if !filter.done() {
+            tr.push_level(height - 1, chunk_data.clone())?;
+        }
 
-            for addr_bytes in chunk_data.chunks(address::ADDRESS_SZ) {
-                if let Some(current_range) = ranges.get(range_idx) {
-                    if current_data_chunk_idx >= current_range.start_idx
-                        && current_data_chunk_idx <= current_range.end_idx
-                    {
-                        filtered_chunk_data.extend_from_slice(addr_bytes);
-                    }
-                    current_data_chunk_idx += 1;
-                    if current_data_chunk_idx > current_range.end_idx {
-                        range_idx += 1;
-                    }
-                }
+        // The client skips the same tree blocks, see DataRangeFilter::done.
+        let mut next_addr = tr.next_addr()?;
+        while let Some((height, _)) = next_addr {
+            if height == 0 || !filter.done() {
+                break;
             }
-
-            tr.push_level(height - 1, filtered_chunk_data)?;
-        } else if ranges.get(range_idx).is_some() {
-            tr.push_level(height - 1, chunk_data.clone())?;
+            next_addr = tr.next_addr()?;
         }
 
-        match tr.next_addr()? {
+        match next_addr {
             Some((height, chunk_address)) => {
                 next = (
                     height,
@@ -396,6 +647,7 @@ fn send_partial_htree(
                 );
             }
             None => {
+                filter.finish()?;
                 write_packet(
                     w,
                     &Packet::Chunk(Chunk {
@@ -417,13 +669,26 @@ fn send_partial_htree(
     }
 }
 
-fn gc(repo: &mut repository::Repo, w: &mut dyn std::io::Write) -> Result<(), failure::Error> {
+fn gc(
+    repo: &mut repository::Repo,
+    req: TGc,
+    w: &mut dyn std::io::Write,
+) -> Result<(), failure::Error> {
     let mut update_progress_msg = |msg| {
         write_packet(w, &Packet::Progress(Progress::SetMessage(msg)))?;
         Ok(())
     };
 
-    let stats = repo.gc(&mut update_progress_msg)?;
+    // The worker count comes from the client, don't let it start unbounded threads.
+    let max_sweep_workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .max(repository::DEFAULT_GC_SWEEP_WORKERS);
+    let sweep_workers = match req.sweep_workers {
+        Some(sweep_workers) => (sweep_workers as usize).clamp(1, max_sweep_workers),
+        None => repository::DEFAULT_GC_SWEEP_WORKERS,
+    };
+    let stats = repo.gc(req.pacing, sweep_workers, &mut update_progress_msg)?;
 
     write_packet(w, &Packet::RGc(RGc { stats }))?;
     Ok(())
@@ -431,26 +696,50 @@ fn gc(repo: &mut repository::Repo, w: &mut dyn std::io::Write) -> Result<(), fai
 
 fn item_sync(
     repo: &mut repository::Repo,
-    after: i64,
-    request_gc_generation: Option<Xid>,
+    req: TRequestItemSync,
     w: &mut dyn std::io::Write,
 ) -> Result<(), failure::Error> {
-    repo.item_sync(after, request_gc_generation, &mut |event| match event {
-        repository::ItemSyncEvent::Start(gc_generation) => {
-            write_packet(
-                w,
-                &Packet::RRequestItemSync(RRequestItemSync { gc_generation }),
-            )?;
-            Ok(())
-        }
-        repository::ItemSyncEvent::LogOps(ops) => {
-            write_packet(w, &Packet::SyncLogOps(ops))?;
-            Ok(())
-        }
-        repository::ItemSyncEvent::End => {
-            write_packet(w, &Packet::SyncLogOps(vec![]))?;
-            Ok(())
-        }
-    })?;
+    repo.item_sync(
+        req.after,
+        req.gc_generation,
+        req.want_snapshot,
+        req.accept_snapshot,
+        &mut |event| match event {
+            repository::ItemSyncEvent::Start(
+                gc_generation,
+                item_log_head,
+                last_op_id,
+                snapshot,
+            ) => {
+                write_packet(
+                    w,
+                    &Packet::RRequestItemSync(RRequestItemSync {
+                        gc_generation,
+                        item_log_head,
+                        snapshot,
+                        snapshots_supported: true,
+                        last_op_id,
+                    }),
+                )?;
+                Ok(())
+            }
+            repository::ItemSyncEvent::LogDigests(digests) => {
+                write_packet(w, &Packet::SyncLogDigests(digests))?;
+                Ok(())
+            }
+            repository::ItemSyncEvent::LogOps(ops) => {
+                if req.accept_compressed {
+                    write_packet(w, &Packet::CompressedSyncLogOps(compress_log_ops(&ops)?))?;
+                } else {
+                    write_packet(w, &Packet::SyncLogOps(ops))?;
+                }
+                Ok(())
+            }
+            repository::ItemSyncEvent::End => {
+                write_packet(w, &Packet::SyncLogOps(vec![]))?;
+                Ok(())
+            }
+        },
+    )?;
     Ok(())
 }
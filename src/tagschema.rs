@@ -0,0 +1,133 @@
+// Tag schemas restrict the tags items may be sent with, for example:
+//
+//   # Every item needs a hostname and a type.
+//   required hostname type
+//   # No other tags may be used.
+//   allowed name
+//   # Tag values must match a glob.
+//   match type backup-*
+//
+// Tags are encrypted, so schemas are always checked by the client,
+// even when the schema is provided by the repository.
+
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+pub struct TagSchema {
+    source: String,
+    required: Vec<String>,
+    // None means any tag is allowed.
+    allowed: Option<Vec<String>>,
+    patterns: Vec<(String, glob::Pattern)>,
+}
+
+pub fn parse(source: &str, text: &str) -> Result<TagSchema, failure::Error> {
+    let mut schema = TagSchema {
+        source: source.to_string(),
+        required: Vec::new(),
+        allowed: None,
+        patterns: Vec::new(),
+    };
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        match words.next().unwrap() {
+            "required" => schema.required.extend(words.map(|w| w.to_string())),
+            "allowed" => schema
+                .allowed
+                .get_or_insert_with(Vec::new)
+                .extend(words.map(|w| w.to_string())),
+            "match" => {
+                let (tag, pattern) = match (words.next(), words.next(), words.next()) {
+                    (Some(tag), Some(pattern), None) => (tag, pattern),
+                    _ => failure::bail!("{} line {}: expected 'match TAG GLOB'", source, i + 1),
+                };
+                let pattern = match glob::Pattern::new(pattern) {
+                    Ok(pattern) => pattern,
+                    Err(err) => {
+                        failure::bail!("{} line {}: invalid glob pattern: {}", source, i + 1, err)
+                    }
+                };
+                schema.patterns.push((tag.to_string(), pattern));
+            }
+            directive => failure::bail!(
+                "{} line {}: unknown directive '{}', expected 'required', 'allowed' or 'match'",
+                source,
+                i + 1,
+                directive
+            ),
+        }
+    }
+
+    Ok(schema)
+}
+
+impl TagSchema {
+    pub fn validate(&self, tags: &BTreeMap<String, String>) -> Result<(), failure::Error> {
+        for tag in self.required.iter() {
+            if !tags.contains_key(tag) {
+                failure::bail!("{} requires the tag '{}'", self.source, tag);
+            }
+        }
+
+        if let Some(ref allowed) = self.allowed {
+            for tag in tags.keys() {
+                if !allowed.contains(tag) && !self.required.contains(tag) {
+                    failure::bail!("{} does not allow the tag '{}'", self.source, tag);
+                }
+            }
+        }
+
+        for (tag, pattern) in self.patterns.iter() {
+            if let Some(value) = tags.get(tag) {
+                if !pattern.matches(value) {
+                    failure::bail!(
+                        "{} requires the tag '{}' to match '{}', got '{}'",
+                        self.source,
+                        tag,
+                        pattern.as_str(),
+                        value
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_tags() {
+        let schema = parse(
+            "test schema",
+            "# comment\nrequired hostname\nallowed name\nmatch type backup-*\nallowed type\n",
+        )
+        .unwrap();
+        let mut tags = BTreeMap::new();
+        assert!(schema.validate(&tags).is_err());
+        tags.insert("hostname".to_string(), "x".to_string());
+        assert!(schema.validate(&tags).is_ok());
+        tags.insert("type".to_string(), "backup-db".to_string());
+        assert!(schema.validate(&tags).is_ok());
+        tags.insert("type".to_string(), "db".to_string());
+        assert!(schema.validate(&tags).is_err());
+        tags.remove("type");
+        tags.insert("other".to_string(), "x".to_string());
+        assert!(schema.validate(&tags).is_err());
+    }
+
+    #[test]
+    fn invalid_schemas() {
+        assert!(parse("s", "require x").is_err());
+        assert!(parse("s", "match x").is_err());
+        assert!(parse("s", "match x [").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+// Grouping and gap detection for 'bupstash timeline'.
+
+use super::xid::*;
+use std::collections::BTreeMap;
+
+pub struct Entry {
+    pub id: Xid,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct Group {
+    // The value of each group by tag, None if the items do not have it.
+    pub key: Vec<(String, Option<String>)>,
+    pub entries: Vec<Entry>,
+}
+
+pub fn group_items(
+    group_by: &[String],
+    items: Vec<(Xid, chrono::DateTime<chrono::Utc>, BTreeMap<String, String>)>,
+) -> Vec<Group> {
+    let mut groups: BTreeMap<Vec<Option<String>>, Vec<Entry>> = BTreeMap::new();
+    for (id, timestamp, tags) in items.into_iter() {
+        let key = group_by.iter().map(|t| tags.get(t).cloned()).collect();
+        groups.entry(key).or_default().push(Entry { id, timestamp });
+    }
+    groups
+        .into_iter()
+        .map(|(key, mut entries)| {
+            entries.sort_by_key(|e| e.timestamp);
+            Group {
+                key: group_by.iter().cloned().zip(key).collect(),
+                entries,
+            }
+        })
+        .collect()
+}
+
+// Intervals longer than twice the median interval between items are gaps,
+// so a group with a daily schedule reports a missed day. Items sent together
+// are not an interval of the schedule and are skipped.
+pub fn default_gap_threshold(entries: &[Entry]) -> Option<chrono::Duration> {
+    let mut intervals: Vec<chrono::Duration> = entries
+        .windows(2)
+        .map(|w| w[1].timestamp.signed_duration_since(w[0].timestamp))
+        .filter(|d| *d > chrono::Duration::zero())
+        .collect();
+    if intervals.is_empty() {
+        return None;
+    }
+    intervals.sort();
+    Some(intervals[intervals.len() / 2] * 2)
+}
+
+// The gaps in a group as (index of the item before the gap, length), a gap
+// after the last item runs until now.
+pub fn find_gaps(
+    entries: &[Entry],
+    threshold: chrono::Duration,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<(usize, chrono::Duration)> {
+    let mut gaps = Vec::new();
+    for (i, e) in entries.iter().enumerate() {
+        let next = match entries.get(i + 1) {
+            Some(next) => next.timestamp,
+            None => now,
+        };
+        let interval = next.signed_duration_since(e.timestamp);
+        if interval > threshold {
+            gaps.push((i, interval));
+        }
+    }
+    gaps
+}
+
+// Durations are rounded down to their largest unit, that is enough to spot a missed backup.
+pub fn format_gap(d: chrono::Duration) -> String {
+    let (n, unit) = if d.num_days() > 0 {
+        (d.num_days(), "day")
+    } else if d.num_hours() > 0 {
+        (d.num_hours(), "hour")
+    } else if d.num_minutes() > 0 {
+        (d.num_minutes(), "minute")
+    } else {
+        (d.num_seconds(), "second")
+    };
+    if n == 1 {
+        format!("{} {}", n, unit)
+    } else {
+        format!("{} {}s", n, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeline_gaps() {
+        let start = chrono::Utc::now() - chrono::Duration::days(30);
+        let mut items = Vec::new();
+        for day in [0, 1, 2, 6, 7].iter() {
+            let mut tags = BTreeMap::new();
+            tags.insert("name".to_string(), "a".to_string());
+            items.push((Xid::new(), start + chrono::Duration::days(*day), tags));
+        }
+        items.push((Xid::new(), start, BTreeMap::new()));
+
+        let groups = group_items(&["name".to_string()], items);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].key, vec![("name".to_string(), None)]);
+        assert_eq!(groups[0].entries.len(), 1);
+        assert_eq!(groups[1].entries.len(), 5);
+
+        let entries = &groups[1].entries;
+        let threshold = default_gap_threshold(entries).unwrap();
+        assert_eq!(threshold, chrono::Duration::days(2));
+        let now = start + chrono::Duration::days(8);
+        let gaps = find_gaps(entries, threshold, now);
+        assert_eq!(gaps, vec![(2, chrono::Duration::days(4))]);
+        assert_eq!(format_gap(gaps[0].1), "4 days");
+        assert_eq!(format_gap(chrono::Duration::hours(1)), "1 hour");
+        assert!(default_gap_threshold(&groups[0].entries).is_none());
+    }
+}
@@ -1,7 +1,9 @@
 // EXtended tar functionality.
 
+use super::acl;
 use std::convert::TryInto;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::fs::MetadataExt;
 
 fn format_pax_extended_record(key: &[u8], value: &[u8]) -> Vec<u8> {
@@ -29,16 +31,57 @@ fn format_pax_extended_record(key: &[u8], value: &[u8]) -> Vec<u8> {
 }
 
 cfg_if::cfg_if! {
-    if #[cfg(linux)] {
+    if #[cfg(target_os = "linux")] {
 
         fn dev_major(dev: u64) -> u32 {
-            ((dev >> 32) & 0xffff_f000) |
-            ((dev >>  8) & 0x0000_0fff)
+            (((dev >> 32) & 0xffff_f000) |
+             ((dev >>  8) & 0x0000_0fff)) as u32
         }
 
         fn dev_minor(dev: u64) -> u32 {
-            ((dev >> 12) & 0xffff_ff00) |
-            ((dev      ) & 0x0000_00ff)
+            (((dev >> 12) & 0xffff_ff00) |
+             ((dev      ) & 0x0000_00ff)) as u32
+        }
+
+        pub fn make_dev(major: u32, minor: u32) -> u64 {
+            let (major, minor) = (major as u64, minor as u64);
+            ((major & 0xffff_f000) << 32) | ((major & 0x0000_0fff) << 8) |
+            ((minor & 0xffff_ff00) << 12) | (minor & 0x0000_00ff)
+        }
+
+    } else if #[cfg(target_os = "freebsd")] {
+
+        // See sys/types.h, dev_t is 64 bits since FreeBSD 12.
+        fn dev_major(dev: u64) -> u32 {
+            (((dev >> 32) & 0xffff_ff00) |
+             ((dev >>  8) & 0x0000_00ff)) as u32
+        }
+
+        fn dev_minor(dev: u64) -> u32 {
+            (((dev >> 24) & 0x0000_ff00) |
+             ((dev      ) & 0xffff_00ff)) as u32
+        }
+
+        pub fn make_dev(major: u32, minor: u32) -> u64 {
+            let (major, minor) = (major as u64, minor as u64);
+            ((major & 0xffff_ff00) << 32) | ((major & 0x0000_00ff) << 8) |
+            ((minor & 0x0000_ff00) << 24) | (minor & 0xffff_00ff)
+        }
+
+    } else if #[cfg(target_os = "openbsd")] {
+
+        // See sys/types.h.
+        fn dev_major(dev: u64) -> u32 {
+            ((dev >> 8) & 0xff) as u32
+        }
+
+        fn dev_minor(dev: u64) -> u32 {
+            ((dev & 0xff) | ((dev & 0xffff_0000) >> 8)) as u32
+        }
+
+        pub fn make_dev(major: u32, minor: u32) -> u64 {
+            let (major, minor) = (major as u64, minor as u64);
+            ((major & 0xff) << 8) | (minor & 0xff) | ((minor & 0x00ff_ff00) << 8)
         }
 
     } else {
@@ -51,6 +94,56 @@ cfg_if::cfg_if! {
             panic!("unable to get device minor number on this platform (file a bug report)");
         }
 
+        pub fn make_dev(_major: u32, _minor: u32) -> u64 {
+            panic!("unable to make device numbers on this platform (file a bug report)");
+        }
+
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "linux")] {
+
+        // Returns None if the file has no label or the filesystem does not support them.
+        fn selinux_context(full_path: &std::path::Path) -> Result<Option<Vec<u8>>, std::io::Error> {
+            let path = std::ffi::CString::new(full_path.as_os_str().as_bytes())?;
+            let name = b"security.selinux\0";
+            let mut buf: Vec<u8> = vec![0; 256];
+            loop {
+                let n = unsafe {
+                    libc::lgetxattr(
+                        path.as_ptr(),
+                        name.as_ptr() as *const libc::c_char,
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                if n >= 0 {
+                    buf.truncate(n as usize);
+                    // The kernel includes the trailing null byte, tar does not.
+                    while buf.last() == Some(&0) {
+                        buf.pop();
+                    }
+                    return Ok(Some(buf));
+                }
+                let err = std::io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::ERANGE) => {
+                        let len = buf.len();
+                        buf.resize(len * 2, 0);
+                    }
+                    Some(libc::ENODATA) | Some(libc::ENOTSUP) => return Ok(None),
+                    _ => return Err(err),
+                }
+            }
+        }
+
+    } else {
+
+        fn selinux_context(_full_path: &std::path::Path) -> Result<Option<Vec<u8>>, std::io::Error> {
+            Ok(None)
+        }
+
     }
 }
 
@@ -58,10 +151,26 @@ pub fn dirent_to_tarheader(
     metadata: &std::fs::Metadata,
     full_path: &std::path::PathBuf,
     short_path: &std::path::PathBuf,
+    capture_selinux: bool,
+    acls: Option<&acl::PosixAcls>,
+    deterministic: bool,
 ) -> Result<Vec<u8>, std::io::Error> {
+    // Tar has no entry type for unix sockets, they are only recorded in the index.
+    if metadata.file_type().is_socket() {
+        return Ok(Vec::new());
+    }
+
     let mut pax_ext_records = Vec::new();
     let mut ustar_hdr = tar::Header::new_ustar();
-    ustar_hdr.set_metadata(&metadata);
+    // Headers are stored verbatim in the repository and 'get' returns them unchanged,
+    // the header mode is pinned so the encoding does not drift with the tar crate defaults.
+    ustar_hdr.set_metadata_in_mode(&metadata, tar::HeaderMode::Complete);
+    // Unlike tar::HeaderMode::Deterministic, permissions are kept as they are.
+    if deterministic {
+        ustar_hdr.set_mtime(0);
+        ustar_hdr.set_uid(0);
+        ustar_hdr.set_gid(0);
+    }
 
     match ustar_hdr.set_path(&short_path) {
         Ok(()) => (),
@@ -102,26 +211,335 @@ pub fn dirent_to_tarheader(
         _ => (),
     }
 
+    if capture_selinux {
+        if let Some(context) = selinux_context(full_path)? {
+            // The record name GNU tar uses, restored with 'tar --selinux -x'.
+            let context_record = format_pax_extended_record(b"RHT.security.selinux", &context);
+            pax_ext_records.extend_from_slice(&context_record);
+        }
+    }
+
+    if let Some(acls) = acls {
+        // The record names star and GNU tar use, restored with 'tar --acls -x'.
+        if let Some(ref access) = acls.access {
+            let access_record = format_pax_extended_record(b"SCHILY.acl.access", access.as_bytes());
+            pax_ext_records.extend_from_slice(&access_record);
+        }
+        if let Some(ref default) = acls.default {
+            let default_record =
+                format_pax_extended_record(b"SCHILY.acl.default", default.as_bytes());
+            pax_ext_records.extend_from_slice(&default_record);
+        }
+    }
+
     ustar_hdr.set_cksum();
 
     let mut hdr_bytes = Vec::new();
 
     if !pax_ext_records.is_empty() {
-        let mut pax_ext_hdr = tar::Header::new_ustar();
-        pax_ext_hdr.set_entry_type(tar::EntryType::XHeader);
-        pax_ext_hdr.set_size(pax_ext_records.len().try_into().unwrap());
-        pax_ext_hdr.set_cksum();
-        hdr_bytes.extend_from_slice(&pax_ext_hdr.as_bytes()[..]);
-        hdr_bytes.extend_from_slice(&pax_ext_records);
-        let remaining = 512 - (hdr_bytes.len() % 512);
-        if remaining < 512 {
-            let buf = [0; 512];
-            hdr_bytes.extend_from_slice(&buf[..remaining as usize]);
-        }
-        debug_assert!(hdr_bytes.len() % 512 == 0);
+        hdr_bytes.extend_from_slice(&pax_ext_header(&pax_ext_records));
     }
 
     hdr_bytes.extend_from_slice(&ustar_hdr.as_bytes()[..]);
 
     Ok(hdr_bytes)
 }
+
+fn pax_ext_header(pax_ext_records: &[u8]) -> Vec<u8> {
+    let mut hdr_bytes = Vec::new();
+    let mut pax_ext_hdr = tar::Header::new_ustar();
+    pax_ext_hdr.set_entry_type(tar::EntryType::XHeader);
+    pax_ext_hdr.set_size(pax_ext_records.len().try_into().unwrap());
+    pax_ext_hdr.set_cksum();
+    hdr_bytes.extend_from_slice(&pax_ext_hdr.as_bytes()[..]);
+    hdr_bytes.extend_from_slice(pax_ext_records);
+    let remaining = 512 - (hdr_bytes.len() % 512);
+    if remaining < 512 {
+        let buf = [0; 512];
+        hdr_bytes.extend_from_slice(&buf[..remaining as usize]);
+    }
+    debug_assert!(hdr_bytes.len() % 512 == 0);
+    hdr_bytes
+}
+
+type PaxExtendedRecords = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn parse_pax_extended_records(mut data: &[u8]) -> Result<PaxExtendedRecords, std::io::Error> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid pax extended header",
+        )
+    };
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let space = data.iter().position(|b| *b == b' ').ok_or_else(invalid)?;
+        let record_len: usize = std::str::from_utf8(&data[..space])
+            .map_err(|_| invalid())?
+            .parse()
+            .map_err(|_| invalid())?;
+        if record_len <= space + 1 || record_len > data.len() || data[record_len - 1] != b'\n' {
+            return Err(invalid());
+        }
+        let kv = &data[space + 1..record_len - 1];
+        let eq = kv.iter().position(|b| *b == b'=').ok_or_else(invalid)?;
+        records.push((kv[..eq].to_vec(), kv[eq + 1..].to_vec()));
+        data = &data[record_len..];
+    }
+    Ok(records)
+}
+
+fn padded_size(size: u64) -> u64 {
+    size.div_ceil(512) * 512
+}
+
+// Rewrites tar streams so every entry is placed under a top level directory,
+// 'get --allow-many' uses this to concatenate several directory snapshots.
+// The end of archive blocks of each stream are dropped, finish writes the final ones.
+pub struct PrefixTarWriter<'a> {
+    out: &'a mut dyn std::io::Write,
+    prefix: Vec<u8>,
+    buf: Vec<u8>,
+    // Bytes of entry data, including padding, to copy through unchanged.
+    data_remaining: u64,
+    // Type and size of the extended header being read, and their contents once read.
+    ext_hdr: Option<(tar::EntryType, u64)>,
+    pax_ext_records: Option<Vec<u8>>,
+    long_name: Option<Vec<u8>>,
+    long_link: Option<Vec<u8>>,
+    ended: bool,
+}
+
+impl<'a> PrefixTarWriter<'a> {
+    pub fn new(out: &'a mut dyn std::io::Write) -> Self {
+        PrefixTarWriter {
+            out,
+            prefix: Vec::new(),
+            buf: Vec::new(),
+            data_remaining: 0,
+            ext_hdr: None,
+            pax_ext_records: None,
+            long_name: None,
+            long_link: None,
+            ended: false,
+        }
+    }
+
+    pub fn start_archive(&mut self, prefix: &str) {
+        self.prefix = prefix.as_bytes().to_vec();
+        self.buf.clear();
+        self.data_remaining = 0;
+        self.ext_hdr = None;
+        self.pax_ext_records = None;
+        self.long_name = None;
+        self.long_link = None;
+        self.ended = false;
+    }
+
+    pub fn finish(self) -> Result<(), std::io::Error> {
+        self.out.write_all(&[0; 1024])?;
+        self.out.flush()
+    }
+
+    fn prefixed_path(&self, path: &[u8]) -> Vec<u8> {
+        let mut prefixed = self.prefix.clone();
+        if path == b"." || path == b"./" {
+            return prefixed;
+        }
+        let path = path.strip_prefix(b"./").unwrap_or(path);
+        prefixed.push(b'/');
+        prefixed.extend_from_slice(path);
+        prefixed
+    }
+
+    fn set_ext_hdr(&mut self, mut data: Vec<u8>) {
+        match self.ext_hdr.take() {
+            Some((tar::EntryType::XHeader, _)) => self.pax_ext_records = Some(data),
+            Some((entry_type, _)) => {
+                // GNU long names are null terminated.
+                while data.last() == Some(&0) {
+                    data.pop();
+                }
+                if entry_type == tar::EntryType::GNULongName {
+                    self.long_name = Some(data);
+                } else {
+                    self.long_link = Some(data);
+                }
+            }
+            None => (),
+        }
+    }
+
+    fn write_header(&mut self, block: &[u8]) -> Result<(), std::io::Error> {
+        if block.iter().all(|b| *b == 0) {
+            self.ended = true;
+            return Ok(());
+        }
+
+        let mut hdr = tar::Header::new_old();
+        hdr.as_mut_bytes().copy_from_slice(block);
+        let size = hdr.entry_size()?;
+
+        let entry_type = hdr.entry_type();
+        match entry_type {
+            tar::EntryType::XHeader | tar::EntryType::GNULongName | tar::EntryType::GNULongLink => {
+                if size > 1024 * 1024 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "tar extended header too large",
+                    ));
+                }
+                self.ext_hdr = Some((entry_type, size));
+                if size == 0 {
+                    self.set_ext_hdr(Vec::new());
+                }
+                return Ok(());
+            }
+            tar::EntryType::XGlobalHeader => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "unsupported tar global header",
+                ));
+            }
+            _ => (),
+        }
+
+        let mut records = match self.pax_ext_records.take() {
+            Some(records) => parse_pax_extended_records(&records)?,
+            None => Vec::new(),
+        };
+        let path = match records.iter().position(|(k, _)| k == b"path") {
+            Some(i) => records.remove(i).1,
+            None => match self.long_name.take() {
+                Some(long_name) => long_name,
+                None => hdr.path_bytes().into_owned(),
+            },
+        };
+        let path = self.prefixed_path(&path);
+        if hdr.set_path(std::ffi::OsStr::from_bytes(&path)).is_err() {
+            records.push((b"path".to_vec(), path));
+        }
+        // Link targets are kept as they are, but GNU long links become pax records.
+        if let Some(long_link) = self.long_link.take() {
+            if !records.iter().any(|(k, _)| k == b"linkpath") {
+                records.push((b"linkpath".to_vec(), long_link));
+            }
+        }
+        hdr.set_cksum();
+
+        if !records.is_empty() {
+            let mut pax_ext_records = Vec::new();
+            for (k, v) in records.iter() {
+                pax_ext_records.extend_from_slice(&format_pax_extended_record(k, v));
+            }
+            self.out.write_all(&pax_ext_header(&pax_ext_records))?;
+        }
+        self.out.write_all(&hdr.as_bytes()[..])?;
+        self.data_remaining = padded_size(size);
+        Ok(())
+    }
+}
+
+impl<'a> std::io::Write for PrefixTarWriter<'a> {
+    fn write(&mut self, mut data: &[u8]) -> Result<usize, std::io::Error> {
+        let n = data.len();
+        while !data.is_empty() && !self.ended {
+            if self.data_remaining > 0 {
+                let k = std::cmp::min(self.data_remaining, data.len() as u64) as usize;
+                self.out.write_all(&data[..k])?;
+                self.data_remaining -= k as u64;
+                data = &data[k..];
+                continue;
+            }
+
+            let want = match self.ext_hdr {
+                Some((_, size)) => padded_size(size) as usize,
+                None => 512,
+            };
+            let k = std::cmp::min(want - self.buf.len(), data.len());
+            self.buf.extend_from_slice(&data[..k]);
+            data = &data[k..];
+            if self.buf.len() < want {
+                continue;
+            }
+
+            let mut block = std::mem::take(&mut self.buf);
+            match self.ext_hdr {
+                Some((_, size)) => {
+                    block.truncate(size as usize);
+                    self.set_ext_hdr(block);
+                }
+                None => self.write_header(&block)?,
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd"))]
+    fn device_numbers() {
+        for (major, minor) in [(0, 0), (8, 1), (255, 65537)].iter() {
+            let dev = make_dev(*major, *minor);
+            assert_eq!((dev_major(dev), dev_minor(dev)), (*major, *minor));
+        }
+    }
+
+    #[test]
+    fn prefix_tar_streams() {
+        let long_name = "d/".to_string() + &"x".repeat(150);
+        let mut archives = Vec::new();
+        for name in ["a", long_name.as_str()].iter() {
+            let mut builder = tar::Builder::new(Vec::new());
+            let mut hdr = tar::Header::new_ustar();
+            hdr.set_entry_type(tar::EntryType::Directory);
+            hdr.set_size(0);
+            builder
+                .append_data(&mut hdr, ".", std::io::empty())
+                .unwrap();
+            let mut hdr = tar::Header::new_ustar();
+            hdr.set_size(3);
+            builder.append_data(&mut hdr, name, &b"abc"[..]).unwrap();
+            archives.push(builder.into_inner().unwrap());
+        }
+
+        let mut out = Vec::new();
+        let mut w = PrefixTarWriter::new(&mut out);
+        for (i, archive) in archives.iter().enumerate() {
+            w.start_archive(&format!("item{}", i));
+            // Small writes so headers are split across calls.
+            for chunk in archive.chunks(100) {
+                w.write_all(chunk).unwrap();
+            }
+        }
+        w.finish().unwrap();
+
+        let mut paths = Vec::new();
+        let mut ar = tar::Archive::new(&out[..]);
+        for ent in ar.entries().unwrap() {
+            let mut ent = ent.unwrap();
+            let path = ent.path().unwrap().to_string_lossy().to_string();
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut ent, &mut data).unwrap();
+            paths.push((path, data.len()));
+        }
+        assert_eq!(
+            paths,
+            vec![
+                ("item0".to_string(), 0),
+                ("item0/a".to_string(), 3),
+                ("item1".to_string(), 0),
+                (format!("item1/{}", long_name), 3),
+            ]
+        );
+    }
+}